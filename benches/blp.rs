@@ -0,0 +1,105 @@
+//! Criterion benchmarks over synthetic problems of increasing size.
+//!
+//! Run with `cargo bench --features bench`; each group is swept over
+//! [`default_grid`](blprs::bench::default_grid).
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use nalgebra::DVector;
+
+use blprs::bench::{SyntheticProblem, default_grid, synthetic_problem};
+use blprs::demand::{ShareInputs, predict_shares_with};
+use blprs::models::{DemandModel, RandomCoefficientsLogit};
+
+fn problems() -> Vec<(String, SyntheticProblem)> {
+    default_grid()
+        .iter()
+        .map(|spec| {
+            (
+                spec.label(),
+                synthetic_problem(spec).expect("synthetic problem"),
+            )
+        })
+        .collect()
+}
+
+fn benchmarks(c: &mut Criterion) {
+    let problems = problems();
+
+    let mut group = c.benchmark_group("share_prediction");
+    for (label, synthetic) in &problems {
+        let problem = &synthetic.problem;
+        let inputs = ShareInputs::for_parameters(
+            problem.data(),
+            problem.draws(),
+            &synthetic.parameters,
+            &problem.options().contraction,
+        );
+        group.bench_function(BenchmarkId::from_parameter(label), |b| {
+            b.iter(|| predict_shares_with(&synthetic.delta, &inputs).unwrap())
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("contraction");
+    for (label, synthetic) in &problems {
+        let problem = &synthetic.problem;
+        let inputs = ShareInputs::for_parameters(
+            problem.data(),
+            problem.draws(),
+            &synthetic.parameters,
+            &problem.options().contraction,
+        );
+        group.bench_function(BenchmarkId::from_parameter(label), |b| {
+            b.iter(|| RandomCoefficientsLogit.invert(&inputs).unwrap())
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("share_jacobian");
+    for (label, synthetic) in &problems {
+        let problem = &synthetic.problem;
+        let inputs = ShareInputs::for_parameters(
+            problem.data(),
+            problem.draws(),
+            &synthetic.parameters,
+            &problem.options().contraction,
+        );
+        group.bench_function(BenchmarkId::from_parameter(label), |b| {
+            b.iter(|| {
+                RandomCoefficientsLogit
+                    .jacobian(&synthetic.delta, &inputs)
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("moment_jacobian");
+    for (label, synthetic) in &problems {
+        let problem = &synthetic.problem;
+        let k1 = problem.data().linear_dim();
+        let mut theta = DVector::zeros(problem.parameter_count());
+        theta
+            .rows_mut(k1, theta.len() - k1)
+            .copy_from_slice(synthetic.parameters.sigma().as_slice());
+        group.bench_function(BenchmarkId::from_parameter(label), |b| {
+            b.iter(|| problem.moments(&theta).unwrap())
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("objective");
+    for (label, synthetic) in &problems {
+        group.bench_function(BenchmarkId::from_parameter(label), |b| {
+            b.iter(|| synthetic.problem.solve(&synthetic.parameters).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = benchmarks
+}
+criterion_main!(benches);
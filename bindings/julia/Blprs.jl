@@ -0,0 +1,166 @@
+# Julia bindings for blprs, built on the C ABI of the `capi` feature.
+#
+# Build the shared library and point `BLPRS_LIB` at it before loading the module:
+#
+#     cargo build --release --features capi
+#     ENV["BLPRS_LIB"] = "target/release/libblprs.so"   # .dylib on macOS, .dll on Windows
+#     include("bindings/julia/Blprs.jl"); using .Blprs
+#
+#     problem = Blprs.Problem(market_ids, shares, X1, X2, draws; Z = Z)
+#     results = Blprs.optimize(problem, fill(1.0, 1, 1))
+#     results.beta, results.sigma, results.objective, results.converged
+#
+# Julia arrays are column-major like the C interface, so matrices are passed without
+# copying when they are already dense `Float64` arrays.
+module Blprs
+
+export Problem, BlpError, solve, optimize
+
+const libblprs = get(ENV, "BLPRS_LIB", "libblprs")
+
+@enum Status::Cint begin
+    OK = 0
+    NULL_POINTER = 1
+    INVALID_INPUT = 2
+    SINGULAR = 3
+    NOT_CONVERGED = 4
+    NUMERICAL = 5
+    OTHER = 6
+    PANIC = 7
+end
+
+# Vectors held by a results handle, in the order of `blp_vector`, passed as the `Cint`
+# `which`; the library rejects any other code with INVALID_INPUT.
+const BETA, SIGMA, DELTA, XI, SHARES = Cint.(0:4)
+
+"""Error raised when a call into blprs fails, with the library's message."""
+struct BlpError <: Exception
+    status::Status
+    message::String
+end
+
+Base.showerror(io::IO, err::BlpError) = print(io, "blprs ", err.status, ": ", err.message)
+
+function check(status::Cint)
+    status == 0 && return nothing
+    message = unsafe_string(ccall((:blp_last_error, libblprs), Cstring, ()))
+    throw(BlpError(Status(status), message))
+end
+
+"""
+    dense(x, rows) -> Matrix{Float64}
+
+Column-major `Float64` copy of a vector or matrix with `rows` rows (a vector becomes a
+single column); `nothing` becomes an empty `rows x 0` matrix.
+"""
+dense(::Nothing, rows::Integer) = Matrix{Float64}(undef, rows, 0)
+dense(x::AbstractVector, rows::Integer) = dense(reshape(x, :, 1), rows)
+function dense(x::AbstractMatrix, rows::Integer)
+    size(x, 1) == rows || throw(DimensionMismatch("expected $rows rows, found $(size(x, 1))"))
+    return convert(Matrix{Float64}, x)
+end
+
+"""
+    market_codes(ids) -> Vector{Int64}
+
+Integer market ids; non-integer ids (strings, symbols) are numbered in order of first
+appearance. Rows of the same market must still be contiguous.
+"""
+market_codes(ids::AbstractVector{<:Integer}) = convert(Vector{Int64}, ids)
+function market_codes(ids::AbstractVector)
+    codes = Dict{eltype(ids),Int64}()
+    return [get!(codes, id, length(codes)) for id in ids]
+end
+
+"""
+A BLP problem held by the library; released when garbage collected.
+"""
+mutable struct Problem
+    handle::Ptr{Cvoid}
+    products::Int
+    k1::Int
+    k2::Int
+
+    function Problem(market_ids, shares, X1, X2, draws; Z = nothing, weights = nothing)
+        n = length(shares)
+        ids = market_codes(market_ids)
+        s = convert(Vector{Float64}, shares)
+        x1, x2, z = dense(X1, n), dense(X2, n), dense(Z, n)
+        nodes = dense(draws, size(draws, 1))
+        w = weights === nothing ? C_NULL : convert(Vector{Float64}, weights)
+        out = Ref{Ptr{Cvoid}}(C_NULL)
+        check(ccall((:blp_problem_new, libblprs), Cint,
+            (Csize_t, Ptr{Int64}, Ptr{Float64}, Ptr{Float64}, Csize_t, Ptr{Float64},
+             Csize_t, Ptr{Float64}, Csize_t, Ptr{Float64}, Csize_t, Ptr{Float64},
+             Ptr{Ptr{Cvoid}}),
+            n, ids, s, x1, size(x1, 2), x2, size(x2, 2), z, size(z, 2), nodes,
+            size(nodes, 1), w, out))
+        problem = new(out[], n, size(x1, 2), size(x2, 2))
+        finalizer(problem) do problem
+            ccall((:blp_problem_free, libblprs), Cvoid, (Ptr{Cvoid},), problem.handle)
+        end
+        return problem
+    end
+end
+
+function vector(results::Ptr{Cvoid}, which::Cint)
+    len = Ref{Csize_t}(0)
+    check(ccall((:blp_results_length, libblprs), Cint,
+        (Ptr{Cvoid}, Cint, Ptr{Csize_t}), results, which, len))
+    values = Vector{Float64}(undef, len[])
+    check(ccall((:blp_results_copy, libblprs), Cint,
+        (Ptr{Cvoid}, Cint, Ptr{Float64}, Csize_t), results, which, values, len[]))
+    return values
+end
+
+# Copy a results handle into a NamedTuple and release it.
+function collect_results(problem::Problem, results::Ptr{Cvoid}; extra...)
+    try
+        objective = Ref{Float64}(NaN)
+        check(ccall((:blp_results_objective, libblprs), Cint,
+            (Ptr{Cvoid}, Ptr{Float64}), results, objective))
+        return (
+            beta = vector(results, BETA),
+            sigma = reshape(vector(results, SIGMA), problem.k2, problem.k2),
+            delta = vector(results, DELTA),
+            xi = vector(results, XI),
+            shares = vector(results, SHARES),
+            objective = objective[],
+            extra...,
+        )
+    finally
+        ccall((:blp_results_free, libblprs), Cvoid, (Ptr{Cvoid},), results)
+    end
+end
+
+"""
+    solve(problem, sigma) -> NamedTuple
+
+Solve at the `K2 x K2` matrix `sigma`, returning `beta`, `sigma`, `delta`, `xi`,
+`shares`, and the GMM `objective`.
+"""
+function solve(problem::Problem, sigma::AbstractMatrix)
+    sigma = dense(sigma, problem.k2)
+    out = Ref{Ptr{Cvoid}}(C_NULL)
+    check(ccall((:blp_problem_solve, libblprs), Cint,
+        (Ptr{Cvoid}, Ptr{Float64}, Ptr{Ptr{Cvoid}}), problem.handle, sigma, out))
+    return collect_results(problem, out[])
+end
+
+"""
+    optimize(problem, initial_sigma) -> NamedTuple
+
+Minimize the GMM objective from `initial_sigma`, whose zero entries stay fixed. Returns
+the fields of [`solve`](@ref) plus `converged`.
+"""
+function optimize(problem::Problem, initial_sigma::AbstractMatrix)
+    sigma = dense(initial_sigma, problem.k2)
+    converged = Ref{Bool}(false)
+    out = Ref{Ptr{Cvoid}}(C_NULL)
+    check(ccall((:blp_problem_optimize, libblprs), Cint,
+        (Ptr{Cvoid}, Ptr{Float64}, Ptr{Bool}, Ptr{Ptr{Cvoid}}),
+        problem.handle, sigma, converged, out))
+    return collect_results(problem, out[]; converged = converged[])
+end
+
+end # module
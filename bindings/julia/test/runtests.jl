@@ -0,0 +1,47 @@
+# Tests for the Julia bindings. The helpers run anywhere; the round trip through the
+# library runs when `BLPRS_LIB` points at a build of the `capi` feature:
+#
+#     cargo build --release --features capi
+#     BLPRS_LIB=target/release/libblprs.so julia bindings/julia/test/runtests.jl
+using Test
+
+include(joinpath(@__DIR__, "..", "Blprs.jl"))
+using .Blprs
+
+@testset "Blprs" begin
+    @testset "dense" begin
+        @test size(Blprs.dense(nothing, 3)) == (3, 0)
+        @test Blprs.dense([1, 2, 3], 3) == reshape([1.0, 2.0, 3.0], 3, 1)
+        @test Blprs.dense([1 2; 3 4], 2) isa Matrix{Float64}
+        @test_throws DimensionMismatch Blprs.dense([1.0, 2.0], 3)
+    end
+
+    @testset "market_codes" begin
+        @test Blprs.market_codes([3, 3, 7]) == Int64[3, 3, 7]
+        @test Blprs.market_codes(["b", "b", "a", "a"]) == Int64[0, 0, 1, 1]
+        @test Blprs.market_codes([:x, :y, :y]) == Int64[0, 1, 1]
+    end
+
+    if haskey(ENV, "BLPRS_LIB")
+        @testset "library round trip" begin
+            x = [1.0, 2.0, 1.5, 0.5, 1.2, 0.8]
+            w = [0.3, -0.2, 0.5, 0.1, -0.4, 0.2]
+            problem = Problem(["a", "a", "a", "b", "b", "b"],
+                [0.2, 0.3, 0.1, 0.25, 0.15, 0.2], [ones(6) x], x, [-1.0, -0.3, 0.4, 1.1];
+                Z = [ones(6) x w x .^ 2])
+            solved = solve(problem, fill(0.5, 1, 1))
+            @test length(solved.beta) == 2
+            @test solved.sigma == fill(0.5, 1, 1)
+            @test length(solved.delta) == length(solved.xi) == length(solved.shares) == 6
+            @test solved.objective >= 0
+
+            optimized = optimize(problem, fill(1.0, 1, 1))
+            @test optimized.converged isa Bool
+            @test optimized.objective <= solve(problem, fill(1.0, 1, 1)).objective
+
+            @test_throws DimensionMismatch solve(problem, fill(0.5, 2, 2))
+            @test_throws BlpError Problem([1, 2, 1], [0.2, 0.3, 0.1], ones(3), nothing,
+                [0.0])
+        end
+    end
+end
@@ -0,0 +1,73 @@
+/*
+ * C interface to blprs, available when the crate is built with the `capi` feature:
+ *
+ *     cargo build --release --features capi
+ *
+ * Matrices are flat column-major arrays passed with their dimensions. Every function
+ * returns a blp_status; on failure, blp_last_error() describes the error on the
+ * calling thread. Handles are released with the matching _free function.
+ */
+#ifndef BLPRS_H
+#define BLPRS_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum blp_status {
+    BLP_OK = 0,
+    BLP_NULL_POINTER = 1,
+    BLP_INVALID_INPUT = 2,
+    BLP_SINGULAR = 3,
+    BLP_NOT_CONVERGED = 4,
+    BLP_NUMERICAL = 5,
+    BLP_OTHER = 6,
+    BLP_PANIC = 7
+} blp_status;
+
+/* Codes for the `which` argument of blp_results_length and blp_results_copy, which
+ * take a plain int; any other value fails with BLP_INVALID_INPUT. */
+typedef enum blp_vector {
+    BLP_BETA = 0,   /* K1 */
+    BLP_SIGMA = 1,  /* K2 * K2, column-major */
+    BLP_DELTA = 2,  /* N */
+    BLP_XI = 3,     /* N */
+    BLP_SHARES = 4  /* N */
+} blp_vector;
+
+typedef struct BlpProblemHandle blp_problem;
+typedef struct BlpResultsHandle blp_results;
+
+/* x1: products x k1, x2: products x k2, instruments: products x kz (kz = 0 uses X1),
+ * draws: draw_count x k2. weights may be NULL for equal weights. */
+blp_status blp_problem_new(size_t products, const int64_t *market_ids,
+                           const double *shares, const double *x1, size_t k1,
+                           const double *x2, size_t k2, const double *instruments,
+                           size_t kz, const double *draws, size_t draw_count,
+                           const double *weights, blp_problem **out);
+void blp_problem_free(blp_problem *problem);
+
+blp_status blp_problem_solve(const blp_problem *problem, const double *sigma,
+                             blp_results **out);
+/* Zero entries of initial_sigma are held fixed; converged may be NULL. */
+blp_status blp_problem_optimize(const blp_problem *problem, const double *initial_sigma,
+                                bool *converged, blp_results **out);
+
+blp_status blp_results_length(const blp_results *results, int which, size_t *len);
+blp_status blp_results_copy(const blp_results *results, int which, double *out,
+                            size_t len);
+blp_status blp_results_objective(const blp_results *results, double *value);
+void blp_results_free(blp_results *results);
+
+/* Valid until the next failing call on the same thread. */
+const char *blp_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* BLPRS_H */
@@ -0,0 +1,339 @@
+//! Experimental: markets that only report category-level (aggregate) shares.
+//!
+//! Some data sets observe product-level shares in a subset of markets (e.g. scanner
+//! data from a panel of stores) but only the total share of each product category
+//! elsewhere. [`AggregateMarkets`] holds the characteristics of the products in the
+//! aggregate-only markets together with their observed category totals. At given
+//! nonlinear parameters, `beta` is concentrated out on the product-level markets as
+//! usual; the aggregate markets then contribute one moment per market and category,
+//! `ln S_gt - ln sum_{j in g} s_jt(X1 beta, theta)`, which sets their unobserved `xi`
+//! to its mean of zero. [`Problem::mixed_objective`] adds the weighted sum of squares of
+//! these moments to the product-level GMM objective, and
+//! [`Problem::estimate_mixed`] minimizes the sum over `sigma` (and `rho` under nesting).
+
+use nalgebra::DVector;
+
+use crate::data::ProductData;
+use crate::demand::ShareInputs;
+use crate::error::{BlpError, Result};
+use crate::estimation::{Problem, ProblemResults};
+use crate::optimization::{
+    MAX_RHO, nelder_mead, search_coordinate, searched_entries, sigma_entry, starting_sigma,
+};
+use crate::parameters::NonlinearParameters;
+
+/// Observed total share of one category in one aggregate market.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CategoryShare {
+    /// Market the category is observed in.
+    pub market_id: String,
+    /// Category label.
+    pub category: String,
+    /// Rows of the category's products in [`AggregateMarkets::products`].
+    pub products: Vec<usize>,
+    /// Observed share of the category.
+    pub observed: f64,
+}
+
+/// Products of aggregate-only markets and their observed category shares.
+#[derive(Clone, Debug)]
+pub struct AggregateMarkets {
+    products: ProductData,
+    categories: Vec<CategoryShare>,
+    weight: f64,
+}
+
+impl AggregateMarkets {
+    /// Group the products of `products` into categories by `category_ids`, one per
+    /// product. The product shares are only used through their category totals within
+    /// each market, so any split of an observed category share across its products will
+    /// do.
+    pub fn new(products: ProductData, category_ids: Vec<String>) -> Result<Self> {
+        if category_ids.len() != products.product_count() {
+            return Err(BlpError::dimension_mismatch(
+                "category ids length",
+                products.product_count(),
+                category_ids.len(),
+            ));
+        }
+        let mut categories: Vec<CategoryShare> = Vec::new();
+        for market in products.partition().markets() {
+            let first = categories.len();
+            for row in market.range() {
+                let category = &category_ids[row];
+                let share = products.shares()[row];
+                match categories[first..]
+                    .iter_mut()
+                    .find(|group| &group.category == category)
+                {
+                    Some(group) => {
+                        group.products.push(row);
+                        group.observed += share;
+                    }
+                    None => categories.push(CategoryShare {
+                        market_id: market.id().to_string(),
+                        category: category.clone(),
+                        products: vec![row],
+                        observed: share,
+                    }),
+                }
+            }
+        }
+        Ok(Self {
+            products,
+            categories,
+            weight: 1.0,
+        })
+    }
+
+    /// Scale the aggregate moments' sum of squares by `weight` (default one) relative to
+    /// the product-level objective.
+    pub fn with_weight(mut self, weight: f64) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    /// Products of the aggregate-only markets.
+    pub fn products(&self) -> &ProductData {
+        &self.products
+    }
+
+    /// Observed category shares, in market order and order of first appearance within
+    /// each market.
+    pub fn categories(&self) -> &[CategoryShare] {
+        &self.categories
+    }
+
+    /// Weight on the aggregate moments.
+    pub fn weight(&self) -> f64 {
+        self.weight
+    }
+}
+
+/// Product-level GMM objective combined with the aggregate-market moments.
+#[derive(Clone, Debug)]
+pub struct MixedObjective {
+    /// Product-level objective plus the weighted sum of squared aggregate moments.
+    pub value: f64,
+    /// Solution on the product-level markets, including the concentrated-out `beta`.
+    pub results: ProblemResults,
+    /// Predicted share of each category in [`AggregateMarkets::categories`].
+    pub predicted: DVector<f64>,
+    /// `ln(observed / predicted)` of each category.
+    pub moments: DVector<f64>,
+}
+
+/// Outcome of [`Problem::estimate_mixed`].
+#[derive(Clone, Debug)]
+pub struct MixedEstimate {
+    /// Objective at the best parameters found.
+    pub objective: MixedObjective,
+    /// Number of optimizer iterations.
+    pub iterations: usize,
+    /// Number of objective evaluations, including failed ones.
+    pub evaluations: usize,
+    /// Whether the termination tolerances were met.
+    pub converged: bool,
+}
+
+impl Problem {
+    /// Evaluate the mixed objective at `parameters`: solve the product-level problem, then
+    /// predict the aggregate markets' category shares at `delta = X1 beta`.
+    pub fn mixed_objective(
+        &self,
+        parameters: &NonlinearParameters,
+        aggregate: &AggregateMarkets,
+    ) -> Result<MixedObjective> {
+        let products = aggregate.products();
+        let data = self.data();
+        if products.linear_dim() != data.linear_dim() {
+            return Err(BlpError::dimension_mismatch(
+                "aggregate X1 columns",
+                data.linear_dim(),
+                products.linear_dim(),
+            ));
+        }
+        if products.nonlinear_dim() != data.nonlinear_dim() {
+            return Err(BlpError::dimension_mismatch(
+                "aggregate X2 columns",
+                data.nonlinear_dim(),
+                products.nonlinear_dim(),
+            ));
+        }
+
+        let results = self.solve(parameters)?;
+        let delta = products.x1() * &results.beta;
+        let inputs = ShareInputs::for_parameters(
+            products,
+            self.draws(),
+            parameters,
+            &self.options().contraction,
+        );
+        let shares = self.model().shares(&delta, &inputs)?;
+        let predicted = DVector::from_iterator(
+            aggregate.categories().len(),
+            aggregate.categories().iter().map(|category| {
+                category
+                    .products
+                    .iter()
+                    .map(|&row| shares[row])
+                    .sum::<f64>()
+            }),
+        );
+        let moments = DVector::from_iterator(
+            predicted.len(),
+            aggregate
+                .categories()
+                .iter()
+                .zip(predicted.iter())
+                .map(|(category, predicted)| (category.observed / predicted).ln()),
+        );
+        if moments.iter().any(|moment| !moment.is_finite()) {
+            return Err(BlpError::NumericalError {
+                context: "aggregate category share",
+            });
+        }
+        Ok(MixedObjective {
+            value: results.gmm_value + aggregate.weight() * moments.norm_squared(),
+            results,
+            predicted,
+            moments,
+        })
+    }
+
+    /// Minimize the mixed objective over the nonzero entries of the starting `sigma` in
+    /// [`ProblemOptions::optimization`](crate::ProblemOptions::optimization), with its
+    /// termination settings and bounds, and over `rho` when a starting value is set.
+    pub fn estimate_mixed(&self, aggregate: &AggregateMarkets) -> Result<MixedEstimate> {
+        if let Some(problem) = self.recording_run()? {
+            return problem.estimate_mixed(aggregate);
+        }
+        let options = &self.options().optimization;
+        let start = options
+            .initial_sigma
+            .clone()
+            .ok_or_else(|| BlpError::missing_component("starting values for sigma"))?;
+        if let Some(bounds) = &options.bounds {
+            bounds.validate(self.data().nonlinear_dim())?;
+        }
+        let start = starting_sigma(start, options);
+        let entries = searched_entries(&start, options);
+        let to_parameters = |point: &[f64]| {
+            let mut sigma = start.clone();
+            for (&entry, value) in entries.iter().zip(point) {
+                sigma[entry] = sigma_entry(entry, *value, options);
+            }
+            if let Some(bounds) = &options.bounds {
+                bounds.clamp(&mut sigma);
+            }
+            let parameters = NonlinearParameters::new(sigma);
+            match point.get(entries.len()) {
+                Some(&rho) => parameters.with_rho(rho.clamp(0.0, MAX_RHO)),
+                None => parameters,
+            }
+        };
+        let mut origin: Vec<f64> = entries
+            .iter()
+            .map(|&entry| search_coordinate(entry, start[entry], options))
+            .collect();
+        origin.extend(options.initial_rho);
+
+        let mut best: Option<MixedObjective> = None;
+        let searching = self.deferring_conditioning();
+        let search = nelder_mead(
+            origin,
+            options,
+            |_| {},
+            |point| match searching.mixed_objective(&to_parameters(point), aggregate) {
+                Ok(objective) => {
+                    let value = objective.value;
+                    if best.as_ref().is_none_or(|best| value < best.value) {
+                        best = Some(objective);
+                    }
+                    value
+                }
+                Err(err) => {
+                    log::debug!("infeasible mixed-data candidate: {err}");
+                    f64::INFINITY
+                }
+            },
+        );
+        let mut objective = match best {
+            Some(objective) => objective,
+            None => self.mixed_objective(&to_parameters(&search.point), aggregate)?,
+        };
+        self.complete_conditioning(&mut objective.results);
+        Ok(MixedEstimate {
+            objective,
+            iterations: search.iterations,
+            evaluations: search.evaluations,
+            converged: search.converged,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::DMatrix;
+
+    use super::*;
+    use crate::OptimizationOptions;
+    use crate::data::ProductDataBuilder;
+    use crate::integration::SimulationDraws;
+    use crate::options::ProblemOptions;
+    use crate::parameters::NonlinearParameters;
+    use crate::testing::TestMarkets;
+
+    #[test]
+    fn aggregate_markets_add_category_moments() {
+        let n = 36;
+        let markets = TestMarkets::new(n);
+        let x = markets.x.clone();
+        let markets = markets
+            .with_x1(DMatrix::from_fn(n, 2, |j, k| [1.0, x[j]][k]), vec![1])
+            .with_instruments(DMatrix::from_fn(n, 3, |j, k| [1.0, x[j], x[j] * x[j]][k]));
+        let (x1, z) = (markets.x1(), markets.instruments());
+        let draws = SimulationDraws::standard_normal(20, 1, 5);
+        let truth = NonlinearParameters::new(DMatrix::from_element(1, 1, 0.8));
+        let delta = DVector::from_fn(n, |j, _| -1.0 + x[j]);
+        let market_ids: Vec<String> = (0..n).map(|j| format!("m{}", j / 3)).collect();
+        let build = |rows: std::ops::Range<usize>, shares: DVector<f64>| {
+            ProductDataBuilder::new(market_ids[rows.clone()].to_vec(), shares)
+                .x1(x1.rows(rows.start, rows.len()).into_owned())
+                .x2(x1.rows(rows.start, rows.len()).columns(1, 1).into_owned())
+                .instruments(z.rows(rows.start, rows.len()).into_owned())
+                .build()
+                .unwrap()
+        };
+        let shares = markets.simulate(&draws, &truth, &delta).shares().clone();
+
+        let detailed = build(0..18, shares.rows(0, 18).into_owned());
+        // Only category totals are known in the aggregate markets, so split them evenly.
+        let categories: Vec<String> = (0..18).map(|j| ["a", "a", "b"][j % 3].into()).collect();
+        let split = DVector::from_fn(18, |j, _| {
+            let first = 18 + j / 3 * 3;
+            match j % 3 {
+                2 => shares[first + 2],
+                _ => (shares[first] + shares[first + 1]) / 2.0,
+            }
+        });
+        let aggregate = AggregateMarkets::new(build(18..n, split), categories).unwrap();
+        assert_eq!(aggregate.categories().len(), 12);
+        assert_eq!(aggregate.categories()[0].products, vec![0, 1]);
+
+        let options = ProblemOptions::default().with_optimization(
+            OptimizationOptions::new(DMatrix::from_element(1, 1, 1.5)).with_tolerances(1e-12, 1e-4),
+        );
+        let problem = Problem::with_options(detailed, draws, options).unwrap();
+        let exact = problem.mixed_objective(&truth, &aggregate).unwrap();
+        assert!(exact.moments.amax() < 1e-8);
+        let off = problem
+            .mixed_objective(&DMatrix::from_element(1, 1, 1.5).into(), &aggregate)
+            .unwrap();
+        assert!(off.value > exact.value + 1e-6);
+
+        let estimate = problem.estimate_mixed(&aggregate).unwrap();
+        let sigma = estimate.objective.results.sigma[(0, 0)];
+        assert!((sigma - 0.8).abs() < 0.05, "sigma {sigma}");
+    }
+}
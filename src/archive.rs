@@ -0,0 +1,225 @@
+//! Versioned, self-describing serialization of estimation results.
+//!
+//! Results are written as a JSON envelope carrying a schema name, a schema version,
+//! and the crate version that produced them. Readers accept every earlier schema
+//! version and migrate it forward, so replication archives written by older releases
+//! remain loadable as [`ProblemResults`] evolves.
+//!
+//! Additive changes to the results (new fields with sensible defaults) are handled
+//! with `#[serde(default)]` and do not bump the version. Anything that renames,
+//! removes, or reinterprets a field bumps [`RESULTS_SCHEMA_VERSION`] and adds a step
+//! to [`migrate`].
+
+use std::fs;
+use std::path::Path;
+
+use nalgebra::DMatrix;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
+
+use crate::error::{BlpError, Result};
+use crate::estimation::ProblemResults;
+
+/// Schema name stored in every results archive.
+pub const RESULTS_SCHEMA: &str = "blprs.problem_results";
+
+/// Latest results schema version written by this crate.
+///
+/// - Version 1: `delta`, `beta`, `xi`, `predicted_shares`, `gmm_value`, `contraction`,
+///   `weighting_matrix`, and `options_used`.
+/// - Version 2: adds `sigma`, `conditioning`, and `profiling`.
+pub const RESULTS_SCHEMA_VERSION: u32 = 2;
+
+#[derive(Serialize)]
+struct EnvelopeRef<'a> {
+    schema: &'a str,
+    version: u32,
+    crate_version: &'a str,
+    results: &'a ProblemResults,
+}
+
+impl ProblemResults {
+    /// Serialize the results into a versioned JSON archive.
+    pub fn to_json(&self) -> Result<String> {
+        let envelope = EnvelopeRef {
+            schema: RESULTS_SCHEMA,
+            version: RESULTS_SCHEMA_VERSION,
+            crate_version: env!("CARGO_PKG_VERSION"),
+            results: self,
+        };
+        serde_json::to_string_pretty(&envelope).map_err(|err| BlpError::Serialization {
+            context: "results archive",
+            message: err.to_string(),
+        })
+    }
+
+    /// Parse a JSON archive written by this or any earlier crate version.
+    pub fn from_json(json: &str) -> Result<Self> {
+        let value: Value = serde_json::from_str(json).map_err(|err| BlpError::Serialization {
+            context: "results archive",
+            message: err.to_string(),
+        })?;
+        let schema = value.get("schema").and_then(Value::as_str);
+        if schema != Some(RESULTS_SCHEMA) {
+            return Err(BlpError::Serialization {
+                context: "results archive",
+                message: format!("expected schema `{RESULTS_SCHEMA}`, found {schema:?}"),
+            });
+        }
+        let version = value
+            .get("version")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| BlpError::Serialization {
+                context: "results archive",
+                message: "missing schema version".to_string(),
+            })? as u32;
+        let results = value
+            .get("results")
+            .cloned()
+            .ok_or_else(|| BlpError::Serialization {
+                context: "results archive",
+                message: "missing results payload".to_string(),
+            })?;
+        let migrated = migrate(results, version)?;
+        serde_json::from_value(migrated).map_err(|err| BlpError::Serialization {
+            context: "results archive",
+            message: err.to_string(),
+        })
+    }
+
+    /// Write a versioned JSON archive to `path`.
+    pub fn write_json<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        fs::write(path, self.to_json()?).map_err(|err| BlpError::io("writing results archive", err))
+    }
+
+    /// Read a JSON archive from `path`, migrating older schema versions.
+    pub fn read_json<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let json =
+            fs::read_to_string(path).map_err(|err| BlpError::io("reading results archive", err))?;
+        Self::from_json(&json)
+    }
+}
+
+/// Upgrade a results payload from schema `version` to [`RESULTS_SCHEMA_VERSION`].
+pub fn migrate(mut results: Value, version: u32) -> Result<Value> {
+    if version == 0 || version > RESULTS_SCHEMA_VERSION {
+        return Err(BlpError::UnsupportedSchema {
+            found: version,
+            supported: RESULTS_SCHEMA_VERSION,
+        });
+    }
+    if version < 2 {
+        results = migrate_v1_to_v2(results)?;
+    }
+    Ok(results)
+}
+
+/// Version 1 did not record `sigma` or the numerical diagnostics. `sigma` is restored as
+/// an empty matrix and the condition numbers as unknown (`NaN`).
+fn migrate_v1_to_v2(mut results: Value) -> Result<Value> {
+    let object = results
+        .as_object_mut()
+        .ok_or_else(|| BlpError::Serialization {
+            context: "results archive",
+            message: "results payload must be an object".to_string(),
+        })?;
+    let empty = serde_json::to_value(DMatrix::<f64>::zeros(0, 0)).map_err(|err| {
+        BlpError::Serialization {
+            context: "results migration",
+            message: err.to_string(),
+        }
+    })?;
+    let threshold = object
+        .get("options_used")
+        .and_then(|options| options.get("gmm"))
+        .and_then(|gmm| gmm.get("condition_warning_threshold"))
+        .cloned()
+        .unwrap_or(Value::from(1e12));
+    object.entry("sigma").or_insert(empty);
+    object.entry("conditioning").or_insert_with(|| {
+        serde_json::json!({
+            "ztz": null,
+            "weighting": null,
+            "xzwzx": null,
+            "threshold": threshold,
+        })
+    });
+    object.entry("profiling").or_insert_with(|| {
+        serde_json::json!({
+            "contraction_seconds": 0.0,
+            "linear_seconds": 0.0,
+            "reused_contraction_seconds": 0.0,
+        })
+    });
+    Ok(results)
+}
+
+/// Deserialize an `f64` that JSON may have stored as `null` (non-finite values) as `NaN`.
+pub(crate) fn nan_if_null<'de, D>(deserializer: D) -> std::result::Result<f64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<f64>::deserialize(deserializer)?.unwrap_or(f64::NAN))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Problem;
+    use crate::data::ProductDataBuilder;
+    use crate::integration::SimulationDraws;
+    use crate::parameters::NonlinearParameters;
+    use nalgebra::DVector;
+
+    fn solved() -> ProblemResults {
+        let market_ids = vec!["m1".to_string(), "m1".to_string(), "m2".to_string()];
+        let shares = DVector::from_vec(vec![0.3, 0.2, 0.4]);
+        let x1 = DMatrix::from_row_slice(3, 2, &[1.0, 10.0, 1.0, 15.0, 1.0, 12.0]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .build()
+            .unwrap();
+        let problem = Problem::new(data, SimulationDraws::standard_normal(1, 0, 1)).unwrap();
+        problem.solve(&NonlinearParameters::default()).unwrap()
+    }
+
+    #[test]
+    fn round_trips_current_schema() {
+        let results = solved();
+        let restored = ProblemResults::from_json(&results.to_json().unwrap()).unwrap();
+        assert_eq!(restored.beta, results.beta);
+        assert_eq!(restored.delta, results.delta);
+        assert_eq!(restored.conditioning.ztz, results.conditioning.ztz);
+        assert_eq!(restored.provenance, results.provenance);
+        assert_eq!(restored.provenance.draw_seed, Some(1));
+    }
+
+    #[test]
+    fn migrates_version_one_archives() {
+        let results = solved();
+        let mut payload = serde_json::to_value(&results).unwrap();
+        let object = payload.as_object_mut().unwrap();
+        for field in ["sigma", "conditioning", "profiling", "provenance"] {
+            object.remove(field);
+        }
+        let archive = serde_json::json!({
+            "schema": RESULTS_SCHEMA,
+            "version": 1,
+            "crate_version": "0.0.1",
+            "results": payload,
+        });
+
+        let restored = ProblemResults::from_json(&archive.to_string()).unwrap();
+        assert_eq!(restored.beta, results.beta);
+        assert_eq!(restored.sigma.nrows(), 0);
+        assert!(restored.conditioning.ztz.is_nan());
+
+        let future = archive
+            .to_string()
+            .replace("\"version\":1", "\"version\":99");
+        assert!(matches!(
+            ProblemResults::from_json(&future),
+            Err(BlpError::UnsupportedSchema { found: 99, .. })
+        ));
+    }
+}
@@ -0,0 +1,169 @@
+//! Parameterized synthetic problems for benchmarking, enabled by the `bench` feature.
+//!
+//! [`SyntheticSpec`] fixes the number of markets `T`, products per market `J`, draws
+//! `R`, and random coefficients `K2`; [`synthetic_problem`] simulates shares from known
+//! parameters so every stage of estimation can be timed on a realistic problem of that
+//! size. `benches/blp.rs` sweeps [`default_grid`] with criterion
+//! (`cargo bench --features bench`), and the same problems can be used to size hardware
+//! before a long run.
+
+use nalgebra::{DMatrix, DVector};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+
+use crate::data::ProductDataBuilder;
+use crate::demand::{ShareInputs, predict_shares_with};
+use crate::error::Result;
+use crate::estimation::Problem;
+use crate::integration::SimulationDraws;
+use crate::parameters::NonlinearParameters;
+
+/// Size of a synthetic problem.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SyntheticSpec {
+    /// Number of markets `T`.
+    pub markets: usize,
+    /// Products per market `J`.
+    pub products: usize,
+    /// Simulation draws `R`.
+    pub draws: usize,
+    /// Random coefficients `K2`.
+    pub k2: usize,
+    /// Seed for the characteristics, unobservables, and draws.
+    pub seed: u64,
+}
+
+impl SyntheticSpec {
+    /// `markets` markets of `products` products each, with `draws` draws and `k2`
+    /// random coefficients.
+    pub fn new(markets: usize, products: usize, draws: usize, k2: usize) -> Self {
+        Self {
+            markets,
+            products,
+            draws,
+            k2,
+            seed: 0,
+        }
+    }
+
+    /// Simulate with `seed`.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Short name such as `T100_J20_R200_K2`, for benchmark ids.
+    pub fn label(&self) -> String {
+        format!(
+            "T{}_J{}_R{}_K{}",
+            self.markets, self.products, self.draws, self.k2
+        )
+    }
+}
+
+/// The sizes swept by `benches/blp.rs`: a small baseline and one larger value of each
+/// of `J`, `T`, `R`, and `K2`.
+pub fn default_grid() -> Vec<SyntheticSpec> {
+    let base = SyntheticSpec::new(50, 10, 100, 2);
+    vec![
+        base,
+        SyntheticSpec {
+            products: 50,
+            ..base
+        },
+        SyntheticSpec {
+            markets: 500,
+            ..base
+        },
+        SyntheticSpec {
+            draws: 1_000,
+            ..base
+        },
+        SyntheticSpec { k2: 5, ..base },
+    ]
+}
+
+/// A simulated problem together with the parameters and mean utilities that generated
+/// its shares.
+#[derive(Clone, Debug)]
+pub struct SyntheticProblem {
+    /// Problem built on the simulated shares.
+    pub problem: Problem,
+    /// True nonlinear parameters: a diagonal `sigma` of 0.5.
+    pub parameters: NonlinearParameters,
+    /// True mean utilities.
+    pub delta: DVector<f64>,
+}
+
+/// Simulate a problem of size `spec`.
+///
+/// `X1` is a constant and `K2` uniform characteristics, which also make up `X2`. Mean
+/// utilities are `-2 + sum_k x_k / K2 + xi` with small uniform `xi`, and the
+/// instruments are the characteristics, their squares, and the sums of rival
+/// characteristics within each market.
+pub fn synthetic_problem(spec: &SyntheticSpec) -> Result<SyntheticProblem> {
+    let SyntheticSpec {
+        markets,
+        products,
+        draws,
+        k2,
+        seed,
+    } = *spec;
+    let n = markets * products;
+    let mut rng = SmallRng::seed_from_u64(seed);
+    let x = DMatrix::from_fn(n, k2, |_, _| rng.gen_range(-1.0..1.0));
+    let xi = DVector::from_fn(n, |_, _| rng.gen_range(-0.1..0.1));
+    let delta = DVector::from_fn(n, |j, _| -2.0 + x.row(j).sum() / k2.max(1) as f64 + xi[j]);
+    let x1 = DMatrix::from_fn(n, k2 + 1, |j, k| if k == 0 { 1.0 } else { x[(j, k - 1)] });
+    let rivals = DMatrix::from_fn(n, k2, |j, k| {
+        let start = j / products * products;
+        (start..start + products)
+            .map(|row| x[(row, k)])
+            .sum::<f64>()
+            - x[(j, k)]
+    });
+    let instruments = DMatrix::from_fn(n, 1 + 3 * k2, |j, k| match k {
+        0 => 1.0,
+        k if k <= k2 => x[(j, k - 1)],
+        k if k <= 2 * k2 => x[(j, k - 1 - k2)].powi(2),
+        k => rivals[(j, k - 1 - 2 * k2)],
+    });
+    let market_ids: Vec<String> = (0..n).map(|j| format!("m{}", j / products)).collect();
+    let build = |shares| {
+        ProductDataBuilder::new(market_ids.clone(), shares)
+            .x1(x1.clone())
+            .x2_from_x1((1..=k2).collect())
+            .instruments(instruments.clone())
+            .build()
+    };
+
+    let simulation = SimulationDraws::standard_normal(draws, k2, seed);
+    let parameters = NonlinearParameters::new(DMatrix::from_diagonal_element(k2, k2, 0.5));
+    let placeholder = build(DVector::from_element(n, 1.0 / (products + 1) as f64))?;
+    let contraction = Default::default();
+    let inputs = ShareInputs::for_parameters(&placeholder, &simulation, &parameters, &contraction);
+    let shares = predict_shares_with(&delta, &inputs)?;
+    Ok(SyntheticProblem {
+        problem: Problem::new(build(shares)?, simulation)?,
+        parameters,
+        delta,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn synthetic_shares_invert_to_the_true_delta() {
+        let spec = SyntheticSpec::new(4, 3, 20, 2).with_seed(7);
+        assert_eq!(spec.label(), "T4_J3_R20_K2");
+        let synthetic = synthetic_problem(&spec).unwrap();
+        let data = synthetic.problem.data();
+        assert_eq!(data.product_count(), 12);
+        assert_eq!(data.instrument_dim(), 7);
+        let results = synthetic.problem.solve(&synthetic.parameters).unwrap();
+        assert!((results.delta - &synthetic.delta).amax() < 1e-8);
+        assert_eq!(default_grid().len(), 5);
+    }
+}
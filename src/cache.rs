@@ -0,0 +1,443 @@
+//! Least-recently-used cache of inner-loop solutions keyed by parameters and options.
+//!
+//! Optimizers revisit points during line searches and finite differencing; with
+//! [`ProblemOptions::cache_capacity`](crate::ProblemOptions::cache_capacity) set, a
+//! repeated `sigma` reuses the stored contraction solution (and share Jacobian) instead
+//! of re-solving.
+
+use std::collections::VecDeque;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::PoisonError;
+use std::time::Instant;
+
+use nalgebra::{DMatrix, DVector};
+use serde::{Deserialize, Serialize};
+
+use crate::data::ProductData;
+use crate::demand::{ShareInputs, invert_model, logit_inversion};
+use crate::error::Result;
+use crate::estimation::{InnerSolution, Problem};
+use crate::options::ProblemOptions;
+use crate::parallel;
+use crate::parameters::NonlinearParameters;
+use crate::solving::ContractionOptions;
+
+/// Running counts of cache lookups, reported in
+/// [`ProfilingReport::cache`](crate::diagnostics::ProfilingReport::cache).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheStatistics {
+    /// Lookups answered from the cache.
+    pub hits: u64,
+    /// Lookups that had to solve the inner loop.
+    pub misses: u64,
+    /// Entries dropped to stay within capacity.
+    pub evictions: u64,
+}
+
+impl CacheStatistics {
+    /// Fraction of lookups answered from the cache.
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            0.0
+        } else {
+            self.hits as f64 / lookups as f64
+        }
+    }
+}
+
+/// Inner-loop output stored for one `(sigma, options)` key.
+#[derive(Clone, Debug)]
+pub(crate) struct CachedInner {
+    pub(crate) inner: InnerSolution,
+    /// Seconds the contraction took when it was solved.
+    pub(crate) seconds: f64,
+    /// `d delta / d vec(sigma)`, filled in the first time a caller asks for it.
+    pub(crate) delta_jacobian: Option<DMatrix<f64>>,
+}
+
+/// Most recently used entries first.
+#[derive(Debug, Default)]
+pub(crate) struct InnerCache {
+    entries: VecDeque<(u64, CachedInner)>,
+    /// Starting `delta`s carried over from a problem on fewer markets.
+    warm_starts: Vec<(u64, NonlinearParameters, DVector<f64>)>,
+    statistics: CacheStatistics,
+}
+
+impl InnerCache {
+    /// Hash of everything the inner-loop solution depends on besides the data and draws.
+    pub(crate) fn key(
+        model: &str,
+        parameters: &NonlinearParameters,
+        options: &ContractionOptions,
+    ) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        model.hash(&mut hasher);
+        for matrix in std::iter::once(parameters.sigma()).chain(parameters.pi()) {
+            matrix.shape().hash(&mut hasher);
+            for value in matrix.iter() {
+                value.to_bits().hash(&mut hasher);
+            }
+        }
+        parameters.rho().map(f64::to_bits).hash(&mut hasher);
+        options.tolerance.to_bits().hash(&mut hasher);
+        options.max_iterations.hash(&mut hasher);
+        options.damping.to_bits().hash(&mut hasher);
+        options.minimum_share.to_bits().hash(&mut hasher);
+        options.tolerance_scaling.hash(&mut hasher);
+        options.share_tolerance.map(f64::to_bits).hash(&mut hasher);
+        options.cpu_budget.map(f64::to_bits).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Look up `key`, guarding against hash collisions by comparing the parameters exactly.
+    pub(crate) fn get(
+        &mut self,
+        key: u64,
+        parameters: &NonlinearParameters,
+    ) -> Option<CachedInner> {
+        let position = self
+            .entries
+            .iter()
+            .position(|(stored, entry)| *stored == key && entry.inner.parameters == *parameters);
+        match position {
+            Some(position) => {
+                self.statistics.hits += 1;
+                let entry = self.entries.remove(position)?;
+                let value = entry.1.clone();
+                self.entries.push_front(entry);
+                Some(value)
+            }
+            None => {
+                self.statistics.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Store `value` as the most recent entry, evicting the oldest beyond `capacity`.
+    pub(crate) fn insert(&mut self, key: u64, value: CachedInner, capacity: usize) {
+        self.entries.retain(|(stored, entry)| {
+            !(*stored == key && entry.inner.parameters == value.inner.parameters)
+        });
+        self.entries.push_front((key, value));
+        while self.entries.len() > capacity {
+            self.entries.pop_back();
+            self.statistics.evictions += 1;
+        }
+    }
+
+    pub(crate) fn statistics(&self) -> CacheStatistics {
+        self.statistics
+    }
+
+    /// A cache for the same products followed by `appended`: every stored solution
+    /// becomes a warm start whose new markets begin at the plain-logit inversion.
+    pub(crate) fn extended(&self, appended: &ProductData) -> Self {
+        let tail = logit_inversion(appended);
+        let extend = |delta: &DVector<f64>| -> DVector<f64> {
+            delta
+                .iter()
+                .chain(tail.iter())
+                .copied()
+                .collect::<Vec<_>>()
+                .into()
+        };
+        let warm_starts = self
+            .entries
+            .iter()
+            .map(|(key, entry)| {
+                (
+                    *key,
+                    entry.inner.parameters.clone(),
+                    extend(&entry.inner.delta),
+                )
+            })
+            .chain(
+                self.warm_starts
+                    .iter()
+                    .map(|(key, parameters, delta)| (*key, parameters.clone(), extend(delta))),
+            )
+            .collect();
+        Self {
+            warm_starts,
+            ..Self::default()
+        }
+    }
+
+    fn warm_start(&self, key: u64, parameters: &NonlinearParameters) -> Option<DVector<f64>> {
+        self.warm_starts
+            .iter()
+            .find(|(stored, stored_parameters, _)| {
+                *stored == key && stored_parameters == parameters
+            })
+            .map(|(_, _, delta)| delta.clone())
+    }
+}
+
+impl Problem {
+    /// Lookup counts for this problem's inner-loop cache since it was constructed.
+    pub fn cache_statistics(&self) -> CacheStatistics {
+        self.inner_cache()
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .statistics()
+    }
+
+    /// Solve the inner loop at `parameters`, or reuse a cached solution when
+    /// `options.cache_capacity` is nonzero. With `with_jacobian`, the returned entry also
+    /// carries `d delta / d vec(sigma)`. The flag reports whether the contraction was
+    /// reused.
+    pub(crate) fn cached_inner(
+        &self,
+        parameters: &NonlinearParameters,
+        options: &ProblemOptions,
+        initial_delta: Option<&DVector<f64>>,
+        with_jacobian: bool,
+    ) -> Result<(CachedInner, bool)> {
+        let mut inputs = ShareInputs::for_parameters(
+            self.data(),
+            self.draws(),
+            parameters,
+            &options.contraction,
+        );
+        // Nothing identifies what an external solver converges to, so its solutions are
+        // never stored or reused.
+        let capacity = if options.contraction.solver.is_some() {
+            0
+        } else {
+            options.cache_capacity
+        };
+        let key = InnerCache::key(self.model().name(), parameters, &options.contraction);
+        let warm_start = match initial_delta {
+            None if capacity > 0 => self
+                .inner_cache()
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .warm_start(key, parameters),
+            _ => None,
+        };
+        if let Some(delta) = initial_delta.or(warm_start.as_ref()) {
+            inputs = inputs.with_initial_delta(delta);
+        }
+        let jacobian = |delta: &DVector<f64>| -> Result<DMatrix<f64>> {
+            parallel::install(&options.parallelism, || self.delta_jacobian(delta, &inputs))
+        };
+
+        if capacity > 0 {
+            let found = self
+                .inner_cache()
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get(key, parameters);
+            if let Some(mut entry) = found {
+                if with_jacobian && entry.delta_jacobian.is_none() {
+                    entry.delta_jacobian = Some(jacobian(&entry.inner.delta)?);
+                    self.inner_cache()
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .insert(key, entry.clone(), capacity);
+                }
+                return Ok((entry, true));
+            }
+        }
+
+        let started = Instant::now();
+        let (delta, contraction, predicted_shares) =
+            parallel::install(&options.parallelism, || {
+                let (delta, contraction) = invert_model(self.model(), &inputs)?;
+                let predicted_shares = self.model().shares(&delta, &inputs)?;
+                Ok((delta, contraction, predicted_shares))
+            })?;
+        let seconds = started.elapsed().as_secs_f64();
+        let delta_jacobian = if with_jacobian {
+            Some(jacobian(&delta)?)
+        } else {
+            None
+        };
+        let entry = CachedInner {
+            inner: InnerSolution {
+                parameters: parameters.clone(),
+                delta,
+                predicted_shares,
+                contraction,
+            },
+            seconds,
+            delta_jacobian,
+        };
+        // A solution cut short by the CPU budget depends on how fast this run happened to
+        // be, so it is not reused.
+        if capacity > 0 && !entry.inner.contraction.is_truncated() {
+            self.inner_cache()
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(key, entry.clone(), capacity);
+        }
+        Ok((entry, false))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::ProductDataBuilder;
+    use crate::error::BlpError;
+    use crate::integration::SimulationDraws;
+    use crate::parameters::NonlinearParameters;
+    use crate::solving::{
+        ContractionSummary, ConvergenceCriterion, FixedPointOperator, FixedPointSolver,
+    };
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn revisited_sigma_reuses_the_least_recently_used_entries() {
+        let market_ids = ["m1", "m1", "m2", "m2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.25, 0.15]);
+        let x1 = DMatrix::from_row_slice(4, 2, &[1.0, 1.0, 1.0, 2.0, 1.0, 1.5, 1.0, 0.5]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1.clone())
+            .x2(x1.columns(1, 1).into_owned())
+            .build()
+            .unwrap();
+        let options = ProblemOptions::default().with_cache_capacity(1);
+        let draws = SimulationDraws::standard_normal(30, 1, 3);
+        let problem = Problem::with_options(data, draws, options).unwrap();
+        let first = NonlinearParameters::new(DMatrix::from_element(1, 1, 0.5));
+        let second = NonlinearParameters::new(DMatrix::from_element(1, 1, 0.9));
+
+        let solved = problem.solve(&first).unwrap();
+        let reused = problem.solve(&first).unwrap();
+        assert_eq!(reused.delta, solved.delta);
+        assert_eq!(reused.profiling.cache.hits, 1);
+        problem.solve(&second).unwrap();
+        let evicted = problem.solve(&first).unwrap();
+        assert_eq!(
+            evicted.profiling.cache,
+            CacheStatistics {
+                hits: 1,
+                misses: 3,
+                evictions: 2,
+            }
+        );
+        assert!(problem.cache_statistics().hit_rate() > 0.0);
+
+        // A contraction truncated by the CPU budget is solved afresh every time.
+        let mut options = problem.options().clone();
+        options.contraction = options.contraction.with_cpu_budget(0.0);
+        let budgeted =
+            Problem::with_options(problem.data().clone(), problem.draws().clone(), options)
+                .unwrap();
+        let truncated = budgeted.solve(&second).unwrap();
+        assert!(truncated.contraction.is_truncated());
+        let repeated = budgeted.solve(&second).unwrap();
+        assert_eq!(repeated.profiling.cache.hits, 0);
+        assert_ne!(
+            InnerCache::key("logit", &second, &budgeted.options().contraction),
+            InnerCache::key("logit", &second, &problem.options().contraction)
+        );
+    }
+
+    /// Plain iteration that counts how often it is asked to solve.
+    #[derive(Debug, Default)]
+    struct Counting(AtomicUsize);
+
+    impl FixedPointSolver for Counting {
+        fn solve(
+            &self,
+            operator: &mut dyn FixedPointOperator,
+            mut x: DVector<f64>,
+            options: &ContractionOptions,
+        ) -> Result<(DVector<f64>, ContractionSummary)> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            for iterations in 1..=options.max_iterations {
+                let next = operator.apply(&x)?;
+                let max_gap = (&next - &x).amax();
+                x = next;
+                if max_gap < options.tolerance {
+                    return Ok((
+                        x,
+                        ContractionSummary {
+                            iterations,
+                            max_gap,
+                            criterion: Some(ConvergenceCriterion::DeltaUpdate),
+                            ..Default::default()
+                        },
+                    ));
+                }
+            }
+            Err(BlpError::NumericalError {
+                context: "test solver",
+            })
+        }
+    }
+
+    #[test]
+    fn external_solvers_bypass_the_cache() {
+        let market_ids = ["m1", "m1", "m2"].map(String::from).to_vec();
+        let x1 = DMatrix::from_row_slice(3, 2, &[1.0, 1.0, 1.0, 2.0, 1.0, 0.5]);
+        let data = ProductDataBuilder::new(market_ids, DVector::from_vec(vec![0.2, 0.3, 0.4]))
+            .x1(x1.clone())
+            .x2(x1.columns(1, 1).into_owned())
+            .build()
+            .unwrap();
+        let solver = Arc::new(Counting::default());
+        let mut options = ProblemOptions::default().with_cache_capacity(4);
+        options.contraction.solver = Some(solver.clone());
+        let draws = SimulationDraws::standard_normal(30, 1, 3);
+        let problem = Problem::with_options(data, draws, options).unwrap();
+        let parameters = NonlinearParameters::new(DMatrix::from_element(1, 1, 0.5));
+
+        problem.solve(&parameters).unwrap();
+        let repeated = problem.solve(&parameters).unwrap();
+        assert_eq!(repeated.profiling.cache.hits, 0);
+        assert_eq!(solver.0.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn appended_markets_warm_start_from_cached_solutions() {
+        let build = |markets: [&str; 2], shares: [f64; 4], prices: [f64; 4]| {
+            let market_ids = [markets[0], markets[0], markets[1], markets[1]];
+            let x1 = DMatrix::from_fn(4, 2, |j, k| if k == 0 { 1.0 } else { prices[j] });
+            ProductDataBuilder::new(
+                market_ids.map(String::from).to_vec(),
+                DVector::from_row_slice(&shares),
+            )
+            .x1(x1.clone())
+            .x2(x1.columns(1, 1).into_owned())
+            .build()
+            .unwrap()
+        };
+        let first = build(["w1", "w2"], [0.2, 0.3, 0.25, 0.15], [1.0, 2.0, 1.5, 0.5]);
+        let second = build(["w3", "w4"], [0.1, 0.4, 0.3, 0.2], [2.5, 1.0, 0.8, 1.2]);
+        let options = ProblemOptions::default().with_cache_capacity(2);
+        let draws = SimulationDraws::standard_normal(30, 1, 3);
+        let problem = Problem::with_options(first.clone(), draws.clone(), options).unwrap();
+        let parameters = NonlinearParameters::new(DMatrix::from_element(1, 1, 0.5));
+        problem.solve(&parameters).unwrap();
+
+        let extended = problem.extend_data(&second).unwrap();
+        assert_eq!(extended.data().partition().market_count(), 4);
+        assert_eq!(extended.data().partition().market_of(5), 2);
+        let warm = extended.solve(&parameters).unwrap();
+        let rebuilt = first
+            .append_markets(&second)
+            .unwrap()
+            .to_builder()
+            .build()
+            .unwrap();
+        let cold = Problem::new(rebuilt, draws)
+            .unwrap()
+            .solve(&parameters)
+            .unwrap();
+        assert!((&warm.delta - &cold.delta).amax() < 1e-8);
+        assert!((&warm.beta - &cold.beta).amax() < 1e-6);
+        assert!(warm.contraction.iterations < cold.contraction.iterations);
+
+        let err = extended.extend_data(&second).unwrap_err();
+        assert!(
+            matches!(err, BlpError::InconsistentSpecification { mismatches } if mismatches.len() == 2)
+        );
+    }
+}
@@ -0,0 +1,636 @@
+//! C-compatible interface for calling the estimator from MATLAB, Julia, Stata, or C.
+//!
+//! Enabled by the `capi` feature. `cargo build --release --features capi` produces the
+//! shared (`cdylib`) and static libraries; `include/blprs.h` declares everything below,
+//! and `bindings/julia/Blprs.jl` wraps it for Julia.
+//!
+//! Problems and results are opaque handles created by [`blp_problem_new`],
+//! [`blp_problem_solve`], and [`blp_problem_optimize`] and released with the matching
+//! `_free` function. Matrices are flat column-major `double` arrays (the native layout of
+//! MATLAB, Julia, and Fortran) passed with their dimensions, and market ids are 64-bit
+//! integers. Every function returns a [`BlpStatus`]; on failure, [`blp_last_error`]
+//! describes the error on the calling thread. Panics are caught at the boundary and
+//! reported as [`BlpStatus::Panic`].
+
+use std::cell::RefCell;
+use std::ffi::{CString, c_char, c_int};
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::ptr;
+
+use nalgebra::{DMatrix, DVector};
+
+use crate::data::ProductDataBuilder;
+use crate::error::BlpError;
+use crate::estimation::{Problem, ProblemResults};
+use crate::integration::SimulationDraws;
+use crate::parameters::NonlinearParameters;
+
+/// Outcome of a C API call.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlpStatus {
+    /// The call succeeded.
+    Ok = 0,
+    /// A required pointer was null.
+    NullPointer = 1,
+    /// The inputs were inconsistent: wrong dimensions, non-positive shares, or an
+    /// inconsistent specification.
+    InvalidInput = 2,
+    /// A matrix could not be factorized.
+    Singular = 3,
+    /// The contraction did not converge.
+    NotConverged = 4,
+    /// A numerical routine produced a non-finite value.
+    Numerical = 5,
+    /// Any other estimation error.
+    Other = 6,
+    /// The library panicked; the handles passed in should not be used again.
+    Panic = 7,
+}
+
+impl From<&BlpError> for BlpStatus {
+    fn from(error: &BlpError) -> Self {
+        match error {
+            BlpError::DimensionMismatch { .. }
+            | BlpError::NonContiguousMarket { .. }
+            | BlpError::DuplicateProduct { .. }
+            | BlpError::MissingValue { .. }
+            | BlpError::NonPositiveShare { .. }
+            | BlpError::NonPositiveOutsideShare { .. }
+            | BlpError::InvalidWeights { .. }
+            | BlpError::InvalidNestingParameter { .. }
+            | BlpError::InvalidBounds { .. }
+            | BlpError::InconsistentSpecification { .. }
+            | BlpError::Underidentified { .. }
+            | BlpError::MissingComponent { .. } => Self::InvalidInput,
+            BlpError::SingularMatrix { .. } => Self::Singular,
+            BlpError::ContractionDidNotConverge { .. }
+            | BlpError::ContinuouslyUpdatedDidNotConverge { .. } => Self::NotConverged,
+            BlpError::NumericalError { .. } => Self::Numerical,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// Vector held by a results handle, for [`blp_results_length`] and [`blp_results_copy`].
+///
+/// Those functions take the code as a plain `int`, since a foreign caller can pass any
+/// value; codes outside the enum are rejected with [`BlpStatus::InvalidInput`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlpVector {
+    /// Linear parameters `beta` (`K1`).
+    Beta = 0,
+    /// Random coefficient scale `sigma`, column-major (`K2 * K2`).
+    Sigma = 1,
+    /// Mean utilities `delta` (`N`).
+    Delta = 2,
+    /// Structural errors `xi` (`N`).
+    Xi = 3,
+    /// Predicted shares (`N`).
+    Shares = 4,
+}
+
+impl TryFrom<c_int> for BlpVector {
+    /// The code that names no vector.
+    type Error = c_int;
+
+    fn try_from(code: c_int) -> Result<Self, c_int> {
+        match code {
+            0 => Ok(Self::Beta),
+            1 => Ok(Self::Sigma),
+            2 => Ok(Self::Delta),
+            3 => Ok(Self::Xi),
+            4 => Ok(Self::Shares),
+            _ => Err(code),
+        }
+    }
+}
+
+/// Opaque handle to a [`Problem`].
+pub struct BlpProblemHandle(Problem);
+
+/// Opaque handle to [`ProblemResults`].
+pub struct BlpResultsHandle(ProblemResults);
+
+/// Why a call failed before reaching the estimator, or the estimator's error.
+enum Failure {
+    Null(&'static str),
+    Code(&'static str, c_int),
+    Blp(BlpError),
+}
+
+impl From<BlpError> for Failure {
+    fn from(error: BlpError) -> Self {
+        Self::Blp(error)
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn record(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+}
+
+/// Run `body`, converting its failure or panic into a status and recording the message.
+fn call(body: impl FnOnce() -> Result<(), Failure>) -> BlpStatus {
+    match catch_unwind(AssertUnwindSafe(body)) {
+        Ok(Ok(())) => BlpStatus::Ok,
+        Ok(Err(Failure::Null(name))) => {
+            record(format!("`{name}` is a null pointer"));
+            BlpStatus::NullPointer
+        }
+        Ok(Err(Failure::Code(name, code))) => {
+            record(format!("`{name}` is not a valid code: {code}"));
+            BlpStatus::InvalidInput
+        }
+        Ok(Err(Failure::Blp(error))) => {
+            record(error.to_string());
+            BlpStatus::from(&error)
+        }
+        Err(_) => {
+            record("blprs panicked".to_string());
+            BlpStatus::Panic
+        }
+    }
+}
+
+/// View `len` values at `pointer`; null is accepted only when `len` is zero.
+///
+/// # Safety
+///
+/// A non-null `pointer` must be valid for reads of `len` values.
+unsafe fn values<'a, T>(
+    pointer: *const T,
+    len: usize,
+    name: &'static str,
+) -> Result<&'a [T], Failure> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    if pointer.is_null() {
+        return Err(Failure::Null(name));
+    }
+    // SAFETY: non-null and valid for `len` reads by the caller's contract.
+    Ok(unsafe { std::slice::from_raw_parts(pointer, len) })
+}
+
+/// # Safety
+///
+/// A non-null `pointer` must be valid for reads of `rows * columns` values.
+unsafe fn matrix(
+    pointer: *const f64,
+    rows: usize,
+    columns: usize,
+    name: &'static str,
+) -> Result<DMatrix<f64>, Failure> {
+    // SAFETY: forwarded from the caller.
+    let values = unsafe { values(pointer, rows * columns, name) }?;
+    Ok(DMatrix::from_column_slice(rows, columns, values))
+}
+
+/// # Safety
+///
+/// A non-null `handle` must point to a live handle.
+unsafe fn handle<'a, T>(handle: *const T, name: &'static str) -> Result<&'a T, Failure> {
+    // SAFETY: forwarded from the caller.
+    unsafe { handle.as_ref() }.ok_or(Failure::Null(name))
+}
+
+/// Build a problem from `products` rows of data and `draw_count` simulation draws.
+///
+/// `x1` is `products x k1`, `x2` is `products x k2`, `instruments` is `products x kz`
+/// (with `kz = 0` using `X1` as its own instruments), and `draws` is
+/// `draw_count x k2`, all column-major. `weights` may be null for equal weights. Rows
+/// of the same market must be contiguous. On success `*out` receives a handle to free
+/// with [`blp_problem_free`].
+///
+/// # Safety
+///
+/// Every non-null pointer must be valid for the number of values its dimensions imply,
+/// and `out` must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn blp_problem_new(
+    products: usize,
+    market_ids: *const i64,
+    shares: *const f64,
+    x1: *const f64,
+    k1: usize,
+    x2: *const f64,
+    k2: usize,
+    instruments: *const f64,
+    kz: usize,
+    draws: *const f64,
+    draw_count: usize,
+    weights: *const f64,
+    out: *mut *mut BlpProblemHandle,
+) -> BlpStatus {
+    call(|| {
+        if out.is_null() {
+            return Err(Failure::Null("out"));
+        }
+        // SAFETY: the caller guarantees the pointers match the dimensions.
+        let (market_ids, shares, x1, x2, instruments, nodes) = unsafe {
+            (
+                values(market_ids, products, "market_ids")?,
+                values(shares, products, "shares")?,
+                matrix(x1, products, k1, "x1")?,
+                matrix(x2, products, k2, "x2")?,
+                matrix(instruments, products, kz, "instruments")?,
+                matrix(draws, draw_count, k2, "draws")?,
+            )
+        };
+        let weights = if weights.is_null() {
+            DVector::from_element(draw_count, 1.0 / draw_count.max(1) as f64)
+        } else {
+            // SAFETY: as above.
+            DVector::from_column_slice(unsafe { values(weights, draw_count, "weights")? })
+        };
+        let mut builder = ProductDataBuilder::new(
+            market_ids.iter().map(|id| id.to_string()).collect(),
+            DVector::from_column_slice(shares),
+        )
+        .x1(x1)
+        .x2(x2);
+        if kz > 0 {
+            builder = builder.instruments(instruments);
+        }
+        let problem = Problem::new(builder.build()?, SimulationDraws::new(nodes, weights)?)?;
+        // SAFETY: `out` is non-null and valid for writes.
+        unsafe { *out = Box::into_raw(Box::new(BlpProblemHandle(problem))) };
+        Ok(())
+    })
+}
+
+/// Release a problem handle; null is ignored.
+///
+/// # Safety
+///
+/// `problem` must come from [`blp_problem_new`] and not have been freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn blp_problem_free(problem: *mut BlpProblemHandle) {
+    if !problem.is_null() {
+        // SAFETY: created by `Box::into_raw` in `blp_problem_new`.
+        drop(unsafe { Box::from_raw(problem) });
+    }
+}
+
+/// Solve the problem at the `k2 x k2` column-major `sigma`. On success `*out` receives a
+/// handle to free with [`blp_results_free`].
+///
+/// # Safety
+///
+/// `problem` must be a live handle, `sigma` valid for `k2 * k2` reads, and `out` valid
+/// for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn blp_problem_solve(
+    problem: *const BlpProblemHandle,
+    sigma: *const f64,
+    out: *mut *mut BlpResultsHandle,
+) -> BlpStatus {
+    call(|| {
+        // SAFETY: the caller guarantees a live handle and matching `sigma`.
+        let problem = &unsafe { handle(problem, "problem") }?.0;
+        let k2 = problem.data().nonlinear_dim();
+        let sigma = unsafe { matrix(sigma, k2, k2, "sigma") }?;
+        let results = problem.solve(&NonlinearParameters::new(sigma))?;
+        write_results(out, results)
+    })
+}
+
+/// Minimize the GMM objective starting from the `k2 x k2` column-major `initial_sigma`,
+/// whose zero entries are held fixed. `converged` may be null. On success `*out`
+/// receives a handle to free with [`blp_results_free`].
+///
+/// # Safety
+///
+/// `problem` must be a live handle, `initial_sigma` valid for `k2 * k2` reads, `out`
+/// valid for writes, and a non-null `converged` valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn blp_problem_optimize(
+    problem: *const BlpProblemHandle,
+    initial_sigma: *const f64,
+    converged: *mut bool,
+    out: *mut *mut BlpResultsHandle,
+) -> BlpStatus {
+    call(|| {
+        // SAFETY: the caller guarantees a live handle and matching `initial_sigma`.
+        let problem = &unsafe { handle(problem, "problem") }?.0;
+        let k2 = problem.data().nonlinear_dim();
+        let sigma = unsafe { matrix(initial_sigma, k2, k2, "initial_sigma") }?;
+        let mut options = problem.options().clone();
+        options.optimization.initial_sigma = Some(sigma);
+        let optimized = problem.with_options_override(options).optimize()?;
+        if !converged.is_null() {
+            // SAFETY: non-null and valid for writes.
+            unsafe { *converged = optimized.converged };
+        }
+        write_results(out, optimized.results)
+    })
+}
+
+fn write_results(out: *mut *mut BlpResultsHandle, results: ProblemResults) -> Result<(), Failure> {
+    if out.is_null() {
+        return Err(Failure::Null("out"));
+    }
+    // SAFETY: non-null, and valid for writes by the calling function's contract.
+    unsafe { *out = Box::into_raw(Box::new(BlpResultsHandle(results))) };
+    Ok(())
+}
+
+/// Release a results handle; null is ignored.
+///
+/// # Safety
+///
+/// `results` must come from [`blp_problem_solve`] or [`blp_problem_optimize`] and not
+/// have been freed.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn blp_results_free(results: *mut BlpResultsHandle) {
+    if !results.is_null() {
+        // SAFETY: created by `Box::into_raw` in `write_results`.
+        drop(unsafe { Box::from_raw(results) });
+    }
+}
+
+/// The vector a [`BlpVector`] code names, or the failure for an unknown code.
+fn vector(results: &ProblemResults, which: c_int) -> Result<&[f64], Failure> {
+    let which = BlpVector::try_from(which).map_err(|code| Failure::Code("which", code))?;
+    Ok(match which {
+        BlpVector::Beta => results.beta.as_slice(),
+        BlpVector::Sigma => results.sigma.as_slice(),
+        BlpVector::Delta => results.delta.as_slice(),
+        BlpVector::Xi => results.xi.as_slice(),
+        BlpVector::Shares => results.predicted_shares.as_slice(),
+    })
+}
+
+/// Write the number of values in the [`BlpVector`] coded `which` to `*len`.
+///
+/// # Safety
+///
+/// `results` must be a live handle and `len` valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn blp_results_length(
+    results: *const BlpResultsHandle,
+    which: c_int,
+    len: *mut usize,
+) -> BlpStatus {
+    call(|| {
+        // SAFETY: the caller guarantees a live handle.
+        let results = &unsafe { handle(results, "results") }?.0;
+        let values = vector(results, which)?;
+        if len.is_null() {
+            return Err(Failure::Null("len"));
+        }
+        // SAFETY: non-null and valid for writes.
+        unsafe { *len = values.len() };
+        Ok(())
+    })
+}
+
+/// Copy the [`BlpVector`] coded `which` into the `len` values at `out`; `len` must equal
+/// its length.
+///
+/// # Safety
+///
+/// `results` must be a live handle and `out` valid for `len` writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn blp_results_copy(
+    results: *const BlpResultsHandle,
+    which: c_int,
+    out: *mut f64,
+    len: usize,
+) -> BlpStatus {
+    call(|| {
+        // SAFETY: the caller guarantees a live handle.
+        let results = &unsafe { handle(results, "results") }?.0;
+        let values = vector(results, which)?;
+        if len != values.len() {
+            return Err(BlpError::dimension_mismatch("output length", values.len(), len).into());
+        }
+        if out.is_null() && len > 0 {
+            return Err(Failure::Null("out"));
+        }
+        // SAFETY: `out` is valid for `len` writes and cannot overlap the results.
+        unsafe { ptr::copy_nonoverlapping(values.as_ptr(), out, len) };
+        Ok(())
+    })
+}
+
+/// Write the GMM objective to `*value`.
+///
+/// # Safety
+///
+/// `results` must be a live handle and `value` valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn blp_results_objective(
+    results: *const BlpResultsHandle,
+    value: *mut f64,
+) -> BlpStatus {
+    call(|| {
+        // SAFETY: the caller guarantees a live handle.
+        let results = &unsafe { handle(results, "results") }?.0;
+        if value.is_null() {
+            return Err(Failure::Null("value"));
+        }
+        // SAFETY: non-null and valid for writes.
+        unsafe { *value = results.gmm_value };
+        Ok(())
+    })
+}
+
+/// Message describing the last failed call on this thread, as a NUL-terminated string
+/// owned by the library and valid until the next failing call on the same thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn blp_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+
+    #[test]
+    fn problems_round_trip_through_handles() {
+        let market_ids = [1_i64, 1, 2, 2];
+        let shares = [0.2, 0.3, 0.25, 0.15];
+        let x1 = [1.0, 1.0, 1.0, 1.0, 1.0, 2.0, 1.5, 0.5];
+        let x2 = [1.0, 2.0, 1.5, 0.5];
+        let draws = [-1.0, 0.0, 1.0];
+        let sigma = [0.5];
+        let mut problem = ptr::null_mut();
+        let mut results = ptr::null_mut();
+        let mut len = 0;
+        let mut objective = f64::NAN;
+        unsafe {
+            let status = blp_problem_new(
+                4,
+                market_ids.as_ptr(),
+                shares.as_ptr(),
+                x1.as_ptr(),
+                2,
+                x2.as_ptr(),
+                1,
+                ptr::null(),
+                0,
+                draws.as_ptr(),
+                3,
+                ptr::null(),
+                &mut problem,
+            );
+            assert_eq!(status, BlpStatus::Ok);
+            assert_eq!(
+                blp_problem_solve(problem, sigma.as_ptr(), &mut results),
+                BlpStatus::Ok
+            );
+            assert_eq!(
+                blp_results_length(results, BlpVector::Delta as c_int, &mut len),
+                BlpStatus::Ok
+            );
+            let mut delta = vec![0.0; len];
+            assert_eq!(
+                blp_results_copy(results, BlpVector::Delta as c_int, delta.as_mut_ptr(), len),
+                BlpStatus::Ok
+            );
+            assert_eq!(
+                blp_results_objective(results, &mut objective),
+                BlpStatus::Ok
+            );
+            let expected = (*problem)
+                .0
+                .solve(&DMatrix::from_element(1, 1, 0.5).into())
+                .unwrap();
+            assert_eq!(delta, expected.delta.as_slice());
+            assert_eq!(objective, expected.gmm_value);
+
+            let mut beta = [0.0; 3];
+            let status = blp_results_copy(results, BlpVector::Beta as c_int, beta.as_mut_ptr(), 3);
+            assert_eq!(status, BlpStatus::InvalidInput);
+            assert!(
+                CStr::from_ptr(blp_last_error())
+                    .to_str()
+                    .unwrap()
+                    .contains("output length")
+            );
+            // Codes outside `BlpVector` are rejected rather than read as an enum.
+            for code in [-1, 5, c_int::MAX] {
+                assert_eq!(
+                    blp_results_length(results, code, &mut len),
+                    BlpStatus::InvalidInput
+                );
+                let status = blp_results_copy(results, code, beta.as_mut_ptr(), 3);
+                assert_eq!(status, BlpStatus::InvalidInput);
+            }
+            assert!(
+                CStr::from_ptr(blp_last_error())
+                    .to_str()
+                    .unwrap()
+                    .contains("`which`")
+            );
+            assert_eq!(
+                blp_problem_solve(problem, ptr::null(), &mut results),
+                BlpStatus::NullPointer
+            );
+            blp_results_free(results);
+            blp_problem_free(problem);
+        }
+    }
+
+    #[test]
+    fn julia_bindings_mirror_the_status_and_vector_codes() {
+        let bindings = include_str!("../bindings/julia/Blprs.jl");
+        let statuses = [
+            ("OK", BlpStatus::Ok),
+            ("NULL_POINTER", BlpStatus::NullPointer),
+            ("INVALID_INPUT", BlpStatus::InvalidInput),
+            ("SINGULAR", BlpStatus::Singular),
+            ("NOT_CONVERGED", BlpStatus::NotConverged),
+            ("NUMERICAL", BlpStatus::Numerical),
+            ("OTHER", BlpStatus::Other),
+            ("PANIC", BlpStatus::Panic),
+        ];
+        for (name, status) in statuses {
+            let line = format!("    {name} = {}\n", status as i32);
+            assert!(bindings.contains(&line), "missing `{}`", line.trim());
+        }
+        let order = [
+            BlpVector::Beta,
+            BlpVector::Sigma,
+            BlpVector::Delta,
+            BlpVector::Xi,
+            BlpVector::Shares,
+        ];
+        assert!(bindings.contains("const BETA, SIGMA, DELTA, XI, SHARES = Cint.(0:4)"));
+        for (code, which) in order.into_iter().enumerate() {
+            assert_eq!(which as usize, code);
+            assert_eq!(BlpVector::try_from(code as c_int), Ok(which));
+        }
+    }
+
+    #[test]
+    fn julia_optimize_call_sequence_returns_every_vector() {
+        // `market_codes` numbers string ids from zero; `dense` passes column-major arrays.
+        let market_ids = [0_i64, 0, 0, 1, 1, 1];
+        let shares = [0.2, 0.3, 0.1, 0.25, 0.15, 0.2];
+        let x = [1.0, 2.0, 1.5, 0.5, 1.2, 0.8];
+        let w = [0.3, -0.2, 0.5, 0.1, -0.4, 0.2];
+        let x1: Vec<f64> = [[1.0; 6], x].concat();
+        let z: Vec<f64> = [[1.0; 6], x, w, x.map(|v| v * v)].concat();
+        let draws = [-1.0, -0.3, 0.4, 1.1];
+        let mut problem = ptr::null_mut();
+        let mut results = ptr::null_mut();
+        let mut converged = false;
+        unsafe {
+            let status = blp_problem_new(
+                6,
+                market_ids.as_ptr(),
+                shares.as_ptr(),
+                x1.as_ptr(),
+                2,
+                x.as_ptr(),
+                1,
+                z.as_ptr(),
+                4,
+                draws.as_ptr(),
+                4,
+                ptr::null(),
+                &mut problem,
+            );
+            assert_eq!(status, BlpStatus::Ok);
+            let status =
+                blp_problem_optimize(problem, [1.0].as_ptr(), &mut converged, &mut results);
+            assert_eq!(status, BlpStatus::Ok);
+
+            let expected = &(*results).0;
+            let lengths = [
+                (BlpVector::Beta, 2),
+                (BlpVector::Sigma, 1),
+                (BlpVector::Delta, 6),
+                (BlpVector::Xi, 6),
+                (BlpVector::Shares, 6),
+            ];
+            for (which, length) in lengths {
+                let which = which as c_int;
+                let mut len = 0;
+                assert_eq!(blp_results_length(results, which, &mut len), BlpStatus::Ok);
+                assert_eq!(len, length);
+                let mut values = vec![f64::NAN; len];
+                let status = blp_results_copy(results, which, values.as_mut_ptr(), len);
+                assert_eq!(status, BlpStatus::Ok);
+                assert!(vector(expected, which).is_ok_and(|expected| values == expected));
+            }
+            let mut objective = f64::NAN;
+            assert_eq!(
+                blp_results_objective(results, &mut objective),
+                BlpStatus::Ok
+            );
+            assert_eq!(objective, expected.gmm_value);
+            blp_results_free(results);
+            blp_problem_free(problem);
+        }
+    }
+}
@@ -0,0 +1,217 @@
+//! Counterfactual predictions from a solved problem without re-estimation.
+
+use nalgebra::DVector;
+
+use crate::data::DataMatrix;
+use crate::demand::{ShareInputs, inclusive_values};
+use crate::error::{BlpError, Result};
+use crate::estimation::{Problem, ProblemResults};
+use crate::integration::SimulationDraws;
+
+/// Shares and welfare under an alternative consumer population.
+#[derive(Clone, Debug)]
+pub struct PopulationCounterfactual {
+    /// Predicted product shares holding `delta` and `sigma` at their estimated values.
+    pub shares: DVector<f64>,
+    /// Expected inclusive value of each market, in utils (see [`inclusive_values`]).
+    pub inclusive_values: DVector<f64>,
+    /// Change in the expected inclusive value relative to the estimation population.
+    pub inclusive_value_changes: DVector<f64>,
+    /// Effective sample size of the counterfactual integration weights.
+    pub effective_draws: f64,
+}
+
+/// Mean utilities split into the part explained by `X1` and the structural error.
+#[derive(Clone, Debug)]
+pub struct DeltaDecomposition {
+    /// `X1 * beta`.
+    pub linear: DVector<f64>,
+    /// Unobserved product quality `xi = delta - X1 * beta`.
+    pub xi: DVector<f64>,
+}
+
+/// New values for one characteristic, e.g. a vector of counterfactual prices.
+#[derive(Clone, Debug)]
+pub struct CharacteristicChange {
+    /// Matrix the characteristic appears in; must be `X1` or `X2`.
+    pub matrix: DataMatrix,
+    /// Column within that matrix.
+    pub column: usize,
+    /// Replacement values, one per product.
+    pub values: DVector<f64>,
+}
+
+impl CharacteristicChange {
+    /// Replace column `column` of `matrix` with `values`.
+    pub fn new(matrix: DataMatrix, column: usize, values: DVector<f64>) -> Self {
+        Self {
+            matrix,
+            column,
+            values,
+        }
+    }
+}
+
+/// Shares after changing observed characteristics with `xi` held fixed.
+#[derive(Clone, Debug)]
+pub struct CharacteristicCounterfactual {
+    /// Counterfactual mean utilities `X1' * beta + xi`.
+    pub delta: DVector<f64>,
+    /// Counterfactual product shares.
+    pub shares: DVector<f64>,
+    /// Counterfactual minus estimated shares.
+    pub share_changes: DVector<f64>,
+}
+
+impl Problem {
+    /// Split the estimated mean utilities into `X1 * beta` and `xi`.
+    pub fn decompose_delta(&self, results: &ProblemResults) -> DeltaDecomposition {
+        let linear = self.data().x1() * &results.beta;
+        DeltaDecomposition {
+            xi: &results.delta - &linear,
+            linear,
+        }
+    }
+
+    /// Partial-equilibrium counterfactual: change observed characteristics (typically
+    /// price) and recompute shares holding `xi`, `beta`, and `sigma` fixed.
+    ///
+    /// A characteristic that enters both linearly and through a random coefficient
+    /// appears in both `X1` and `X2`, and both columns must be changed for a
+    /// consistent counterfactual.
+    pub fn counterfactual_characteristics(
+        &self,
+        results: &ProblemResults,
+        changes: &[CharacteristicChange],
+    ) -> Result<CharacteristicCounterfactual> {
+        let mut data = self.data().clone();
+        for change in changes {
+            if change.matrix == DataMatrix::Instruments {
+                return Err(BlpError::InvalidCounterfactual {
+                    reason: "instruments do not enter shares",
+                });
+            }
+            data.set_column(change.matrix, change.column, &change.values)?;
+        }
+        let delta = data.x1() * &results.beta + self.decompose_delta(results).xi;
+        let inputs = ShareInputs::new(
+            &data,
+            self.draws(),
+            &results.sigma,
+            &self.options().contraction,
+        );
+        let shares = self.model().shares(&delta, &inputs)?;
+        Ok(CharacteristicCounterfactual {
+            share_changes: &shares - &results.predicted_shares,
+            delta,
+            shares,
+        })
+    }
+
+    /// Predict shares and inclusive values for a different population of agents.
+    ///
+    /// `draws` is typically the problem's own draws re-weighted with
+    /// [`SimulationDraws::reweight_to_means`] to match projected demographics. Mean
+    /// utilities and nonlinear parameters are taken from `results`, so nothing is
+    /// re-estimated.
+    pub fn counterfactual_population(
+        &self,
+        results: &ProblemResults,
+        draws: &SimulationDraws,
+    ) -> Result<PopulationCounterfactual> {
+        if draws.dimension() != self.draws().dimension() {
+            return Err(BlpError::dimension_mismatch(
+                "counterfactual draw dimension",
+                self.draws().dimension(),
+                draws.dimension(),
+            ));
+        }
+        let contraction = &self.options().contraction;
+        let baseline = ShareInputs::new(self.data(), self.draws(), &results.sigma, contraction);
+        let inputs = ShareInputs::new(self.data(), draws, &results.sigma, contraction);
+
+        let shares = self.model().shares(&results.delta, &inputs)?;
+        let values = inclusive_values(&results.delta, &inputs)?;
+        let changes = &values - inclusive_values(&results.delta, &baseline)?;
+        Ok(PopulationCounterfactual {
+            shares,
+            inclusive_values: values,
+            inclusive_value_changes: changes,
+            effective_draws: draws.effective_sample_size(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use nalgebra::DMatrix;
+
+    use super::*;
+    use crate::data::ProductDataBuilder;
+    use crate::parameters::NonlinearParameters;
+
+    #[test]
+    fn counterfactual_population_shifts_shares() {
+        let market_ids = vec!["m1".to_string(), "m1".to_string()];
+        let shares = DVector::from_vec(vec![0.2, 0.3]);
+        let x1 = DMatrix::from_row_slice(2, 1, &[1.0, 3.0]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1.clone())
+            .x2(x1)
+            .build()
+            .unwrap();
+        let draws = SimulationDraws::standard_normal(100, 1, 11);
+        let taste = DMatrix::from_fn(100, 1, |i, _| draws.draws()[(i, 0)]);
+        let draws = draws.with_demographics(taste).unwrap();
+        let problem = Problem::new(data, draws.clone()).unwrap();
+        let parameters = NonlinearParameters::new(DMatrix::from_element(1, 1, 1.0));
+        let results = problem.solve(&parameters).unwrap();
+
+        let same = problem.counterfactual_population(&results, &draws).unwrap();
+        assert_relative_eq!(same.shares, results.predicted_shares, epsilon = 1e-12);
+        assert_relative_eq!(same.inclusive_value_changes[0], 0.0, epsilon = 1e-12);
+
+        // Tilting towards high-taste agents raises demand for the high-x product.
+        let shifted_mean = draws.demographic_means().unwrap().add_scalar(0.5);
+        let shifted = draws.reweight_to_means(&shifted_mean).unwrap();
+        let counterfactual = problem
+            .counterfactual_population(&results, &shifted)
+            .unwrap();
+        assert!(counterfactual.shares[1] > results.predicted_shares[1]);
+        assert!(counterfactual.inclusive_value_changes[0] > 0.0);
+    }
+
+    #[test]
+    fn unchanged_characteristics_reproduce_estimated_shares() {
+        let market_ids = vec!["m1".to_string(), "m1".to_string(), "m2".to_string()];
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.4]);
+        let x1 = DMatrix::from_row_slice(3, 2, &[1.0, 1.0, 1.0, 2.0, 1.0, 1.5]);
+        let instruments = DMatrix::from_row_slice(3, 2, &[1.0, 0.5, 1.0, 2.5, 1.0, 1.0]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1.clone())
+            .instruments(instruments)
+            .build()
+            .unwrap();
+        let problem = Problem::new(data, SimulationDraws::standard_normal(1, 0, 1)).unwrap();
+        let results = problem.solve(&NonlinearParameters::default()).unwrap();
+
+        let unchanged = CharacteristicChange::new(DataMatrix::X1, 1, x1.column(1).into_owned());
+        let same = problem
+            .counterfactual_characteristics(&results, &[unchanged])
+            .unwrap();
+        assert_relative_eq!(same.shares, results.predicted_shares, epsilon = 1e-12);
+
+        let raised = CharacteristicChange::new(DataMatrix::X1, 1, x1.column(1).add_scalar(1.0));
+        let moved = problem
+            .counterfactual_characteristics(&results, &[raised])
+            .unwrap();
+        let direction = results.beta[1].signum();
+        assert!(
+            moved
+                .share_changes
+                .iter()
+                .all(|change| change * direction > 0.0)
+        );
+    }
+}
@@ -1,20 +1,435 @@
 //! Product-level data containers and validation utilities used by the BLP estimator.
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
 
-use nalgebra::{DMatrix, DVector};
+use nalgebra::{DMatrix, DMatrixView, DVector};
+use serde::{Deserialize, Serialize};
 
 use crate::error::{BlpError, Result};
+use crate::estimation::LinearEstimator;
+use crate::formulation::DesignMatrix;
 
 /// Represents product-level data required for BLP estimation.
 #[derive(Clone, Debug)]
 pub struct ProductData {
     market_ids: Vec<String>,
+    product_ids: Option<Vec<String>>,
+    nesting_ids: Option<Vec<String>>,
+    nests: Vec<usize>,
+    clustering_ids: Option<Vec<String>>,
+    market_groups: Option<Vec<String>>,
+    absorbed_ids: Vec<Vec<String>>,
+    fixed_effects: Option<FixedEffects>,
+    linear_estimator: LinearEstimator,
+    least_squares_weights: Option<DVector<f64>>,
+    endogenous: Vec<usize>,
+    donor: Option<DonorInstruments>,
     shares: DVector<f64>,
     x1: DMatrix<f64>,
     x2: DMatrix<f64>,
+    rc_types: Vec<RandomCoefficientType>,
     instruments: DMatrix<f64>,
+    labels: ColumnLabels,
+    merged: Vec<MergedProduct>,
+    imputed: Vec<ImputedCell>,
+    winsorized: Vec<WinsorizedValue>,
     partition: MarketPartition,
+    packed: PackedProducts,
+}
+
+/// How [`ProductDataBuilder`] treats rows that share a product id within a market.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Reject duplicated products with [`BlpError::DuplicateProduct`].
+    #[default]
+    Reject,
+    /// Merge duplicated rows: shares are summed and characteristics combined by the rule.
+    Aggregate(AggregationRule),
+}
+
+/// How the taste shift of an `X2` column enters utility, as in pyBLP's `rc_types`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RandomCoefficientType {
+    /// The coefficient is `sigma_k' nu + pi_k' d`, normal given the demographics.
+    #[default]
+    Linear,
+    /// The coefficient is `exp(sigma_k' nu + pi_k' d)`, lognormal and positive for every
+    /// consumer. Enter price as `-price` for a price sensitivity that is negative for
+    /// everyone, and keep the column out of `X1`: its location comes from `pi` on a
+    /// demographic column of ones.
+    Log,
+}
+
+/// Level of a two-level market structure, such as a city (market) within a year (group).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MarketLevel {
+    /// The markets whose shares sum to one minus the outside share.
+    Market,
+    /// The groups of markets set by [`ProductDataBuilder::market_groups`].
+    Group,
+}
+
+/// Markets that share an upper-level id, with their products.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MarketGroup {
+    /// Upper-level identifier.
+    pub id: String,
+    /// Positions of the group's markets in the partition.
+    pub markets: Vec<usize>,
+    /// Rows of the group's products, in row order.
+    pub products: Vec<usize>,
+}
+
+/// Rule used to combine the characteristics of merged duplicate rows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AggregationRule {
+    /// Simple average across the merged rows.
+    Mean,
+    /// Average weighted by each row's share.
+    ShareWeightedMean,
+    /// Keep the characteristics of the first row.
+    First,
+}
+
+/// Record of duplicate rows that were merged into a single product.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MergedProduct {
+    /// Market containing the duplicates.
+    pub market_id: String,
+    /// Duplicated product identifier.
+    pub product_id: String,
+    /// Row indices in the builder's input that were merged, in input order.
+    pub source_rows: Vec<usize>,
+    /// Share of the merged product (the sum of the merged shares).
+    pub share: f64,
+}
+
+/// Identifies one of the characteristic matrices held by [`ProductData`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DataMatrix {
+    /// Linear characteristics.
+    X1,
+    /// Nonlinear characteristics.
+    X2,
+    /// Instruments.
+    Instruments,
+}
+
+impl DataMatrix {
+    /// Conventional short name of the matrix.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::X1 => "X1",
+            Self::X2 => "X2",
+            Self::Instruments => "Z",
+        }
+    }
+}
+
+/// A missing (`NaN`) characteristic cell presented to an imputation rule.
+#[derive(Clone, Copy, Debug)]
+pub struct MissingCell<'a> {
+    /// Matrix containing the cell.
+    pub matrix: DataMatrix,
+    /// Row (product index) of the cell.
+    pub row: usize,
+    /// Column of the cell.
+    pub column: usize,
+    /// Market of the product.
+    pub market_id: &'a str,
+    /// Product identifier, when supplied.
+    pub product_id: Option<&'a str>,
+    /// The full column, including other missing values, for context.
+    pub values: nalgebra::DVectorView<'a, f64>,
+}
+
+/// User-supplied imputation rule; returning `None` leaves the cell unresolved.
+pub type ImputationFn = dyn Fn(&MissingCell<'_>) -> Option<f64> + Send + Sync;
+
+/// Strategy used to fill missing (`NaN`) characteristics while building [`ProductData`].
+#[derive(Clone, Default)]
+pub enum Imputation {
+    /// Reject missing values with [`BlpError::MissingValue`].
+    #[default]
+    Reject,
+    /// Mean of the non-missing values of the same column within the market.
+    MarketMean,
+    /// Median of the non-missing values of the same column within the market.
+    MarketMedian,
+    /// Mean of the same product's values in other markets (requires product ids).
+    CarryAcrossMarkets,
+    /// Arbitrary user rule.
+    Custom(Arc<ImputationFn>),
+}
+
+impl fmt::Debug for Imputation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Reject => f.write_str("Reject"),
+            Self::MarketMean => f.write_str("MarketMean"),
+            Self::MarketMedian => f.write_str("MarketMedian"),
+            Self::CarryAcrossMarkets => f.write_str("CarryAcrossMarkets"),
+            Self::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// Record of a single imputed cell.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImputedCell {
+    /// Matrix containing the cell.
+    pub matrix: DataMatrix,
+    /// Row of the cell in the builder's input.
+    pub row: usize,
+    /// Column of the cell.
+    pub column: usize,
+    /// Value that was filled in.
+    pub value: f64,
+}
+
+/// Column that can be winsorized or inspected for outliers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DataColumn {
+    /// Observed market shares.
+    Shares,
+    /// A column of one of the characteristic matrices.
+    Matrix(DataMatrix, usize),
+}
+
+/// Clip extreme values to pooled quantiles before estimation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Winsorization {
+    /// Lower quantile in `[0, 1)`; values below it are raised to it.
+    pub lower: f64,
+    /// Upper quantile in `(0, 1]`; values above it are lowered to it.
+    pub upper: f64,
+    /// Columns to winsorize.
+    pub columns: Vec<DataColumn>,
+}
+
+impl Winsorization {
+    /// Winsorize `columns` at the `lower` and `upper` quantiles.
+    pub fn new(lower: f64, upper: f64, columns: Vec<DataColumn>) -> Self {
+        Self {
+            lower,
+            upper,
+            columns,
+        }
+    }
+}
+
+/// Record of a single value changed by winsorization.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WinsorizedValue {
+    /// Column that was clipped.
+    pub column: DataColumn,
+    /// Row of the value.
+    pub row: usize,
+    /// Value before clipping.
+    pub original: f64,
+    /// Value after clipping.
+    pub clipped: f64,
+}
+
+/// Names of the columns of `X1`, `X2`, and `Z`, used to label reports and results.
+///
+/// Columns without user-supplied names are labeled `x1_0`, `x2_0`, `z_0`, and so on.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ColumnLabels {
+    /// Names of the linear characteristics.
+    pub x1: Vec<String>,
+    /// Names of the nonlinear characteristics.
+    pub x2: Vec<String>,
+    /// Names of the instruments.
+    pub instruments: Vec<String>,
+}
+
+impl ColumnLabels {
+    /// `(X1 column, X2 column)` pairs that hold the same characteristic, matched by label.
+    ///
+    /// A characteristic with both a mean and a random coefficient (usually price) appears
+    /// in both matrices under one label; the builder checks that the two columns agree.
+    pub fn shared(&self) -> Vec<(usize, usize)> {
+        self.x2
+            .iter()
+            .enumerate()
+            .filter_map(|(x2, label)| {
+                let x1 = self.x1.iter().position(|name| name == label)?;
+                Some((x1, x2))
+            })
+            .collect()
+    }
+}
+
+/// Dummy columns for each distinct id in `groups`, in order of first appearance, with
+/// labels `group[<id>]`.
+pub fn group_dummies(groups: &[String]) -> (DMatrix<f64>, Vec<String>) {
+    let mut distinct: Vec<&String> = Vec::new();
+    for group in groups {
+        if !distinct.contains(&group) {
+            distinct.push(group);
+        }
+    }
+    let dummies = DMatrix::from_fn(groups.len(), distinct.len(), |row, column| {
+        if &groups[row] == distinct[column] {
+            1.0
+        } else {
+            0.0
+        }
+    });
+    let names = distinct
+        .iter()
+        .map(|group| format!("group[{group}]"))
+        .collect();
+    (dummies, names)
+}
+
+fn default_labels(prefix: &str, count: usize) -> Vec<String> {
+    (0..count).map(|i| format!("{prefix}_{i}")).collect()
+}
+
+/// Columns observed in a second dataset, such as a survey of input prices, matched to
+/// products by market and product id for two-sample IV.
+///
+/// A key may appear on several donor rows, e.g. repeated price quotes; products receive
+/// the mean of their rows, and the sampling variance of that mean is carried into the
+/// moment covariance when the columns are used as instruments.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DonorSample {
+    market_ids: Vec<String>,
+    product_ids: Vec<String>,
+    values: DMatrix<f64>,
+    labels: Vec<String>,
+}
+
+/// Donor columns matched to the rows of a product dataset.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DonorJoin {
+    /// Mean of the matching donor rows, one row per product.
+    pub values: DMatrix<f64>,
+    /// Sampling covariance of each product's mean: the covariance of its donor rows
+    /// divided by their number, or zero for products matched by a single row.
+    pub variances: Vec<DMatrix<f64>>,
+    /// Number of donor rows matched by each product.
+    pub matches: Vec<usize>,
+}
+
+impl DonorSample {
+    /// Donor rows keyed by `market_ids` and `product_ids`, with one row of `values` each.
+    pub fn new(
+        market_ids: Vec<String>,
+        product_ids: Vec<String>,
+        values: DMatrix<f64>,
+    ) -> Result<Self> {
+        for (context, length) in [
+            ("donor product ids length", product_ids.len()),
+            ("donor rows", values.nrows()),
+        ] {
+            if length != market_ids.len() {
+                return Err(BlpError::dimension_mismatch(
+                    context,
+                    market_ids.len(),
+                    length,
+                ));
+            }
+        }
+        let labels = default_labels("donor", values.ncols());
+        Ok(Self {
+            market_ids,
+            product_ids,
+            values,
+            labels,
+        })
+    }
+
+    /// Names the donor columns; they label the instruments they become.
+    pub fn with_labels(mut self, labels: Vec<String>) -> Result<Self> {
+        if labels.len() != self.values.ncols() {
+            return Err(BlpError::dimension_mismatch(
+                "donor labels",
+                self.values.ncols(),
+                labels.len(),
+            ));
+        }
+        self.labels = labels;
+        Ok(self)
+    }
+
+    /// Names of the donor columns.
+    pub fn labels(&self) -> &[String] {
+        &self.labels
+    }
+
+    /// Number of donor columns.
+    pub fn column_count(&self) -> usize {
+        self.values.ncols()
+    }
+
+    /// Match every `(market, product)` key to the donor rows with the same key. Keys
+    /// without a donor row are reported together as
+    /// [`BlpError::InconsistentSpecification`].
+    ///
+    /// Cost shifters from a second sample can be joined this way and passed to the
+    /// supply side as `X3`.
+    pub fn join(&self, market_ids: &[String], product_ids: &[String]) -> Result<DonorJoin> {
+        if product_ids.len() != market_ids.len() {
+            return Err(BlpError::dimension_mismatch(
+                "product ids length",
+                market_ids.len(),
+                product_ids.len(),
+            ));
+        }
+        let mut rows: HashMap<(&str, &str), Vec<usize>> = HashMap::new();
+        for (row, (market, product)) in self.market_ids.iter().zip(&self.product_ids).enumerate() {
+            rows.entry((market, product)).or_default().push(row);
+        }
+        let k = self.values.ncols();
+        let mut values = DMatrix::zeros(market_ids.len(), k);
+        let mut variances = Vec::with_capacity(market_ids.len());
+        let mut matches = Vec::with_capacity(market_ids.len());
+        let mut missing = Vec::new();
+        for (row, (market, product)) in market_ids.iter().zip(product_ids).enumerate() {
+            let Some(donors) = rows.get(&(market.as_str(), product.as_str())) else {
+                missing.push(format!(
+                    "no donor rows for product `{product}` in market `{market}`"
+                ));
+                continue;
+            };
+            let sample = self.values.select_rows(donors.iter());
+            let count = donors.len();
+            let mean = sample.row_mean();
+            values.set_row(row, &mean);
+            let variance = if count > 1 {
+                let centered = DMatrix::from_fn(count, k, |i, c| sample[(i, c)] - mean[c]);
+                centered.transpose() * centered / ((count - 1) * count) as f64
+            } else {
+                DMatrix::zeros(k, k)
+            };
+            variances.push(variance);
+            matches.push(count);
+        }
+        if !missing.is_empty() {
+            return Err(BlpError::InconsistentSpecification {
+                mismatches: missing,
+            });
+        }
+        Ok(DonorJoin {
+            values,
+            variances,
+            matches,
+        })
+    }
+}
+
+/// Donor instruments appended to `Z` from column `first_column` on.
+#[derive(Clone, Debug)]
+struct DonorInstruments {
+    sample: DonorSample,
+    first_column: usize,
+    variances: Vec<DMatrix<f64>>,
 }
 
 impl ProductData {
@@ -63,6 +478,11 @@ impl ProductData {
         &self.x2
     }
 
+    /// How each `X2` column's random coefficient enters utility.
+    pub fn rc_types(&self) -> &[RandomCoefficientType] {
+        &self.rc_types
+    }
+
     /// Returns a read-only view of the instrument matrix (`Z`).
     pub fn instruments(&self) -> &DMatrix<f64> {
         &self.instruments
@@ -73,6 +493,11 @@ impl ProductData {
         &self.shares
     }
 
+    /// `X2` packed into one contiguous block per market.
+    pub(crate) fn packed_x2(&self) -> &PackedProducts {
+        &self.packed
+    }
+
     /// Provides access to the precomputed market partition.
     pub fn partition(&self) -> &MarketPartition {
         &self.partition
@@ -84,20 +509,572 @@ impl ProductData {
         self.partition.markets[market_idx].outside_share
     }
 
+    /// Returns the market identifier of every product, in row order.
+    pub fn market_ids(&self) -> &[String] {
+        &self.market_ids
+    }
+
     /// Returns the market identifier for a given product index.
     pub fn market_id(&self, product_index: usize) -> &str {
         &self.market_ids[product_index]
     }
+
+    /// Returns the product identifier for a given product index, when ids were supplied.
+    pub fn product_id(&self, product_index: usize) -> Option<&str> {
+        self.product_ids
+            .as_ref()
+            .map(|ids| ids[product_index].as_str())
+    }
+
+    /// Returns the nesting group of every product, in row order, when groups were supplied.
+    pub fn nesting_ids(&self) -> Option<&[String]> {
+        self.nesting_ids.as_deref()
+    }
+
+    /// Returns the cluster of every product, in row order, when clusters were supplied for
+    /// clustered standard errors and weighting matrices.
+    pub fn clustering_ids(&self) -> Option<&[String]> {
+        self.clustering_ids.as_deref()
+    }
+
+    /// Upper-level id of every market, in partition order, when market groups were set.
+    pub fn market_groups(&self) -> Option<&[String]> {
+        self.market_groups.as_deref()
+    }
+
+    /// The id of every product at `level`, e.g. to absorb, cluster, or build instruments
+    /// at that level.
+    pub fn level_ids(&self, level: MarketLevel) -> Result<Vec<String>> {
+        match level {
+            MarketLevel::Market => Ok(self.market_ids.clone()),
+            MarketLevel::Group => {
+                let groups = self
+                    .market_groups
+                    .as_ref()
+                    .ok_or_else(|| BlpError::missing_component("market groups"))?;
+                Ok((0..self.product_count())
+                    .map(|row| groups[self.partition.market_of(row)].clone())
+                    .collect())
+            }
+        }
+    }
+
+    /// The market groups in order of first appearance, with their markets and products.
+    pub fn market_group_partition(&self) -> Result<Vec<MarketGroup>> {
+        let groups = self
+            .market_groups
+            .as_ref()
+            .ok_or_else(|| BlpError::missing_component("market groups"))?;
+        let mut partition: Vec<MarketGroup> = Vec::new();
+        for (index, market) in self.partition.markets().enumerate() {
+            let position = match partition.iter().position(|group| group.id == groups[index]) {
+                Some(position) => position,
+                None => {
+                    partition.push(MarketGroup {
+                        id: groups[index].clone(),
+                        markets: Vec::new(),
+                        products: Vec::new(),
+                    });
+                    partition.len() - 1
+                }
+            };
+            partition[position].markets.push(index);
+            partition[position].products.extend(market.range());
+        }
+        Ok(partition)
+    }
+
+    /// How `beta` is estimated: IV-GMM when instruments were supplied, otherwise least
+    /// squares on `X1`.
+    pub fn linear_estimator(&self) -> LinearEstimator {
+        self.linear_estimator
+    }
+
+    /// Positions of the `X1` columns declared endogenous.
+    pub fn endogenous_columns(&self) -> &[usize] {
+        &self.endogenous
+    }
+
+    /// The second sample whose columns were joined onto the instruments, if any.
+    pub fn donor_sample(&self) -> Option<&DonorSample> {
+        self.donor.as_ref().map(|donor| &donor.sample)
+    }
+
+    /// First instrument column taken from the donor sample and the sampling covariance
+    /// of every product's donor columns.
+    pub(crate) fn donor_variances(&self) -> Option<(usize, &[DMatrix<f64>])> {
+        self.donor
+            .as_ref()
+            .map(|donor| (donor.first_column, donor.variances.as_slice()))
+    }
+
+    /// Instruments and labels other than the donor columns, which the builder re-joins.
+    fn own_instruments(&self) -> (DMatrix<f64>, Vec<String>) {
+        let own = self
+            .donor
+            .as_ref()
+            .map_or(self.instruments.ncols(), |donor| donor.first_column);
+        (
+            self.instruments.columns(0, own).into_owned(),
+            self.labels.instruments[..own].to_vec(),
+        )
+    }
+
+    /// Regression weights of the [`WeightedLeastSquares`](LinearEstimator::WeightedLeastSquares)
+    /// estimator, if any.
+    pub fn least_squares_weights(&self) -> Option<&DVector<f64>> {
+        self.least_squares_weights.as_ref()
+    }
+
+    /// The group ids of every absorbed fixed-effect dimension, each in row order.
+    pub fn absorbed_ids(&self) -> &[Vec<String>] {
+        &self.absorbed_ids
+    }
+
+    /// Residual of `values` (one per product) after absorbing the fixed effects; `values`
+    /// itself when none are absorbed.
+    pub fn demean(&self, values: &DVector<f64>) -> DVector<f64> {
+        let mut residual = values.clone();
+        if let Some(fixed_effects) = &self.fixed_effects {
+            fixed_effects.demean(residual.as_mut_slice());
+        }
+        residual
+    }
+
+    /// Position of every product's nesting group among the distinct groups, in row order.
+    /// Empty when no groups were supplied.
+    pub fn nest_indices(&self) -> &[usize] {
+        &self.nests
+    }
+
+    /// Number of distinct nesting groups (zero without nesting).
+    pub fn nest_count(&self) -> usize {
+        self.nests.iter().max().map_or(0, |&nest| nest + 1)
+    }
+
+    /// Column names of `X1`, `X2`, and `Z`.
+    pub fn labels(&self) -> &ColumnLabels {
+        &self.labels
+    }
+
+    /// Duplicate products that were merged under [`DuplicatePolicy::Aggregate`].
+    pub fn merged_products(&self) -> &[MergedProduct] {
+        &self.merged
+    }
+
+    /// Characteristic cells that were filled in by the builder's [`Imputation`] rule.
+    pub fn imputed_cells(&self) -> &[ImputedCell] {
+        &self.imputed
+    }
+
+    /// Values clipped by the builder's [`Winsorization`].
+    pub fn winsorized_values(&self) -> &[WinsorizedValue] {
+        &self.winsorized
+    }
+
+    /// Values of a single column (shares or a characteristic column).
+    pub fn column(&self, column: DataColumn) -> Result<DVector<f64>> {
+        match column {
+            DataColumn::Shares => Ok(self.shares.clone()),
+            DataColumn::Matrix(kind, index) => {
+                let matrix = match kind {
+                    DataMatrix::X1 => &self.x1,
+                    DataMatrix::X2 => &self.x2,
+                    DataMatrix::Instruments => &self.instruments,
+                };
+                if index >= matrix.ncols() {
+                    return Err(BlpError::dimension_mismatch(
+                        kind.name(),
+                        matrix.ncols(),
+                        index + 1,
+                    ));
+                }
+                Ok(matrix.column(index).into_owned())
+            }
+        }
+    }
+
+    /// Restrict the data to the markets at positions `markets` (in partition order).
+    ///
+    /// Column labels and product ids are kept; builder-time records such as
+    /// [`imputed_cells`](Self::imputed_cells) are not carried over.
+    pub fn select_markets(&self, markets: &[usize]) -> Result<Self> {
+        let segments: Vec<&MarketSegment> = self.partition.markets().collect();
+        let mut rows = Vec::new();
+        for &market in markets {
+            let segment = segments.get(market).ok_or(BlpError::dimension_mismatch(
+                "market index",
+                segments.len(),
+                market + 1,
+            ))?;
+            rows.extend(segment.range());
+        }
+        let select = |matrix: &DMatrix<f64>| matrix.select_rows(rows.iter());
+        let mut builder = ProductDataBuilder::new(
+            rows.iter()
+                .map(|&row| self.market_ids[row].clone())
+                .collect(),
+            self.shares.select_rows(rows.iter()),
+        )
+        .x1(select(&self.x1))
+        .x2(select(&self.x2))
+        .x1_labels(self.labels.x1.clone())
+        .x2_labels(self.labels.x2.clone())
+        .rc_types(self.rc_types.clone());
+        let (instruments, instrument_labels) = self.own_instruments();
+        builder = builder.instrument_labels(instrument_labels);
+        if let Some(ids) = &self.product_ids {
+            builder = builder.product_ids(rows.iter().map(|&row| ids[row].clone()).collect());
+        }
+        if let Some(ids) = &self.nesting_ids {
+            builder = builder.nesting_ids(rows.iter().map(|&row| ids[row].clone()).collect());
+        }
+        if let Some(ids) = &self.clustering_ids {
+            builder = builder.clustering_ids(rows.iter().map(|&row| ids[row].clone()).collect());
+        }
+        if self.market_groups.is_some() {
+            let ids = self.level_ids(MarketLevel::Group)?;
+            builder = builder.market_groups(rows.iter().map(|&row| ids[row].clone()).collect());
+        }
+        for ids in &self.absorbed_ids {
+            builder = builder.absorb(rows.iter().map(|&row| ids[row].clone()).collect());
+        }
+        builder = builder.endogenous(self.endogenous.clone());
+        builder = match (self.linear_estimator, &self.least_squares_weights) {
+            (LinearEstimator::InstrumentalVariables, _) => {
+                builder.instruments(select(&instruments))
+            }
+            (_, Some(weights)) => builder.least_squares_weights(weights.select_rows(rows.iter())),
+            (_, None) => builder,
+        };
+        if let Some(donor) = &self.donor {
+            builder = builder.donor_instruments(donor.sample.clone());
+        }
+        builder.build()
+    }
+
+    /// The products in `rows`, one whole market, with only what share prediction needs:
+    /// shares, `X1`, `X2` and its random-coefficient types, and nesting ids.
+    pub(crate) fn market_products(&self, rows: Range<usize>) -> Result<Self> {
+        let mut builder = ProductDataBuilder::new(
+            self.market_ids[rows.clone()].to_vec(),
+            self.shares.rows(rows.start, rows.len()).into_owned(),
+        )
+        .x1(self.x1.rows(rows.start, rows.len()).into_owned())
+        .x2(self.x2.rows(rows.start, rows.len()).into_owned())
+        .rc_types(self.rc_types.clone());
+        if let Some(ids) = &self.nesting_ids {
+            builder = builder.nesting_ids(ids[rows].to_vec());
+        }
+        builder.build()
+    }
+
+    /// A copy with `columns` appended to the instruments under `labels`, e.g. the output of
+    /// [`blp_instruments`](crate::instruments::blp_instruments).
+    pub fn append_instruments(
+        &self,
+        columns: &DMatrix<f64>,
+        labels: Vec<String>,
+    ) -> Result<ProductData> {
+        if columns.nrows() != self.product_count() {
+            return Err(BlpError::dimension_mismatch(
+                "appended instrument rows",
+                self.product_count(),
+                columns.nrows(),
+            ));
+        }
+        let (own, own_labels) = self.own_instruments();
+        let instruments = DMatrix::from_fn(
+            self.product_count(),
+            own.ncols() + columns.ncols(),
+            |row, column| match column.checked_sub(own.ncols()) {
+                Some(appended) => columns[(row, appended)],
+                None => own[(row, column)],
+            },
+        );
+        let mut builder = self
+            .to_builder()
+            .instruments(instruments)
+            .instrument_labels([own_labels, labels].concat());
+        if let Some(donor) = &self.donor {
+            builder = builder.donor_instruments(donor.sample.clone());
+        }
+        builder.build()
+    }
+
+    /// A copy with the markets of `markets`, e.g. the latest week of a panel, appended
+    /// after the existing ones.
+    ///
+    /// `markets` was validated when it was built, so only its agreement with this data is
+    /// checked: the same columns and labels, the same optional ids, and no market that is
+    /// already present. The partition and the packed `X2` blocks are extended rather than
+    /// rebuilt. Data with absorbed fixed effects or donor instruments, whose instruments
+    /// depend on every market, must be rebuilt through [`to_builder`](Self::to_builder)
+    /// instead. Builder-time records are concatenated and keep indexing the inputs of
+    /// their own builders.
+    pub fn append_markets(&self, markets: &ProductData) -> Result<ProductData> {
+        let mut mismatches = Vec::new();
+        for (name, current, appended) in [
+            ("X1", &self.labels.x1, &markets.labels.x1),
+            ("X2", &self.labels.x2, &markets.labels.x2),
+            ("Z", &self.labels.instruments, &markets.labels.instruments),
+        ] {
+            if current != appended {
+                mismatches.push(format!(
+                    "appended {name} columns {appended:?} differ from {current:?}"
+                ));
+            }
+        }
+        for (name, current, appended) in [
+            (
+                "product ids",
+                self.product_ids.is_some(),
+                markets.product_ids.is_some(),
+            ),
+            (
+                "nesting ids",
+                self.nesting_ids.is_some(),
+                markets.nesting_ids.is_some(),
+            ),
+            (
+                "clustering ids",
+                self.clustering_ids.is_some(),
+                markets.clustering_ids.is_some(),
+            ),
+            (
+                "market groups",
+                self.market_groups.is_some(),
+                markets.market_groups.is_some(),
+            ),
+            (
+                "least-squares weights",
+                self.least_squares_weights.is_some(),
+                markets.least_squares_weights.is_some(),
+            ),
+        ] {
+            if current != appended {
+                mismatches.push(format!(
+                    "{name} are set on only one of the existing and appended markets"
+                ));
+            }
+        }
+        if self.rc_types != markets.rc_types {
+            mismatches.push("appended markets use different random coefficient types".to_string());
+        }
+        if self.linear_estimator != markets.linear_estimator
+            || self.endogenous != markets.endogenous
+        {
+            mismatches.push("appended markets use a different linear estimator".to_string());
+        }
+        for data in [self, markets] {
+            if !data.absorbed_ids.is_empty() || data.donor.is_some() {
+                mismatches.push(
+                    "absorbed fixed effects and donor instruments need a full rebuild through \
+                     `to_builder`"
+                        .to_string(),
+                );
+                break;
+            }
+        }
+        let existing: HashSet<&str> = self.partition.markets().map(MarketSegment::id).collect();
+        for market in markets.partition.markets() {
+            if existing.contains(market.id()) {
+                mismatches.push(format!("market `{}` is already present", market.id()));
+            }
+        }
+        if !mismatches.is_empty() {
+            return Err(BlpError::InconsistentSpecification { mismatches });
+        }
+
+        let concat = |a: &Option<Vec<String>>, b: &Option<Vec<String>>| {
+            a.as_ref()
+                .zip(b.as_ref())
+                .map(|(a, b)| [a.as_slice(), b].concat())
+        };
+        let stack = |a: &DMatrix<f64>, b: &DMatrix<f64>| {
+            let mut stacked = a.clone().insert_rows(a.nrows(), b.nrows(), 0.0);
+            stacked.rows_mut(a.nrows(), b.nrows()).copy_from(b);
+            stacked
+        };
+        let nesting_ids = concat(&self.nesting_ids, &markets.nesting_ids);
+        let mut partition = self.partition.clone();
+        partition.append(&markets.partition);
+        let mut packed = self.packed.clone();
+        packed.append(&markets.packed);
+        Ok(ProductData {
+            market_ids: [self.market_ids.as_slice(), &markets.market_ids].concat(),
+            product_ids: concat(&self.product_ids, &markets.product_ids),
+            nests: nesting_ids.as_deref().map_or_else(Vec::new, nest_indices),
+            nesting_ids,
+            clustering_ids: concat(&self.clustering_ids, &markets.clustering_ids),
+            market_groups: concat(&self.market_groups, &markets.market_groups),
+            absorbed_ids: Vec::new(),
+            fixed_effects: None,
+            linear_estimator: self.linear_estimator,
+            least_squares_weights: self
+                .least_squares_weights
+                .as_ref()
+                .zip(markets.least_squares_weights.as_ref())
+                .map(|(a, b)| a.iter().chain(b.iter()).copied().collect::<Vec<_>>().into()),
+            endogenous: self.endogenous.clone(),
+            donor: None,
+            shares: self
+                .shares
+                .iter()
+                .chain(markets.shares.iter())
+                .copied()
+                .collect::<Vec<_>>()
+                .into(),
+            x1: stack(&self.x1, &markets.x1),
+            x2: stack(&self.x2, &markets.x2),
+            rc_types: self.rc_types.clone(),
+            instruments: stack(&self.instruments, &markets.instruments),
+            labels: self.labels.clone(),
+            merged: [self.merged.as_slice(), &markets.merged].concat(),
+            imputed: [self.imputed.as_slice(), &markets.imputed].concat(),
+            winsorized: [self.winsorized.as_slice(), &markets.winsorized].concat(),
+            partition,
+            packed,
+        })
+    }
+
+    /// A builder pre-filled with this data's arrays, product ids, and labels, for
+    /// constructing a modified copy that is validated again on [`build`](ProductDataBuilder::build).
+    ///
+    /// Builder-time records such as [`merged_products`](Self::merged_products) are not
+    /// carried over.
+    pub fn to_builder(&self) -> ProductDataBuilder {
+        let mut builder = ProductDataBuilder::new(self.market_ids.clone(), self.shares.clone())
+            .x1(self.x1.clone())
+            .x2(self.x2.clone())
+            .x1_labels(self.labels.x1.clone())
+            .x2_labels(self.labels.x2.clone())
+            .rc_types(self.rc_types.clone());
+        let (instruments, instrument_labels) = self.own_instruments();
+        builder = builder.instrument_labels(instrument_labels);
+        if let Some(ids) = &self.product_ids {
+            builder = builder.product_ids(ids.clone());
+        }
+        if let Some(ids) = &self.nesting_ids {
+            builder = builder.nesting_ids(ids.clone());
+        }
+        if let Some(ids) = &self.clustering_ids {
+            builder = builder.clustering_ids(ids.clone());
+        }
+        if let Ok(ids) = self.level_ids(MarketLevel::Group) {
+            builder = builder.market_groups(ids);
+        }
+        for ids in &self.absorbed_ids {
+            builder = builder.absorb(ids.clone());
+        }
+        builder = builder.endogenous(self.endogenous.clone());
+        builder = match (self.linear_estimator, &self.least_squares_weights) {
+            (LinearEstimator::InstrumentalVariables, _) => builder.instruments(instruments),
+            (_, Some(weights)) => builder.least_squares_weights(weights.clone()),
+            (_, None) => builder,
+        };
+        match &self.donor {
+            Some(donor) => builder.donor_instruments(donor.sample.clone()),
+            None => builder,
+        }
+    }
+
+    /// Keep only the nonlinear characteristics at positions `columns`, in that order.
+    pub fn select_nonlinear(&self, columns: &[usize]) -> Result<Self> {
+        if let Some(&column) = columns.iter().find(|&&column| column >= self.x2.ncols()) {
+            return Err(BlpError::dimension_mismatch(
+                "X2 column",
+                self.x2.ncols(),
+                column + 1,
+            ));
+        }
+        let mut selected = self.clone();
+        selected.x2 = self.x2.select_columns(columns.iter());
+        selected.packed = PackedProducts::new(&selected.x2, &selected.partition);
+        selected.labels.x2 = columns
+            .iter()
+            .map(|&column| self.labels.x2[column].clone())
+            .collect();
+        Ok(selected)
+    }
+
+    /// Overwrite one characteristic column, e.g. to evaluate a counterfactual.
+    pub(crate) fn set_column(
+        &mut self,
+        kind: DataMatrix,
+        index: usize,
+        values: &DVector<f64>,
+    ) -> Result<()> {
+        let matrix = match kind {
+            DataMatrix::X1 => &mut self.x1,
+            DataMatrix::X2 => &mut self.x2,
+            DataMatrix::Instruments => &mut self.instruments,
+        };
+        if index >= matrix.ncols() {
+            return Err(BlpError::dimension_mismatch(
+                kind.name(),
+                matrix.ncols(),
+                index + 1,
+            ));
+        }
+        if values.len() != matrix.nrows() {
+            return Err(BlpError::dimension_mismatch(
+                "counterfactual column length",
+                matrix.nrows(),
+                values.len(),
+            ));
+        }
+        matrix.set_column(index, values);
+        if kind == DataMatrix::X2 {
+            self.packed = PackedProducts::new(&self.x2, &self.partition);
+        }
+        Ok(())
+    }
+
+    /// Human-readable name of a column, using the column labels.
+    pub fn column_label(&self, column: DataColumn) -> String {
+        match column {
+            DataColumn::Shares => "shares".to_string(),
+            DataColumn::Matrix(kind, index) => {
+                let labels = match kind {
+                    DataMatrix::X1 => &self.labels.x1,
+                    DataMatrix::X2 => &self.labels.x2,
+                    DataMatrix::Instruments => &self.labels.instruments,
+                };
+                labels
+                    .get(index)
+                    .cloned()
+                    .unwrap_or_else(|| format!("{}_{index}", kind.name()))
+            }
+        }
+    }
 }
 
 /// Builder that validates dimensions and market structure before constructing [`ProductData`].
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct ProductDataBuilder {
     market_ids: Vec<String>,
+    product_ids: Option<Vec<String>>,
+    nesting_ids: Option<Vec<String>>,
+    clustering_ids: Option<Vec<String>>,
+    clustering_level: Option<MarketLevel>,
+    market_groups: Option<Vec<String>>,
+    absorbed_ids: Vec<Vec<String>>,
+    absorbed_levels: Vec<MarketLevel>,
+    least_squares_weights: Option<DVector<f64>>,
+    endogenous: Vec<usize>,
+    donor: Option<DonorSample>,
     shares: DVector<f64>,
     x1: Option<DMatrix<f64>>,
     x2: Option<DMatrix<f64>>,
     instruments: Option<DMatrix<f64>>,
+    labels: ColumnLabels,
+    duplicates: DuplicatePolicy,
+    imputation: Imputation,
+    winsorization: Option<Winsorization>,
+    error_components: Option<Vec<String>>,
+    x2_from_x1: Option<Vec<usize>>,
+    rc_types: Option<Vec<RandomCoefficientType>>,
 }
 
 impl ProductDataBuilder {
@@ -105,34 +1082,258 @@ impl ProductDataBuilder {
     pub fn new(market_ids: Vec<String>, shares: DVector<f64>) -> Self {
         Self {
             market_ids,
+            product_ids: None,
+            nesting_ids: None,
+            clustering_ids: None,
+            clustering_level: None,
+            market_groups: None,
+            absorbed_ids: Vec::new(),
+            absorbed_levels: Vec::new(),
+            least_squares_weights: None,
+            endogenous: Vec::new(),
+            donor: None,
             shares,
             x1: None,
             x2: None,
             instruments: None,
+            labels: ColumnLabels::default(),
+            duplicates: DuplicatePolicy::default(),
+            imputation: Imputation::default(),
+            winsorization: None,
+            error_components: None,
+            x2_from_x1: None,
+            rc_types: None,
         }
     }
 
+    /// Replaces the observed market shares.
+    pub fn shares(mut self, shares: DVector<f64>) -> Self {
+        self.shares = shares;
+        self
+    }
+
+    /// Assigns every product to a nesting group for nested logit demand. Groups are
+    /// shared across markets by name.
+    pub fn nesting_ids(mut self, ids: Vec<String>) -> Self {
+        self.nesting_ids = Some(ids);
+        self
+    }
+
+    /// Assigns every product to a cluster (e.g. its market or firm) whose moment
+    /// contributions may be correlated, for
+    /// [`MomentCovariance::Clustered`](crate::options::MomentCovariance::Clustered).
+    pub fn clustering_ids(mut self, ids: Vec<String>) -> Self {
+        self.clustering_ids = Some(ids);
+        self.clustering_level = None;
+        self
+    }
+
+    /// Cluster on the markets or the market groups instead of explicit ids.
+    pub fn cluster_by(mut self, level: MarketLevel) -> Self {
+        self.clustering_level = Some(level);
+        self.clustering_ids = None;
+        self
+    }
+
+    /// Places every market in an upper-level group, e.g. the year of a city-year market,
+    /// given one id per product. The id must be the same for all products of a market.
+    pub fn market_groups(mut self, ids: Vec<String>) -> Self {
+        self.market_groups = Some(ids);
+        self
+    }
+
+    /// Absorb a fixed effect for every group in `ids` (one id per product) instead of
+    /// including its dummies in `X1`; call once per dimension, e.g. product and market.
+    ///
+    /// The instruments are demeaned within the groups when the data are built, and the
+    /// linear IV step regresses the demeaned `delta` on `X1` through them, which by
+    /// Frisch–Waugh–Lovell matches including the dummies while `X1'Z` stays small.
+    /// Several dimensions are demeaned by alternating projections. `X1` itself keeps
+    /// its levels for counterfactuals and elasticities, must not contain a constant or
+    /// any other column the fixed effects absorb, and `xi` is reported net of the fixed
+    /// effects.
+    pub fn absorb(mut self, ids: Vec<String>) -> Self {
+        self.absorbed_ids.push(ids);
+        self
+    }
+
+    /// Absorb a fixed effect for every market or every market group, as [`Self::absorb`].
+    pub fn absorb_level(mut self, level: MarketLevel) -> Self {
+        self.absorbed_levels.push(level);
+        self
+    }
+
     /// Sets the linear characteristics matrix (`X1`).
     pub fn x1(mut self, matrix: DMatrix<f64>) -> Self {
         self.x1 = Some(matrix);
         self
     }
 
+    /// Declare the `X1` columns at `columns` (typically price) endogenous. Building fails
+    /// with [`BlpError::EndogenousInstrument`] if any of them is also an instrument
+    /// column with identical values, which would make the IV estimates inconsistent,
+    /// including when no instruments are supplied and `X1` instruments itself.
+    pub fn endogenous(mut self, columns: Vec<usize>) -> Self {
+        self.endogenous = columns;
+        self
+    }
+
     /// Sets the nonlinear characteristics matrix (`X2`).
     pub fn x2(mut self, matrix: DMatrix<f64>) -> Self {
         self.x2 = Some(matrix);
+        self.x2_from_x1 = None;
         self
     }
 
-    /// Sets the instrument matrix (`Z`).
+    /// Use the `X1` columns at `columns` as `X2`, so characteristics with both a mean and
+    /// a random coefficient are stored once and share their `X1` labels. Replaces any
+    /// matrix passed to [`x2`](Self::x2).
+    pub fn x2_from_x1(mut self, columns: Vec<usize>) -> Self {
+        self.x2 = None;
+        self.x2_from_x1 = Some(columns);
+        self
+    }
+
+    /// Sets the instrument matrix (`Z`). Replaces any
+    /// [`least_squares_weights`](Self::least_squares_weights) and
+    /// [`donor_instruments`](Self::donor_instruments).
+    ///
+    /// Without instruments, `X1` is treated as exogenous and instruments itself, so `beta`
+    /// is estimated by ordinary least squares and the results are flagged with
+    /// [`LinearEstimator::OrdinaryLeastSquares`].
     pub fn instruments(mut self, matrix: DMatrix<f64>) -> Self {
         self.instruments = Some(matrix);
+        self.least_squares_weights = None;
+        self.donor = None;
+        self
+    }
+
+    /// Append the columns of a second sample to the instruments, matched on market and
+    /// product id (two-sample IV). Requires [`product_ids`](Self::product_ids); every
+    /// product must match at least one donor row. Without
+    /// [`instruments`](Self::instruments), `X1` is used alongside the donor columns and
+    /// `beta` is still estimated by IV.
+    ///
+    /// The robust and clustered moment covariances add `sum_j xi_j^2 V_j`, where `V_j` is
+    /// the sampling covariance of product `j`'s donor means (see [`DonorJoin`]), so
+    /// weighting matrices and standard errors reflect the noise in the joined
+    /// instruments. The adjustment ignores any demeaning by absorbed fixed effects.
+    pub fn donor_instruments(mut self, sample: DonorSample) -> Self {
+        self.donor = Some(sample);
+        self.least_squares_weights = None;
+        self
+    }
+
+    /// Estimate `beta` by weighted least squares with one positive weight per product
+    /// instead of by IV, treating `X1` as exogenous. The instruments become
+    /// `diag(weights) X1`, which makes the exactly identified linear step
+    /// `(X1' diag(w) X1)^{-1} X1' diag(w) delta` and the sandwich standard errors
+    /// heteroskedasticity-robust WLS errors. Replaces any [`instruments`](Self::instruments)
+    /// and [`donor_instruments`](Self::donor_instruments).
+    pub fn least_squares_weights(mut self, weights: DVector<f64>) -> Self {
+        self.least_squares_weights = Some(weights);
+        self.instruments = None;
+        self.donor = None;
+        self
+    }
+
+    /// Sets product identifiers, one per row.
+    pub fn product_ids(mut self, ids: Vec<String>) -> Self {
+        self.product_ids = Some(ids);
+        self
+    }
+
+    /// Names the columns of `X1`.
+    pub fn x1_labels(mut self, labels: Vec<String>) -> Self {
+        self.labels.x1 = labels;
+        self
+    }
+
+    /// Names the columns of `X2`.
+    pub fn x2_labels(mut self, labels: Vec<String>) -> Self {
+        self.labels.x2 = labels;
+        self
+    }
+
+    /// Names the columns of `Z`.
+    pub fn instrument_labels(mut self, labels: Vec<String>) -> Self {
+        self.labels.instruments = labels;
+        self
+    }
+
+    /// Sets `X1` and its labels from an evaluated formula, so coefficients on generated
+    /// columns such as `C(region)[west]` are labeled in results and reports.
+    pub fn x1_design(self, design: DesignMatrix) -> Self {
+        self.x1(design.matrix).x1_labels(design.names)
+    }
+
+    /// Sets `X2` and its labels from an evaluated formula. Replaces any
+    /// [`x2_from_x1`](Self::x2_from_x1) selection.
+    pub fn x2_design(self, design: DesignMatrix) -> Self {
+        self.x2(design.matrix).x2_labels(design.names)
+    }
+
+    /// Sets `Z` and its labels from an evaluated formula.
+    pub fn instrument_design(self, design: DesignMatrix) -> Self {
+        self.instruments(design.matrix)
+            .instrument_labels(design.names)
+    }
+
+    /// Choose how rows with the same product id within a market are handled.
+    ///
+    /// Duplicates are only detected when product ids have been supplied.
+    pub fn duplicates(mut self, policy: DuplicatePolicy) -> Self {
+        self.duplicates = policy;
+        self
+    }
+
+    /// Choose how missing (`NaN`) characteristics are filled in.
+    ///
+    /// Imputation runs before duplicate handling; cells that remain unresolved are
+    /// reported as [`BlpError::MissingValue`].
+    pub fn impute(mut self, imputation: Imputation) -> Self {
+        self.imputation = imputation;
+        self
+    }
+
+    /// Clip extreme shares or characteristics to pooled quantiles.
+    ///
+    /// Winsorization runs after imputation and before duplicate handling. Clipped
+    /// shares still have to leave a positive outside share in every market.
+    pub fn winsorize(mut self, winsorization: Winsorization) -> Self {
+        self.winsorization = Some(winsorization);
+        self
+    }
+
+    /// Append one `X2` column per group in `group_ids` (one id per product) holding that
+    /// group's dummy, so random coefficients on them act as nesting-style error
+    /// components: products in the same group share an unobserved taste shock.
+    ///
+    /// Columns follow the order in which groups first appear and are labelled
+    /// `group[<id>]`; the scale of each shock is the matching diagonal entry of `sigma`.
+    pub fn error_components(mut self, group_ids: Vec<String>) -> Self {
+        self.error_components = Some(group_ids);
+        self
+    }
+
+    /// Set how each `X2` column's random coefficient enters utility, one entry per column;
+    /// columns added by [`Self::error_components`] are always linear.
+    pub fn rc_types(mut self, rc_types: Vec<RandomCoefficientType>) -> Self {
+        self.rc_types = Some(rc_types);
         self
     }
 
     /// Finalizes construction after validating shapes and market structure.
-    pub fn build(self) -> Result<ProductData> {
+    pub fn build(mut self) -> Result<ProductData> {
         let n = self.market_ids.len();
+        let group_of_market = self.group_of_market()?;
+        if let Some(level) = self.clustering_level {
+            self.clustering_ids = Some(self.level_ids(level, group_of_market.as_ref())?);
+        }
+        for level in std::mem::take(&mut self.absorbed_levels) {
+            let ids = self.level_ids(level, group_of_market.as_ref())?;
+            self.absorbed_ids.push(ids);
+        }
         if self.shares.len() != n {
             return Err(BlpError::dimension_mismatch(
                 "shares length",
@@ -157,12 +1358,97 @@ impl ProductDataBuilder {
             return Err(BlpError::dimension_mismatch("X1 rows", n, x1.nrows()));
         }
 
-        let x2 = self.x2.unwrap_or_else(|| DMatrix::zeros(n, 0));
+        let mut labels = self.labels;
+        let mut x2 = match &self.x2_from_x1 {
+            Some(columns) => {
+                if let Some(&column) = columns.iter().find(|&&column| column >= x1.ncols()) {
+                    return Err(BlpError::dimension_mismatch("X1", x1.ncols(), column + 1));
+                }
+                if labels.x1.is_empty() {
+                    labels.x1 = default_labels("x1", x1.ncols());
+                } else if labels.x1.len() != x1.ncols() {
+                    return Err(BlpError::dimension_mismatch(
+                        "X1 labels",
+                        x1.ncols(),
+                        labels.x1.len(),
+                    ));
+                }
+                labels.x2 = columns
+                    .iter()
+                    .map(|&column| labels.x1[column].clone())
+                    .collect();
+                x1.select_columns(columns.iter())
+            }
+            None => self.x2.unwrap_or_else(|| DMatrix::zeros(n, 0)),
+        };
         if x2.nrows() != n {
             return Err(BlpError::dimension_mismatch("X2 rows", n, x2.nrows()));
         }
+        let mut rc_types = match self.rc_types {
+            Some(rc_types) if rc_types.len() != x2.ncols() => {
+                return Err(BlpError::dimension_mismatch(
+                    "random coefficient types",
+                    x2.ncols(),
+                    rc_types.len(),
+                ));
+            }
+            Some(rc_types) => rc_types,
+            None => vec![RandomCoefficientType::Linear; x2.ncols()],
+        };
+        if let Some(groups) = &self.error_components {
+            if groups.len() != n {
+                return Err(BlpError::dimension_mismatch(
+                    "error component groups",
+                    n,
+                    groups.len(),
+                ));
+            }
+            if labels.x2.is_empty() {
+                labels.x2 = default_labels("x2", x2.ncols());
+            }
+            let (dummies, names) = group_dummies(groups);
+            let existing = x2.ncols();
+            x2 = x2.insert_columns(existing, dummies.ncols(), 0.0);
+            x2.columns_mut(existing, dummies.ncols())
+                .copy_from(&dummies);
+            labels.x2.extend(names);
+            rc_types.resize(x2.ncols(), RandomCoefficientType::Linear);
+        }
 
-        let instruments = self.instruments.unwrap_or_else(|| x1.clone());
+        let instruments_from_x1 = self.instruments.is_none();
+        let linear_estimator = match (&self.instruments, &self.least_squares_weights) {
+            (Some(_), _) => LinearEstimator::InstrumentalVariables,
+            (None, _) if self.donor.is_some() => LinearEstimator::InstrumentalVariables,
+            (None, Some(_)) => LinearEstimator::WeightedLeastSquares,
+            (None, None) => LinearEstimator::OrdinaryLeastSquares,
+        };
+        if let Some(weights) = &self.least_squares_weights {
+            if weights.len() != n {
+                return Err(BlpError::dimension_mismatch(
+                    "least-squares weights length",
+                    n,
+                    weights.len(),
+                ));
+            }
+            if weights
+                .iter()
+                .any(|weight| !(*weight > 0.0 && weight.is_finite()))
+            {
+                return Err(BlpError::InconsistentSpecification {
+                    mismatches: vec!["least-squares weights must be positive".to_string()],
+                });
+            }
+        }
+        let mut instruments = match (self.instruments, &self.least_squares_weights) {
+            (Some(instruments), _) => instruments,
+            (None, Some(weights)) => DMatrix::from_fn(n, x1.ncols(), |row, column| {
+                weights[row] * x1[(row, column)]
+            }),
+            (None, None) => x1.clone(),
+        };
+        if linear_estimator != LinearEstimator::InstrumentalVariables {
+            log::info!("no instruments supplied; estimating beta by least squares on exogenous X1");
+        }
         if instruments.nrows() != n {
             return Err(BlpError::dimension_mismatch(
                 "Z rows",
@@ -171,17 +1457,713 @@ impl ProductDataBuilder {
             ));
         }
 
-        let partition = MarketPartition::new(&self.market_ids, &self.shares)?;
+        if let Some(ids) = &self.product_ids
+            && ids.len() != n
+        {
+            return Err(BlpError::dimension_mismatch(
+                "product ids length",
+                n,
+                ids.len(),
+            ));
+        }
+        if let Some(ids) = &self.nesting_ids
+            && ids.len() != n
+        {
+            return Err(BlpError::dimension_mismatch(
+                "nesting ids length",
+                n,
+                ids.len(),
+            ));
+        }
+        if let Some(ids) = &self.clustering_ids
+            && ids.len() != n
+        {
+            return Err(BlpError::dimension_mismatch(
+                "clustering ids length",
+                n,
+                ids.len(),
+            ));
+        }
+        if let Some(ids) = self.absorbed_ids.iter().find(|ids| ids.len() != n) {
+            return Err(BlpError::dimension_mismatch(
+                "absorbed fixed effect ids length",
+                n,
+                ids.len(),
+            ));
+        }
 
-        Ok(ProductData {
+        if instruments_from_x1 && labels.instruments.is_empty() {
+            labels.instruments = labels.x1.clone();
+        }
+        let own_instruments = instruments.ncols();
+        if let Some(donor) = &self.donor {
+            let ids = self
+                .product_ids
+                .as_ref()
+                .ok_or_else(|| BlpError::missing_component("product ids"))?;
+            let joined = donor.join(&self.market_ids, ids)?;
+            instruments = instruments.insert_columns(own_instruments, donor.column_count(), 0.0);
+            instruments
+                .columns_mut(own_instruments, donor.column_count())
+                .copy_from(&joined.values);
+            if labels.instruments.is_empty() {
+                labels.instruments = default_labels("z", own_instruments);
+            }
+            labels.instruments.extend(donor.labels().iter().cloned());
+        }
+        for (context, names, columns, prefix) in [
+            ("X1 labels", &mut labels.x1, x1.ncols(), "x1"),
+            ("X2 labels", &mut labels.x2, x2.ncols(), "x2"),
+            (
+                "Z labels",
+                &mut labels.instruments,
+                instruments.ncols(),
+                "z",
+            ),
+        ] {
+            if names.is_empty() {
+                *names = default_labels(prefix, columns);
+            } else if names.len() != columns {
+                return Err(BlpError::dimension_mismatch(context, columns, names.len()));
+            }
+        }
+        for (matrix, names) in [("X1", &labels.x1), ("X2", &labels.x2)] {
+            for (index, label) in names.iter().enumerate() {
+                if names[..index].contains(label) {
+                    return Err(BlpError::DuplicateLabel {
+                        matrix,
+                        label: label.clone(),
+                    });
+                }
+            }
+        }
+        for (column1, column2) in labels.shared() {
+            let same = x1
+                .column(column1)
+                .iter()
+                .zip(x2.column(column2).iter())
+                .all(|(a, b)| a == b || (a.is_nan() && b.is_nan()));
+            if !same {
+                return Err(BlpError::InconsistentSharedColumn {
+                    label: labels.x2[column2].clone(),
+                });
+            }
+        }
+        if let Some(&column) = self.endogenous.iter().find(|&&column| column >= x1.ncols()) {
+            return Err(BlpError::dimension_mismatch("X1", x1.ncols(), column + 1));
+        }
+        for &column in &self.endogenous {
+            let values = x1.column(column);
+            let instrumented = linear_estimator != LinearEstimator::InstrumentalVariables
+                || instruments.column_iter().any(|instrument| {
+                    instrument
+                        .iter()
+                        .zip(values.iter())
+                        .all(|(a, b)| a == b || (a.is_nan() && b.is_nan()))
+                });
+            if instrumented {
+                return Err(BlpError::EndogenousInstrument {
+                    label: labels.x1[column].clone(),
+                });
+            }
+        }
+
+        let mut rows = Rows {
             market_ids: self.market_ids,
+            product_ids: self.product_ids,
+            nesting_ids: self.nesting_ids,
+            clustering_ids: self.clustering_ids,
+            absorbed_ids: self.absorbed_ids,
+            least_squares_weights: self.least_squares_weights,
             shares: self.shares,
             x1,
             x2,
             instruments,
+        };
+        let imputed = rows.impute(&self.imputation)?;
+        let winsorized = match &self.winsorization {
+            Some(winsorization) => rows.winsorize(winsorization)?,
+            None => Vec::new(),
+        };
+        let merged = rows.deduplicate(self.duplicates)?;
+        if linear_estimator != LinearEstimator::InstrumentalVariables {
+            // Rebuild from the imputed and merged X1 rather than combining instruments.
+            rows.instruments = match &rows.least_squares_weights {
+                Some(weights) => {
+                    DMatrix::from_fn(rows.x1.nrows(), rows.x1.ncols(), |row, column| {
+                        weights[row] * rows.x1[(row, column)]
+                    })
+                }
+                None => rows.x1.clone(),
+            };
+        }
+
+        let partition = MarketPartition::new(&rows.market_ids, &rows.shares)?;
+        let nests = rows
+            .nesting_ids
+            .as_deref()
+            .map_or_else(Vec::new, nest_indices);
+
+        let fixed_effects = FixedEffects::new(&rows.absorbed_ids);
+        if let Some(fixed_effects) = &fixed_effects {
+            let absorbed: Vec<String> = rows
+                .x1
+                .column_iter()
+                .zip(&labels.x1)
+                .filter(|(column, _)| {
+                    let mut residual = column.into_owned();
+                    fixed_effects.demean(residual.as_mut_slice());
+                    residual.amax() <= 1e-10 * column.amax().max(f64::MIN_POSITIVE)
+                })
+                .map(|(_, label)| format!("X1 column `{label}` is absorbed by the fixed effects"))
+                .collect();
+            if !absorbed.is_empty() {
+                return Err(BlpError::InconsistentSpecification {
+                    mismatches: absorbed,
+                });
+            }
+            for mut column in rows.instruments.column_iter_mut() {
+                fixed_effects.demean(column.as_mut_slice());
+            }
+        }
+
+        let donor = match self.donor {
+            Some(sample) => {
+                // Rows may have been merged since the join; match the final keys again.
+                let ids = rows.product_ids.as_deref().unwrap_or_default();
+                let variances = sample.join(&rows.market_ids, ids)?.variances;
+                Some(DonorInstruments {
+                    sample,
+                    first_column: own_instruments,
+                    variances,
+                })
+            }
+            None => None,
+        };
+
+        let market_groups = group_of_market.map(|groups| {
+            partition
+                .markets()
+                .map(|market| groups[market.id()].clone())
+                .collect()
+        });
+        let packed = PackedProducts::new(&rows.x2, &partition);
+        Ok(ProductData {
+            market_ids: rows.market_ids,
+            product_ids: rows.product_ids,
+            nesting_ids: rows.nesting_ids,
+            nests,
+            clustering_ids: rows.clustering_ids,
+            market_groups,
+            absorbed_ids: rows.absorbed_ids,
+            fixed_effects,
+            linear_estimator,
+            least_squares_weights: rows.least_squares_weights,
+            endogenous: self.endogenous,
+            donor,
+            shares: rows.shares,
+            x1: rows.x1,
+            x2: rows.x2,
+            rc_types,
+            instruments: rows.instruments,
+            labels,
+            merged,
+            imputed,
+            winsorized,
             partition,
+            packed,
         })
     }
+
+    /// Maps every market to its group, checking that no market spans two groups.
+    fn group_of_market(&self) -> Result<Option<HashMap<String, String>>> {
+        let Some(groups) = &self.market_groups else {
+            return Ok(None);
+        };
+        if groups.len() != self.market_ids.len() {
+            return Err(BlpError::dimension_mismatch(
+                "market group ids length",
+                self.market_ids.len(),
+                groups.len(),
+            ));
+        }
+        let mut group_of_market: HashMap<String, String> = HashMap::new();
+        let mut mismatches = Vec::new();
+        for (market, group) in self.market_ids.iter().zip(groups) {
+            match group_of_market.get(market) {
+                Some(existing) if existing != group => {
+                    let mismatch =
+                        format!("market `{market}` is in groups `{existing}` and `{group}`");
+                    if !mismatches.contains(&mismatch) {
+                        mismatches.push(mismatch);
+                    }
+                }
+                Some(_) => {}
+                None => {
+                    group_of_market.insert(market.clone(), group.clone());
+                }
+            }
+        }
+        if !mismatches.is_empty() {
+            return Err(BlpError::InconsistentSpecification { mismatches });
+        }
+        Ok(Some(group_of_market))
+    }
+
+    fn level_ids(
+        &self,
+        level: MarketLevel,
+        group_of_market: Option<&HashMap<String, String>>,
+    ) -> Result<Vec<String>> {
+        match level {
+            MarketLevel::Market => Ok(self.market_ids.clone()),
+            MarketLevel::Group => {
+                let groups =
+                    group_of_market.ok_or_else(|| BlpError::missing_component("market groups"))?;
+                Ok(self
+                    .market_ids
+                    .iter()
+                    .map(|market| groups[market].clone())
+                    .collect())
+            }
+        }
+    }
+}
+
+/// Index of every product's nest among the sorted distinct nesting ids.
+fn nest_indices(ids: &[String]) -> Vec<usize> {
+    let mut groups: Vec<&str> = ids.iter().map(String::as_str).collect();
+    groups.sort_unstable();
+    groups.dedup();
+    ids.iter()
+        .map(|id| groups.partition_point(|group| *group < id.as_str()))
+        .collect()
+}
+
+/// Row-aligned arrays manipulated while building [`ProductData`].
+struct Rows {
+    market_ids: Vec<String>,
+    product_ids: Option<Vec<String>>,
+    nesting_ids: Option<Vec<String>>,
+    clustering_ids: Option<Vec<String>>,
+    absorbed_ids: Vec<Vec<String>>,
+    least_squares_weights: Option<DVector<f64>>,
+    shares: DVector<f64>,
+    x1: DMatrix<f64>,
+    x2: DMatrix<f64>,
+    instruments: DMatrix<f64>,
+}
+
+impl Rows {
+    /// Fill `NaN` characteristics according to `rule`, returning the filled cells.
+    fn impute(&mut self, rule: &Imputation) -> Result<Vec<ImputedCell>> {
+        let mut imputed = Vec::new();
+        for kind in [DataMatrix::X1, DataMatrix::X2, DataMatrix::Instruments] {
+            let matrix = match kind {
+                DataMatrix::X1 => &self.x1,
+                DataMatrix::X2 => &self.x2,
+                DataMatrix::Instruments => &self.instruments,
+            };
+            let mut fills = Vec::new();
+            for column in 0..matrix.ncols() {
+                let values = matrix.column(column);
+                for row in 0..matrix.nrows() {
+                    if !values[row].is_nan() {
+                        continue;
+                    }
+                    let cell = MissingCell {
+                        matrix: kind,
+                        row,
+                        column,
+                        market_id: &self.market_ids[row],
+                        product_id: self.product_ids.as_ref().map(|ids| ids[row].as_str()),
+                        values,
+                    };
+                    let value = match rule {
+                        Imputation::Reject => None,
+                        Imputation::MarketMean => self.market_values(&cell).map(|v| mean(&v)),
+                        Imputation::MarketMedian => self.market_values(&cell).map(median),
+                        Imputation::CarryAcrossMarkets => {
+                            self.product_values(&cell).map(|v| mean(&v))
+                        }
+                        Imputation::Custom(function) => function(&cell),
+                    };
+                    match value {
+                        Some(value) if value.is_finite() => fills.push(ImputedCell {
+                            matrix: kind,
+                            row,
+                            column,
+                            value,
+                        }),
+                        _ => {
+                            return Err(BlpError::MissingValue {
+                                matrix: kind.name(),
+                                row,
+                                column,
+                            });
+                        }
+                    }
+                }
+            }
+            let matrix = match kind {
+                DataMatrix::X1 => &mut self.x1,
+                DataMatrix::X2 => &mut self.x2,
+                DataMatrix::Instruments => &mut self.instruments,
+            };
+            for fill in &fills {
+                matrix[(fill.row, fill.column)] = fill.value;
+            }
+            imputed.extend(fills);
+        }
+        if !imputed.is_empty() {
+            log::info!("imputed {} missing characteristic values", imputed.len());
+        }
+        Ok(imputed)
+    }
+
+    /// Clip the requested columns to their pooled quantiles.
+    fn winsorize(&mut self, winsorization: &Winsorization) -> Result<Vec<WinsorizedValue>> {
+        let Winsorization {
+            lower,
+            upper,
+            columns,
+        } = winsorization;
+        if !(0.0..=1.0).contains(lower) || !(0.0..=1.0).contains(upper) || lower >= upper {
+            return Err(BlpError::InvalidQuantiles {
+                lower: *lower,
+                upper: *upper,
+            });
+        }
+
+        let mut clipped = Vec::new();
+        for &column in columns {
+            let values: &mut [f64] = match column {
+                DataColumn::Shares => self.shares.as_mut_slice(),
+                DataColumn::Matrix(kind, index) => {
+                    let matrix = match kind {
+                        DataMatrix::X1 => &mut self.x1,
+                        DataMatrix::X2 => &mut self.x2,
+                        DataMatrix::Instruments => &mut self.instruments,
+                    };
+                    if index >= matrix.ncols() {
+                        return Err(BlpError::dimension_mismatch(
+                            kind.name(),
+                            matrix.ncols(),
+                            index + 1,
+                        ));
+                    }
+                    let rows = matrix.nrows();
+                    &mut matrix.as_mut_slice()[index * rows..(index + 1) * rows]
+                }
+            };
+            let mut sorted = values.to_vec();
+            sorted.sort_by(f64::total_cmp);
+            let low = quantile(&sorted, *lower);
+            let high = quantile(&sorted, *upper);
+            for (row, value) in values.iter_mut().enumerate() {
+                let bounded = value.clamp(low, high);
+                if bounded != *value {
+                    clipped.push(WinsorizedValue {
+                        column,
+                        row,
+                        original: *value,
+                        clipped: bounded,
+                    });
+                    *value = bounded;
+                }
+            }
+        }
+        Ok(clipped)
+    }
+
+    /// Non-missing values of the cell's column within the same market.
+    fn market_values(&self, cell: &MissingCell<'_>) -> Option<Vec<f64>> {
+        let values: Vec<f64> = (0..self.market_ids.len())
+            .filter(|&row| self.market_ids[row] == cell.market_id)
+            .map(|row| cell.values[row])
+            .filter(|value| !value.is_nan())
+            .collect();
+        (!values.is_empty()).then_some(values)
+    }
+
+    /// Non-missing values of the cell's column for the same product in other markets.
+    fn product_values(&self, cell: &MissingCell<'_>) -> Option<Vec<f64>> {
+        let ids = self.product_ids.as_ref()?;
+        let product = cell.product_id?;
+        let values: Vec<f64> = (0..ids.len())
+            .filter(|&row| ids[row] == product && self.market_ids[row] != cell.market_id)
+            .map(|row| cell.values[row])
+            .filter(|value| !value.is_nan())
+            .collect();
+        (!values.is_empty()).then_some(values)
+    }
+
+    /// Detect rows sharing `(market id, product id)` and reject or merge them.
+    fn deduplicate(&mut self, policy: DuplicatePolicy) -> Result<Vec<MergedProduct>> {
+        let Some(product_ids) = &self.product_ids else {
+            return Ok(Vec::new());
+        };
+
+        let mut groups: Vec<Vec<usize>> = Vec::new();
+        let mut group_of: HashMap<(&str, &str), usize> = HashMap::new();
+        for (row, (market, product)) in self.market_ids.iter().zip(product_ids).enumerate() {
+            match group_of.get(&(market.as_str(), product.as_str())) {
+                Some(&group) => {
+                    if policy == DuplicatePolicy::Reject {
+                        return Err(BlpError::DuplicateProduct {
+                            market_id: market.clone(),
+                            product_id: product.clone(),
+                        });
+                    }
+                    groups[group].push(row);
+                }
+                None => {
+                    group_of.insert((market, product), groups.len());
+                    groups.push(vec![row]);
+                }
+            }
+        }
+        if groups.len() == self.market_ids.len() {
+            return Ok(Vec::new());
+        }
+        let DuplicatePolicy::Aggregate(rule) = policy else {
+            unreachable!("duplicates are rejected above");
+        };
+
+        let combine = |matrix: &DMatrix<f64>, rows: &[usize], shares: &DVector<f64>| {
+            let mut combined = DVector::zeros(matrix.ncols());
+            match rule {
+                AggregationRule::First => combined = matrix.row(rows[0]).transpose(),
+                AggregationRule::Mean => {
+                    for &row in rows {
+                        combined += matrix.row(row).transpose();
+                    }
+                    combined /= rows.len() as f64;
+                }
+                AggregationRule::ShareWeightedMean => {
+                    let total: f64 = rows.iter().map(|&row| shares[row]).sum();
+                    for &row in rows {
+                        combined += matrix.row(row).transpose() * (shares[row] / total);
+                    }
+                }
+            }
+            combined.transpose()
+        };
+
+        let m = groups.len();
+        let mut merged = Vec::new();
+        let mut shares = DVector::zeros(m);
+        let mut x1 = DMatrix::zeros(m, self.x1.ncols());
+        let mut x2 = DMatrix::zeros(m, self.x2.ncols());
+        let mut instruments = DMatrix::zeros(m, self.instruments.ncols());
+        for (target, rows) in groups.iter().enumerate() {
+            shares[target] = rows.iter().map(|&row| self.shares[row]).sum();
+            x1.set_row(target, &combine(&self.x1, rows, &self.shares));
+            x2.set_row(target, &combine(&self.x2, rows, &self.shares));
+            instruments.set_row(target, &combine(&self.instruments, rows, &self.shares));
+            if rows.len() > 1 {
+                merged.push(MergedProduct {
+                    market_id: self.market_ids[rows[0]].clone(),
+                    product_id: product_ids[rows[0]].clone(),
+                    source_rows: rows.clone(),
+                    share: shares[target],
+                });
+            }
+        }
+        for entry in &merged {
+            log::info!(
+                "merged {} rows of product `{}` in market `{}`",
+                entry.source_rows.len(),
+                entry.product_id,
+                entry.market_id
+            );
+        }
+
+        self.market_ids = groups
+            .iter()
+            .map(|rows| self.market_ids[rows[0]].clone())
+            .collect();
+        self.product_ids = Some(
+            groups
+                .iter()
+                .map(|rows| product_ids[rows[0]].clone())
+                .collect(),
+        );
+        if let Some(nesting_ids) = &self.nesting_ids {
+            self.nesting_ids = Some(
+                groups
+                    .iter()
+                    .map(|rows| nesting_ids[rows[0]].clone())
+                    .collect(),
+            );
+        }
+        if let Some(clustering_ids) = &self.clustering_ids {
+            self.clustering_ids = Some(
+                groups
+                    .iter()
+                    .map(|rows| clustering_ids[rows[0]].clone())
+                    .collect(),
+            );
+        }
+        for ids in &mut self.absorbed_ids {
+            *ids = groups.iter().map(|rows| ids[rows[0]].clone()).collect();
+        }
+        if let Some(weights) = &self.least_squares_weights {
+            self.least_squares_weights = Some(DVector::from_iterator(
+                groups.len(),
+                groups
+                    .iter()
+                    .map(|rows| rows.iter().map(|&row| weights[row]).sum()),
+            ));
+        }
+        self.shares = shares;
+        self.x1 = x1;
+        self.x2 = x2;
+        self.instruments = instruments;
+        Ok(merged)
+    }
+}
+
+fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+fn median(mut values: Vec<f64>) -> f64 {
+    values.sort_by(f64::total_cmp);
+    quantile(&values, 0.5)
+}
+
+/// Linearly interpolated quantile of an ascending, non-empty slice.
+pub(crate) fn quantile(sorted: &[f64], q: f64) -> f64 {
+    let position = q * (sorted.len() - 1) as f64;
+    let below = position.floor() as usize;
+    let above = position.ceil() as usize;
+    let fraction = position - below as f64;
+    sorted[below] + fraction * (sorted[above] - sorted[below])
+}
+
+/// Fixed-effect dimensions absorbed from the linear IV step.
+#[derive(Clone, Debug)]
+struct FixedEffects {
+    /// For each dimension, the group of every product.
+    groups: Vec<Vec<usize>>,
+    /// For each dimension, the number of products in every group.
+    sizes: Vec<Vec<f64>>,
+}
+
+/// Alternating projections stop once a full pass moves no group mean by more than this,
+/// relative to the largest value being demeaned.
+const DEMEANING_TOLERANCE: f64 = 1e-12;
+const DEMEANING_ITERATIONS: usize = 10_000;
+
+impl FixedEffects {
+    fn new(ids: &[Vec<String>]) -> Option<Self> {
+        if ids.is_empty() {
+            return None;
+        }
+        let mut groups = Vec::with_capacity(ids.len());
+        let mut sizes = Vec::with_capacity(ids.len());
+        for dimension in ids {
+            let mut distinct: Vec<&str> = dimension.iter().map(String::as_str).collect();
+            distinct.sort_unstable();
+            distinct.dedup();
+            let indices: Vec<usize> = dimension
+                .iter()
+                .map(|id| distinct.partition_point(|group| *group < id.as_str()))
+                .collect();
+            let mut counts = vec![0.0; distinct.len()];
+            for &group in &indices {
+                counts[group] += 1.0;
+            }
+            groups.push(indices);
+            sizes.push(counts);
+        }
+        Some(Self { groups, sizes })
+    }
+
+    /// Subtract the group means of every dimension until `values` is orthogonal to all of
+    /// the fixed effects; one pass is exact for a single dimension.
+    fn demean(&self, values: &mut [f64]) {
+        let scale = values
+            .iter()
+            .fold(0.0_f64, |max, value| max.max(value.abs()))
+            .max(f64::MIN_POSITIVE);
+        for _ in 0..DEMEANING_ITERATIONS {
+            let mut largest = 0.0_f64;
+            for (groups, sizes) in self.groups.iter().zip(&self.sizes) {
+                let mut means = vec![0.0; sizes.len()];
+                for (value, &group) in values.iter().zip(groups) {
+                    means[group] += value;
+                }
+                for (mean, size) in means.iter_mut().zip(sizes) {
+                    *mean /= size;
+                    largest = largest.max(mean.abs());
+                }
+                for (value, &group) in values.iter_mut().zip(groups) {
+                    *value -= means[group];
+                }
+            }
+            if self.groups.len() == 1 || largest <= DEMEANING_TOLERANCE * scale {
+                return;
+            }
+        }
+        log::warn!(
+            "fixed effect demeaning stopped after {DEMEANING_ITERATIONS} passes without converging"
+        );
+    }
+}
+
+/// `X2` rearranged so that each market's rows form one contiguous column-major block.
+///
+/// In the `N x K2` matrix a market's rows are strided by `N` across columns; packing
+/// them lets the per-market product `X2_m * tastes` read one dense `J_m x K2` block,
+/// which stays in cache while every consumer in a draw block is processed.
+#[derive(Clone, Debug)]
+pub(crate) struct PackedProducts {
+    values: Vec<f64>,
+    /// Start of each market's block in `values`, followed by the total length.
+    offsets: Vec<usize>,
+    rows: Vec<usize>,
+    columns: usize,
+}
+
+impl PackedProducts {
+    fn new(x2: &DMatrix<f64>, partition: &MarketPartition) -> Self {
+        let columns = x2.ncols();
+        let mut values = Vec::with_capacity(x2.len());
+        let mut offsets = Vec::with_capacity(partition.market_count() + 1);
+        let mut rows = Vec::with_capacity(partition.market_count());
+        for market in partition.markets() {
+            offsets.push(values.len());
+            rows.push(market.product_count());
+            for column in x2.column_iter() {
+                values.extend_from_slice(&column.as_slice()[market.range()]);
+            }
+        }
+        offsets.push(values.len());
+        Self {
+            values,
+            offsets,
+            rows,
+            columns,
+        }
+    }
+
+    /// Add the blocks of markets placed after the existing ones.
+    fn append(&mut self, other: &PackedProducts) {
+        let shift = self.values.len();
+        self.offsets.pop();
+        self.offsets
+            .extend(other.offsets.iter().map(|offset| offset + shift));
+        self.values.extend_from_slice(&other.values);
+        self.rows.extend_from_slice(&other.rows);
+    }
+
+    /// The `J_m x K2` block of the market at position `market` in the partition.
+    pub(crate) fn market(&self, market: usize) -> DMatrixView<'_, f64> {
+        let block = &self.values[self.offsets[market]..self.offsets[market + 1]];
+        DMatrixView::from_slice(block, self.rows[market], self.columns)
+    }
 }
 
 /// Describes the markets contained in the product data.
@@ -245,6 +2227,23 @@ impl MarketPartition {
         })
     }
 
+    /// Add the markets of `other`, whose products follow the existing ones.
+    fn append(&mut self, other: &MarketPartition) {
+        let (products, markets) = (self.product_to_market.len(), self.markets.len());
+        self.markets
+            .extend(other.markets.iter().map(|market| MarketSegment {
+                start: market.start + products,
+                end: market.end + products,
+                ..market.clone()
+            }));
+        self.product_to_market.extend(
+            other
+                .product_to_market
+                .iter()
+                .map(|market| market + markets),
+        );
+    }
+
     /// Returns the number of distinct markets.
     pub fn market_count(&self) -> usize {
         self.markets.len()
@@ -295,6 +2294,100 @@ impl MarketSegment {
 mod tests {
     use super::*;
 
+    #[test]
+    fn endogenous_columns_cannot_instrument_themselves() {
+        let market_ids = ["a", "a", "b"].map(String::from).to_vec();
+        let x1 = DMatrix::from_row_slice(3, 2, &[1.0, 2.0, 1.0, 3.0, 1.0, 2.5]);
+        let builder = ProductDataBuilder::new(market_ids, DVector::from_element(3, 0.2))
+            .x1(x1.clone())
+            .x1_labels(vec!["constant".into(), "price".into()])
+            .endogenous(vec![1]);
+
+        let err = builder.clone().build().unwrap_err();
+        assert!(matches!(err, BlpError::EndogenousInstrument { label } if label == "price"));
+        let err = builder.clone().instruments(x1.clone()).build().unwrap_err();
+        assert!(matches!(err, BlpError::EndogenousInstrument { .. }));
+
+        let cost = DMatrix::from_row_slice(3, 2, &[1.0, 0.4, 1.0, 0.9, 1.0, 0.7]);
+        let data = builder.instruments(cost).build().unwrap();
+        assert_eq!(data.endogenous_columns(), &[1]);
+        assert_eq!(
+            data.to_builder().build().unwrap().endogenous_columns(),
+            &[1]
+        );
+    }
+
+    #[test]
+    fn donor_instruments_join_on_keys_and_widen_the_moment_covariance() {
+        use crate::estimation::moment_covariance;
+        use crate::options::MomentCovariance;
+
+        let ids = |values: &[&str]| values.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+        let donor = DonorSample::new(
+            ids(&["a", "a", "a", "b", "b"]),
+            ids(&["p", "p", "q", "p", "q"]),
+            DMatrix::from_column_slice(5, 1, &[1.0, 3.0, 5.0, 4.0, 6.0]),
+        )
+        .unwrap()
+        .with_labels(vec!["input_price".into()])
+        .unwrap();
+        let builder =
+            ProductDataBuilder::new(ids(&["a", "a", "b", "b"]), DVector::from_element(4, 0.2))
+                .product_ids(ids(&["p", "q", "p", "q"]))
+                .x1(DMatrix::from_column_slice(4, 1, &[1.0, 2.0, 0.5, 1.5]))
+                .donor_instruments(donor.clone());
+        let data = builder.clone().build().unwrap();
+        assert_eq!(
+            data.linear_estimator(),
+            LinearEstimator::InstrumentalVariables
+        );
+        assert_eq!(data.labels().instruments, ["z_0", "input_price"]);
+        assert_eq!(
+            data.instruments().column(1).as_slice(),
+            &[2.0, 5.0, 4.0, 6.0]
+        );
+
+        // Only the first product has two donor rows: its mean has variance 2 / 2.
+        let xi = DVector::from_element(4, 2.0);
+        let covariance = moment_covariance(&data, &xi, MomentCovariance::Robust).unwrap();
+        let z = data.instruments();
+        let unadjusted = z.transpose() * DMatrix::from_diagonal(&xi.map(|e| e * e)) * z;
+        assert_eq!(covariance[(1, 1)] - unadjusted[(1, 1)], 4.0);
+        assert_eq!(covariance[(0, 0)], unadjusted[(0, 0)]);
+
+        let rebuilt = data.to_builder().build().unwrap();
+        assert_eq!(rebuilt.instruments(), data.instruments());
+        let subset = data.select_markets(&[1]).unwrap();
+        assert_eq!(subset.instrument_dim(), 2);
+
+        let unmatched = builder.product_ids(ids(&["p", "q", "p", "r"])).build();
+        assert!(matches!(
+            unmatched,
+            Err(BlpError::InconsistentSpecification { mismatches }) if mismatches.len() == 1
+        ));
+    }
+
+    #[test]
+    fn packed_products_hold_each_market_contiguously() {
+        let market_ids = ["a", "a", "b", "b", "b"].map(String::from).to_vec();
+        let x2 = DMatrix::from_fn(5, 2, |j, k| (10 * k + j) as f64);
+        let mut data = ProductDataBuilder::new(market_ids, DVector::from_element(5, 0.1))
+            .x1(DMatrix::from_element(5, 1, 1.0))
+            .x2(x2.clone())
+            .build()
+            .unwrap();
+        let second = data.packed_x2().market(1);
+        assert_eq!(second.shape(), (3, 2));
+        assert_eq!(second, x2.rows(2, 3));
+        assert_eq!(data.packed_x2().market(0), x2.rows(0, 2));
+
+        let column = DVector::from_element(5, -1.0);
+        data.set_column(DataMatrix::X2, 1, &column).unwrap();
+        assert_eq!(data.packed_x2().market(1).column(1), column.rows(2, 3));
+        let selected = data.select_nonlinear(&[1]).unwrap();
+        assert_eq!(selected.packed_x2().market(0).shape(), (2, 1));
+    }
+
     #[test]
     fn builder_validates_and_constructs_partition() {
         let market_ids = vec!["m1".to_string(), "m1".to_string(), "m2".to_string()];
@@ -319,6 +2412,140 @@ mod tests {
         assert!(iter.next().is_some());
     }
 
+    #[test]
+    fn duplicate_products_are_rejected_or_merged() {
+        let market_ids = vec!["m1".to_string(), "m1".to_string(), "m1".to_string()];
+        let product_ids = vec!["a".to_string(), "b".to_string(), "a".to_string()];
+        let shares = DVector::from_vec(vec![0.1, 0.2, 0.3]);
+        let x1 = DMatrix::from_row_slice(3, 1, &[10.0, 11.0, 14.0]);
+        let builder = ProductDataBuilder::new(market_ids, shares)
+            .product_ids(product_ids)
+            .x1(x1);
+
+        let rejected = builder.clone().build();
+        assert!(matches!(rejected, Err(BlpError::DuplicateProduct { .. })));
+
+        let data = builder
+            .duplicates(DuplicatePolicy::Aggregate(
+                AggregationRule::ShareWeightedMean,
+            ))
+            .build()
+            .unwrap();
+        assert_eq!(data.product_count(), 2);
+        assert!((data.shares()[0] - 0.4).abs() < 1e-12);
+        assert!((data.x1()[(0, 0)] - 13.0).abs() < 1e-12);
+        assert_eq!(data.merged_products()[0].source_rows, vec![0, 2]);
+    }
+
+    #[test]
+    fn missing_characteristics_are_imputed_or_rejected() {
+        let market_ids = vec!["m1".to_string(), "m1".to_string(), "m2".to_string()];
+        let product_ids = vec!["a".to_string(), "b".to_string(), "a".to_string()];
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.4]);
+        let x1 = DMatrix::from_row_slice(3, 1, &[f64::NAN, 4.0, 7.0]);
+        let builder = ProductDataBuilder::new(market_ids, shares)
+            .product_ids(product_ids)
+            .x1(x1);
+
+        let rejected = builder.clone().build();
+        assert!(matches!(
+            rejected,
+            Err(BlpError::MissingValue { row: 0, .. })
+        ));
+
+        let by_market = builder
+            .clone()
+            .impute(Imputation::MarketMean)
+            .build()
+            .unwrap();
+        assert_eq!(by_market.x1()[(0, 0)], 4.0);
+        let carried = builder
+            .clone()
+            .impute(Imputation::CarryAcrossMarkets)
+            .build()
+            .unwrap();
+        assert_eq!(carried.x1()[(0, 0)], 7.0);
+        assert_eq!(carried.instruments()[(0, 0)], 7.0);
+        let custom = builder
+            .impute(Imputation::Custom(Arc::new(|_| Some(1.5))))
+            .build()
+            .unwrap();
+        assert_eq!(custom.imputed_cells().len(), 2);
+    }
+
+    #[test]
+    fn winsorization_clips_to_quantiles() {
+        let market_ids = (0..5).map(|i| format!("m{i}")).collect();
+        let shares = DVector::from_vec(vec![0.1, 0.2, 0.3, 0.4, 0.5]);
+        let x1 = DMatrix::from_row_slice(5, 1, &[1.0, 2.0, 3.0, 4.0, 100.0]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .winsorize(Winsorization::new(
+                0.0,
+                0.75,
+                vec![DataColumn::Matrix(DataMatrix::X1, 0)],
+            ))
+            .build()
+            .unwrap();
+        assert_eq!(data.x1()[(4, 0)], 4.0);
+        assert_eq!(data.winsorized_values().len(), 1);
+        assert_eq!(data.winsorized_values()[0].original, 100.0);
+    }
+
+    #[test]
+    fn shared_columns_must_agree_and_labels_must_be_unique() {
+        let market_ids = ["m1", "m1", "m2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.4]);
+        let x1 = DMatrix::from_row_slice(3, 2, &[1.0, 10.0, 1.0, 12.0, 1.0, 11.0]);
+        let builder = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1.clone())
+            .x1_labels(vec!["const".into(), "price".into()]);
+
+        let data = builder.clone().x2_from_x1(vec![1]).build().unwrap();
+        assert_eq!(data.labels().x2, vec!["price"]);
+        assert_eq!(data.x2().column(0), x1.column(1));
+
+        let rescaled = builder
+            .clone()
+            .x2(x1.columns(1, 1) / 10.0)
+            .x2_labels(vec!["price".into()])
+            .build();
+        assert!(matches!(
+            rescaled,
+            Err(BlpError::InconsistentSharedColumn { label }) if label == "price"
+        ));
+        let doubled = builder
+            .x2(x1.clone())
+            .x2_labels(vec!["price".into(), "price".into()])
+            .build();
+        assert!(matches!(
+            doubled,
+            Err(BlpError::DuplicateLabel { matrix: "X2", .. })
+        ));
+    }
+
+    #[test]
+    fn error_components_append_group_dummies_to_x2() {
+        let market_ids = ["m1", "m1", "m1", "m2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.1, 0.2, 0.3, 0.4]);
+        let x1 = DMatrix::from_row_slice(4, 1, &[1.0, 2.0, 3.0, 4.0]);
+        let groups = ["sedan", "suv", "sedan", "suv"].map(String::from).to_vec();
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1.clone())
+            .x2(x1)
+            .x2_labels(vec!["price".into()])
+            .error_components(groups)
+            .build()
+            .unwrap();
+        assert_eq!(data.nonlinear_dim(), 3);
+        assert_eq!(
+            data.labels().x2,
+            vec!["price", "group[sedan]", "group[suv]"]
+        );
+        assert_eq!(data.x2().column(1).as_slice(), &[1.0, 0.0, 1.0, 0.0]);
+        assert_eq!(data.x2().column(2).as_slice(), &[0.0, 1.0, 0.0, 1.0]);
+    }
+
     #[test]
     fn builder_detects_non_contiguous_market() {
         let market_ids = vec!["m1".to_string(), "m2".to_string(), "m1".to_string()];
@@ -328,4 +2555,63 @@ mod tests {
         let result = ProductDataBuilder::new(market_ids, shares).x1(x1).build();
         assert!(matches!(result, Err(BlpError::NonContiguousMarket { .. })));
     }
+
+    #[test]
+    fn market_groups_resolve_fixed_effects_clusters_and_instruments() {
+        let market_ids = ["c1-y1", "c1-y1", "c2-y1", "c2-y1", "c1-y2", "c1-y2"]
+            .map(String::from)
+            .to_vec();
+        let years = ["y1", "y1", "y1", "y1", "y2", "y2"]
+            .map(String::from)
+            .to_vec();
+        let size = DMatrix::from_column_slice(6, 1, &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        let builder = ProductDataBuilder::new(market_ids.clone(), DVector::from_element(6, 0.2))
+            .x1(size)
+            .market_groups(years.clone());
+        let data = builder
+            .clone()
+            .absorb_level(MarketLevel::Group)
+            .cluster_by(MarketLevel::Market)
+            .build()
+            .unwrap();
+
+        assert_eq!(data.market_groups().unwrap(), ["y1", "y1", "y2"]);
+        assert_eq!(data.level_ids(MarketLevel::Group).unwrap(), years);
+        assert_eq!(data.absorbed_ids(), [years]);
+        assert_eq!(data.clustering_ids().unwrap(), market_ids);
+        let groups = data.market_group_partition().unwrap();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(
+            (groups[0].markets.clone(), groups[0].products.clone()),
+            (vec![0, 1], vec![0, 1, 2, 3])
+        );
+        assert_eq!(
+            data.select_markets(&[1, 2])
+                .unwrap()
+                .market_groups()
+                .unwrap(),
+            ["y1", "y2"]
+        );
+
+        // Rival sums run over every city of the year.
+        let firms = ["a", "b", "a", "b", "a", "b"].map(String::from);
+        let (sums, _) =
+            crate::instruments::blp_instruments_by_level(&data, &firms, &[0], MarketLevel::Group)
+                .unwrap();
+        assert_eq!(sums.row(0).iter().copied().collect::<Vec<_>>(), [3.0, 6.0]);
+        assert_eq!(sums.row(5).iter().copied().collect::<Vec<_>>(), [0.0, 5.0]);
+
+        let split = ["y1", "y2", "y1", "y1", "y2", "y2"]
+            .map(String::from)
+            .to_vec();
+        let err = builder.clone().market_groups(split).build().unwrap_err();
+        assert!(
+            matches!(err, BlpError::InconsistentSpecification { mismatches } if mismatches.len() == 1)
+        );
+        let ungrouped = ProductDataBuilder::new(market_ids, DVector::from_element(6, 0.2))
+            .x1(DMatrix::from_element(6, 1, 1.0))
+            .cluster_by(MarketLevel::Group)
+            .build();
+        assert!(matches!(ungrouped, Err(BlpError::MissingComponent { .. })));
+    }
 }
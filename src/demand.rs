@@ -1,11 +1,152 @@
-//! Demand-side primitives: share prediction and the BLP contraction mapping.
+//! Demand-side primitives: share prediction, the BLP contraction mapping, and a
+//! single-market [`MarketSolver`] for working with one market in isolation.
 
-use nalgebra::{DMatrix, DVector};
+use std::ops::Range;
+use std::time::Instant;
 
-use crate::data::ProductData;
+use nalgebra::{DMatrix, DMatrixViewMut, DVector};
+use rayon::prelude::*;
+
+use crate::data::{ProductData, ProductDataBuilder, RandomCoefficientType};
 use crate::error::{BlpError, Result};
 use crate::integration::SimulationDraws;
-use crate::solving::{ContractionOptions, ContractionSummary};
+use crate::models::{DemandModel, RandomCoefficientsLogit, nested_jacobian};
+use crate::parameters::{NonlinearParameters, is_diagonal};
+use crate::solving::{
+    ContractionOptions, ContractionSummary, ConvergenceCriterion, FixedPointOperator,
+    ToleranceScaling,
+};
+
+/// Everything besides `delta` that is needed to predict market shares.
+///
+/// Downstream code should prefer [`predict_shares_with`] over the positional
+/// [`predict_shares`]: new model components are added here as optional settings,
+/// so call sites built on `ShareInputs` keep compiling as the model grows.
+#[derive(Clone, Copy, Debug)]
+pub struct ShareInputs<'a> {
+    data: &'a ProductData,
+    draws: &'a SimulationDraws,
+    sigma: &'a DMatrix<f64>,
+    pi: Option<&'a DMatrix<f64>>,
+    rho: Option<f64>,
+    availability: Option<&'a DMatrix<f64>>,
+    offsets: Option<&'a DMatrix<f64>>,
+    initial_delta: Option<&'a DVector<f64>>,
+    options: &'a ContractionOptions,
+}
+
+impl<'a> ShareInputs<'a> {
+    /// Bundle the product data, draws, nonlinear parameters, and numerical options.
+    pub fn new(
+        data: &'a ProductData,
+        draws: &'a SimulationDraws,
+        sigma: &'a DMatrix<f64>,
+        options: &'a ContractionOptions,
+    ) -> Self {
+        Self {
+            data,
+            draws,
+            sigma,
+            pi: None,
+            rho: None,
+            availability: None,
+            offsets: None,
+            initial_delta: None,
+            options,
+        }
+    }
+
+    /// Bundle the inputs for every parameter in `parameters`: `sigma`, plus `pi` and
+    /// `rho` when they are set.
+    pub fn for_parameters(
+        data: &'a ProductData,
+        draws: &'a SimulationDraws,
+        parameters: &'a NonlinearParameters,
+        options: &'a ContractionOptions,
+    ) -> Self {
+        let mut inputs = Self::new(data, draws, parameters.sigma(), options);
+        inputs.pi = parameters.pi();
+        inputs.rho = parameters.rho();
+        inputs
+    }
+
+    /// Interact `X2` with the draws' demographics through `pi` (`K2 x D`).
+    pub fn with_pi(mut self, pi: &'a DMatrix<f64>) -> Self {
+        self.pi = Some(pi);
+        self
+    }
+
+    /// Correlate tastes within nests by `rho`.
+    pub fn with_rho(mut self, rho: f64) -> Self {
+        self.rho = Some(rho);
+        self
+    }
+
+    /// Restrict choice sets with an `N x R` matrix whose entry `(j, r)` is one when
+    /// product `j` is available to simulated consumer `r` and zero otherwise.
+    pub fn with_availability(mut self, availability: &'a DMatrix<f64>) -> Self {
+        self.availability = Some(availability);
+        self
+    }
+
+    /// Add an `N x R` matrix of consumer-specific utilities, entry `(j, r)` for product
+    /// `j` and simulated consumer `r`, on top of `delta` and the random coefficients.
+    pub fn with_offsets(mut self, offsets: &'a DMatrix<f64>) -> Self {
+        self.offsets = Some(offsets);
+        self
+    }
+
+    /// Start the inversion from `delta` instead of the plain-logit inversion.
+    pub fn with_initial_delta(mut self, delta: &'a DVector<f64>) -> Self {
+        self.initial_delta = Some(delta);
+        self
+    }
+
+    /// Product data the shares are predicted for.
+    pub fn data(&self) -> &'a ProductData {
+        self.data
+    }
+
+    /// Simulation draws used to integrate over consumer heterogeneity.
+    pub fn draws(&self) -> &'a SimulationDraws {
+        self.draws
+    }
+
+    /// Nonlinear parameters.
+    pub fn sigma(&self) -> &'a DMatrix<f64> {
+        self.sigma
+    }
+
+    /// Optional demographic interactions.
+    pub fn pi(&self) -> Option<&'a DMatrix<f64>> {
+        self.pi
+    }
+
+    /// Optional nesting parameter.
+    pub fn rho(&self) -> Option<f64> {
+        self.rho
+    }
+
+    /// Optional consumer-specific product availability.
+    pub fn availability(&self) -> Option<&'a DMatrix<f64>> {
+        self.availability
+    }
+
+    /// Optional consumer-specific utilities.
+    pub fn offsets(&self) -> Option<&'a DMatrix<f64>> {
+        self.offsets
+    }
+
+    /// Optional warm start for the inversion.
+    pub fn initial_delta(&self) -> Option<&'a DVector<f64>> {
+        self.initial_delta
+    }
+
+    /// Numerical options (share floor) applied during prediction.
+    pub fn options(&self) -> &'a ContractionOptions {
+        self.options
+    }
+}
 
 /// Computes model-implied product shares given mean utilities `delta` and
 /// nonlinear parameters `sigma`.
@@ -16,6 +157,18 @@ pub fn predict_shares(
     draws: &SimulationDraws,
     options: &ContractionOptions,
 ) -> Result<DVector<f64>> {
+    predict_shares_with(delta, &ShareInputs::new(data, draws, sigma, options))
+}
+
+/// Computes model-implied product shares at `delta` for the model described by `inputs`.
+pub fn predict_shares_with(delta: &DVector<f64>, inputs: &ShareInputs<'_>) -> Result<DVector<f64>> {
+    let ShareInputs {
+        data,
+        draws,
+        availability,
+        options,
+        ..
+    } = *inputs;
     let n = delta.len();
     if n != data.product_count() {
         return Err(BlpError::dimension_mismatch(
@@ -26,10 +179,147 @@ pub fn predict_shares(
     }
 
     let k2 = data.nonlinear_dim();
-    if k2 == 0 {
+    if k2 == 0
+        && availability.is_none()
+        && inputs.pi.is_none()
+        && inputs.rho.is_none()
+        && inputs.offsets.is_none()
+    {
         return predict_simple_logit(delta, data, options);
     }
 
+    let probabilities = individual_shares(delta, inputs)?;
+    let mut predicted = DVector::zeros(n);
+    for (draw_index, weight) in draws.weights().iter().enumerate() {
+        for product_index in 0..n {
+            let probability = probabilities[(product_index, draw_index)];
+            if probability == 0.0 {
+                continue;
+            }
+            let share = *weight * probability;
+            if share < options.minimum_share {
+                return Err(BlpError::NumericalError {
+                    context: "predicted share underflow",
+                });
+            }
+            predicted[product_index] += share;
+        }
+    }
+
+    Ok(predicted)
+}
+
+/// `sigma nu'`, one column per draw. A diagonal `sigma` scales each row of `nu'` by its
+/// standard deviation instead of forming the full product.
+fn random_tastes(sigma: &DMatrix<f64>, nu: &DMatrix<f64>) -> DMatrix<f64> {
+    if is_diagonal(sigma) {
+        DMatrix::from_fn(sigma.nrows(), nu.nrows(), |k, r| sigma[(k, k)] * nu[(r, k)])
+    } else {
+        sigma * nu.transpose()
+    }
+}
+
+/// Replace the taste shifts `t` (`K2 x R`) of lognormal coefficients by `exp(t)`.
+pub(crate) fn exponentiate_log_tastes(data: &ProductData, tastes: &mut DMatrix<f64>) {
+    for (k, rc_type) in data.rc_types().iter().enumerate() {
+        if *rc_type == RandomCoefficientType::Log {
+            tastes.row_mut(k).apply(|taste| *taste = taste.exp());
+        }
+    }
+}
+
+/// Every consumer's random coefficients, `K2 x R`, at `sigma` and `pi`.
+pub(crate) fn consumer_tastes(
+    data: &ProductData,
+    draws: &SimulationDraws,
+    sigma: &DMatrix<f64>,
+    pi: Option<&DMatrix<f64>>,
+) -> DMatrix<f64> {
+    let mut tastes = random_tastes(sigma, draws.draws());
+    if let Some(pi) = pi
+        && let Some(demographics) = draws.demographics()
+    {
+        tastes += pi * demographics.transpose();
+    }
+    exponentiate_log_tastes(data, &mut tastes);
+    tastes
+}
+
+/// `d coefficient / d taste shift` of every consumer (`K2 x R`): one for linear
+/// coefficients and the coefficient itself for lognormal ones. `None` when every
+/// coefficient is linear.
+pub(crate) fn taste_slopes(inputs: &ShareInputs<'_>) -> Option<DMatrix<f64>> {
+    let data = inputs.data();
+    if !data.rc_types().contains(&RandomCoefficientType::Log) {
+        return None;
+    }
+    let mut slopes = consumer_tastes(data, inputs.draws(), inputs.sigma(), inputs.pi());
+    for (k, rc_type) in data.rc_types().iter().enumerate() {
+        if *rc_type == RandomCoefficientType::Linear {
+            slopes.row_mut(k).fill(1.0);
+        }
+    }
+    Some(slopes)
+}
+
+/// Choice probabilities of each simulated consumer: an `N x R` matrix whose column `r`
+/// holds the logit probabilities of consumer `r` over the products in each market, or
+/// the [`NestedLogit`](crate::models::NestedLogit) probabilities when
+/// [`ShareInputs::rho`] is set.
+///
+/// Integration weights are *not* applied; aggregate shares are the weighted row sums.
+/// Blocks of consumers are processed in parallel on the current rayon pool, so a single
+/// large market is spread across threads as well.
+pub fn individual_shares(delta: &DVector<f64>, inputs: &ShareInputs<'_>) -> Result<DMatrix<f64>> {
+    let ShareInputs {
+        data,
+        draws,
+        sigma,
+        pi,
+        rho,
+        availability,
+        offsets,
+        ..
+    } = *inputs;
+    let n = data.product_count();
+    if delta.len() != n {
+        return Err(BlpError::dimension_mismatch("delta length", n, delta.len()));
+    }
+    let nesting = match rho {
+        Some(rho) => {
+            if !(0.0..1.0).contains(&rho) {
+                return Err(BlpError::InvalidNestingParameter { rho });
+            }
+            if data.nesting_ids().is_none() {
+                return Err(BlpError::missing_component("nesting ids"));
+            }
+            if availability.is_some() {
+                return Err(BlpError::Unsupported {
+                    operation: "consumer-specific availability with nesting",
+                });
+            }
+            Some((data.nest_indices(), rho))
+        }
+        None => None,
+    };
+    if let Some(availability) = availability {
+        if availability.nrows() != n {
+            return Err(BlpError::dimension_mismatch(
+                "availability rows",
+                n,
+                availability.nrows(),
+            ));
+        }
+        if availability.ncols() != draws.draw_count() {
+            return Err(BlpError::dimension_mismatch(
+                "availability columns",
+                draws.draw_count(),
+                availability.ncols(),
+            ));
+        }
+    }
+
+    let k2 = data.nonlinear_dim();
     if sigma.nrows() != k2 || sigma.ncols() != k2 {
         return Err(BlpError::dimension_mismatch(
             "sigma dimension",
@@ -45,45 +335,208 @@ pub fn predict_shares(
         ));
     }
 
-    let mut predicted = DVector::zeros(n);
-    let draws_matrix = draws.draws();
-    let weights = draws.weights();
-
-    for (draw_index, weight) in weights.iter().enumerate() {
-        let draw = draws_matrix.row(draw_index).transpose();
-        let taste = sigma * draw;
-
-        for market in data.partition().markets() {
-            let range = market.range();
-            let mut exp_utilities = Vec::with_capacity(range.len());
-            let mut denominator = 1.0_f64;
-
-            for product_index in range.clone() {
-                let mu = data.x2().row(product_index).dot(&taste);
-                let utility = delta[product_index] + mu;
-                let exp_u = utility.exp();
-                if !exp_u.is_finite() {
-                    return Err(BlpError::NumericalError {
-                        context: "utility exponentiation",
-                    });
+    let mut tastes = random_tastes(sigma, draws.draws());
+    if let Some(pi) = pi {
+        let demographics = draws
+            .demographics()
+            .ok_or_else(|| BlpError::missing_component("demographics"))?;
+        if pi.nrows() != k2 {
+            return Err(BlpError::dimension_mismatch("pi rows", k2, pi.nrows()));
+        }
+        if pi.ncols() != demographics.ncols() {
+            return Err(BlpError::dimension_mismatch(
+                "pi columns",
+                demographics.ncols(),
+                pi.ncols(),
+            ));
+        }
+        tastes += pi * demographics.transpose();
+    }
+    exponentiate_log_tastes(data, &mut tastes);
+
+    // Entry (j, r) of the taste shifts is x2_j' * (sigma * nu_r + pi * d_r), with the
+    // lognormal coefficients exponentiated. Each block of
+    // consumers multiplies every market's packed X2 block by their columns of tastes, so
+    // the products are read contiguously and the result lands in the contiguous slices
+    // of each consumer's column that the softmax below works on.
+    let draw_count = draws.draw_count();
+    let mut probabilities = match offsets {
+        Some(offsets) => {
+            if offsets.shape() != (n, draw_count) {
+                return Err(BlpError::dimension_mismatch(
+                    "utility offset rows",
+                    n,
+                    offsets.nrows(),
+                ));
+            }
+            offsets.clone()
+        }
+        None => DMatrix::zeros(n, draw_count),
+    };
+    let packed = data.packed_x2();
+    let markets: Vec<_> = data.partition().markets().map(|m| m.range()).collect();
+    probabilities
+        .as_mut_slice()
+        .par_chunks_mut(n * DRAW_BLOCK)
+        .enumerate()
+        .try_for_each(|(block_index, block)| {
+            let first_draw = block_index * DRAW_BLOCK;
+            let width = block.len() / n.max(1);
+            if k2 > 0 {
+                let block_tastes = tastes.columns(first_draw, width);
+                let mut shifts = DMatrixViewMut::from_slice(block, n, width);
+                for (market, products) in markets.iter().enumerate() {
+                    shifts.rows_mut(products.start, products.len()).gemm(
+                        1.0,
+                        &packed.market(market),
+                        &block_tastes,
+                        1.0,
+                    );
                 }
-                exp_utilities.push(exp_u);
-                denominator += exp_u;
             }
-
-            for (offset, product_index) in range.enumerate() {
-                let share = *weight * exp_utilities[offset] / denominator;
-                if share < options.minimum_share {
-                    return Err(BlpError::NumericalError {
-                        context: "predicted share underflow",
-                    });
+            for (offset, column) in block.chunks_mut(n).enumerate() {
+                let draw_index = first_draw + offset;
+                for products in &markets {
+                    let utilities = &mut column[products.clone()];
+                    match nesting {
+                        Some((nests, rho)) => {
+                            for (utility, mean) in utilities
+                                .iter_mut()
+                                .zip(&delta.as_slice()[products.clone()])
+                            {
+                                *utility += mean;
+                            }
+                            nested_softmax(utilities, &nests[products.clone()], rho)?;
+                        }
+                        None => {
+                            softmax(utilities, delta, availability, products.start, draw_index)?
+                        }
+                    }
                 }
-                predicted[product_index] += share;
             }
+            Ok(())
+        })?;
+
+    Ok(probabilities)
+}
+
+/// Number of consumers handled together by one task when computing choice probabilities
+/// or accumulating large Jacobians.
+pub(crate) const DRAW_BLOCK: usize = 64;
+
+/// Replace one consumer's taste shifts over a market's products (rows `first..` of the
+/// product data) with their logit choice probabilities.
+fn softmax(
+    utilities: &mut [f64],
+    delta: &DVector<f64>,
+    availability: Option<&DMatrix<f64>>,
+    first: usize,
+    draw_index: usize,
+) -> Result<()> {
+    if let [utility] = utilities {
+        // A lone product has logistic probabilities, which stay finite for any
+        // utility instead of overflowing through exp(u).
+        let available = availability.map_or(1.0, |a| a[(first, draw_index)]);
+        *utility = if available == 0.0 {
+            0.0
+        } else {
+            1.0 / (1.0 + (-(delta[first] + *utility)).exp() / available)
+        };
+        return Ok(());
+    }
+    let mut denominator = 1.0_f64;
+    for (offset, utility) in utilities.iter_mut().enumerate() {
+        let product_index = first + offset;
+        let available = availability.map_or(1.0, |a| a[(product_index, draw_index)]);
+        let exp_u = if available == 0.0 {
+            0.0
+        } else {
+            available * (delta[product_index] + *utility).exp()
+        };
+        if !exp_u.is_finite() {
+            return Err(BlpError::NumericalError {
+                context: "utility exponentiation",
+            });
         }
+        *utility = exp_u;
+        denominator += exp_u;
     }
+    for probability in utilities.iter_mut() {
+        *probability /= denominator;
+    }
+    Ok(())
+}
 
-    Ok(predicted)
+/// Replace one consumer's utilities over a market's products with nested logit choice
+/// probabilities, where `nests[j]` is the group of product `j` and `rho` the within-nest
+/// correlation. The outside good forms its own nest with utility zero.
+///
+/// Product `j` in nest `h` is chosen with probability `s_{j|h} s_h`, where
+/// `s_{j|h} = exp(V_j / (1 - rho)) / D_h`, `D_h = sum_{k in h} exp(V_k / (1 - rho))`, and
+/// `s_h = D_h^{1 - rho} / (1 + sum_g D_g^{1 - rho})`. Both sums are taken relative to
+/// their largest term, so large utilities do not overflow.
+pub(crate) fn nested_softmax(utilities: &mut [f64], nests: &[usize], rho: f64) -> Result<()> {
+    let scale = 1.0 - rho;
+    // (nest, largest scaled utility, sum of exponentials relative to it)
+    let mut groups: Vec<(usize, f64, f64)> = Vec::new();
+    for (utility, &nest) in utilities.iter().zip(nests) {
+        if !utility.is_finite() {
+            return Err(BlpError::NumericalError {
+                context: "utility exponentiation",
+            });
+        }
+        let scaled = utility / scale;
+        match groups.iter_mut().find(|(group, ..)| *group == nest) {
+            Some((_, largest, sum)) if scaled > *largest => {
+                *sum = *sum * (*largest - scaled).exp() + 1.0;
+                *largest = scaled;
+            }
+            Some((_, largest, sum)) => *sum += (scaled - *largest).exp(),
+            None => groups.push((nest, scaled, 1.0)),
+        }
+    }
+    let inclusive: Vec<f64> = groups
+        .iter()
+        .map(|(_, largest, sum)| scale * (largest + sum.ln()))
+        .collect();
+    let top = inclusive.iter().copied().fold(0.0_f64, f64::max);
+    let denominator = (-top).exp() + inclusive.iter().map(|i| (i - top).exp()).sum::<f64>();
+    for (utility, &nest) in utilities.iter_mut().zip(nests) {
+        let group = groups
+            .iter()
+            .position(|(candidate, ..)| *candidate == nest)
+            .expect("every nest was visited above");
+        let (_, largest, sum) = groups[group];
+        let within = (*utility / scale - largest).exp() / sum;
+        *utility = within * (inclusive[group] - top).exp() / denominator;
+    }
+    Ok(())
+}
+
+/// Expected inclusive value `E_r[ln(1 + sum_j exp(V_jrt))]` of each market, in utils.
+///
+/// This is consumer surplus per consumer up to the constant of integration, measured in
+/// utility units; divide by the (negative of the) marginal utility of income to convert
+/// it to money. Differences across counterfactuals are the usual welfare measure.
+pub fn inclusive_values(delta: &DVector<f64>, inputs: &ShareInputs<'_>) -> Result<DVector<f64>> {
+    let probabilities = individual_shares(delta, inputs)?;
+    let weights = inputs.draws().weights();
+    let markets = inputs.data().partition();
+    let mut values = DVector::zeros(markets.market_count());
+
+    for (market_index, market) in markets.markets().enumerate() {
+        for (draw_index, weight) in weights.iter().enumerate() {
+            // ln(1 + sum exp(V)) = -ln(s_0); summing the inside probabilities is exact
+            // enough here because s_0 only becomes tiny when the value is large.
+            let inside: f64 = market
+                .range()
+                .map(|product_index| probabilities[(product_index, draw_index)])
+                .sum();
+            values[market_index] -= weight * (1.0 - inside).ln();
+        }
+    }
+
+    Ok(values)
 }
 
 fn predict_simple_logit(
@@ -131,58 +584,626 @@ pub fn solve_delta(
     sigma: &DMatrix<f64>,
     options: &ContractionOptions,
 ) -> Result<(DVector<f64>, ContractionSummary)> {
-    let n = data.product_count();
-    let mut delta = DVector::zeros(n);
+    let inputs = ShareInputs::new(data, draws, sigma, options);
+    invert_model(&RandomCoefficientsLogit, &inputs)
+}
+
+/// Runs the BLP contraction `delta <- delta + damping * ln(s / s(delta))` for an arbitrary
+/// share map, starting from the plain-logit inversion `ln(s_j) - ln(s_0)`.
+///
+/// This is the inversion shared by every [`DemandModel`] that
+/// does not provide a closed form. When [`ContractionOptions::solver`] is set, the
+/// [`ContractionOperator`] is handed to that solver instead of being iterated directly.
+pub fn contract<F>(
+    data: &ProductData,
+    options: &ContractionOptions,
+    shares: F,
+) -> Result<(DVector<f64>, ContractionSummary)>
+where
+    F: FnMut(&DVector<f64>) -> Result<DVector<f64>>,
+{
+    contract_from(data, options, None, shares)
+}
 
-    // Initialize using the standard log share ratio: delta = log(s_j) - log(s_0)
-    for (product_index, share) in data.shares().iter().enumerate() {
-        let outside = data.outside_share_for_product(product_index);
-        delta[product_index] = (share / outside).ln();
+/// [`contract`] started from `initial` when given, e.g. the delta of a previous solve.
+pub fn contract_from<F>(
+    data: &ProductData,
+    options: &ContractionOptions,
+    initial: Option<&DVector<f64>>,
+    shares: F,
+) -> Result<(DVector<f64>, ContractionSummary)>
+where
+    F: FnMut(&DVector<f64>) -> Result<DVector<f64>>,
+{
+    let initial = match initial {
+        Some(delta) if delta.len() != data.product_count() => {
+            return Err(BlpError::dimension_mismatch(
+                "initial delta length",
+                data.product_count(),
+                delta.len(),
+            ));
+        }
+        Some(delta) => delta.clone(),
+        None => logit_inversion(data),
+    };
+    match &options.solver {
+        Some(solver) => {
+            let started = Instant::now();
+            let mut operator = ContractionOperator::new(data, options, shares);
+            let (delta, mut summary) = solver.solve(&mut operator, initial, options)?;
+            summary.max_share_error = operator.share_error(&delta)?;
+            summary.seconds = started.elapsed().as_secs_f64();
+            summary.share_evaluations = operator.evaluations();
+            Ok((delta, summary))
+        }
+        None => DeltaSolver::new(data, options, shares)
+            .with_initial_delta(initial)?
+            .solve(),
     }
+}
 
-    let mut max_gap = f64::INFINITY;
-    let mut iteration = 0usize;
+/// Invert `model` at `inputs`: in one piece, or one market at a time under a
+/// [`cpu_budget`](ContractionOptions::cpu_budget).
+///
+/// The budget is split across markets in proportion to their products, and each
+/// market's contraction is timed on its own and stops when its share runs out. Time left
+/// over by markets that converge early is then handed to the truncated ones, which
+/// continue from where they stopped, so a few stubborn markets cannot crowd out the rest.
+/// Markets still moving after that are listed in
+/// [`ContractionSummary::truncated_markets`].
+pub(crate) fn invert_model(
+    model: &dyn DemandModel,
+    inputs: &ShareInputs<'_>,
+) -> Result<(DVector<f64>, ContractionSummary)> {
+    let Some(budget) = inputs.options.cpu_budget else {
+        return model.invert(inputs);
+    };
+    let started = Instant::now();
+    let data = inputs.data;
+    let n = data.product_count().max(1) as f64;
+    let markets: Vec<Range<usize>> = data.partition().markets().map(|m| m.range()).collect();
+    let share =
+        |rows: &Range<usize>, total: f64, products: f64| total * rows.len() as f64 / products;
+    let mut solved: Vec<(DVector<f64>, ContractionSummary)> = markets
+        .par_iter()
+        .map(|rows| {
+            let initial = inputs
+                .initial_delta
+                .map(|delta| delta.rows(rows.start, rows.len()).into_owned());
+            invert_market(model, inputs, rows.clone(), share(rows, budget, n), initial)
+        })
+        .collect::<Result<_>>()?;
 
-    while iteration < options.max_iterations {
-        let predicted = predict_shares(&delta, data, sigma, draws, options)?;
-        max_gap = 0.0;
+    let leftover: f64 = markets
+        .iter()
+        .zip(&solved)
+        .filter(|(_, (_, summary))| !summary.is_truncated())
+        .map(|(rows, (_, summary))| (share(rows, budget, n) - summary.seconds).max(0.0))
+        .sum();
+    let stragglers: f64 = markets
+        .iter()
+        .zip(&solved)
+        .filter(|(_, (_, summary))| summary.is_truncated())
+        .map(|(rows, _)| rows.len() as f64)
+        .sum();
+    if leftover > 0.0 && stragglers > 0.0 {
+        let resumed: Vec<Option<(DVector<f64>, ContractionSummary)>> = markets
+            .par_iter()
+            .zip(solved.par_iter())
+            .map(|(rows, (delta, summary))| {
+                if !summary.is_truncated() {
+                    return Ok(None);
+                }
+                let extra = share(rows, leftover, stragglers);
+                invert_market(model, inputs, rows.clone(), extra, Some(delta.clone())).map(Some)
+            })
+            .collect::<Result<_>>()?;
+        for ((delta, summary), resumed) in solved.iter_mut().zip(resumed) {
+            if let Some((next, more)) = resumed {
+                *delta = next;
+                summary.iterations += more.iterations;
+                summary.share_evaluations += more.share_evaluations;
+                summary.seconds += more.seconds;
+                summary.max_gap = more.max_gap;
+                summary.max_share_error = more.max_share_error;
+                summary.truncated_markets = more.truncated_markets;
+                summary.criterion = more.criterion;
+            }
+        }
+    }
 
-        for product_index in 0..n {
-            let observed = data.shares()[product_index];
+    let mut delta = DVector::zeros(data.product_count());
+    let mut total = ContractionSummary {
+        max_gap: 0.0,
+        criterion: solved.first().and_then(|(_, summary)| summary.criterion),
+        ..Default::default()
+    };
+    for (rows, (market_delta, summary)) in markets.iter().zip(solved) {
+        delta
+            .rows_mut(rows.start, rows.len())
+            .copy_from(&market_delta);
+        total.iterations = total.iterations.max(summary.iterations);
+        total.max_gap = total.max_gap.max(summary.max_gap);
+        total.max_share_error = total.max_share_error.max(summary.max_share_error);
+        total.share_evaluations += summary.share_evaluations;
+        if total.criterion != summary.criterion {
+            total.criterion = None;
+        }
+        total.truncated_markets.extend(summary.truncated_markets);
+    }
+    total.seconds = started.elapsed().as_secs_f64();
+    Ok((delta, total))
+}
+
+/// Invert `model` on the products in `rows` alone, within `budget` seconds and from
+/// `initial` when given.
+fn invert_market(
+    model: &dyn DemandModel,
+    inputs: &ShareInputs<'_>,
+    rows: Range<usize>,
+    budget: f64,
+    initial: Option<DVector<f64>>,
+) -> Result<(DVector<f64>, ContractionSummary)> {
+    let data = inputs.data.market_products(rows.clone())?;
+    let select = |matrix: &DMatrix<f64>| matrix.rows(rows.start, rows.len()).into_owned();
+    let availability = inputs.availability.map(select);
+    let offsets = inputs.offsets.map(select);
+    let options = ContractionOptions {
+        cpu_budget: Some(budget),
+        ..inputs.options.clone()
+    };
+    let market = ShareInputs {
+        data: &data,
+        availability: availability.as_ref(),
+        offsets: offsets.as_ref(),
+        initial_delta: initial.as_ref(),
+        options: &options,
+        ..*inputs
+    };
+    let selected = model.select_products(rows);
+    selected.as_deref().unwrap_or(model).invert(&market)
+}
+
+/// Plain-logit inversion `ln(s_j) - ln(s_0)`, the starting point of the contraction.
+pub(crate) fn logit_inversion(data: &ProductData) -> DVector<f64> {
+    DVector::from_fn(data.product_count(), |product_index, _| {
+        (data.shares()[product_index] / data.outside_share_for_product(product_index)).ln()
+    })
+}
+
+/// Per-product multipliers applied to the update before the tolerance check.
+fn tolerance_weights(data: &ProductData, scaling: ToleranceScaling) -> DVector<f64> {
+    let shares = data.shares();
+    let mut weights = match scaling {
+        ToleranceScaling::Uniform => return DVector::from_element(shares.len(), 1.0),
+        ToleranceScaling::ProductShare => shares.clone(),
+        ToleranceScaling::MarketShare => {
+            let mut weights = DVector::zeros(shares.len());
+            for market in data.partition().markets() {
+                let range = market.range();
+                let inside = shares.rows(range.start, range.len()).sum();
+                weights.rows_mut(range.start, range.len()).fill(inside);
+            }
+            weights
+        }
+    };
+    let largest = weights.max();
+    if largest > 0.0 {
+        weights /= largest;
+    }
+    weights
+}
+
+/// The BLP contraction `T(delta) = delta + damping * ln(s / s(delta))` as a
+/// [`FixedPointOperator`], for use with external acceleration schemes.
+pub struct ContractionOperator<'a, F> {
+    data: &'a ProductData,
+    options: &'a ContractionOptions,
+    shares: F,
+    share_residual: f64,
+    evaluations: usize,
+}
+
+impl<'a, F> ContractionOperator<'a, F>
+where
+    F: FnMut(&DVector<f64>) -> Result<DVector<f64>>,
+{
+    /// Wrap a share map for the observed shares in `data`.
+    pub fn new(data: &'a ProductData, options: &'a ContractionOptions, shares: F) -> Self {
+        Self {
+            data,
+            options,
+            shares,
+            share_residual: f64::INFINITY,
+            evaluations: 0,
+        }
+    }
+
+    /// `max_j |s_j - s_j(delta)|` at the `delta` most recently passed to
+    /// [`apply`](FixedPointOperator::apply) (infinite before the first call).
+    pub fn share_residual(&self) -> f64 {
+        self.share_residual
+    }
+
+    /// Number of share predictions made so far.
+    pub fn evaluations(&self) -> usize {
+        self.evaluations
+    }
+
+    /// `max_j |s_j - s_j(delta)|` from one more share prediction at `delta`, counted in
+    /// [`evaluations`](Self::evaluations) but not stored as the share residual.
+    pub(crate) fn share_error(&mut self, delta: &DVector<f64>) -> Result<f64> {
+        let predicted = (self.shares)(delta)?;
+        self.evaluations += 1;
+        Ok((self.data.shares() - predicted).amax())
+    }
+}
+
+impl<F> FixedPointOperator for ContractionOperator<'_, F>
+where
+    F: FnMut(&DVector<f64>) -> Result<DVector<f64>>,
+{
+    fn dimension(&self) -> usize {
+        self.data.product_count()
+    }
+
+    fn apply(&mut self, delta: &DVector<f64>) -> Result<DVector<f64>> {
+        let predicted = (self.shares)(delta)?;
+        self.evaluations += 1;
+        self.share_residual = (self.data.shares() - &predicted).amax();
+        let mut next = delta.clone();
+        for (product_index, observed) in self.data.shares().iter().enumerate() {
             let model = predicted[product_index];
-            if model < options.minimum_share {
+            if model < self.options.minimum_share {
                 return Err(BlpError::NumericalError {
                     context: "predicted share underflow",
                 });
             }
-            let update = (observed / model).ln();
-            let damped = options.damping * update;
-            delta[product_index] += damped;
-            max_gap = max_gap.max(damped.abs());
+            next[product_index] += self.options.damping * (observed / model).ln();
         }
+        Ok(next)
+    }
+}
 
-        iteration += 1;
-        if max_gap < options.tolerance {
-            return Ok((
-                delta,
-                ContractionSummary {
-                    iterations: iteration,
-                    max_gap,
-                },
+/// Stateful BLP contraction that can be advanced one iteration at a time.
+///
+/// [`contract`] drives this solver to convergence. Use it directly to embed the
+/// inversion in another algorithm, e.g. to interleave delta updates with an EM step,
+/// warm-start from a previous solution, or inspect the iterate between steps.
+pub struct DeltaSolver<'a, F> {
+    operator: ContractionOperator<'a, F>,
+    delta: DVector<f64>,
+    update: DVector<f64>,
+    tolerance_weights: DVector<f64>,
+    iterations: usize,
+    max_gap: f64,
+    seconds: f64,
+}
+
+impl<'a, F> DeltaSolver<'a, F>
+where
+    F: FnMut(&DVector<f64>) -> Result<DVector<f64>>,
+{
+    /// Start from the plain-logit inversion `ln(s_j) - ln(s_0)`.
+    pub fn new(data: &'a ProductData, options: &'a ContractionOptions, shares: F) -> Self {
+        Self {
+            operator: ContractionOperator::new(data, options, shares),
+            delta: logit_inversion(data),
+            update: DVector::from_element(data.product_count(), f64::INFINITY),
+            tolerance_weights: tolerance_weights(data, options.tolerance_scaling),
+            iterations: 0,
+            max_gap: f64::INFINITY,
+            seconds: 0.0,
+        }
+    }
+
+    /// Replace the starting point, e.g. with the delta from a nearby parameter value.
+    pub fn with_initial_delta(mut self, delta: DVector<f64>) -> Result<Self> {
+        let expected = self.operator.dimension();
+        if delta.len() != expected {
+            return Err(BlpError::dimension_mismatch(
+                "initial delta length",
+                expected,
+                delta.len(),
             ));
         }
+        self.delta = delta;
+        Ok(self)
     }
 
-    Err(BlpError::ContractionDidNotConverge {
-        iterations: iteration,
-        max_gap,
-    })
+    /// Current iterate.
+    pub fn delta(&self) -> &DVector<f64> {
+        &self.delta
+    }
+
+    /// Number of iterations performed so far.
+    pub fn iterations(&self) -> usize {
+        self.iterations
+    }
+
+    /// Largest absolute update in the most recent iteration (infinite before the first).
+    pub fn max_gap(&self) -> f64 {
+        self.max_gap
+    }
+
+    /// Whether a convergence criterion has been met (see [`criterion`](Self::criterion)).
+    pub fn converged(&self) -> bool {
+        self.criterion().is_some()
+    }
+
+    /// The criterion met by the most recent iteration, if any.
+    ///
+    /// The delta criterion weights the update according to
+    /// [`tolerance_scaling`](ContractionOptions::tolerance_scaling). The share criterion
+    /// uses the shares predicted at the start of the iteration, so the returned iterate is
+    /// one contraction step past the point where it was met.
+    pub fn criterion(&self) -> Option<ConvergenceCriterion> {
+        let options = self.operator.options;
+        if self.update.component_mul(&self.tolerance_weights).amax() < options.tolerance {
+            Some(ConvergenceCriterion::DeltaUpdate)
+        } else if options
+            .share_tolerance
+            .is_some_and(|tolerance| self.operator.share_residual < tolerance)
+        {
+            Some(ConvergenceCriterion::ShareResidual)
+        } else {
+            None
+        }
+    }
+
+    /// Perform one contraction update and return its largest absolute change.
+    pub fn step(&mut self) -> Result<f64> {
+        let started = Instant::now();
+        let next = self.operator.apply(&self.delta)?;
+        self.update = &next - &self.delta;
+        self.max_gap = self.update.amax();
+        self.delta = next;
+        self.iterations += 1;
+        self.seconds += started.elapsed().as_secs_f64();
+        Ok(self.max_gap)
+    }
+
+    /// Iterate until convergence or until `max_iterations` total iterations have run.
+    ///
+    /// With a [`cpu_budget`](ContractionOptions::cpu_budget), stops once this contraction
+    /// has run for that many seconds and reports the markets that were still moving.
+    /// [`solve_delta`] and [`Problem`](crate::Problem) split the budget across markets
+    /// first.
+    pub fn solve(mut self) -> Result<(DVector<f64>, ContractionSummary)> {
+        let started = Instant::now();
+        while self.iterations < self.operator.options.max_iterations {
+            self.step()?;
+            if self.converged() {
+                return self.finish(Self::into_parts);
+            }
+            if let Some(budget) = self.operator.options.cpu_budget
+                && started.elapsed().as_secs_f64() >= budget
+            {
+                return self.finish(Self::truncate);
+            }
+        }
+
+        Err(BlpError::ContractionDidNotConverge {
+            iterations: self.iterations,
+            max_gap: self.max_gap,
+        })
+    }
+
+    /// Consume the solver, returning the current iterate and its diagnostics. The share
+    /// error is that of the last prediction, made before the final update.
+    pub fn into_parts(self) -> (DVector<f64>, ContractionSummary) {
+        let criterion = self.criterion();
+        (
+            self.delta,
+            ContractionSummary {
+                iterations: self.iterations,
+                max_gap: self.max_gap,
+                truncated_markets: Vec::new(),
+                criterion,
+                seconds: self.seconds,
+                max_share_error: self.operator.share_residual,
+                share_evaluations: self.operator.evaluations,
+            },
+        )
+    }
+
+    /// Predict the shares at the final iterate once more, so that the summary reports the
+    /// share error of the returned `delta` rather than of the one before it.
+    fn finish(
+        mut self,
+        parts: impl FnOnce(Self) -> (DVector<f64>, ContractionSummary),
+    ) -> Result<(DVector<f64>, ContractionSummary)> {
+        let started = Instant::now();
+        let error = self.operator.share_error(&self.delta)?;
+        self.seconds += started.elapsed().as_secs_f64();
+        let (delta, mut summary) = parts(self);
+        summary.max_share_error = error;
+        Ok((delta, summary))
+    }
+
+    /// Stop early, recording the markets whose last update exceeded the tolerance.
+    fn truncate(self) -> (DVector<f64>, ContractionSummary) {
+        let tolerance = self.operator.options.tolerance;
+        let truncated_markets: Vec<String> = self
+            .operator
+            .data
+            .partition()
+            .markets()
+            .filter(|market| {
+                market
+                    .range()
+                    .any(|row| (self.update[row] * self.tolerance_weights[row]).abs() >= tolerance)
+            })
+            .map(|market| market.id().to_string())
+            .collect();
+        log::warn!(
+            "contraction CPU budget spent after {} iterations; {} market(s) truncated",
+            self.iterations,
+            truncated_markets.len()
+        );
+        let (delta, mut summary) = self.into_parts();
+        summary.truncated_markets = truncated_markets;
+        (delta, summary)
+    }
+}
+
+impl<F> std::fmt::Debug for DeltaSolver<'_, F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DeltaSolver")
+            .field("delta", &self.delta)
+            .field("iterations", &self.iterations)
+            .field("max_gap", &self.max_gap)
+            .finish_non_exhaustive()
+    }
+}
+
+/// One market in isolation: its observed shares, `X2` block, and draws, with the
+/// nonlinear parameters fixed.
+///
+/// Useful for prototyping model variants or debugging a single market without building a
+/// full [`Problem`](crate::Problem): the random coefficients logit shares, inversion,
+/// share Jacobian, and elasticities are all available from the market's own matrices.
+#[derive(Clone, Debug)]
+pub struct MarketSolver {
+    data: ProductData,
+    draws: SimulationDraws,
+    parameters: NonlinearParameters,
+    options: ContractionOptions,
+}
+
+impl MarketSolver {
+    /// A market with observed `shares` and nonlinear characteristics `x2` (one row per
+    /// product), starting from `sigma = 0`. The draws need one column per `X2` column.
+    pub fn new(shares: DVector<f64>, x2: DMatrix<f64>, draws: SimulationDraws) -> Result<Self> {
+        if draws.dimension() != x2.ncols() {
+            return Err(BlpError::dimension_mismatch(
+                "draw columns",
+                x2.ncols(),
+                draws.dimension(),
+            ));
+        }
+        let market_ids = vec!["market".to_string(); shares.len()];
+        let sigma = DMatrix::zeros(x2.ncols(), x2.ncols());
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(DMatrix::from_element(x2.nrows(), 1, 1.0))
+            .x2(x2)
+            .build()?;
+        Ok(Self {
+            data,
+            draws,
+            parameters: NonlinearParameters::new(sigma),
+            options: ContractionOptions::default(),
+        })
+    }
+
+    /// Group the products into nests, one id per product, for use with `rho`.
+    pub fn with_nesting_ids(mut self, ids: Vec<String>) -> Result<Self> {
+        self.data = self.data.to_builder().nesting_ids(ids).build()?;
+        Ok(self)
+    }
+
+    /// Set how each `X2` column's random coefficient enters utility, one entry per column.
+    pub fn with_rc_types(mut self, rc_types: Vec<RandomCoefficientType>) -> Result<Self> {
+        self.data = self.data.to_builder().rc_types(rc_types).build()?;
+        Ok(self)
+    }
+
+    /// Evaluate everything at `parameters`, checked against the market and draws.
+    pub fn with_parameters(mut self, parameters: NonlinearParameters) -> Result<Self> {
+        parameters.validate(&self.data, &self.draws)?;
+        self.parameters = parameters;
+        Ok(self)
+    }
+
+    /// Use `options` for share prediction and the inversion.
+    pub fn with_options(mut self, options: ContractionOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// The market as product data with a constant `X1`.
+    pub fn data(&self) -> &ProductData {
+        &self.data
+    }
+
+    /// Nonlinear parameters the market is evaluated at.
+    pub fn parameters(&self) -> &NonlinearParameters {
+        &self.parameters
+    }
+
+    fn inputs(&self) -> ShareInputs<'_> {
+        ShareInputs::for_parameters(&self.data, &self.draws, &self.parameters, &self.options)
+    }
+
+    /// Predicted shares at mean utilities `delta`.
+    pub fn shares(&self, delta: &DVector<f64>) -> Result<DVector<f64>> {
+        RandomCoefficientsLogit.shares(delta, &self.inputs())
+    }
+
+    /// Mean utilities that reproduce the observed shares.
+    pub fn solve(&self) -> Result<(DVector<f64>, ContractionSummary)> {
+        RandomCoefficientsLogit.invert(&self.inputs())
+    }
+
+    /// Share Jacobian `ds_j / d delta_k` at `delta`.
+    pub fn jacobian(&self, delta: &DVector<f64>) -> Result<DMatrix<f64>> {
+        let mut blocks = RandomCoefficientsLogit.jacobian(delta, &self.inputs())?;
+        Ok(blocks.swap_remove(0))
+    }
+
+    /// Elasticities `(x_k / s_j) ds_j / dx_k` of the shares with respect to `X2` column
+    /// `column`, whose mean coefficient (e.g. the price coefficient in `beta`) is
+    /// `coefficient`; each consumer's coefficient adds their taste shift for the column.
+    /// For a [`Log`](RandomCoefficientType::Log) column each consumer's coefficient is
+    /// instead `exp(coefficient + taste shift)`, so `coefficient` is a location outside
+    /// `pi`, zero when `pi` carries it.
+    pub fn elasticities(
+        &self,
+        delta: &DVector<f64>,
+        column: usize,
+        coefficient: f64,
+    ) -> Result<DMatrix<f64>> {
+        let x2 = self.data.x2();
+        if column >= x2.ncols() {
+            return Err(BlpError::dimension_mismatch("X2", x2.ncols(), column + 1));
+        }
+        let sigma = self.parameters.sigma();
+        if sigma.shape() != (x2.ncols(), x2.ncols()) {
+            return Err(BlpError::dimension_mismatch(
+                "sigma columns",
+                x2.ncols(),
+                sigma.ncols(),
+            ));
+        }
+        let inputs = self.inputs();
+        let probabilities = individual_shares(delta, &inputs)?;
+        // Lognormal tastes come back exponentiated, so the location only scales them.
+        let tastes = consumer_tastes(&self.data, &self.draws, sigma, self.parameters.pi());
+        let slopes = match self.data.rc_types()[column] {
+            RandomCoefficientType::Linear => tastes.row(column).add_scalar(coefficient),
+            RandomCoefficientType::Log => tastes.row(column) * coefficient.exp(),
+        };
+        let nests = self.data.nest_indices();
+        let n = delta.len();
+        let mut derivatives = DMatrix::zeros(n, n);
+        for (r, weight) in self.draws.weights().iter().enumerate() {
+            let shares = probabilities.column(r);
+            let responses = match self.parameters.rho() {
+                Some(rho) => nested_jacobian(shares.as_slice(), nests, rho),
+                None => DMatrix::from_diagonal(&shares) - shares * shares.transpose(),
+            };
+            derivatives += responses * (weight * slopes[r]);
+        }
+        let predicted = probabilities * self.draws.weights();
+        Ok(DMatrix::from_fn(n, n, |j, k| {
+            derivatives[(j, k)] * x2[(k, column)] / predicted[j]
+        }))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::data::ProductDataBuilder;
     use approx::assert_relative_eq;
 
     /// Reproduces the homogeneous logit solution where the contraction converges in one step.
@@ -206,4 +1227,511 @@ mod tests {
         let expected_delta0 = (data.shares()[0] / outside).ln();
         assert_relative_eq!(delta[0], expected_delta0, epsilon = 1e-9);
     }
+
+    #[test]
+    fn unavailable_products_receive_no_share() {
+        let market_ids = vec!["m1".to_string(), "m1".to_string()];
+        let shares = DVector::from_vec(vec![0.2, 0.3]);
+        let x1 = DMatrix::from_row_slice(2, 1, &[1.0, 1.0]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .build()
+            .unwrap();
+        let draws = SimulationDraws::standard_normal(2, 0, 5);
+        let sigma = DMatrix::<f64>::zeros(0, 0);
+        let options = ContractionOptions::default();
+        // Product 1 is unavailable to the second consumer.
+        let availability = DMatrix::from_row_slice(2, 2, &[1.0, 1.0, 1.0, 0.0]);
+        let delta = DVector::zeros(2);
+
+        let inputs =
+            ShareInputs::new(&data, &draws, &sigma, &options).with_availability(&availability);
+        let shares = predict_shares_with(&delta, &inputs).unwrap();
+        assert_relative_eq!(shares[0], 0.5 * (1.0 / 3.0) + 0.5 * 0.5, epsilon = 1e-12);
+        assert_relative_eq!(shares[1], 0.5 * (1.0 / 3.0), epsilon = 1e-12);
+    }
+
+    #[test]
+    fn delta_solver_steps_match_solve() {
+        let market_ids = vec!["m1".to_string(), "m1".to_string()];
+        let shares = DVector::from_vec(vec![0.2, 0.3]);
+        let x1 = DMatrix::from_row_slice(2, 1, &[1.0, 2.0]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1.clone())
+            .x2(x1)
+            .build()
+            .unwrap();
+        let draws = SimulationDraws::standard_normal(20, 1, 9);
+        let sigma = DMatrix::from_element(1, 1, 0.5);
+        let options = ContractionOptions::default();
+        let inputs = ShareInputs::new(&data, &draws, &sigma, &options);
+
+        let mut solver = DeltaSolver::new(&data, &options, |d: &DVector<f64>| {
+            predict_shares_with(d, &inputs)
+        });
+        while !solver.converged() {
+            solver.step().unwrap();
+        }
+        let (expected, summary) = solve_delta(&data, &draws, &sigma, &options).unwrap();
+        assert_eq!(solver.iterations(), summary.iterations);
+        assert_relative_eq!(*solver.delta(), expected, epsilon = 1e-12);
+    }
+
+    /// Plain iteration written against the public traits, as an external crate would.
+    #[derive(Debug)]
+    struct Iterate;
+
+    impl crate::solving::FixedPointSolver for Iterate {
+        fn solve(
+            &self,
+            operator: &mut dyn FixedPointOperator,
+            mut x: DVector<f64>,
+            options: &ContractionOptions,
+        ) -> Result<(DVector<f64>, ContractionSummary)> {
+            for iterations in 1..=options.max_iterations {
+                let next = operator.apply(&x)?;
+                let max_gap = (&next - &x).amax();
+                x = next;
+                if max_gap < options.tolerance {
+                    return Ok((
+                        x,
+                        ContractionSummary {
+                            iterations,
+                            max_gap,
+                            criterion: Some(ConvergenceCriterion::DeltaUpdate),
+                            ..Default::default()
+                        },
+                    ));
+                }
+            }
+            Err(BlpError::NumericalError {
+                context: "test solver",
+            })
+        }
+    }
+
+    #[test]
+    fn external_solver_drives_contraction() {
+        let market_ids = vec!["m1".to_string(), "m1".to_string()];
+        let shares = DVector::from_vec(vec![0.2, 0.3]);
+        let x1 = DMatrix::from_row_slice(2, 1, &[1.0, 2.0]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1.clone())
+            .x2(x1)
+            .build()
+            .unwrap();
+        let draws = SimulationDraws::standard_normal(20, 1, 9);
+        let sigma = DMatrix::from_element(1, 1, 0.5);
+        let options = ContractionOptions::default();
+        let (expected, summary) = solve_delta(&data, &draws, &sigma, &options).unwrap();
+        assert_eq!(summary.share_evaluations, summary.iterations + 1);
+
+        // Cost and accuracy are recorded around the external solver as well.
+        let external = ContractionOptions::default().with_solver(Iterate);
+        let (delta, external) = solve_delta(&data, &draws, &sigma, &external).unwrap();
+        assert_relative_eq!(delta, expected, epsilon = 1e-12);
+        assert_eq!(external.share_evaluations, external.iterations + 1);
+        assert!(external.max_share_error < 1e-8 && external.seconds > 0.0);
+    }
+
+    #[test]
+    fn contraction_summary_reports_the_final_share_error() {
+        let market_ids = ["m1", "m1", "m2", "m2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.1, 0.45]);
+        let x1 = DMatrix::from_row_slice(4, 1, &[1.0, 2.0, 1.5, 0.5]);
+        let data = ProductDataBuilder::new(market_ids, shares.clone())
+            .x1(x1.clone())
+            .x2(x1)
+            .build()
+            .unwrap();
+        let draws = SimulationDraws::standard_normal(30, 1, 4);
+        let sigma = DMatrix::from_element(1, 1, 1.0);
+        // A loose share tolerance stops while the final update still moves the shares.
+        let options = ContractionOptions::default().with_share_tolerance(1e-4);
+        let (delta, summary) = solve_delta(&data, &draws, &sigma, &options).unwrap();
+        assert_eq!(summary.criterion, Some(ConvergenceCriterion::ShareResidual));
+
+        let inputs = ShareInputs::new(&data, &draws, &sigma, &options);
+        let predicted = predict_shares_with(&delta, &inputs).unwrap();
+        assert_relative_eq!(
+            summary.max_share_error,
+            (&shares - predicted).amax(),
+            epsilon = 1e-15
+        );
+        assert!(summary.max_share_error < 1e-4);
+        assert_eq!(summary.share_evaluations, summary.iterations + 1);
+        assert!(summary.seconds > 0.0);
+
+        // Stepping by hand reports the error of the prediction before the last update.
+        let mut solver = DeltaSolver::new(&data, &options, |delta: &DVector<f64>| {
+            predict_shares_with(delta, &inputs)
+        });
+        let before = solver.delta().clone();
+        solver.step().unwrap();
+        let (_, stepped) = solver.into_parts();
+        let predicted = predict_shares_with(&before, &inputs).unwrap();
+        assert_eq!(stepped.max_share_error, (&shares - predicted).amax());
+        assert_eq!(stepped.share_evaluations, 1);
+    }
+
+    #[test]
+    fn share_scaled_tolerance_relaxes_tiny_products() {
+        let market_ids = ["m1", "m1", "m2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.001, 0.3, 0.5]);
+        let x1 = DMatrix::from_row_slice(3, 1, &[1.0, 2.0, 1.5]);
+        let data = ProductDataBuilder::new(market_ids, shares.clone())
+            .x1(x1.clone())
+            .x2(x1)
+            .build()
+            .unwrap();
+        let draws = SimulationDraws::standard_normal(20, 1, 9);
+        let sigma = DMatrix::from_element(1, 1, 1.5);
+        let uniform = ContractionOptions {
+            tolerance: 1e-8,
+            ..ContractionOptions::default()
+        };
+        let (_, strict) = solve_delta(&data, &draws, &sigma, &uniform).unwrap();
+
+        for scaling in [
+            ToleranceScaling::ProductShare,
+            ToleranceScaling::MarketShare,
+        ] {
+            let options = uniform.clone().with_tolerance_scaling(scaling);
+            let (delta, summary) = solve_delta(&data, &draws, &sigma, &options).unwrap();
+            assert!(summary.iterations <= strict.iterations);
+            let inputs = ShareInputs::new(&data, &draws, &sigma, &options);
+            let predicted = predict_shares_with(&delta, &inputs).unwrap();
+            assert!((predicted - &shares).amax() < 1e-7);
+        }
+        let weights = tolerance_weights(&data, ToleranceScaling::ProductShare);
+        assert_relative_eq!(weights[2], 1.0);
+        assert_relative_eq!(weights[0], 0.002);
+    }
+
+    #[test]
+    fn share_residual_criterion_is_reported() {
+        let market_ids = ["m1", "m1", "m2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.4]);
+        let x1 = DMatrix::from_row_slice(3, 1, &[1.0, 2.0, 1.5]);
+        let data = ProductDataBuilder::new(market_ids, shares.clone())
+            .x1(x1.clone())
+            .x2(x1)
+            .build()
+            .unwrap();
+        let draws = SimulationDraws::standard_normal(20, 1, 9);
+        let sigma = DMatrix::from_element(1, 1, 1.5);
+        let strict = ContractionOptions {
+            tolerance: 1e-13,
+            ..ContractionOptions::default()
+        };
+        let (_, by_delta) = solve_delta(&data, &draws, &sigma, &strict).unwrap();
+        assert_eq!(by_delta.criterion, Some(ConvergenceCriterion::DeltaUpdate));
+
+        let options = strict.with_share_tolerance(1e-6);
+        let (delta, by_shares) = solve_delta(&data, &draws, &sigma, &options).unwrap();
+        assert_eq!(
+            by_shares.criterion,
+            Some(ConvergenceCriterion::ShareResidual)
+        );
+        assert!(by_shares.iterations < by_delta.iterations);
+        let inputs = ShareInputs::new(&data, &draws, &sigma, &options);
+        let predicted = predict_shares_with(&delta, &inputs).unwrap();
+        assert!((predicted - &shares).amax() < 1e-6);
+    }
+
+    #[test]
+    fn exhausted_cpu_budget_reports_truncated_markets() {
+        let market_ids = ["m1", "m1", "m2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.4]);
+        let x1 = DMatrix::from_row_slice(3, 1, &[1.0, 2.0, 1.5]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1.clone())
+            .x2(x1)
+            .build()
+            .unwrap();
+        let draws = SimulationDraws::standard_normal(20, 1, 9);
+        let sigma = DMatrix::from_element(1, 1, 1.5);
+        let options = ContractionOptions::default().with_cpu_budget(0.0);
+
+        let (_, summary) = solve_delta(&data, &draws, &sigma, &options).unwrap();
+        assert_eq!(summary.iterations, 1);
+        assert!(summary.is_truncated());
+        assert_eq!(summary.truncated_markets, vec!["m1", "m2"]);
+    }
+
+    #[test]
+    fn cpu_budget_is_split_across_markets() {
+        // Market m1 has no heterogeneity, so the logit start already solves it.
+        let market_ids = ["m1", "m1", "m2", "m2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.4, 0.1]);
+        let x2 = DMatrix::from_column_slice(4, 1, &[0.0, 0.0, 2.0, -1.0]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(DMatrix::from_element(4, 1, 1.0))
+            .x2(x2)
+            .build()
+            .unwrap();
+        let draws = SimulationDraws::standard_normal(20, 1, 9);
+        let sigma = DMatrix::from_element(1, 1, 1.5);
+        let (expected, _) =
+            solve_delta(&data, &draws, &sigma, &ContractionOptions::default()).unwrap();
+
+        // The stubborn market cannot hold up the one that converges.
+        let spent = ContractionOptions::default().with_cpu_budget(0.0);
+        let (delta, summary) = solve_delta(&data, &draws, &sigma, &spent).unwrap();
+        assert_eq!(summary.truncated_markets, vec!["m2"]);
+        assert_eq!(summary.criterion, None);
+        assert_relative_eq!(delta.rows(0, 2), expected.rows(0, 2), epsilon = 1e-12);
+        assert_ne!(delta.rows(2, 2), expected.rows(2, 2));
+
+        let ample = ContractionOptions::default().with_cpu_budget(60.0);
+        let (delta, summary) = solve_delta(&data, &draws, &sigma, &ample).unwrap();
+        assert!(!summary.is_truncated());
+        assert_eq!(summary.criterion, Some(ConvergenceCriterion::DeltaUpdate));
+        assert_relative_eq!(delta, expected, epsilon = 1e-8);
+    }
+
+    #[test]
+    fn single_product_markets_use_logistic_shares() {
+        let market_ids = ["m1", "m2", "m3"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.2, 0.6, 0.05]);
+        let x1 = DMatrix::from_row_slice(3, 1, &[1.0, 2.0, 0.5]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1.clone())
+            .x2(x1)
+            .build()
+            .unwrap();
+        let draws = SimulationDraws::standard_normal(20, 1, 3);
+        let sigma = DMatrix::from_element(1, 1, 0.7);
+        let options = ContractionOptions::default();
+
+        let (delta, _) = solve_delta(&data, &draws, &sigma, &options).unwrap();
+        let inputs = ShareInputs::new(&data, &draws, &sigma, &options);
+        let predicted = predict_shares_with(&delta, &inputs).unwrap();
+        assert_relative_eq!(predicted, data.shares().clone(), epsilon = 1e-8);
+
+        // Utilities that would overflow exp() still give valid probabilities.
+        let extreme = DVector::from_vec(vec![800.0, -800.0, 0.0]);
+        let probabilities = individual_shares(&extreme, &inputs).unwrap();
+        assert!(
+            probabilities
+                .row(0)
+                .iter()
+                .all(|p| (*p - 1.0).abs() < 1e-12)
+        );
+        assert!(probabilities.row(1).iter().all(|p| *p == 0.0));
+    }
+
+    #[test]
+    fn softmax_handles_lone_products_availability_and_overflow() {
+        let delta = DVector::from_vec(vec![0.0, 1.0, 2.0]);
+        let mut utilities = [0.5, -0.2];
+        softmax(&mut utilities, &delta, None, 1, 0).unwrap();
+        let (a, b) = (1.5_f64.exp(), 1.8_f64.exp());
+        assert_relative_eq!(utilities[0], a / (1.0 + a + b), epsilon = 1e-15);
+        assert_relative_eq!(utilities[1], b / (1.0 + a + b), epsilon = 1e-15);
+
+        // Product 2 is unavailable to consumer 1 and half as attractive to consumer 0.
+        let availability = DMatrix::from_row_slice(3, 2, &[1.0, 1.0, 1.0, 1.0, 0.5, 0.0]);
+        let mut utilities = [0.5, -0.2];
+        softmax(&mut utilities, &delta, Some(&availability), 1, 1).unwrap();
+        assert_relative_eq!(utilities[0], a / (1.0 + a), epsilon = 1e-15);
+        assert_eq!(utilities[1], 0.0);
+        let mut utilities = [0.5, -0.2];
+        softmax(&mut utilities, &delta, Some(&availability), 1, 0).unwrap();
+        assert_relative_eq!(utilities[1], 0.5 * b / (1.0 + a + 0.5 * b), epsilon = 1e-15);
+
+        // A lone product stays finite at utilities where exp(u) overflows.
+        let mut lone = [800.0];
+        softmax(&mut lone, &delta, None, 0, 0).unwrap();
+        assert_eq!(lone[0], 1.0);
+        let mut lone = [800.0];
+        softmax(&mut lone, &delta, Some(&availability), 2, 1).unwrap();
+        assert_eq!(lone[0], 0.0);
+        let mut overflowing = [800.0, 0.0];
+        assert!(matches!(
+            softmax(&mut overflowing, &delta, None, 0, 0),
+            Err(BlpError::NumericalError { .. })
+        ));
+    }
+
+    #[test]
+    fn individual_shares_normalize_each_market_across_consumer_blocks() {
+        let market_ids = ["a", "b", "b", "c", "c", "c"].map(String::from).to_vec();
+        let x2 = DMatrix::from_fn(6, 1, |j, _| 0.3 * j as f64 - 0.5);
+        let data = ProductDataBuilder::new(market_ids, DVector::from_element(6, 0.1))
+            .x1(x2.clone())
+            .x2(x2.clone())
+            .build()
+            .unwrap();
+        let draws = SimulationDraws::standard_normal(DRAW_BLOCK + 6, 1, 9);
+        let sigma = DMatrix::from_element(1, 1, 0.7);
+        let delta = DVector::from_fn(6, |j, _| -1.0 + 0.2 * j as f64);
+        let options = ContractionOptions::default();
+
+        let probabilities =
+            individual_shares(&delta, &ShareInputs::new(&data, &draws, &sigma, &options)).unwrap();
+        assert_eq!(probabilities.shape(), (6, DRAW_BLOCK + 6));
+        for draw in [0, DRAW_BLOCK - 1, DRAW_BLOCK + 5] {
+            let nu = draws.draws()[(draw, 0)];
+            let exp_u = DVector::from_fn(6, |j, _| (delta[j] + x2[(j, 0)] * 0.7 * nu).exp());
+            for market in [0..1, 1..3, 3..6] {
+                let denominator = 1.0 + exp_u.rows_range(market.clone()).sum();
+                for j in market {
+                    assert_relative_eq!(
+                        probabilities[(j, draw)],
+                        exp_u[j] / denominator,
+                        epsilon = 1e-14
+                    );
+                }
+            }
+        }
+
+        // Without random coefficients every consumer has the plain logit probabilities.
+        let homogeneous =
+            ProductDataBuilder::new(data.market_ids().to_vec(), data.shares().clone())
+                .x1(x2)
+                .build()
+                .unwrap();
+        let draws = SimulationDraws::standard_normal(3, 0, 9);
+        let empty = DMatrix::zeros(0, 0);
+        let logit = individual_shares(
+            &delta,
+            &ShareInputs::new(&homogeneous, &draws, &empty, &options),
+        )
+        .unwrap();
+        for draw in 1..3 {
+            assert_eq!(logit.column(draw), logit.column(0));
+        }
+        assert_relative_eq!(
+            logit[(0, 0)],
+            1.0 / (1.0 + (-delta[0]).exp()),
+            epsilon = 1e-15
+        );
+    }
+
+    #[test]
+    fn batched_utilities_and_blocked_jacobian_match_direct_evaluation() {
+        let products = 12;
+        let market_ids = vec!["m1".to_string(); products];
+        let shares = DVector::from_element(products, 0.05);
+        let x2 = DMatrix::from_fn(products, 2, |j, k| ((j + 1) * (k + 2)) as f64 / 10.0);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x2.columns(0, 1).into_owned())
+            .x2(x2)
+            .build()
+            .unwrap();
+        let draws = SimulationDraws::standard_normal(150, 2, 4);
+        let sigma = DMatrix::from_row_slice(2, 2, &[0.8, 0.0, 0.3, 0.5]);
+        let delta = DVector::from_fn(products, |j, _| -2.0 + 0.1 * j as f64);
+        let unblocked = ContractionOptions::default();
+        let blocked = ContractionOptions {
+            large_market_products: 2,
+            ..ContractionOptions::default()
+        };
+
+        let reference = ShareInputs::new(&data, &draws, &sigma, &unblocked);
+        let chunked = ShareInputs::new(&data, &draws, &sigma, &blocked);
+        // The batched utilities agree with a direct per-consumer evaluation.
+        let probabilities = individual_shares(&delta, &chunked).unwrap();
+        for draw in [0, 70, 149] {
+            let tastes = &sigma * draws.draws().row(draw).transpose();
+            let exp_u = DVector::from_fn(products, |j, _| {
+                (delta[j] + (data.x2().row(j) * &tastes)[0]).exp()
+            });
+            let expected = &exp_u / (1.0 + exp_u.sum());
+            assert_relative_eq!(
+                probabilities.column(draw).into_owned(),
+                expected,
+                epsilon = 1e-14
+            );
+        }
+        let expected = RandomCoefficientsLogit
+            .jacobian(&delta, &reference)
+            .unwrap();
+        let jacobian = RandomCoefficientsLogit.jacobian(&delta, &chunked).unwrap();
+        assert_relative_eq!(jacobian[0], expected[0], epsilon = 1e-12);
+    }
+
+    #[test]
+    fn market_solver_inverts_and_differentiates_one_market() {
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.1]);
+        let x2 = DMatrix::from_column_slice(3, 1, &[1.0, 2.0, 1.5]);
+        let draws = SimulationDraws::standard_normal(200, 1, 3);
+        let parameters = NonlinearParameters::new(DMatrix::from_element(1, 1, 0.5));
+        let market = MarketSolver::new(shares.clone(), x2, draws)
+            .unwrap()
+            .with_parameters(parameters.clone())
+            .unwrap();
+        let (delta, _) = market.solve().unwrap();
+        let predicted = market.shares(&delta).unwrap();
+        assert_relative_eq!(predicted, shares, epsilon = 1e-8);
+
+        // Elasticities with respect to a characteristic match finite differences of
+        // the shares when delta moves by the mean coefficient times the change.
+        let coefficient = -2.0;
+        let elasticities = market.elasticities(&delta, 0, coefficient).unwrap();
+        let jacobian = market.jacobian(&delta).unwrap();
+        let step = 1e-6;
+        for k in 0..3 {
+            let mut x2 = market.data().x2().clone();
+            x2[(k, 0)] += step;
+            let mut shifted = delta.clone();
+            shifted[k] += coefficient * step;
+            let moved = MarketSolver::new(shares.clone(), x2, market.draws.clone())
+                .unwrap()
+                .with_parameters(parameters.clone())
+                .unwrap();
+            let derivative = (moved.shares(&shifted).unwrap() - &predicted) / step;
+            for j in 0..3 {
+                let expected = derivative[j] * market.data().x2()[(k, 0)] / predicted[j];
+                assert_relative_eq!(elasticities[(j, k)], expected, epsilon = 1e-4);
+            }
+            assert!(jacobian[(k, k)] > 0.0);
+        }
+        assert!(market.elasticities(&delta, 1, coefficient).is_err());
+        assert!(market.with_parameters(parameters.with_rho(0.5)).is_err());
+    }
+
+    #[test]
+    fn market_solver_elasticities_exponentiate_lognormal_coefficients() {
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.1]);
+        let x2 = DMatrix::from_column_slice(3, 1, &[-1.0, -2.0, -1.5]);
+        let draws = SimulationDraws::standard_normal(200, 1, 3)
+            .with_demographics(DMatrix::from_element(200, 1, 1.0))
+            .unwrap();
+        let market = |x2: DMatrix<f64>, location: f64| {
+            MarketSolver::new(shares.clone(), x2, draws.clone())
+                .unwrap()
+                .with_rc_types(vec![RandomCoefficientType::Log])
+                .unwrap()
+                .with_parameters(
+                    NonlinearParameters::new(DMatrix::from_element(1, 1, 0.5))
+                        .with_pi(DMatrix::from_element(1, 1, location)),
+                )
+                .unwrap()
+        };
+        let located = market(x2.clone(), 0.7);
+        let (delta, _) = located.solve().unwrap();
+        let predicted = located.shares(&delta).unwrap();
+
+        // With the location in pi, only the consumer coefficients move the shares.
+        let elasticities = located.elasticities(&delta, 0, 0.0).unwrap();
+        let step = 1e-6;
+        for k in 0..3 {
+            let mut moved = x2.clone();
+            moved[(k, 0)] += step;
+            let derivative = (market(moved, 0.7).shares(&delta).unwrap() - &predicted) / step;
+            for j in 0..3 {
+                let expected = derivative[j] * x2[(k, 0)] / predicted[j];
+                assert_relative_eq!(elasticities[(j, k)], expected, epsilon = 1e-4);
+            }
+        }
+        // A location passed as the coefficient is exponentiated with the taste, scaling
+        // every consumer's coefficient rather than adding to it.
+        let shifted = located.elasticities(&delta, 0, 0.3).unwrap();
+        assert_relative_eq!(shifted, elasticities * 0.3_f64.exp(), epsilon = 1e-10);
+
+        let mismatched = SimulationDraws::standard_normal(10, 2, 3);
+        assert!(MarketSolver::new(shares.clone(), x2, mismatched).is_err());
+    }
 }
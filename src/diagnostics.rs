@@ -0,0 +1,1252 @@
+//! Numerical diagnostics reported alongside estimation results.
+
+use crate::cache::CacheStatistics;
+use crate::data::{DataColumn, DataMatrix, ProductData, RandomCoefficientType, quantile};
+use crate::error::{BlpError, Result};
+use crate::estimation::{Problem, ProblemResults, compute_linear_parameters, inverse_ztz};
+use crate::linalg::{Factorizations, cholesky_inverse, condition_number};
+use crate::options::{LinearSolver, WeightingMatrix};
+use crate::parameters::NonlinearParameters;
+use crate::statistics::chi_squared_sf;
+use nalgebra::{DMatrix, DVector};
+use serde::{Deserialize, Serialize};
+
+/// Sizes of a configured problem and a rough cost model for one objective evaluation.
+///
+/// The cost figures are order-of-magnitude estimates intended for sanity-checking a
+/// configuration before a long run, not precise operation counts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProblemDimensions {
+    /// Total number of products `N` across all markets.
+    pub products: usize,
+    /// Number of markets `T`.
+    pub markets: usize,
+    /// Number of products `J_t` in each market, in market order.
+    pub products_per_market: Vec<usize>,
+    /// Number of linear characteristics `K1`.
+    pub k1: usize,
+    /// Number of nonlinear characteristics `K2`.
+    pub k2: usize,
+    /// Number of cost characteristics `K3`; zero without a supply side.
+    pub k3: usize,
+    /// Number of demand-side instruments.
+    pub instruments: usize,
+    /// Number of simulation draws `R`.
+    pub draws: usize,
+    /// Effective sample size of the integration weights, shared by every market.
+    pub effective_draws: f64,
+    /// Approximate floating-point operations for one share prediction (one contraction iteration).
+    pub flops_per_share_prediction: f64,
+    /// Approximate floating-point operations for the linear IV step and objective.
+    pub flops_per_linear_step: f64,
+    /// Approximate bytes held by the product data, draws, and per-evaluation work vectors.
+    pub memory_bytes: usize,
+}
+
+impl ProblemDimensions {
+    /// Estimated operations for one objective evaluation assuming `contraction_iterations`.
+    pub fn flops_per_evaluation(&self, contraction_iterations: usize) -> f64 {
+        self.flops_per_share_prediction * contraction_iterations as f64 + self.flops_per_linear_step
+    }
+
+    /// Size of the largest market, which bounds per-market work buffers.
+    pub fn max_products_per_market(&self) -> usize {
+        self.products_per_market.iter().copied().max().unwrap_or(0)
+    }
+}
+
+/// Wall-clock breakdown of a single solve, in seconds.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ProfilingReport {
+    /// Time spent in the contraction mapping and final share prediction.
+    pub contraction_seconds: f64,
+    /// Time spent on the weighting matrix, linear IV step, and objective.
+    pub linear_seconds: f64,
+    /// Contraction time that was avoided by reusing a previous inner-loop solution,
+    /// e.g. when only the weighting matrix changed between GMM steps or the inner-loop
+    /// cache already held the solution.
+    pub reused_contraction_seconds: f64,
+    /// Inner-loop cache lookups for the problem so far.
+    #[serde(default)]
+    pub cache: CacheStatistics,
+}
+
+/// Condition numbers of the matrices factorized during the linear IV step.
+///
+/// Poor conditioning is the usual cause of [`SingularMatrix`](crate::error::BlpError::SingularMatrix)
+/// failures, so these values are logged and kept in the results. During a search such as
+/// [`Problem::optimize`] only the final estimates, and evaluations whose factorizations
+/// fell back to a pseudo-inverse, carry the condition numbers of `W` and `X1'Z W Z'X1`;
+/// the other evaluations leave them at NaN.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConditioningReport {
+    /// Condition number of `Z'Z`.
+    #[serde(deserialize_with = "crate::archive::nan_if_null")]
+    pub ztz: f64,
+    /// Condition number of the GMM weighting matrix `W`.
+    #[serde(deserialize_with = "crate::archive::nan_if_null")]
+    pub weighting: f64,
+    /// Condition number of `X1'Z W Z'X1`.
+    #[serde(deserialize_with = "crate::archive::nan_if_null")]
+    pub xzwzx: f64,
+    /// Threshold above which a warning was emitted.
+    pub threshold: f64,
+    /// Matrices whose Cholesky factorization failed and were replaced by the SVD
+    /// pseudo-inverse (see
+    /// [`GmmOptions::strict_factorizations`](crate::GmmOptions::strict_factorizations)).
+    #[serde(default)]
+    pub pseudo_inverses: Vec<String>,
+    /// Rank and estimated error of the weighting matrix under
+    /// [`GmmOptions::low_rank_weighting`](crate::GmmOptions::low_rank_weighting).
+    #[serde(default)]
+    pub weighting_approximation: Option<WeightingApproximation>,
+}
+
+/// How well the low-rank weighting matrix approximates the exact one.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WeightingApproximation {
+    /// Principal directions kept.
+    pub rank: usize,
+    /// Relative error `||S v - S_r v|| / ||S v||` of the approximated matrix on random
+    /// probe vectors `v`; NaN for a two-step update outside [`Problem::optimize`].
+    #[serde(deserialize_with = "crate::archive::nan_if_null")]
+    pub relative_error: f64,
+}
+
+impl ConditioningReport {
+    /// Compute the report and log each condition number, warning when it exceeds `threshold`.
+    pub fn compute(
+        x1: &DMatrix<f64>,
+        instruments: &DMatrix<f64>,
+        weighting: &DMatrix<f64>,
+        threshold: f64,
+    ) -> Self {
+        let ztz = condition_number(&(instruments.transpose() * instruments));
+        Self::with_ztz(ztz, x1, instruments, weighting, threshold)
+    }
+
+    /// [`compute`](Self::compute) given the condition number of `Z'Z`, which depends on
+    /// the data alone.
+    pub(crate) fn with_ztz(
+        ztz: f64,
+        x1: &DMatrix<f64>,
+        instruments: &DMatrix<f64>,
+        weighting: &DMatrix<f64>,
+        threshold: f64,
+    ) -> Self {
+        let zx = instruments.transpose() * x1;
+        let report = Self {
+            ztz,
+            weighting: condition_number(weighting),
+            xzwzx: if weighting.nrows() == zx.nrows() {
+                condition_number(&(zx.transpose() * weighting * &zx))
+            } else {
+                f64::NAN
+            },
+            threshold,
+            pseudo_inverses: Vec::new(),
+            weighting_approximation: None,
+        };
+        report.log();
+        report
+    }
+
+    /// The report for a low-rank weighting matrix, which is singular by construction:
+    /// only `X1'Z W Z'X1` is checked, and `ztz` and `weighting` are left at NaN rather
+    /// than paying for an SVD of an `L x L` matrix.
+    pub(crate) fn low_rank(
+        x1: &DMatrix<f64>,
+        instruments: &DMatrix<f64>,
+        weighting: &DMatrix<f64>,
+        threshold: f64,
+        approximation: WeightingApproximation,
+    ) -> Self {
+        let zx = instruments.transpose() * x1;
+        let report = Self {
+            ztz: f64::NAN,
+            weighting: f64::NAN,
+            xzwzx: condition_number(&(zx.transpose() * weighting * &zx)),
+            threshold,
+            pseudo_inverses: Vec::new(),
+            weighting_approximation: Some(approximation),
+        };
+        report.log();
+        report
+    }
+
+    /// The report of an evaluation inside a search, which keeps the condition number of
+    /// `Z'Z` and leaves the others at NaN until the search settles on its estimates.
+    pub(crate) fn deferred(
+        ztz: f64,
+        threshold: f64,
+        approximation: Option<WeightingApproximation>,
+    ) -> Self {
+        Self {
+            ztz: if approximation.is_some() {
+                f64::NAN
+            } else {
+                ztz
+            },
+            weighting: f64::NAN,
+            xzwzx: f64::NAN,
+            threshold,
+            pseudo_inverses: Vec::new(),
+            weighting_approximation: approximation,
+        }
+    }
+
+    /// Whether any of the tracked condition numbers exceeds the warning threshold.
+    pub fn is_ill_conditioned(&self) -> bool {
+        self.tracked()
+            .iter()
+            .any(|(_, value)| exceeds(*value, self.threshold))
+    }
+
+    fn tracked(&self) -> Vec<(&'static str, f64)> {
+        let mut tracked = vec![("X'ZWZ'X", self.xzwzx)];
+        if self.weighting_approximation.is_none() {
+            tracked.splice(0..0, [("Z'Z", self.ztz), ("W", self.weighting)]);
+        }
+        tracked
+    }
+
+    fn log(&self) {
+        for (name, value) in self.tracked() {
+            log::debug!("condition number of {name}: {value:.3e}");
+            if exceeds(value, self.threshold) {
+                log::warn!(
+                    "condition number of {name} is {value:.3e}, above the threshold {:.1e}; \
+                     the linear IV step may be numerically unreliable",
+                    self.threshold
+                );
+            }
+        }
+    }
+}
+
+/// NaN condition numbers (from non-finite inputs) are treated as exceeding any threshold.
+fn exceeds(value: f64, threshold: f64) -> bool {
+    value.is_nan() || value > threshold
+}
+
+/// A single observation flagged by [`extreme_observations`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExtremeObservation {
+    /// Market the product belongs to.
+    pub market_id: String,
+    /// Row of the product in the data.
+    pub row: usize,
+    /// Column the value was taken from.
+    pub column: DataColumn,
+    /// Label of the column.
+    pub label: String,
+    /// Observed value.
+    pub value: f64,
+    /// Robust z-score `(v - median) / (1.4826 * MAD)` against the pooled column.
+    /// Shares are scored on the log scale.
+    pub score: f64,
+}
+
+/// List the `per_market` most extreme observations in each market across `columns`.
+///
+/// Extreme shares and characteristics are a common cause of contraction failure; this
+/// is meant to be inspected before deciding whether to [winsorize](crate::data::Winsorization).
+/// Observations are ordered by market, then by decreasing absolute score. Columns with
+/// no spread (zero MAD) are skipped.
+pub fn extreme_observations(
+    data: &ProductData,
+    columns: &[DataColumn],
+    per_market: usize,
+) -> Result<Vec<ExtremeObservation>> {
+    let mut scored = Vec::with_capacity(columns.len());
+    for &column in columns {
+        let raw = data.column(column)?;
+        let values = if column == DataColumn::Shares {
+            raw.map(f64::ln)
+        } else {
+            raw.clone()
+        };
+        let mut sorted = values.as_slice().to_vec();
+        sorted.sort_by(f64::total_cmp);
+        let median = quantile(&sorted, 0.5);
+        let mut deviations: Vec<f64> = sorted.iter().map(|v| (v - median).abs()).collect();
+        deviations.sort_by(f64::total_cmp);
+        let scale = 1.4826 * quantile(&deviations, 0.5);
+        if scale > 0.0 {
+            scored.push((column, raw, values.map(|v| (v - median) / scale)));
+        }
+    }
+
+    let mut observations = Vec::new();
+    for market in data.partition().markets() {
+        let mut candidates: Vec<ExtremeObservation> = scored
+            .iter()
+            .flat_map(|(column, raw, scores)| {
+                market.range().map(move |row| ExtremeObservation {
+                    market_id: market.id().to_string(),
+                    row,
+                    column: *column,
+                    label: data.column_label(*column),
+                    value: raw[row],
+                    score: scores[row],
+                })
+            })
+            .collect();
+        candidates.sort_by(|a, b| b.score.abs().total_cmp(&a.score.abs()));
+        candidates.truncate(per_market);
+        observations.extend(candidates);
+    }
+    Ok(observations)
+}
+
+/// Lagrange-multiplier test of the plain logit against random coefficients.
+#[derive(Clone, Debug)]
+pub struct LogitSpecificationTest {
+    /// Labels of the characteristics whose rival sums were tested.
+    pub rival_columns: Vec<String>,
+    /// `N * R^2` from regressing the logit `xi` on `X1` and the rival sums.
+    pub statistic: f64,
+    /// Number of rival-sum columns that add rank beyond `X1`.
+    pub degrees_of_freedom: usize,
+    /// Chi-squared upper-tail probability of the statistic.
+    pub p_value: f64,
+}
+
+impl LogitSpecificationTest {
+    /// Whether the plain logit is rejected at `level` (e.g. `0.05`).
+    pub fn rejects_logit(&self, level: f64) -> bool {
+        self.p_value < level
+    }
+}
+
+/// Pre-estimation test of whether substitution patterns reject the plain logit.
+///
+/// Under the plain logit, substitution depends on rival products only through the
+/// inclusive value, so the structural error `xi` from the IV logit regression of
+/// `ln(s_j / s_0)` on `X1` should be uncorrelated with the characteristics of rival
+/// products. With random coefficients on a characteristic, products facing close
+/// rivals lose share to them, which shows up as correlation between `xi` and the sum
+/// of that characteristic over the other products in the market.
+///
+/// The rival sums are formed for every `X2` column (the candidate random coefficients),
+/// or for every `X1` column when `X2` is empty, and the test statistic is `N * R^2`
+/// from the auxiliary regression of `xi` on `X1` and those sums. A small p-value
+/// supports a random coefficients specification.
+pub fn logit_specification_test(data: &ProductData) -> Result<LogitSpecificationTest> {
+    let n = data.product_count();
+    let delta = DVector::from_fn(n, |row, _| {
+        (data.shares()[row] / data.outside_share_for_product(row)).ln()
+    });
+    let factorizations = &mut Factorizations::strict();
+    let weighting = inverse_ztz(data.instruments(), factorizations)?;
+    let beta =
+        compute_linear_parameters(data, &delta, &weighting, LinearSolver::Svd, factorizations)?;
+    let xi = &delta - data.x1() * beta;
+
+    let (kind, characteristics) = if data.nonlinear_dim() > 0 {
+        (DataMatrix::X2, data.x2())
+    } else {
+        (DataMatrix::X1, data.x1())
+    };
+    let mut rival_sums = DMatrix::zeros(n, characteristics.ncols());
+    for market in data.partition().markets() {
+        for column in 0..characteristics.ncols() {
+            let total: f64 = market
+                .range()
+                .map(|row| characteristics[(row, column)])
+                .sum();
+            for row in market.range() {
+                rival_sums[(row, column)] = total - characteristics[(row, column)];
+            }
+        }
+    }
+
+    let x1 = data.x1();
+    let regressors = DMatrix::from_fn(n, x1.ncols() + rival_sums.ncols(), |row, column| {
+        if column < x1.ncols() {
+            x1[(row, column)]
+        } else {
+            rival_sums[(row, column - x1.ncols())]
+        }
+    });
+    let coefficients = regressors
+        .clone()
+        .svd(true, true)
+        .solve(&xi, 1e-10 * regressors.norm())
+        .map_err(|_| crate::error::BlpError::singular("logit specification test"))?;
+    let residuals = &xi - &regressors * coefficients;
+    let centered = xi.add_scalar(-xi.mean());
+    let r_squared = 1.0 - residuals.norm_squared() / centered.norm_squared();
+    let statistic = n as f64 * r_squared;
+    let degrees_of_freedom = rank(&regressors).saturating_sub(rank(x1));
+
+    Ok(LogitSpecificationTest {
+        rival_columns: (0..characteristics.ncols())
+            .map(|column| data.column_label(DataColumn::Matrix(kind, column)))
+            .collect(),
+        statistic,
+        degrees_of_freedom,
+        p_value: chi_squared_sf(statistic, degrees_of_freedom),
+    })
+}
+
+/// Durbin–Wu–Hausman test of whether the `X1` columns that are not instruments are
+/// exogenous in the logit regression.
+#[derive(Clone, Debug)]
+pub struct HausmanTest {
+    /// OLS estimates of the logit regression `ln(s_j / s_0) = X1 beta + xi`.
+    pub ols_beta: DVector<f64>,
+    /// 2SLS estimates of the same regression.
+    pub iv_beta: DVector<f64>,
+    /// Labels of the `X1` columns treated as endogenous (not spanned by `Z`).
+    pub endogenous_columns: Vec<String>,
+    /// Wald statistic on the first-stage residuals in the control-function regression.
+    pub statistic: f64,
+    /// Number of endogenous columns.
+    pub degrees_of_freedom: usize,
+    /// Chi-squared upper-tail probability (one when nothing is endogenous).
+    pub p_value: f64,
+}
+
+impl HausmanTest {
+    /// Whether exogeneity (OLS and IV agree) is rejected at `level`, i.e. instrumenting
+    /// price is warranted.
+    pub fn rejects_exogeneity(&self, level: f64) -> bool {
+        self.p_value < level
+    }
+}
+
+/// Pre-estimation check of price endogeneity comparing OLS and IV logit estimates.
+///
+/// Uses the regression form of the Hausman test: each `X1` column is projected on `Z`,
+/// and the first-stage residuals of the columns the instruments do not span are added to
+/// the OLS regression. Their joint significance is asymptotically equivalent to the
+/// Hausman contrast between OLS and 2SLS under homoskedastic `xi`.
+pub fn hausman_test(data: &ProductData) -> Result<HausmanTest> {
+    let n = data.product_count();
+    let x1 = data.x1();
+    let z = data.instruments();
+    if z.ncols() < x1.ncols() {
+        return Err(BlpError::Underidentified {
+            instruments: z.ncols(),
+            parameters: x1.ncols(),
+        });
+    }
+    let delta = DVector::from_fn(n, |row, _| {
+        (data.shares()[row] / data.outside_share_for_product(row)).ln()
+    });
+    let least_squares = |design: &DMatrix<f64>| {
+        design
+            .clone()
+            .svd(true, true)
+            .solve(&delta, 1e-10 * design.norm())
+            .map_err(|_| BlpError::singular("Hausman test"))
+    };
+    let ols_beta = least_squares(x1)?;
+    let factorizations = &mut Factorizations::strict();
+    let weighting = inverse_ztz(z, factorizations)?;
+    let iv_beta =
+        compute_linear_parameters(data, &delta, &weighting, LinearSolver::Svd, factorizations)?;
+
+    let first_stage = x1 - z * (&weighting * (z.transpose() * x1));
+    let endogenous: Vec<usize> = (0..x1.ncols())
+        .filter(|&column| first_stage.column(column).norm() > 1e-8 * x1.column(column).norm())
+        .collect();
+    let (statistic, p_value) = if endogenous.is_empty() {
+        (0.0, 1.0)
+    } else {
+        let design = DMatrix::from_fn(n, x1.ncols() + endogenous.len(), |row, column| {
+            if column < x1.ncols() {
+                x1[(row, column)]
+            } else {
+                first_stage[(row, endogenous[column - x1.ncols()])]
+            }
+        });
+        let coefficients = least_squares(&design)?;
+        let residuals = &delta - &design * &coefficients;
+        let variance = residuals.norm_squared() / (n - design.ncols()) as f64;
+        let inverse = cholesky_inverse(&(design.transpose() * &design))
+            .ok_or_else(|| BlpError::singular("Hausman test"))?;
+        let k = x1.ncols();
+        let m = endogenous.len();
+        let gamma = coefficients.rows(k, m).into_owned();
+        let covariance = inverse.view((k, k), (m, m)) * variance;
+        let precision =
+            cholesky_inverse(&covariance).ok_or_else(|| BlpError::singular("Hausman test"))?;
+        let statistic = gamma.dot(&(precision * &gamma));
+        (statistic, chi_squared_sf(statistic, m))
+    };
+
+    Ok(HausmanTest {
+        ols_beta,
+        iv_beta,
+        endogenous_columns: endogenous
+            .iter()
+            .map(|&column| data.column_label(DataColumn::Matrix(DataMatrix::X1, column)))
+            .collect(),
+        statistic,
+        degrees_of_freedom: endogenous.len(),
+        p_value,
+    })
+}
+
+/// Summary of the structural errors within one group of products.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResidualGroup {
+    /// Group identifier, e.g. a market, firm, or product family.
+    pub group: String,
+    /// Number of products in the group.
+    pub count: usize,
+    /// Mean of `xi` within the group.
+    pub mean: f64,
+    /// Variance of `xi` around the group mean (zero for singletons).
+    pub variance: f64,
+}
+
+/// Post-estimation diagnostics of `xi` grouped by an arbitrary product classification.
+#[derive(Clone, Debug)]
+pub struct ResidualDiagnostics {
+    /// One summary per group, in order of first appearance.
+    pub groups: Vec<ResidualGroup>,
+    /// Average product of demeaned `xi` over distinct pairs in the same group, relative
+    /// to the pooled variance; `NaN` when every group is a singleton.
+    pub within_correlation: f64,
+    /// Breusch–Pagan statistic `N * R^2` from regressing `xi^2` on group dummies.
+    pub breusch_pagan: f64,
+    /// Number of groups minus one.
+    pub degrees_of_freedom: usize,
+    /// Chi-squared upper-tail probability of the Breusch–Pagan statistic.
+    pub p_value: f64,
+}
+
+impl ResidualDiagnostics {
+    /// Whether a common variance across groups is rejected at `level`, suggesting a
+    /// heteroskedasticity-robust weighting matrix.
+    pub fn rejects_homoskedasticity(&self, level: f64) -> bool {
+        self.p_value < level
+    }
+}
+
+/// Summarize the structural errors `xi` by `groups` (one id per product, e.g.
+/// [`ProductData::market_ids`], firm, or product family ids).
+///
+/// Substantial within-group correlation suggests clustering standard errors and the
+/// weighting matrix at that level; a small Breusch–Pagan p-value says the variance of
+/// `xi` differs across groups.
+pub fn residual_diagnostics(xi: &DVector<f64>, groups: &[String]) -> Result<ResidualDiagnostics> {
+    let n = xi.len();
+    if groups.len() != n {
+        return Err(BlpError::dimension_mismatch(
+            "residual groups",
+            n,
+            groups.len(),
+        ));
+    }
+    let mut members: Vec<(&String, Vec<usize>)> = Vec::new();
+    for (row, group) in groups.iter().enumerate() {
+        match members.iter_mut().find(|(id, _)| *id == group) {
+            Some((_, rows)) => rows.push(row),
+            None => members.push((group, vec![row])),
+        }
+    }
+
+    let mean = xi.mean();
+    let demeaned = xi.add_scalar(-mean);
+    let variance = demeaned.norm_squared() / n as f64;
+    let squared = xi.map(|value| value * value);
+    let squared_mean = squared.mean();
+    let (mut cross, mut pairs, mut between) = (0.0, 0.0, 0.0);
+    let summaries = members
+        .iter()
+        .map(|(group, rows)| {
+            let count = rows.len() as f64;
+            let group_mean = rows.iter().map(|&row| xi[row]).sum::<f64>() / count;
+            let spread = rows
+                .iter()
+                .map(|&row| (xi[row] - group_mean).powi(2))
+                .sum::<f64>();
+            let sum: f64 = rows.iter().map(|&row| demeaned[row]).sum();
+            let own: f64 = rows.iter().map(|&row| demeaned[row].powi(2)).sum();
+            cross += sum * sum - own;
+            pairs += count * (count - 1.0);
+            let squared_group = rows.iter().map(|&row| squared[row]).sum::<f64>() / count;
+            between += count * (squared_group - squared_mean).powi(2);
+            ResidualGroup {
+                group: group.to_string(),
+                count: rows.len(),
+                mean: group_mean,
+                variance: if rows.len() > 1 {
+                    spread / (count - 1.0)
+                } else {
+                    0.0
+                },
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let within_correlation = if pairs > 0.0 {
+        cross / pairs / variance
+    } else {
+        f64::NAN
+    };
+    let total = squared.add_scalar(-squared_mean).norm_squared();
+    let breusch_pagan = if total > 0.0 {
+        n as f64 * between / total
+    } else {
+        0.0
+    };
+    let degrees_of_freedom = summaries.len().saturating_sub(1);
+    Ok(ResidualDiagnostics {
+        groups: summaries,
+        within_correlation,
+        breusch_pagan,
+        degrees_of_freedom,
+        p_value: chi_squared_sf(breusch_pagan, degrees_of_freedom),
+    })
+}
+
+/// Approximate effect of dropping one market on the estimates.
+#[derive(Clone, Debug)]
+pub struct MarketInfluence {
+    /// Market identifier.
+    pub market_id: String,
+    /// Estimated change in `theta = [beta; vec(sigma)]` when the market is left out;
+    /// zero for parameters that were not estimated.
+    pub shift: DVector<f64>,
+    /// Increase in the full-sample objective at the shifted estimates,
+    /// `N^2 * shift' G' W G shift`, a GMM analogue of Cook's distance.
+    pub distance: f64,
+    /// Whether `distance` exceeds the flagging threshold.
+    pub influential: bool,
+}
+
+/// Leave-one-market-out influence of every market, in partition order.
+#[derive(Clone, Debug)]
+pub struct InfluenceReport {
+    /// Positions in `theta` of the parameters that were estimated.
+    pub parameters: Vec<usize>,
+    /// One entry per market.
+    pub markets: Vec<MarketInfluence>,
+}
+
+impl InfluenceReport {
+    /// Identifiers of the flagged markets.
+    pub fn influential_markets(&self) -> Vec<&str> {
+        self.markets
+            .iter()
+            .filter(|market| market.influential)
+            .map(|market| market.market_id.as_str())
+            .collect()
+    }
+}
+
+impl Problem {
+    /// One-step approximation to re-estimating without each market in turn.
+    ///
+    /// With moments `g = Z' xi / N`, Jacobian `G`, and the weighting matrix `W` in
+    /// `results`, dropping market `t` moves the estimates by approximately
+    /// `(G' W G)^{-1} G' W g_t`, where `g_t` is the market's contribution to `g`. The
+    /// estimated parameters are `beta` (unless it was fixed) and the nonzero elements of
+    /// `sigma`. A market is flagged when its distance exceeds `threshold` times the mean
+    /// distance over markets. Only the demand moments enter.
+    pub fn market_influence(
+        &self,
+        results: &ProblemResults,
+        threshold: f64,
+    ) -> Result<InfluenceReport> {
+        let data = self.data();
+        let k1 = data.linear_dim();
+        let mut theta = DVector::zeros(self.parameter_count());
+        theta.rows_mut(0, k1).copy_from(&results.beta);
+        theta
+            .rows_mut(k1, results.sigma.len())
+            .copy_from_slice(results.sigma.as_slice());
+        let fixed_beta = results.options_used.gmm.fixed_beta.is_some();
+        let parameters: Vec<usize> = (0..theta.len())
+            .filter(|&index| {
+                if index < k1 {
+                    !fixed_beta
+                } else {
+                    theta[index] != 0.0
+                }
+            })
+            .collect();
+        if parameters.len() > data.instrument_dim() {
+            return Err(BlpError::Underidentified {
+                instruments: data.instrument_dim(),
+                parameters: parameters.len(),
+            });
+        }
+
+        let moments = self.moments(&theta)?;
+        let jacobian = moments.jacobian.select_columns(&parameters);
+        let weighting = &results.weighting_matrix;
+        let hessian = jacobian.transpose() * weighting * &jacobian;
+        let projection = cholesky_inverse(&hessian).ok_or_else(|| {
+            log::warn!(
+                "Cholesky of G'WG failed; condition number {:.3e}",
+                condition_number(&hessian)
+            );
+            BlpError::singular("G'WG")
+        })? * jacobian.transpose()
+            * weighting;
+
+        let scale = 1.0 / data.product_count() as f64;
+        let mut markets = Vec::with_capacity(data.partition().market_count());
+        for market in data.partition().markets() {
+            let range = market.range();
+            let z = data.instruments().rows(range.start, range.len());
+            let xi = moments.xi.rows(range.start, range.len());
+            let contribution = z.transpose() * xi * scale;
+            let step = &projection * contribution;
+            let mut shift = DVector::zeros(theta.len());
+            for (position, &index) in parameters.iter().enumerate() {
+                shift[index] = step[position];
+            }
+            let moved = &jacobian * &step;
+            let distance = moved.dot(&(weighting * &moved)) / (scale * scale);
+            markets.push(MarketInfluence {
+                market_id: market.id().to_string(),
+                shift,
+                distance,
+                influential: false,
+            });
+        }
+        let mean = markets.iter().map(|market| market.distance).sum::<f64>() / markets.len() as f64;
+        for market in &mut markets {
+            market.influential = market.distance > threshold * mean;
+        }
+        Ok(InfluenceReport {
+            parameters,
+            markets,
+        })
+    }
+}
+
+/// Share of nonzero entries in one of the data matrices.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MatrixSparsity {
+    /// Matrix described.
+    pub matrix: DataMatrix,
+    /// Number of nonzero entries.
+    pub nonzeros: usize,
+    /// Total number of entries.
+    pub entries: usize,
+    /// Columns whose entries are all zero.
+    pub zero_columns: Vec<usize>,
+}
+
+impl MatrixSparsity {
+    fn of(matrix: DataMatrix, values: &DMatrix<f64>) -> Self {
+        Self {
+            matrix,
+            nonzeros: values.iter().filter(|value| **value != 0.0).count(),
+            entries: values.len(),
+            zero_columns: (0..values.ncols())
+                .filter(|&column| values.column(column).iter().all(|value| *value == 0.0))
+                .collect(),
+        }
+    }
+
+    /// Fraction of nonzero entries (one for an empty matrix).
+    pub fn density(&self) -> f64 {
+        if self.entries == 0 {
+            1.0
+        } else {
+            self.nonzeros as f64 / self.entries as f64
+        }
+    }
+}
+
+/// Audit of a configured problem, produced by [`Problem::dry_run`] without solving it.
+#[derive(Clone, Debug)]
+pub struct DryRunReport {
+    /// Sizes and the rough cost model of one objective evaluation.
+    pub dimensions: ProblemDimensions,
+    /// Number of moment conditions: demand instruments plus supply instruments when a
+    /// supply side is attached.
+    pub moments: usize,
+    /// Number of linear parameters concentrated out (zero when `beta` is fixed).
+    pub linear_parameters: usize,
+    /// Labels of the nonlinear parameters the optimizer searches over: the nonzero
+    /// entries of the starting `sigma`, and `rho` when a starting value is set.
+    pub nonlinear_parameters: Vec<String>,
+    /// Nonzero pattern of `X1`, `X2`, and `Z`.
+    pub sparsity: Vec<MatrixSparsity>,
+    /// Threads available to per-market work.
+    pub threads: usize,
+    /// Markets large enough to spread their share Jacobian over blocks of consumers.
+    pub large_markets: usize,
+    /// Model components and options that will be used, e.g. `supply side`.
+    pub features: Vec<String>,
+    /// Misconfigurations that would fail or weaken estimation.
+    pub warnings: Vec<String>,
+}
+
+impl DryRunReport {
+    /// Moments in excess of the estimated parameters; negative when underidentified.
+    pub fn overidentifying_restrictions(&self) -> isize {
+        self.moments as isize - (self.linear_parameters + self.nonlinear_parameters.len()) as isize
+    }
+
+    /// Whether no warnings were raised.
+    pub fn is_ready(&self) -> bool {
+        self.warnings.is_empty()
+    }
+}
+
+impl Problem {
+    /// Check the configuration without running the contraction or the optimizer: report
+    /// dimensions, memory, moments, sparsity, the thread plan, and the features in use,
+    /// and collect anything that would fail or weaken estimation as warnings.
+    ///
+    /// Construction already rejects inconsistent shapes, so the warnings cover what only
+    /// shows up later: a missing starting `sigma`, too few moments, a singular or
+    /// ill-conditioned `Z'Z`, all-zero columns, a fixed `beta` of the wrong length, and
+    /// too few effective draws.
+    pub fn dry_run(&self) -> DryRunReport {
+        let data = self.data();
+        let draws = self.draws();
+        let options = self.options();
+        let optimization = &options.optimization;
+        let k2 = data.nonlinear_dim();
+        let mut warnings = Vec::new();
+        let mut features = vec![format!("model: {}", self.model().name())];
+
+        let nonlinear_parameters = match &optimization.initial_sigma {
+            Some(sigma) => {
+                let mut start = NonlinearParameters::new(sigma.clone());
+                if let Some(rho) = optimization.initial_rho {
+                    start = start.with_rho(rho);
+                }
+                start
+                    .labelled(data)
+                    .into_iter()
+                    .filter(|(label, value)| *value != 0.0 || label == "rho")
+                    .map(|(label, _)| label)
+                    .collect()
+            }
+            None => {
+                if k2 > 0 {
+                    warnings.push(format!(
+                        "no starting sigma for the {k2} X2 columns; optimization needs one"
+                    ));
+                }
+                Vec::new()
+            }
+        };
+        let linear_parameters = match &options.gmm.fixed_beta {
+            Some(beta) => {
+                if beta.len() != data.linear_dim() {
+                    warnings.push(format!(
+                        "fixed beta has {} entries but X1 has {} columns",
+                        beta.len(),
+                        data.linear_dim()
+                    ));
+                }
+                features.push("fixed beta".to_string());
+                0
+            }
+            None => data.linear_dim(),
+        };
+
+        let mut moments = data.instrument_dim();
+        if let Some(supply) = self.supply() {
+            moments += supply.instruments().ncols();
+            features.push("supply side".to_string());
+        }
+        let parameters = linear_parameters + nonlinear_parameters.len();
+        if moments < parameters {
+            warnings.push(format!(
+                "{moments} moments cannot identify {parameters} parameters"
+            ));
+        }
+
+        let instruments = data.instruments();
+        if instruments.ncols() > 0 {
+            let ztz = instruments.transpose() * instruments;
+            let condition = condition_number(&ztz);
+            if rank(&ztz) < ztz.nrows() {
+                warnings.push("Z'Z is singular; some instruments are collinear".to_string());
+            } else if exceeds(condition, options.gmm.condition_warning_threshold) {
+                warnings.push(format!(
+                    "Z'Z is ill-conditioned (condition number {condition:.3e})"
+                ));
+            }
+        }
+        let sparsity: Vec<MatrixSparsity> = [
+            (DataMatrix::X1, data.x1()),
+            (DataMatrix::X2, data.x2()),
+            (DataMatrix::Instruments, instruments),
+        ]
+        .into_iter()
+        .map(|(matrix, values)| MatrixSparsity::of(matrix, values))
+        .collect();
+        for pattern in &sparsity {
+            if !pattern.zero_columns.is_empty() {
+                warnings.push(format!(
+                    "{} columns {:?} are entirely zero",
+                    pattern.matrix.name(),
+                    pattern.zero_columns
+                ));
+            }
+        }
+
+        let effective = draws.effective_sample_size();
+        if !draws.is_enumerated() && effective < options.contraction.minimum_effective_draws {
+            warnings.push(format!(
+                "integration weights have an effective sample size of {effective:.1} out of {} \
+                 draws",
+                draws.draw_count()
+            ));
+        }
+
+        if k2 > 0 {
+            features.push(format!(
+                "random coefficients on {}",
+                data.labels().x2.join(", ")
+            ));
+        }
+        let lognormal: Vec<&str> = data
+            .rc_types()
+            .iter()
+            .zip(&data.labels().x2)
+            .filter(|(rc_type, _)| **rc_type == RandomCoefficientType::Log)
+            .map(|(_, label)| label.as_str())
+            .collect();
+        if !lognormal.is_empty() {
+            features.push(format!(
+                "lognormal coefficients on {}",
+                lognormal.join(", ")
+            ));
+        }
+        if let Some(demographics) = draws.demographics() {
+            features.push(format!("{} demographic columns", demographics.ncols()));
+        }
+        if data.nesting_ids().is_some() {
+            features.push("nesting groups".to_string());
+        }
+        if let Ok(groups) = data.market_group_partition() {
+            features.push(format!("markets within {} market groups", groups.len()));
+        }
+        if !data.absorbed_ids().is_empty() {
+            features.push(format!(
+                "{} absorbed fixed-effect dimensions",
+                data.absorbed_ids().len()
+            ));
+        }
+        if let Some(donor) = data.donor_sample() {
+            features.push(format!(
+                "{} donor instruments from a second sample",
+                donor.column_count()
+            ));
+        }
+        if optimization.initial_rho.is_some() {
+            features.push("estimated rho".to_string());
+        }
+        if optimization.initial_pi.is_some() {
+            features.push("estimated pi".to_string());
+        }
+        if optimization.bounds.is_some() {
+            features.push("sigma bounds".to_string());
+        }
+        if let Some(mask) = &optimization.mask {
+            features.push(format!("{} parameters fixed by mask", mask.fixed_count()));
+        }
+        if optimization.diagonal_sigma {
+            features.push("diagonal sigma".to_string());
+        }
+        if optimization.cholesky {
+            features.push("Cholesky-parametrized sigma".to_string());
+        }
+        if matches!(options.gmm.weighting, WeightingMatrix::ContinuouslyUpdated) {
+            features.push("continuously-updated weighting".to_string());
+        } else if options.gmm.update_weighting {
+            features.push(format!(
+                "up to {} GMM steps with efficient weighting",
+                options.gmm.max_iterations.max(2)
+            ));
+        }
+        if let Some(settings) = &options.gmm.low_rank_weighting
+            && settings.rank < data.instrument_dim()
+        {
+            features.push(format!("rank-{} sketched weighting matrix", settings.rank));
+        }
+        if options.contraction.solver.is_some() {
+            features.push("external fixed-point solver".to_string());
+        }
+        if options.contraction.cpu_budget.is_some() {
+            features.push("contraction CPU budget".to_string());
+        }
+        if options.cache_capacity > 0 {
+            features.push(format!("inner-loop cache of {}", options.cache_capacity));
+        }
+        if options.progress.is_some() {
+            features.push("progress streaming".to_string());
+        }
+
+        let dimensions = self.dimensions();
+        let large_markets = dimensions
+            .products_per_market
+            .iter()
+            .filter(|&&products| products >= options.contraction.large_market_products)
+            .count();
+        DryRunReport {
+            dimensions,
+            moments,
+            linear_parameters,
+            nonlinear_parameters,
+            sparsity,
+            threads: options.parallelism.split(1).1,
+            large_markets,
+            features,
+            warnings,
+        }
+    }
+}
+
+fn rank(matrix: &DMatrix<f64>) -> usize {
+    let singular_values = matrix.singular_values();
+    let cutoff = 1e-10 * singular_values.max();
+    singular_values
+        .iter()
+        .filter(|value| **value > cutoff)
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{DataMatrix, ProductDataBuilder};
+    use crate::parameters::NonlinearParameters;
+    use nalgebra::DVector;
+
+    #[test]
+    fn extreme_observations_rank_within_market() {
+        let market_ids = ["a", "a", "a", "b", "b", "b"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.1, 0.1, 0.1, 0.1, 0.1, 0.1]);
+        let x1 = DMatrix::from_row_slice(6, 1, &[1.0, 2.0, 3.0, 2.0, 50.0, 1.0]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .build()
+            .unwrap();
+        let columns = [DataColumn::Shares, DataColumn::Matrix(DataMatrix::X1, 0)];
+        let extremes = extreme_observations(&data, &columns, 1).unwrap();
+        assert_eq!(extremes.len(), 2);
+        assert_eq!(extremes[1].market_id, "b");
+        assert_eq!(extremes[1].row, 4);
+        assert_eq!(extremes[1].label, "x1_0");
+    }
+
+    #[test]
+    fn logit_test_detects_random_coefficients() {
+        use crate::demand::{ShareInputs, predict_shares_with};
+        use crate::integration::SimulationDraws;
+        use crate::solving::ContractionOptions;
+
+        // Simulate shares from a model with a large random coefficient on x.
+        let markets = 40;
+        let per_market = 4;
+        let n = markets * per_market;
+        let market_ids = (0..n).map(|row| format!("m{}", row / per_market)).collect();
+        let x = DMatrix::from_fn(n, 1, |row, _| ((row * 7919) % 97) as f64 / 24.0);
+        let x1 = DMatrix::from_fn(n, 2, |row, column| if column == 0 { 1.0 } else { x[row] });
+        let placeholder = DVector::from_element(n, 0.1);
+        let data = ProductDataBuilder::new(market_ids, placeholder)
+            .x1(x1)
+            .x2(x.clone())
+            .build()
+            .unwrap();
+        let draws = SimulationDraws::standard_normal(500, 1, 17);
+        let sigma = DMatrix::from_element(1, 1, 2.0);
+        let delta = DVector::from_fn(n, |row, _| -2.0 + 0.5 * x[row]);
+        let options = ContractionOptions {
+            minimum_share: 0.0,
+            ..ContractionOptions::default()
+        };
+        let inputs = ShareInputs::new(&data, &draws, &sigma, &options);
+        let shares = predict_shares_with(&delta, &inputs).unwrap();
+        let simulated = ProductDataBuilder::new(
+            (0..n).map(|row| format!("m{}", row / per_market)).collect(),
+            shares,
+        )
+        .x1(data.x1().clone())
+        .x2(x)
+        .build()
+        .unwrap();
+
+        let test = logit_specification_test(&simulated).unwrap();
+        assert_eq!(test.degrees_of_freedom, 1);
+        assert_eq!(test.rival_columns, vec!["x2_0"]);
+        assert!(test.rejects_logit(0.01), "p-value {}", test.p_value);
+    }
+
+    #[test]
+    fn hausman_test_detects_endogenous_price() {
+        let markets = 40;
+        let n = markets * 2;
+        let market_ids = (0..n).map(|j| format!("m{}", j / 2)).collect();
+        let cost = DVector::from_fn(n, |j, _| ((j * 37) % 23) as f64 / 23.0);
+        let xi = DVector::from_fn(n, |j, _| ((j * 53) % 19) as f64 / 19.0 - 0.5);
+        // Price loads on xi, so OLS is biased towards zero.
+        let price = DVector::from_fn(n, |j, _| 1.0 + cost[j] + 0.8 * xi[j]);
+        let delta = DVector::from_fn(n, |j, _| 0.5 - 2.0 * price[j] + xi[j]);
+        let mut shares = DVector::zeros(n);
+        for market in 0..markets {
+            let (a, b) = (delta[2 * market].exp(), delta[2 * market + 1].exp());
+            shares[2 * market] = a / (1.0 + a + b);
+            shares[2 * market + 1] = b / (1.0 + a + b);
+        }
+        let x1 = DMatrix::from_fn(n, 2, |j, k| if k == 0 { 1.0 } else { price[j] });
+        let z = DMatrix::from_fn(n, 2, |j, k| if k == 0 { 1.0 } else { cost[j] });
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .instruments(z)
+            .x1_labels(vec!["const".into(), "price".into()])
+            .build()
+            .unwrap();
+
+        let test = hausman_test(&data).unwrap();
+        assert_eq!(test.endogenous_columns, vec!["price"]);
+        assert!(
+            (test.iv_beta[1] + 2.0).abs() < 0.2,
+            "iv {}",
+            test.iv_beta[1]
+        );
+        assert!(test.ols_beta[1] > test.iv_beta[1]);
+        assert!(test.rejects_exogeneity(0.01), "p-value {}", test.p_value);
+    }
+
+    #[test]
+    fn residual_diagnostics_detect_group_patterns() {
+        // Four groups of five; the last group is both shifted and far noisier.
+        let groups: Vec<String> = (0..20).map(|j| format!("firm{}", j / 5)).collect();
+        let noise = |j: usize| ((j * 37) % 11) as f64 / 11.0 - 0.5;
+        let xi = DVector::from_fn(20, |j, _| {
+            if j >= 15 {
+                2.0 + 8.0 * noise(j)
+            } else {
+                0.2 * noise(j)
+            }
+        });
+
+        let diagnostics = residual_diagnostics(&xi, &groups).unwrap();
+        assert_eq!(diagnostics.groups.len(), 4);
+        assert_eq!(diagnostics.groups[3].group, "firm3");
+        assert_eq!(diagnostics.groups[3].count, 5);
+        assert!(diagnostics.groups[3].variance > 100.0 * diagnostics.groups[0].variance);
+        assert!(diagnostics.within_correlation > 0.0);
+        assert_eq!(diagnostics.degrees_of_freedom, 3);
+        assert!(
+            diagnostics.rejects_homoskedasticity(0.05),
+            "p-value {}",
+            diagnostics.p_value
+        );
+        assert!(residual_diagnostics(&xi, &groups[..3]).is_err());
+    }
+
+    #[test]
+    fn market_influence_approximates_dropping_each_market() {
+        use crate::integration::SimulationDraws;
+
+        let markets = 10;
+        let market_ids: Vec<String> = (0..2 * markets).map(|j| format!("m{}", j / 2)).collect();
+        let wave = |j: usize| ((j * 7) % 5) as f64 / 5.0;
+        let shares = DVector::from_fn(2 * markets, |j, _| {
+            if j == 2 * markets - 2 {
+                0.6
+            } else {
+                0.1 + 0.15 * wave(j)
+            }
+        });
+        let x1 = DMatrix::from_fn(2 * markets, 2, |j, col| {
+            if col == 0 { 1.0 } else { 1.0 + wave(j + 3) }
+        });
+        let instruments = DMatrix::from_fn(2 * markets, 3, |j, col| match col {
+            0 => 1.0,
+            1 => x1[(j, 1)],
+            _ => ((j * 3) % 4) as f64,
+        });
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .instruments(instruments)
+            .build()
+            .unwrap();
+        let problem = Problem::new(data, SimulationDraws::standard_normal(1, 0, 1)).unwrap();
+        let results = problem.solve(&NonlinearParameters::default()).unwrap();
+
+        let report = problem.market_influence(&results, 3.0).unwrap();
+        assert_eq!(report.parameters, vec![0, 1]);
+        assert_eq!(report.markets.len(), markets);
+        assert_eq!(report.influential_markets(), vec!["m9"]);
+
+        let without_first = problem
+            .with_modified_data(|data| data.select_markets(&(1..markets).collect::<Vec<_>>()))
+            .unwrap()
+            .solve(&NonlinearParameters::default())
+            .unwrap();
+        let exact = &without_first.beta - &results.beta;
+        let approximate = &report.markets[0].shift;
+        assert!(
+            (approximate - &exact).norm() < 0.5 * exact.norm(),
+            "approximate {approximate} exact {exact}"
+        );
+    }
+
+    #[test]
+    fn dry_run_reports_plan_and_warnings() {
+        use crate::integration::SimulationDraws;
+        use crate::options::{OptimizationOptions, ProblemOptions};
+
+        let market_ids = ["a", "a", "b", "b"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.25, 0.15]);
+        let x1 = DMatrix::from_row_slice(4, 2, &[1.0, 1.0, 1.0, 2.0, 1.0, 1.5, 1.0, 0.5]);
+        let z = DMatrix::from_row_slice(
+            4,
+            3,
+            &[1.0, 1.0, 0.0, 1.0, 2.0, 0.0, 1.0, 1.5, 0.0, 1.0, 0.5, 0.0],
+        );
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .x2_from_x1(vec![1])
+            .instruments(z)
+            .build()
+            .unwrap();
+        let draws = SimulationDraws::standard_normal(50, 1, 1);
+
+        let problem = Problem::new(data.clone(), draws.clone()).unwrap();
+        let report = problem.dry_run();
+        assert_eq!(report.moments, 3);
+        assert_eq!(report.linear_parameters, 2);
+        assert!(report.nonlinear_parameters.is_empty());
+        assert_eq!(report.sparsity[2].zero_columns, vec![2]);
+        assert_eq!(report.sparsity[2].density(), 8.0 / 12.0);
+        assert!(
+            report
+                .warnings
+                .iter()
+                .any(|warning| warning.contains("starting sigma"))
+        );
+        assert!(
+            report
+                .warnings
+                .iter()
+                .any(|warning| warning.contains("singular"))
+        );
+        assert!(!report.is_ready());
+
+        let options = ProblemOptions::default()
+            .with_optimization(OptimizationOptions::new(DMatrix::from_element(1, 1, 0.5)))
+            .with_cache_capacity(4);
+        let problem = Problem::with_options(data, draws, options).unwrap();
+        let report = problem.dry_run();
+        assert_eq!(report.nonlinear_parameters, vec!["sigma[x1_1]".to_string()]);
+        assert_eq!(report.overidentifying_restrictions(), 0);
+        assert!(
+            report
+                .features
+                .contains(&"inner-loop cache of 4".to_string())
+        );
+        assert!(report.threads >= 1);
+    }
+}
@@ -0,0 +1,279 @@
+//! Per-market tasks for distributing objective evaluations across machines.
+//!
+//! The inner loop decomposes by market: `delta_t` depends only on market `t`'s data.
+//! [`Problem::market_tasks`] packages each market as a serializable [`MarketTask`] that
+//! a worker evaluates on its own (e.g. behind an MPI or job-queue layer), and
+//! [`Problem::aggregate_market_contributions`] reassembles the returned
+//! [`MarketContribution`]s in partition order, whatever order they arrive in, before
+//! running the same linear IV step as [`Problem::solve`].
+
+use std::collections::HashMap;
+use std::time::Instant;
+
+use nalgebra::{DMatrix, DVector};
+use serde::{Deserialize, Serialize};
+
+use crate::data::{ProductDataBuilder, RandomCoefficientType};
+use crate::demand::{ShareInputs, invert_model};
+use crate::diagnostics::ProfilingReport;
+use crate::error::{BlpError, Result};
+use crate::estimation::{InnerSolution, Problem, ProblemResults};
+use crate::integration::SimulationDraws;
+use crate::options::ProblemOptions;
+use crate::parameters::NonlinearParameters;
+use crate::solving::{ContractionOptions, ContractionSummary};
+
+/// Everything a worker needs to solve one market's inner loop.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MarketTask {
+    /// Market identifier.
+    pub market_id: String,
+    /// Observed shares of the market's products.
+    pub shares: DVector<f64>,
+    /// Linear characteristics of the market's products.
+    pub x1: DMatrix<f64>,
+    /// Nonlinear characteristics of the market's products.
+    pub x2: DMatrix<f64>,
+    /// How each `X2` column's random coefficient enters utility; empty for all linear.
+    #[serde(default)]
+    pub rc_types: Vec<RandomCoefficientType>,
+    /// Integration nodes, one row per simulated consumer.
+    pub draws: DMatrix<f64>,
+    /// Integration weights.
+    pub weights: DVector<f64>,
+    /// Nonlinear parameters to evaluate at.
+    pub sigma: DMatrix<f64>,
+    /// Contraction settings (an external fixed-point solver is not transferred).
+    pub options: ContractionOptions,
+}
+
+/// A worker's result for one market.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MarketContribution {
+    /// Market identifier, used to put contributions back in order.
+    pub market_id: String,
+    /// Mean utilities of the market's products.
+    pub delta: DVector<f64>,
+    /// Predicted shares at `delta`.
+    pub predicted_shares: DVector<f64>,
+    /// `d delta_t / d vec(sigma)`, for gradient-based outer loops.
+    pub delta_jacobian: DMatrix<f64>,
+    /// Diagnostics from the market's contraction.
+    pub contraction: ContractionSummary,
+    /// Wall-clock seconds the worker spent.
+    pub seconds: f64,
+}
+
+impl MarketTask {
+    /// Solve the market's contraction under the random coefficients logit.
+    pub fn evaluate(&self) -> Result<MarketContribution> {
+        let started = Instant::now();
+        let mut builder = ProductDataBuilder::new(
+            vec![self.market_id.clone(); self.shares.len()],
+            self.shares.clone(),
+        )
+        .x1(self.x1.clone())
+        .x2(self.x2.clone());
+        if !self.rc_types.is_empty() {
+            builder = builder.rc_types(self.rc_types.clone());
+        }
+        let data = builder.build()?;
+        let draws = SimulationDraws::new(self.draws.clone(), self.weights.clone())?;
+        let options = ProblemOptions::default().with_contraction(self.options.clone());
+        let problem = Problem::with_options(data, draws, options)?;
+
+        let inputs = ShareInputs::new(
+            problem.data(),
+            problem.draws(),
+            &self.sigma,
+            &problem.options().contraction,
+        );
+        let (delta, contraction) = invert_model(problem.model(), &inputs)?;
+        let predicted_shares = problem.model().shares(&delta, &inputs)?;
+        let delta_jacobian = problem.delta_jacobian(&delta, &inputs)?;
+        Ok(MarketContribution {
+            market_id: self.market_id.clone(),
+            delta,
+            predicted_shares,
+            delta_jacobian,
+            contraction,
+            seconds: started.elapsed().as_secs_f64(),
+        })
+    }
+}
+
+/// Results assembled from distributed market contributions.
+#[derive(Clone, Debug)]
+pub struct DistributedEvaluation {
+    /// Same output as [`Problem::solve`] at the task's `sigma`.
+    pub results: ProblemResults,
+    /// Stacked `d delta / d vec(sigma)`, one row per product.
+    pub delta_jacobian: DMatrix<f64>,
+}
+
+impl Problem {
+    /// Split the inner loop at `sigma` into one task per market, in partition order.
+    pub fn market_tasks(&self, sigma: &DMatrix<f64>) -> Vec<MarketTask> {
+        let data = self.data();
+        self.data()
+            .partition()
+            .markets()
+            .map(|market| {
+                let range = market.range();
+                MarketTask {
+                    market_id: market.id().to_string(),
+                    shares: data.shares().rows(range.start, range.len()).into_owned(),
+                    x1: data.x1().rows(range.start, range.len()).into_owned(),
+                    x2: data.x2().rows(range.start, range.len()).into_owned(),
+                    rc_types: data.rc_types().to_vec(),
+                    draws: self.draws().draws().clone(),
+                    weights: self.draws().weights().clone(),
+                    sigma: sigma.clone(),
+                    options: self.options().contraction.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Combine one contribution per market into full results at `sigma`.
+    ///
+    /// Contributions may arrive in any order; they are placed by market id so the result
+    /// is identical to evaluating the tasks in sequence. The contraction summary reports
+    /// the largest iteration count and gap over markets, and a criterion only when every
+    /// market converged under the same one.
+    pub fn aggregate_market_contributions(
+        &self,
+        sigma: &DMatrix<f64>,
+        contributions: Vec<MarketContribution>,
+    ) -> Result<DistributedEvaluation> {
+        let data = self.data();
+        let markets = data.partition().market_count();
+        if contributions.len() != markets {
+            return Err(BlpError::dimension_mismatch(
+                "market contributions",
+                markets,
+                contributions.len(),
+            ));
+        }
+        let mut by_market: HashMap<String, MarketContribution> = contributions
+            .into_iter()
+            .map(|contribution| (contribution.market_id.clone(), contribution))
+            .collect();
+
+        let n = data.product_count();
+        let k2 = data.nonlinear_dim();
+        let mut delta = DVector::zeros(n);
+        let mut predicted_shares = DVector::zeros(n);
+        let mut delta_jacobian = DMatrix::zeros(n, k2 * k2);
+        let mut contraction = ContractionSummary::default();
+        let mut criteria = Vec::with_capacity(markets);
+        let mut seconds = 0.0;
+        for market in data.partition().markets() {
+            let contribution =
+                by_market
+                    .remove(market.id())
+                    .ok_or_else(|| BlpError::UnknownMarket {
+                        market_id: market.id().to_string(),
+                    })?;
+            let range = market.range();
+            if contribution.delta.len() != range.len() {
+                return Err(BlpError::dimension_mismatch(
+                    "market contribution delta",
+                    range.len(),
+                    contribution.delta.len(),
+                ));
+            }
+            delta
+                .rows_mut(range.start, range.len())
+                .copy_from(&contribution.delta);
+            predicted_shares
+                .rows_mut(range.start, range.len())
+                .copy_from(&contribution.predicted_shares);
+            delta_jacobian
+                .rows_mut(range.start, range.len())
+                .copy_from(&contribution.delta_jacobian);
+            contraction.iterations = contraction
+                .iterations
+                .max(contribution.contraction.iterations);
+            contraction.max_gap = contraction.max_gap.max(contribution.contraction.max_gap);
+            contraction.max_share_error = contraction
+                .max_share_error
+                .max(contribution.contraction.max_share_error);
+            contraction.share_evaluations += contribution.contraction.share_evaluations;
+            contraction.seconds += contribution.contraction.seconds;
+            contraction
+                .truncated_markets
+                .extend(contribution.contraction.truncated_markets);
+            criteria.push(contribution.contraction.criterion);
+            seconds += contribution.seconds;
+        }
+
+        if criteria.windows(2).all(|pair| pair[0] == pair[1]) {
+            contraction.criterion = criteria.first().copied().flatten();
+        }
+
+        let inner = InnerSolution {
+            parameters: NonlinearParameters::new(sigma.clone()),
+            delta,
+            predicted_shares,
+            contraction,
+        };
+        let results = self.linear_step(
+            inner,
+            self.options(),
+            ProfilingReport {
+                contraction_seconds: seconds,
+                ..ProfilingReport::default()
+            },
+        )?;
+        Ok(DistributedEvaluation {
+            results,
+            delta_jacobian,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+    use crate::parameters::NonlinearParameters;
+
+    #[test]
+    fn out_of_order_contributions_reproduce_solve() {
+        let market_ids = ["m1", "m1", "m2", "m2", "m3"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.25, 0.15, 0.4]);
+        let x1 = DMatrix::from_row_slice(5, 2, &[1.0, 1.0, 1.0, 2.0, 1.0, 1.5, 1.0, 0.5, 1.0, 1.2]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1.clone())
+            .x2(x1.columns(1, 1).into_owned())
+            .build()
+            .unwrap();
+        let problem = Problem::new(data, SimulationDraws::standard_normal(30, 1, 4)).unwrap();
+        let sigma = DMatrix::from_element(1, 1, 0.7);
+        let expected = problem
+            .solve(&NonlinearParameters::new(sigma.clone()))
+            .unwrap();
+
+        let tasks = problem.market_tasks(&sigma);
+        assert_eq!(tasks.len(), 3);
+        // Round-trip through JSON as a remote worker would.
+        let mut contributions: Vec<MarketContribution> = tasks
+            .iter()
+            .map(|task| {
+                let wire = serde_json::to_string(task).unwrap();
+                let task: MarketTask = serde_json::from_str(&wire).unwrap();
+                task.evaluate().unwrap()
+            })
+            .collect();
+        contributions.reverse();
+
+        let evaluation = problem
+            .aggregate_market_contributions(&sigma, contributions)
+            .unwrap();
+        assert_relative_eq!(evaluation.results.delta, expected.delta, epsilon = 1e-8);
+        assert_relative_eq!(evaluation.results.beta, expected.beta, epsilon = 1e-8);
+        assert_eq!(evaluation.delta_jacobian.shape(), (5, 1));
+    }
+}
@@ -0,0 +1,208 @@
+//! Diversion ratios and their split within and across groups of products.
+//!
+//! Following pyBLP, each market's diversion matrix has entry `(j, k)` equal to the
+//! fraction of product `j`'s lost sales that go to product `k`, with the diagonal
+//! holding the fraction that goes to the outside good. Rows therefore sum to one.
+
+use nalgebra::{DMatrix, DVector};
+
+use crate::demand::{ShareInputs, individual_shares};
+use crate::elasticities::Characteristic;
+use crate::error::{BlpError, Result};
+use crate::estimation::{Problem, ProblemResults};
+
+/// Where each product's diverted sales go, aggregated by group.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GroupDiversion {
+    /// Fraction diverted to other products in the same group.
+    pub within: f64,
+    /// Fraction diverted to products in other groups.
+    pub across: f64,
+    /// Fraction diverted to the outside good.
+    pub outside: f64,
+}
+
+impl Problem {
+    /// Short-run (marginal) diversion ratios: the share of sales lost by `j` after a small
+    /// change in its characteristic (typically price) that is captured by each rival,
+    /// `-(ds_k / dx_j) / (ds_j / dx_j)`.
+    pub fn diversion_ratios(
+        &self,
+        results: &ProblemResults,
+        characteristic: Characteristic,
+    ) -> Result<Vec<DMatrix<f64>>> {
+        let derivatives = self.share_derivatives(results, characteristic)?;
+        Ok(derivatives
+            .iter()
+            .map(|derivatives| {
+                let size = derivatives.nrows();
+                DMatrix::from_fn(size, size, |j, k| {
+                    if j == k {
+                        // Sales not captured by inside rivals leave for the outside good.
+                        derivatives.column(j).sum() / derivatives[(j, j)]
+                    } else {
+                        -derivatives[(k, j)] / derivatives[(j, j)]
+                    }
+                })
+            })
+            .collect())
+    }
+
+    /// Long-run (second-choice) diversion ratios: where `j`'s customers go when `j` is
+    /// removed from the choice set, `(s_k(without j) - s_k) / s_j`.
+    ///
+    /// Removing `j` rescales each consumer's remaining logit probabilities by
+    /// `1 / (1 - p_ij)` at fixed `delta`, so no re-solving is needed.
+    pub fn second_choice_diversion(&self, results: &ProblemResults) -> Result<Vec<DMatrix<f64>>> {
+        let data = self.data();
+        let inputs = ShareInputs::new(
+            data,
+            self.draws(),
+            &results.sigma,
+            &self.options().contraction,
+        );
+        let probabilities = individual_shares(&results.delta, &inputs)?;
+        let weights = self.draws().weights();
+
+        let mut matrices = Vec::with_capacity(data.partition().market_count());
+        for market in data.partition().markets() {
+            let range = market.range();
+            let mut diversion = DMatrix::zeros(range.len(), range.len());
+            for (draw, weight) in weights.iter().enumerate() {
+                let inside: f64 = range.clone().map(|row| probabilities[(row, draw)]).sum();
+                for (j, product_j) in range.clone().enumerate() {
+                    let p_j = probabilities[(product_j, draw)];
+                    let scale = weight * p_j / (1.0 - p_j);
+                    for (k, product_k) in range.clone().enumerate() {
+                        let destination = if j == k {
+                            1.0 - inside
+                        } else {
+                            probabilities[(product_k, draw)]
+                        };
+                        diversion[(j, k)] += scale * destination;
+                    }
+                }
+            }
+            for (j, product_j) in range.clone().enumerate() {
+                diversion
+                    .row_mut(j)
+                    .unscale_mut(results.predicted_shares[product_j]);
+            }
+            matrices.push(diversion);
+        }
+        Ok(matrices)
+    }
+
+    /// Split each product's diversion into its own group, other groups, and the outside
+    /// good, e.g. with nests or firms as `groups` (one label per product).
+    pub fn group_diversion(
+        &self,
+        diversion: &[DMatrix<f64>],
+        groups: &[String],
+    ) -> Result<Vec<GroupDiversion>> {
+        let data = self.data();
+        if groups.len() != data.product_count() {
+            return Err(BlpError::dimension_mismatch(
+                "group labels",
+                data.product_count(),
+                groups.len(),
+            ));
+        }
+        let mut summaries = Vec::with_capacity(data.product_count());
+        for (market, matrix) in data.partition().markets().zip(diversion) {
+            let range = market.range();
+            for (j, product_j) in range.clone().enumerate() {
+                let mut summary = GroupDiversion {
+                    within: 0.0,
+                    across: 0.0,
+                    outside: matrix[(j, j)],
+                };
+                for (k, product_k) in range.clone().enumerate() {
+                    if j == k {
+                        continue;
+                    } else if groups[product_j] == groups[product_k] {
+                        summary.within += matrix[(j, k)];
+                    } else {
+                        summary.across += matrix[(j, k)];
+                    }
+                }
+                summaries.push(summary);
+            }
+        }
+        Ok(summaries)
+    }
+
+    /// Largest violation of the model's analytic diversion restrictions, as a check on
+    /// computed diversion ratios.
+    ///
+    /// Under the plain logit (no random coefficients) both diversion measures satisfy
+    /// independence of irrelevant alternatives: `D_jk = s_k / (1 - s_j)`, including the
+    /// outside good. Returns `None` for specifications without a closed-form restriction.
+    pub fn diversion_restriction_gap(
+        &self,
+        results: &ProblemResults,
+        diversion: &[DMatrix<f64>],
+    ) -> Option<f64> {
+        if self.data().nonlinear_dim() != 0 {
+            return None;
+        }
+        let shares: &DVector<f64> = &results.predicted_shares;
+        let mut gap = 0.0_f64;
+        for (market, matrix) in self.data().partition().markets().zip(diversion) {
+            let range = market.range();
+            let outside = 1.0 - range.clone().map(|row| shares[row]).sum::<f64>();
+            for (j, product_j) in range.clone().enumerate() {
+                for (k, product_k) in range.clone().enumerate() {
+                    let destination = if j == k { outside } else { shares[product_k] };
+                    let expected = destination / (1.0 - shares[product_j]);
+                    gap = gap.max((matrix[(j, k)] - expected).abs());
+                }
+            }
+        }
+        Some(gap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+    use crate::data::ProductDataBuilder;
+    use crate::integration::SimulationDraws;
+    use crate::parameters::NonlinearParameters;
+
+    #[test]
+    fn logit_diversion_satisfies_iia() {
+        let market_ids = ["m1", "m1", "m1", "m2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.1, 0.4]);
+        let x1 = DMatrix::from_row_slice(4, 2, &[1.0, 1.0, 1.0, 2.0, 1.0, 0.5, 1.0, 1.5]);
+        let instruments = DMatrix::from_row_slice(4, 2, &[1.0, 0.5, 1.0, 2.5, 1.0, 0.7, 1.0, 1.0]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .instruments(instruments)
+            .build()
+            .unwrap();
+        let problem = Problem::new(data, SimulationDraws::standard_normal(1, 0, 1)).unwrap();
+        let results = problem.solve(&NonlinearParameters::default()).unwrap();
+
+        let marginal = problem
+            .diversion_ratios(&results, Characteristic::linear(1))
+            .unwrap();
+        let second_choice = problem.second_choice_diversion(&results).unwrap();
+        for diversion in [&marginal, &second_choice] {
+            let gap = problem.diversion_restriction_gap(&results, diversion);
+            assert!(gap.unwrap() < 1e-10);
+            assert_relative_eq!(diversion[0].row(0).sum(), 1.0, epsilon = 1e-12);
+        }
+
+        let groups = ["a", "a", "b", "a"].map(String::from).to_vec();
+        let split = problem.group_diversion(&second_choice, &groups).unwrap();
+        assert_relative_eq!(split[0].within, second_choice[0][(0, 1)]);
+        assert_relative_eq!(split[0].across, second_choice[0][(0, 2)]);
+        assert_relative_eq!(split[3].outside, 1.0, epsilon = 1e-12);
+        // A lone product loses all its customers to the outside good.
+        assert_eq!(marginal[1].shape(), (1, 1));
+        assert_relative_eq!(marginal[1][(0, 0)], 1.0, epsilon = 1e-12);
+    }
+}
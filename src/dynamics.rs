@@ -0,0 +1,140 @@
+//! Building blocks for myopic dynamic demand on panels of markets.
+//!
+//! When the same population of consumers shops in a sequence of markets (periods), a
+//! consumer who buys an inside good leaves the market, so the mass of each consumer
+//! type still shopping in period `t` is the product of their outside-good probabilities
+//! in earlier periods. These helpers evaluate that chain at estimated parameters; they
+//! do not estimate a dynamic model.
+
+use nalgebra::{DMatrix, DVector};
+
+use crate::demand::{ShareInputs, individual_shares};
+use crate::error::{BlpError, Result};
+use crate::estimation::{Problem, ProblemResults};
+
+/// Per-period objects along a chain of markets.
+#[derive(Clone, Debug)]
+pub struct ChainedDemand {
+    /// Market ids in chain order.
+    pub periods: Vec<String>,
+    /// `ln(1 + sum_j exp(V_jrt))` for each period (row) and consumer type (column).
+    pub inclusive_values: DMatrix<f64>,
+    /// Fraction of each consumer type (column) that has not bought before each period
+    /// (row); the first row is all ones.
+    pub remaining: DMatrix<f64>,
+    /// Shares of each period's products among the original population,
+    /// `sum_r w_r * remaining_rt * p_jrt`, in the market's product order.
+    pub chained_shares: Vec<DVector<f64>>,
+    /// Fraction of the original population still without a purchase after the last
+    /// period.
+    pub never_bought: f64,
+}
+
+impl ChainedDemand {
+    /// Expected inclusive value of each period over the full population, in utils.
+    pub fn expected_inclusive_values(&self, weights: &DVector<f64>) -> DVector<f64> {
+        &self.inclusive_values * weights
+    }
+}
+
+impl Problem {
+    /// Chain the markets `periods` (in order) over the problem's consumer types, treating
+    /// each purchase as terminal.
+    ///
+    /// Draws are shared across markets, so consumer type `r` is the same person in every
+    /// period. Static shares are recovered in the first period.
+    pub fn chain_markets(
+        &self,
+        results: &ProblemResults,
+        periods: &[&str],
+    ) -> Result<ChainedDemand> {
+        let data = self.data();
+        let draws = self.draws();
+        let markets: Vec<_> = data.partition().markets().collect();
+        let inputs = ShareInputs::new(data, draws, &results.sigma, &self.options().contraction);
+        let probabilities = individual_shares(&results.delta, &inputs)?;
+
+        let agents = draws.weights().len();
+        let mut inclusive_values = DMatrix::zeros(periods.len(), agents);
+        let mut remaining = DMatrix::zeros(periods.len(), agents);
+        let mut chained_shares = Vec::with_capacity(periods.len());
+        let mut still_shopping = DVector::from_element(agents, 1.0);
+
+        for (period, id) in periods.iter().enumerate() {
+            let market = markets
+                .iter()
+                .find(|market| market.id() == *id)
+                .ok_or_else(|| BlpError::UnknownMarket {
+                    market_id: id.to_string(),
+                })?;
+            let range = market.range();
+            remaining.row_mut(period).tr_copy_from(&still_shopping);
+
+            let mut shares = DVector::zeros(range.len());
+            for (agent, weight) in draws.weights().iter().enumerate() {
+                let mass = weight * still_shopping[agent];
+                let mut inside = 0.0;
+                for (position, row) in range.clone().enumerate() {
+                    let probability = probabilities[(row, agent)];
+                    shares[position] += mass * probability;
+                    inside += probability;
+                }
+                let outside = 1.0 - inside;
+                inclusive_values[(period, agent)] = -outside.ln();
+                still_shopping[agent] *= outside;
+            }
+            chained_shares.push(shares);
+        }
+
+        Ok(ChainedDemand {
+            periods: periods.iter().map(|id| id.to_string()).collect(),
+            inclusive_values,
+            remaining,
+            chained_shares,
+            never_bought: still_shopping.dot(draws.weights()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+    use crate::data::ProductDataBuilder;
+    use crate::integration::SimulationDraws;
+    use crate::parameters::NonlinearParameters;
+
+    #[test]
+    fn chained_shares_deplete_the_population() {
+        let market_ids = ["t1", "t1", "t2", "t2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.25, 0.15]);
+        let x1 = DMatrix::from_row_slice(4, 1, &[1.0, 2.0, 1.5, 0.5]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1.clone())
+            .x2(x1)
+            .build()
+            .unwrap();
+        let problem = Problem::new(data, SimulationDraws::standard_normal(50, 1, 3)).unwrap();
+        let results = problem
+            .solve(&NonlinearParameters::new(DMatrix::from_element(1, 1, 0.8)))
+            .unwrap();
+
+        let chain = problem.chain_markets(&results, &["t1", "t2"]).unwrap();
+        let first = &chain.chained_shares[0];
+        assert_relative_eq!(first[0], 0.2, epsilon = 1e-8);
+        assert_relative_eq!(first[1], 0.3, epsilon = 1e-8);
+        // Second-period buyers come only from first-period non-buyers.
+        let second = &chain.chained_shares[1];
+        assert!(second[0] < 0.25 && second[1] < 0.15);
+        let bought: f64 = chain.chained_shares.iter().map(|shares| shares.sum()).sum();
+        assert_relative_eq!(bought + chain.never_bought, 1.0, epsilon = 1e-10);
+
+        let expected = chain.expected_inclusive_values(problem.draws().weights());
+        assert!(expected.iter().all(|value| *value > 0.0));
+        assert!(matches!(
+            problem.chain_markets(&results, &["t3"]),
+            Err(BlpError::UnknownMarket { .. })
+        ));
+    }
+}
@@ -0,0 +1,442 @@
+//! Elasticities of market shares with respect to product characteristics.
+
+use nalgebra::{DMatrix, DVector};
+
+use crate::data::ProductData;
+use crate::demand::{ShareInputs, consumer_tastes, individual_shares};
+use crate::error::{BlpError, Result};
+use crate::estimation::{Problem, ProblemResults};
+
+/// Where a characteristic enters utility.
+///
+/// A characteristic with both a mean coefficient and a random coefficient (the usual case
+/// for price) appears in `X1` and in `X2`; its marginal utility for consumer `i` is then
+/// `beta[x1] + (sigma * nu_i + pi * d_i)[x2]`, exponentiated for a
+/// [`Log`](crate::data::RandomCoefficientType::Log) coefficient. When the two columns share a label (see
+/// [`ColumnLabels::shared`](crate::data::ColumnLabels::shared)), naming either one is
+/// enough: the other is filled in so both coefficients enter the chain rule.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Characteristic {
+    /// Column of `X1`, if the characteristic has a linear coefficient.
+    pub x1: Option<usize>,
+    /// Column of `X2`, if the characteristic has a random coefficient.
+    pub x2: Option<usize>,
+}
+
+impl Characteristic {
+    /// A characteristic that only enters through `X1`.
+    pub fn linear(column: usize) -> Self {
+        Self {
+            x1: Some(column),
+            x2: None,
+        }
+    }
+
+    /// A characteristic that only enters through `X2`.
+    pub fn nonlinear(column: usize) -> Self {
+        Self {
+            x1: None,
+            x2: Some(column),
+        }
+    }
+
+    /// A characteristic with both a linear and a random coefficient.
+    pub fn both(x1: usize, x2: usize) -> Self {
+        Self {
+            x1: Some(x1),
+            x2: Some(x2),
+        }
+    }
+
+    /// Find a characteristic by its column label in `X1` and/or `X2`.
+    pub fn by_label(data: &ProductData, label: &str) -> Result<Self> {
+        let labels = data.labels();
+        let characteristic = Self {
+            x1: labels.x1.iter().position(|name| name == label),
+            x2: labels.x2.iter().position(|name| name == label),
+        };
+        if characteristic.x1.is_none() && characteristic.x2.is_none() {
+            return Err(BlpError::missing_component(
+                "characteristic with that label",
+            ));
+        }
+        Ok(characteristic)
+    }
+
+    /// Add the matching column of the other matrix when the characteristic is shared.
+    fn completed(self, data: &ProductData) -> Self {
+        let shared = data.labels().shared();
+        Self {
+            x1: self.x1.or_else(|| {
+                let x2 = self.x2?;
+                shared.iter().find(|pair| pair.1 == x2).map(|pair| pair.0)
+            }),
+            x2: self.x2.or_else(|| {
+                let x1 = self.x1?;
+                shared.iter().find(|pair| pair.0 == x1).map(|pair| pair.1)
+            }),
+        }
+    }
+
+    /// Observed values of the characteristic, taken from `X1` when it appears there.
+    pub(crate) fn values(&self, data: &ProductData) -> DVector<f64> {
+        match (self.x1, self.x2) {
+            (Some(column), _) => data.x1().column(column).into_owned(),
+            (None, Some(column)) => data.x2().column(column).into_owned(),
+            (None, None) => DVector::zeros(data.product_count()),
+        }
+    }
+}
+
+/// Scaling applied to the share derivatives.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ElasticityKind {
+    /// `(ds_j / dx_k) * x_k / s_j`: percentage change in share per percentage change in `x`.
+    #[default]
+    Elasticity,
+    /// `(ds_j / dx_k) / s_j`: percentage change in share per unit change in `x`. Use this
+    /// for dummy characteristics and anything with zeros.
+    SemiElasticity,
+}
+
+/// Equal-width histogram, returned as data for tables and plots.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Histogram {
+    /// Bin edges; bin `i` covers `[edges[i], edges[i + 1])`, the last bin is closed.
+    pub edges: Vec<f64>,
+    /// Total weight that falls in each bin (a count when unweighted).
+    pub counts: Vec<f64>,
+}
+
+/// Distribution of a set of elasticities.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ElasticitySummary {
+    /// Number of values summarized.
+    pub count: usize,
+    /// Weighted mean.
+    pub mean: f64,
+    /// `(probability, value)` pairs from the inverse of the weighted empirical CDF.
+    pub quantiles: Vec<(f64, f64)>,
+    /// Histogram over `[min, max]`.
+    pub histogram: Histogram,
+}
+
+impl ElasticitySummary {
+    /// Summarize equally weighted values, e.g. own elasticities across products.
+    pub fn new(values: &[f64], probabilities: &[f64], bins: usize) -> Self {
+        Self::weighted(values, &vec![1.0; values.len()], probabilities, bins)
+    }
+
+    /// Summarize weighted values, e.g. consumer-type elasticities with integration weights.
+    /// Non-finite values are skipped.
+    pub fn weighted(values: &[f64], weights: &[f64], probabilities: &[f64], bins: usize) -> Self {
+        let mut pairs: Vec<(f64, f64)> = values
+            .iter()
+            .zip(weights)
+            .filter(|(value, _)| value.is_finite())
+            .map(|(value, weight)| (*value, *weight))
+            .collect();
+        pairs.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let total: f64 = pairs.iter().map(|(_, weight)| weight).sum();
+        let mean = pairs
+            .iter()
+            .map(|(value, weight)| value * weight)
+            .sum::<f64>()
+            / total;
+
+        let quantiles = probabilities
+            .iter()
+            .map(|&probability| {
+                let mut cumulative = 0.0;
+                let value = pairs
+                    .iter()
+                    .find(|(_, weight)| {
+                        cumulative += weight;
+                        cumulative >= probability * total
+                    })
+                    .or(pairs.last())
+                    .map_or(f64::NAN, |(value, _)| *value);
+                (probability, value)
+            })
+            .collect();
+
+        let bins = bins.max(1);
+        let (low, high) = match (pairs.first(), pairs.last()) {
+            (Some(first), Some(last)) => (first.0, last.0),
+            _ => (0.0, 0.0),
+        };
+        let width = (high - low) / bins as f64;
+        let edges = (0..=bins).map(|edge| low + width * edge as f64).collect();
+        let mut counts = vec![0.0; bins];
+        for (value, weight) in &pairs {
+            let bin = if width > 0.0 {
+                (((value - low) / width) as usize).min(bins - 1)
+            } else {
+                0
+            };
+            counts[bin] += weight;
+        }
+
+        Self {
+            count: pairs.len(),
+            mean,
+            quantiles,
+            histogram: Histogram { edges, counts },
+        }
+    }
+}
+
+impl Problem {
+    /// Own elasticities `e_jj` of every product, stacked in product order.
+    pub fn own_elasticities(
+        &self,
+        results: &ProblemResults,
+        characteristic: Characteristic,
+        kind: ElasticityKind,
+    ) -> Result<DVector<f64>> {
+        let matrices = self.elasticities(results, characteristic, kind)?;
+        Ok(DVector::from_iterator(
+            self.data().product_count(),
+            matrices
+                .iter()
+                .flat_map(|matrix| matrix.diagonal().data.as_vec().clone()),
+        ))
+    }
+
+    /// Own elasticities of each simulated consumer's choice probabilities, as an `N x R`
+    /// matrix: `alpha_i * x_j * (1 - p_ij)` (without `x_j` for semi-elasticities).
+    ///
+    /// Summarize a column-wise average with [`ElasticitySummary::weighted`] and the draw
+    /// weights to describe how price sensitivity varies across consumer types.
+    pub fn consumer_own_elasticities(
+        &self,
+        results: &ProblemResults,
+        characteristic: Characteristic,
+        kind: ElasticityKind,
+    ) -> Result<DMatrix<f64>> {
+        let inputs = ShareInputs::new(
+            self.data(),
+            self.draws(),
+            &results.sigma,
+            &self.options().contraction,
+        );
+        let probabilities = individual_shares(&results.delta, &inputs)?;
+        let marginal_utility = self.marginal_utilities(results, characteristic)?;
+        let values = characteristic.values(self.data());
+        Ok(DMatrix::from_fn(
+            probabilities.nrows(),
+            probabilities.ncols(),
+            |product, draw| {
+                let level = match kind {
+                    ElasticityKind::Elasticity => values[product],
+                    ElasticityKind::SemiElasticity => 1.0,
+                };
+                marginal_utility[draw] * level * (1.0 - probabilities[(product, draw)])
+            },
+        ))
+    }
+
+    /// Marginal utility of the characteristic for each simulated consumer.
+    fn marginal_utilities(
+        &self,
+        results: &ProblemResults,
+        characteristic: Characteristic,
+    ) -> Result<DVector<f64>> {
+        let data = self.data();
+        if let Some(column) = characteristic.x1
+            && column >= data.linear_dim()
+        {
+            return Err(BlpError::dimension_mismatch(
+                "X1",
+                data.linear_dim(),
+                column + 1,
+            ));
+        }
+        if let Some(column) = characteristic.x2
+            && column >= data.nonlinear_dim()
+        {
+            return Err(BlpError::dimension_mismatch(
+                "X2",
+                data.nonlinear_dim(),
+                column + 1,
+            ));
+        }
+        let characteristic = characteristic.completed(data);
+        let linear = characteristic.x1.map_or(0.0, |column| results.beta[column]);
+        let tastes =
+            consumer_tastes(data, self.draws(), &results.sigma, results.pi.as_ref()).transpose();
+        Ok(DVector::from_fn(self.draws().draw_count(), |draw, _| {
+            linear
+                + characteristic
+                    .x2
+                    .map_or(0.0, |column| tastes[(draw, column)])
+        }))
+    }
+
+    /// Elasticities of shares with respect to a characteristic, one `J_t x J_t` matrix per
+    /// market whose entry `(j, k)` is the response of product `j`'s share to product
+    /// `k`'s characteristic.
+    ///
+    /// Evaluated at the mean utilities and parameters in `results`, using the random
+    /// coefficients logit choice probabilities.
+    pub fn elasticities(
+        &self,
+        results: &ProblemResults,
+        characteristic: Characteristic,
+        kind: ElasticityKind,
+    ) -> Result<Vec<DMatrix<f64>>> {
+        let data = self.data();
+        let values = characteristic.values(data);
+        let shares = &results.predicted_shares;
+        let mut matrices = self.share_derivatives(results, characteristic)?;
+        for (market, derivatives) in data.partition().markets().zip(&mut matrices) {
+            let range = market.range();
+            for (j, product_j) in range.clone().enumerate() {
+                for (k, product_k) in range.clone().enumerate() {
+                    let level = match kind {
+                        ElasticityKind::Elasticity => values[product_k],
+                        ElasticityKind::SemiElasticity => 1.0,
+                    };
+                    derivatives[(j, k)] *= level / shares[product_j];
+                }
+            }
+        }
+        Ok(matrices)
+    }
+
+    /// Share derivatives `ds_j / dx_k`, one `J_t x J_t` matrix per market.
+    pub fn share_derivatives(
+        &self,
+        results: &ProblemResults,
+        characteristic: Characteristic,
+    ) -> Result<Vec<DMatrix<f64>>> {
+        let data = self.data();
+        let marginal_utility = self.marginal_utilities(results, characteristic)?;
+        let inputs = ShareInputs::new(
+            data,
+            self.draws(),
+            &results.sigma,
+            &self.options().contraction,
+        );
+        let probabilities = individual_shares(&results.delta, &inputs)?;
+        let weights = self.draws().weights();
+
+        let mut matrices = Vec::with_capacity(data.partition().market_count());
+        for market in data.partition().markets() {
+            let range = market.range();
+            let mut derivatives = DMatrix::zeros(range.len(), range.len());
+            for (draw, weight) in weights.iter().enumerate() {
+                let scale = weight * marginal_utility[draw];
+                for (j, product_j) in range.clone().enumerate() {
+                    let p_j = probabilities[(product_j, draw)];
+                    for (k, product_k) in range.clone().enumerate() {
+                        let own = if j == k { 1.0 } else { 0.0 };
+                        derivatives[(j, k)] +=
+                            scale * p_j * (own - probabilities[(product_k, draw)]);
+                    }
+                }
+            }
+            matrices.push(derivatives);
+        }
+        Ok(matrices)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+    use crate::data::ProductDataBuilder;
+    use crate::integration::SimulationDraws;
+    use crate::parameters::NonlinearParameters;
+
+    #[test]
+    fn logit_elasticities_match_closed_form() {
+        let market_ids = vec!["m1".to_string(), "m1".to_string(), "m2".to_string()];
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.4]);
+        let x1 = DMatrix::from_row_slice(3, 2, &[1.0, 1.0, 1.0, 2.0, 1.0, 1.5]);
+        let instruments = DMatrix::from_row_slice(3, 2, &[1.0, 0.5, 1.0, 2.5, 1.0, 1.0]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .instruments(instruments)
+            .x1_labels(vec!["const".into(), "price".into()])
+            .build()
+            .unwrap();
+        let problem = Problem::new(data, SimulationDraws::standard_normal(1, 0, 1)).unwrap();
+        let results = problem.solve(&NonlinearParameters::default()).unwrap();
+        let price = Characteristic::by_label(problem.data(), "price").unwrap();
+        assert_eq!(price, Characteristic::linear(1));
+
+        let e = problem
+            .elasticities(&results, price, ElasticityKind::Elasticity)
+            .unwrap();
+        let beta = results.beta[1];
+        let s = &results.predicted_shares;
+        assert_relative_eq!(e[0][(0, 0)], beta * 1.0 * (1.0 - s[0]), epsilon = 1e-10);
+        assert_relative_eq!(e[0][(0, 1)], -beta * 2.0 * s[1], epsilon = 1e-10);
+        assert_relative_eq!(e[1][(0, 0)], beta * 1.5 * (1.0 - s[2]), epsilon = 1e-10);
+
+        let semi = problem
+            .elasticities(&results, price, ElasticityKind::SemiElasticity)
+            .unwrap();
+        assert_relative_eq!(semi[0][(0, 1)], -beta * s[1], epsilon = 1e-10);
+
+        let own = problem
+            .own_elasticities(&results, price, ElasticityKind::Elasticity)
+            .unwrap();
+        assert_relative_eq!(own[2], e[1][(0, 0)]);
+        let consumers = problem
+            .consumer_own_elasticities(&results, price, ElasticityKind::Elasticity)
+            .unwrap();
+        assert_relative_eq!(consumers[(1, 0)], e[0][(1, 1)], epsilon = 1e-10);
+    }
+
+    #[test]
+    fn shared_characteristic_uses_both_coefficients() {
+        let market_ids = ["m1", "m1", "m2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.4]);
+        let x1 = DMatrix::from_row_slice(3, 2, &[1.0, 1.0, 1.0, 2.0, 1.0, 1.5]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .x1_labels(vec!["const".into(), "price".into()])
+            .x2_from_x1(vec![1])
+            .build()
+            .unwrap();
+        assert_eq!(data.labels().shared(), vec![(1, 0)]);
+        let problem = Problem::new(data, SimulationDraws::standard_normal(20, 1, 2)).unwrap();
+        let results = problem
+            .solve(&NonlinearParameters::new(DMatrix::from_element(1, 1, 0.5)))
+            .unwrap();
+
+        let both = problem
+            .elasticities(
+                &results,
+                Characteristic::both(1, 0),
+                ElasticityKind::Elasticity,
+            )
+            .unwrap();
+        let nonlinear = problem
+            .elasticities(
+                &results,
+                Characteristic::nonlinear(0),
+                ElasticityKind::Elasticity,
+            )
+            .unwrap();
+        assert_eq!(nonlinear, both);
+    }
+
+    #[test]
+    fn summary_reports_quantiles_and_histogram() {
+        let values = [-4.0, -3.0, -2.0, -1.0];
+        let summary = ElasticitySummary::new(&values, &[0.5, 1.0], 2);
+        assert_eq!(summary.count, 4);
+        assert_relative_eq!(summary.mean, -2.5);
+        assert_eq!(summary.quantiles, vec![(0.5, -3.0), (1.0, -1.0)]);
+        assert_eq!(summary.histogram.edges, vec![-4.0, -2.5, -1.0]);
+        assert_eq!(summary.histogram.counts, vec![2.0, 2.0]);
+
+        let weighted = ElasticitySummary::weighted(&values, &[0.7, 0.1, 0.1, 0.1], &[0.5], 1);
+        assert_eq!(weighted.quantiles, vec![(0.5, -4.0)]);
+    }
+}
@@ -18,10 +18,84 @@ pub enum BlpError {
     #[error("market identifiers must appear in contiguous blocks; market `{market_id}` is split")]
     NonContiguousMarket { market_id: String },
 
+    /// Raised when a product id appears more than once within a market.
+    #[error("product `{product_id}` appears more than once in market `{market_id}`")]
+    DuplicateProduct {
+        market_id: String,
+        product_id: String,
+    },
+
+    /// Raised when a characteristic is missing (`NaN`) and no imputation rule resolves it.
+    #[error("missing value in {matrix} at row {row}, column {column}")]
+    MissingValue {
+        matrix: &'static str,
+        row: usize,
+        column: usize,
+    },
+
+    /// Raised when a column label is repeated within `X1` or `X2`, which would count the
+    /// characteristic twice.
+    #[error("label `{label}` appears more than once in {matrix}")]
+    DuplicateLabel { matrix: &'static str, label: String },
+
+    /// Raised when `X1` and `X2` share a column label but not the column's values.
+    #[error("column `{label}` appears in X1 and X2 with different values")]
+    InconsistentSharedColumn { label: String },
+
+    /// Raised when an `X1` column declared endogenous is also used, unchanged, as an
+    /// instrument (including when no instruments were supplied and `X1` instruments itself).
+    #[error("endogenous column `{label}` also appears untransformed among the instruments")]
+    EndogenousInstrument { label: String },
+
+    /// Raised when a parameter is referred to by a name that does not exist.
+    #[error("no parameter named `{name}`")]
+    UnknownParameter { name: String },
+
+    /// Raised when a market id does not appear in the product data.
+    #[error("market `{market_id}` does not appear in the product data")]
+    UnknownMarket { market_id: String },
+
+    /// Raised when a counterfactual is specified in a way the model cannot evaluate.
+    #[error("invalid counterfactual: {reason}")]
+    InvalidCounterfactual { reason: &'static str },
+
+    /// Raised when winsorization quantiles are outside `[0, 1]` or not increasing.
+    #[error("invalid winsorization quantiles: lower {lower}, upper {upper}")]
+    InvalidQuantiles { lower: f64, upper: f64 },
+
+    /// Raised when there are too few instruments to test or identify the parameters.
+    #[error("{instruments} instruments cannot identify {parameters} parameters")]
+    Underidentified {
+        instruments: usize,
+        parameters: usize,
+    },
+
     /// Raised when product shares are missing or non-positive.
     #[error("product share at index {index} must be positive, found {share}")]
     NonPositiveShare { index: usize, share: f64 },
 
+    /// Raised when log marginal costs are requested but a recovered cost is not positive.
+    #[error("marginal cost at index {index} must be positive for log costs, found {cost}")]
+    NonPositiveCost { index: usize, cost: f64 },
+
+    /// Raised when a nesting parameter lies outside `[0, 1)`.
+    #[error("nesting parameter rho must lie in [0, 1), found {rho}")]
+    InvalidNestingParameter { rho: f64 },
+
+    /// Raised when a bound on `sigma` is NaN or its lower bound lies above its upper bound.
+    #[error("sigma[{row}, {column}] has lower bound {lower} above upper bound {upper}")]
+    InvalidBounds {
+        row: usize,
+        column: usize,
+        lower: f64,
+        upper: f64,
+    },
+
+    /// Raised at problem construction when the data, draws, and starting parameters
+    /// disagree; every disagreement found is listed.
+    #[error("inconsistent problem specification: {}", mismatches.join("; "))]
+    InconsistentSpecification { mismatches: Vec<String> },
+
     /// Raised when the outside good share becomes non-positive.
     #[error("outside share for market `{market_id}` must be positive, found {share}")]
     NonPositiveOutsideShare { market_id: String, share: f64 },
@@ -45,13 +119,70 @@ pub enum BlpError {
         max_gap: f64,
     },
 
+    /// Raised when the search over `beta` under continuously-updated weighting stops
+    /// before the objective is minimized.
+    #[error(
+        "continuously-updated search over beta did not converge after {iterations} steps; \
+         last promised decrease {decrease}"
+    )]
+    ContinuouslyUpdatedDidNotConverge {
+        /// Number of steps taken.
+        iterations: usize,
+        /// Objective decrease promised by the last step.
+        decrease: f64,
+    },
+
     /// Raised when numerical routines produce NaN.
     #[error("encountered NaN during {context}")]
     NumericalError { context: &'static str },
 
+    /// Raised when an operation is not available for the current problem configuration.
+    #[error("{operation} is not supported for this problem")]
+    Unsupported { operation: &'static str },
+
     /// Raised when a required component has not been provided to a builder or solver.
     #[error("{component} must be provided before solving the problem")]
     MissingComponent { component: &'static str },
+
+    /// Raised when long-format input cannot be pivoted into a rectangular table.
+    #[error("invalid long-format data: {message}")]
+    InvalidLongFormat { message: String },
+
+    /// Raised when a formula cannot be parsed or evaluated against the data.
+    #[error("invalid formula: {message}")]
+    InvalidFormula { message: String },
+
+    /// Raised when reading from or writing to an external file fails.
+    #[error("I/O failure during {context}: {source}")]
+    Io {
+        /// Human-readable context describing the operation.
+        context: &'static str,
+        /// Underlying operating-system error.
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// Raised when results cannot be serialized or an archive cannot be parsed.
+    #[error("failed to (de)serialize {context}: {message}")]
+    Serialization {
+        /// Human-readable context describing the operation.
+        context: &'static str,
+        /// Message reported by the serializer.
+        message: String,
+    },
+
+    /// Raised when a bounded thread pool cannot be created.
+    #[error("failed to build thread pool: {message}")]
+    ThreadPool { message: String },
+
+    /// Raised when an archive was written with a schema this crate version cannot read.
+    #[error("results schema version {found} is not supported (latest known is {supported})")]
+    UnsupportedSchema {
+        /// Version tag found in the archive.
+        found: u32,
+        /// Latest version understood by this crate.
+        supported: u32,
+    },
 }
 
 impl BlpError {
@@ -73,6 +204,11 @@ impl BlpError {
     pub fn missing_component(component: &'static str) -> Self {
         Self::MissingComponent { component }
     }
+
+    /// Helper to wrap an I/O failure with the operation that triggered it.
+    pub fn io(context: &'static str, source: std::io::Error) -> Self {
+        Self::Io { context, source }
+    }
 }
 
 /// Type alias for results returned by this crate.
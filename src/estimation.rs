@@ -1,13 +1,38 @@
 //! High-level demand estimation pipeline that mirrors `pyBLP.Problem`.
 
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::time::Instant;
+
 use nalgebra::{DMatrix, DVector};
+use serde::{Deserialize, Serialize};
 
+use crate::cache::InnerCache;
 use crate::data::ProductData;
-use crate::demand::{predict_shares, solve_delta};
+use crate::diagnostics::{
+    ConditioningReport, ProblemDimensions, ProfilingReport, WeightingApproximation,
+};
 use crate::error::{BlpError, Result};
 use crate::integration::SimulationDraws;
-use crate::options::{ProblemOptions, WeightingMatrix};
+use crate::linalg::{
+    Factorizations, condition_number, least_squares_qr, least_squares_svd, low_rank_inverse,
+};
+use crate::macro_moments::{MacroMoment, MacroMomentResults};
+use crate::models::{DemandModel, RandomCoefficientsLogit};
+use crate::options::{
+    GmmOptions, LinearSolver, LowRankWeighting, MomentCovariance, ProblemOptions, WeightingMatrix,
+};
+use crate::parameters::NonlinearParameters;
+use crate::progress::ProgressWriter;
+use crate::provenance::Provenance;
 use crate::solving::ContractionSummary;
+use crate::supply::{SupplyResults, SupplySide};
+
+/// The continuously-updated search over `beta` stops once a step promises to lower the
+/// objective by at most this fraction, gives up after `CUE_ITERATIONS` steps, and fails
+/// once a step has been halved below `CUE_MINIMUM_STEP` without lowering the objective.
+const CUE_TOLERANCE: f64 = 1e-12;
+const CUE_ITERATIONS: usize = 100;
+const CUE_MINIMUM_STEP: f64 = 1e-10;
 
 /// High-level wrapper that mirrors `pyBLP.Problem` on the demand side.
 #[derive(Clone, Debug)]
@@ -15,6 +40,14 @@ pub struct Problem {
     data: ProductData,
     draws: SimulationDraws,
     options: ProblemOptions,
+    model: Arc<dyn DemandModel>,
+    cache: Arc<Mutex<InnerCache>>,
+    supply: Option<Arc<SupplySide>>,
+    macro_moments: Arc<Vec<MacroMoment>>,
+    progress: Option<Arc<Mutex<ProgressWriter>>>,
+    ztz_condition: Arc<OnceLock<f64>>,
+    provenance: Arc<OnceLock<Provenance>>,
+    deferred_conditioning: bool,
 }
 
 impl Problem {
@@ -24,22 +57,190 @@ impl Problem {
     }
 
     /// Construct a new problem with explicit solver options.
+    ///
+    /// The draws, the starting `sigma`, and its bounds are checked against the `X2`
+    /// columns up front; every disagreement is listed in a single
+    /// [`BlpError::InconsistentSpecification`].
     pub fn with_options(
         data: ProductData,
         draws: SimulationDraws,
         options: ProblemOptions,
     ) -> Result<Self> {
-        if data.nonlinear_dim() == 0 && draws.dimension() != 0 {
-            return Err(BlpError::dimension_mismatch(
-                "draw dimension",
-                0,
-                draws.dimension(),
-            ));
+        let mismatches = specification_mismatches(&data, &draws, &options);
+        if !mismatches.is_empty() {
+            return Err(BlpError::InconsistentSpecification { mismatches });
+        }
+        let effective = draws.effective_sample_size();
+        if !draws.is_enumerated() && effective < options.contraction.minimum_effective_draws {
+            log::warn!(
+                "integration weights have an effective sample size of {effective:.1} out of {} \
+                 draws; simulated shares may be unreliable",
+                draws.draw_count()
+            );
         }
         Ok(Self {
             data,
             draws,
             options,
+            model: Arc::new(RandomCoefficientsLogit),
+            cache: Arc::default(),
+            supply: None,
+            macro_moments: Arc::default(),
+            progress: None,
+            ztz_condition: Arc::default(),
+            provenance: Arc::default(),
+            deferred_conditioning: false,
+        })
+    }
+
+    /// Replace the demand model used for share prediction and inversion.
+    pub fn with_model<M: DemandModel + 'static>(mut self, model: M) -> Self {
+        self.model = Arc::new(model);
+        self.cache = Arc::default();
+        self
+    }
+
+    /// A new problem on a transformed copy of the product data, sharing the draws,
+    /// options, and model, e.g. for robustness loops.
+    ///
+    /// `modify` receives a copy of the data and returns the replacement; build it with
+    /// [`ProductData::select_markets`] to drop markets or
+    /// [`ProductData::to_builder`] to change columns, so it is validated again.
+    ///
+    /// ```no_run
+    /// # fn run(problem: &blprs::Problem) -> blprs::error::Result<()> {
+    /// let without_first = problem.with_modified_data(|data| {
+    ///     let keep: Vec<usize> = (1..data.partition().market_count()).collect();
+    ///     data.select_markets(&keep)
+    /// })?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn with_modified_data<F>(&self, modify: F) -> Result<Self>
+    where
+        F: FnOnce(ProductData) -> Result<ProductData>,
+    {
+        self.with_data(modify(self.data.clone())?)
+    }
+
+    /// Same draws, options, and model on different product data.
+    pub(crate) fn with_data(&self, data: ProductData) -> Result<Self> {
+        self.with_inputs(data, self.draws.clone())
+    }
+
+    /// The same problem on other draws of the same dimension, with an empty inner-loop
+    /// cache and without re-checking the draws' effective sample size.
+    pub(crate) fn with_draws_unchecked(&self, draws: SimulationDraws) -> Self {
+        let mut problem = self.clone();
+        problem.draws = draws;
+        problem.cache = Arc::default();
+        problem.provenance = Arc::default();
+        problem
+    }
+
+    /// The same problem under other options, sharing the inner-loop cache.
+    pub(crate) fn with_options_override(&self, options: ProblemOptions) -> Self {
+        let mut problem = self.clone();
+        problem.options = options;
+        problem
+    }
+
+    /// The same problem with one progress writer shared by every evaluation of a run, so
+    /// the records are numbered and timed across the run; `None` without
+    /// [`ProblemOptions::progress`] or when a run is already being recorded.
+    pub(crate) fn recording_run(&self) -> Result<Option<Self>> {
+        match &self.options.progress {
+            Some(progress) if self.progress.is_none() => {
+                let mut problem = self.clone();
+                problem.progress = Some(Arc::new(Mutex::new(ProgressWriter::open(progress)?)));
+                Ok(Some(problem))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The same problem for the evaluations of a search: unless a factorization falls
+    /// back to a pseudo-inverse, results leave the condition numbers of `W` and
+    /// `X1'Z W Z'X1` to [`complete_conditioning`](Self::complete_conditioning).
+    pub(crate) fn deferring_conditioning(&self) -> Self {
+        let mut problem = self.clone();
+        problem.deferred_conditioning = true;
+        problem
+    }
+
+    /// Fill in the condition numbers a search deferred, once, on its estimates.
+    pub(crate) fn complete_conditioning(&self, results: &mut ProblemResults) {
+        if results.conditioning.pseudo_inverses.is_empty() {
+            results.conditioning = self.conditioning(
+                &results.weighting_matrix,
+                results.conditioning.threshold,
+                results.conditioning.weighting_approximation,
+            );
+        }
+    }
+
+    /// The conditioning report under `weighting`, with the condition number of `Z'Z`
+    /// computed once per problem.
+    fn conditioning(
+        &self,
+        weighting: &DMatrix<f64>,
+        threshold: f64,
+        approximation: Option<WeightingApproximation>,
+    ) -> ConditioningReport {
+        let (x1, instruments) = (self.data.x1(), self.data.instruments());
+        match approximation {
+            Some(approximation) => {
+                ConditioningReport::low_rank(x1, instruments, weighting, threshold, approximation)
+            }
+            None => ConditioningReport::with_ztz(
+                self.ztz_condition(),
+                x1,
+                instruments,
+                weighting,
+                threshold,
+            ),
+        }
+    }
+
+    /// Condition number of `Z'Z`, computed on first use.
+    fn ztz_condition(&self) -> f64 {
+        *self.ztz_condition.get_or_init(|| {
+            let instruments = self.data.instruments();
+            condition_number(&(instruments.transpose() * instruments))
+        })
+    }
+
+    /// Same options and model on different product data and draws. The supply side and
+    /// macro moments are kept only when the products are unchanged market by market.
+    pub(crate) fn with_inputs(&self, data: ProductData, draws: SimulationDraws) -> Result<Self> {
+        let same_products = data.market_ids() == self.data.market_ids();
+        let mut problem = Self::with_options(data, draws, self.options.clone())?;
+        problem.model = Arc::clone(&self.model);
+        if same_products {
+            problem.supply = self.supply.clone();
+            problem.macro_moments = Arc::clone(&self.macro_moments);
+        }
+        Ok(problem)
+    }
+
+    /// The same problem with the markets of `markets` appended to its data (see
+    /// [`ProductData::append_markets`]), e.g. to re-estimate as weekly data arrive.
+    ///
+    /// Options and the demand model carry over; the supply side and macro moments, which
+    /// are tied to the old products, do not. Cached inner-loop solutions become warm
+    /// starts: a revisited `sigma` starts the contraction from the stored `delta` on the
+    /// old markets, which are then already solved.
+    pub fn extend_data(&self, markets: &ProductData) -> Result<Self> {
+        let data = self.data.append_markets(markets)?;
+        let problem = self.with_inputs(data, self.draws.clone())?;
+        let cache = self
+            .cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .extended(markets);
+        Ok(Self {
+            cache: Arc::new(Mutex::new(cache)),
+            ..problem
         })
     }
 
@@ -63,59 +264,468 @@ impl Problem {
         &self.options
     }
 
-    /// Solve the model for a given nonlinear parameter matrix `sigma` using the stored options.
-    pub fn solve(&self, sigma: &DMatrix<f64>) -> Result<ProblemResults> {
-        self.solve_with_options(sigma, &self.options)
+    /// Accessor for the demand model variant.
+    pub fn model(&self) -> &dyn DemandModel {
+        self.model.as_ref()
+    }
+
+    /// The attached supply side, if any.
+    pub fn supply(&self) -> Option<&SupplySide> {
+        self.supply.as_deref()
+    }
+
+    pub(crate) fn set_supply(&mut self, supply: Option<Arc<SupplySide>>) {
+        self.supply = supply;
+    }
+
+    /// The attached macro moments, empty unless set by [`Problem::with_macro_moments`].
+    pub fn macro_moments(&self) -> &[MacroMoment] {
+        &self.macro_moments
+    }
+
+    pub(crate) fn set_macro_moments(&mut self, moments: Arc<Vec<MacroMoment>>) {
+        self.macro_moments = moments;
+    }
+
+    pub(crate) fn inner_cache(&self) -> &Mutex<InnerCache> {
+        &self.cache
+    }
+
+    /// Report problem sizes together with a rough per-evaluation cost estimate.
+    pub fn dimensions(&self) -> ProblemDimensions {
+        let n = self.data.product_count();
+        let k1 = self.data.linear_dim();
+        let k2 = self.data.nonlinear_dim();
+        let kz = self.data.instrument_dim();
+        let k3 = self.supply().map_or(0, |supply| supply.x3().ncols());
+        let r = self.draws.draw_count();
+        let products_per_market: Vec<usize> = self
+            .data
+            .partition()
+            .markets()
+            .map(|market| market.product_count())
+            .collect();
+
+        // Per draw: the taste vector (K2^2), one K2-dot per product, and roughly ten
+        // operations per product for the exponential and the logit normalization.
+        let flops_per_share_prediction =
+            r as f64 * ((k2 * k2) as f64 + n as f64 * (2.0 * k2 as f64 + 10.0));
+        // Z'X, Z'delta, X'ZWZ'X, its factorization, and the objective quadratic form.
+        let flops_per_linear_step = 2.0 * (n * kz * (k1 + 1)) as f64
+            + 2.0 * (k1 * kz * kz + k1 * k1 * kz) as f64
+            + (k1 * k1 * k1) as f64 / 3.0
+            + 2.0 * (kz * kz) as f64;
+        let data_values = n * (1 + k1 + k2 + k3 + kz) + r * (k2 + 1);
+        // delta, xi, predicted shares, and the contraction's working copy.
+        let work_values = 4 * n + kz * kz + k1 * k1;
+        let memory_bytes = (data_values + work_values) * std::mem::size_of::<f64>();
+
+        ProblemDimensions {
+            products: n,
+            markets: products_per_market.len(),
+            products_per_market,
+            k1,
+            k2,
+            k3,
+            instruments: kz,
+            draws: r,
+            effective_draws: self.draws.effective_sample_size(),
+            flops_per_share_prediction,
+            flops_per_linear_step,
+            memory_bytes,
+        }
+    }
+
+    /// Solve the model at the given nonlinear parameters using the stored options.
+    ///
+    /// The parameters are checked against the data and draws first (see
+    /// [`NonlinearParameters::validate`]).
+    pub fn solve(&self, parameters: &NonlinearParameters) -> Result<ProblemResults> {
+        self.solve_with_options(parameters, &self.options)
+    }
+
+    /// Solve the model at a bare `sigma` matrix.
+    #[deprecated(note = "pass `NonlinearParameters` to `Problem::solve`")]
+    pub fn solve_sigma(&self, sigma: &DMatrix<f64>) -> Result<ProblemResults> {
+        self.solve(&NonlinearParameters::new(sigma.clone()))
+    }
+
+    /// Solve the model at a bare `sigma` matrix with an explicit options override.
+    #[deprecated(note = "pass `NonlinearParameters` to `Problem::solve_with_options`")]
+    pub fn solve_sigma_with_options(
+        &self,
+        sigma: &DMatrix<f64>,
+        options: &ProblemOptions,
+    ) -> Result<ProblemResults> {
+        self.solve_with_options(&NonlinearParameters::new(sigma.clone()), options)
     }
 
     /// Solve the model with an explicit options override.
     pub fn solve_with_options(
         &self,
-        sigma: &DMatrix<f64>,
+        parameters: &NonlinearParameters,
+        options: &ProblemOptions,
+    ) -> Result<ProblemResults> {
+        self.solve_warm(parameters, options, None)
+    }
+
+    /// Resume from earlier results, e.g. estimated on a subset of markets or with fewer draws.
+    ///
+    /// Starts from `previous`'s nonlinear parameters, warm-starts the contraction from `previous.delta`, and
+    /// reuses `previous.weighting_matrix`. The delta cache is only used when the products
+    /// match and the weighting matrix only when the instrument count matches; otherwise the
+    /// usual starting values from `options` apply.
+    pub fn solve_from(
+        &self,
+        previous: &ProblemResults,
         options: &ProblemOptions,
     ) -> Result<ProblemResults> {
-        let (delta, contraction) =
-            solve_delta(&self.data, &self.draws, sigma, &options.contraction)?;
+        let mut options = options.clone();
+        let instruments = self.data.instrument_dim();
+        if previous.weighting_matrix.nrows() == instruments
+            && previous.weighting_matrix.ncols() == instruments
+        {
+            options.gmm.weighting = WeightingMatrix::Provided(previous.weighting_matrix.clone());
+        } else {
+            log::debug!("previous weighting matrix does not match the instruments; not reused");
+        }
+        let initial = if previous.delta.len() == self.data.product_count() {
+            Some(&previous.delta)
+        } else {
+            log::debug!("previous delta does not match the products; not reused");
+            None
+        };
+        self.solve_warm(&previous.parameters(), &options, initial)
+    }
 
-        let weighting = match &options.gmm.weighting {
-            WeightingMatrix::InverseZTZ => inverse_ztz(self.data.instruments())?,
-            WeightingMatrix::Provided(matrix) => matrix.clone(),
+    fn solve_warm(
+        &self,
+        parameters: &NonlinearParameters,
+        options: &ProblemOptions,
+        initial_delta: Option<&DVector<f64>>,
+    ) -> Result<ProblemResults> {
+        parameters.validate(&self.data, &self.draws)?;
+        let started = Instant::now();
+        let (cached, reused) = self.cached_inner(parameters, options, initial_delta, false)?;
+        let elapsed = started.elapsed().as_secs_f64();
+        let profiling = ProfilingReport {
+            contraction_seconds: elapsed,
+            reused_contraction_seconds: if reused { cached.seconds } else { 0.0 },
+            ..ProfilingReport::default()
         };
+        self.linear_step(cached.inner, options, profiling)
+    }
 
-        let beta = compute_linear_parameters(&self.data, &delta, &weighting)?;
-        let xi = &delta - self.data.x1() * &beta;
-        let predicted_shares =
-            predict_shares(&delta, &self.data, sigma, &self.draws, &options.contraction)?;
-        let gmm_value = compute_gmm_objective(&self.data, &xi, &weighting);
+    /// Re-run only the linear IV step and GMM objective under a new weighting matrix.
+    ///
+    /// The mean utilities, predicted shares, and contraction diagnostics depend on `sigma`
+    /// alone, so they are carried over from `previous` rather than recomputed. This is the
+    /// step that separates the first and second stages of two-step GMM; the skipped
+    /// contraction time is recorded in [`ProfilingReport::reused_contraction_seconds`].
+    pub fn reweight(
+        &self,
+        previous: &ProblemResults,
+        weighting: WeightingMatrix,
+    ) -> Result<ProblemResults> {
+        let mut options = previous.options_used.clone();
+        options.gmm.weighting = weighting;
+        let inner = InnerSolution {
+            parameters: previous.parameters(),
+            delta: previous.delta.clone(),
+            predicted_shares: previous.predicted_shares.clone(),
+            contraction: previous.contraction.clone(),
+        };
+        let saved =
+            previous.profiling.contraction_seconds + previous.profiling.reused_contraction_seconds;
+        self.linear_step(
+            inner,
+            &options,
+            ProfilingReport {
+                reused_contraction_seconds: saved,
+                ..ProfilingReport::default()
+            },
+        )
+    }
 
-        Ok(ProblemResults {
-            delta,
+    /// Concentrate out `beta` (unless it is fixed) and evaluate the objective given a
+    /// solved inner loop.
+    pub(crate) fn linear_step(
+        &self,
+        inner: InnerSolution,
+        options: &ProblemOptions,
+        mut profiling: ProfilingReport,
+    ) -> Result<ProblemResults> {
+        let started = Instant::now();
+        let gmm = &options.gmm;
+        let mut factorizations = Factorizations::new(gmm.strict_factorizations);
+        let continuously_updated = matches!(gmm.weighting, WeightingMatrix::ContinuouslyUpdated);
+        let (mut weighting, mut approximation) = match &gmm.weighting {
+            WeightingMatrix::InverseZTZ | WeightingMatrix::ContinuouslyUpdated => {
+                initial_weighting(self.data.instruments(), gmm, &mut factorizations)?
+            }
+            // A provided matrix under low-rank weighting is a two-step update, whose
+            // error the optimizer fills in.
+            WeightingMatrix::Provided(matrix) => (
+                matrix.clone(),
+                gmm.low_rank_weighting
+                    .filter(|settings| settings.rank < self.data.instrument_dim())
+                    .map(|settings| WeightingApproximation {
+                        rank: settings.rank,
+                        relative_error: f64::NAN,
+                    }),
+            ),
+        };
+
+        let beta = match &gmm.fixed_beta {
+            Some(beta) if beta.len() != self.data.linear_dim() => {
+                return Err(BlpError::dimension_mismatch(
+                    "fixed beta length",
+                    self.data.linear_dim(),
+                    beta.len(),
+                ));
+            }
+            Some(beta) => beta.clone(),
+            None => {
+                let mut beta = compute_linear_parameters(
+                    &self.data,
+                    &inner.delta,
+                    &weighting,
+                    gmm.linear_solver,
+                    &mut factorizations,
+                )?;
+                if continuously_updated {
+                    beta = self.continuously_updated_beta(
+                        &inner.delta,
+                        beta,
+                        gmm,
+                        &mut factorizations,
+                    )?;
+                }
+                beta
+            }
+        };
+        let xi = self.data.demean(&(&inner.delta - self.data.x1() * &beta));
+        if continuously_updated {
+            (weighting, approximation) =
+                updated_weighting(&self.data, &xi, gmm, &mut factorizations)?;
+        }
+        let fallbacks = factorizations.into_fallbacks();
+        let threshold = gmm.condition_warning_threshold;
+        let mut conditioning = if self.deferred_conditioning && fallbacks.is_empty() {
+            ConditioningReport::deferred(self.ztz_condition(), threshold, approximation)
+        } else {
+            self.conditioning(&weighting, threshold, approximation)
+        };
+        conditioning.pseudo_inverses = fallbacks;
+        let gmm_value = compute_gmm_objective(&self.data, &xi, &weighting);
+        let mut results = ProblemResults {
+            sigma: inner.parameters.sigma().clone(),
+            pi: inner.parameters.pi().cloned(),
+            rho: inner.parameters.rho(),
+            delta: inner.delta,
             beta,
             xi,
-            predicted_shares,
+            predicted_shares: inner.predicted_shares,
             gmm_value,
-            contraction,
+            contraction: inner.contraction,
             weighting_matrix: weighting,
+            conditioning,
+            profiling: ProfilingReport::default(),
+            provenance: self
+                .provenance
+                .get_or_init(|| Provenance::capture(&self.data, &self.draws))
+                .clone(),
             options_used: options.clone(),
+            supply: None,
+            macro_moments: None,
+            gmm_steps: Vec::new(),
+            covariance: None,
+            linear_estimator: self.data.linear_estimator(),
+        };
+        if let Some(supply) = &self.supply {
+            let supply = self.supply_results(&results, supply)?;
+            results.gmm_value += supply.gmm_value;
+            results.supply = Some(supply);
+        }
+        if !self.macro_moments.is_empty() {
+            let macro_moments = self.macro_moment_results(&results)?;
+            results.gmm_value += macro_moments.gmm_value;
+            results.macro_moments = Some(macro_moments);
+        }
+        profiling.linear_seconds = started.elapsed().as_secs_f64();
+        profiling.cache = self.cache_statistics();
+        results.profiling = profiling;
+
+        if let Some(progress) = &options.progress {
+            match &self.progress {
+                Some(writer) => writer
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .record_results(&results, progress.include_residuals)?,
+                None => ProgressWriter::open(progress)?
+                    .record_results(&results, progress.include_residuals)?,
+            }
+        }
+        Ok(results)
+    }
+
+    /// Minimize the continuously-updated objective `Q(b) = g(b)' S(b)^{-1} g(b)` over
+    /// `beta`, with the weighting rebuilt from each candidate's `xi`, from the first-step
+    /// `beta`.
+    ///
+    /// Each iteration takes a Gauss–Newton step `-(2 G'WG)^{-1} dQ/db` with `G = Z'X1`
+    /// and the exact gradient `dQ/db = -2 G'v - v' (dS/db) v` for `v = W g`, which
+    /// accounts for the weighting moving with `beta`, and halves it until the objective
+    /// falls enough. The search stops once the step promises a decrease of at most
+    /// `CUE_TOLERANCE` of the objective. When the weighting is a low-rank approximation or
+    /// a pseudo-inverse, the gradient treats it as the inverse of `S`, so the search also
+    /// stops once no step lowers the objective.
+    fn continuously_updated_beta(
+        &self,
+        delta: &DVector<f64>,
+        mut beta: DVector<f64>,
+        gmm: &GmmOptions,
+        factorizations: &mut Factorizations,
+    ) -> Result<DVector<f64>> {
+        let kind = gmm.moment_covariance;
+        let z = self.data.instruments();
+        let delta = self.data.demean(delta);
+        let x1 = self.data.x1();
+        let x1 = DMatrix::from_columns(
+            &(0..x1.ncols())
+                .map(|k| self.data.demean(&x1.column(k).into_owned()))
+                .collect::<Vec<_>>(),
+        );
+        let jacobian = z.transpose() * &x1;
+        let low_rank = gmm
+            .low_rank_weighting
+            .is_some_and(|settings| settings.rank < self.data.instrument_dim());
+        // Fallbacks are recorded by the caller, which rebuilds the weighting at the
+        // returned `beta`; here they only mark the weighting as approximate.
+        let evaluate = |beta: &DVector<f64>| {
+            let xi = &delta - &x1 * beta;
+            let mut factorizations = Factorizations::new(gmm.strict_factorizations);
+            let (weighting, _) = updated_weighting(&self.data, &xi, gmm, &mut factorizations)?;
+            let value = compute_gmm_objective(&self.data, &xi, &weighting);
+            if !value.is_finite() {
+                return Err(BlpError::NumericalError {
+                    context: "continuously-updated objective",
+                });
+            }
+            let approximate = low_rank || !factorizations.into_fallbacks().is_empty();
+            Ok((value, xi, weighting, approximate))
+        };
+
+        let (mut value, mut xi, mut weighting, mut approximate) = evaluate(&beta)?;
+        let mut promised = f64::NAN;
+        for iteration in 0..CUE_ITERATIONS {
+            let weighted = &weighting * (z.transpose() * &xi);
+            let scores = moment_scores(&self.data, &xi, kind)? * &weighted;
+            let donor = self.data.donor_variances().map(|(first, variances)| {
+                let k = variances.first().map_or(0, DMatrix::nrows);
+                let block = weighted.rows(first, k);
+                variances
+                    .iter()
+                    .map(|variance| block.dot(&(variance * block)))
+                    .collect::<Vec<_>>()
+            });
+            let projected = z * &weighted;
+            let mut gradient = DVector::zeros(x1.ncols());
+            for k in 0..x1.ncols() {
+                let column = x1.column(k).into_owned();
+                // dS/db_k = U_k'U + U'U_k (plus the donor block), with U_k = -U(x1_k).
+                let moved = moment_scores(&self.data, &column, kind)? * &weighted;
+                let mut curvature = -2.0 * moved.dot(&scores);
+                if let Some(donor) = &donor {
+                    curvature -= 2.0
+                        * donor
+                            .iter()
+                            .zip(xi.iter().zip(column.iter()))
+                            .map(|(quadratic, (residual, x))| quadratic * residual * x)
+                            .sum::<f64>();
+                }
+                gradient[k] = -2.0 * column.dot(&projected) - curvature;
+            }
+            let hessian = jacobian.transpose() * &weighting * &jacobian * 2.0;
+            let step = -factorizations.solve(&hessian, &gradient, "continuously-updated G'WG")?;
+            let decrease = -gradient.dot(&step);
+            promised = decrease;
+            if !decrease.is_finite() {
+                return Err(BlpError::NumericalError {
+                    context: "continuously-updated step",
+                });
+            }
+            if decrease <= CUE_TOLERANCE * value.max(f64::MIN_POSITIVE) {
+                return Ok(beta);
+            }
+
+            let mut scale = 1.0;
+            loop {
+                let candidate = &beta + &step * scale;
+                let (candidate_value, candidate_xi, candidate_weighting, candidate_approximate) =
+                    evaluate(&candidate)?;
+                if candidate_value <= value - 1e-4 * scale * decrease {
+                    (beta, value, xi, weighting, approximate) = (
+                        candidate,
+                        candidate_value,
+                        candidate_xi,
+                        candidate_weighting,
+                        candidate_approximate,
+                    );
+                    break;
+                }
+                scale /= 2.0;
+                if scale < CUE_MINIMUM_STEP {
+                    return if approximate {
+                        Ok(beta)
+                    } else {
+                        Err(BlpError::ContinuouslyUpdatedDidNotConverge {
+                            iterations: iteration + 1,
+                            decrease,
+                        })
+                    };
+                }
+            }
+        }
+        Err(BlpError::ContinuouslyUpdatedDidNotConverge {
+            iterations: CUE_ITERATIONS,
+            decrease: promised,
         })
     }
 
     /// Backwards-compatible helper for earlier API versions that called `estimate` directly.
+    #[deprecated(note = "pass `NonlinearParameters` to `Problem::solve_with_options`")]
     pub fn estimate(
         &self,
         sigma: &DMatrix<f64>,
         options: &ProblemOptions,
     ) -> Result<ProblemResults> {
-        self.solve_with_options(sigma, options)
+        self.solve_with_options(&NonlinearParameters::new(sigma.clone()), options)
     }
 }
 
+/// Estimator behind the linear parameters, set by whether instruments were supplied.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LinearEstimator {
+    /// IV-GMM with the supplied instruments.
+    #[default]
+    InstrumentalVariables,
+    /// No instruments were supplied: `X1` is treated as exogenous and instruments itself.
+    OrdinaryLeastSquares,
+    /// No instruments were supplied and `beta` is weighted least squares with
+    /// [`ProductDataBuilder::least_squares_weights`](crate::data::ProductDataBuilder::least_squares_weights).
+    WeightedLeastSquares,
+}
+
 /// Fluent builder for [`Problem`], mirroring pyBLP's keyword-heavy constructors.
 #[derive(Clone, Debug, Default)]
 pub struct ProblemBuilder {
     products: Option<ProductData>,
     draws: Option<SimulationDraws>,
     options: ProblemOptions,
+    model: Option<Arc<dyn DemandModel>>,
+    supply: Option<SupplySide>,
+    macro_moments: Vec<MacroMoment>,
 }
 
 impl ProblemBuilder {
@@ -142,6 +752,24 @@ impl ProblemBuilder {
         self
     }
 
+    /// Choose the demand model variant (defaults to [`RandomCoefficientsLogit`]).
+    pub fn model<M: DemandModel + 'static>(mut self, model: M) -> Self {
+        self.model = Some(Arc::new(model));
+        self
+    }
+
+    /// Estimate demand jointly with a Bertrand–Nash supply side.
+    pub fn supply(mut self, supply: SupplySide) -> Self {
+        self.supply = Some(supply);
+        self
+    }
+
+    /// Stack aggregate moments into the objective; see [`Problem::with_macro_moments`].
+    pub fn macro_moments(mut self, moments: Vec<MacroMoment>) -> Self {
+        self.macro_moments = moments;
+        self
+    }
+
     /// Finalise the builder into a fully-configured problem.
     pub fn build(self) -> Result<Problem> {
         let products = self
@@ -150,13 +778,164 @@ impl ProblemBuilder {
         let draws = self
             .draws
             .ok_or_else(|| BlpError::missing_component("simulation draws"))?;
-        Problem::with_options(products, draws, self.options)
+        let mut problem = Problem::with_options(products, draws, self.options)?;
+        if let Some(model) = self.model {
+            problem.model = model;
+        }
+        if !self.macro_moments.is_empty() {
+            problem = problem.with_macro_moments(self.macro_moments)?;
+        }
+        match self.supply {
+            Some(supply) => problem.with_supply(supply),
+            None => Ok(problem),
+        }
     }
 }
 
-/// Describes the result of a BLP estimation run.
+/// Every way in which the draws and the starting `sigma` and its bounds disagree with
+/// the `X2` columns, named after the offending component.
+fn specification_mismatches(
+    data: &ProductData,
+    draws: &SimulationDraws,
+    options: &ProblemOptions,
+) -> Vec<String> {
+    let k2 = data.nonlinear_dim();
+    let labels = &data.labels().x2;
+    let mut mismatches = Vec::new();
+    if draws.dimension() != k2 {
+        mismatches.push(format!(
+            "draws have {} columns but X2 has {k2} ({})",
+            draws.dimension(),
+            labels.join(", ")
+        ));
+    }
+    if let Some(demographics) = draws.demographics()
+        && demographics.nrows() != draws.draw_count()
+    {
+        mismatches.push(format!(
+            "demographics have {} rows but there are {} draws",
+            demographics.nrows(),
+            draws.draw_count()
+        ));
+    }
+    let optimization = &options.optimization;
+    let sigma = optimization.initial_sigma.as_ref();
+    if let Some(sigma) = sigma
+        && sigma.shape() != (k2, k2)
+    {
+        mismatches.push(format!(
+            "starting sigma is {}x{} but X2 has {k2} columns",
+            sigma.nrows(),
+            sigma.ncols()
+        ));
+    }
+    if let Some(bounds) = &optimization.bounds {
+        for (name, bound) in [("lower", &bounds.lower), ("upper", &bounds.upper)] {
+            if bound.shape() != (k2, k2) {
+                mismatches.push(format!(
+                    "{name} sigma bounds are {}x{} but X2 has {k2} columns",
+                    bound.nrows(),
+                    bound.ncols()
+                ));
+            }
+        }
+        if bounds.lower.shape() == (k2, k2) && bounds.upper.shape() == (k2, k2) {
+            let sigma = sigma.filter(|sigma| sigma.shape() == (k2, k2));
+            for column in 0..k2 {
+                for row in 0..k2 {
+                    let (lower, upper) = (bounds.lower[(row, column)], bounds.upper[(row, column)]);
+                    if lower > upper || lower.is_nan() || upper.is_nan() {
+                        mismatches.push(format!(
+                            "sigma[{}, {}] has lower bound {lower} above upper bound {upper}",
+                            labels[row], labels[column]
+                        ));
+                    } else if sigma.is_some_and(|sigma| sigma[(row, column)] == 0.0)
+                        && lower != upper
+                        && !(lower..=upper).contains(&0.0)
+                    {
+                        mismatches.push(format!(
+                            "sigma[{}, {}] is held at zero but bounded to [{lower}, {upper}]",
+                            labels[row], labels[column]
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    let demographics = draws
+        .demographics()
+        .map(|demographics| demographics.ncols());
+    match (&optimization.initial_pi, demographics) {
+        (Some(_), None) => {
+            mismatches.push("starting pi is set but the draws have no demographics".into())
+        }
+        (Some(pi), Some(demographics)) if pi.shape() != (k2, demographics) => {
+            mismatches.push(format!(
+                "starting pi is {}x{} but X2 has {k2} columns and the draws have {demographics} \
+                 demographics",
+                pi.nrows(),
+                pi.ncols()
+            ))
+        }
+        _ => {}
+    }
+    if let Some(mask) = &optimization.mask {
+        let (rows, columns) = mask.sigma_shape();
+        if (rows, columns) != (k2, k2) {
+            mismatches.push(format!(
+                "mask sigma is {rows}x{columns} but X2 has {k2} columns"
+            ));
+        }
+        if let Some((rows, columns)) = mask.pi_shape()
+            && (rows, columns) != (k2, demographics.unwrap_or(0))
+        {
+            mismatches.push(format!(
+                "mask pi is {rows}x{columns} but X2 has {k2} columns and the draws have {} \
+                 demographics",
+                demographics.unwrap_or(0)
+            ));
+        }
+        if mask.fixes_pi() && optimization.initial_pi.is_none() {
+            mismatches.push("the mask fixes pi entries but there is no starting pi".into());
+        }
+    }
+    if let Some(rho) = optimization.initial_rho {
+        if !(0.0..1.0).contains(&rho) {
+            mismatches.push(format!("starting rho {rho} is outside [0, 1)"));
+        }
+        if data.nesting_ids().is_none() {
+            mismatches.push("starting rho is set but the products have no nesting ids".into());
+        }
+    }
+    if options.gmm.moment_covariance == MomentCovariance::Clustered
+        && data.clustering_ids().is_none()
+    {
+        mismatches.push("clustered moment covariance needs clustering ids".into());
+    }
+    mismatches
+}
+
+/// Output of the inner loop at fixed nonlinear parameters, which does not depend on the
+/// weighting matrix.
 #[derive(Clone, Debug)]
+pub(crate) struct InnerSolution {
+    pub(crate) parameters: NonlinearParameters,
+    pub(crate) delta: DVector<f64>,
+    pub(crate) predicted_shares: DVector<f64>,
+    pub(crate) contraction: ContractionSummary,
+}
+
+/// Describes the result of a BLP estimation run.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ProblemResults {
+    /// Random coefficient scale at which the model was solved.
+    pub sigma: DMatrix<f64>,
+    /// Demographic interactions at which the model was solved, if any.
+    #[serde(default)]
+    pub pi: Option<DMatrix<f64>>,
+    /// Nesting parameter at which the model was solved, if any.
+    #[serde(default)]
+    pub rho: Option<f64>,
     /// Mean utilities recovered by the contraction mapping.
     pub delta: DVector<f64>,
     /// Linear taste parameters (equivalent to `beta` in BLP).
@@ -165,14 +944,119 @@ pub struct ProblemResults {
     pub xi: DVector<f64>,
     /// Model-implied market shares corresponding to `delta`.
     pub predicted_shares: DVector<f64>,
-    /// Value of the GMM objective at the solution.
+    /// Value of the GMM objective at the solution, including the supply moments when a
+    /// supply side is attached and any macro moments.
     pub gmm_value: f64,
     /// Diagnostics from the contraction mapping.
     pub contraction: ContractionSummary,
     /// Weighting matrix used during estimation.
     pub weighting_matrix: DMatrix<f64>,
+    /// Condition numbers of the matrices used in the linear IV step.
+    pub conditioning: ConditioningReport,
+    /// Wall-clock breakdown of the solve.
+    pub profiling: ProfilingReport,
+    /// Crate version, environment, draw seed, and input fingerprints for replication.
+    #[serde(default)]
+    pub provenance: Provenance,
     /// Options that were in effect during estimation.
     pub options_used: ProblemOptions,
+    /// Markups, marginal costs, and cost-side estimates when a supply side is attached.
+    #[serde(default)]
+    pub supply: Option<SupplyResults>,
+    /// Model values of the macro moments when any are attached.
+    #[serde(default)]
+    pub macro_moments: Option<MacroMomentResults>,
+    /// Every GMM step of a multi-step [`Problem::optimize`] run, in order; empty for a
+    /// single solve. The other fields describe the last step.
+    #[serde(default)]
+    pub gmm_steps: Vec<GmmStep>,
+    /// Asymptotic covariance of `theta = [beta; vec(sigma); vec(pi)]` (without `vec(pi)`
+    /// when `pi` is `None`), with zero rows and columns for entries held fixed. Filled in
+    /// by [`Problem::optimize`]; after a plain solve, set it from
+    /// [`Problem::parameter_covariance`].
+    #[serde(default)]
+    pub covariance: Option<DMatrix<f64>>,
+    /// Whether `beta` comes from IV-GMM or, without instruments, from least squares.
+    #[serde(default)]
+    pub linear_estimator: LinearEstimator,
+}
+
+impl ProblemResults {
+    /// The nonlinear parameters at which the model was solved.
+    pub fn parameters(&self) -> NonlinearParameters {
+        let mut parameters = NonlinearParameters::new(self.sigma.clone());
+        if let Some(pi) = &self.pi {
+            parameters = parameters.with_pi(pi.clone());
+        }
+        if let Some(rho) = self.rho {
+            parameters = parameters.with_rho(rho);
+        }
+        parameters
+    }
+
+    /// Covariance `sigma sigma'` of the random coefficients across consumers.
+    pub fn sigma_covariance(&self) -> DMatrix<f64> {
+        &self.sigma * self.sigma.transpose()
+    }
+
+    /// Standard errors of `theta = [beta; vec(sigma); vec(pi)]`, when the covariance is
+    /// known.
+    pub fn standard_errors(&self) -> Option<DVector<f64>> {
+        self.covariance
+            .as_ref()
+            .map(|covariance| covariance.diagonal().map(f64::sqrt))
+    }
+
+    /// Standard errors of `beta`, when the covariance is known.
+    pub fn beta_se(&self) -> Option<DVector<f64>> {
+        self.standard_error_block(0, self.beta.len())
+    }
+
+    /// Standard errors of `sigma`, zero for entries held fixed, when the covariance is
+    /// known.
+    pub fn sigma_se(&self) -> Option<DMatrix<f64>> {
+        let (rows, columns) = self.sigma.shape();
+        self.standard_error_block(self.beta.len(), rows * columns)
+            .map(|errors| DMatrix::from_column_slice(rows, columns, errors.as_slice()))
+    }
+
+    /// Standard errors of `pi`, zero for entries held fixed, when `pi` was estimated and
+    /// the covariance is known.
+    pub fn pi_se(&self) -> Option<DMatrix<f64>> {
+        let (rows, columns) = self.pi.as_ref()?.shape();
+        self.standard_error_block(self.beta.len() + self.sigma.len(), rows * columns)
+            .map(|errors| DMatrix::from_column_slice(rows, columns, errors.as_slice()))
+    }
+
+    /// The `len` standard errors from `start`, or `None` when the covariance is unknown
+    /// or does not cover them (e.g. one built or deserialized for fewer parameters).
+    fn standard_error_block(&self, start: usize, len: usize) -> Option<DVector<f64>> {
+        let errors = self.standard_errors()?;
+        errors
+            .as_slice()
+            .get(start..start + len)
+            .map(DVector::from_column_slice)
+    }
+}
+
+/// Estimates after one step of multi-step GMM.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct GmmStep {
+    /// Nonlinear parameters minimizing this step's objective.
+    pub sigma: DMatrix<f64>,
+    /// Demographic interactions minimizing this step's objective, when they are
+    /// estimated.
+    #[serde(default)]
+    pub pi: Option<DMatrix<f64>>,
+    /// Nesting parameter minimizing this step's objective, when it is estimated.
+    #[serde(default)]
+    pub rho: Option<f64>,
+    /// Linear parameters at `sigma`.
+    pub beta: DVector<f64>,
+    /// Objective under this step's weighting matrix.
+    pub gmm_value: f64,
+    /// Weighting matrix used in this step.
+    pub weighting_matrix: DMatrix<f64>,
 }
 
 /// Backwards-compatible alias for earlier versions of the crate.
@@ -181,10 +1065,12 @@ pub type BlpProblem = Problem;
 pub type EstimationResult = ProblemResults;
 
 /// Computes the optimal linear parameters via two-stage least squares.
-fn compute_linear_parameters(
+pub(crate) fn compute_linear_parameters(
     data: &ProductData,
     delta: &DVector<f64>,
     weighting: &DMatrix<f64>,
+    solver: LinearSolver,
+    factorizations: &mut Factorizations,
 ) -> Result<DVector<f64>> {
     let x1 = data.x1();
     let z = data.instruments();
@@ -202,12 +1088,28 @@ fn compute_linear_parameters(
         ));
     }
 
+    if solver != LinearSolver::Cholesky {
+        // Whiten the moments with W = LL' and solve min ||L'Z'X beta - L'Z'delta|| directly.
+        let factor = factorizations.factor(weighting, "W")?;
+        let design = factor.transpose() * &zx;
+        let target = factor.transpose() * (&z_t * delta);
+        let solution = match solver {
+            LinearSolver::Qr => least_squares_qr(&design, &target),
+            _ => least_squares_svd(&design, &target, 1e-12),
+        };
+        return solution.ok_or_else(|| {
+            log::warn!(
+                "least-squares solve of L'Z'X failed; condition number {:.3e}",
+                condition_number(&design)
+            );
+            BlpError::singular("L'Z'X")
+        });
+    }
+
     let xzwzx = &xz * weighting * &zx;
     let rhs = xz * (weighting * (z_t * delta));
 
-    let cholesky =
-        nalgebra::linalg::Cholesky::new(xzwzx).ok_or_else(|| BlpError::singular("X'ZWZX"))?;
-    Ok(cholesky.solve(&rhs))
+    factorizations.solve(&xzwzx, &rhs, "X'ZWZ'X")
 }
 
 /// Evaluates the standard BLP GMM objective.
@@ -219,12 +1121,146 @@ fn compute_gmm_objective(data: &ProductData, xi: &DVector<f64>, weighting: &DMat
     ztxi.dot(&w_ztxi)
 }
 
-fn inverse_ztz(z: &DMatrix<f64>) -> Result<DMatrix<f64>> {
-    let z_t = z.transpose();
-    let ztz = &z_t * z;
-    let cholesky =
-        nalgebra::linalg::Cholesky::new(ztz).ok_or_else(|| BlpError::singular("Z'Z inversion"))?;
-    Ok(cholesky.inverse())
+pub(crate) fn inverse_ztz(
+    z: &DMatrix<f64>,
+    factorizations: &mut Factorizations,
+) -> Result<DMatrix<f64>> {
+    factorizations.inverse(&(z.transpose() * z), "Z'Z")
+}
+
+/// Unscaled covariance of the moment contributions `z_j xi_j`: `Z' diag(xi^2) Z`, or the
+/// sum over clusters of `(Z_c' xi_c)(Z_c' xi_c)'`, plus `sum_j xi_j^2 V_j` for the
+/// sampling covariances `V_j` of donor instruments.
+pub(crate) fn moment_covariance(
+    data: &ProductData,
+    xi: &DVector<f64>,
+    kind: MomentCovariance,
+) -> Result<DMatrix<f64>> {
+    let scores = moment_scores(data, xi, kind)?;
+    let mut covariance = scores.transpose() * scores;
+    if let Some((first, block)) = donor_covariance(data, xi) {
+        let k = block.nrows();
+        let mut view = covariance.view_mut((first, first), (k, k));
+        view += block;
+    }
+    Ok(covariance)
+}
+
+/// Rows whose outer products sum to the moment covariance before the donor term: the
+/// contributions `z_j xi_j`, or their sums within clusters.
+fn moment_scores(
+    data: &ProductData,
+    xi: &DVector<f64>,
+    kind: MomentCovariance,
+) -> Result<DMatrix<f64>> {
+    let z = data.instruments();
+    let scores = DMatrix::from_fn(z.nrows(), z.ncols(), |j, k| z[(j, k)] * xi[j]);
+    let scores = match kind {
+        MomentCovariance::Robust => scores,
+        MomentCovariance::Clustered => {
+            let ids = data
+                .clustering_ids()
+                .ok_or_else(|| BlpError::missing_component("clustering ids"))?;
+            let mut clusters: Vec<&str> = ids.iter().map(String::as_str).collect();
+            clusters.sort_unstable();
+            clusters.dedup();
+            let mut sums = DMatrix::zeros(clusters.len(), z.ncols());
+            for (row, id) in ids.iter().enumerate() {
+                let cluster = clusters.partition_point(|cluster| *cluster < id.as_str());
+                let mut sum = sums.row_mut(cluster);
+                sum += scores.row(row);
+            }
+            sums
+        }
+    };
+    Ok(scores)
+}
+
+/// First donor column and `sum_j xi_j^2 V_j`, when there are donor instruments.
+fn donor_covariance(data: &ProductData, xi: &DVector<f64>) -> Option<(usize, DMatrix<f64>)> {
+    let (first, variances) = data.donor_variances()?;
+    let k = variances.first().map_or(0, DMatrix::nrows);
+    let block = variances
+        .iter()
+        .zip(xi.iter())
+        .fold(DMatrix::zeros(k, k), |block, (variance, residual)| {
+            block + variance * residual.powi(2)
+        });
+    Some((first, block))
+}
+
+/// `(Z'Z)^{-1}`, or its low-rank approximation under
+/// [`GmmOptions::low_rank_weighting`](crate::GmmOptions::low_rank_weighting).
+pub(crate) fn initial_weighting(
+    z: &DMatrix<f64>,
+    gmm: &GmmOptions,
+    factorizations: &mut Factorizations,
+) -> Result<(DMatrix<f64>, Option<WeightingApproximation>)> {
+    match &gmm.low_rank_weighting {
+        Some(settings) if settings.rank < z.ncols() => {
+            low_rank_weighting(z.ncols(), |x| z.transpose() * (z * x), settings)
+        }
+        _ => Ok((inverse_ztz(z, factorizations)?, None)),
+    }
+}
+
+/// [`efficient_weighting`], or its low-rank approximation under
+/// [`GmmOptions::low_rank_weighting`](crate::GmmOptions::low_rank_weighting), which
+/// never forms the moment covariance.
+pub(crate) fn updated_weighting(
+    data: &ProductData,
+    xi: &DVector<f64>,
+    gmm: &GmmOptions,
+    factorizations: &mut Factorizations,
+) -> Result<(DMatrix<f64>, Option<WeightingApproximation>)> {
+    match &gmm.low_rank_weighting {
+        Some(settings) if settings.rank < data.instrument_dim() => {
+            let scores = moment_scores(data, xi, gmm.moment_covariance)?;
+            let donor = donor_covariance(data, xi);
+            let apply = |x: &DMatrix<f64>| {
+                let mut product = scores.transpose() * (&scores * x);
+                if let Some((first, block)) = &donor {
+                    let k = block.nrows();
+                    let mut rows = product.rows_mut(*first, k);
+                    rows += block * x.rows(*first, k);
+                }
+                product
+            };
+            low_rank_weighting(data.instrument_dim(), apply, settings)
+        }
+        _ => Ok((
+            efficient_weighting(data, xi, gmm.moment_covariance, factorizations)?,
+            None,
+        )),
+    }
+}
+
+fn low_rank_weighting(
+    dimension: usize,
+    apply: impl Fn(&DMatrix<f64>) -> DMatrix<f64>,
+    settings: &LowRankWeighting,
+) -> Result<(DMatrix<f64>, Option<WeightingApproximation>)> {
+    let (weighting, relative_error) =
+        low_rank_inverse(dimension, apply, settings).ok_or(BlpError::NumericalError {
+            context: "low-rank weighting matrix",
+        })?;
+    log::debug!("low-rank weighting matrix with relative error {relative_error:.3e}");
+    let approximation = WeightingApproximation {
+        rank: settings.rank.min(dimension),
+        relative_error,
+    };
+    Ok((weighting, Some(approximation)))
+}
+
+/// Efficient weighting matrix, the inverse of the [`moment_covariance`] of first-step
+/// structural errors.
+pub(crate) fn efficient_weighting(
+    data: &ProductData,
+    xi: &DVector<f64>,
+    kind: MomentCovariance,
+    factorizations: &mut Factorizations,
+) -> Result<DMatrix<f64>> {
+    factorizations.inverse(&moment_covariance(data, xi, kind)?, "moment covariance")
 }
 
 #[cfg(test)]
@@ -233,6 +1269,10 @@ mod tests {
 
     use super::*;
     use crate::data::ProductDataBuilder;
+    use crate::elasticities::Characteristic;
+    use crate::optimization::{BlockCoordinateOptions, ParameterBlocks, ParameterCoordinate};
+    use crate::options::{OptimizationOptions, ParameterBounds};
+    use crate::parameters::{NonlinearParameters, ParameterMask};
 
     #[test]
     fn estimate_linear_logit_matches_closed_form() {
@@ -244,13 +1284,17 @@ mod tests {
             .build()
             .unwrap();
         let draws = SimulationDraws::standard_normal(1, 0, 42);
-        let sigma = DMatrix::<f64>::zeros(0, 0);
+        let parameters = NonlinearParameters::default();
         let problem = Problem::new(data, draws).unwrap();
         let options = ProblemOptions::default();
 
-        let result = problem.solve_with_options(&sigma, &options).unwrap();
+        let result = problem.solve_with_options(&parameters, &options).unwrap();
         assert_eq!(result.contraction.iterations, 1);
+        let logit = problem.clone().with_model(crate::models::Logit);
+        let closed_form = logit.solve_with_options(&parameters, &options).unwrap();
+        assert_relative_eq!(closed_form.beta, result.beta, epsilon = 1e-9);
         assert!(result.gmm_value >= 0.0);
+        assert!(!result.conditioning.is_ill_conditioned());
 
         // Homogeneous logit reduces to simple IV regression with instruments = X.
         let outside = 0.5_f64;
@@ -258,6 +1302,417 @@ mod tests {
         assert_relative_eq!(result.delta[0], delta_0, epsilon = 1e-9);
     }
 
+    #[test]
+    fn qr_and_svd_linear_solvers_match_cholesky() {
+        let market_ids = vec!["m1".to_string(), "m1".to_string(), "m2".to_string()];
+        let shares = DVector::from_vec(vec![0.3, 0.2, 0.4]);
+        let x1 = DMatrix::from_row_slice(3, 2, &[1.0, 10.0, 1.0, 15.0, 1.0, 12.0]);
+        let z = DMatrix::from_row_slice(3, 3, &[1.0, 1.0, 0.5, 1.0, 2.0, 0.1, 1.0, 0.5, 0.9]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .instruments(z)
+            .build()
+            .unwrap();
+        let problem = Problem::new(data, SimulationDraws::standard_normal(1, 0, 1)).unwrap();
+        let parameters = NonlinearParameters::default();
+
+        let baseline = problem.solve(&parameters).unwrap();
+        for solver in [LinearSolver::Qr, LinearSolver::Svd] {
+            let options = ProblemOptions::default().with_linear_solver(solver);
+            let result = problem.solve_with_options(&parameters, &options).unwrap();
+            assert_relative_eq!(result.beta, baseline.beta, epsilon = 1e-8);
+        }
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn deprecated_sigma_shims_forward_to_typed_parameters() {
+        let market_ids = ["m1", "m1", "m2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.3, 0.2, 0.4]);
+        let x1 = DMatrix::from_row_slice(3, 2, &[1.0, 2.0, 1.0, 1.0, 1.0, 0.5]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .x2_from_x1(vec![1])
+            .build()
+            .unwrap();
+        let problem = Problem::new(data, SimulationDraws::standard_normal(10, 1, 2)).unwrap();
+        let sigma = DMatrix::from_element(1, 1, 0.4);
+        let options = problem.options().clone();
+        let typed = problem
+            .solve_with_options(&NonlinearParameters::new(sigma.clone()), &options)
+            .unwrap();
+        for results in [
+            problem.solve_sigma(&sigma).unwrap(),
+            problem.solve_sigma_with_options(&sigma, &options).unwrap(),
+            problem.estimate(&sigma, &options).unwrap(),
+        ] {
+            assert_eq!(results.delta, typed.delta);
+            assert_eq!(results.gmm_value, typed.gmm_value);
+        }
+    }
+
+    #[test]
+    fn solve_from_warm_starts_the_contraction() {
+        let market_ids = vec!["m1".to_string(), "m1".to_string(), "m2".to_string()];
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.4]);
+        let x1 = DMatrix::from_row_slice(3, 2, &[1.0, 1.0, 1.0, 2.0, 1.0, 1.5]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1.clone())
+            .x2(x1.columns(1, 1).into_owned())
+            .build()
+            .unwrap();
+        let problem = Problem::new(data, SimulationDraws::standard_normal(50, 1, 8)).unwrap();
+        let parameters = NonlinearParameters::new(DMatrix::from_element(1, 1, 0.8));
+        let first = problem.solve(&parameters).unwrap();
+
+        let resumed = problem.solve_from(&first, problem.options()).unwrap();
+        assert!(resumed.contraction.iterations < first.contraction.iterations);
+        assert_relative_eq!(resumed.beta, first.beta, epsilon = 1e-7);
+        assert_eq!(resumed.weighting_matrix, first.weighting_matrix);
+    }
+
+    #[test]
+    fn fixed_beta_replaces_the_concentrated_linear_step() {
+        let market_ids = vec!["m1".to_string(), "m1".to_string(), "m2".to_string()];
+        let shares = DVector::from_vec(vec![0.3, 0.2, 0.4]);
+        let x1 = DMatrix::from_row_slice(3, 2, &[1.0, 10.0, 1.0, 15.0, 1.0, 12.0]);
+        let z = DMatrix::from_row_slice(3, 3, &[1.0, 1.0, 0.5, 1.0, 2.0, 0.1, 1.0, 0.5, 0.9]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .instruments(z)
+            .build()
+            .unwrap();
+        let problem = Problem::new(data, SimulationDraws::standard_normal(1, 0, 1)).unwrap();
+        let parameters = NonlinearParameters::default();
+        let concentrated = problem.solve(&parameters).unwrap();
+
+        let at_optimum = ProblemOptions::default().with_fixed_beta(concentrated.beta.clone());
+        let same = problem
+            .solve_with_options(&parameters, &at_optimum)
+            .unwrap();
+        assert_relative_eq!(same.gmm_value, concentrated.gmm_value, epsilon = 1e-10);
+
+        let elsewhere = concentrated.beta.add_scalar(0.1);
+        let options = ProblemOptions::default().with_fixed_beta(elsewhere.clone());
+        let fixed = problem.solve_with_options(&parameters, &options).unwrap();
+        assert_eq!(fixed.beta, elsewhere);
+        assert!(fixed.gmm_value > concentrated.gmm_value);
+
+        let wrong = ProblemOptions::default().with_fixed_beta(DVector::zeros(3));
+        assert!(problem.solve_with_options(&parameters, &wrong).is_err());
+    }
+
+    #[test]
+    fn reweight_reuses_contraction_output() {
+        let market_ids = vec!["m1".to_string(), "m1".to_string(), "m2".to_string()];
+        let shares = DVector::from_vec(vec![0.3, 0.2, 0.4]);
+        let x1 = DMatrix::from_row_slice(3, 2, &[1.0, 10.0, 1.0, 15.0, 1.0, 12.0]);
+        let x2 = DMatrix::from_row_slice(3, 1, &[10.0, 15.0, 12.0]);
+        let z = DMatrix::from_row_slice(3, 3, &[1.0, 1.0, 0.5, 1.0, 2.0, 0.1, 1.0, 0.5, 0.9]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .x2(x2)
+            .instruments(z)
+            .build()
+            .unwrap();
+        let problem = Problem::new(data, SimulationDraws::standard_normal(50, 1, 3)).unwrap();
+        let parameters = NonlinearParameters::new(DMatrix::from_row_slice(1, 1, &[0.1]));
+
+        let first = problem.solve(&parameters).unwrap();
+        let identity = WeightingMatrix::Provided(DMatrix::identity(3, 3));
+        let second = problem.reweight(&first, identity.clone()).unwrap();
+        let direct = problem
+            .solve_with_options(
+                &parameters,
+                &ProblemOptions::default().with_weighting(identity),
+            )
+            .unwrap();
+
+        assert_eq!(second.delta, first.delta);
+        assert_eq!(second.profiling.contraction_seconds, 0.0);
+        assert_relative_eq!(second.beta, direct.beta, epsilon = 1e-10);
+        assert_relative_eq!(second.gmm_value, direct.gmm_value, epsilon = 1e-10);
+    }
+
+    #[test]
+    fn continuously_updated_weighting_follows_xi() {
+        let market_ids = ["m1", "m1", "m2", "m2", "m3", "m3"]
+            .map(String::from)
+            .to_vec();
+        let shares = DVector::from_vec(vec![0.3, 0.2, 0.4, 0.1, 0.25, 0.35]);
+        let x1 = DMatrix::from_fn(6, 2, |j, k| if k == 0 { 1.0 } else { (j * j % 5) as f64 });
+        let z = DMatrix::from_fn(6, 3, |j, k| match k {
+            0 => 1.0,
+            1 => (j * j % 5) as f64,
+            _ => (j % 3) as f64 + 0.5,
+        });
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .instruments(z)
+            .build()
+            .unwrap();
+        let problem = Problem::new(data, SimulationDraws::standard_normal(1, 0, 3)).unwrap();
+        let parameters = NonlinearParameters::default();
+        let cue = ProblemOptions::default().with_weighting(WeightingMatrix::ContinuouslyUpdated);
+
+        let results = problem.solve_with_options(&parameters, &cue).unwrap();
+        let efficient = efficient_weighting(
+            problem.data(),
+            &results.xi,
+            MomentCovariance::Robust,
+            &mut Factorizations::strict(),
+        )
+        .unwrap();
+        assert_relative_eq!(results.weighting_matrix, efficient, epsilon = 1e-10);
+        let objective = compute_gmm_objective(problem.data(), &results.xi, &efficient);
+        assert_relative_eq!(results.gmm_value, objective, epsilon = 1e-12);
+        // Beta minimizes the objective with the weighting rebuilt from each beta's xi,
+        // which no point of a fine grid around it improves on.
+        let continuously_updated = |beta: &DVector<f64>| {
+            let xi = problem
+                .data()
+                .demean(&(&results.delta - problem.data().x1() * beta));
+            let weighting = efficient_weighting(
+                problem.data(),
+                &xi,
+                MomentCovariance::Robust,
+                &mut Factorizations::strict(),
+            )
+            .unwrap();
+            compute_gmm_objective(problem.data(), &xi, &weighting)
+        };
+        let mut grid_minimum = (f64::INFINITY, results.beta.clone());
+        for a in -50..=50 {
+            for b in -50..=50 {
+                let beta = &results.beta + DVector::from_vec(vec![a as f64, b as f64]) * 2e-3;
+                let value = continuously_updated(&beta);
+                if value < grid_minimum.0 {
+                    grid_minimum = (value, beta);
+                }
+            }
+        }
+        assert!(results.gmm_value <= grid_minimum.0 * (1.0 + 1e-10));
+        assert!((&grid_minimum.1 - &results.beta).amax() < 2e-3);
+        // This is not the iterated-GMM fixed point, whose beta would minimize the
+        // objective under its own weighting.
+        let reweighted = compute_linear_parameters(
+            problem.data(),
+            &results.delta,
+            &results.weighting_matrix,
+            Default::default(),
+            &mut Factorizations::strict(),
+        )
+        .unwrap();
+        assert!((&reweighted - &results.beta).amax() > 1e-6);
+        assert!(continuously_updated(&reweighted) > results.gmm_value);
+
+        let fixed = cue.with_fixed_beta(results.beta.clone() * 0.5);
+        let shifted = problem.solve_with_options(&parameters, &fixed).unwrap();
+        assert!((&shifted.weighting_matrix - &results.weighting_matrix).amax() > 1e-8);
+    }
+
+    #[test]
+    fn continuously_updated_beta_is_stationary_under_clustered_weighting() {
+        let n = 40;
+        let x = DVector::from_fn(n, |j, _| ((j * 7919) % 97) as f64 / 48.5 - 1.0);
+        let w = DVector::from_fn(n, |j, _| ((j * 31) % 11) as f64 / 5.5 - 1.0);
+        let shares = DVector::from_fn(n, |j, _| 0.1 + 0.05 * x[j] + 0.02 * w[j] * x[j]);
+        let data = ProductDataBuilder::new((0..n).map(|j| format!("m{}", j / 4)).collect(), shares)
+            .x1(DMatrix::from_fn(n, 3, |j, k| [1.0, x[j], w[j]][k]))
+            .instruments(DMatrix::from_fn(n, 5, |j, k| {
+                [1.0, x[j], w[j], x[j] * w[j], x[j] * x[j]][k]
+            }))
+            .clustering_ids((0..n).map(|j| format!("c{}", j % 20)).collect())
+            .build()
+            .unwrap();
+        let options = ProblemOptions::default()
+            .with_weighting(WeightingMatrix::ContinuouslyUpdated)
+            .with_moment_covariance(MomentCovariance::Clustered)
+            .with_strict_factorizations(true);
+        let problem =
+            Problem::with_options(data, SimulationDraws::standard_normal(1, 0, 3), options)
+                .unwrap();
+        let results = problem.solve(&NonlinearParameters::default()).unwrap();
+
+        // No small move of any entry of beta lowers the objective with the weighting
+        // rebuilt at the moved beta.
+        for k in 0..3 {
+            for step in [-1e-4, 1e-4] {
+                let mut beta = results.beta.clone();
+                beta[k] += step;
+                let xi = problem
+                    .data()
+                    .demean(&(&results.delta - problem.data().x1() * beta));
+                let weighting = efficient_weighting(
+                    problem.data(),
+                    &xi,
+                    MomentCovariance::Clustered,
+                    &mut Factorizations::strict(),
+                )
+                .unwrap();
+                assert!(compute_gmm_objective(problem.data(), &xi, &weighting) > results.gmm_value);
+            }
+        }
+    }
+
+    #[test]
+    fn standard_errors_need_a_covariance_covering_the_parameters() {
+        let market_ids = ["m1", "m1", "m2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.3, 0.2, 0.4]);
+        let x1 = DMatrix::from_row_slice(3, 2, &[1.0, 2.0, 1.0, 1.0, 1.0, 0.5]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .x2_from_x1(vec![1])
+            .build()
+            .unwrap();
+        let problem = Problem::new(data, SimulationDraws::standard_normal(10, 1, 2)).unwrap();
+        let mut results = problem
+            .solve(&NonlinearParameters::new(DMatrix::from_element(1, 1, 0.4)))
+            .unwrap();
+
+        results.covariance = Some(DMatrix::from_diagonal(&DVector::from_vec(vec![
+            4.0, 9.0, 1.0,
+        ])));
+        assert_eq!(results.beta_se().unwrap().as_slice(), &[2.0, 3.0]);
+        assert_eq!(results.sigma_se().unwrap()[(0, 0)], 1.0);
+        // A covariance of beta alone, e.g. from an older archive, has no sigma block.
+        results.covariance = Some(DMatrix::identity(2, 2));
+        assert!(results.beta_se().is_some());
+        assert!(results.sigma_se().is_none());
+        results.covariance = Some(DMatrix::identity(1, 1));
+        assert!(results.beta_se().is_none());
+
+        // Nor does the covariance of beta and sigma have a pi block.
+        results.pi = Some(DMatrix::zeros(1, 1));
+        results.covariance = Some(DMatrix::identity(3, 3));
+        assert!(results.sigma_se().is_some());
+        assert!(results.pi_se().is_none());
+        results.covariance = Some(DMatrix::identity(4, 4));
+        assert_eq!(results.pi_se().unwrap().shape(), (1, 1));
+    }
+
+    #[test]
+    fn collinear_instruments_fall_back_to_the_pseudo_inverse() {
+        let market_ids = ["m1", "m1", "m2", "m2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.3, 0.2, 0.4, 0.1]);
+        let x1 = DMatrix::from_row_slice(4, 2, &[1.0, 1.0, 1.0, 2.0, 1.0, 0.5, 1.0, 3.0]);
+        let z = DMatrix::from_fn(4, 3, |j, k| if k == 2 { x1[(j, 1)] } else { x1[(j, k)] });
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .instruments(z)
+            .build()
+            .unwrap();
+        let problem = Problem::new(data, SimulationDraws::standard_normal(1, 0, 3)).unwrap();
+        let parameters = NonlinearParameters::default();
+
+        let results = problem.solve(&parameters).unwrap();
+        assert_eq!(
+            results.conditioning.pseudo_inverses,
+            vec!["Z'Z".to_string()]
+        );
+        assert!(results.gmm_value.is_finite());
+        let strict = ProblemOptions::default().with_strict_factorizations(true);
+        let err = problem
+            .solve_with_options(&parameters, &strict)
+            .unwrap_err();
+        assert!(matches!(err, BlpError::SingularMatrix { context: "Z'Z" }));
+    }
+
+    #[test]
+    fn absorbed_fixed_effects_match_explicit_dummies() {
+        let market_ids: Vec<String> = (0..9).map(|j| format!("m{}", j / 3)).collect();
+        let shares = DVector::from_fn(9, |j, _| 0.05 + 0.03 * ((j * 7) % 5) as f64);
+        let x = DVector::from_fn(9, |j, _| ((j * j) % 7) as f64 - 2.0);
+        let w = DVector::from_fn(9, |j, _| ((j * 5) % 4) as f64 + 0.5 * x[j]);
+        let dummies = DMatrix::from_fn(9, 3, |j, k| if j / 3 == k { 1.0 } else { 0.0 });
+        let draws = SimulationDraws::standard_normal(1, 0, 3);
+        let parameters = NonlinearParameters::default();
+
+        let explicit = ProductDataBuilder::new(market_ids.clone(), shares.clone())
+            .x1(DMatrix::from_fn(9, 4, |j, k| {
+                if k == 0 { x[j] } else { dummies[(j, k - 1)] }
+            }))
+            .instruments(DMatrix::from_fn(9, 5, |j, k| match k {
+                0 => x[j],
+                1 => w[j],
+                k => dummies[(j, k - 2)],
+            }))
+            .build()
+            .unwrap();
+        let data = ProductDataBuilder::new(market_ids.clone(), shares)
+            .x1(DMatrix::from_column_slice(9, 1, x.as_slice()))
+            .instruments(DMatrix::from_fn(
+                9,
+                2,
+                |j, k| if k == 0 { x[j] } else { w[j] },
+            ))
+            .absorb(market_ids)
+            .build()
+            .unwrap();
+        let explicit = Problem::new(explicit, draws.clone())
+            .unwrap()
+            .solve(&parameters)
+            .unwrap();
+        let absorbed = Problem::new(data.clone(), draws)
+            .unwrap()
+            .solve(&parameters)
+            .unwrap();
+
+        assert_relative_eq!(absorbed.beta[0], explicit.beta[0], epsilon = 1e-10);
+        assert_relative_eq!(absorbed.xi, explicit.xi, epsilon = 1e-10);
+        assert_relative_eq!(absorbed.gmm_value, explicit.gmm_value, epsilon = 1e-10);
+
+        let constant = data
+            .to_builder()
+            .x1(DMatrix::from_element(9, 1, 1.0))
+            .build();
+        assert!(matches!(
+            constant,
+            Err(BlpError::InconsistentSpecification { .. })
+        ));
+    }
+
+    #[test]
+    fn missing_instruments_fall_back_to_flagged_least_squares() {
+        let market_ids = ["m1", "m1", "m2", "m2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.3, 0.2, 0.4, 0.1]);
+        let x1 = DMatrix::from_row_slice(4, 2, &[1.0, 1.0, 1.0, 2.0, 1.0, 0.5, 1.0, 3.0]);
+        let weights = DVector::from_vec(vec![1.0, 2.0, 0.5, 1.5]);
+        let builder = ProductDataBuilder::new(market_ids, shares).x1(x1.clone());
+        let draws = SimulationDraws::standard_normal(1, 0, 3);
+        let parameters = NonlinearParameters::default();
+
+        let ols = Problem::new(builder.clone().build().unwrap(), draws.clone()).unwrap();
+        let results = ols.solve(&parameters).unwrap();
+        assert_eq!(
+            results.linear_estimator,
+            LinearEstimator::OrdinaryLeastSquares
+        );
+        let normal = x1.transpose() * &x1;
+        let expected = normal.try_inverse().unwrap() * x1.transpose() * &results.delta;
+        assert_relative_eq!(results.beta, expected, epsilon = 1e-10);
+
+        let data = builder
+            .least_squares_weights(weights.clone())
+            .build()
+            .unwrap();
+        let wls = Problem::new(data, draws).unwrap();
+        let results = wls.solve(&parameters).unwrap();
+        assert_eq!(
+            results.linear_estimator,
+            LinearEstimator::WeightedLeastSquares
+        );
+        let weighted = DMatrix::from_diagonal(&weights);
+        let normal = x1.transpose() * &weighted * &x1;
+        let expected = normal.try_inverse().unwrap() * x1.transpose() * &weighted * &results.delta;
+        assert_relative_eq!(results.beta, expected, epsilon = 1e-10);
+        let subset = wls.data().select_markets(&[1]).unwrap();
+        assert_eq!(
+            subset.linear_estimator(),
+            LinearEstimator::WeightedLeastSquares
+        );
+    }
+
     #[test]
     fn builder_requires_components() {
         let market_ids = vec!["m1".to_string(), "m1".to_string()];
@@ -288,4 +1743,251 @@ mod tests {
             .expect_err("missing products");
         assert!(matches!(err, BlpError::MissingComponent { .. }));
     }
+
+    #[test]
+    fn dimensions_report_sizes_including_the_supply_side() {
+        let market_ids = ["m1", "m1", "m2", "m2", "m2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.1, 0.2, 0.3]);
+        let x1 = DMatrix::from_fn(5, 2, |j, k| if k == 0 { 1.0 } else { 1.0 + j as f64 });
+        let z = DMatrix::from_fn(5, 3, |j, k| (1.0 + j as f64).powi(k as i32));
+        let products = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1.clone())
+            .x2(x1.columns(1, 1).into_owned())
+            .instruments(z.clone())
+            .build()
+            .unwrap();
+        let problem = Problem::new(products, SimulationDraws::standard_normal(4, 1, 7)).unwrap();
+
+        let dimensions = problem.dimensions();
+        assert_eq!(dimensions.products, 5);
+        assert_eq!(dimensions.markets, 2);
+        assert_eq!(dimensions.products_per_market, vec![2, 3]);
+        assert_eq!(dimensions.max_products_per_market(), 3);
+        assert_eq!((dimensions.k1, dimensions.k2, dimensions.k3), (2, 1, 0));
+        assert_eq!((dimensions.instruments, dimensions.draws), (3, 4));
+        assert!(dimensions.flops_per_evaluation(10) > dimensions.flops_per_evaluation(1));
+
+        let firms = ["a", "b", "a", "b", "c"].map(String::from).to_vec();
+        let supply = SupplySide::new(firms, z.clone(), z, Characteristic::linear(1));
+        let joint = problem.with_supply(supply).unwrap().dimensions();
+        assert_eq!(joint.k3, 3);
+        assert!(joint.memory_bytes > dimensions.memory_bytes);
+    }
+
+    #[test]
+    fn inconsistent_specifications_are_reported_together() {
+        let market_ids = ["m1", "m1", "m2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.3, 0.2, 0.4]);
+        let x1 = DMatrix::from_row_slice(3, 3, &[1.0, 1.0, 2.0, 1.0, 2.0, 1.0, 1.0, 1.5, 0.5]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .x2_from_x1(vec![1, 2])
+            .x1_labels(vec!["constant".into(), "price".into(), "size".into()])
+            .build()
+            .unwrap();
+        let starting = DMatrix::from_row_slice(2, 2, &[1.0, 0.0, 0.0, 1.0]);
+        let bounds = ParameterBounds::new(
+            DMatrix::from_element(2, 2, 0.1),
+            DMatrix::from_element(2, 2, 5.0),
+        );
+        let options = ProblemOptions::default()
+            .with_optimization(OptimizationOptions::new(starting).with_bounds(bounds));
+
+        let err = Problem::with_options(
+            data.clone(),
+            SimulationDraws::standard_normal(10, 1, 2),
+            options.clone(),
+        )
+        .expect_err("draws and bounds disagree with the specification");
+        let BlpError::InconsistentSpecification { mismatches } = err else {
+            panic!("expected an inconsistent specification, got {err}");
+        };
+        assert_eq!(mismatches.len(), 3);
+        assert!(mismatches[0].contains("X2 has 2 (price, size)"));
+        assert!(mismatches[1].contains("sigma[size, price]"));
+        assert!(mismatches[2].contains("sigma[price, size]"));
+        assert!(
+            Problem::with_options(
+                data.clone(),
+                SimulationDraws::standard_normal(10, 2, 2),
+                options
+            )
+            .is_err()
+        );
+
+        // Starting pi and the mask are checked against X2 and the demographics.
+        let draws = SimulationDraws::standard_normal(10, 2, 2);
+        let optimization = OptimizationOptions::new(DMatrix::identity(2, 2))
+            .with_pi(DMatrix::zeros(2, 1))
+            .with_mask(ParameterMask::new(3).with_demographics(1));
+        let options = ProblemOptions::default().with_optimization(optimization);
+        let err = Problem::with_options(data.clone(), draws.clone(), options.clone())
+            .expect_err("pi without demographics and a 3x3 mask");
+        let BlpError::InconsistentSpecification { mismatches } = err else {
+            panic!("expected an inconsistent specification, got {err}");
+        };
+        assert_eq!(mismatches.len(), 3, "{mismatches:?}");
+        assert!(mismatches[0].contains("no demographics"));
+        assert!(mismatches[1].contains("mask sigma is 3x3"));
+        assert!(mismatches[2].contains("mask pi is 3x1"));
+        let income = DMatrix::from_element(10, 2, 1.0);
+        let err = Problem::with_options(data, draws.with_demographics(income).unwrap(), options)
+            .expect_err("pi and the mask disagree with two demographics");
+        let BlpError::InconsistentSpecification { mismatches } = err else {
+            panic!("expected an inconsistent specification, got {err}");
+        };
+        assert!(mismatches[0].contains("starting pi is 2x1"));
+        assert!(mismatches[2].contains("draws have 2 demographics"));
+    }
+
+    #[test]
+    fn reversed_bounds_are_rejected_and_equal_bounds_may_move_zeros() {
+        let market_ids = ["m1", "m1", "m2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.3, 0.2, 0.4]);
+        let x1 = DMatrix::from_row_slice(3, 2, &[1.0, 2.0, 1.0, 1.0, 1.0, 0.5]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .x2_from_x1(vec![1])
+            .build()
+            .unwrap();
+        let draws = SimulationDraws::standard_normal(10, 1, 2);
+        let problem = |bounds: ParameterBounds| {
+            let options = ProblemOptions::default()
+                .with_optimization(OptimizationOptions::new(DMatrix::zeros(1, 1)))
+                .with_bounds(bounds);
+            Problem::with_options(data.clone(), draws.clone(), options)
+        };
+
+        // A zero start outside the bounds is only consistent when the bounds fix it.
+        assert!(problem(ParameterBounds::unbounded(1).with_lower(0, 0, 0.5)).is_err());
+        assert!(problem(ParameterBounds::unbounded(1).with_fixed(0, 0, 0.5)).is_ok());
+        let err = problem(
+            ParameterBounds::unbounded(1)
+                .with_lower(0, 0, 1.0)
+                .with_upper(0, 0, 0.5),
+        )
+        .expect_err("reversed bounds");
+        let BlpError::InconsistentSpecification { mismatches } = err else {
+            panic!("expected an inconsistent specification, got {err}");
+        };
+        assert_eq!(mismatches.len(), 1);
+        assert!(mismatches[0].contains("lower bound 1 above upper bound 0.5"));
+
+        // Reversed or NaN bounds are errors without a starting sigma, and when a search
+        // is given its start directly.
+        let reversed = ParameterBounds::unbounded(1)
+            .with_lower(0, 0, 1.0)
+            .with_upper(0, 0, 0.5);
+        let unstarted = ProblemOptions::default().with_bounds(reversed.clone());
+        assert!(matches!(
+            Problem::with_options(data.clone(), draws.clone(), unstarted),
+            Err(BlpError::InconsistentSpecification { .. })
+        ));
+        let valid = problem(ParameterBounds::unbounded(1)).unwrap();
+        let start = NonlinearParameters::new(DMatrix::from_element(1, 1, 0.7));
+        let search = BlockCoordinateOptions::new(ParameterBlocks::new(vec![vec![
+            ParameterCoordinate::Sigma(0, 0),
+        ]]));
+        let nan = ParameterBounds::unbounded(1).with_upper(0, 0, f64::NAN);
+        for bounds in [reversed, nan] {
+            let overridden =
+                valid.with_options_override(valid.options().clone().with_bounds(bounds));
+            assert!(matches!(
+                overridden.optimize_blocks(&start, &search),
+                Err(BlpError::InvalidBounds {
+                    row: 0,
+                    column: 0,
+                    ..
+                })
+            ));
+            assert!(matches!(
+                overridden.optimize(),
+                Err(BlpError::InvalidBounds { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn modified_data_is_revalidated_and_shares_settings() {
+        let market_ids = ["m1", "m1", "m2", "m2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.25, 0.15]);
+        let x1 = DMatrix::from_row_slice(4, 2, &[1.0, 1.0, 1.0, 2.0, 1.0, 1.5, 1.0, 0.5]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .build()
+            .unwrap();
+        let options = ProblemOptions::default().with_max_gmm_iterations(3);
+        let problem =
+            Problem::with_options(data, SimulationDraws::standard_normal(1, 0, 7), options)
+                .unwrap();
+
+        let dropped = problem
+            .with_modified_data(|data| data.select_markets(&[1]))
+            .unwrap();
+        assert_eq!(dropped.data().product_count(), 2);
+        assert_eq!(dropped.options().gmm.max_iterations, 3);
+        assert_eq!(dropped.draws().draw_count(), 1);
+
+        let perturbed = problem
+            .with_modified_data(|data| {
+                let z = data.instruments().map(|value| value * 1.1);
+                data.to_builder().instruments(z).build()
+            })
+            .unwrap();
+        assert_relative_eq!(perturbed.data().instruments()[(1, 1)], 2.2);
+        let invalid = problem
+            .with_modified_data(|data| data.to_builder().instruments(DMatrix::zeros(3, 2)).build());
+        assert!(matches!(invalid, Err(BlpError::DimensionMismatch { .. })));
+    }
+
+    #[test]
+    fn low_rank_weighting_sketches_the_leading_moment_directions() {
+        let factors = DMatrix::from_fn(30, 4, |i, k| ((i * (k + 2)) as f64).sin());
+        let covariance = &factors * factors.transpose();
+        let settings = LowRankWeighting::new(4);
+        let (inverse, error) = low_rank_inverse(30, |x| &covariance * x, &settings).unwrap();
+        let exact = crate::linalg::pseudo_inverse(&covariance).unwrap();
+        assert!((&inverse - &exact).amax() < 1e-6 * exact.amax());
+        assert!(error < 1e-10);
+
+        let n = 60;
+        let market_ids = (0..n).map(|j| format!("m{}", j / 3)).collect();
+        let x = DVector::from_fn(n, |j, _| (j as f64 * 0.37).sin() + 1.5);
+        let shares = DVector::from_fn(n, |j, _| 0.1 + 0.05 * (j % 3) as f64);
+        let instruments =
+            DMatrix::from_fn(n, 30, |j, k| (x[j] * (k as f64 + 1.0) * 0.3).cos() + x[j]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(DMatrix::from_fn(
+                n,
+                2,
+                |j, k| if k == 0 { 1.0 } else { x[j] },
+            ))
+            .instruments(instruments)
+            .build()
+            .unwrap();
+        let draws = SimulationDraws::standard_normal(1, 0, 3);
+        let parameters = NonlinearParameters::default();
+        for rank in [5, 30] {
+            let options = ProblemOptions::default()
+                .with_weighting(WeightingMatrix::ContinuouslyUpdated)
+                .with_low_rank_weighting(LowRankWeighting::new(rank));
+            let problem = Problem::with_options(data.clone(), draws.clone(), options).unwrap();
+            let results = problem.solve(&parameters).unwrap();
+            let approximation = results.conditioning.weighting_approximation;
+            assert!(results.beta.iter().all(|value| value.is_finite()));
+            match approximation {
+                Some(approximation) => {
+                    assert_eq!(approximation.rank, 5);
+                    assert!(approximation.relative_error.is_finite());
+                    assert_eq!(
+                        results
+                            .weighting_matrix
+                            .rank(1e-8 * results.weighting_matrix.amax()),
+                        5
+                    );
+                }
+                None => assert_eq!(rank, 30),
+            }
+        }
+    }
 }
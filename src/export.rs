@@ -0,0 +1,216 @@
+//! Tidy (long-format) tables of per-market matrices.
+//!
+//! Elasticities, diversion ratios, and share derivatives are returned as one `J_t x J_t`
+//! matrix per market. Plotting and reporting tools usually want one row per
+//! `(market, product_i, product_j)` instead; [`tidy_pairs`] flattens the matrices and the
+//! writers below save the result as CSV or, with the `arrow` feature, as an Arrow IPC
+//! file.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use nalgebra::DMatrix;
+use serde::{Deserialize, Serialize};
+
+use crate::data::ProductData;
+use crate::error::{BlpError, Result};
+
+/// One entry of a per-market product-by-product matrix.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PairRecord {
+    /// Market the two products belong to.
+    pub market_id: String,
+    /// Row product: its product id, or its row in the data when no ids were supplied.
+    pub product_i: String,
+    /// Column product, labelled like `product_i`.
+    pub product_j: String,
+    /// Matrix entry `(i, j)`.
+    pub value: f64,
+}
+
+/// Flatten one matrix per market (in partition order) into long-format records, row by
+/// row within each market.
+pub fn tidy_pairs(data: &ProductData, matrices: &[DMatrix<f64>]) -> Result<Vec<PairRecord>> {
+    let markets = data.partition().market_count();
+    if matrices.len() != markets {
+        return Err(BlpError::dimension_mismatch(
+            "per-market matrices",
+            markets,
+            matrices.len(),
+        ));
+    }
+    let label = |row: usize| {
+        data.product_id(row)
+            .map_or_else(|| row.to_string(), str::to_string)
+    };
+    let mut records = Vec::with_capacity(matrices.iter().map(|matrix| matrix.len()).sum());
+    for (market, matrix) in data.partition().markets().zip(matrices) {
+        let range = market.range();
+        if matrix.shape() != (range.len(), range.len()) {
+            return Err(BlpError::dimension_mismatch(
+                "per-market matrix size",
+                range.len(),
+                matrix.nrows(),
+            ));
+        }
+        for (i, row_i) in range.clone().enumerate() {
+            for (j, row_j) in range.clone().enumerate() {
+                records.push(PairRecord {
+                    market_id: market.id().to_string(),
+                    product_i: label(row_i),
+                    product_j: label(row_j),
+                    value: matrix[(i, j)],
+                });
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// Write records as CSV with columns `market_id,product_i,product_j,<value_name>`.
+pub fn write_pairs_csv<P: AsRef<Path>>(
+    records: &[PairRecord],
+    value_name: &str,
+    path: P,
+) -> Result<()> {
+    let file = File::create(path).map_err(|err| BlpError::io("creating CSV export", err))?;
+    let mut writer = BufWriter::new(file);
+    let write = |writer: &mut BufWriter<File>, fields: [&str; 4]| {
+        let fields: Vec<String> = fields.iter().map(|field| csv_field(field)).collect();
+        writeln!(writer, "{}", fields.join(","))
+            .map_err(|err| BlpError::io("writing CSV export", err))
+    };
+    write(
+        &mut writer,
+        ["market_id", "product_i", "product_j", value_name],
+    )?;
+    for record in records {
+        write(
+            &mut writer,
+            [
+                &record.market_id,
+                &record.product_i,
+                &record.product_j,
+                &record.value.to_string(),
+            ],
+        )?;
+    }
+    writer
+        .flush()
+        .map_err(|err| BlpError::io("flushing CSV export", err))
+}
+
+/// Quote a field when it contains a delimiter, quote, or line break.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Write records as an Arrow IPC file with three string columns and a `Float64`
+/// column named `value_name`.
+#[cfg(feature = "arrow")]
+pub fn write_pairs_arrow<P: AsRef<Path>>(
+    records: &[PairRecord],
+    value_name: &str,
+    path: P,
+) -> Result<()> {
+    use std::sync::Arc;
+
+    use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray};
+    use arrow_ipc::writer::FileWriter;
+    use arrow_schema::{DataType, Field, Schema};
+
+    let arrow_error = |err: arrow_schema::ArrowError| BlpError::Serialization {
+        context: "Arrow export",
+        message: err.to_string(),
+    };
+    let strings = |field: fn(&PairRecord) -> &str| -> ArrayRef {
+        Arc::new(StringArray::from_iter_values(records.iter().map(field)))
+    };
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("market_id", DataType::Utf8, false),
+        Field::new("product_i", DataType::Utf8, false),
+        Field::new("product_j", DataType::Utf8, false),
+        Field::new(value_name, DataType::Float64, false),
+    ]));
+    let batch = RecordBatch::try_new(
+        Arc::clone(&schema),
+        vec![
+            strings(|record| &record.market_id),
+            strings(|record| &record.product_i),
+            strings(|record| &record.product_j),
+            Arc::new(Float64Array::from_iter_values(
+                records.iter().map(|record| record.value),
+            )),
+        ],
+    )
+    .map_err(arrow_error)?;
+
+    let file = File::create(path).map_err(|err| BlpError::io("creating Arrow export", err))?;
+    let mut writer = FileWriter::try_new(BufWriter::new(file), &schema).map_err(arrow_error)?;
+    writer.write(&batch).map_err(arrow_error)?;
+    writer.finish().map_err(arrow_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::DVector;
+
+    use super::*;
+    use crate::data::ProductDataBuilder;
+
+    #[test]
+    fn matrices_flatten_to_labelled_rows() {
+        let market_ids = ["m1", "m1", "m2"].map(String::from).to_vec();
+        let data = ProductDataBuilder::new(market_ids, DVector::from_vec(vec![0.2, 0.3, 0.4]))
+            .x1(DMatrix::from_element(3, 1, 1.0))
+            .product_ids(["a", "b,c", "a"].map(String::from).to_vec())
+            .build()
+            .unwrap();
+        let matrices = vec![
+            DMatrix::from_row_slice(2, 2, &[1.0, 2.0, 3.0, 4.0]),
+            DMatrix::from_element(1, 1, 5.0),
+        ];
+        let records = tidy_pairs(&data, &matrices).unwrap();
+        assert_eq!(records.len(), 5);
+        assert_eq!(
+            records[1],
+            PairRecord {
+                market_id: "m1".into(),
+                product_i: "a".into(),
+                product_j: "b,c".into(),
+                value: 2.0,
+            }
+        );
+        assert!(tidy_pairs(&data, &matrices[..1]).is_err());
+
+        let path = std::env::temp_dir().join(format!("blprs-tidy-{}.csv", std::process::id()));
+        write_pairs_csv(&records, "elasticity", &path).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines[0], "market_id,product_i,product_j,elasticity");
+        assert_eq!(lines[2], "m1,a,\"b,c\",2");
+        assert_eq!(lines.len(), 6);
+    }
+
+    #[cfg(feature = "arrow")]
+    #[test]
+    fn arrow_export_writes_an_ipc_file() {
+        let records = vec![PairRecord {
+            market_id: "m1".into(),
+            product_i: "0".into(),
+            product_j: "0".into(),
+            value: -1.5,
+        }];
+        let path = std::env::temp_dir().join(format!("blprs-tidy-{}.arrow", std::process::id()));
+        write_pairs_arrow(&records, "elasticity", &path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(bytes.starts_with(b"ARROW1"));
+    }
+}
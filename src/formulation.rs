@@ -1,9 +1,32 @@
-//! Lightweight placeholder for pyBLP-style formulas.
+//! pyBLP-style formulas that build design matrices from named data columns.
 //!
-//! A full translation of pyBLP will eventually parse and evaluate expressions like
-//! `"0 + prices + x1 + x2"`. For now, this type stores the raw expression so that
-//! builders and configuration structs can accept user intent with parity to the
-//! Python API.
+//! A [`Formulation`] such as `"1 + prices + log(hpwt) + C(region)"` is parsed into terms
+//! and evaluated against [`FormulaData`], producing a [`DesignMatrix`] whose column
+//! names can be passed straight to [`ProductDataBuilder`](crate::data::ProductDataBuilder)
+//! as `X1`, `X2`, or `X3` labels.
+//!
+//! The grammar follows patsy, which pyBLP uses:
+//!
+//! - Terms are separated by `+`. An intercept column named `1` is included unless the
+//!   formula contains `0` or `- 1`.
+//! - `a:b` is the elementwise product of two factors and `a*b` expands to
+//!   `a + b + a:b`.
+//! - `log`, `exp`, `sqrt`, and `abs` transform a column or arithmetic expression, and
+//!   `I(...)` evaluates arithmetic with `+ - * /` and `**` (or `^`) literally.
+//! - `C(column)` dummy-codes a categorical column with one column per level, in sorted
+//!   order, named `C(column)[level]`. When the design already spans a constant (an
+//!   intercept or an earlier categorical term), the reference level is omitted: the
+//!   first level unless chosen with `C(column, Treatment('level'))` or
+//!   [`FormulaData::with_reference`].
+//!
+//! Duplicate terms are kept once, in order of first appearance.
+
+use std::collections::HashMap;
+
+use nalgebra::{DMatrix, DVector};
+
+use crate::error::{BlpError, Result};
+use crate::ingest::WideTable;
 
 /// Represents a symbolic specification of linear or nonlinear characteristics.
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -23,6 +46,82 @@ impl Formulation {
     pub fn expression(&self) -> &str {
         &self.expression
     }
+
+    /// Names of the terms in the formula, including `1` for the intercept, without
+    /// evaluating them.
+    pub fn terms(&self) -> Result<Vec<String>> {
+        let parsed = self.parse()?;
+        let mut names: Vec<String> = parsed.terms.iter().map(Term::name).collect();
+        if parsed.intercept {
+            names.insert(0, "1".to_string());
+        }
+        Ok(names)
+    }
+
+    /// Evaluate the formula against `data`.
+    pub fn design(&self, data: &FormulaData) -> Result<DesignMatrix> {
+        let parsed = self.parse()?;
+        let rows = data.rows();
+        let mut columns: Vec<DVector<f64>> = Vec::new();
+        let mut names = Vec::new();
+        let mut spans_constant = parsed.intercept;
+        if parsed.intercept {
+            columns.push(DVector::from_element(rows, 1.0));
+            names.push("1".to_string());
+        }
+        for term in &parsed.terms {
+            // Each factor contributes one or more columns; the term is their products.
+            let mut products = vec![(String::new(), DVector::from_element(rows, 1.0))];
+            let mut categorical = false;
+            for factor in &term.factors {
+                let factor_columns = match factor {
+                    Factor::Numeric { expression, name } => {
+                        vec![(name.clone(), evaluate(expression, data, name)?)]
+                    }
+                    Factor::Categorical { column, reference } => {
+                        categorical = true;
+                        dummies(data, column, reference.as_deref(), spans_constant)?
+                    }
+                };
+                products = products
+                    .iter()
+                    .flat_map(|(left, values)| {
+                        factor_columns.iter().map(move |(right, factor_values)| {
+                            let name = if left.is_empty() {
+                                right.clone()
+                            } else {
+                                format!("{left}:{right}")
+                            };
+                            (name, values.component_mul(factor_values))
+                        })
+                    })
+                    .collect();
+            }
+            if categorical && term.factors.len() == 1 {
+                spans_constant = true;
+            }
+            for (name, values) in products {
+                names.push(name);
+                columns.push(values);
+            }
+        }
+        let matrix = if columns.is_empty() {
+            DMatrix::zeros(rows, 0)
+        } else {
+            DMatrix::from_columns(&columns)
+        };
+        Ok(DesignMatrix { matrix, names })
+    }
+
+    fn parse(&self) -> Result<ParsedFormula> {
+        let tokens = tokenize(&self.expression)?;
+        FormulaParser {
+            source: &self.expression,
+            tokens: &tokens,
+            position: 0,
+        }
+        .formula()
+    }
 }
 
 impl From<&str> for Formulation {
@@ -37,13 +136,707 @@ impl From<String> for Formulation {
     }
 }
 
+/// Named numeric and categorical columns, one value per product, that formulas refer to.
+#[derive(Clone, Debug, Default)]
+pub struct FormulaData {
+    rows: usize,
+    numeric: HashMap<String, DVector<f64>>,
+    categorical: HashMap<String, Vec<String>>,
+    references: HashMap<String, String>,
+}
+
+impl FormulaData {
+    /// Empty data with `rows` products.
+    pub fn new(rows: usize) -> Self {
+        Self {
+            rows,
+            ..Self::default()
+        }
+    }
+
+    /// Add or replace a numeric column.
+    pub fn with_numeric(mut self, name: impl Into<String>, values: DVector<f64>) -> Result<Self> {
+        self.check_length(values.len())?;
+        self.numeric.insert(name.into(), values);
+        Ok(self)
+    }
+
+    /// Add or replace a categorical column, usable inside `C(...)`.
+    pub fn with_categorical(
+        mut self,
+        name: impl Into<String>,
+        values: Vec<String>,
+    ) -> Result<Self> {
+        self.check_length(values.len())?;
+        self.categorical.insert(name.into(), values);
+        Ok(self)
+    }
+
+    /// Omit `level` of `column` from `C(column)` dummies instead of the first sorted
+    /// level. A `Treatment` reference written in the formula takes precedence.
+    pub fn with_reference(mut self, column: impl Into<String>, level: impl Into<String>) -> Self {
+        self.references.insert(column.into(), level.into());
+        self
+    }
+
+    /// Number of products.
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    fn check_length(&self, length: usize) -> Result<()> {
+        if length != self.rows {
+            return Err(BlpError::dimension_mismatch(
+                "formula column length",
+                self.rows,
+                length,
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl From<&WideTable> for FormulaData {
+    /// Every numeric column of the table, plus `market_ids` and `product_ids` as
+    /// categorical columns.
+    fn from(table: &WideTable) -> Self {
+        let mut data = Self::new(table.market_ids().len());
+        for (index, name) in table.column_names().iter().enumerate() {
+            data.numeric
+                .insert(name.clone(), table.values().column(index).into_owned());
+        }
+        data.categorical
+            .insert("market_ids".to_string(), table.market_ids().to_vec());
+        data.categorical
+            .insert("product_ids".to_string(), table.product_ids().to_vec());
+        data
+    }
+}
+
+/// A design matrix with one name per column.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DesignMatrix {
+    /// Values, one row per product.
+    pub matrix: DMatrix<f64>,
+    /// Column names, e.g. `1`, `prices`, `log(hpwt)`, `C(region)[west]`.
+    pub names: Vec<String>,
+}
+
+#[derive(Debug)]
+struct ParsedFormula {
+    intercept: bool,
+    terms: Vec<Term>,
+}
+
+#[derive(Clone, Debug)]
+struct Term {
+    factors: Vec<Factor>,
+}
+
+impl Term {
+    fn name(&self) -> String {
+        self.factors
+            .iter()
+            .map(Factor::name)
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Factor {
+    Numeric {
+        expression: Expression,
+        name: String,
+    },
+    Categorical {
+        column: String,
+        reference: Option<String>,
+    },
+}
+
+impl Factor {
+    fn name(&self) -> String {
+        match self {
+            Factor::Numeric { name, .. } => name.clone(),
+            Factor::Categorical { column, .. } => format!("C({column})"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Function {
+    Log,
+    Exp,
+    Sqrt,
+    Abs,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "log" => Some(Self::Log),
+            "exp" => Some(Self::Exp),
+            "sqrt" => Some(Self::Sqrt),
+            "abs" => Some(Self::Abs),
+            _ => None,
+        }
+    }
+
+    fn apply(self, value: f64) -> f64 {
+        match self {
+            Self::Log => value.ln(),
+            Self::Exp => value.exp(),
+            Self::Sqrt => value.sqrt(),
+            Self::Abs => value.abs(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Expression {
+    Number(f64),
+    Column(String),
+    Call(Function, Box<Expression>),
+    Negate(Box<Expression>),
+    Binary(char, Box<Expression>, Box<Expression>),
+}
+
+fn evaluate(expression: &Expression, data: &FormulaData, name: &str) -> Result<DVector<f64>> {
+    let values = evaluate_unchecked(expression, data)?;
+    if let Some(row) = values.iter().position(|value| !value.is_finite()) {
+        return Err(invalid(format!("`{name}` is not finite at row {row}")));
+    }
+    Ok(values)
+}
+
+fn evaluate_unchecked(expression: &Expression, data: &FormulaData) -> Result<DVector<f64>> {
+    Ok(match expression {
+        Expression::Number(value) => DVector::from_element(data.rows, *value),
+        Expression::Column(column) => match data.numeric.get(column) {
+            Some(values) => values.clone(),
+            None if data.categorical.contains_key(column) => {
+                return Err(invalid(format!(
+                    "column `{column}` is categorical; wrap it in C(...)"
+                )));
+            }
+            None => return Err(invalid(format!("unknown column `{column}`"))),
+        },
+        Expression::Call(function, argument) => {
+            evaluate_unchecked(argument, data)?.map(|value| function.apply(value))
+        }
+        Expression::Negate(argument) => -evaluate_unchecked(argument, data)?,
+        Expression::Binary(operator, left, right) => {
+            let left = evaluate_unchecked(left, data)?;
+            let right = evaluate_unchecked(right, data)?;
+            left.zip_map(&right, |a, b| match operator {
+                '+' => a + b,
+                '-' => a - b,
+                '*' => a * b,
+                '/' => a / b,
+                _ => a.powf(b),
+            })
+        }
+    })
+}
+
+/// Dummies for the levels of a categorical column, omitting the reference level when the
+/// design already spans a constant.
+fn dummies(
+    data: &FormulaData,
+    column: &str,
+    reference: Option<&str>,
+    spans_constant: bool,
+) -> Result<Vec<(String, DVector<f64>)>> {
+    let values: Vec<String> = match (data.categorical.get(column), data.numeric.get(column)) {
+        (Some(values), _) => values.clone(),
+        (None, Some(values)) => values.iter().map(|value| value.to_string()).collect(),
+        (None, None) => return Err(invalid(format!("unknown column `{column}`"))),
+    };
+    let mut levels = values.clone();
+    levels.sort_unstable();
+    levels.dedup();
+    let reference = match reference.or(data.references.get(column).map(String::as_str)) {
+        Some(level) if !levels.iter().any(|existing| existing == level) => {
+            return Err(invalid(format!(
+                "reference level `{level}` does not occur in column `{column}`"
+            )));
+        }
+        Some(level) => level.to_string(),
+        None => levels.first().cloned().unwrap_or_default(),
+    };
+    Ok(levels
+        .iter()
+        .filter(|level| !spans_constant || **level != reference)
+        .map(|level| {
+            let indicator =
+                DVector::from_iterator(values.len(), values.iter().map(|v| f64::from(v == level)));
+            (format!("C({column})[{level}]"), indicator)
+        })
+        .collect())
+}
+
+fn invalid(message: String) -> BlpError {
+    BlpError::InvalidFormula { message }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Name(String),
+    Text(String),
+    Symbol(&'static str),
+}
+
+/// Tokens with their byte ranges in the source, so factor names keep the user's text.
+fn tokenize(source: &str) -> Result<Vec<(Token, usize, usize)>> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut position = 0;
+    while position < bytes.len() {
+        let start = position;
+        let byte = bytes[position];
+        if byte.is_ascii_whitespace() {
+            position += 1;
+            continue;
+        }
+        if byte.is_ascii_digit() || byte == b'.' {
+            while position < bytes.len()
+                && (bytes[position].is_ascii_digit() || bytes[position] == b'.')
+            {
+                position += 1;
+            }
+            let text = &source[start..position];
+            let value = text
+                .parse()
+                .map_err(|_| invalid(format!("invalid number `{text}`")))?;
+            tokens.push((Token::Number(value), start, position));
+            continue;
+        }
+        if byte.is_ascii_alphabetic() || byte == b'_' {
+            while position < bytes.len()
+                && (bytes[position].is_ascii_alphanumeric() || bytes[position] == b'_')
+            {
+                position += 1;
+            }
+            tokens.push((
+                Token::Name(source[start..position].to_string()),
+                start,
+                position,
+            ));
+            continue;
+        }
+        if byte == b'\'' || byte == b'"' {
+            let end = source[start + 1..]
+                .find(byte as char)
+                .ok_or_else(|| invalid(format!("unterminated string in `{source}`")))?;
+            position = start + 2 + end;
+            tokens.push((
+                Token::Text(source[start + 1..start + 1 + end].to_string()),
+                start,
+                position,
+            ));
+            continue;
+        }
+        let symbol = match (byte, bytes.get(position + 1)) {
+            (b'*', Some(b'*')) => "**",
+            (b'+', _) => "+",
+            (b'-', _) => "-",
+            (b'*', _) => "*",
+            (b'/', _) => "/",
+            (b'^', _) => "^",
+            (b':', _) => ":",
+            (b'(', _) => "(",
+            (b')', _) => ")",
+            (b',', _) => ",",
+            (b'=', _) => "=",
+            _ => {
+                return Err(invalid(format!(
+                    "unexpected character `{}` at position {start}",
+                    &source[start..].chars().next().unwrap_or_default()
+                )));
+            }
+        };
+        position += symbol.len();
+        tokens.push((Token::Symbol(symbol), start, position));
+    }
+    Ok(tokens)
+}
+
+struct FormulaParser<'a> {
+    source: &'a str,
+    tokens: &'a [(Token, usize, usize)],
+    position: usize,
+}
+
+impl FormulaParser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _, _)| token)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, symbol: &'static str) -> Result<()> {
+        match self.next() {
+            Some(Token::Symbol(found)) if found == symbol => Ok(()),
+            _ => Err(invalid(format!("expected `{symbol}` in `{}`", self.source))),
+        }
+    }
+
+    fn eat(&mut self, symbol: &'static str) -> bool {
+        if self.peek() == Some(&Token::Symbol(symbol)) {
+            self.position += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn start(&self) -> usize {
+        self.tokens
+            .get(self.position)
+            .map_or(self.source.len(), |(_, start, _)| *start)
+    }
+
+    fn end(&self) -> usize {
+        self.tokens[self.position - 1].2
+    }
+
+    fn formula(mut self) -> Result<ParsedFormula> {
+        let mut intercept = true;
+        let mut terms: Vec<Term> = Vec::new();
+        let mut subtract = self.eat("-");
+        loop {
+            if self.peek().is_none() {
+                break;
+            }
+            if let Some(Token::Number(value)) = self.peek()
+                && (*value == 0.0 || *value == 1.0)
+            {
+                let value = *value;
+                self.position += 1;
+                intercept = value == 1.0 && !subtract;
+            } else if subtract {
+                return Err(invalid(format!(
+                    "only the intercept can be removed in `{}`",
+                    self.source
+                )));
+            } else {
+                for term in self.expanded_term()? {
+                    if !terms.iter().any(|existing| existing.name() == term.name()) {
+                        terms.push(term);
+                    }
+                }
+            }
+            match self.next() {
+                None => break,
+                Some(Token::Symbol("+")) => subtract = false,
+                Some(Token::Symbol("-")) => subtract = true,
+                Some(_) => {
+                    return Err(invalid(format!(
+                        "expected `+` or `-` between terms in `{}`",
+                        self.source
+                    )));
+                }
+            }
+            if self.peek().is_none() {
+                return Err(invalid(format!("`{}` ends with an operator", self.source)));
+            }
+        }
+        Ok(ParsedFormula { intercept, terms })
+    }
+
+    /// `a*b*c` expands to every non-empty interaction of its parts, lower orders first.
+    fn expanded_term(&mut self) -> Result<Vec<Term>> {
+        let mut parts = vec![self.interaction()?];
+        while self.eat("*") {
+            parts.push(self.interaction()?);
+        }
+        let mut terms: Vec<Term> = Vec::new();
+        for mask in 1..(1usize << parts.len()) {
+            let factors = parts
+                .iter()
+                .enumerate()
+                .filter(|(index, _)| mask & (1 << index) != 0)
+                .flat_map(|(_, part)| part.factors.clone())
+                .collect();
+            terms.push(Term { factors });
+        }
+        terms.sort_by_key(|term| term.factors.len());
+        Ok(terms)
+    }
+
+    fn interaction(&mut self) -> Result<Term> {
+        let mut factors = vec![self.factor()?];
+        while self.eat(":") {
+            factors.push(self.factor()?);
+        }
+        Ok(Term { factors })
+    }
+
+    fn factor(&mut self) -> Result<Factor> {
+        let start = self.start();
+        let name = match self.next() {
+            Some(Token::Name(name)) => name,
+            _ => {
+                return Err(invalid(format!(
+                    "expected a column or function in `{}`",
+                    self.source
+                )));
+            }
+        };
+        if !self.eat("(") {
+            return Ok(Factor::Numeric {
+                expression: Expression::Column(name.clone()),
+                name,
+            });
+        }
+        let factor = match name.as_str() {
+            "C" => match self.next() {
+                Some(Token::Name(column)) => Factor::Categorical {
+                    column,
+                    reference: self.treatment()?,
+                },
+                _ => {
+                    return Err(invalid(format!(
+                        "C(...) takes a column name in `{}`",
+                        self.source
+                    )));
+                }
+            },
+            "I" => Factor::Numeric {
+                expression: self.sum()?,
+                name: String::new(),
+            },
+            other => {
+                let function = Function::from_name(other)
+                    .ok_or_else(|| invalid(format!("unknown function `{other}`")))?;
+                Factor::Numeric {
+                    expression: Expression::Call(function, Box::new(self.sum()?)),
+                    name: String::new(),
+                }
+            }
+        };
+        self.expect(")")?;
+        Ok(match factor {
+            Factor::Numeric { expression, .. } => Factor::Numeric {
+                expression,
+                name: self.source[start..self.end()].to_string(),
+            },
+            categorical => categorical,
+        })
+    }
+
+    /// The optional `, Treatment('level')` or `, Treatment(reference='level')` after a
+    /// categorical column.
+    fn treatment(&mut self) -> Result<Option<String>> {
+        if !self.eat(",") {
+            return Ok(None);
+        }
+        if self.next() != Some(Token::Name("Treatment".to_string())) {
+            return Err(invalid(format!(
+                "C(...) only accepts Treatment contrasts in `{}`",
+                self.source
+            )));
+        }
+        self.expect("(")?;
+        if self.peek() == Some(&Token::Name("reference".to_string())) {
+            self.position += 1;
+            self.expect("=")?;
+        }
+        let level = match self.next() {
+            Some(Token::Text(level)) => level,
+            Some(Token::Number(level)) => level.to_string(),
+            _ => {
+                return Err(invalid(format!(
+                    "Treatment(...) takes a reference level in `{}`",
+                    self.source
+                )));
+            }
+        };
+        self.expect(")")?;
+        Ok(Some(level))
+    }
+
+    fn sum(&mut self) -> Result<Expression> {
+        let mut expression = self.product()?;
+        loop {
+            let operator = if self.eat("+") {
+                '+'
+            } else if self.eat("-") {
+                '-'
+            } else {
+                return Ok(expression);
+            };
+            expression =
+                Expression::Binary(operator, Box::new(expression), Box::new(self.product()?));
+        }
+    }
+
+    fn product(&mut self) -> Result<Expression> {
+        let mut expression = self.unary()?;
+        loop {
+            let operator = if self.eat("*") {
+                '*'
+            } else if self.eat("/") {
+                '/'
+            } else {
+                return Ok(expression);
+            };
+            expression =
+                Expression::Binary(operator, Box::new(expression), Box::new(self.unary()?));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expression> {
+        if self.eat("-") {
+            return Ok(Expression::Negate(Box::new(self.unary()?)));
+        }
+        let base = self.atom()?;
+        if self.eat("**") || self.eat("^") {
+            return Ok(Expression::Binary(
+                '^',
+                Box::new(base),
+                Box::new(self.unary()?),
+            ));
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> Result<Expression> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Expression::Number(value)),
+            Some(Token::Name(name)) => {
+                if !self.eat("(") {
+                    return Ok(Expression::Column(name));
+                }
+                let function = Function::from_name(&name)
+                    .ok_or_else(|| invalid(format!("unknown function `{name}`")))?;
+                let argument = self.sum()?;
+                self.expect(")")?;
+                Ok(Expression::Call(function, Box::new(argument)))
+            }
+            Some(Token::Symbol("(")) => {
+                let expression = self.sum()?;
+                self.expect(")")?;
+                Ok(expression)
+            }
+            _ => Err(invalid(format!(
+                "incomplete expression in `{}`",
+                self.source
+            ))),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::Formulation;
+    use super::*;
+    use crate::data::ProductDataBuilder;
 
     #[test]
     fn stores_expression() {
         let f = Formulation::new("0 + prices + x1");
         assert_eq!(f.expression(), "0 + prices + x1");
     }
+
+    #[test]
+    fn builds_named_design_matrices() {
+        let data = FormulaData::new(4)
+            .with_numeric("prices", DVector::from_vec(vec![1.0, 2.0, 3.0, 4.0]))
+            .unwrap()
+            .with_numeric("hpwt", DVector::from_vec(vec![1.0, 1.0, 2.0, 4.0]))
+            .unwrap()
+            .with_categorical(
+                "region",
+                ["north", "south", "north", "west"]
+                    .map(String::from)
+                    .to_vec(),
+            )
+            .unwrap();
+
+        let design = Formulation::new("1 + prices + log(hpwt) + C(region) + I(prices ** 2)")
+            .design(&data)
+            .unwrap();
+        assert_eq!(
+            design.names,
+            [
+                "1",
+                "prices",
+                "log(hpwt)",
+                "C(region)[south]",
+                "C(region)[west]",
+                "I(prices ** 2)"
+            ]
+        );
+        assert_eq!(design.matrix.column(2)[3], 4.0_f64.ln());
+        assert_eq!(design.matrix.column(3).as_slice(), &[0.0, 1.0, 0.0, 0.0]);
+        assert_eq!(design.matrix.column(5)[2], 9.0);
+
+        let interacted = Formulation::new("0 + prices*hpwt + C(region)")
+            .design(&data)
+            .unwrap();
+        assert_eq!(interacted.names[..3], ["prices", "hpwt", "prices:hpwt"]);
+        assert_eq!(interacted.names.len(), 6);
+        assert_eq!(interacted.matrix[(3, 2)], 16.0);
+        assert_eq!(
+            Formulation::new("prices - 1").terms().unwrap(),
+            vec!["prices".to_string()]
+        );
+
+        for bad in [
+            "prices +",
+            "log(",
+            "region",
+            "C(missing)",
+            "log(prices - 2)",
+        ] {
+            let err = Formulation::new(bad).design(&data).unwrap_err();
+            assert!(matches!(err, BlpError::InvalidFormula { .. }), "{bad}");
+        }
+    }
+
+    #[test]
+    fn categorical_reference_levels_carry_through_to_labels() {
+        let region = ["north", "south", "north", "west"]
+            .map(String::from)
+            .to_vec();
+        let data = FormulaData::new(4)
+            .with_numeric("prices", DVector::from_vec(vec![1.0, 2.0, 3.0, 4.0]))
+            .unwrap()
+            .with_categorical("region", region)
+            .unwrap();
+
+        let explicit = Formulation::new("1 + prices + C(region, Treatment('south'))")
+            .design(&data)
+            .unwrap();
+        assert_eq!(
+            explicit.names,
+            ["1", "prices", "C(region)[north]", "C(region)[west]"]
+        );
+        let defaulted = Formulation::new("1 + prices + C(region)")
+            .design(&data.clone().with_reference("region", "south"))
+            .unwrap();
+        assert_eq!(defaulted, explicit);
+        let keyword = Formulation::new("0 + C(region, Treatment(reference=\"west\"))")
+            .design(&data)
+            .unwrap();
+        assert_eq!(keyword.names.len(), 3);
+        assert!(
+            Formulation::new("1 + C(region, Treatment('east'))")
+                .design(&data)
+                .is_err()
+        );
+
+        let product_data =
+            ProductDataBuilder::new(vec!["m".to_string(); 4], DVector::from_element(4, 0.2))
+                .x1_design(explicit)
+                .build()
+                .unwrap();
+        assert_eq!(product_data.labels().x1[3], "C(region)[west]");
+    }
 }
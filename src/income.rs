@@ -0,0 +1,315 @@
+//! Experimental: price and income in the Berry, Levinsohn, and Pakes (1995) form.
+//!
+//! Instead of a linear price coefficient, consumer `i` with income `y_i` gets utility
+//! `alpha ln(y_i - p_j)` from product `j` and `alpha ln(y_i)` from the outside good, so
+//! price sensitivity falls with income. Only the difference matters, so the model adds
+//! `alpha ln(1 - p_j / y_i)` to each consumer's utility; products priced at or above a
+//! consumer's income are out of their reach. [`IncomeForm::Ratio`] uses the simpler
+//! `-alpha p_j / y_i` instead. Incomes are a column of the draws' demographics.
+//!
+//! Prices enter only through this term, so they should not also be columns of `X1` or
+//! `X2`, and `alpha` is a nonlinear parameter: [`Problem::estimate_income_price`]
+//! searches over it together with `sigma`. Post-estimation tools that recompute
+//! individual choice probabilities themselves (elasticities, diversion ratios, micro
+//! data) do not yet include the income term.
+
+use std::ops::Range;
+
+use nalgebra::{DMatrix, DVector};
+
+use crate::demand::ShareInputs;
+use crate::error::{BlpError, Result};
+use crate::estimation::{Problem, ProblemResults};
+use crate::models::{DemandModel, RandomCoefficientsLogit};
+use crate::optimization::{
+    initial_sigma, nelder_mead, search_coordinate, searched_entries, sigma_entry, starting_sigma,
+};
+use crate::parameters::NonlinearParameters;
+use crate::solving::ContractionSummary;
+
+/// How price and income enter utility under [`IncomePriceLogit`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IncomeForm {
+    /// `alpha ln(y_i - p_j)`, relative to `alpha ln(y_i)` for the outside good.
+    #[default]
+    LogDifference,
+    /// `-alpha p_j / y_i`.
+    Ratio,
+}
+
+/// Random coefficients logit with the BLP (1995) income-price term.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IncomePriceLogit {
+    alpha: f64,
+    prices: DVector<f64>,
+    income_column: usize,
+    form: IncomeForm,
+}
+
+impl IncomePriceLogit {
+    /// Price coefficient `alpha` on the product `prices`, with incomes in column
+    /// `income_column` of the draws' demographics.
+    pub fn new(alpha: f64, prices: DVector<f64>, income_column: usize) -> Self {
+        Self {
+            alpha,
+            prices,
+            income_column,
+            form: IncomeForm::default(),
+        }
+    }
+
+    /// Use `form` for the income-price term.
+    pub fn with_form(mut self, form: IncomeForm) -> Self {
+        self.form = form;
+        self
+    }
+
+    /// The same model with price coefficient `alpha`.
+    pub fn with_alpha(&self, alpha: f64) -> Self {
+        Self {
+            alpha,
+            ..self.clone()
+        }
+    }
+
+    /// Price coefficient.
+    pub fn alpha(&self) -> f64 {
+        self.alpha
+    }
+
+    /// Product prices.
+    pub fn prices(&self) -> &DVector<f64> {
+        &self.prices
+    }
+
+    /// Form of the income-price term.
+    pub fn form(&self) -> IncomeForm {
+        self.form
+    }
+}
+
+impl DemandModel for IncomePriceLogit {
+    fn name(&self) -> &'static str {
+        "income-price logit"
+    }
+
+    fn shares(&self, delta: &DVector<f64>, inputs: &ShareInputs<'_>) -> Result<DVector<f64>> {
+        let offsets = self.price_utilities(inputs)?;
+        RandomCoefficientsLogit.shares(delta, &inputs.with_offsets(&offsets))
+    }
+
+    fn jacobian(
+        &self,
+        delta: &DVector<f64>,
+        inputs: &ShareInputs<'_>,
+    ) -> Result<Vec<DMatrix<f64>>> {
+        let offsets = self.price_utilities(inputs)?;
+        RandomCoefficientsLogit.jacobian(delta, &inputs.with_offsets(&offsets))
+    }
+
+    fn offsets(&self, inputs: &ShareInputs<'_>) -> Result<Option<DMatrix<f64>>> {
+        self.price_utilities(inputs).map(Some)
+    }
+
+    fn select_products(&self, rows: Range<usize>) -> Option<Box<dyn DemandModel>> {
+        Some(Box::new(Self {
+            prices: self.prices.rows(rows.start, rows.len()).into_owned(),
+            ..self.clone()
+        }))
+    }
+
+    fn invert(&self, inputs: &ShareInputs<'_>) -> Result<(DVector<f64>, ContractionSummary)> {
+        let offsets = self.price_utilities(inputs)?;
+        RandomCoefficientsLogit.invert(&inputs.with_offsets(&offsets))
+    }
+}
+
+impl IncomePriceLogit {
+    /// The income-price term of every product (rows) for every consumer (columns).
+    fn price_utilities(&self, inputs: &ShareInputs<'_>) -> Result<DMatrix<f64>> {
+        let n = inputs.data().product_count();
+        if self.prices.len() != n {
+            return Err(BlpError::dimension_mismatch(
+                "price length",
+                n,
+                self.prices.len(),
+            ));
+        }
+        let demographics = inputs
+            .draws()
+            .demographics()
+            .ok_or_else(|| BlpError::missing_component("demographics"))?;
+        if self.income_column >= demographics.ncols() {
+            return Err(BlpError::dimension_mismatch(
+                "income column",
+                demographics.ncols(),
+                self.income_column + 1,
+            ));
+        }
+        let incomes = demographics.column(self.income_column);
+        if incomes
+            .iter()
+            .any(|&income| income.is_nan() || income <= 0.0)
+        {
+            return Err(BlpError::NumericalError {
+                context: "non-positive income",
+            });
+        }
+        Ok(DMatrix::from_fn(n, incomes.len(), |j, r| {
+            let ratio = self.prices[j] / incomes[r];
+            match self.form {
+                IncomeForm::LogDifference if ratio >= 1.0 => f64::NEG_INFINITY,
+                IncomeForm::LogDifference => self.alpha * (-ratio).ln_1p(),
+                IncomeForm::Ratio => -self.alpha * ratio,
+            }
+        }))
+    }
+}
+
+/// Outcome of [`Problem::estimate_income_price`].
+#[derive(Clone, Debug)]
+pub struct IncomePriceEstimate {
+    /// Model at the estimated `alpha`.
+    pub model: IncomePriceLogit,
+    /// Solution at the estimated `alpha` and `sigma`.
+    pub results: ProblemResults,
+    /// Number of optimizer iterations.
+    pub iterations: usize,
+    /// Number of objective evaluations, including failed ones.
+    pub evaluations: usize,
+    /// Whether the termination tolerances were met.
+    pub converged: bool,
+}
+
+impl Problem {
+    /// Estimate `alpha` from `initial.alpha()`, together with the nonzero entries of the
+    /// starting `sigma` in [`ProblemOptions::optimization`](crate::ProblemOptions::optimization)
+    /// when the problem has random coefficients, by minimizing the GMM objective under
+    /// `initial`'s income-price term.
+    pub fn estimate_income_price(&self, initial: &IncomePriceLogit) -> Result<IncomePriceEstimate> {
+        if let Some(problem) = self.recording_run()? {
+            return problem.estimate_income_price(initial);
+        }
+        let options = &self.options().optimization;
+        let start = initial_sigma(options, self.data().nonlinear_dim())?;
+        let start = starting_sigma(start, options);
+        let entries = searched_entries(&start, options);
+        let candidate = |point: &[f64]| {
+            let mut sigma = start.clone();
+            for (&entry, value) in entries.iter().zip(&point[1..]) {
+                sigma[entry] = sigma_entry(entry, *value, options);
+            }
+            if let Some(bounds) = &options.bounds {
+                bounds.clamp(&mut sigma);
+            }
+            (
+                initial.with_alpha(point[0]),
+                NonlinearParameters::new(sigma),
+            )
+        };
+        let searching = self.deferring_conditioning();
+        let solve = |point: &[f64]| {
+            let (model, parameters) = candidate(point);
+            let results = searching
+                .clone()
+                .with_model(model.clone())
+                .solve(&parameters)?;
+            Ok::<_, BlpError>((model, results))
+        };
+
+        let mut origin = vec![initial.alpha()];
+        origin.extend(
+            entries
+                .iter()
+                .map(|&entry| search_coordinate(entry, start[entry], options)),
+        );
+        let mut best: Option<(IncomePriceLogit, ProblemResults)> = None;
+        let search = nelder_mead(
+            origin,
+            options,
+            |_| {},
+            |point| match solve(point) {
+                Ok((model, results)) => {
+                    let value = results.gmm_value;
+                    if best.as_ref().is_none_or(|(_, best)| value < best.gmm_value) {
+                        best = Some((model, results));
+                    }
+                    value
+                }
+                Err(err) => {
+                    log::debug!("infeasible income-price candidate: {err}");
+                    f64::INFINITY
+                }
+            },
+        );
+        let (model, mut results) = match best {
+            Some(best) => best,
+            None => solve(&search.point)?,
+        };
+        self.complete_conditioning(&mut results);
+        Ok(IncomePriceEstimate {
+            model,
+            results,
+            iterations: search.iterations,
+            evaluations: search.evaluations,
+            converged: search.converged,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OptimizationOptions;
+    use crate::integration::SimulationDraws;
+    use crate::options::ProblemOptions;
+    use crate::testing::TestMarkets;
+
+    #[test]
+    fn income_price_term_is_estimated() {
+        let markets = TestMarkets::new(24);
+        let (x, w) = (markets.x.clone(), markets.w.clone());
+        let prices = DVector::from_fn(24, |j, _| 2.0 + 0.8 * w[j] + 0.3 * x[j]);
+        let markets = markets
+            .with_x1(DMatrix::from_fn(24, 2, |j, k| [1.0, x[j]][k]), vec![1])
+            .with_instruments(DMatrix::from_fn(24, 5, |j, k| {
+                [1.0, x[j], w[j], w[j] * w[j], x[j] * w[j]][k]
+            }));
+        let incomes = DMatrix::from_fn(10, 1, |r, _| 4.0 + r as f64);
+        let draws = SimulationDraws::standard_normal(10, 1, 7)
+            .with_demographics(incomes)
+            .unwrap();
+        let truth = IncomePriceLogit::new(3.0, prices.clone(), 0);
+        let sigma = DMatrix::from_element(1, 1, 0.5);
+        let delta = DVector::from_fn(24, |j, _| 1.0 + x[j]);
+        let data = markets.simulate_with(&draws, &sigma.clone().into(), |inputs| {
+            let shares = truth.shares(&delta, inputs)?;
+            let ratio = truth.clone().with_form(IncomeForm::Ratio);
+            assert!((ratio.shares(&delta, inputs)? - &shares).amax() > 1e-4);
+            Ok(shares)
+        });
+
+        let options = ProblemOptions::default().with_optimization(
+            OptimizationOptions::new(DMatrix::from_element(1, 1, 0.8)).with_tolerances(1e-10, 1e-3),
+        );
+        let problem = Problem::with_options(data, draws, options).unwrap();
+        let exact = problem
+            .clone()
+            .with_model(truth.clone())
+            .solve(&sigma.clone().into())
+            .unwrap();
+        assert!(exact.gmm_value < 1e-16);
+
+        let estimate = problem
+            .estimate_income_price(&truth.with_alpha(2.0))
+            .unwrap();
+        let alpha = estimate.model.alpha();
+        assert!((alpha - 3.0).abs() < 0.05, "alpha {alpha}");
+        assert!((estimate.results.sigma[(0, 0)] - 0.5).abs() < 0.05);
+        assert!(
+            problem
+                .estimate_income_price(&IncomePriceLogit::new(2.0, prices.rows(0, 3).into(), 0))
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,460 @@
+//! Inference on the estimated parameters: the GMM sandwich covariance and
+//! weak-identification-robust confidence sets for `sigma`.
+
+use nalgebra::DMatrix;
+
+use crate::demand::ShareInputs;
+use crate::error::{BlpError, Result};
+use crate::estimation::{Problem, ProblemResults, moment_covariance};
+use crate::linalg::Factorizations;
+use crate::optimization::{is_held_fixed, searched_pi_entries};
+use crate::options::WeightingMatrix;
+use crate::parallel;
+use crate::statistics::chi_squared_sf;
+
+/// Anderson–Rubin-type test of one candidate `sigma`.
+#[derive(Clone, Debug)]
+pub struct RobustTest {
+    /// Candidate nonlinear parameters.
+    pub sigma: DMatrix<f64>,
+    /// `xi' P_Z xi / (xi' xi / N)` with `beta` concentrated out.
+    pub statistic: f64,
+    /// Upper-tail probability under the chi-squared reference distribution.
+    pub p_value: f64,
+}
+
+/// Identification-robust confidence set for `sigma`, obtained by test inversion.
+#[derive(Clone, Debug)]
+pub struct RobustConfidenceSet {
+    /// Every candidate that was tested, in grid order.
+    pub tests: Vec<RobustTest>,
+    /// Coverage level, e.g. `0.95`.
+    pub level: f64,
+    /// Instruments minus estimated linear parameters.
+    pub degrees_of_freedom: usize,
+}
+
+impl RobustConfidenceSet {
+    /// Candidates not rejected at `1 - level`.
+    pub fn accepted(&self) -> impl Iterator<Item = &RobustTest> {
+        self.tests
+            .iter()
+            .filter(move |test| test.p_value >= 1.0 - self.level)
+    }
+
+    /// Element-wise smallest and largest accepted `sigma` (the projection of the set onto
+    /// each parameter), or `None` when every candidate was rejected.
+    pub fn bounds(&self) -> Option<(DMatrix<f64>, DMatrix<f64>)> {
+        let mut accepted = self.accepted();
+        let first = accepted.next()?;
+        let (mut lower, mut upper) = (first.sigma.clone(), first.sigma.clone());
+        for test in accepted {
+            lower = lower.inf(&test.sigma);
+            upper = upper.sup(&test.sigma);
+        }
+        Some((lower, upper))
+    }
+}
+
+/// Diagonal `sigma` matrices on the Cartesian grid of `points` evenly spaced values
+/// between each `(lower, upper)` pair, for use with
+/// [`robust_confidence_set`](Problem::robust_confidence_set).
+pub fn diagonal_sigma_grid(bounds: &[(f64, f64)], points: usize) -> Vec<DMatrix<f64>> {
+    let axis = |&(lower, upper): &(f64, f64)| -> Vec<f64> {
+        match points {
+            0 => Vec::new(),
+            1 => vec![0.5 * (lower + upper)],
+            _ => (0..points)
+                .map(|i| lower + (upper - lower) * i as f64 / (points - 1) as f64)
+                .collect(),
+        }
+    };
+    let mut grid = vec![Vec::new()];
+    for range in bounds {
+        let values = axis(range);
+        grid = grid
+            .iter()
+            .flat_map(|prefix| {
+                values.iter().map(move |value| {
+                    let mut next = prefix.clone();
+                    next.push(*value);
+                    next
+                })
+            })
+            .collect();
+    }
+    grid.into_iter()
+        .map(|diagonal| DMatrix::from_diagonal(&diagonal.into()))
+        .collect()
+}
+
+impl Problem {
+    /// Weak-identification-robust confidence set for `sigma` by inverting an
+    /// Anderson–Rubin-type test over the candidates in `grid`.
+    ///
+    /// At each candidate the contraction is solved, `beta` is concentrated out by 2SLS,
+    /// and `xi' Z (Z'Z)^{-1} Z' xi` is scaled by the residual variance. Under the null the
+    /// statistic is asymptotically chi-squared with `K_Z - K_1` degrees of freedom
+    /// (`K_Z` when `beta` is held at [`GmmOptions::fixed_beta`](crate::GmmOptions::fixed_beta))
+    /// whether or not the instruments are strong for `sigma`, assuming homoskedastic
+    /// `xi`. Candidates are evaluated in parallel.
+    ///
+    /// The statistic covers only the demand moments at the candidate `sigma`, so a supply
+    /// side, macro moments, or a configured starting `pi` or `rho` are refused with
+    /// [`BlpError::InconsistentSpecification`].
+    pub fn robust_confidence_set(
+        &self,
+        grid: &[DMatrix<f64>],
+        level: f64,
+    ) -> Result<RobustConfidenceSet> {
+        let mut mismatches = Vec::new();
+        if self.supply().is_some() {
+            mismatches.push(
+                "the Anderson–Rubin test covers only demand, but a supply side is attached".into(),
+            );
+        }
+        if !self.macro_moments().is_empty() {
+            mismatches.push(
+                "the Anderson–Rubin test covers only demand, but macro moments are attached".into(),
+            );
+        }
+        let optimization = &self.options().optimization;
+        if optimization.initial_pi.is_some() {
+            mismatches.push("the Anderson–Rubin grid holds sigma only, but pi is estimated".into());
+        }
+        if optimization.initial_rho.is_some() {
+            mismatches
+                .push("the Anderson–Rubin grid holds sigma only, but rho is estimated".into());
+        }
+        if !mismatches.is_empty() {
+            return Err(BlpError::InconsistentSpecification { mismatches });
+        }
+        let data = self.data();
+        let instruments = data.instrument_dim();
+        let parameters = if self.options().gmm.fixed_beta.is_some() {
+            0
+        } else {
+            data.linear_dim()
+        };
+        if instruments <= parameters {
+            return Err(BlpError::Underidentified {
+                instruments,
+                parameters,
+            });
+        }
+        let degrees_of_freedom = instruments - parameters;
+        let mut options = self.options().clone();
+        options.gmm.weighting = WeightingMatrix::InverseZTZ;
+        options.progress = None;
+
+        let tests = parallel::run_nested(&options.parallelism, grid.len(), |index| {
+            let sigma = &grid[index];
+            let results = self.solve_with_options(&sigma.clone().into(), &options)?;
+            let variance = results.xi.norm_squared() / data.product_count() as f64;
+            let statistic = results.gmm_value / variance;
+            Ok(RobustTest {
+                sigma: sigma.clone(),
+                statistic,
+                p_value: chi_squared_sf(statistic, degrees_of_freedom),
+            })
+        })?;
+
+        Ok(RobustConfidenceSet {
+            tests,
+            level,
+            degrees_of_freedom,
+        })
+    }
+}
+
+impl Problem {
+    /// Heteroskedasticity-robust GMM covariance of `theta = [beta; vec(sigma); vec(pi)]`
+    /// at `results`, with zero rows and columns for the entries held fixed. `vec(pi)`
+    /// is left out when `results` has no `pi`.
+    ///
+    /// With moments `g = Z' xi / N`, their Jacobian `G` with respect to the estimated
+    /// parameters (`beta` unless it was fixed, and the nonzero entries of `sigma` and
+    /// `pi` that neither the bounds nor the mask hold), the
+    /// weighting matrix `W` in `results`, and `S = sum_j z_j z_j' xi_j^2 / N`, the
+    /// covariance is `(G'WG)^{-1} G'W S W G (G'WG)^{-1} / N`, which reduces to
+    /// `(G' S^{-1} G)^{-1} / N` under the efficient weighting matrix. Under
+    /// [`MomentCovariance::Clustered`](crate::MomentCovariance::Clustered), `S` sums the
+    /// contributions within each cluster before taking outer products. Only the demand
+    /// moments enter, so a supply side or macro moments are refused, as is `rho`. A
+    /// singular `G'WG` is pseudo-inverted unless
+    /// [`GmmOptions::strict_factorizations`](crate::GmmOptions::strict_factorizations) was
+    /// set.
+    pub fn parameter_covariance(&self, results: &ProblemResults) -> Result<DMatrix<f64>> {
+        if results.rho.is_some() {
+            return Err(BlpError::Unsupported {
+                operation: "standard errors with a nesting parameter",
+            });
+        }
+        if self.supply().is_some() {
+            return Err(BlpError::Unsupported {
+                operation: "standard errors with a supply side",
+            });
+        }
+        if !self.macro_moments().is_empty() {
+            return Err(BlpError::Unsupported {
+                operation: "standard errors with macro moments",
+            });
+        }
+        let data = self.data();
+        let n = data.product_count() as f64;
+        let k1 = data.linear_dim();
+        let k2 = data.nonlinear_dim();
+        let demographics = results.pi.as_ref().map_or(0, DMatrix::ncols);
+        let p = k1 + k2 * k2 + k2 * demographics;
+        let optimization = &results.options_used.optimization;
+        let fixed_beta = results.options_used.gmm.fixed_beta.is_some();
+        let sigma = results.sigma.as_slice();
+        let pi_entries = searched_pi_entries(results.pi.as_ref(), optimization);
+        let parameters: Vec<usize> = (0..p)
+            .filter(|&index| {
+                if index < k1 {
+                    !fixed_beta
+                } else if index < k1 + k2 * k2 {
+                    let entry = ((index - k1) % k2, (index - k1) / k2);
+                    sigma[index - k1] != 0.0 && !is_held_fixed(entry, optimization)
+                } else {
+                    let offset = index - k1 - k2 * k2;
+                    pi_entries.contains(&(offset % k2, offset / k2))
+                }
+            })
+            .collect();
+        let z = data.instruments();
+        if parameters.len() > z.ncols() {
+            return Err(BlpError::Underidentified {
+                instruments: z.ncols(),
+                parameters: parameters.len(),
+            });
+        }
+
+        let nonlinear = results.parameters();
+        let inputs = ShareInputs::for_parameters(
+            data,
+            self.draws(),
+            &nonlinear,
+            &self.options().contraction,
+        );
+        let delta_jacobian = self.delta_jacobian(&results.delta, &inputs)?;
+        let z_t = z.transpose();
+        let mut jacobian = DMatrix::zeros(z.ncols(), p);
+        jacobian
+            .columns_mut(0, k1)
+            .copy_from(&(&z_t * data.x1() / -n));
+        jacobian
+            .columns_mut(k1, k2 * k2)
+            .copy_from(&(&z_t * delta_jacobian / n));
+        if demographics > 0 {
+            let pi_jacobian = self.delta_pi_jacobian(&results.delta, &inputs)?;
+            if pi_jacobian.ncols() != k2 * demographics {
+                return Err(BlpError::dimension_mismatch(
+                    "demographics for pi",
+                    demographics,
+                    pi_jacobian.ncols() / k2.max(1),
+                ));
+            }
+            jacobian
+                .columns_mut(k1 + k2 * k2, k2 * demographics)
+                .copy_from(&(&z_t * pi_jacobian / n));
+        }
+        let jacobian = jacobian.select_columns(&parameters);
+
+        let moment_covariance = moment_covariance(
+            data,
+            &results.xi,
+            results.options_used.gmm.moment_covariance,
+        )? / n;
+        let weighting = &results.weighting_matrix;
+        let hessian = jacobian.transpose() * weighting * &jacobian;
+        let bread = Factorizations::new(results.options_used.gmm.strict_factorizations)
+            .inverse(&hessian, "G'WG")?;
+        let projected = weighting * &jacobian;
+        let meat = projected.transpose() * moment_covariance * &projected;
+        let estimated = &bread * meat * &bread / n;
+
+        let mut covariance = DMatrix::zeros(p, p);
+        for (a, &row) in parameters.iter().enumerate() {
+            for (b, &column) in parameters.iter().enumerate() {
+                covariance[(row, column)] = estimated[(a, b)];
+            }
+        }
+        Ok(covariance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::DVector;
+
+    use super::*;
+    use crate::data::ProductDataBuilder;
+    use crate::integration::SimulationDraws;
+    use crate::macro_moments::{AgentGroup, MacroMoment, MacroStatistic};
+    use crate::parameters::NonlinearParameters;
+    use crate::testing::TestMarkets;
+
+    #[test]
+    fn confidence_set_covers_the_true_sigma() {
+        let markets = TestMarkets::new(60);
+        let (x, xi) = (markets.x.clone(), markets.xi(0.1));
+        let rival = DVector::from_fn(60, |j, _| {
+            let start = j / 3 * 3;
+            (start..start + 3).map(|k| x[k]).sum::<f64>() - x[j]
+        });
+        let markets = markets
+            .with_x1(DMatrix::from_fn(60, 2, |j, k| [1.0, x[j]][k]), vec![1])
+            .with_instruments(DMatrix::from_fn(60, 4, |j, k| {
+                [1.0, x[j], x[j] * x[j], rival[j]][k]
+            }));
+        let draws = SimulationDraws::standard_normal(100, 1, 11);
+        let true_sigma = DMatrix::from_element(1, 1, 1.5);
+        let delta = DVector::from_fn(60, |j, _| -1.0 + x[j] + xi[j]);
+        let data = markets.simulate(
+            &draws,
+            &NonlinearParameters::new(true_sigma.clone()),
+            &delta,
+        );
+        let problem = Problem::new(data, draws).unwrap();
+
+        let grid = diagonal_sigma_grid(&[(0.0, 6.0)], 5);
+        let set = problem.robust_confidence_set(&grid, 0.95).unwrap();
+        assert_eq!(set.degrees_of_freedom, 2);
+        let truth = set
+            .tests
+            .iter()
+            .find(|test| test.sigma == true_sigma)
+            .unwrap();
+        assert!(truth.p_value > 0.05);
+        let (lower, upper) = set.bounds().unwrap();
+        assert!(lower[(0, 0)] <= 1.5 && upper[(0, 0)] >= 1.5);
+        assert!(set.accepted().count() < grid.len());
+
+        // Holding beta fixed leaves every instrument's restriction to test.
+        let fixed = problem
+            .options()
+            .clone()
+            .with_fixed_beta(DVector::from_vec(vec![-1.0, 1.0]));
+        let fixed = problem.with_options_override(fixed);
+        let set = fixed.robust_confidence_set(&grid[..1], 0.95).unwrap();
+        assert_eq!(set.degrees_of_freedom, 4);
+
+        // Moments or parameters outside the demand grid are refused.
+        let inside = MacroMoment::new(
+            "inside",
+            MacroStatistic::InsideShare(AgentGroup::All),
+            0.5,
+            0.01,
+        );
+        let with_macro = problem.clone().with_macro_moments(vec![inside]).unwrap();
+        let with_pi = problem.with_options_override(
+            problem.options().clone().with_optimization(
+                crate::options::OptimizationOptions::new(DMatrix::from_element(1, 1, 1.0))
+                    .with_pi(DMatrix::zeros(1, 1)),
+            ),
+        );
+        for refused in [with_macro, with_pi] {
+            assert!(matches!(
+                refused.robust_confidence_set(&grid, 0.95),
+                Err(BlpError::InconsistentSpecification { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn sandwich_covariance_matches_robust_2sls_and_covers_sigma() {
+        use crate::options::{OptimizationOptions, ProblemOptions};
+
+        let n = 60;
+        let markets = TestMarkets::new(n);
+        let (x, w, xi) = (markets.x.clone(), markets.w.clone(), markets.xi(0.2));
+        let x1 = DMatrix::from_fn(n, 2, |j, k| [1.0, x[j]][k]);
+        let z = DMatrix::from_fn(n, 4, |j, k| [1.0, x[j], w[j], x[j] * w[j]][k]);
+        let markets = markets
+            .with_x1(x1.clone(), vec![1])
+            .with_instruments(z.clone());
+        let delta = DVector::from_fn(n, |j, _| -1.0 + x[j] + xi[j]);
+        let draws = SimulationDraws::standard_normal(30, 1, 3);
+        let truth = NonlinearParameters::new(DMatrix::from_element(1, 1, 0.8));
+        let data = markets.simulate(&draws, &truth, &delta);
+
+        // With sigma fixed at zero, the sandwich is the robust 2SLS covariance of beta.
+        let problem = Problem::new(data.clone(), draws.clone()).unwrap();
+        let logit = problem.solve(&DMatrix::zeros(1, 1).into()).unwrap();
+        let covariance = problem.parameter_covariance(&logit).unwrap();
+        let ztz_inverse = (z.transpose() * &z).try_inverse().unwrap();
+        let xz = x1.transpose() * &z;
+        let a_inverse = (&xz * &ztz_inverse * xz.transpose()).try_inverse().unwrap();
+        let scores = DMatrix::from_fn(n, 4, |j, k| z[(j, k)] * logit.xi[j]);
+        let middle =
+            &xz * &ztz_inverse * scores.transpose() * &scores * &ztz_inverse * xz.transpose();
+        let expected = &a_inverse * middle * &a_inverse;
+        approx::assert_relative_eq!(
+            covariance.view((0, 0), (2, 2)).into_owned(),
+            expected,
+            max_relative = 1e-8
+        );
+        assert_eq!(covariance[(2, 2)], 0.0);
+
+        let options = ProblemOptions::default().with_optimization(
+            OptimizationOptions::new(DMatrix::from_element(1, 1, 1.2)).with_tolerances(1e-10, 1e-3),
+        );
+        let problem = Problem::with_options(data, draws, options).unwrap();
+        let estimate = problem.optimize().unwrap().results;
+        let beta_se = estimate.beta_se().unwrap();
+        let sigma_se = estimate.sigma_se().unwrap();
+        assert!(beta_se.iter().all(|se| se.is_finite() && *se > 0.0));
+        let sigma = estimate.sigma[(0, 0)];
+        assert!(sigma_se[(0, 0)] > 0.0);
+        assert!(
+            (sigma - 0.8).abs() < 3.0 * sigma_se[(0, 0)],
+            "sigma {sigma} se {sigma_se}"
+        );
+    }
+
+    #[test]
+    fn clustered_covariance_sums_within_clusters() {
+        use crate::options::{MomentCovariance, ProblemOptions};
+
+        let n = 30;
+        let x = DVector::from_fn(n, |j, _| ((j * 7919) % 97) as f64 / 48.5 - 1.0);
+        let w = DVector::from_fn(n, |j, _| ((j * 31) % 11) as f64 / 5.5 - 1.0);
+        let shares = DVector::from_fn(n, |j, _| 0.1 + 0.05 * x[j] + 0.02 * w[j]);
+        let build = |clusters: &dyn Fn(usize) -> String| {
+            ProductDataBuilder::new(
+                (0..n).map(|j| format!("m{}", j / 3)).collect(),
+                shares.clone(),
+            )
+            .x1(DMatrix::from_fn(n, 2, |j, k| [1.0, x[j]][k]))
+            .instruments(DMatrix::from_fn(n, 3, |j, k| [1.0, x[j], w[j]][k]))
+            .clustering_ids((0..n).map(clusters).collect())
+            .build()
+            .unwrap()
+        };
+        let draws = SimulationDraws::standard_normal(1, 0, 1);
+        let clustered =
+            ProblemOptions::default().with_moment_covariance(MomentCovariance::Clustered);
+        let standard_error = |data, options: ProblemOptions| {
+            let problem = Problem::with_options(data, draws.clone(), options).unwrap();
+            let mut results = problem.solve(&Default::default()).unwrap();
+            results.covariance = Some(problem.parameter_covariance(&results).unwrap());
+            results.beta_se().unwrap()
+        };
+
+        // Singleton clusters reproduce the heteroskedasticity-robust errors.
+        let robust = standard_error(build(&|j| j.to_string()), ProblemOptions::default());
+        let singletons = standard_error(build(&|j| j.to_string()), clustered.clone());
+        approx::assert_relative_eq!(robust, singletons, max_relative = 1e-10);
+        let markets = standard_error(build(&|j| format!("m{}", j / 3)), clustered.clone());
+        assert!((&markets - &robust).amax() > 1e-6);
+
+        let unclustered =
+            ProductDataBuilder::new(vec!["m".into(); 2], DVector::from_vec(vec![0.2, 0.3]))
+                .x1(DMatrix::from_element(2, 1, 1.0))
+                .build()
+                .unwrap();
+        assert!(Problem::with_options(unclustered, draws, clustered).is_err());
+    }
+}
@@ -0,0 +1,283 @@
+//! Ingestion of long-format (tidy) product data.
+//!
+//! Many datasets arrive as one record per `(market, product, variable)` cell rather
+//! than as wide numeric matrices. [`LongTable`] collects such records, validates them
+//! (no duplicate or missing cells), and pivots them into a [`WideTable`] with named
+//! columns from which [`ProductData`] can be assembled by column name.
+//!
+//! Pivoting groups rows by market in order of first appearance, so long-format input
+//! does not need to be sorted into contiguous market blocks beforehand.
+
+use std::collections::{HashMap, HashSet};
+
+use nalgebra::{DMatrix, DVector};
+
+use crate::data::{ProductData, ProductDataBuilder};
+use crate::error::{BlpError, Result};
+
+/// A single `(market, product, variable, value)` observation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LongRecord {
+    /// Market identifier.
+    pub market_id: String,
+    /// Product identifier, unique within its market.
+    pub product_id: String,
+    /// Name of the variable (e.g. `"shares"`, `"prices"`).
+    pub variable: String,
+    /// Observed value.
+    pub value: f64,
+}
+
+/// Collection of long-format records awaiting validation and pivoting.
+#[derive(Clone, Debug, Default)]
+pub struct LongTable {
+    records: Vec<LongRecord>,
+}
+
+impl LongTable {
+    /// Create an empty table.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append one observation.
+    pub fn push<M, P, V>(&mut self, market_id: M, product_id: P, variable: V, value: f64)
+    where
+        M: Into<String>,
+        P: Into<String>,
+        V: Into<String>,
+    {
+        self.records.push(LongRecord {
+            market_id: market_id.into(),
+            product_id: product_id.into(),
+            variable: variable.into(),
+            value,
+        });
+    }
+
+    /// Number of records collected so far.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Whether no records have been collected.
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Validate the records and pivot them to one row per product.
+    ///
+    /// Rows are ordered by market (first appearance) and then by product (first
+    /// appearance within the market); columns follow the first appearance of each
+    /// variable. Duplicate cells and cells missing from the rectangular layout are
+    /// reported as [`BlpError::InvalidLongFormat`].
+    pub fn pivot(&self) -> Result<WideTable> {
+        self.pivot_impl(false)
+    }
+
+    /// Like [`LongTable::pivot`], but cells absent from the input are left as `NaN` so
+    /// that the builder's [`Imputation`](crate::data::Imputation) rule can fill them.
+    pub fn pivot_allowing_missing(&self) -> Result<WideTable> {
+        self.pivot_impl(true)
+    }
+
+    fn pivot_impl(&self, allow_missing: bool) -> Result<WideTable> {
+        let mut market_order: Vec<&str> = Vec::new();
+        let mut products: HashMap<&str, Vec<&str>> = HashMap::new();
+        let mut seen: HashSet<(&str, &str)> = HashSet::new();
+        let mut variables: Vec<&str> = Vec::new();
+        let mut variable_index: HashMap<&str, usize> = HashMap::new();
+
+        for record in &self.records {
+            let market = record.market_id.as_str();
+            let entry = products.entry(market).or_insert_with(|| {
+                market_order.push(market);
+                Vec::new()
+            });
+            if seen.insert((market, &record.product_id)) {
+                entry.push(&record.product_id);
+            }
+            if !variable_index.contains_key(record.variable.as_str()) {
+                variable_index.insert(&record.variable, variables.len());
+                variables.push(&record.variable);
+            }
+        }
+
+        let mut row_index: HashMap<(&str, &str), usize> = HashMap::new();
+        let mut market_ids = Vec::new();
+        let mut product_ids = Vec::new();
+        for market in &market_order {
+            for product in &products[market] {
+                row_index.insert((market, product), market_ids.len());
+                market_ids.push(market.to_string());
+                product_ids.push(product.to_string());
+            }
+        }
+
+        let mut values = DMatrix::from_element(market_ids.len(), variables.len(), f64::NAN);
+        let mut filled = DMatrix::from_element(market_ids.len(), variables.len(), false);
+        for record in &self.records {
+            let row = row_index[&(record.market_id.as_str(), record.product_id.as_str())];
+            let column = variable_index[record.variable.as_str()];
+            if filled[(row, column)] {
+                return Err(BlpError::InvalidLongFormat {
+                    message: format!(
+                        "duplicate value for `{}` of product `{}` in market `{}`",
+                        record.variable, record.product_id, record.market_id
+                    ),
+                });
+            }
+            filled[(row, column)] = true;
+            values[(row, column)] = record.value;
+        }
+
+        // With `allow_missing`, unfilled cells stay NaN for the builder's imputation rule.
+        if !allow_missing
+            && let Some((row, column)) = (0..filled.nrows())
+                .flat_map(|row| (0..filled.ncols()).map(move |column| (row, column)))
+                .find(|cell| !filled[*cell])
+        {
+            return Err(BlpError::InvalidLongFormat {
+                message: format!(
+                    "missing value for `{}` of product `{}` in market `{}`",
+                    variables[column], product_ids[row], market_ids[row]
+                ),
+            });
+        }
+
+        Ok(WideTable {
+            market_ids,
+            product_ids,
+            columns: variables.into_iter().map(str::to_string).collect(),
+            values,
+        })
+    }
+}
+
+/// Wide table with one row per product and one named column per variable.
+#[derive(Clone, Debug)]
+pub struct WideTable {
+    market_ids: Vec<String>,
+    product_ids: Vec<String>,
+    columns: Vec<String>,
+    values: DMatrix<f64>,
+}
+
+impl WideTable {
+    /// Market identifier of every row.
+    pub fn market_ids(&self) -> &[String] {
+        &self.market_ids
+    }
+
+    /// Product identifier of every row.
+    pub fn product_ids(&self) -> &[String] {
+        &self.product_ids
+    }
+
+    /// Variable names, in column order.
+    pub fn column_names(&self) -> &[String] {
+        &self.columns
+    }
+
+    /// Full matrix of values.
+    pub fn values(&self) -> &DMatrix<f64> {
+        &self.values
+    }
+
+    /// A single column by name.
+    pub fn column(&self, name: &str) -> Result<DVector<f64>> {
+        let index = self.column_index(name)?;
+        Ok(self.values.column(index).into_owned())
+    }
+
+    /// Several columns by name, stacked in the requested order.
+    pub fn columns(&self, names: &[&str]) -> Result<DMatrix<f64>> {
+        let mut matrix = DMatrix::zeros(self.values.nrows(), names.len());
+        for (target, name) in names.iter().enumerate() {
+            let source = self.column_index(name)?;
+            matrix.set_column(target, &self.values.column(source));
+        }
+        Ok(matrix)
+    }
+
+    /// Start a [`ProductDataBuilder`] from named columns, for callers that need to set
+    /// further options (imputation, duplicate handling) before building.
+    pub fn builder(
+        &self,
+        shares: &str,
+        x1: &[&str],
+        x2: &[&str],
+        instruments: &[&str],
+    ) -> Result<ProductDataBuilder> {
+        let labels = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        let mut builder = ProductDataBuilder::new(self.market_ids.clone(), self.column(shares)?)
+            .product_ids(self.product_ids.clone())
+            .x1(self.columns(x1)?)
+            .x1_labels(labels(x1))
+            .x2(self.columns(x2)?)
+            .x2_labels(labels(x2));
+        if !instruments.is_empty() {
+            builder = builder
+                .instruments(self.columns(instruments)?)
+                .instrument_labels(labels(instruments));
+        }
+        Ok(builder)
+    }
+
+    /// Assemble validated [`ProductData`] by naming the share, `X1`, `X2`, and instrument
+    /// columns. An empty `instruments` list defaults the instruments to `X1`.
+    pub fn to_product_data(
+        &self,
+        shares: &str,
+        x1: &[&str],
+        x2: &[&str],
+        instruments: &[&str],
+    ) -> Result<ProductData> {
+        self.builder(shares, x1, x2, instruments)?.build()
+    }
+
+    fn column_index(&self, name: &str) -> Result<usize> {
+        self.columns
+            .iter()
+            .position(|column| column == name)
+            .ok_or_else(|| BlpError::InvalidLongFormat {
+                message: format!("unknown column `{name}`"),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pivots_interleaved_markets_into_contiguous_blocks() {
+        let mut table = LongTable::new();
+        for (market, product, share, price) in [
+            ("m1", "a", 0.3, 10.0),
+            ("m2", "a", 0.4, 12.0),
+            ("m1", "b", 0.2, 15.0),
+        ] {
+            table.push(market, product, "shares", share);
+            table.push(market, product, "prices", price);
+        }
+
+        let wide = table.pivot().unwrap();
+        assert_eq!(wide.market_ids(), ["m1", "m1", "m2"]);
+        assert_eq!(wide.product_ids(), ["a", "b", "a"]);
+        assert_eq!(wide.column("prices").unwrap()[1], 15.0);
+
+        let data = wide
+            .to_product_data("shares", &["prices"], &["prices"], &[])
+            .unwrap();
+        assert_eq!(data.partition().market_count(), 2);
+        assert_eq!(data.labels().x2, vec!["prices".to_string()]);
+        assert_eq!(data.product_id(2), Some("a"));
+
+        table.push("m2", "a", "prices", 13.0);
+        assert!(matches!(
+            table.pivot(),
+            Err(BlpError::InvalidLongFormat { .. })
+        ));
+    }
+}
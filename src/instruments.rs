@@ -0,0 +1,655 @@
+//! Constructing instruments from the data or from first-stage estimates.
+//!
+//! [`blp_instruments`] builds the classic BLP (1995) instruments, sums of characteristics
+//! over a product's own firm's other products and over its rivals' products, and
+//! [`differentiation_instruments`] the Gandhi and Houde (2019) differentiation
+//! instruments from the `X2` characteristics of the products in each market. Append
+//! either to `Z` with [`ProductData::append_instruments`]. [`reduce_instruments`] goes the
+//! other way, shrinking a large instrument set to its leading principal components or
+//! to ridge fits of the `X1` columns.
+//!
+//! [`ProblemResults::compute_optimal_instruments`] approximates Chamberlain's (1987)
+//! efficient instruments `E[d xi / d theta | Z]` at a first-stage estimate, following
+//! pyBLP's approximate method: the structural errors are set to their expectation of
+//! zero, so mean utilities are `E[X1 | Z] beta`, where every `X1` column (and the `X2`
+//! column that shares its label) is replaced by its fitted value from a regression on
+//! the instruments. Exogenous columns that are themselves instruments are unchanged.
+//! [`OptimalInstrumentResults::to_problem`] swaps the result in for the original
+//! instruments, ready for a second, more efficient estimation.
+
+use nalgebra::{DMatrix, DVector};
+
+use crate::data::{MarketLevel, ProductData};
+use crate::demand::{ShareInputs, invert_model};
+use crate::error::{BlpError, Result};
+use crate::estimation::{Problem, ProblemResults, inverse_ztz};
+use crate::linalg::Factorizations;
+use crate::options::WeightingMatrix;
+
+/// Classic BLP instruments from the `X1` columns at `columns`, which should be exogenous
+/// characteristics (usually the constant among them, but not price).
+///
+/// For product `j` and characteristic `k`, `own[k]` sums `x_lk` over the other products
+/// `l` of `j`'s firm in the same market and `rival[k]` over the other firms' products.
+/// With the constant, these are the numbers of own-firm and rival products.
+pub fn blp_instruments(
+    data: &ProductData,
+    firm_ids: &[String],
+    columns: &[usize],
+) -> Result<(DMatrix<f64>, Vec<String>)> {
+    blp_instruments_by_level(data, firm_ids, columns, MarketLevel::Market)
+}
+
+/// [`blp_instruments`] summed over the other products of the same market or, at
+/// [`MarketLevel::Group`], of every market in the same market group, e.g. all cities of
+/// a year.
+pub fn blp_instruments_by_level(
+    data: &ProductData,
+    firm_ids: &[String],
+    columns: &[usize],
+    level: MarketLevel,
+) -> Result<(DMatrix<f64>, Vec<String>)> {
+    let n = data.product_count();
+    if firm_ids.len() != n {
+        return Err(BlpError::dimension_mismatch(
+            "firm ids length",
+            n,
+            firm_ids.len(),
+        ));
+    }
+    let k1 = data.linear_dim();
+    if let Some(&column) = columns.iter().find(|&&column| column >= k1) {
+        return Err(BlpError::dimension_mismatch("X1 column", k1, column + 1));
+    }
+
+    let groups: Vec<Vec<usize>> = match level {
+        MarketLevel::Market => data
+            .partition()
+            .markets()
+            .map(|market| market.range().collect())
+            .collect(),
+        MarketLevel::Group => data
+            .market_group_partition()?
+            .into_iter()
+            .map(|group| group.products)
+            .collect(),
+    };
+    let mut instruments = DMatrix::zeros(n, 2 * columns.len());
+    for products in &groups {
+        for &j in products {
+            for &l in products.iter().filter(|&&l| l != j) {
+                let offset = if firm_ids[l] == firm_ids[j] { 0 } else { 1 };
+                for (index, &column) in columns.iter().enumerate() {
+                    instruments[(j, 2 * index + offset)] += data.x1()[(l, column)];
+                }
+            }
+        }
+    }
+    let labels = columns
+        .iter()
+        .flat_map(|&column| {
+            let name = &data.labels().x1[column];
+            [format!("own[{name}]"), format!("rival[{name}]")]
+        })
+        .collect();
+    Ok((instruments, labels))
+}
+
+/// Which summary of characteristic differences the differentiation instruments use.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DifferentiationVersion {
+    /// Number of other products whose characteristic lies within one standard deviation
+    /// (across all products) of the product's own.
+    #[default]
+    Local,
+    /// Sum of squared differences to the other products.
+    Quadratic,
+}
+
+/// Gandhi–Houde differentiation instruments from the `X2` characteristics, with labels
+/// such as `local[x]` or `quadratic[x]`.
+///
+/// For product `j` and characteristic `k`, the differences `x_lk - x_jk` to the other
+/// products `l` of the same market are summarized by `version`. With `firm_ids`, every
+/// characteristic gets two columns, one over `j`'s own firm's other products (`_own`)
+/// and one over rival firms' products (`_rival`); without, one column over all other
+/// products. Characteristics that do not vary across products are skipped.
+pub fn differentiation_instruments(
+    data: &ProductData,
+    firm_ids: Option<&[String]>,
+    version: DifferentiationVersion,
+) -> Result<(DMatrix<f64>, Vec<String>)> {
+    let n = data.product_count();
+    if let Some(firm_ids) = firm_ids
+        && firm_ids.len() != n
+    {
+        return Err(BlpError::dimension_mismatch(
+            "firm ids length",
+            n,
+            firm_ids.len(),
+        ));
+    }
+    let prefix = match version {
+        DifferentiationVersion::Local => "local",
+        DifferentiationVersion::Quadratic => "quadratic",
+    };
+    let groups: &[&str] = if firm_ids.is_some() {
+        &["_own", "_rival"]
+    } else {
+        &[""]
+    };
+
+    let mut columns = Vec::new();
+    let mut labels = Vec::new();
+    for (characteristic, label) in data.x2().column_iter().zip(&data.labels().x2) {
+        let mean = characteristic.mean();
+        let spread = (characteristic.map(|x| (x - mean).powi(2)).sum() / n as f64).sqrt();
+        if spread == 0.0 {
+            continue;
+        }
+        let mut summaries = vec![DVector::zeros(n); groups.len()];
+        for market in data.partition().markets() {
+            for j in market.range() {
+                for l in market.range().filter(|&l| l != j) {
+                    let difference = characteristic[l] - characteristic[j];
+                    let group = match firm_ids {
+                        Some(firms) if firms[l] == firms[j] => 0,
+                        Some(_) => 1,
+                        None => 0,
+                    };
+                    summaries[group][j] += match version {
+                        DifferentiationVersion::Local if difference.abs() < spread => 1.0,
+                        DifferentiationVersion::Local => 0.0,
+                        DifferentiationVersion::Quadratic => difference * difference,
+                    };
+                }
+            }
+        }
+        for (summary, suffix) in summaries.into_iter().zip(groups) {
+            columns.push(summary);
+            labels.push(format!("{prefix}{suffix}[{label}]"));
+        }
+    }
+    let matrix = if columns.is_empty() {
+        DMatrix::zeros(n, 0)
+    } else {
+        DMatrix::from_columns(&columns)
+    };
+    Ok((matrix, labels))
+}
+
+/// How [`reduce_instruments`] combines the instruments it reduces.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InstrumentReduction {
+    /// The leading principal components of the standardized instruments, labelled
+    /// `pc_0`, `pc_1`, and so on.
+    PrincipalComponents(usize),
+    /// Ridge fits `S (S'S + lambda N I)^{-1} S' x` of every non-constant `X1` column `x`
+    /// on the standardized instruments `S`, labelled `ridge[x]`, with penalty `lambda`.
+    Ridge(f64),
+}
+
+/// A reduced instrument set and the projection that produced it.
+#[derive(Clone, Debug)]
+pub struct ReducedInstruments {
+    /// The kept columns followed by the combinations.
+    pub instruments: DMatrix<f64>,
+    /// Names of the columns of `instruments`.
+    pub labels: Vec<String>,
+    /// Columns of `Z` passed through unchanged.
+    pub kept: Vec<usize>,
+    /// Columns of `Z` that were combined.
+    pub reduced: Vec<usize>,
+    /// Means of the combined columns.
+    pub center: DVector<f64>,
+    /// Standard deviations of the combined columns (one for constant columns).
+    pub scale: DVector<f64>,
+    /// Weights of the standardized combined columns in each combination.
+    pub projection: DMatrix<f64>,
+    /// Share of the standardized variance explained by each principal component; `None`
+    /// for ridge fits.
+    pub explained_variance: Option<DVector<f64>>,
+}
+
+impl ReducedInstruments {
+    /// Apply the same standardization and projection to instruments `z` laid out like
+    /// the original `Z`, e.g. for markets appended later.
+    pub fn apply(&self, z: &DMatrix<f64>) -> Result<DMatrix<f64>> {
+        let columns = self.kept.len() + self.reduced.len();
+        if z.ncols() != columns {
+            return Err(BlpError::dimension_mismatch(
+                "instrument columns",
+                columns,
+                z.ncols(),
+            ));
+        }
+        let standardized = DMatrix::from_fn(z.nrows(), self.reduced.len(), |j, k| {
+            (z[(j, self.reduced[k])] - self.center[k]) / self.scale[k]
+        });
+        let combined = standardized * &self.projection;
+        Ok(DMatrix::from_fn(
+            z.nrows(),
+            self.kept.len() + combined.ncols(),
+            |j, k| match k.checked_sub(self.kept.len()) {
+                Some(component) => combined[(j, component)],
+                None => z[(j, self.kept[k])],
+            },
+        ))
+    }
+
+    /// `data` with its instruments replaced by the reduced set.
+    pub fn to_data(&self, data: &ProductData) -> Result<ProductData> {
+        data.to_builder()
+            .instruments(self.instruments.clone())
+            .instrument_labels(self.labels.clone())
+            .build()
+    }
+}
+
+/// Reduce the instruments of `data` to a few combinations, passing the columns in `keep`
+/// (usually the constant and the other exogenous `X1` columns) through unchanged.
+///
+/// The other columns are standardized and combined as `reduction` asks, a principled
+/// alternative to dropping instruments by hand when there are too many for the sample.
+/// Principal components keep the directions in which the instruments vary most; ridge
+/// fits keep those that predict `X1`, which includes the endogenous characteristics.
+pub fn reduce_instruments(
+    data: &ProductData,
+    keep: &[usize],
+    reduction: InstrumentReduction,
+) -> Result<ReducedInstruments> {
+    let z = data.instruments();
+    let (n, columns) = z.shape();
+    if let Some(&column) = keep.iter().find(|&&column| column >= columns) {
+        return Err(BlpError::dimension_mismatch(
+            "Z column",
+            columns,
+            column + 1,
+        ));
+    }
+    let reduced: Vec<usize> = (0..columns)
+        .filter(|column| !keep.contains(column))
+        .collect();
+    let center = DVector::from_iterator(
+        reduced.len(),
+        reduced.iter().map(|&column| z.column(column).mean()),
+    );
+    let scale = DVector::from_iterator(
+        reduced.len(),
+        reduced.iter().zip(center.iter()).map(|(&column, mean)| {
+            let variance = z.column(column).map(|value| (value - mean).powi(2)).sum() / n as f64;
+            if variance > 0.0 { variance.sqrt() } else { 1.0 }
+        }),
+    );
+    let standardized = DMatrix::from_fn(n, reduced.len(), |j, k| {
+        (z[(j, reduced[k])] - center[k]) / scale[k]
+    });
+    let gram = standardized.transpose() * &standardized;
+
+    let (projection, names, explained_variance) = match reduction {
+        InstrumentReduction::PrincipalComponents(components) => {
+            if components == 0 || components > reduced.len() {
+                return Err(BlpError::dimension_mismatch(
+                    "principal components",
+                    reduced.len(),
+                    components,
+                ));
+            }
+            let eigen = gram.symmetric_eigen();
+            let mut order: Vec<usize> = (0..reduced.len()).collect();
+            order.sort_by(|&a, &b| eigen.eigenvalues[b].total_cmp(&eigen.eigenvalues[a]));
+            order.truncate(components);
+            let mut projection = eigen.eigenvectors.select_columns(order.iter());
+            // Fix the signs so the largest loading of every component is positive.
+            for mut vector in projection.column_iter_mut() {
+                if vector[vector.iamax()] < 0.0 {
+                    vector.neg_mut();
+                }
+            }
+            let total = eigen.eigenvalues.sum().max(f64::MIN_POSITIVE);
+            let explained = DVector::from_iterator(
+                components,
+                order.iter().map(|&index| eigen.eigenvalues[index] / total),
+            );
+            let names: Vec<String> = (0..components).map(|index| format!("pc_{index}")).collect();
+            (projection, names, Some(explained))
+        }
+        InstrumentReduction::Ridge(penalty) => {
+            if !(penalty > 0.0 && penalty.is_finite()) {
+                return Err(BlpError::InconsistentSpecification {
+                    mismatches: vec![format!("ridge penalty {penalty} must be positive")],
+                });
+            }
+            let targets: Vec<usize> = (0..data.linear_dim())
+                .filter(|&column| data.x1().column(column).variance() > 0.0)
+                .collect();
+            let x1 = data.x1().select_columns(targets.iter());
+            let regularized =
+                &gram + DMatrix::identity(reduced.len(), reduced.len()) * (penalty * n as f64);
+            let projection = regularized
+                .cholesky()
+                .ok_or_else(|| BlpError::singular("ridge normal equations"))?
+                .solve(&(standardized.transpose() * x1));
+            let names = targets
+                .iter()
+                .map(|&column| format!("ridge[{}]", data.labels().x1[column]))
+                .collect();
+            (projection, names, None)
+        }
+    };
+
+    let labels: Vec<String> = keep
+        .iter()
+        .map(|&column| data.labels().instruments[column].clone())
+        .chain(names)
+        .collect();
+    let mut result = ReducedInstruments {
+        instruments: DMatrix::zeros(0, 0),
+        labels,
+        kept: keep.to_vec(),
+        reduced,
+        center,
+        scale,
+        projection,
+        explained_variance,
+    };
+    result.instruments = result.apply(z)?;
+    Ok(result)
+}
+
+/// Step of the central difference taken in `rho`.
+const RHO_STEP: f64 = 1e-5;
+
+/// Approximate optimal instruments computed by
+/// [`ProblemResults::compute_optimal_instruments`].
+#[derive(Clone, Debug)]
+pub struct OptimalInstrumentResults {
+    /// One column per estimated parameter: the expected `X1` columns (the derivatives
+    /// with respect to `beta`, up to sign), then `d delta / d sigma` for every nonzero
+    /// entry of `sigma` in column-major order, then `d delta / d rho` under nesting.
+    pub instruments: DMatrix<f64>,
+    /// Names of the instrument columns, e.g. `price` and `sigma[price]`.
+    pub labels: Vec<String>,
+    /// Mean utilities `E[X1 | Z] beta` at which the Jacobians were evaluated.
+    pub expected_delta: DVector<f64>,
+    /// Shares implied by `expected_delta`.
+    pub expected_shares: DVector<f64>,
+}
+
+impl OptimalInstrumentResults {
+    /// `problem` with its instruments replaced by the optimal ones and the weighting
+    /// matrix reset to `(Z'Z)^{-1}`. The new problem is exactly identified.
+    pub fn to_problem(&self, problem: &Problem) -> Result<Problem> {
+        let data = problem
+            .data()
+            .to_builder()
+            .instruments(self.instruments.clone())
+            .instrument_labels(self.labels.clone())
+            .build()?;
+        let mut options = problem.options().clone();
+        options.gmm.weighting = WeightingMatrix::InverseZTZ;
+        Ok(problem.with_data(data)?.with_options_override(options))
+    }
+}
+
+impl ProblemResults {
+    /// Approximate the optimal instruments for `problem` at these estimates.
+    ///
+    /// Only demand-side instruments are computed, and `pi` is treated as known.
+    pub fn compute_optimal_instruments(
+        &self,
+        problem: &Problem,
+    ) -> Result<OptimalInstrumentResults> {
+        let data = problem.data();
+        let draws = problem.draws();
+        let parameters = self.parameters();
+        parameters.validate(data, draws)?;
+        if self.beta.len() != data.linear_dim() {
+            return Err(BlpError::dimension_mismatch(
+                "beta length",
+                data.linear_dim(),
+                self.beta.len(),
+            ));
+        }
+
+        let z = data.instruments();
+        let mut factorizations = Factorizations::new(problem.options().gmm.strict_factorizations);
+        let x1 = z * (inverse_ztz(z, &mut factorizations)? * (z.transpose() * data.x1()));
+        let mut x2 = data.x2().clone();
+        for (x1_column, x2_column) in data.labels().shared() {
+            x2.set_column(x2_column, &x1.column(x1_column));
+        }
+        let expected = data.to_builder().x1(x1.clone()).x2(x2).build()?;
+        let expected_delta = &x1 * &self.beta;
+
+        let contraction = &problem.options().contraction;
+        let inputs = ShareInputs::for_parameters(&expected, draws, &parameters, contraction);
+        let model = problem.model();
+        let expected_shares = model.shares(&expected_delta, &inputs)?;
+        let sigma_jacobian = problem.delta_jacobian(&expected_delta, &inputs)?;
+
+        let k2 = data.nonlinear_dim();
+        let names = &data.labels().x2;
+        let mut columns: Vec<DVector<f64>> = x1.column_iter().map(|c| c.into_owned()).collect();
+        let mut labels = data.labels().x1.clone();
+        for column in 0..k2 {
+            for row in 0..k2 {
+                if self.sigma[(row, column)] == 0.0 {
+                    continue;
+                }
+                columns.push(sigma_jacobian.column(column * k2 + row).into_owned());
+                labels.push(if row == column {
+                    format!("sigma[{}]", names[row])
+                } else {
+                    format!("sigma[{}, {}]", names[row], names[column])
+                });
+            }
+        }
+        if let Some(rho) = self.rho {
+            // Invert the expected shares on either side of rho.
+            let observed = expected
+                .to_builder()
+                .shares(expected_shares.clone())
+                .build()?;
+            let (lower, upper) = ((rho - RHO_STEP).max(0.0), rho + RHO_STEP);
+            let invert = |rho: f64| -> Result<DVector<f64>> {
+                let nested = parameters.clone().with_rho(rho);
+                let inputs = ShareInputs::for_parameters(&observed, draws, &nested, contraction)
+                    .with_initial_delta(&expected_delta);
+                Ok(invert_model(model, &inputs)?.0)
+            };
+            columns.push((invert(upper)? - invert(lower)?) / (upper - lower));
+            labels.push("rho".to_string());
+        }
+
+        Ok(OptimalInstrumentResults {
+            instruments: DMatrix::from_columns(&columns),
+            labels,
+            expected_delta,
+            expected_shares,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OptimizationOptions;
+    use crate::data::ProductDataBuilder;
+    use crate::integration::SimulationDraws;
+    use crate::options::ProblemOptions;
+    use crate::parameters::NonlinearParameters;
+    use crate::testing::TestMarkets;
+
+    #[test]
+    fn blp_instruments_sum_own_and_rival_characteristics() {
+        let market_ids = ["m1", "m1", "m1", "m2", "m2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.1, 0.2, 0.3, 0.25, 0.15]);
+        let x1 = DMatrix::from_row_slice(
+            5,
+            3,
+            &[
+                1.0, 2.0, 5.0, 1.0, 3.0, 6.0, 1.0, 4.0, 7.0, 1.0, 1.0, 8.0, 1.0, 2.0, 9.0,
+            ],
+        );
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1.clone())
+            .x1_labels(vec!["constant".into(), "size".into(), "price".into()])
+            .instruments(x1.columns(0, 2).into_owned())
+            .instrument_labels(vec!["constant".into(), "size".into()])
+            .build()
+            .unwrap();
+        let firms = ["a", "a", "b", "a", "a"].map(String::from);
+
+        let (sums, labels) = blp_instruments(&data, &firms, &[0, 1]).unwrap();
+        assert_eq!(
+            labels,
+            [
+                "own[constant]",
+                "rival[constant]",
+                "own[size]",
+                "rival[size]"
+            ]
+        );
+        assert_eq!(
+            sums.row(0).iter().copied().collect::<Vec<_>>(),
+            [1.0, 1.0, 3.0, 4.0]
+        );
+        assert_eq!(
+            sums.row(2).iter().copied().collect::<Vec<_>>(),
+            [0.0, 2.0, 0.0, 5.0]
+        );
+        assert_eq!(
+            sums.row(4).iter().copied().collect::<Vec<_>>(),
+            [1.0, 0.0, 1.0, 0.0]
+        );
+
+        let extended = data.append_instruments(&sums, labels).unwrap();
+        assert_eq!(extended.instrument_dim(), 6);
+        assert_eq!(extended.labels().instruments[5], "rival[size]");
+        assert!(blp_instruments(&data, &firms, &[3]).is_err());
+        assert!(blp_instruments(&data, &firms[..4], &[0]).is_err());
+    }
+
+    #[test]
+    fn differentiation_instruments_summarize_rivals() {
+        let market_ids = ["m1", "m1", "m1", "m2", "m2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.1, 0.2, 0.3, 0.25, 0.15]);
+        let x = [1.0, 1.5, 4.0, 2.0, 2.2];
+        let x1 = DMatrix::from_fn(5, 3, |j, k| [1.0, x[j], 7.0][k]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .x2_from_x1(vec![1, 2])
+            .x1_labels(vec!["constant".into(), "x".into(), "fixed".into()])
+            .build()
+            .unwrap();
+
+        let (local, labels) =
+            differentiation_instruments(&data, None, DifferentiationVersion::Local).unwrap();
+        assert_eq!(labels, ["local[x]"]);
+        // The standard deviation of x is about 1.02.
+        assert_eq!(local.column(0).as_slice(), &[1.0, 1.0, 0.0, 1.0, 1.0]);
+
+        let firms = ["a", "a", "b", "a", "b"].map(String::from);
+        let (quadratic, labels) =
+            differentiation_instruments(&data, Some(&firms), DifferentiationVersion::Quadratic)
+                .unwrap();
+        assert_eq!(labels, ["quadratic_own[x]", "quadratic_rival[x]"]);
+        assert_eq!(
+            quadratic.row(0).iter().copied().collect::<Vec<_>>(),
+            [0.25, 9.0]
+        );
+        assert_eq!(
+            quadratic.row(2).iter().copied().collect::<Vec<_>>(),
+            [0.0, 15.25]
+        );
+        assert!(differentiation_instruments(&data, Some(&firms[..2]), Default::default()).is_err());
+    }
+
+    #[test]
+    fn optimal_instruments_feed_a_second_stage() {
+        let n = 24;
+        let markets = TestMarkets::new(n);
+        let (x, w) = (markets.x.clone(), markets.w.clone());
+        let shock = DVector::from_fn(n, |j, _| ((j * 53) % 17) as f64 / 17.0 - 0.5);
+        let price = DVector::from_fn(n, |j, _| 1.0 + 0.5 * w[j] + 0.3 * x[j] + 0.2 * shock[j]);
+        let x1 = DMatrix::from_fn(n, 3, |j, k| [1.0, x[j], price[j]][k]);
+        let markets = markets
+            .with_x1(x1.clone(), vec![2])
+            .with_x1_labels(&["constant", "x", "price"])
+            .with_instruments(DMatrix::from_fn(n, 5, |j, k| {
+                [1.0, x[j], w[j], x[j] * w[j], w[j] * w[j]][k]
+            }));
+        let draws = SimulationDraws::standard_normal(20, 1, 3);
+        let truth = NonlinearParameters::new(DMatrix::from_element(1, 1, 0.6));
+        let delta = DVector::from_fn(n, |j, _| 1.0 + x[j] - price[j]);
+        let data = markets.simulate(&draws, &truth, &delta);
+        let options = ProblemOptions::default().with_optimization(
+            OptimizationOptions::new(DMatrix::from_element(1, 1, 1.0)).with_tolerances(1e-10, 1e-4),
+        );
+        let problem = Problem::with_options(data, draws, options).unwrap();
+        let first = problem.solve(&truth).unwrap();
+
+        let optimal = first.compute_optimal_instruments(&problem).unwrap();
+        assert_eq!(optimal.instruments.shape(), (n, 4));
+        assert_eq!(optimal.labels, ["constant", "x", "price", "sigma[price]"]);
+        // Exogenous columns are instruments and pass through unchanged.
+        assert!((optimal.instruments.column(1) - x1.column(1)).amax() < 1e-10);
+        assert!((optimal.instruments.column(2) - x1.column(2)).amax() > 1e-3);
+        assert!((&optimal.expected_delta - &first.delta).amax() > 0.0);
+
+        let second = optimal.to_problem(&problem).unwrap();
+        assert_eq!(second.data().instrument_dim(), 4);
+        let estimate = second.optimize().unwrap().results;
+        assert!((estimate.sigma[(0, 0)] - 0.6).abs() < 1e-2);
+        assert!((estimate.beta[2] + 1.0).abs() < 1e-2);
+    }
+
+    #[test]
+    fn reduced_instruments_keep_the_leading_directions() {
+        let n = 12;
+        let market_ids: Vec<String> = (0..n).map(|j| format!("m{}", j / 3)).collect();
+        let shares = DVector::from_element(n, 0.2);
+        let factor: Vec<f64> = (0..n).map(|j| (j as f64 * 1.3).sin()).collect();
+        let x1 = DMatrix::from_fn(n, 3, |j, k| [1.0, (j % 4) as f64, 2.0 * factor[j]][k]);
+        // Three noisy copies of the factor driving price plus a constant column.
+        let z = DMatrix::from_fn(n, 6, |j, k| match k {
+            0 => 1.0,
+            1 => x1[(j, 1)],
+            5 => 3.0,
+            _ => factor[j] * k as f64 + 0.01 * ((j * k) as f64).cos(),
+        });
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .x1_labels(vec!["constant".into(), "x".into(), "price".into()])
+            .instruments(z.clone())
+            .build()
+            .unwrap();
+
+        let pca = reduce_instruments(&data, &[0, 1], InstrumentReduction::PrincipalComponents(1))
+            .unwrap();
+        assert_eq!(pca.labels, ["z_0", "z_1", "pc_0"]);
+        assert_eq!(pca.reduced, [2, 3, 4, 5]);
+        assert_eq!(pca.scale[3], 1.0);
+        assert!(pca.explained_variance.as_ref().unwrap()[0] > 0.99);
+        let correlation = |a: DVector<f64>, b: DVector<f64>| {
+            let (a, b) = (a.add_scalar(-a.mean()), b.add_scalar(-b.mean()));
+            a.dot(&b) / (a.norm() * b.norm())
+        };
+        let factor = DVector::from_vec(factor);
+        assert!(correlation(pca.instruments.column(2).into_owned(), factor.clone()) > 0.999);
+        assert_eq!(pca.apply(&z).unwrap(), pca.instruments);
+        assert_eq!(pca.to_data(&data).unwrap().instrument_dim(), 3);
+
+        let ridge = reduce_instruments(&data, &[0, 1], InstrumentReduction::Ridge(1e-3)).unwrap();
+        assert_eq!(ridge.labels, ["z_0", "z_1", "ridge[x]", "ridge[price]"]);
+        assert!(ridge.explained_variance.is_none());
+        assert!(correlation(ridge.instruments.column(3).into_owned(), factor) > 0.999);
+
+        assert!(reduce_instruments(&data, &[6], InstrumentReduction::Ridge(1.0)).is_err());
+        assert!(reduce_instruments(&data, &[], InstrumentReduction::Ridge(0.0)).is_err());
+        assert!(
+            reduce_instruments(&data, &[0], InstrumentReduction::PrincipalComponents(6)).is_err()
+        );
+    }
+}
@@ -1,17 +1,51 @@
 //! Monte Carlo integration helpers for simulating heterogeneous consumer tastes.
 
+use std::path::Path;
+
 use nalgebra::{DMatrix, DVector};
-use rand::SeedableRng;
 use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
 use rand_distr::{Distribution, StandardNormal};
 
 use crate::error::{BlpError, Result};
+use crate::linalg::cholesky_solve;
+
+/// How integration weights read from an external file are checked.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WeightNormalization {
+    /// Weights must already sum to one (within `1e-8`).
+    #[default]
+    Require,
+    /// Rescale positive weights to sum to one, e.g. for Gauss-Hermite weights that sum
+    /// to `pi^(d/2)`.
+    Rescale,
+}
+
+/// Accuracy diagnostics for agents compressed with [`SimulationDraws::compress`].
+#[derive(Clone, Debug)]
+pub struct CompressionReport {
+    /// Number of agents before compression.
+    pub original_agents: usize,
+    /// Number of representative nodes after compression.
+    pub nodes: usize,
+    /// Lloyd iterations performed.
+    pub iterations: usize,
+    /// Weighted within-cluster variance as a fraction of the total variance of the
+    /// standardized features; zero means no information was lost.
+    pub within_variance_ratio: f64,
+    /// Largest absolute difference between the weighted covariance matrices of the
+    /// features (draws, then demographics) before and after compression.
+    pub covariance_error: f64,
+}
 
 /// Represents simulated consumer heterogeneity used in BLP demand estimation.
 #[derive(Clone, Debug)]
 pub struct SimulationDraws {
     draws: DMatrix<f64>,
     weights: DVector<f64>,
+    demographics: Option<DMatrix<f64>>,
+    seed: Option<u64>,
+    type_counts: Option<Vec<u64>>,
 }
 
 impl SimulationDraws {
@@ -38,7 +72,13 @@ impl SimulationDraws {
             return Err(BlpError::InvalidWeights { slack });
         }
 
-        Ok(Self { draws, weights })
+        Ok(Self {
+            draws,
+            weights,
+            demographics: None,
+            seed: None,
+            type_counts: None,
+        })
     }
 
     /// Generates standard normal draws with uniform weights.
@@ -52,7 +92,148 @@ impl SimulationDraws {
         let matrix = DMatrix::from_vec(draws, dimension, values);
         let weight = 1.0 / draws as f64;
         let weights = DVector::from_element(draws, weight);
-        Self::new(matrix, weights).expect("validated gaussian draws")
+        let mut draws = Self::new(matrix, weights).expect("validated gaussian draws");
+        draws.seed = Some(seed);
+        draws
+    }
+
+    /// Exact enumeration of a few discrete consumer types, e.g. income bins.
+    ///
+    /// Row `i` of `nodes` is type `i`'s taste node and `counts[i]` the number (or
+    /// population mass in integer units) of consumers of that type; the weights are the
+    /// exact type frequencies, so results are deterministic and free of simulation
+    /// error. Attach per-type demographics with
+    /// [`with_demographics`](Self::with_demographics).
+    pub fn enumerated(nodes: DMatrix<f64>, counts: Vec<u64>) -> Result<Self> {
+        if nodes.nrows() == 0 {
+            return Err(BlpError::dimension_mismatch("consumer types", 1, 0));
+        }
+        if nodes.nrows() != counts.len() {
+            return Err(BlpError::dimension_mismatch(
+                "consumer type counts",
+                nodes.nrows(),
+                counts.len(),
+            ));
+        }
+        if counts.contains(&0) {
+            return Err(BlpError::InvalidWeights { slack: 0.0 });
+        }
+        let total: u64 = counts.iter().sum();
+        let weights = DVector::from_iterator(
+            counts.len(),
+            counts.iter().map(|&count| count as f64 / total as f64),
+        );
+        Ok(Self {
+            draws: nodes,
+            weights,
+            demographics: None,
+            seed: None,
+            type_counts: Some(counts),
+        })
+    }
+
+    /// Enumerate types from agent-level data in which agent `a` has type
+    /// `assignments[a]`, a row of `nodes`. Types that no agent has are an error.
+    pub fn from_type_assignments(nodes: DMatrix<f64>, assignments: &[usize]) -> Result<Self> {
+        let mut counts = vec![0; nodes.nrows()];
+        for &assignment in assignments {
+            let count = counts.get_mut(assignment).ok_or_else(|| {
+                BlpError::dimension_mismatch("consumer type", nodes.nrows(), assignment + 1)
+            })?;
+            *count += 1;
+        }
+        Self::enumerated(nodes, counts)
+    }
+
+    /// Number of consumers of each type when the draws enumerate discrete types.
+    pub fn type_counts(&self) -> Option<&[u64]> {
+        self.type_counts.as_deref()
+    }
+
+    /// Whether the draws are an exact enumeration of consumer types rather than a sample.
+    pub fn is_enumerated(&self) -> bool {
+        self.type_counts.is_some()
+    }
+
+    /// Read nodes and weights from a CSV file with a header row.
+    ///
+    /// See [`parse_csv`](Self::parse_csv) for the expected layout.
+    pub fn read_csv<P: AsRef<Path>>(path: P, normalization: WeightNormalization) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| BlpError::io("reading integration nodes", err))?;
+        Self::parse_csv(&text, normalization)
+    }
+
+    /// Parse nodes and weights from CSV text, e.g. exported from a sparse-grid generator,
+    /// Stata, or Matlab.
+    ///
+    /// The first row is a header. A column named `weight` or `weights` (case-insensitive)
+    /// holds the integration weights; every other column is a node dimension, in order.
+    /// Without a weight column the nodes are weighted uniformly.
+    pub fn parse_csv(text: &str, normalization: WeightNormalization) -> Result<Self> {
+        let parse_error = |message: String| BlpError::Serialization {
+            context: "integration nodes CSV",
+            message,
+        };
+        let mut lines = text.lines().filter(|line| !line.trim().is_empty());
+        let header: Vec<&str> = lines
+            .next()
+            .ok_or_else(|| parse_error("missing header row".to_string()))?
+            .split(',')
+            .map(str::trim)
+            .collect();
+        let weight_column = header
+            .iter()
+            .position(|name| matches!(name.to_ascii_lowercase().as_str(), "weight" | "weights"));
+        let dimension = header.len() - usize::from(weight_column.is_some());
+
+        let mut nodes = Vec::new();
+        let mut weights = Vec::new();
+        for (line_index, line) in lines.enumerate() {
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            if fields.len() != header.len() {
+                return Err(parse_error(format!(
+                    "row {} has {} fields, expected {}",
+                    line_index + 1,
+                    fields.len(),
+                    header.len()
+                )));
+            }
+            for (column, field) in fields.iter().enumerate() {
+                let value: f64 = field.parse().map_err(|_| {
+                    parse_error(format!(
+                        "row {}, column '{}': cannot parse '{field}'",
+                        line_index + 1,
+                        header[column]
+                    ))
+                })?;
+                if Some(column) == weight_column {
+                    weights.push(value);
+                } else {
+                    nodes.push(value);
+                }
+            }
+        }
+
+        let count = nodes.len().checked_div(dimension).unwrap_or(weights.len());
+        if count == 0 {
+            return Err(BlpError::dimension_mismatch("simulation draws", 1, 0));
+        }
+        let draws = DMatrix::from_row_slice(count, dimension, &nodes);
+        let mut weights = if weight_column.is_some() {
+            DVector::from_vec(weights)
+        } else {
+            DVector::from_element(count, 1.0 / count as f64)
+        };
+        if normalization == WeightNormalization::Rescale {
+            if let Some(weight) = weights.iter().find(|weight| **weight <= 0.0) {
+                return Err(BlpError::InvalidWeights { slack: *weight });
+            }
+            let sum = weights.sum();
+            log::info!("rescaling {count} integration weights that summed to {sum}");
+            weights /= sum;
+        }
+        Self::new(draws, weights)
     }
 
     /// Number of Monte Carlo draws.
@@ -70,10 +251,318 @@ impl SimulationDraws {
         &self.draws
     }
 
+    /// Seed the draws were generated from, if they were generated by this crate.
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
     /// Returns the associated integration weights (normalized to sum to one).
     pub fn weights(&self) -> &DVector<f64> {
         &self.weights
     }
+
+    /// Keep only the draw dimensions at positions `columns`, in that order.
+    pub fn select_dimensions(&self, columns: &[usize]) -> Result<Self> {
+        if let Some(&column) = columns.iter().find(|&&column| column >= self.dimension()) {
+            return Err(BlpError::dimension_mismatch(
+                "draw dimension",
+                self.dimension(),
+                column + 1,
+            ));
+        }
+        let mut selected = self.clone();
+        selected.draws = self.draws.select_columns(columns.iter());
+        Ok(selected)
+    }
+
+    /// Attach observed demographics, one row per agent, to the draws.
+    pub fn with_demographics(mut self, demographics: DMatrix<f64>) -> Result<Self> {
+        if demographics.nrows() != self.draw_count() {
+            return Err(BlpError::dimension_mismatch(
+                "demographic rows",
+                self.draw_count(),
+                demographics.nrows(),
+            ));
+        }
+        self.demographics = Some(demographics);
+        Ok(self)
+    }
+
+    /// Agent demographics, if any were attached.
+    pub fn demographics(&self) -> Option<&DMatrix<f64>> {
+        self.demographics.as_ref()
+    }
+
+    /// Weighted mean of each demographic under the current integration weights.
+    pub fn demographic_means(&self) -> Result<DVector<f64>> {
+        let demographics = self
+            .demographics
+            .as_ref()
+            .ok_or_else(|| BlpError::missing_component("demographics"))?;
+        Ok(demographics.transpose() * &self.weights)
+    }
+
+    /// Re-weight the agents so that the weighted demographic means equal `targets`.
+    ///
+    /// Uses exponential tilting: the new weights are `w_i exp(lambda'd_i)`, normalized, with
+    /// `lambda` chosen by Newton's method. Among all reweightings that hit the targets this
+    /// is the one closest to the current weights in Kullback-Leibler divergence, so agents
+    /// and their draws are kept and only their importance changes. Targets must lie
+    /// strictly inside the range spanned by the agents' demographics.
+    pub fn reweight_to_means(&self, targets: &DVector<f64>) -> Result<Self> {
+        let demographics = self
+            .demographics
+            .as_ref()
+            .ok_or_else(|| BlpError::missing_component("demographics"))?;
+        if targets.len() != demographics.ncols() {
+            return Err(BlpError::dimension_mismatch(
+                "demographic targets",
+                demographics.ncols(),
+                targets.len(),
+            ));
+        }
+        let centered = DMatrix::from_fn(demographics.nrows(), demographics.ncols(), |i, d| {
+            demographics[(i, d)] - targets[d]
+        });
+
+        let mut lambda = DVector::zeros(targets.len());
+        for _ in 0..100 {
+            let tilt = (&centered * &lambda).map(f64::exp);
+            let unnormalized = self.weights.component_mul(&tilt);
+            let weights = &unnormalized / unnormalized.sum();
+            let gap = centered.transpose() * &weights;
+            if gap.amax() < 1e-10 {
+                let mut reweighted = self.clone();
+                reweighted.weights = weights;
+                reweighted.type_counts = None;
+                return Ok(reweighted);
+            }
+            let weighted = DMatrix::from_fn(centered.nrows(), centered.ncols(), |i, d| {
+                weights[i] * centered[(i, d)]
+            });
+            let hessian = centered.transpose() * weighted - &gap * gap.transpose();
+            let step = cholesky_solve(&hessian, &gap).ok_or(BlpError::NumericalError {
+                context: "demographic reweighting",
+            })?;
+            lambda -= step;
+        }
+        Err(BlpError::InvalidCounterfactual {
+            reason: "demographic targets cannot be reached by re-weighting the agents",
+        })
+    }
+
+    /// Compress the agents into `nodes` representative nodes with weighted k-means.
+    ///
+    /// Agents are clustered on their standardized draws and demographics; each node sits
+    /// at the weighted mean of its cluster and carries the cluster's total weight, so
+    /// weighted feature means are preserved exactly while the spread within clusters is
+    /// lost. Prediction cost scales with the number of agents, so this trades
+    /// integration accuracy for speed on very large empirical samples. Compare shares with
+    /// [`Problem::counterfactual_population`](crate::Problem::counterfactual_population)
+    /// to check the effect on a specific model.
+    pub fn compress(&self, nodes: usize, seed: u64) -> Result<(Self, CompressionReport)> {
+        let count = self.draw_count();
+        if nodes == 0 || nodes > count {
+            return Err(BlpError::dimension_mismatch(
+                "compressed nodes",
+                count,
+                nodes,
+            ));
+        }
+        let features = self.features();
+        let means = features.transpose() * &self.weights;
+        let scales = DVector::from_fn(features.ncols(), |column, _| {
+            let variance: f64 = (0..count)
+                .map(|row| self.weights[row] * (features[(row, column)] - means[column]).powi(2))
+                .sum();
+            if variance > 0.0 { variance.sqrt() } else { 1.0 }
+        });
+        let standardized = DMatrix::from_fn(count, features.ncols(), |row, column| {
+            (features[(row, column)] - means[column]) / scales[column]
+        });
+
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let mut centers = self.seed_centers(&standardized, nodes, &mut rng);
+        let mut assignment = vec![usize::MAX; count];
+        let mut iterations = 0;
+        while iterations < 100 {
+            iterations += 1;
+            let mut changed = false;
+            for (row, assigned) in assignment.iter_mut().enumerate() {
+                let nearest = nearest_center(&standardized, row, &centers).0;
+                changed |= *assigned != nearest;
+                *assigned = nearest;
+            }
+            centers = DMatrix::zeros(nodes, features.ncols());
+            let mut totals = vec![0.0; nodes];
+            for (row, &cluster) in assignment.iter().enumerate() {
+                totals[cluster] += self.weights[row];
+                for column in 0..features.ncols() {
+                    centers[(cluster, column)] += self.weights[row] * standardized[(row, column)];
+                }
+            }
+            for (cluster, total) in totals.iter().enumerate() {
+                if *total > 0.0 {
+                    centers.row_mut(cluster).unscale_mut(*total);
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        // Drop clusters that ended up empty and aggregate the original features.
+        let mut totals = vec![0.0; nodes];
+        let mut sums = DMatrix::<f64>::zeros(nodes, features.ncols());
+        for (row, &cluster) in assignment.iter().enumerate() {
+            totals[cluster] += self.weights[row];
+            for column in 0..features.ncols() {
+                sums[(cluster, column)] += self.weights[row] * features[(row, column)];
+            }
+        }
+        let kept: Vec<usize> = (0..nodes)
+            .filter(|&cluster| totals[cluster] > 0.0)
+            .collect();
+        let compressed_features = DMatrix::from_fn(kept.len(), features.ncols(), |i, column| {
+            sums[(kept[i], column)] / totals[kept[i]]
+        });
+        let weights = DVector::from_iterator(kept.len(), kept.iter().map(|&c| totals[c]));
+        let weights = &weights / weights.sum();
+
+        let dimension = self.dimension();
+        let mut compressed = Self::new(
+            compressed_features.columns(0, dimension).into_owned(),
+            weights,
+        )?;
+        compressed.seed = self.seed;
+        if self.demographics.is_some() {
+            compressed.demographics = Some(
+                compressed_features
+                    .columns(dimension, features.ncols() - dimension)
+                    .into_owned(),
+            );
+        }
+
+        let within: f64 = assignment
+            .iter()
+            .enumerate()
+            .map(|(row, &cluster)| {
+                self.weights[row] * squared_distance(&standardized, row, &centers, cluster)
+            })
+            .sum();
+        let total: f64 = (0..count)
+            .map(|row| self.weights[row] * standardized.row(row).norm_squared())
+            .sum();
+        let covariance_error = (weighted_covariance(&features, &self.weights)
+            - weighted_covariance(&compressed.features(), &compressed.weights))
+        .amax();
+        let report = CompressionReport {
+            original_agents: count,
+            nodes: compressed.draw_count(),
+            iterations,
+            within_variance_ratio: if total > 0.0 { within / total } else { 0.0 },
+            covariance_error,
+        };
+        log::debug!(
+            "compressed {count} agents into {} nodes; within-cluster variance ratio {:.3e}",
+            report.nodes,
+            report.within_variance_ratio
+        );
+        Ok((compressed, report))
+    }
+
+    /// Draws followed by demographics, one row per agent.
+    fn features(&self) -> DMatrix<f64> {
+        match &self.demographics {
+            Some(demographics) => {
+                let mut features =
+                    DMatrix::zeros(self.draw_count(), self.dimension() + demographics.ncols());
+                features
+                    .columns_mut(0, self.dimension())
+                    .copy_from(&self.draws);
+                features
+                    .columns_mut(self.dimension(), demographics.ncols())
+                    .copy_from(demographics);
+                features
+            }
+            None => self.draws.clone(),
+        }
+    }
+
+    /// Weighted k-means++ initialization.
+    fn seed_centers(
+        &self,
+        points: &DMatrix<f64>,
+        nodes: usize,
+        rng: &mut SmallRng,
+    ) -> DMatrix<f64> {
+        let mut chosen = vec![sample_index(self.weights.as_slice(), rng)];
+        let mut closest = vec![f64::INFINITY; points.nrows()];
+        while chosen.len() < nodes {
+            let latest = points.select_rows([chosen[chosen.len() - 1]].iter());
+            for (row, distance) in closest.iter_mut().enumerate() {
+                *distance = distance.min(squared_distance(points, row, &latest, 0));
+            }
+            let scores: Vec<f64> = closest
+                .iter()
+                .zip(self.weights.iter())
+                .map(|(distance, weight)| weight * distance)
+                .collect();
+            if scores.iter().all(|score| *score == 0.0) {
+                break;
+            }
+            chosen.push(sample_index(&scores, rng));
+        }
+        points.select_rows(chosen.iter())
+    }
+
+    /// Kish effective sample size `(sum w)^2 / sum w^2` of the integration weights.
+    ///
+    /// Equals [`draw_count`](Self::draw_count) for uniform weights and falls towards one
+    /// as importance or empirical weights concentrate on a few agents. Draws are shared
+    /// across markets, so the same value applies to every market.
+    pub fn effective_sample_size(&self) -> f64 {
+        let sum: f64 = self.weights.iter().sum();
+        sum * sum / self.weights.norm_squared()
+    }
+}
+
+/// Index and squared distance of the center closest to `points[row]`.
+fn nearest_center(points: &DMatrix<f64>, row: usize, centers: &DMatrix<f64>) -> (usize, f64) {
+    (0..centers.nrows())
+        .map(|cluster| (cluster, squared_distance(points, row, centers, cluster)))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap_or((0, 0.0))
+}
+
+fn squared_distance(
+    points: &DMatrix<f64>,
+    row: usize,
+    centers: &DMatrix<f64>,
+    cluster: usize,
+) -> f64 {
+    (points.row(row) - centers.row(cluster)).norm_squared()
+}
+
+/// Draw an index with probability proportional to `scores`.
+fn sample_index(scores: &[f64], rng: &mut SmallRng) -> usize {
+    let total: f64 = scores.iter().sum();
+    let mut target = rng.r#gen::<f64>() * total;
+    for (index, score) in scores.iter().enumerate() {
+        target -= score;
+        if target <= 0.0 && *score > 0.0 {
+            return index;
+        }
+    }
+    scores.iter().rposition(|score| *score > 0.0).unwrap_or(0)
+}
+
+fn weighted_covariance(features: &DMatrix<f64>, weights: &DVector<f64>) -> DMatrix<f64> {
+    let means = features.transpose() * weights;
+    let centered = DMatrix::from_fn(features.nrows(), features.ncols(), |row, column| {
+        (features[(row, column)] - means[column]) * weights[row].sqrt()
+    });
+    centered.transpose() * centered
 }
 
 #[cfg(test)]
@@ -87,5 +576,86 @@ mod tests {
         assert_eq!(draws.dimension(), 2);
         let weights_sum: f64 = draws.weights.iter().sum();
         assert!((weights_sum - 1.0).abs() < 1e-10);
+        assert!((draws.effective_sample_size() - 128.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn concentrated_weights_reduce_effective_sample_size() {
+        let matrix = DMatrix::from_row_slice(4, 1, &[-1.0, 0.0, 1.0, 2.0]);
+        let weights = DVector::from_vec(vec![0.7, 0.1, 0.1, 0.1]);
+        let draws = SimulationDraws::new(matrix, weights).unwrap();
+        assert!((draws.effective_sample_size() - 1.0 / 0.52).abs() < 1e-12);
+    }
+
+    #[test]
+    fn reweighting_matches_demographic_targets() {
+        let draws = SimulationDraws::standard_normal(200, 1, 3);
+        let income = DMatrix::from_fn(200, 1, |i, _| 1.0 + draws.draws()[(i, 0)].abs());
+        let draws = draws.with_demographics(income).unwrap();
+        let target = draws.demographic_means().unwrap() * 1.1;
+
+        let reweighted = draws.reweight_to_means(&target).unwrap();
+        let means = reweighted.demographic_means().unwrap();
+        assert!((means[0] - target[0]).abs() < 1e-9);
+        assert!((reweighted.weights().sum() - 1.0).abs() < 1e-12);
+        assert!(reweighted.effective_sample_size() < 200.0);
+
+        let impossible = DVector::from_element(1, 100.0);
+        assert!(draws.reweight_to_means(&impossible).is_err());
+    }
+
+    #[test]
+    fn compression_preserves_means_and_weights() {
+        let draws = SimulationDraws::standard_normal(400, 1, 21);
+        let income = DMatrix::from_fn(400, 1, |i, _| (draws.draws()[(i, 0)] * 0.5).exp());
+        let draws = draws.with_demographics(income).unwrap();
+
+        let (compressed, report) = draws.compress(40, 4).unwrap();
+        assert!(compressed.draw_count() <= 40);
+        assert_eq!(report.original_agents, 400);
+        assert!((compressed.weights().sum() - 1.0).abs() < 1e-12);
+        let before = draws.demographic_means().unwrap();
+        let after = compressed.demographic_means().unwrap();
+        assert!((before[0] - after[0]).abs() < 1e-10);
+        assert!(report.within_variance_ratio < 0.05);
+        assert!(report.covariance_error < 0.05);
+    }
+
+    #[test]
+    fn parses_nodes_from_csv() {
+        let text = "nu0,nu1,weight\n-1.0,0.5,0.25\n1.0,-0.5,0.75\n";
+        let draws = SimulationDraws::parse_csv(text, WeightNormalization::Require).unwrap();
+        assert_eq!(draws.draw_count(), 2);
+        assert_eq!(draws.dimension(), 2);
+        assert_eq!(draws.draws()[(1, 1)], -0.5);
+        assert_eq!(draws.weights()[1], 0.75);
+
+        let unnormalized = "nu,weight\n-1.0,1.0\n1.0,3.0\n";
+        assert!(SimulationDraws::parse_csv(unnormalized, WeightNormalization::Require).is_err());
+        let rescaled =
+            SimulationDraws::parse_csv(unnormalized, WeightNormalization::Rescale).unwrap();
+        assert!((rescaled.weights()[1] - 0.75).abs() < 1e-12);
+    }
+
+    #[test]
+    fn enumerated_types_use_exact_frequencies() {
+        let nodes = DMatrix::from_row_slice(3, 1, &[-1.0, 0.0, 1.0]);
+        let draws = SimulationDraws::enumerated(nodes.clone(), vec![1, 2, 1]).unwrap();
+        assert_eq!(draws.weights().as_slice(), &[0.25, 0.5, 0.25]);
+        assert_eq!(draws.type_counts(), Some(&[1, 2, 1][..]));
+        assert_eq!(draws.seed(), None);
+
+        let assigned =
+            SimulationDraws::from_type_assignments(nodes.clone(), &[2, 1, 0, 1]).unwrap();
+        assert_eq!(assigned.weights(), draws.weights());
+        assert!(SimulationDraws::from_type_assignments(nodes.clone(), &[0, 1]).is_err());
+        assert!(SimulationDraws::from_type_assignments(nodes, &[3]).is_err());
+
+        let tilted = draws
+            .with_demographics(DMatrix::from_row_slice(3, 1, &[1.0, 2.0, 4.0]))
+            .unwrap()
+            .reweight_to_means(&DVector::from_element(1, 2.5))
+            .unwrap();
+        assert!(!tilted.is_enumerated());
     }
 }
@@ -0,0 +1,236 @@
+//! Latent-class (finite mixture) consumer heterogeneity.
+//!
+//! Consumers belong to one of `C` classes: class `c` makes up a share `pi_c` of every
+//! market and has random coefficients `b_c`, so `u_ijt = delta_jt + x2_jt' b_c +
+//! epsilon_ijt`. This is the random coefficients logit with the classes as integration
+//! nodes weighted by `pi` and `sigma` fixed at the identity, so share prediction,
+//! inversion, and the linear IV step are the same as for continuous heterogeneity; only
+//! the outer search runs over class locations and shares instead of `sigma`.
+
+use nalgebra::{DMatrix, DVector};
+
+use crate::error::{BlpError, Result};
+use crate::estimation::{Problem, ProblemResults};
+use crate::integration::SimulationDraws;
+use crate::models::RandomCoefficientsLogit;
+use crate::optimization::nelder_mead;
+
+/// Locations and population shares of a finite mixture of consumer classes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LatentClasses {
+    locations: DMatrix<f64>,
+    shares: DVector<f64>,
+}
+
+impl LatentClasses {
+    /// Class `c` has random coefficients `locations.row(c)` (one column per `X2`
+    /// characteristic) and population share `shares[c]`; shares must be positive and sum
+    /// to one.
+    pub fn new(locations: DMatrix<f64>, shares: DVector<f64>) -> Result<Self> {
+        SimulationDraws::new(locations.clone(), shares.clone())?;
+        Ok(Self { locations, shares })
+    }
+
+    /// Classes from an unconstrained parameter vector laid out as by
+    /// [`parameters`](Self::parameters).
+    pub fn from_parameters(values: &[f64], classes: usize, dimension: usize) -> Result<Self> {
+        let expected = classes * dimension + classes.saturating_sub(1);
+        if classes == 0 || values.len() != expected {
+            return Err(BlpError::dimension_mismatch(
+                "latent class parameters",
+                expected,
+                values.len(),
+            ));
+        }
+        let locations = DMatrix::from_row_slice(classes, dimension, &values[..classes * dimension]);
+        let logits = std::iter::once(0.0).chain(values[classes * dimension..].iter().copied());
+        let largest = logits.clone().fold(f64::NEG_INFINITY, f64::max);
+        let exponentials = DVector::from_iterator(classes, logits.map(|l| (l - largest).exp()));
+        let shares = &exponentials / exponentials.sum();
+        Self::new(locations, shares)
+    }
+
+    /// Locations in row-major order followed by the log odds `ln(pi_c / pi_0)` of every
+    /// class but the first, which an optimizer can search without constraints.
+    pub fn parameters(&self) -> Vec<f64> {
+        let mut values: Vec<f64> = self.locations.transpose().iter().copied().collect();
+        values.extend(
+            self.shares
+                .iter()
+                .skip(1)
+                .map(|share| (share / self.shares[0]).ln()),
+        );
+        values
+    }
+
+    /// Random coefficients of each class, one row per class.
+    pub fn locations(&self) -> &DMatrix<f64> {
+        &self.locations
+    }
+
+    /// Population share of each class.
+    pub fn shares(&self) -> &DVector<f64> {
+        &self.shares
+    }
+
+    /// Number of classes.
+    pub fn class_count(&self) -> usize {
+        self.shares.len()
+    }
+
+    /// The classes as integration nodes and weights.
+    pub fn draws(&self) -> Result<SimulationDraws> {
+        SimulationDraws::new(self.locations.clone(), self.shares.clone())
+    }
+}
+
+/// Outcome of [`Problem::estimate_latent_classes`].
+#[derive(Clone, Debug)]
+pub struct LatentClassEstimate {
+    /// Estimated class locations and shares.
+    pub classes: LatentClasses,
+    /// Solution at the estimated classes; its `sigma` is the identity.
+    pub results: ProblemResults,
+    /// Number of optimizer iterations.
+    pub iterations: usize,
+    /// Number of objective evaluations, including failed ones.
+    pub evaluations: usize,
+    /// Whether the termination tolerances were met.
+    pub converged: bool,
+}
+
+impl Problem {
+    /// Solve the inner loop and linear IV step with `classes` in place of the draws,
+    /// under the random coefficients logit.
+    pub fn solve_latent_classes(&self, classes: &LatentClasses) -> Result<ProblemResults> {
+        let k2 = self.data().nonlinear_dim();
+        if classes.locations().ncols() != k2 {
+            return Err(BlpError::dimension_mismatch(
+                "latent class location columns",
+                k2,
+                classes.locations().ncols(),
+            ));
+        }
+        self.with_draws_unchecked(classes.draws()?)
+            .with_model(RandomCoefficientsLogit)
+            .solve(&DMatrix::identity(k2, k2).into())
+    }
+
+    /// Estimate class locations and shares by minimizing the GMM objective from
+    /// `initial`, with the termination settings in
+    /// [`ProblemOptions::optimization`](crate::ProblemOptions::optimization) (its starting
+    /// `sigma` and bounds do not apply).
+    ///
+    /// Each class adds `K2 + 1` parameters, which need as many excluded instruments.
+    /// Classes are only identified up to relabelling.
+    pub fn estimate_latent_classes(&self, initial: &LatentClasses) -> Result<LatentClassEstimate> {
+        let k2 = self.data().nonlinear_dim();
+        let classes = initial.class_count();
+        let parameters = classes * k2 + classes - 1;
+        let excluded = self
+            .data()
+            .instrument_dim()
+            .saturating_sub(self.data().linear_dim());
+        if parameters > excluded {
+            return Err(BlpError::Underidentified {
+                instruments: excluded,
+                parameters,
+            });
+        }
+
+        let mut best: Option<(LatentClasses, ProblemResults)> = None;
+        let search = nelder_mead(
+            initial.parameters(),
+            &self.options().optimization,
+            |_| {},
+            |point| {
+                let solved =
+                    LatentClasses::from_parameters(point, classes, k2).and_then(|candidate| {
+                        let results = self.solve_latent_classes(&candidate)?;
+                        Ok((candidate, results))
+                    });
+                match solved {
+                    Ok((candidate, results)) => {
+                        let value = results.gmm_value;
+                        if best.as_ref().is_none_or(|(_, best)| value < best.gmm_value) {
+                            best = Some((candidate, results));
+                        }
+                        value
+                    }
+                    Err(err) => {
+                        log::debug!("infeasible latent classes: {err}");
+                        f64::INFINITY
+                    }
+                }
+            },
+        );
+        let (classes, results) = match best {
+            Some(best) => best,
+            None => {
+                self.solve_latent_classes(initial)?;
+                return Err(BlpError::missing_component("feasible latent classes"));
+            }
+        };
+        Ok(LatentClassEstimate {
+            classes,
+            results,
+            iterations: search.iterations,
+            evaluations: search.evaluations,
+            converged: search.converged,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+    use crate::parameters::NonlinearParameters;
+    use crate::testing::TestMarkets;
+
+    #[test]
+    fn latent_classes_rationalize_mixture_shares() {
+        let markets = TestMarkets::new(24);
+        let (x, w, x1) = (markets.x.clone(), markets.w.clone(), markets.x1().clone());
+        let markets = markets
+            .with_x1(x1, vec![1])
+            .with_instruments(DMatrix::from_fn(24, 7, |j, k| {
+                [
+                    1.0,
+                    x[j],
+                    w[j],
+                    x[j] * x[j],
+                    w[j] * x[j],
+                    x[j].powi(3),
+                    w[j] * w[j],
+                ][k]
+            }));
+        let truth = LatentClasses::new(
+            DMatrix::from_row_slice(2, 1, &[-1.0, 1.5]),
+            DVector::from_vec(vec![0.6, 0.4]),
+        )
+        .unwrap();
+        let round_trip = LatentClasses::from_parameters(&truth.parameters(), 2, 1).unwrap();
+        assert_relative_eq!(round_trip.shares(), truth.shares(), epsilon = 1e-12);
+
+        let delta = DVector::from_fn(24, |j, _| -1.0 + x[j] - w[j]);
+        let identity = NonlinearParameters::new(DMatrix::identity(1, 1));
+        let data = markets.simulate(&truth.draws().unwrap(), &identity, &delta);
+        let problem = Problem::new(data, SimulationDraws::standard_normal(10, 1, 1)).unwrap();
+
+        let exact = problem.solve_latent_classes(&truth).unwrap();
+        assert!(exact.gmm_value < 1e-12);
+        assert_relative_eq!(exact.beta[1], 1.0, epsilon = 1e-6);
+
+        let start = LatentClasses::new(
+            DMatrix::from_row_slice(2, 1, &[-0.5, 1.0]),
+            DVector::from_vec(vec![0.5, 0.5]),
+        )
+        .unwrap();
+        let initial = problem.solve_latent_classes(&start).unwrap().gmm_value;
+        let estimate = problem.estimate_latent_classes(&start).unwrap();
+        assert!(estimate.results.gmm_value < 0.01 * initial);
+        assert_eq!(estimate.classes.class_count(), 2);
+    }
+}
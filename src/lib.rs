@@ -4,10 +4,46 @@
 //! [pyBLP](https://github.com/jeffgortmaker/pyblp) while embracing idiomatic Rust.
 //! It offers tools to
 //!
-//! - manage product-level market data (`data` module),
-//! - describe simulation draws for heterogeneous consumers (`integration` module),
-//! - solve the BLP contraction mapping (`solving` module), and
-//! - assemble a two-step GMM estimator (`estimation` module).
+//! - manage product-level market data (`data` module) and pivot long-format
+//!   inputs into it (`ingest` module),
+//! - describe simulation draws for heterogeneous consumers (`integration` module) or
+//!   a finite mixture of consumer classes (`latent` module), and, experimentally, let
+//!   price sensitivity vary with income as in BLP (1995) (`income` module),
+//! - bundle `sigma`, demographic interactions `pi`, and the nesting parameter `rho`
+//!   into validated nonlinear parameters (`parameters` module),
+//! - solve the BLP contraction mapping (`solving` module) and reuse solutions at
+//!   revisited parameters (`cache` module),
+//! - assemble a two-step GMM estimator (`estimation` module), stack Bertrand–Nash
+//!   cost-side moments (`supply` module) and aggregate moments with observed targets
+//!   (`macro_moments` module) onto it, and, experimentally, add markets that only
+//!   report category-level shares (`aggregate` module),
+//! - compute elasticities with respect to any characteristic (`elasticities` module)
+//!   and diversion ratios (`diversion` module), and export them as tidy tables
+//!   (`export` module),
+//! - predict outcomes under counterfactual populations (`counterfactual` module),
+//! - chain markets over time into myopic dynamic demand objects (`dynamics` module),
+//! - simulate consumer-level data and choice-conditional demographics from estimates
+//!   (`micro` module),
+//! - split the inner loop into serializable per-market tasks (`distributed` module),
+//! - minimize the GMM objective over `sigma`, one block of parameters at a time if
+//!   needed, and prune negligible random coefficients (`optimization` module),
+//! - restart the search from perturbed starting values and flag local minima
+//!   (`multistart` module),
+//! - compute sandwich standard errors for `beta` and `sigma` and build
+//!   weak-identification-robust confidence sets for `sigma` (`inference` module),
+//! - build BLP and differentiation instruments from product characteristics and
+//!   approximate optimal instruments from first-stage estimates, or reduce a large
+//!   instrument set to a few combinations (`instruments` module),
+//! - evaluate the GMM moments and their Jacobian for external estimators (`moments`
+//!   module),
+//! - compare specifications by cross-validation over markets and cross-fit the
+//!   efficient weighting matrix (`validation` module),
+//! - re-estimate on noisy copies of the shares or instruments to gauge sensitivity
+//!   (`robustness` module),
+//! - print results as plain, Markdown, or LaTeX tables (`report` module),
+//! - call the estimator from C, MATLAB, Julia, or Stata through a C ABI (`capi`
+//!   module, behind the `capi` feature), and
+//! - stream intermediate results to disk during long runs (`progress` module).
 //!
 //! The implementation focuses on clarity and extensibility. Heavy inline
 //! documentation and unit tests illustrate the essential ingredients of BLP:
@@ -20,7 +56,7 @@
 //! ```no_run
 //! use blprs::data::{ProductData, ProductDataBuilder};
 //! use blprs::integration::SimulationDraws;
-//! use blprs::{Problem, ProblemOptions};
+//! use blprs::{NonlinearParameters, Problem, ProblemOptions};
 //! use nalgebra::{DMatrix, DVector};
 //!
 //! // Assume we have N products and K1 linear, K2 nonlinear characteristics.
@@ -45,25 +81,69 @@
 //!     .options(ProblemOptions::default())
 //!     .build()
 //!     .expect("well-formed problem");
-//! let sigma = DMatrix::from_row_slice(1, 1, &[2.0]);
+//! let parameters = NonlinearParameters::new(DMatrix::from_row_slice(1, 1, &[2.0]));
 //!
-//! let result = problem.solve(&sigma).expect("converged");
+//! let result = problem.solve(&parameters).expect("converged");
 //! println!("Estimated betas: {:?}", result.beta);
 //! ```
 //!
-//! The crate is still under heavy development. Supply-side estimation,
-//! optimal instruments, and many advanced `pyBLP` options are tracked in the
-//! public roadmap.
+//! The crate is still under heavy development. Many advanced `pyBLP` options are
+//! tracked in the public roadmap.
 
+pub mod aggregate;
+pub mod archive;
+#[cfg(feature = "bench")]
+pub mod bench;
+pub mod cache;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod counterfactual;
 pub mod data;
 pub mod demand;
+pub mod diagnostics;
+pub mod distributed;
+pub mod diversion;
+pub mod dynamics;
+pub mod elasticities;
 pub mod error;
 pub mod estimation;
+pub mod export;
 pub mod formulation;
+pub mod income;
+pub mod inference;
+pub mod ingest;
+pub mod instruments;
 pub mod integration;
+pub mod latent;
+pub mod linalg;
+pub mod macro_moments;
+pub mod micro;
+pub mod models;
+pub mod moments;
+pub mod multistart;
+pub mod optimization;
 pub mod options;
+mod parallel;
+pub mod parameters;
+pub mod progress;
+pub mod provenance;
+pub mod report;
+pub mod robustness;
 pub mod solving;
+pub mod statistics;
+pub mod supply;
+#[cfg(test)]
+mod testing;
+pub mod validation;
 
-pub use estimation::{BlpProblem, EstimationResult, Problem, ProblemBuilder, ProblemResults};
-pub use options::{EstimationOptions, GmmOptions, ProblemOptions, WeightingMatrix};
-pub use solving::{ContractionOptions, ContractionSummary};
+pub use estimation::{
+    BlpProblem, EstimationResult, GmmStep, LinearEstimator, Problem, ProblemBuilder, ProblemResults,
+};
+pub use models::{DemandModel, Logit, NestedLogit, PureCharacteristics, RandomCoefficientsLogit};
+pub use options::{
+    EstimationOptions, GmmOptions, LinearSolver, LowRankWeighting, MomentCovariance,
+    OptimizationOptions, ParallelismOptions, ParameterBounds, ProblemOptions, WeightingMatrix,
+};
+pub use parameters::{NonlinearParameters, ParameterMask};
+pub use progress::{ProgressFormat, ProgressOptions};
+pub use solving::{ContractionOptions, ContractionSummary, ConvergenceCriterion, ToleranceScaling};
@@ -0,0 +1,390 @@
+//! Small dense linear-algebra helpers shared by the estimation pipeline.
+//!
+//! Symmetric positive-definite factorizations (`Z'Z`, `X'ZWZ'X`, covariance matrices)
+//! go through [`cholesky_solve`], [`cholesky_inverse`], and [`cholesky_factor`]. By default they use
+//! nalgebra's pure-Rust kernels; enabling the `faer` feature routes them through
+//! [`faer`](https://docs.rs/faer), which is substantially faster once the number of
+//! instruments reaches the thousands.
+//!
+//! Inside estimation a failed Cholesky is replaced by the SVD [`pseudo_inverse`] unless
+//! [`GmmOptions::strict_factorizations`](crate::GmmOptions::strict_factorizations) is set.
+//! For huge instrument sets, [`low_rank_inverse`] approximates the inverse of a moment
+//! covariance from a randomized sketch without ever factoring it.
+
+use nalgebra::{DMatrix, DVector};
+use rand::SeedableRng;
+use rand::rngs::SmallRng;
+use rand_distr::{Distribution, StandardNormal};
+
+use crate::error::{BlpError, Result};
+use crate::options::LowRankWeighting;
+
+/// Name of the dense factorization backend selected at compile time.
+pub fn backend_name() -> &'static str {
+    if cfg!(feature = "faer") {
+        "faer"
+    } else {
+        "nalgebra"
+    }
+}
+
+/// Solves `matrix * x = rhs` for a symmetric positive-definite `matrix`.
+///
+/// Returns `None` when the Cholesky factorization fails.
+pub fn cholesky_solve(matrix: &DMatrix<f64>, rhs: &DVector<f64>) -> Option<DVector<f64>> {
+    #[cfg(feature = "faer")]
+    {
+        faer_backend::cholesky_solve(matrix, rhs)
+    }
+    #[cfg(not(feature = "faer"))]
+    {
+        nalgebra::linalg::Cholesky::new(matrix.clone()).map(|cholesky| cholesky.solve(rhs))
+    }
+}
+
+/// Inverts a symmetric positive-definite `matrix` through its Cholesky factor.
+///
+/// Returns `None` when the Cholesky factorization fails.
+pub fn cholesky_inverse(matrix: &DMatrix<f64>) -> Option<DMatrix<f64>> {
+    #[cfg(feature = "faer")]
+    {
+        faer_backend::cholesky_inverse(matrix)
+    }
+    #[cfg(not(feature = "faer"))]
+    {
+        nalgebra::linalg::Cholesky::new(matrix.clone()).map(|cholesky| cholesky.inverse())
+    }
+}
+
+/// The lower-triangular Cholesky factor `L` of a symmetric positive-definite `matrix`,
+/// with `matrix = LL'`.
+///
+/// Returns `None` when the Cholesky factorization fails.
+pub fn cholesky_factor(matrix: &DMatrix<f64>) -> Option<DMatrix<f64>> {
+    #[cfg(feature = "faer")]
+    {
+        faer_backend::cholesky_factor(matrix)
+    }
+    #[cfg(not(feature = "faer"))]
+    {
+        nalgebra::linalg::Cholesky::new(matrix.clone()).map(|cholesky| cholesky.l())
+    }
+}
+
+/// Moore–Penrose pseudo-inverse through the SVD, treating singular values below
+/// `sigma_max * max(rows, cols) * epsilon` as zero.
+///
+/// Returns `None` when the matrix contains non-finite entries.
+pub fn pseudo_inverse(matrix: &DMatrix<f64>) -> Option<DMatrix<f64>> {
+    if matrix.iter().any(|value| !value.is_finite()) {
+        return None;
+    }
+    if matrix.is_empty() {
+        return Some(DMatrix::zeros(matrix.ncols(), matrix.nrows()));
+    }
+    let svd = matrix.clone().svd(true, true);
+    let cutoff =
+        svd.singular_values.max() * matrix.nrows().max(matrix.ncols()) as f64 * f64::EPSILON;
+    svd.pseudo_inverse(cutoff).ok()
+}
+
+/// Pseudo-inverse of the best rank-`settings.rank` approximation of a symmetric positive
+/// semi-definite `dimension x dimension` matrix `S`, seen only through `apply(X) = S X`,
+/// and an estimate of the approximation's relative error `||S v - S_r v|| / ||S v||`
+/// on random probe vectors.
+///
+/// A randomized range finder (Halko, Martinsson, and Tropp, 2011) multiplies `S` by
+/// `rank + oversampling` Gaussian vectors, sharpened by `power_iterations` further
+/// products, and diagonalizes `S` on the span of the result. The cost is a few products
+/// with `S` plus `O(dimension * rank^2)`, against `O(dimension^3)` for a factorization.
+/// Eigenvalues below `lambda_max * dimension * epsilon` are dropped, so the rank can be
+/// lower than requested. Returns `None` when `S` is zero or not finite on the sketch.
+pub fn low_rank_inverse(
+    dimension: usize,
+    apply: impl Fn(&DMatrix<f64>) -> DMatrix<f64>,
+    settings: &LowRankWeighting,
+) -> Option<(DMatrix<f64>, f64)> {
+    const PROBES: usize = 4;
+    let mut rng = SmallRng::seed_from_u64(settings.seed);
+    let mut gaussian = |columns: usize| {
+        DMatrix::from_fn(dimension, columns, |_, _| StandardNormal.sample(&mut rng))
+    };
+    let width = (settings.rank + settings.oversampling).clamp(1, dimension.max(1));
+    let mut range = apply(&gaussian(width));
+    for _ in 0..settings.power_iterations {
+        range = apply(&range.qr().q());
+    }
+    let basis = range.qr().q();
+    let projected = basis.transpose() * apply(&basis);
+    let projected = (&projected + projected.transpose()) * 0.5;
+    if projected.iter().any(|value| !value.is_finite()) {
+        return None;
+    }
+    let eigen = projected.symmetric_eigen();
+    let mut order: Vec<usize> = (0..eigen.eigenvalues.len()).collect();
+    order.sort_by(|&a, &b| eigen.eigenvalues[b].total_cmp(&eigen.eigenvalues[a]));
+    let largest = eigen.eigenvalues[order[0]];
+    let cutoff = largest * dimension as f64 * f64::EPSILON;
+    order.retain(|&index| eigen.eigenvalues[index] > cutoff);
+    order.truncate(settings.rank);
+    if largest <= 0.0 || order.is_empty() {
+        return None;
+    }
+    let vectors = basis * eigen.eigenvectors.select_columns(order.iter());
+    let values = DVector::from_iterator(order.len(), order.iter().map(|&i| eigen.eigenvalues[i]));
+
+    let probes = gaussian(PROBES);
+    let exact = apply(&probes);
+    let approximate = &vectors * DMatrix::from_diagonal(&values) * (vectors.transpose() * &probes);
+    let error = (&exact - approximate).norm() / exact.norm().max(f64::MIN_POSITIVE);
+    let inverse =
+        &vectors * DMatrix::from_diagonal(&values.map(|value| 1.0 / value)) * vectors.transpose();
+    Some((inverse, error))
+}
+
+/// Symmetric positive-definite factorizations that fall back to the SVD when Cholesky
+/// fails, recording where they did.
+///
+/// In strict mode a failed Cholesky is a [`BlpError::SingularMatrix`] instead. Either way
+/// the failure is logged with the matrix's condition number.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Factorizations {
+    strict: bool,
+    fallbacks: Vec<String>,
+}
+
+impl Factorizations {
+    pub(crate) fn new(strict: bool) -> Self {
+        Self {
+            strict,
+            fallbacks: Vec::new(),
+        }
+    }
+
+    /// Never fall back, for callers outside an estimation run.
+    pub(crate) fn strict() -> Self {
+        Self::new(true)
+    }
+
+    /// Contexts of the factorizations that used the pseudo-inverse, in order.
+    pub(crate) fn into_fallbacks(self) -> Vec<String> {
+        self.fallbacks
+    }
+
+    /// Inverse of `matrix` through its Cholesky factor, or its pseudo-inverse.
+    pub(crate) fn inverse(
+        &mut self,
+        matrix: &DMatrix<f64>,
+        context: &'static str,
+    ) -> Result<DMatrix<f64>> {
+        match cholesky_inverse(matrix) {
+            Some(inverse) => Ok(inverse),
+            None => self.pseudo_inverse(matrix, context),
+        }
+    }
+
+    /// Solution of `matrix * x = rhs` through Cholesky, or the minimum-norm solution.
+    pub(crate) fn solve(
+        &mut self,
+        matrix: &DMatrix<f64>,
+        rhs: &DVector<f64>,
+        context: &'static str,
+    ) -> Result<DVector<f64>> {
+        match cholesky_solve(matrix, rhs) {
+            Some(solution) => Ok(solution),
+            None => Ok(self.pseudo_inverse(matrix, context)? * rhs),
+        }
+    }
+
+    /// A factor `L` with `matrix = LL'`: the Cholesky factor, or `V sqrt(max(lambda, 0))`
+    /// from the eigendecomposition of a positive semi-definite matrix.
+    pub(crate) fn factor(
+        &mut self,
+        matrix: &DMatrix<f64>,
+        context: &'static str,
+    ) -> Result<DMatrix<f64>> {
+        if let Some(factor) = cholesky_factor(matrix) {
+            return Ok(factor);
+        }
+        self.fall_back(matrix, context, "its eigendecomposition")?;
+        let eigen = matrix.clone().symmetric_eigen();
+        let roots = eigen.eigenvalues.map(|value| value.max(0.0).sqrt());
+        Ok(eigen.eigenvectors * DMatrix::from_diagonal(&roots))
+    }
+
+    fn pseudo_inverse(
+        &mut self,
+        matrix: &DMatrix<f64>,
+        context: &'static str,
+    ) -> Result<DMatrix<f64>> {
+        self.fall_back(matrix, context, "the SVD pseudo-inverse")?;
+        pseudo_inverse(matrix).ok_or(BlpError::NumericalError { context })
+    }
+
+    /// Fail in strict mode or on non-finite entries; otherwise record that the Cholesky
+    /// of `context` was replaced by `replacement`.
+    fn fall_back(
+        &mut self,
+        matrix: &DMatrix<f64>,
+        context: &'static str,
+        replacement: &str,
+    ) -> Result<()> {
+        let condition = condition_number(matrix);
+        if self.strict {
+            log::warn!("Cholesky of {context} failed; condition number {condition:.3e}");
+            return Err(BlpError::singular(context));
+        }
+        if matrix.iter().any(|value| !value.is_finite()) {
+            return Err(BlpError::NumericalError { context });
+        }
+        log::warn!(
+            "Cholesky of {context} failed (condition number {condition:.3e}); using {replacement}"
+        );
+        self.fallbacks.push(context.to_string());
+        Ok(())
+    }
+}
+
+/// Two-norm condition number `sigma_max / sigma_min` computed from the singular values.
+///
+/// Returns `f64::INFINITY` for singular or empty matrices and `NaN` when the matrix
+/// contains non-finite entries.
+pub fn condition_number(matrix: &DMatrix<f64>) -> f64 {
+    if matrix.is_empty() {
+        return f64::INFINITY;
+    }
+    if matrix.iter().any(|value| !value.is_finite()) {
+        return f64::NAN;
+    }
+    let singular_values = matrix.singular_values();
+    let max = singular_values.max();
+    let min = singular_values.min();
+    if min <= 0.0 { f64::INFINITY } else { max / min }
+}
+
+/// Solves the least-squares problem `min ||a x - b||` through a Householder QR of `a`.
+///
+/// Returns `None` when `a` is rank deficient (a zero pivot on the diagonal of `R`).
+pub fn least_squares_qr(a: &DMatrix<f64>, b: &DVector<f64>) -> Option<DVector<f64>> {
+    if a.nrows() < a.ncols() {
+        return None;
+    }
+    let qr = a.clone().qr();
+    let r = qr.r();
+    let scale = r.diagonal().amax().max(f64::MIN_POSITIVE);
+    if r.diagonal()
+        .iter()
+        .any(|pivot| pivot.abs() <= scale * f64::EPSILON * a.nrows() as f64)
+    {
+        return None;
+    }
+    let qtb = qr.q().transpose() * b;
+    r.solve_upper_triangular(&qtb)
+}
+
+/// Minimum-norm least-squares solution of `min ||a x - b||` through the SVD of `a`.
+///
+/// Singular values below `tolerance * sigma_max` are treated as zero.
+pub fn least_squares_svd(
+    a: &DMatrix<f64>,
+    b: &DVector<f64>,
+    tolerance: f64,
+) -> Option<DVector<f64>> {
+    let svd = a.clone().svd(true, true);
+    let cutoff = tolerance * svd.singular_values.max();
+    svd.solve(b, cutoff).ok()
+}
+
+#[cfg(feature = "faer")]
+mod faer_backend {
+    use faer::linalg::solvers::{DenseSolveCore, Solve};
+    use faer::{Mat, Side};
+    use nalgebra::{DMatrix, DVector};
+
+    fn to_faer(matrix: &DMatrix<f64>) -> Mat<f64> {
+        Mat::from_fn(matrix.nrows(), matrix.ncols(), |i, j| matrix[(i, j)])
+    }
+
+    pub(super) fn cholesky_solve(
+        matrix: &DMatrix<f64>,
+        rhs: &DVector<f64>,
+    ) -> Option<DVector<f64>> {
+        let llt = to_faer(matrix).llt(Side::Lower).ok()?;
+        let rhs = Mat::from_fn(rhs.len(), 1, |i, _| rhs[i]);
+        let solution = llt.solve(&rhs);
+        Some(DVector::from_fn(solution.nrows(), |i, _| solution[(i, 0)]))
+    }
+
+    pub(super) fn cholesky_inverse(matrix: &DMatrix<f64>) -> Option<DMatrix<f64>> {
+        let llt = to_faer(matrix).llt(Side::Lower).ok()?;
+        let inverse = llt.inverse();
+        Some(DMatrix::from_fn(
+            inverse.nrows(),
+            inverse.ncols(),
+            |i, j| inverse[(i, j)],
+        ))
+    }
+
+    pub(super) fn cholesky_factor(matrix: &DMatrix<f64>) -> Option<DMatrix<f64>> {
+        let llt = to_faer(matrix).llt(Side::Lower).ok()?;
+        let factor = llt.L();
+        Some(DMatrix::from_fn(factor.nrows(), factor.ncols(), |i, j| {
+            factor[(i, j)]
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn condition_number_of_diagonal_matrix() {
+        let matrix = DMatrix::from_diagonal(&nalgebra::DVector::from_vec(vec![4.0, 0.5, 2.0]));
+        assert_relative_eq!(condition_number(&matrix), 8.0, epsilon = 1e-12);
+        assert!(condition_number(&DMatrix::zeros(2, 2)).is_infinite());
+    }
+
+    #[test]
+    fn cholesky_helpers_agree_with_direct_inverse() {
+        let matrix = DMatrix::from_row_slice(2, 2, &[2.0, 0.5, 0.5, 2.0]);
+        let rhs = DVector::from_vec(vec![1.0, 2.0]);
+        let inverse = cholesky_inverse(&matrix).unwrap();
+        let solution = cholesky_solve(&matrix, &rhs).unwrap();
+        assert_relative_eq!(&inverse * &rhs, solution, epsilon = 1e-12);
+        assert_relative_eq!(&matrix * inverse, DMatrix::identity(2, 2), epsilon = 1e-12);
+        let factor = cholesky_factor(&matrix).unwrap();
+        assert_eq!(factor[(0, 1)], 0.0);
+        assert_relative_eq!(&factor * factor.transpose(), matrix, epsilon = 1e-12);
+        assert!(cholesky_inverse(&DMatrix::zeros(2, 2)).is_none());
+        assert!(cholesky_factor(&DMatrix::zeros(2, 2)).is_none());
+    }
+
+    #[test]
+    fn factorizations_fall_back_to_the_pseudo_inverse_unless_strict() {
+        let singular = DMatrix::from_row_slice(2, 2, &[1.0, 1.0, 1.0, 1.0]);
+        let mut factorizations = Factorizations::new(false);
+        let inverse = factorizations.inverse(&singular, "test matrix").unwrap();
+        assert_relative_eq!(&singular * &inverse * &singular, singular, epsilon = 1e-12);
+        let factor = factorizations.factor(&singular, "test matrix").unwrap();
+        assert_relative_eq!(&factor * factor.transpose(), singular, epsilon = 1e-12);
+        assert_eq!(factorizations.into_fallbacks().len(), 2);
+
+        let error = Factorizations::strict().inverse(&singular, "test matrix");
+        assert!(matches!(error, Err(BlpError::SingularMatrix { .. })));
+    }
+
+    #[test]
+    fn least_squares_paths_agree() {
+        let a = DMatrix::from_row_slice(4, 2, &[1.0, 0.0, 1.0, 1.0, 1.0, 2.0, 1.0, 3.0]);
+        let b = DVector::from_vec(vec![1.0, 3.0, 5.1, 6.9]);
+        let normal = cholesky_solve(&(a.transpose() * &a), &(a.transpose() * &b)).unwrap();
+        assert_relative_eq!(least_squares_qr(&a, &b).unwrap(), normal, epsilon = 1e-10);
+        assert_relative_eq!(
+            least_squares_svd(&a, &b, 1e-12).unwrap(),
+            normal,
+            epsilon = 1e-10
+        );
+    }
+}
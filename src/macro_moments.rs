@@ -0,0 +1,325 @@
+//! Aggregate (macro) moments stacked into the GMM objective alongside `E[Z' xi] = 0`.
+//!
+//! A [`MacroMoment`] matches a market-level statistic of the estimated model, such as the
+//! inside share of high-income consumers or the average price paid by them in one
+//! region, to an observed target with a known sampling variance. Attached with
+//! [`Problem::with_macro_moments`], every solve adds
+//! `sum_k (model_k - target_k)^2 / variance_k` to the objective, so the moments pin down
+//! `sigma` and `pi` where the instruments alone are weak. `beta` is still concentrated
+//! out on the demand moments, which the macro moments do not depend on given `delta`.
+
+use std::sync::Arc;
+
+use nalgebra::{DMatrix, DVector};
+use serde::{Deserialize, Serialize};
+
+use crate::demand::{ShareInputs, individual_shares};
+use crate::error::{BlpError, Result};
+use crate::estimation::{Problem, ProblemResults};
+
+/// The simulated consumers a statistic averages over.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum AgentGroup {
+    /// Every consumer.
+    All,
+    /// Consumers whose demographic `column` lies in `[lower, upper)`.
+    Demographic {
+        /// Column of the draws' demographics.
+        column: usize,
+        /// Inclusive lower edge.
+        lower: f64,
+        /// Exclusive upper edge.
+        upper: f64,
+    },
+}
+
+impl AgentGroup {
+    fn contains(&self, demographics: Option<&DMatrix<f64>>, agent: usize) -> bool {
+        match (self, demographics) {
+            (Self::All, _) => true,
+            (
+                Self::Demographic {
+                    column,
+                    lower,
+                    upper,
+                },
+                Some(demographics),
+            ) => (*lower..*upper).contains(&demographics[(agent, *column)]),
+            (Self::Demographic { .. }, None) => false,
+        }
+    }
+}
+
+/// Model statistic matched by a [`MacroMoment`], pooled over the selected markets.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum MacroStatistic {
+    /// Probability that a consumer in the group buys any inside good.
+    InsideShare(AgentGroup),
+    /// Mean of `values` (one per product, e.g. prices) over the purchases of the group.
+    MeanCharacteristic {
+        /// Value of every product, in product-data row order.
+        values: DVector<f64>,
+        /// Consumers whose purchases are averaged.
+        group: AgentGroup,
+    },
+}
+
+/// An aggregate moment with its observed target and sampling variance.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MacroMoment {
+    /// Name used in results.
+    pub label: String,
+    /// What the model predicts.
+    pub statistic: MacroStatistic,
+    /// Markets the statistic pools over; `None` for every market.
+    pub markets: Option<Vec<String>>,
+    /// Observed value.
+    pub target: f64,
+    /// Sampling variance of the observed value.
+    pub variance: f64,
+}
+
+impl MacroMoment {
+    /// Match `statistic` over every market to `target`, observed with `variance`.
+    pub fn new(
+        label: impl Into<String>,
+        statistic: MacroStatistic,
+        target: f64,
+        variance: f64,
+    ) -> Self {
+        Self {
+            label: label.into(),
+            statistic,
+            markets: None,
+            target,
+            variance,
+        }
+    }
+
+    /// Pool the statistic over `markets` only, e.g. the markets of one region.
+    pub fn in_markets(mut self, markets: Vec<String>) -> Self {
+        self.markets = Some(markets);
+        self
+    }
+}
+
+/// Model values of the macro moments and their contribution to the objective.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MacroMomentResults {
+    /// Moment labels, in the order they were attached.
+    pub labels: Vec<String>,
+    /// Model value of every statistic.
+    pub values: DVector<f64>,
+    /// `values - targets`.
+    pub residuals: DVector<f64>,
+    /// `sum_k residual_k^2 / variance_k`, added to the GMM objective.
+    pub gmm_value: f64,
+}
+
+impl Problem {
+    /// Attach aggregate moments that every solve evaluates and adds to the GMM objective.
+    ///
+    /// The moments are treated as independent of each other and of the demand moments.
+    /// The model statistics use random coefficients logit choice probabilities. Sandwich
+    /// standard errors still come from the demand moments alone. Fails when a variance
+    /// is not positive, a demographic group refers to a missing column, `values` has the
+    /// wrong length, or a market is unknown.
+    pub fn with_macro_moments(mut self, moments: Vec<MacroMoment>) -> Result<Self> {
+        let data = self.data();
+        let demographic_columns = self.draws().demographics().map_or(0, |d| d.ncols());
+        let mut mismatches = Vec::new();
+        for moment in &moments {
+            let label = &moment.label;
+            if !(moment.variance > 0.0 && moment.variance.is_finite()) {
+                mismatches.push(format!("macro moment `{label}` needs a positive variance"));
+            }
+            let (group, values) = match &moment.statistic {
+                MacroStatistic::InsideShare(group) => (group, None),
+                MacroStatistic::MeanCharacteristic { values, group } => (group, Some(values)),
+            };
+            if let AgentGroup::Demographic { column, .. } = group
+                && *column >= demographic_columns
+            {
+                mismatches.push(format!(
+                    "macro moment `{label}` uses demographic {column} but the draws have \
+                     {demographic_columns}"
+                ));
+            }
+            if let Some(values) = values
+                && values.len() != data.product_count()
+            {
+                mismatches.push(format!(
+                    "macro moment `{label}` has {} values for {} products",
+                    values.len(),
+                    data.product_count()
+                ));
+            }
+            for market in moment.markets.iter().flatten() {
+                if !data.market_ids().contains(market) {
+                    mismatches.push(format!(
+                        "macro moment `{label}` refers to unknown market `{market}`"
+                    ));
+                }
+            }
+        }
+        if !mismatches.is_empty() {
+            return Err(BlpError::InconsistentSpecification { mismatches });
+        }
+        self.set_macro_moments(Arc::new(moments));
+        Ok(self)
+    }
+
+    /// Model values of the attached macro moments at the estimates in `results`.
+    pub fn macro_moment_results(&self, results: &ProblemResults) -> Result<MacroMomentResults> {
+        let data = self.data();
+        let draws = self.draws();
+        let parameters = results.parameters();
+        let inputs =
+            ShareInputs::for_parameters(data, draws, &parameters, &self.options().contraction);
+        let probabilities = individual_shares(&results.delta, &inputs)?;
+        let demographics = draws.demographics();
+        let moments = self.macro_moments();
+
+        let mut values = DVector::zeros(moments.len());
+        for (index, moment) in moments.iter().enumerate() {
+            let (group, characteristic) = match &moment.statistic {
+                MacroStatistic::InsideShare(group) => (group, None),
+                MacroStatistic::MeanCharacteristic { values, group } => (group, Some(values)),
+            };
+            let (mut numerator, mut denominator) = (0.0, 0.0);
+            for market in data.partition().markets() {
+                if let Some(markets) = &moment.markets
+                    && !markets.iter().any(|id| id == market.id())
+                {
+                    continue;
+                }
+                for (agent, weight) in draws.weights().iter().enumerate() {
+                    if !group.contains(demographics, agent) {
+                        continue;
+                    }
+                    let inside: f64 = market.range().map(|row| probabilities[(row, agent)]).sum();
+                    match characteristic {
+                        None => {
+                            numerator += weight * inside;
+                            denominator += weight;
+                        }
+                        Some(values) => {
+                            numerator += weight
+                                * market
+                                    .range()
+                                    .map(|row| probabilities[(row, agent)] * values[row])
+                                    .sum::<f64>();
+                            denominator += weight * inside;
+                        }
+                    }
+                }
+            }
+            if denominator <= 0.0 {
+                return Err(BlpError::InconsistentSpecification {
+                    mismatches: vec![format!(
+                        "macro moment `{}` averages over no consumers",
+                        moment.label
+                    )],
+                });
+            }
+            values[index] = numerator / denominator;
+        }
+
+        let targets = DVector::from_iterator(moments.len(), moments.iter().map(|m| m.target));
+        let residuals = &values - targets;
+        let gmm_value = residuals
+            .iter()
+            .zip(moments.iter())
+            .map(|(residual, moment)| residual * residual / moment.variance)
+            .sum();
+        Ok(MacroMomentResults {
+            labels: moments.iter().map(|moment| moment.label.clone()).collect(),
+            values,
+            residuals,
+            gmm_value,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::ProductDataBuilder;
+    use crate::integration::SimulationDraws;
+    use crate::parameters::NonlinearParameters;
+
+    #[test]
+    fn macro_moments_add_to_the_objective() {
+        let market_ids = ["a", "a", "b", "b"].map(String::from).to_vec();
+        let prices = DVector::from_vec(vec![1.0, 2.0, 1.5, 3.0]);
+        let x1 = DMatrix::from_fn(4, 2, |j, k| if k == 0 { 1.0 } else { prices[j] });
+        let data =
+            ProductDataBuilder::new(market_ids, DVector::from_vec(vec![0.2, 0.3, 0.25, 0.1]))
+                .x1(x1)
+                .x2_from_x1(vec![1])
+                .instruments(DMatrix::from_fn(4, 3, |j, k| {
+                    (j as f64 + 1.0).powi(k as i32)
+                }))
+                .build()
+                .unwrap();
+        let demographics = DMatrix::from_fn(6, 1, |i, _| i as f64);
+        let draws = SimulationDraws::standard_normal(6, 1, 3)
+            .with_demographics(demographics)
+            .unwrap();
+        let problem = Problem::new(data, draws).unwrap();
+        let parameters = NonlinearParameters::new(DMatrix::from_element(1, 1, 0.5));
+        let baseline = problem.solve(&parameters).unwrap();
+
+        let rich = AgentGroup::Demographic {
+            column: 0,
+            lower: 3.0,
+            upper: f64::INFINITY,
+        };
+        let moments = vec![
+            MacroMoment::new(
+                "inside",
+                MacroStatistic::InsideShare(AgentGroup::All),
+                0.5,
+                0.01,
+            ),
+            MacroMoment::new(
+                "rich price in b",
+                MacroStatistic::MeanCharacteristic {
+                    values: prices.clone(),
+                    group: rich,
+                },
+                2.0,
+                0.04,
+            )
+            .in_markets(vec!["b".into()]),
+        ];
+        let stacked = problem.clone().with_macro_moments(moments).unwrap();
+        let results = stacked.solve(&parameters).unwrap();
+        let macros = results.macro_moments.as_ref().unwrap();
+
+        // Inside shares pool the observed shares, which the contraction matches.
+        assert!((macros.values[0] - 0.425).abs() < 1e-8);
+        assert!((1.5..=3.0).contains(&macros.values[1]));
+        let expected =
+            (macros.values[0] - 0.5).powi(2) / 0.01 + (macros.values[1] - 2.0).powi(2) / 0.04;
+        assert!((macros.gmm_value - expected).abs() < 1e-12);
+        assert!((results.gmm_value - baseline.gmm_value - expected).abs() < 1e-8);
+        // The sandwich covers only the demand moments, so it is refused.
+        assert!(matches!(
+            stacked.parameter_covariance(&results),
+            Err(BlpError::Unsupported { .. })
+        ));
+
+        let bad = MacroMoment::new(
+            "bad",
+            MacroStatistic::InsideShare(AgentGroup::All),
+            0.5,
+            0.0,
+        )
+        .in_markets(vec!["z".into()]);
+        let err = problem.with_macro_moments(vec![bad]).unwrap_err();
+        assert!(
+            matches!(err, BlpError::InconsistentSpecification { mismatches } if mismatches.len() == 2)
+        );
+    }
+}
@@ -0,0 +1,421 @@
+//! Consumer-level (micro) data: simulation from estimated demand and the micro-data
+//! likelihood.
+
+use nalgebra::{DMatrix, DVector};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+
+use crate::demand::{ShareInputs, individual_shares, invert_model, taste_slopes};
+use crate::error::{BlpError, Result};
+use crate::estimation::{Problem, ProblemResults, efficient_weighting};
+use crate::linalg::{Factorizations, cholesky_inverse};
+
+/// One simulated consumer: their type and their choice.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MicroRecord {
+    /// Market the consumer shops in.
+    pub market_id: String,
+    /// Index of the consumer's type among the problem's draws (row of the draw and
+    /// demographic matrices).
+    pub agent: usize,
+    /// Row of the chosen product in the product data, or `None` for the outside good.
+    pub choice: Option<usize>,
+}
+
+/// A simulated consumer-level data set.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MicroData {
+    /// Simulated consumers, grouped by market in partition order.
+    pub records: Vec<MicroRecord>,
+}
+
+impl MicroData {
+    /// Number of simulated consumers.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Whether no consumers were simulated.
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Number of consumers choosing each product (rows of the product data).
+    pub fn choice_counts(&self, products: usize) -> Vec<usize> {
+        let mut counts = vec![0; products];
+        for record in &self.records {
+            if let Some(choice) = record.choice {
+                counts[choice] += 1;
+            }
+        }
+        counts
+    }
+}
+
+/// Expected demographics of the consumers who choose each alternative.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConditionalDemographics {
+    /// `E[d | chose j]`, one row per product and one column per demographic.
+    pub products: DMatrix<f64>,
+    /// `E[d | chose the outside good]`, one row per market.
+    pub outside: DMatrix<f64>,
+}
+
+impl ProblemResults {
+    /// Expected demographics conditional on each choice, `E[d | j] = sum_i w_i p_ij d_i /
+    /// sum_i w_i p_ij`, at the estimated `delta` and `sigma`.
+    ///
+    /// These are the model counterparts of the usual micro moments (e.g. average income of
+    /// buyers of each product). Requires demographics on the problem's draws.
+    pub fn conditional_demographics(&self, problem: &Problem) -> Result<ConditionalDemographics> {
+        let data = problem.data();
+        let draws = problem.draws();
+        let demographics = draws
+            .demographics()
+            .ok_or_else(|| BlpError::missing_component("demographics"))?;
+        let inputs = ShareInputs::new(data, draws, &self.sigma, &problem.options().contraction);
+        let probabilities = individual_shares(&self.delta, &inputs)?;
+
+        // Column i of the weighted probabilities is w_i * p_i.
+        let mut weighted = probabilities;
+        for (mut column, weight) in weighted.column_iter_mut().zip(draws.weights().iter()) {
+            column *= *weight;
+        }
+        let mut products = &weighted * demographics;
+        for (mut row, total) in products.row_iter_mut().zip(weighted.column_sum().iter()) {
+            row /= *total;
+        }
+
+        let markets = data.partition();
+        let mut outside = DMatrix::zeros(markets.market_count(), demographics.ncols());
+        for (market_index, market) in markets.markets().enumerate() {
+            let mut total = 0.0;
+            for (agent, weight) in draws.weights().iter().enumerate() {
+                let inside: f64 = market.range().map(|row| weighted[(row, agent)]).sum();
+                let mass = weight - inside;
+                total += mass;
+                for column in 0..demographics.ncols() {
+                    outside[(market_index, column)] += mass * demographics[(agent, column)];
+                }
+            }
+            outside.row_mut(market_index).unscale_mut(total);
+        }
+
+        Ok(ConditionalDemographics { products, outside })
+    }
+
+    /// Draw `n_consumers` consumers in every market from the estimated model.
+    ///
+    /// Each consumer's type is drawn from the problem's agents according to their
+    /// integration weights, and their choice from that type's logit probabilities at the
+    /// estimated `delta` and `sigma`. Useful for posterior predictive checks against
+    /// observed micro data and for building micro-moment targets in simulations.
+    pub fn simulate_micro_data(
+        &self,
+        problem: &Problem,
+        n_consumers: usize,
+        seed: u64,
+    ) -> Result<MicroData> {
+        let data = problem.data();
+        let draws = problem.draws();
+        let inputs = ShareInputs::new(data, draws, &self.sigma, &problem.options().contraction);
+        let probabilities = individual_shares(&self.delta, &inputs)?;
+        let weights = draws.weights();
+
+        let mut rng = SmallRng::seed_from_u64(seed);
+        let mut records = Vec::with_capacity(n_consumers * data.partition().market_count());
+        for market in data.partition().markets() {
+            for _ in 0..n_consumers {
+                // Weights sum to one up to rounding; never step past the last agent.
+                let agent =
+                    sample(weights.iter().copied(), rng.r#gen::<f64>()).min(weights.len() - 1);
+                let inside = market.range().map(|row| probabilities[(row, agent)]);
+                // Index `J_t` is past the last inside product, i.e. the outside good.
+                let pick = sample(inside, rng.r#gen::<f64>());
+                let choice = (pick < market.product_count()).then(|| market.range().start + pick);
+                records.push(MicroRecord {
+                    market_id: market.id().to_string(),
+                    agent,
+                    choice,
+                });
+            }
+        }
+        Ok(MicroData { records })
+    }
+}
+
+/// Log-likelihood of a consumer choice sample and its per-consumer scores.
+#[derive(Clone, Debug)]
+pub struct MicroLikelihood {
+    /// Sum of log choice probabilities.
+    pub log_likelihood: f64,
+    /// `d ln P(choice_i) / d vec(sigma)`, one row per consumer, with `delta` re-solved
+    /// as `sigma` changes.
+    pub scores: DMatrix<f64>,
+}
+
+/// Aggregate GMM moments and micro-data likelihood scores combined in one objective.
+#[derive(Clone, Debug)]
+pub struct HybridObjective {
+    /// `m' W m` for the stacked moments `m = [g; mean score]`.
+    pub value: f64,
+    /// Aggregate moments `Z' xi / N`.
+    pub macro_moments: DVector<f64>,
+    /// Mean score of the micro sample.
+    pub micro_scores: DVector<f64>,
+    /// Log-likelihood of the micro sample.
+    pub log_likelihood: f64,
+}
+
+impl Problem {
+    /// Log-likelihood of observed choices `micro` at `sigma`, with `delta` from the
+    /// contraction so that aggregate shares are matched exactly.
+    ///
+    /// Each record's `agent` is the consumer's type among the problem's draws, as in data
+    /// simulated by [`ProblemResults::simulate_micro_data`] or survey respondents matched
+    /// to integration nodes.
+    pub fn micro_likelihood(
+        &self,
+        sigma: &DMatrix<f64>,
+        micro: &MicroData,
+    ) -> Result<MicroLikelihood> {
+        let data = self.data();
+        let draws = self.draws();
+        let k2 = data.nonlinear_dim();
+        let inputs = ShareInputs::new(data, draws, sigma, &self.options().contraction);
+        let (delta, _) = invert_model(self.model(), &inputs)?;
+        let probabilities = individual_shares(&delta, &inputs)?;
+        let delta_jacobian = self.delta_jacobian(&delta, &inputs)?;
+        let slopes = taste_slopes(&inputs);
+        let markets: Vec<_> = data.partition().markets().collect();
+
+        let mut log_likelihood = 0.0;
+        let mut scores = DMatrix::zeros(micro.len(), k2 * k2);
+        for (index, record) in micro.records.iter().enumerate() {
+            let market = markets
+                .iter()
+                .find(|market| market.id() == record.market_id)
+                .ok_or_else(|| BlpError::UnknownMarket {
+                    market_id: record.market_id.clone(),
+                })?;
+            let range = market.range();
+            if record.agent >= draws.draw_count() {
+                return Err(BlpError::dimension_mismatch(
+                    "micro record agent",
+                    draws.draw_count(),
+                    record.agent + 1,
+                ));
+            }
+            if let Some(choice) = record.choice
+                && !range.contains(&choice)
+            {
+                return Err(BlpError::dimension_mismatch(
+                    "micro record choice",
+                    range.end,
+                    choice + 1,
+                ));
+            }
+
+            // du_m / dsigma_kl = x2_mk nu_l + d delta_m / dsigma_kl, with the taste term
+            // scaled by the consumer's coefficient when it is lognormal.
+            let utility_derivative = |row: usize, column: usize| {
+                let (l, k) = (column / k2, column % k2);
+                let slope = slopes
+                    .as_ref()
+                    .map_or(1.0, |slopes| slopes[(k, record.agent)]);
+                data.x2()[(row, k)] * draws.draws()[(record.agent, l)] * slope
+                    + delta_jacobian[(row, column)]
+            };
+            let inside: f64 = range
+                .clone()
+                .map(|row| probabilities[(row, record.agent)])
+                .sum();
+            log_likelihood += match record.choice {
+                Some(choice) => probabilities[(choice, record.agent)].ln(),
+                None => (1.0 - inside).ln(),
+            };
+            for column in 0..k2 * k2 {
+                let mean: f64 = range
+                    .clone()
+                    .map(|row| probabilities[(row, record.agent)] * utility_derivative(row, column))
+                    .sum();
+                let own = record
+                    .choice
+                    .map_or(0.0, |choice| utility_derivative(choice, column));
+                scores[(index, column)] = own - mean;
+            }
+        }
+
+        Ok(MicroLikelihood {
+            log_likelihood,
+            scores,
+        })
+    }
+
+    /// Joint objective over aggregate moments and a micro choice sample at
+    /// `theta = [beta; vec(sigma)]`.
+    ///
+    /// The moment blocks are treated as independent and each is weighted by the inverse
+    /// of its estimated sampling variance: `N (Z' diag(xi^2) Z / N)^{-1}` for the
+    /// aggregate moments and `n (S'S / n)^{-1}` for the mean score of the `n` consumers.
+    /// Both blocks are thus on the chi-squared scale, so neither dominates because of
+    /// units or sample size.
+    pub fn hybrid_objective(
+        &self,
+        theta: &DVector<f64>,
+        micro: &MicroData,
+    ) -> Result<HybridObjective> {
+        let data = self.data();
+        let k1 = data.linear_dim();
+        let k2 = data.nonlinear_dim();
+        let moments = self.moments(theta)?;
+        let sigma = DMatrix::from_column_slice(k2, k2, &theta.as_slice()[k1..]);
+        let likelihood = self.micro_likelihood(&sigma, micro)?;
+
+        let n = data.product_count() as f64;
+        let gmm = &self.options().gmm;
+        let macro_weight = efficient_weighting(
+            data,
+            &moments.xi,
+            gmm.moment_covariance,
+            &mut Factorizations::new(gmm.strict_factorizations),
+        )? * (n * n);
+
+        let consumers = micro.len() as f64;
+        let scores = &likelihood.scores;
+        let micro_scores = scores.row_mean().transpose();
+        let micro_covariance = scores.transpose() * scores / consumers;
+        let micro_weight = cholesky_inverse(&micro_covariance)
+            .ok_or_else(|| BlpError::singular("micro score covariance"))?
+            * consumers;
+
+        let value = moments.values.dot(&(&macro_weight * &moments.values))
+            + micro_scores.dot(&(&micro_weight * &micro_scores));
+        Ok(HybridObjective {
+            value,
+            macro_moments: moments.values,
+            micro_scores,
+            log_likelihood: likelihood.log_likelihood,
+        })
+    }
+}
+
+/// Index at which the running sum of `probabilities` first exceeds `uniform`; one past
+/// the end when the probabilities sum to less than `uniform`.
+fn sample(probabilities: impl Iterator<Item = f64>, uniform: f64) -> usize {
+    let mut cumulative = 0.0;
+    let mut count = 0;
+    for (index, probability) in probabilities.enumerate() {
+        cumulative += probability;
+        count = index + 1;
+        if uniform < cumulative {
+            return index;
+        }
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::{DMatrix, DVector};
+
+    use super::*;
+    use crate::data::ProductDataBuilder;
+    use crate::integration::SimulationDraws;
+    use crate::parameters::NonlinearParameters;
+
+    #[test]
+    fn simulated_choices_match_predicted_shares() {
+        let market_ids = vec!["m1".to_string(), "m1".to_string()];
+        let shares = DVector::from_vec(vec![0.2, 0.5]);
+        let x1 = DMatrix::from_row_slice(2, 1, &[1.0, 2.0]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1.clone())
+            .x2(x1)
+            .build()
+            .unwrap();
+        let problem = Problem::new(data, SimulationDraws::standard_normal(30, 1, 2)).unwrap();
+        let results = problem
+            .solve(&NonlinearParameters::new(DMatrix::from_element(1, 1, 0.5)))
+            .unwrap();
+
+        let micro = results.simulate_micro_data(&problem, 20_000, 9).unwrap();
+        assert_eq!(micro.len(), 20_000);
+        assert!(micro.records.iter().all(|record| record.agent < 30));
+        let counts = micro.choice_counts(2);
+        for (count, share) in counts.iter().zip(results.predicted_shares.iter()) {
+            assert!((*count as f64 / 20_000.0 - share).abs() < 0.015);
+        }
+    }
+
+    #[test]
+    fn buyers_of_high_x_products_have_high_taste() {
+        let market_ids = vec!["m1".to_string(), "m1".to_string()];
+        let shares = DVector::from_vec(vec![0.2, 0.3]);
+        let x1 = DMatrix::from_row_slice(2, 1, &[-1.0, 1.0]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1.clone())
+            .x2(x1)
+            .build()
+            .unwrap();
+        let draws = SimulationDraws::standard_normal(200, 1, 4);
+        let taste = draws.draws().clone();
+        let draws = draws.with_demographics(taste).unwrap();
+        let problem = Problem::new(data, draws.clone()).unwrap();
+        let results = problem
+            .solve(&NonlinearParameters::new(DMatrix::from_element(1, 1, 1.0)))
+            .unwrap();
+
+        let conditional = results.conditional_demographics(&problem).unwrap();
+        assert!(conditional.products[(1, 0)] > conditional.products[(0, 0)]);
+
+        // Choice-weighted averages recover the unconditional mean.
+        let s = &results.predicted_shares;
+        let outside = 1.0 - s.sum();
+        let recombined = s[0] * conditional.products[(0, 0)]
+            + s[1] * conditional.products[(1, 0)]
+            + outside * conditional.outside[(0, 0)];
+        let mean = draws.demographic_means().unwrap()[0];
+        assert!((recombined - mean).abs() < 1e-10);
+    }
+
+    #[test]
+    fn micro_scores_match_likelihood_finite_differences() {
+        let market_ids = ["m1", "m1", "m2", "m2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.25, 0.15]);
+        let x1 = DMatrix::from_row_slice(4, 2, &[1.0, 1.0, 1.0, 2.0, 1.0, 1.5, 1.0, 0.5]);
+        let z = DMatrix::from_row_slice(
+            4,
+            3,
+            &[1.0, 0.5, 0.2, 1.0, 2.5, 0.1, 1.0, 1.0, 0.7, 1.0, 0.3, 0.4],
+        );
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1.clone())
+            .x2(x1.columns(1, 1).into_owned())
+            .instruments(z)
+            .build()
+            .unwrap();
+        let problem = Problem::new(data, SimulationDraws::standard_normal(30, 1, 6)).unwrap();
+        let sigma = DMatrix::from_element(1, 1, 0.9);
+        let micro = problem
+            .solve(&NonlinearParameters::new(sigma.clone()))
+            .unwrap()
+            .simulate_micro_data(&problem, 25, 3)
+            .unwrap();
+
+        let likelihood = problem.micro_likelihood(&sigma, &micro).unwrap();
+        let step = 1e-6;
+        let bumped = problem
+            .micro_likelihood(&sigma.add_scalar(step), &micro)
+            .unwrap();
+        let numeric = (bumped.log_likelihood - likelihood.log_likelihood) / step;
+        assert!((likelihood.scores.sum() - numeric).abs() < 1e-4 * numeric.abs().max(1.0));
+
+        let theta = DVector::from_vec(vec![-1.0, 0.5, 0.9]);
+        let hybrid = problem.hybrid_objective(&theta, &micro).unwrap();
+        assert_eq!(hybrid.macro_moments.len(), 3);
+        assert_eq!(hybrid.micro_scores.len(), 1);
+        assert!(hybrid.value > 0.0);
+        assert!((hybrid.log_likelihood - likelihood.log_likelihood).abs() < 1e-10);
+    }
+}
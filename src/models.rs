@@ -0,0 +1,707 @@
+//! Demand model variants behind a common [`DemandModel`] trait.
+//!
+//! The estimation pipeline only needs three operations from a demand model: predicted
+//! shares at a given `delta`, the Jacobian of those shares with respect to `delta`, and
+//! the inversion that recovers `delta` from observed shares. Every variant implements
+//! this trait so that [`Problem`](crate::Problem) stays generic over the model type.
+
+use std::fmt;
+use std::ops::Range;
+
+use nalgebra::{DMatrix, DVector};
+use rayon::prelude::*;
+
+use crate::data::{ProductData, RandomCoefficientType};
+use crate::demand::{
+    DRAW_BLOCK, ShareInputs, contract_from, individual_shares, nested_softmax, predict_shares_with,
+};
+use crate::error::{BlpError, Result};
+use crate::solving::{ContractionSummary, ConvergenceCriterion};
+
+/// Common interface implemented by every demand model variant.
+pub trait DemandModel: fmt::Debug + Send + Sync {
+    /// Short human-readable name used in logs and reports.
+    fn name(&self) -> &'static str;
+
+    /// Model-implied market shares at mean utilities `delta`.
+    fn shares(&self, delta: &DVector<f64>, inputs: &ShareInputs<'_>) -> Result<DVector<f64>>;
+
+    /// Jacobian of shares with respect to `delta`, one `J_t x J_t` block per market.
+    fn jacobian(&self, delta: &DVector<f64>, inputs: &ShareInputs<'_>)
+    -> Result<Vec<DMatrix<f64>>>;
+
+    /// Consumer-specific utilities the model adds on top of `delta` and the random
+    /// coefficients (see [`ShareInputs::with_offsets`]), if any.
+    fn offsets(&self, _inputs: &ShareInputs<'_>) -> Result<Option<DMatrix<f64>>> {
+        Ok(None)
+    }
+
+    /// The model for the products in `rows` (one whole market) alone, used to invert
+    /// markets one at a time under a
+    /// [`cpu_budget`](crate::solving::ContractionOptions::cpu_budget). `None`, the
+    /// default for models without product-level state, means the model applies as is.
+    fn select_products(&self, _rows: Range<usize>) -> Option<Box<dyn DemandModel>> {
+        None
+    }
+
+    /// Recover the mean utilities that rationalize the observed shares.
+    ///
+    /// The default implementation runs the BLP contraction on [`DemandModel::shares`],
+    /// starting from [`ShareInputs::initial_delta`] when one is set.
+    fn invert(&self, inputs: &ShareInputs<'_>) -> Result<(DVector<f64>, ContractionSummary)> {
+        contract_from(
+            inputs.data(),
+            inputs.options(),
+            inputs.initial_delta(),
+            |delta| self.shares(delta, inputs),
+        )
+    }
+}
+
+/// Plain multinomial logit: no consumer heterogeneity and a closed-form inversion.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Logit;
+
+impl DemandModel for Logit {
+    fn name(&self) -> &'static str {
+        "logit"
+    }
+
+    fn shares(&self, delta: &DVector<f64>, inputs: &ShareInputs<'_>) -> Result<DVector<f64>> {
+        let data = inputs.data();
+        if delta.len() != data.product_count() {
+            return Err(BlpError::dimension_mismatch(
+                "delta length",
+                data.product_count(),
+                delta.len(),
+            ));
+        }
+        let mut shares = DVector::zeros(delta.len());
+        for market in data.partition().markets() {
+            let range = market.range();
+            let exp_delta = delta.rows(range.start, range.len()).map(f64::exp);
+            let denominator = 1.0 + exp_delta.sum();
+            if !denominator.is_finite() {
+                return Err(BlpError::NumericalError {
+                    context: "utility exponentiation",
+                });
+            }
+            shares
+                .rows_mut(range.start, range.len())
+                .copy_from(&(exp_delta / denominator));
+        }
+        Ok(shares)
+    }
+
+    fn jacobian(
+        &self,
+        delta: &DVector<f64>,
+        inputs: &ShareInputs<'_>,
+    ) -> Result<Vec<DMatrix<f64>>> {
+        let shares = self.shares(delta, inputs)?;
+        Ok(inputs
+            .data()
+            .partition()
+            .markets()
+            .map(|market| {
+                if market.product_count() == 1 {
+                    let s = shares[market.range().start];
+                    return DMatrix::from_element(1, 1, s * (1.0 - s));
+                }
+                let s = shares.rows(market.range().start, market.product_count());
+                DMatrix::from_diagonal(&s) - s * s.transpose()
+            })
+            .collect())
+    }
+
+    fn invert(&self, inputs: &ShareInputs<'_>) -> Result<(DVector<f64>, ContractionSummary)> {
+        let data = inputs.data();
+        let delta = DVector::from_fn(data.product_count(), |j, _| {
+            (data.shares()[j] / data.outside_share_for_product(j)).ln()
+        });
+        Ok((
+            delta,
+            ContractionSummary {
+                criterion: Some(ConvergenceCriterion::ClosedForm),
+                ..Default::default()
+            },
+        ))
+    }
+}
+
+/// Random coefficients logit (BLP): `u_ijt = delta_jt + x2_jt' Sigma nu_i + epsilon_ijt`.
+///
+/// With no nonlinear characteristics this reduces to the plain logit. When the parameters
+/// carry a nesting parameter `rho` ([`ShareInputs::rho`]), each consumer chooses by
+/// [`NestedLogit`] over their own utilities: the random coefficients nested logit (RCNL).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RandomCoefficientsLogit;
+
+impl DemandModel for RandomCoefficientsLogit {
+    fn name(&self) -> &'static str {
+        "random coefficients logit"
+    }
+
+    fn shares(&self, delta: &DVector<f64>, inputs: &ShareInputs<'_>) -> Result<DVector<f64>> {
+        predict_shares_with(delta, inputs)
+    }
+
+    fn jacobian(
+        &self,
+        delta: &DVector<f64>,
+        inputs: &ShareInputs<'_>,
+    ) -> Result<Vec<DMatrix<f64>>> {
+        let probabilities = individual_shares(delta, inputs)?;
+        let weights = inputs.draws().weights();
+        if let Some(rho) = inputs.rho() {
+            // Every consumer has nested logit probabilities; average their Jacobians.
+            let nests = inputs.data().nest_indices();
+            return Ok(inputs
+                .data()
+                .partition()
+                .markets()
+                .map(|market| {
+                    let range = market.range();
+                    let mut jacobian = DMatrix::zeros(range.len(), range.len());
+                    for (r, weight) in weights.iter().enumerate() {
+                        let column = probabilities.column(r);
+                        let shares = &column.as_slice()[range.clone()];
+                        jacobian += nested_jacobian(shares, &nests[range.clone()], rho) * *weight;
+                    }
+                    jacobian
+                })
+                .collect());
+        }
+        Ok(inputs
+            .data()
+            .partition()
+            .markets()
+            .map(|market| {
+                if market.product_count() == 1 {
+                    let derivative = probabilities
+                        .row(market.range().start)
+                        .iter()
+                        .zip(weights.iter())
+                        .map(|(p, weight)| weight * p * (1.0 - p))
+                        .sum();
+                    return DMatrix::from_element(1, 1, derivative);
+                }
+                let block = probabilities.rows(market.range().start, market.product_count());
+                let weighted = DMatrix::from_fn(block.nrows(), block.ncols(), |j, r| {
+                    block[(j, r)] * weights[r]
+                });
+                let mean = &weighted * DVector::from_element(block.ncols(), 1.0);
+                let draw_count = block.ncols();
+                if block.nrows() < inputs.options().large_market_products
+                    || draw_count <= DRAW_BLOCK
+                {
+                    return DMatrix::from_diagonal(&mean) - &weighted * block.transpose();
+                }
+                // Accumulate the J x J cross products over consumer blocks in parallel.
+                let cross = (0..draw_count)
+                    .step_by(DRAW_BLOCK)
+                    .collect::<Vec<_>>()
+                    .into_par_iter()
+                    .map(|start| {
+                        let len = DRAW_BLOCK.min(draw_count - start);
+                        weighted.columns(start, len) * block.columns(start, len).transpose()
+                    })
+                    .reduce(
+                        || DMatrix::zeros(block.nrows(), block.nrows()),
+                        |left, right| left + right,
+                    );
+                DMatrix::from_diagonal(&mean) - cross
+            })
+            .collect())
+    }
+
+    /// The BLP contraction, with the update scaled by `1 - rho` under nesting as for
+    /// [`NestedLogit`].
+    fn invert(&self, inputs: &ShareInputs<'_>) -> Result<(DVector<f64>, ContractionSummary)> {
+        let mut options = inputs.options().clone();
+        if let Some(rho) = inputs.rho() {
+            options.damping *= 1.0 - rho;
+        }
+        contract_from(inputs.data(), &options, inputs.initial_delta(), |delta| {
+            self.shares(delta, inputs)
+        })
+    }
+}
+
+/// Nested logit: products are grouped into nests (see
+/// [`ProductDataBuilder::nesting_ids`](crate::data::ProductDataBuilder::nesting_ids)) and
+/// `u_ijt = delta_jt + zeta_iht + (1 - rho) epsilon_ijt`, so a consumer's tastes are
+/// correlated across products of the same nest.
+///
+/// `rho = 0` is the plain logit and `rho -> 1` makes products within a nest perfect
+/// substitutes. A `rho` passed with the parameters
+/// ([`ShareInputs::rho`]) takes precedence over the model's own. Like [`Logit`], it has
+/// no random coefficients; `X2` and `sigma` are ignored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NestedLogit {
+    rho: f64,
+}
+
+impl NestedLogit {
+    /// Nested logit with within-nest correlation `rho` in `[0, 1)`.
+    pub fn new(rho: f64) -> Result<Self> {
+        if !(0.0..1.0).contains(&rho) {
+            return Err(BlpError::InvalidNestingParameter { rho });
+        }
+        Ok(Self { rho })
+    }
+
+    /// Within-nest correlation.
+    pub fn rho(&self) -> f64 {
+        self.rho
+    }
+
+    /// The correlation in effect for `inputs`.
+    fn effective_rho(&self, inputs: &ShareInputs<'_>) -> f64 {
+        inputs.rho().unwrap_or(self.rho)
+    }
+}
+
+/// Nest of every product, or an error when the data carries no nesting groups.
+fn nests(data: &ProductData) -> Result<&[usize]> {
+    if data.nesting_ids().is_none() {
+        return Err(BlpError::missing_component("nesting ids"));
+    }
+    Ok(data.nest_indices())
+}
+
+impl DemandModel for NestedLogit {
+    fn name(&self) -> &'static str {
+        "nested logit"
+    }
+
+    fn shares(&self, delta: &DVector<f64>, inputs: &ShareInputs<'_>) -> Result<DVector<f64>> {
+        let data = inputs.data();
+        if delta.len() != data.product_count() {
+            return Err(BlpError::dimension_mismatch(
+                "delta length",
+                data.product_count(),
+                delta.len(),
+            ));
+        }
+        let nests = nests(data)?;
+        let rho = self.effective_rho(inputs);
+        let mut shares = delta.clone();
+        for market in data.partition().markets() {
+            let range = market.range();
+            nested_softmax(
+                &mut shares.as_mut_slice()[range.clone()],
+                &nests[range],
+                rho,
+            )?;
+        }
+        Ok(shares)
+    }
+
+    fn jacobian(
+        &self,
+        delta: &DVector<f64>,
+        inputs: &ShareInputs<'_>,
+    ) -> Result<Vec<DMatrix<f64>>> {
+        let shares = self.shares(delta, inputs)?;
+        let nests = nests(inputs.data())?;
+        let rho = self.effective_rho(inputs);
+        Ok(inputs
+            .data()
+            .partition()
+            .markets()
+            .map(|market| {
+                let range = market.range();
+                nested_jacobian(&shares.as_slice()[range.clone()], &nests[range], rho)
+            })
+            .collect())
+    }
+
+    /// The contraction `delta <- delta + (1 - rho) ln(s / s(delta))`: scaling the BLP
+    /// update by `1 - rho` (on top of any configured damping) keeps it a contraction for
+    /// every `rho` in `[0, 1)`.
+    fn invert(&self, inputs: &ShareInputs<'_>) -> Result<(DVector<f64>, ContractionSummary)> {
+        let mut options = inputs.options().clone();
+        options.damping *= 1.0 - self.effective_rho(inputs);
+        contract_from(inputs.data(), &options, inputs.initial_delta(), |delta| {
+            self.shares(delta, inputs)
+        })
+    }
+}
+
+/// Pure characteristics demand (Berry and Pakes, 2007): `u_ijt = delta_jt + x2_jt' Sigma
+/// nu_i`, with no idiosyncratic logit error, so products are only differentiated through
+/// their characteristics.
+///
+/// Shares of the exact model are step functions of `delta` for a finite set of draws, so
+/// they are smoothed by a logit error of scale `scale`, `u_ijt + scale epsilon_ijt`, which
+/// recovers the pure characteristics model as `scale -> 0` and the random coefficients
+/// logit at `scale = 1`. The inversion is the contraction
+/// `delta <- delta + scale ln(s / s(delta))`, which slows down as the smoothing shrinks;
+/// utilities divided by a very small scale can also overflow. Nesting and lognormal
+/// coefficients are not supported.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PureCharacteristics {
+    scale: f64,
+}
+
+impl PureCharacteristics {
+    /// Pure characteristics demand smoothed by a logit error of scale `scale > 0`.
+    pub fn new(scale: f64) -> Result<Self> {
+        if !scale.is_finite() || scale <= 0.0 {
+            return Err(BlpError::NumericalError {
+                context: "pure characteristics smoothing scale",
+            });
+        }
+        Ok(Self { scale })
+    }
+
+    /// Scale of the smoothing logit error.
+    pub fn scale(&self) -> f64 {
+        self.scale
+    }
+
+    /// Run `f` on the random coefficients logit whose utilities are those of `inputs`
+    /// divided by the smoothing scale, at `delta` divided by it as well.
+    fn scaled<T>(
+        &self,
+        delta: &DVector<f64>,
+        inputs: &ShareInputs<'_>,
+        f: impl FnOnce(&DVector<f64>, &ShareInputs<'_>) -> Result<T>,
+    ) -> Result<T> {
+        if inputs.rho().is_some() {
+            return Err(BlpError::Unsupported {
+                operation: "nesting in the pure characteristics model",
+            });
+        }
+        if inputs
+            .data()
+            .rc_types()
+            .contains(&RandomCoefficientType::Log)
+        {
+            return Err(BlpError::Unsupported {
+                operation: "lognormal coefficients in the pure characteristics model",
+            });
+        }
+        let sigma = inputs.sigma() / self.scale;
+        let pi = inputs.pi().map(|pi| pi / self.scale);
+        let offsets = inputs.offsets().map(|offsets| offsets / self.scale);
+        let mut scaled = ShareInputs::new(inputs.data(), inputs.draws(), &sigma, inputs.options());
+        if let Some(pi) = &pi {
+            scaled = scaled.with_pi(pi);
+        }
+        if let Some(offsets) = &offsets {
+            scaled = scaled.with_offsets(offsets);
+        }
+        if let Some(availability) = inputs.availability() {
+            scaled = scaled.with_availability(availability);
+        }
+        f(&(delta / self.scale), &scaled)
+    }
+}
+
+impl DemandModel for PureCharacteristics {
+    fn name(&self) -> &'static str {
+        "pure characteristics"
+    }
+
+    /// Weighted averages of every consumer's choice probabilities. Unlike
+    /// [`predict_shares_with`], consumers with vanishing probabilities are not an error:
+    /// with little smoothing most consumers never buy most products.
+    fn shares(&self, delta: &DVector<f64>, inputs: &ShareInputs<'_>) -> Result<DVector<f64>> {
+        let probabilities = self.scaled(delta, inputs, individual_shares)?;
+        Ok(probabilities * inputs.draws().weights())
+    }
+
+    fn jacobian(
+        &self,
+        delta: &DVector<f64>,
+        inputs: &ShareInputs<'_>,
+    ) -> Result<Vec<DMatrix<f64>>> {
+        let blocks = self.scaled(delta, inputs, |delta, inputs| {
+            RandomCoefficientsLogit.jacobian(delta, inputs)
+        })?;
+        Ok(blocks.into_iter().map(|block| block / self.scale).collect())
+    }
+
+    fn invert(&self, inputs: &ShareInputs<'_>) -> Result<(DVector<f64>, ContractionSummary)> {
+        let mut options = inputs.options().clone();
+        options.damping *= self.scale;
+        contract_from(inputs.data(), &options, inputs.initial_delta(), |delta| {
+            self.shares(delta, inputs)
+        })
+    }
+}
+
+/// Jacobian of one market's nested logit shares `s` with respect to the utilities:
+/// `ds_j / dV_k = 1{j = k} s_j / (1 - rho) - 1{same nest} rho / (1 - rho) s_j s_{k|h} -
+/// s_j s_k`.
+pub(crate) fn nested_jacobian(shares: &[f64], nests: &[usize], rho: f64) -> DMatrix<f64> {
+    let within = rho / (1.0 - rho);
+    let conditional: Vec<f64> = shares
+        .iter()
+        .zip(nests)
+        .map(|(share, nest)| {
+            let nest_share: f64 = shares
+                .iter()
+                .zip(nests)
+                .filter(|(_, other)| *other == nest)
+                .map(|(share, _)| share)
+                .sum();
+            if nest_share > 0.0 {
+                share / nest_share
+            } else {
+                0.0
+            }
+        })
+        .collect();
+    DMatrix::from_fn(shares.len(), shares.len(), |j, k| {
+        let (s_j, s_k) = (shares[j], shares[k]);
+        let mut derivative = -s_j * s_k;
+        if nests[j] == nests[k] {
+            derivative -= within * s_j * conditional[k];
+        }
+        if j == k {
+            derivative += s_j / (1.0 - rho);
+        }
+        derivative
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::ProductDataBuilder;
+    use crate::integration::SimulationDraws;
+    use crate::solving::ContractionOptions;
+    use approx::assert_relative_eq;
+
+    #[test]
+    fn rc_logit_with_degenerate_heterogeneity_matches_logit() {
+        let market_ids = vec!["m1".to_string(), "m1".to_string(), "m2".to_string()];
+        let shares = DVector::from_vec(vec![0.3, 0.2, 0.4]);
+        let x1 = DMatrix::from_row_slice(3, 1, &[1.0, 1.0, 1.0]);
+        let x2 = DMatrix::from_row_slice(3, 1, &[1.0, 2.0, 3.0]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .x2(x2)
+            .build()
+            .unwrap();
+        let draws = SimulationDraws::standard_normal(20, 1, 9);
+        let sigma = DMatrix::zeros(1, 1);
+        let options = ContractionOptions::default();
+        let inputs = ShareInputs::new(&data, &draws, &sigma, &options);
+
+        let (logit_delta, _) = Logit.invert(&inputs).unwrap();
+        let (rc_delta, _) = RandomCoefficientsLogit.invert(&inputs).unwrap();
+        assert_relative_eq!(logit_delta, rc_delta, epsilon = 1e-8);
+
+        let logit_jacobian = Logit.jacobian(&logit_delta, &inputs).unwrap();
+        let rc_jacobian = RandomCoefficientsLogit
+            .jacobian(&logit_delta, &inputs)
+            .unwrap();
+        assert_eq!(rc_jacobian.len(), 2);
+        for (a, b) in logit_jacobian.iter().zip(&rc_jacobian) {
+            assert_relative_eq!(a, b, epsilon = 1e-12);
+        }
+    }
+
+    #[test]
+    fn single_product_jacobian_matches_finite_differences() {
+        let market_ids = vec!["m1".to_string(), "m2".to_string()];
+        let shares = DVector::from_vec(vec![0.3, 0.4]);
+        let x1 = DMatrix::from_row_slice(2, 1, &[1.0, 2.0]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1.clone())
+            .x2(x1)
+            .build()
+            .unwrap();
+        let draws = SimulationDraws::standard_normal(20, 1, 9);
+        let sigma = DMatrix::from_element(1, 1, 1.2);
+        let options = ContractionOptions::default();
+        let inputs = ShareInputs::new(&data, &draws, &sigma, &options);
+        let delta = DVector::from_vec(vec![-0.5, 0.3]);
+
+        let jacobian = RandomCoefficientsLogit.jacobian(&delta, &inputs).unwrap();
+        let step = 1e-6;
+        let shifted = &delta + DVector::from_element(2, step);
+        let base = RandomCoefficientsLogit.shares(&delta, &inputs).unwrap();
+        let bumped = RandomCoefficientsLogit.shares(&shifted, &inputs).unwrap();
+        for (market, block) in jacobian.iter().enumerate() {
+            assert_eq!(block.shape(), (1, 1));
+            let numeric = (bumped[market] - base[market]) / step;
+            assert_relative_eq!(block[(0, 0)], numeric, epsilon = 1e-6);
+        }
+    }
+
+    #[test]
+    fn nested_logit_inverts_in_closed_form() {
+        let market_ids = ["m1", "m1", "m1", "m2", "m2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.2, 0.1, 0.3, 0.25, 0.35]);
+        let nests = ["a", "a", "b", "b", "a"].map(String::from).to_vec();
+        let build = |nesting_ids: Option<Vec<String>>| {
+            let mut builder = ProductDataBuilder::new(market_ids.clone(), shares.clone())
+                .x1(DMatrix::from_element(5, 1, 1.0));
+            if let Some(ids) = nesting_ids {
+                builder = builder.nesting_ids(ids);
+            }
+            builder.build().unwrap()
+        };
+        let data = build(Some(nests));
+        assert_eq!(data.nest_indices(), &[0, 0, 1, 1, 0]);
+        let draws = SimulationDraws::standard_normal(1, 0, 1);
+        let sigma = DMatrix::zeros(0, 0);
+        let options = ContractionOptions::default();
+        let inputs = ShareInputs::new(&data, &draws, &sigma, &options);
+
+        let rho = 0.6;
+        let model = NestedLogit::new(rho).unwrap();
+        let (delta, summary) = model.invert(&inputs).unwrap();
+        assert!(summary.iterations > 0);
+        // delta_j = ln(s_j / s_0) - rho ln(s_{j|h})
+        let within = [0.2 / 0.3, 0.1 / 0.3, 1.0, 1.0, 1.0];
+        let outside = [0.4, 0.4, 0.4, 0.4, 0.4];
+        for j in 0..5 {
+            let expected = (shares[j] / outside[j]).ln() - rho * f64::ln(within[j]);
+            assert_relative_eq!(delta[j], expected, epsilon = 1e-7);
+        }
+
+        let jacobian = model.jacobian(&delta, &inputs).unwrap();
+        let base = model.shares(&delta, &inputs).unwrap();
+        let step = 1e-7;
+        for k in 0..3 {
+            let mut bumped = delta.clone();
+            bumped[k] += step;
+            let shifted = model.shares(&bumped, &inputs).unwrap();
+            for j in 0..3 {
+                let numeric = (shifted[j] - base[j]) / step;
+                assert_relative_eq!(jacobian[0][(j, k)], numeric, epsilon = 1e-6);
+            }
+        }
+
+        let flat = NestedLogit::new(0.0)
+            .unwrap()
+            .shares(&delta, &inputs)
+            .unwrap();
+        assert_relative_eq!(
+            flat,
+            Logit.shares(&delta, &inputs).unwrap(),
+            epsilon = 1e-12
+        );
+        assert!(NestedLogit::new(1.0).is_err());
+        let unnested = build(None);
+        let inputs = ShareInputs::new(&unnested, &draws, &sigma, &options);
+        assert!(model.shares(&delta, &inputs).is_err());
+    }
+
+    #[test]
+    fn rcnl_nests_each_consumer_and_inverts() {
+        let market_ids = ["m1", "m1", "m1", "m2", "m2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.2, 0.1, 0.3, 0.25, 0.35]);
+        let x1 =
+            DMatrix::from_row_slice(5, 2, &[1.0, 0.5, 1.0, 1.5, 1.0, -0.2, 1.0, 0.8, 1.0, 0.1]);
+        let data = ProductDataBuilder::new(market_ids, shares.clone())
+            .x1(x1)
+            .x2_from_x1(vec![1])
+            .nesting_ids(["a", "a", "b", "b", "a"].map(String::from).to_vec())
+            .build()
+            .unwrap();
+        let draws = SimulationDraws::standard_normal(40, 1, 3);
+        let options = ContractionOptions::default();
+        let delta = DVector::from_vec(vec![-1.0, -1.5, -0.5, -0.8, -0.3]);
+        let rho = 0.4;
+
+        let flat = DMatrix::zeros(1, 1);
+        let inputs = ShareInputs::new(&data, &draws, &flat, &options).with_rho(rho);
+        assert_relative_eq!(
+            RandomCoefficientsLogit.shares(&delta, &inputs).unwrap(),
+            NestedLogit::new(rho)
+                .unwrap()
+                .shares(&delta, &inputs)
+                .unwrap(),
+            epsilon = 1e-12
+        );
+
+        let sigma = DMatrix::from_element(1, 1, 0.8);
+        let inputs = ShareInputs::new(&data, &draws, &sigma, &options).with_rho(rho);
+        let model = RandomCoefficientsLogit;
+        let base = model.shares(&delta, &inputs).unwrap();
+        let jacobian = model.jacobian(&delta, &inputs).unwrap();
+        let step = 1e-7;
+        for k in 0..3 {
+            let mut bumped = delta.clone();
+            bumped[k] += step;
+            let shifted = model.shares(&bumped, &inputs).unwrap();
+            for j in 0..3 {
+                let numeric = (shifted[j] - base[j]) / step;
+                assert_relative_eq!(jacobian[0][(j, k)], numeric, epsilon = 1e-6);
+            }
+        }
+
+        let observed = data.to_builder().shares(base).build().unwrap();
+        let inputs = ShareInputs::new(&observed, &draws, &sigma, &options).with_rho(rho);
+        let (recovered, _) = model.invert(&inputs).unwrap();
+        assert_relative_eq!(recovered, delta, epsilon = 1e-8);
+    }
+
+    #[test]
+    fn pure_characteristics_sharpens_towards_vertical_choice() {
+        let market_ids = ["m1", "m1", "m1", "m2", "m2"].map(String::from).to_vec();
+        let x2 = DMatrix::from_column_slice(5, 1, &[0.5, 1.5, -0.2, 0.8, 0.1]);
+        let data = ProductDataBuilder::new(market_ids, DVector::from_element(5, 0.15))
+            .x1(DMatrix::from_element(5, 1, 1.0))
+            .x2(x2)
+            .build()
+            .unwrap();
+        let draws = SimulationDraws::standard_normal(50, 1, 4);
+        let sigma = DMatrix::from_element(1, 1, 1.0);
+        let options = ContractionOptions::default();
+        let inputs = ShareInputs::new(&data, &draws, &sigma, &options);
+        let delta = DVector::from_vec(vec![-1.0, -1.5, -0.5, -0.8, -0.3]);
+
+        // A unit smoothing scale is the random coefficients logit.
+        let unit = PureCharacteristics::new(1.0).unwrap();
+        assert_relative_eq!(
+            unit.shares(&delta, &inputs).unwrap(),
+            RandomCoefficientsLogit.shares(&delta, &inputs).unwrap(),
+            epsilon = 1e-12
+        );
+
+        // With little smoothing each consumer buys the best option outright.
+        let sharp = PureCharacteristics::new(0.01).unwrap();
+        let shares = sharp.shares(&delta, &inputs).unwrap();
+        let mut exact = DVector::zeros(5);
+        for (r, weight) in draws.weights().iter().enumerate() {
+            for range in [0..3, 3..5] {
+                let nu = draws.draws()[(r, 0)];
+                let utility = |j: usize| delta[j] + data.x2()[(j, 0)] * nu;
+                let best = range.max_by(|&a, &b| utility(a).total_cmp(&utility(b)));
+                if let Some(j) = best.filter(|&j| utility(j) > 0.0) {
+                    exact[j] += weight;
+                }
+            }
+        }
+        assert_relative_eq!(shares, exact, epsilon = 0.03);
+
+        let model = PureCharacteristics::new(0.2).unwrap();
+        let base = model.shares(&delta, &inputs).unwrap();
+        let jacobian = model.jacobian(&delta, &inputs).unwrap();
+        let step = 1e-7;
+        for k in 0..3 {
+            let mut bumped = delta.clone();
+            bumped[k] += step;
+            let shifted = model.shares(&bumped, &inputs).unwrap();
+            for j in 0..3 {
+                let numeric = (shifted[j] - base[j]) / step;
+                assert_relative_eq!(jacobian[0][(j, k)], numeric, epsilon = 1e-5);
+            }
+        }
+        let observed = data.to_builder().shares(base).build().unwrap();
+        let inputs = ShareInputs::new(&observed, &draws, &sigma, &options);
+        let (recovered, _) = model.invert(&inputs).unwrap();
+        assert_relative_eq!(recovered, delta, epsilon = 1e-7);
+
+        assert!(PureCharacteristics::new(0.0).is_err());
+        assert!(model.shares(&delta, &inputs.with_rho(0.3)).is_err());
+    }
+}
@@ -0,0 +1,489 @@
+//! Sample GMM moments and their derivatives, for use outside the built-in estimator.
+//!
+//! The parameter vector is `theta = [beta; vec(sigma)]`, with `sigma` stacked
+//! column-major (the layout of `sigma.as_slice()`).
+
+use nalgebra::{DMatrix, DVector};
+
+use crate::demand::{ShareInputs, individual_shares, taste_slopes};
+use crate::diagnostics::ProfilingReport;
+use crate::error::{BlpError, Result};
+use crate::estimation::{Problem, ProblemResults};
+use crate::models::nested_jacobian;
+use crate::options::WeightingMatrix;
+
+/// Demand-side moment conditions `g(theta) = Z' xi(theta) / N` evaluated at one `theta`.
+#[derive(Clone, Debug)]
+pub struct GmmMoments {
+    /// Sample moments, one per instrument.
+    pub values: DVector<f64>,
+    /// `dg / dtheta`, one row per instrument and one column per element of `theta`.
+    pub jacobian: DMatrix<f64>,
+    /// Mean utilities recovered at `sigma`.
+    pub delta: DVector<f64>,
+    /// Structural errors `delta - X1 beta`.
+    pub xi: DVector<f64>,
+}
+
+impl Problem {
+    /// Number of elements in `theta = [beta; vec(sigma)]`.
+    pub fn parameter_count(&self) -> usize {
+        let k2 = self.data().nonlinear_dim();
+        self.data().linear_dim() + k2 * k2
+    }
+
+    /// Evaluate the stacked sample moments and their Jacobian at `theta`.
+    ///
+    /// Unlike [`solve`](Problem::solve), `beta` is not concentrated out, so the moments
+    /// can be combined with conditions from other models in an external GMM or sieve
+    /// estimator. With `N` products, the built-in objective equals
+    /// `N^2 * g' W g` at the concentrated `beta`. The `sigma` derivatives follow the
+    /// random coefficients logit share formula via the implicit function theorem.
+    pub fn moments(&self, theta: &DVector<f64>) -> Result<GmmMoments> {
+        let data = self.data();
+        let k1 = data.linear_dim();
+        let k2 = data.nonlinear_dim();
+        if theta.len() != self.parameter_count() {
+            return Err(BlpError::dimension_mismatch(
+                "theta length",
+                self.parameter_count(),
+                theta.len(),
+            ));
+        }
+        let beta = theta.rows(0, k1).into_owned();
+        let sigma = DMatrix::from_column_slice(k2, k2, &theta.as_slice()[k1..]);
+
+        let (cached, _) = self.cached_inner(&sigma.into(), self.options(), None, true)?;
+        let delta = cached.inner.delta;
+        let delta_jacobian = cached
+            .delta_jacobian
+            .ok_or_else(|| BlpError::missing_component("delta Jacobian"))?;
+
+        let xi = data.demean(&(&delta - data.x1() * &beta));
+        let scale = 1.0 / data.product_count() as f64;
+        let z_t = data.instruments().transpose();
+        let values = &z_t * &xi * scale;
+        let mut jacobian = DMatrix::zeros(data.instrument_dim(), theta.len());
+        jacobian
+            .columns_mut(0, k1)
+            .copy_from(&(&z_t * data.x1() * -scale));
+        jacobian
+            .columns_mut(k1, k2 * k2)
+            .copy_from(&(&z_t * delta_jacobian * scale));
+
+        Ok(GmmMoments {
+            values,
+            jacobian,
+            delta,
+            xi,
+        })
+    }
+
+    /// The concentrated GMM objective at `sigma` and its analytic gradient with respect to
+    /// `sigma`, shaped like `sigma`.
+    ///
+    /// Because `beta` minimizes the objective given `delta`, the envelope theorem gives
+    /// `dJ / dvec(sigma) = 2 (Z' d delta / d vec(sigma))' W Z' xi`, with the `delta`
+    /// Jacobian from the implicit function theorem. One evaluation costs one contraction
+    /// plus a `J_t x J_t` solve per market, instead of `K2^2` extra contractions for
+    /// finite differences. The gradient covers entries held at zero too; optimizers
+    /// should ignore them. Not available with a supply side or macro moments attached, or
+    /// with [`WeightingMatrix::ContinuouslyUpdated`], whose weighting moves with `sigma`.
+    /// The objective is evaluated at `sigma` alone, so a problem whose options estimate
+    /// `pi` or `rho` is refused with [`BlpError::InconsistentSpecification`].
+    pub fn objective_and_gradient(
+        &self,
+        sigma: &DMatrix<f64>,
+    ) -> Result<(ProblemResults, DMatrix<f64>)> {
+        if self.supply().is_some() {
+            return Err(BlpError::Unsupported {
+                operation: "analytic gradient with a supply side",
+            });
+        }
+        if !self.macro_moments().is_empty() {
+            return Err(BlpError::Unsupported {
+                operation: "analytic gradient with macro moments",
+            });
+        }
+        if matches!(
+            self.options().gmm.weighting,
+            WeightingMatrix::ContinuouslyUpdated
+        ) {
+            return Err(BlpError::Unsupported {
+                operation: "analytic gradient with continuously-updated weighting",
+            });
+        }
+        let optimization = &self.options().optimization;
+        let mut mismatches = Vec::new();
+        if optimization.initial_pi.is_some() {
+            mismatches.push(
+                "the analytic gradient is evaluated at sigma alone, but pi is estimated".into(),
+            );
+        }
+        if optimization.initial_rho.is_some() {
+            mismatches.push(
+                "the analytic gradient is evaluated at sigma alone, but rho is estimated".into(),
+            );
+        }
+        if !mismatches.is_empty() {
+            return Err(BlpError::InconsistentSpecification { mismatches });
+        }
+        let k2 = self.data().nonlinear_dim();
+        if sigma.shape() != (k2, k2) {
+            return Err(BlpError::dimension_mismatch(
+                "sigma rows",
+                k2,
+                sigma.nrows(),
+            ));
+        }
+        let started = std::time::Instant::now();
+        let (cached, reused) =
+            self.cached_inner(&sigma.clone().into(), self.options(), None, true)?;
+        let delta_jacobian = cached
+            .delta_jacobian
+            .ok_or_else(|| BlpError::missing_component("delta Jacobian"))?;
+        let profiling = ProfilingReport {
+            contraction_seconds: started.elapsed().as_secs_f64(),
+            reused_contraction_seconds: if reused { cached.seconds } else { 0.0 },
+            ..ProfilingReport::default()
+        };
+        let results = self.linear_step(cached.inner, self.options(), profiling)?;
+
+        let z_t = self.data().instruments().transpose();
+        let weighted_moments = &results.weighting_matrix * (&z_t * &results.xi);
+        let gradient = (&z_t * delta_jacobian).transpose() * weighted_moments * 2.0;
+        Ok((
+            results,
+            DMatrix::from_column_slice(k2, k2, gradient.as_slice()),
+        ))
+    }
+
+    /// `d delta / d vec(sigma)`, an `N x K2^2` matrix, from
+    /// `-(ds/ddelta)^{-1} ds/dsigma` market by market.
+    pub(crate) fn delta_jacobian(
+        &self,
+        delta: &DVector<f64>,
+        inputs: &ShareInputs<'_>,
+    ) -> Result<DMatrix<f64>> {
+        self.taste_jacobian(delta, inputs, inputs.draws().draws())
+    }
+
+    /// `d delta / d vec(pi)`, an `N x K2 D` matrix, with no columns when the draws
+    /// carry no demographics.
+    pub(crate) fn delta_pi_jacobian(
+        &self,
+        delta: &DVector<f64>,
+        inputs: &ShareInputs<'_>,
+    ) -> Result<DMatrix<f64>> {
+        match inputs.draws().demographics() {
+            Some(demographics) => self.taste_jacobian(delta, inputs, demographics),
+            None => Ok(DMatrix::zeros(inputs.data().product_count(), 0)),
+        }
+    }
+
+    /// `d delta` with respect to the loadings of the tastes on the consumer-level
+    /// `shocks` (`R x L`), an `N x K2 L` matrix with the loadings stacked column-major:
+    /// the draws `nu` for `sigma` and the demographics for `pi`.
+    fn taste_jacobian(
+        &self,
+        delta: &DVector<f64>,
+        inputs: &ShareInputs<'_>,
+        shocks: &DMatrix<f64>,
+    ) -> Result<DMatrix<f64>> {
+        let data = inputs.data();
+        let draws = inputs.draws();
+        let k2 = data.nonlinear_dim();
+        let loadings = shocks.ncols();
+        let mut result = DMatrix::zeros(data.product_count(), k2 * loadings);
+        if k2 * loadings == 0 {
+            return Ok(result);
+        }
+        let offsets = self.model().offsets(inputs)?;
+        let with_offsets = match &offsets {
+            Some(offsets) => inputs.with_offsets(offsets),
+            None => *inputs,
+        };
+        let probabilities = individual_shares(delta, &with_offsets)?;
+        let share_jacobians = self.model().jacobian(delta, inputs)?;
+        let weights = draws.weights();
+        let slopes = taste_slopes(inputs);
+        let slope = |k: usize, r: usize| slopes.as_ref().map_or(1.0, |slopes| slopes[(k, r)]);
+
+        for (market, share_jacobian) in data.partition().markets().zip(share_jacobians) {
+            let range = market.range();
+            let p = probabilities.rows(range.start, range.len());
+            let x2 = data.x2().rows(range.start, range.len());
+            let mut share_derivatives = DMatrix::zeros(range.len(), k2 * loadings);
+            if let Some(rho) = inputs.rho() {
+                // Under nesting, consumer r's shares respond to the taste shift x2_k
+                // shock_rl through their own nested logit Jacobian.
+                let nests = &data.nest_indices()[range.clone()];
+                for (r, weight) in weights.iter().enumerate() {
+                    let column = p.column(r).into_owned();
+                    let shifted = nested_jacobian(column.as_slice(), nests, rho) * x2;
+                    for l in 0..loadings {
+                        for k in 0..k2 {
+                            let mut target = share_derivatives.column_mut(l * k2 + k);
+                            let scale = weight * shocks[(r, l)] * slope(k, r);
+                            target.axpy(scale, &shifted.column(k), 1.0);
+                        }
+                    }
+                }
+            } else {
+                // Column r holds consumer r's probability-weighted characteristics.
+                let mean_x2 = x2.transpose() * p;
+                // ds_j / dsigma_kl = sum_r w_r nu_rl p_jr (x2_jk - mean_x2_kr), scaled by
+                // the consumer's coefficient when it is lognormal, and likewise for pi
+                // with the demographics in place of nu.
+                for l in 0..loadings {
+                    for k in 0..k2 {
+                        let column = l * k2 + k;
+                        for (r, weight) in weights.iter().enumerate() {
+                            let scale = weight * shocks[(r, l)] * slope(k, r);
+                            for j in 0..range.len() {
+                                share_derivatives[(j, column)] +=
+                                    scale * p[(j, r)] * (x2[(j, k)] - mean_x2[(k, r)]);
+                            }
+                        }
+                    }
+                }
+            }
+
+            let solved = share_jacobian
+                .lu()
+                .solve(&share_derivatives)
+                .ok_or_else(|| BlpError::singular("share Jacobian"))?;
+            result
+                .rows_mut(range.start, range.len())
+                .copy_from(&(-solved));
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+    use crate::data::{ProductDataBuilder, RandomCoefficientType};
+    use crate::integration::SimulationDraws;
+    use crate::macro_moments::{AgentGroup, MacroMoment, MacroStatistic};
+    use crate::options::ProblemOptions;
+    use crate::parameters::NonlinearParameters;
+
+    #[test]
+    fn moment_jacobian_matches_finite_differences() {
+        let market_ids = ["m1", "m1", "m2", "m2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.25, 0.15]);
+        let x1 = DMatrix::from_row_slice(4, 2, &[1.0, 1.0, 1.0, 2.0, 1.0, 1.5, 1.0, 0.5]);
+        let instruments = DMatrix::from_row_slice(
+            4,
+            3,
+            &[1.0, 0.5, 0.2, 1.0, 2.5, 0.1, 1.0, 1.0, 0.7, 1.0, 0.3, 0.4],
+        );
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1.clone())
+            .x2(x1.columns(1, 1).into_owned())
+            .instruments(instruments)
+            .build()
+            .unwrap();
+        let problem = Problem::new(data, SimulationDraws::standard_normal(40, 1, 5)).unwrap();
+        let theta = DVector::from_vec(vec![-1.0, 0.5, 0.8]);
+
+        let moments = problem.moments(&theta).unwrap();
+        assert_eq!(moments.jacobian.shape(), (3, problem.parameter_count()));
+        let step = 1e-6;
+        for parameter in 0..theta.len() {
+            let mut shifted = theta.clone();
+            shifted[parameter] += step;
+            let bumped = problem.moments(&shifted).unwrap();
+            let numeric = (&bumped.values - &moments.values) / step;
+            assert_relative_eq!(
+                moments.jacobian.column(parameter).into_owned(),
+                numeric,
+                epsilon = 1e-5
+            );
+        }
+    }
+
+    #[test]
+    fn objective_gradient_matches_finite_differences() {
+        let market_ids = ["m1", "m1", "m2", "m2", "m3", "m3"]
+            .map(String::from)
+            .to_vec();
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.25, 0.15, 0.1, 0.35]);
+        let x1 = DMatrix::from_row_slice(
+            6,
+            3,
+            &[
+                1.0, 1.0, 0.2, 1.0, 2.0, -0.4, 1.0, 1.5, 0.3, 1.0, 0.5, 0.9, 1.0, 1.2, -0.1, 1.0,
+                0.8, 0.6,
+            ],
+        );
+        let instruments = DMatrix::from_fn(6, 5, |j, k| match k {
+            0..=2 => x1[(j, k)],
+            3 => x1[(j, 1)] * x1[(j, 1)],
+            _ => x1[(j, 1)] * x1[(j, 2)],
+        });
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1.clone())
+            .x2(x1.columns(1, 2).into_owned())
+            .instruments(instruments)
+            .build()
+            .unwrap();
+        let problem = Problem::new(data, SimulationDraws::standard_normal(40, 2, 9)).unwrap();
+        let sigma = DMatrix::from_row_slice(2, 2, &[0.8, 0.0, 0.3, 0.5]);
+
+        let (results, gradient) = problem.objective_and_gradient(&sigma).unwrap();
+        assert_eq!(
+            results.gmm_value,
+            problem
+                .solve(&NonlinearParameters::new(sigma.clone()))
+                .unwrap()
+                .gmm_value
+        );
+        let step = 1e-6;
+        for entry in [(0, 0), (1, 0), (1, 1), (0, 1)] {
+            let mut shifted = sigma.clone();
+            shifted[entry] += step;
+            let numeric = (problem
+                .solve(&NonlinearParameters::new(shifted.clone()))
+                .unwrap()
+                .gmm_value
+                - results.gmm_value)
+                / step;
+            assert_relative_eq!(
+                gradient[entry],
+                numeric,
+                epsilon = 1e-4,
+                max_relative = 1e-3
+            );
+        }
+
+        // Terms the gradient does not differentiate are refused.
+        let cue = ProblemOptions::default().with_weighting(WeightingMatrix::ContinuouslyUpdated);
+        let continuously_updated = problem.with_options_override(cue);
+        assert!(matches!(
+            continuously_updated.objective_and_gradient(&sigma),
+            Err(BlpError::Unsupported { .. })
+        ));
+        let inside = MacroMoment::new(
+            "inside",
+            MacroStatistic::InsideShare(AgentGroup::All),
+            0.5,
+            0.01,
+        );
+        let with_macro = problem.clone().with_macro_moments(vec![inside]).unwrap();
+        assert!(matches!(
+            with_macro.objective_and_gradient(&sigma),
+            Err(BlpError::Unsupported { .. })
+        ));
+        // Parameters the sigma matrix cannot carry are refused rather than dropped.
+        let with_rho = problem.with_options_override(
+            ProblemOptions::default()
+                .with_optimization(crate::OptimizationOptions::new(sigma.clone()).with_rho(0.5)),
+        );
+        assert!(matches!(
+            with_rho.objective_and_gradient(&sigma),
+            Err(BlpError::InconsistentSpecification { .. })
+        ));
+    }
+
+    #[test]
+    fn pi_jacobian_matches_finite_differences() {
+        let market_ids = ["m1", "m1", "m2", "m2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.25, 0.15]);
+        let x1 = DMatrix::from_row_slice(4, 2, &[1.0, 1.0, 1.0, 2.0, 1.0, 1.5, 1.0, 0.5]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1.clone())
+            .x2(x1.columns(1, 1).into_owned())
+            .build()
+            .unwrap();
+        let income = DMatrix::from_fn(40, 2, |r, d| ((r * (d + 3)) % 7) as f64 / 3.5 - 1.0);
+        let draws = SimulationDraws::standard_normal(40, 1, 5)
+            .with_demographics(income)
+            .unwrap();
+        let problem = Problem::new(data, draws).unwrap();
+        let parameters = NonlinearParameters::new(DMatrix::from_element(1, 1, 0.5))
+            .with_pi(DMatrix::from_row_slice(1, 2, &[0.8, -0.3]));
+
+        let delta = problem.solve(&parameters).unwrap().delta;
+        let contraction = Default::default();
+        let inputs =
+            ShareInputs::for_parameters(problem.data(), problem.draws(), &parameters, &contraction);
+        let jacobian = problem.delta_pi_jacobian(&delta, &inputs).unwrap();
+        assert_eq!(jacobian.shape(), (4, 2));
+        let step = 1e-6;
+        for entry in 0..2 {
+            let mut pi = parameters.pi().unwrap().clone();
+            pi[(0, entry)] += step;
+            let bumped = problem
+                .solve(&parameters.clone().with_pi(pi))
+                .unwrap()
+                .delta;
+            assert_relative_eq!(
+                jacobian.column(entry).into_owned(),
+                (bumped - &delta) / step,
+                epsilon = 1e-5
+            );
+        }
+    }
+
+    #[test]
+    fn lognormal_coefficients_are_exponentiated_and_differentiated() {
+        let market_ids = ["m1", "m1", "m2", "m2", "m3", "m3"]
+            .map(String::from)
+            .to_vec();
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.25, 0.15, 0.1, 0.35]);
+        let prices = [1.0, 2.0, 1.5, 0.5, 1.2, 0.8];
+        let x1 = DMatrix::from_fn(6, 2, |j, k| if k == 0 { 1.0 } else { (j % 3) as f64 });
+        let instruments = DMatrix::from_fn(6, 4, |j, k| match k {
+            0..=1 => x1[(j, k)],
+            2 => prices[j],
+            _ => prices[j] * prices[j],
+        });
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .x2(DMatrix::from_fn(6, 1, |j, _| -prices[j]))
+            .rc_types(vec![RandomCoefficientType::Log])
+            .instruments(instruments)
+            .build()
+            .unwrap();
+        let draws = SimulationDraws::standard_normal(40, 1, 9);
+        let problem = Problem::new(data, draws.clone()).unwrap();
+        let sigma = DMatrix::from_element(1, 1, 0.4);
+
+        // Every consumer's price coefficient is -exp(sigma nu) < 0.
+        let results = problem
+            .solve(&NonlinearParameters::new(sigma.clone()))
+            .unwrap();
+        let inputs = ShareInputs::new(
+            problem.data(),
+            &draws,
+            &sigma,
+            &problem.options().contraction,
+        );
+        let probabilities = individual_shares(&results.delta, &inputs).unwrap();
+        let r = 7;
+        let coefficient = (0.4 * draws.draws()[(r, 0)]).exp();
+        let utilities = [0, 1].map(|j| (results.delta[j] - coefficient * prices[j]).exp());
+        let expected = utilities[0] / (1.0 + utilities[0] + utilities[1]);
+        assert!((probabilities[(0, r)] - expected).abs() < 1e-12);
+
+        let (results, gradient) = problem.objective_and_gradient(&sigma).unwrap();
+        let step = 1e-6;
+        let numeric = (problem
+            .solve(&NonlinearParameters::new(sigma.add_scalar(step)))
+            .unwrap()
+            .gmm_value
+            - results.gmm_value)
+            / step;
+        assert_relative_eq!(
+            gradient[(0, 0)],
+            numeric,
+            epsilon = 1e-4,
+            max_relative = 1e-3
+        );
+    }
+}
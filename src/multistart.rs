@@ -0,0 +1,371 @@
+//! Restarting the outer search from several starting values.
+//!
+//! The GMM objective is not convex in `sigma`, so a single Nelder–Mead run can stop in a
+//! local minimum. [`Problem::optimize_multistart`] runs [`Problem::optimize`] from the
+//! configured starting values and from randomly perturbed copies of them, keeps every
+//! terminal point, and flags the objective as nonconvex when the converged runs disagree.
+//! Every searched entry of the starting `sigma` (and `pi`) is scaled by an independent
+//! lognormal factor `exp(scale * e)`, which keeps its sign, leaves zero and fixed entries
+//! alone, and is then moved inside any [`ParameterBounds`](crate::ParameterBounds). Starts
+//! run in parallel within the problem's [`ParallelismOptions`](crate::ParallelismOptions)
+//! unless that is switched off.
+
+use nalgebra::{DMatrix, DVector};
+use rand::SeedableRng;
+use rand::rngs::SmallRng;
+use rand_distr::{Distribution, StandardNormal};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{BlpError, Result};
+use crate::estimation::Problem;
+use crate::optimization::{
+    OptimizationResults, initial_sigma, searched_entries, searched_pi_entries,
+};
+use crate::options::OptimizationOptions;
+use crate::parallel;
+
+/// Settings for [`Problem::optimize_multistart`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MultiStartOptions {
+    /// Number of starts, including the unperturbed one.
+    pub starts: usize,
+    /// Standard deviation of the log factor applied to every searched starting value.
+    pub scale: f64,
+    /// Seed of start 1; start `s` uses `seed + s`, so results do not depend on
+    /// scheduling. Start 0 is never perturbed.
+    pub seed: u64,
+    /// Converged objectives that differ by more than this fraction of `1 + |best|` mark
+    /// the objective as nonconvex.
+    pub tolerance: f64,
+    /// Run the starts concurrently as outer tasks; otherwise one after another.
+    pub parallel: bool,
+}
+
+impl Default for MultiStartOptions {
+    fn default() -> Self {
+        Self {
+            starts: 5,
+            scale: 0.5,
+            seed: 0,
+            tolerance: 1e-4,
+            parallel: true,
+        }
+    }
+}
+
+impl MultiStartOptions {
+    /// `starts` starts with log factors of standard deviation `scale`.
+    pub fn new(starts: usize, scale: f64) -> Self {
+        Self {
+            starts,
+            scale,
+            ..Self::default()
+        }
+    }
+
+    /// Seed the perturbations with `seed`.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Flag nonconvexity when converged objectives differ by more than `tolerance`.
+    pub fn with_tolerance(mut self, tolerance: f64) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// Run the starts one after another.
+    pub fn sequential(mut self) -> Self {
+        self.parallel = false;
+        self
+    }
+}
+
+/// Where one start began and where its search stopped.
+#[derive(Clone, Debug)]
+pub struct TerminalPoint {
+    /// Start number, which also determines its perturbation seed.
+    pub start: usize,
+    /// Starting `sigma`.
+    pub initial_sigma: DMatrix<f64>,
+    /// Starting `pi`, when it is estimated.
+    pub initial_pi: Option<DMatrix<f64>>,
+    /// Estimated `sigma`.
+    pub sigma: DMatrix<f64>,
+    /// Estimated `pi`, if any.
+    pub pi: Option<DMatrix<f64>>,
+    /// Estimated `rho`, if any.
+    pub rho: Option<f64>,
+    /// Estimated `beta`.
+    pub beta: DVector<f64>,
+    /// GMM objective at the estimate.
+    pub gmm_value: f64,
+    /// Whether the optimizer met its termination tolerances.
+    pub converged: bool,
+    /// Number of objective evaluations.
+    pub evaluations: usize,
+}
+
+/// Outcome of [`Problem::optimize_multistart`].
+#[derive(Clone, Debug)]
+pub struct MultiStartResults {
+    /// Settings the starts ran with.
+    pub options: MultiStartOptions,
+    /// Terminal points of the successful starts, in start order.
+    pub points: Vec<TerminalPoint>,
+    /// Starts whose optimization failed.
+    pub failures: Vec<usize>,
+    /// The run with the lowest objective.
+    pub best: OptimizationResults,
+    /// Whether converged starts stopped at objectives further apart than
+    /// [`MultiStartOptions::tolerance`], i.e. at different local minima.
+    pub nonconvex: bool,
+}
+
+impl MultiStartResults {
+    /// Largest minus smallest objective across the converged starts.
+    pub fn objective_spread(&self) -> f64 {
+        objective_spread(&self.points)
+    }
+}
+
+/// Largest minus smallest objective across the converged `points`, zero with fewer than
+/// two.
+fn objective_spread(points: &[TerminalPoint]) -> f64 {
+    let (lowest, highest) = points.iter().filter(|point| point.converged).fold(
+        (f64::INFINITY, f64::NEG_INFINITY),
+        |(lowest, highest), point| (lowest.min(point.gmm_value), highest.max(point.gmm_value)),
+    );
+    (highest - lowest).max(0.0)
+}
+
+/// Whether the converged `points` disagree by more than `tolerance * (1 + |best|)`.
+fn is_nonconvex(points: &[TerminalPoint], best: f64, tolerance: f64) -> bool {
+    objective_spread(points) > tolerance * (1.0 + best.abs())
+}
+
+impl Problem {
+    /// Run [`optimize`](Self::optimize) from `multistart.starts` starting values: the
+    /// problem's [`OptimizationOptions`] and perturbed copies of them, keeping the other
+    /// settings. Fails only when every start fails, with the error of the first.
+    pub fn optimize_multistart(&self, multistart: &MultiStartOptions) -> Result<MultiStartResults> {
+        if multistart.starts == 0 {
+            return Err(BlpError::dimension_mismatch("multistart starts", 1, 0));
+        }
+        if !multistart.scale.is_finite() || multistart.scale < 0.0 {
+            return Err(BlpError::NumericalError {
+                context: "multistart scale",
+            });
+        }
+        let run = |start: usize| {
+            let optimization = self.perturbed_start(multistart, start)?;
+            let mut options = self.options().clone();
+            options.optimization = optimization.clone();
+            let optimized = self.with_options_override(options).optimize()?;
+            Ok((optimization, optimized))
+        };
+        let outcomes: Vec<Result<(OptimizationOptions, OptimizationResults)>> =
+            if multistart.parallel {
+                parallel::run_nested(&self.options().parallelism, multistart.starts, |start| {
+                    Ok(run(start))
+                })?
+            } else {
+                (0..multistart.starts).map(run).collect()
+            };
+
+        let mut points = Vec::new();
+        let mut failures = Vec::new();
+        let mut best: Option<OptimizationResults> = None;
+        let mut first_error = None;
+        for (start, outcome) in outcomes.into_iter().enumerate() {
+            match outcome {
+                Ok((optimization, optimized)) => {
+                    let results = &optimized.results;
+                    points.push(TerminalPoint {
+                        start,
+                        initial_sigma: optimization
+                            .initial_sigma
+                            .unwrap_or_else(|| DMatrix::zeros(0, 0)),
+                        initial_pi: optimization.initial_pi,
+                        sigma: results.sigma.clone(),
+                        pi: results.pi.clone(),
+                        rho: results.rho,
+                        beta: results.beta.clone(),
+                        gmm_value: results.gmm_value,
+                        converged: optimized.converged,
+                        evaluations: optimized.evaluations,
+                    });
+                    if best
+                        .as_ref()
+                        .is_none_or(|best| results.gmm_value < best.results.gmm_value)
+                    {
+                        best = Some(optimized);
+                    }
+                }
+                Err(err) => {
+                    log::debug!("multistart start {start} failed: {err}");
+                    failures.push(start);
+                    first_error.get_or_insert(err);
+                }
+            }
+        }
+        let Some(best) = best else {
+            return Err(first_error.expect("every start failed with an error"));
+        };
+
+        let nonconvex = is_nonconvex(&points, best.results.gmm_value, multistart.tolerance);
+        if nonconvex {
+            log::warn!(
+                "multistart objectives differ by {:.3e} across converged starts; the GMM \
+                 objective has several local minima",
+                objective_spread(&points)
+            );
+        }
+        Ok(MultiStartResults {
+            options: multistart.clone(),
+            points,
+            failures,
+            best,
+            nonconvex,
+        })
+    }
+
+    /// The optimization settings of start `start`: unchanged for start 0, otherwise with
+    /// every searched entry of the starting `sigma` and `pi` scaled by a lognormal factor.
+    fn perturbed_start(
+        &self,
+        multistart: &MultiStartOptions,
+        start: usize,
+    ) -> Result<OptimizationOptions> {
+        let mut optimization = self.options().optimization.clone();
+        if start == 0 {
+            return Ok(optimization);
+        }
+        let mut rng = SmallRng::seed_from_u64(multistart.seed.wrapping_add(start as u64));
+        let mut factor = || -> f64 {
+            let e: f64 = StandardNormal.sample(&mut rng);
+            (multistart.scale * e).exp()
+        };
+        let mut sigma = initial_sigma(&optimization, self.data().nonlinear_dim())?;
+        if optimization.initial_sigma.is_some() {
+            for entry in searched_entries(&sigma, &optimization) {
+                sigma[entry] *= factor();
+            }
+            if let Some(bounds) = &optimization.bounds {
+                bounds.clamp(&mut sigma);
+            }
+            optimization.initial_sigma = Some(sigma);
+        }
+        if let Some(pi) = &optimization.initial_pi {
+            let mut pi = pi.clone();
+            for entry in searched_pi_entries(Some(&pi), &optimization) {
+                pi[entry] *= factor();
+            }
+            optimization.initial_pi = Some(pi);
+        }
+        Ok(optimization)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integration::SimulationDraws;
+    use crate::options::ProblemOptions;
+    use crate::testing::TestMarkets;
+
+    #[test]
+    fn perturbed_starts_keep_every_terminal_point() {
+        let shares = DVector::from_fn(24, |j, _| 0.05 + 0.04 * ((j * 3) % 4) as f64);
+        let data = TestMarkets::new(24).data(shares);
+        let start = DMatrix::from_row_slice(2, 2, &[1.0, 0.0, 0.3, 0.5]);
+        let options = ProblemOptions::default()
+            .with_optimization(OptimizationOptions::new(start.clone()).with_tolerances(1e-8, 1e-3));
+        let problem =
+            Problem::with_options(data, SimulationDraws::standard_normal(20, 2, 3), options)
+                .unwrap();
+
+        let multistart = MultiStartOptions::new(3, 0.5).with_seed(7);
+        let results = problem.optimize_multistart(&multistart).unwrap();
+        assert!(
+            results.failures.is_empty(),
+            "failed starts {:?}",
+            results.failures
+        );
+        assert_eq!(results.points.len(), 3);
+        let first = &results.points[0];
+        assert_eq!((first.start, &first.initial_sigma), (0, &start));
+        for point in &results.points[1..] {
+            // Perturbations keep the zero pattern and the signs of the start.
+            assert_eq!(point.initial_sigma[(0, 1)], 0.0);
+            assert!(
+                point
+                    .initial_sigma
+                    .component_mul(&start)
+                    .iter()
+                    .all(|&v| v >= 0.0)
+            );
+            assert_ne!(point.initial_sigma, start);
+        }
+        let lowest = results
+            .points
+            .iter()
+            .map(|point| point.gmm_value)
+            .fold(f64::INFINITY, f64::min);
+        assert_eq!(results.best.results.gmm_value, lowest);
+        assert_eq!(
+            results.nonconvex,
+            is_nonconvex(&results.points, lowest, multistart.tolerance)
+        );
+
+        let sequential = problem
+            .optimize_multistart(&multistart.clone().sequential())
+            .unwrap();
+        let values = |results: &MultiStartResults| -> Vec<f64> {
+            results.points.iter().map(|point| point.gmm_value).collect()
+        };
+        assert_eq!(values(&sequential), values(&results));
+        assert!(
+            problem
+                .optimize_multistart(&MultiStartOptions::new(0, 0.5))
+                .is_err()
+        );
+
+        // Perturbed starts check the bounds as the search does instead of clamping.
+        let mut options = problem.options().clone();
+        options.optimization.bounds = Some(crate::ParameterBounds::nonnegative_diagonal(3));
+        let problem = problem.with_options_override(options);
+        assert!(problem.perturbed_start(&multistart, 0).is_ok());
+        assert!(problem.perturbed_start(&multistart, 1).is_err());
+    }
+
+    #[test]
+    fn distinct_converged_minima_mark_the_objective_nonconvex() {
+        let point = |start: usize, gmm_value: f64, converged: bool| TerminalPoint {
+            start,
+            initial_sigma: DMatrix::identity(1, 1),
+            initial_pi: None,
+            sigma: DMatrix::identity(1, 1),
+            pi: None,
+            rho: None,
+            beta: DVector::zeros(1),
+            gmm_value,
+            converged,
+            evaluations: 1,
+        };
+        // Two starts stopped in the same minimum, a third in a higher one.
+        let same = [point(0, 2.0, true), point(1, 2.0 + 1e-6, true)];
+        assert!(!is_nonconvex(&same, 2.0, 1e-4));
+        let second = [same[0].clone(), same[1].clone(), point(2, 2.5, true)];
+        assert_eq!(objective_spread(&second), 0.5);
+        assert!(is_nonconvex(&second, 2.0, 1e-4));
+        assert!(!is_nonconvex(&second, 2.0, 0.5));
+
+        // Unconverged starts and a lone converged start say nothing about convexity.
+        let stalled = [same[0].clone(), point(1, 9.0, false)];
+        assert_eq!(objective_spread(&stalled), 0.0);
+        assert!(!is_nonconvex(&stalled, 2.0, 1e-4));
+        assert_eq!(objective_spread(&[]), 0.0);
+    }
+}
@@ -0,0 +1,1648 @@
+//! Searching over the nonlinear parameters.
+//!
+//! [`Problem::optimize`] minimizes the GMM objective over `sigma` with the starting
+//! values, bounds, and termination criteria in
+//! [`ProblemOptions::optimization`](crate::ProblemOptions::optimization). Zero entries of
+//! the starting `sigma` stay at zero, as in pyBLP; so do entries with equal bounds in
+//! [`ParameterBounds`](crate::ParameterBounds) and those a [`ParameterMask`] fixes. The
+//! nonzero entries of a starting `pi` are searched alongside `sigma`, and under nesting
+//! so is the nesting parameter `rho`. [`Problem::optimize_blocks`] searches the same
+//! coordinates, addressed as [`ParameterCoordinate`]s, one [`ParameterBlocks`] block at a
+//! time.
+//! [`Problem::prune_heterogeneity`] drops random coefficients whose
+//! estimated spread is negligible and re-estimates the smaller model.
+
+use std::cell::Cell;
+
+use nalgebra::DMatrix;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{BlpError, Result};
+use crate::estimation::{GmmStep, Problem, ProblemResults, updated_weighting};
+use crate::linalg::Factorizations;
+use crate::options::{OptimizationOptions, WeightingMatrix};
+use crate::parameters::{NonlinearParameters, ParameterMask};
+
+/// One coordinate of the nonlinear parameters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParameterCoordinate {
+    /// Entry `(row, column)` of `sigma`.
+    Sigma(usize, usize),
+    /// Entry `(row, column)` of `pi`.
+    Pi(usize, usize),
+    /// The nesting parameter `rho`.
+    Rho,
+}
+
+impl ParameterCoordinate {
+    /// The coordinate's value in `parameters`, or an error when it has no such entry.
+    fn value(self, parameters: &NonlinearParameters) -> Result<f64> {
+        let entry = |matrix: &DMatrix<f64>, row: usize, column: usize| {
+            if row >= matrix.nrows() {
+                Err(BlpError::dimension_mismatch(
+                    "parameter block rows",
+                    matrix.nrows(),
+                    row + 1,
+                ))
+            } else if column >= matrix.ncols() {
+                Err(BlpError::dimension_mismatch(
+                    "parameter block columns",
+                    matrix.ncols(),
+                    column + 1,
+                ))
+            } else {
+                Ok(matrix[(row, column)])
+            }
+        };
+        match self {
+            Self::Sigma(row, column) => entry(parameters.sigma(), row, column),
+            Self::Pi(row, column) => match parameters.pi() {
+                Some(pi) => entry(pi, row, column),
+                None => Err(BlpError::missing_component("starting values for pi")),
+            },
+            Self::Rho => parameters
+                .rho()
+                .ok_or_else(|| BlpError::missing_component("starting value for rho")),
+        }
+    }
+
+    /// `parameters` with the coordinate at `value`; it must be one [`value`](Self::value)
+    /// reads.
+    fn set(self, parameters: &NonlinearParameters, value: f64) -> NonlinearParameters {
+        let mut sigma = parameters.sigma().clone();
+        let mut pi = parameters.pi().cloned();
+        let mut rho = parameters.rho();
+        match self {
+            Self::Sigma(row, column) => sigma[(row, column)] = value,
+            Self::Pi(row, column) => {
+                if let Some(pi) = &mut pi {
+                    pi[(row, column)] = value;
+                }
+            }
+            Self::Rho => rho = Some(value),
+        }
+        let mut updated = NonlinearParameters::new(sigma);
+        if let Some(pi) = pi {
+            updated = updated.with_pi(pi);
+        }
+        match rho {
+            Some(rho) => updated.with_rho(rho),
+            None => updated,
+        }
+    }
+}
+
+/// Groups of nonlinear parameter coordinates that are optimized together.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParameterBlocks {
+    blocks: Vec<Vec<ParameterCoordinate>>,
+}
+
+impl ParameterBlocks {
+    /// Use explicit blocks of coordinates.
+    pub fn new(blocks: Vec<Vec<ParameterCoordinate>>) -> Self {
+        Self { blocks }
+    }
+
+    /// One block per nonzero entry of `sigma` and `pi`, and one for `rho` when it is set.
+    pub fn per_parameter(parameters: &NonlinearParameters) -> Self {
+        Self::new(
+            coordinates(parameters)
+                .into_iter()
+                .map(|coordinate| vec![coordinate])
+                .collect(),
+        )
+    }
+
+    /// A single block with every coordinate of [`per_parameter`](Self::per_parameter)
+    /// (joint search).
+    pub fn joint(parameters: &NonlinearParameters) -> Self {
+        Self::new(vec![coordinates(parameters)])
+    }
+
+    /// The blocks, in the order they are visited.
+    pub fn blocks(&self) -> &[Vec<ParameterCoordinate>] {
+        &self.blocks
+    }
+
+    /// Stack the coordinates covered by the blocks into one vector, in block order.
+    pub fn pack(&self, parameters: &NonlinearParameters) -> Result<Vec<f64>> {
+        self.blocks
+            .iter()
+            .flatten()
+            .map(|&coordinate| coordinate.value(parameters))
+            .collect()
+    }
+
+    /// Write `values` (as produced by [`pack`](Self::pack)) back into `parameters`.
+    pub fn unpack(&self, values: &[f64], parameters: &mut NonlinearParameters) {
+        for (&coordinate, value) in self.blocks.iter().flatten().zip(values) {
+            *parameters = coordinate.set(parameters, *value);
+        }
+    }
+}
+
+fn coordinates(parameters: &NonlinearParameters) -> Vec<ParameterCoordinate> {
+    let sigma = free_entries(parameters.sigma())
+        .into_iter()
+        .map(|(row, column)| ParameterCoordinate::Sigma(row, column));
+    let pi = parameters
+        .pi()
+        .map(free_entries)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(row, column)| ParameterCoordinate::Pi(row, column));
+    let rho = parameters.rho().map(|_| ParameterCoordinate::Rho);
+    sigma.chain(pi).chain(rho).collect()
+}
+
+pub(crate) fn free_entries(sigma: &DMatrix<f64>) -> Vec<(usize, usize)> {
+    let mut entries = Vec::new();
+    for column in 0..sigma.ncols() {
+        for row in 0..sigma.nrows() {
+            if sigma[(row, column)] != 0.0 {
+                entries.push((row, column));
+            }
+        }
+    }
+    entries
+}
+
+/// Whether `sigma[entry]` is fixed by equal bounds or by the mask.
+pub(crate) fn is_held_fixed(entry: (usize, usize), options: &OptimizationOptions) -> bool {
+    options
+        .bounds
+        .as_ref()
+        .is_some_and(|bounds| bounds.is_fixed(entry))
+        || options
+            .mask
+            .as_ref()
+            .is_some_and(|mask| mask.sigma_value(entry).is_some())
+}
+
+/// The nonzero entries of the starting `sigma` that are not fixed by equal bounds or the
+/// mask, and only those on the diagonal under [`OptimizationOptions::diagonal_sigma`].
+pub(crate) fn searched_entries(
+    sigma: &DMatrix<f64>,
+    options: &OptimizationOptions,
+) -> Vec<(usize, usize)> {
+    let mut entries = free_entries(sigma);
+    entries.retain(|&entry| !is_held_fixed(entry, options));
+    if options.diagonal_sigma {
+        entries.retain(|&(row, column)| row == column);
+    }
+    if options.cholesky {
+        entries.retain(|&(row, column)| row >= column);
+    }
+    entries
+}
+
+/// The nonzero entries of the starting `pi` that the mask leaves free.
+pub(crate) fn searched_pi_entries(
+    pi: Option<&DMatrix<f64>>,
+    options: &OptimizationOptions,
+) -> Vec<(usize, usize)> {
+    let mut entries = pi.map(free_entries).unwrap_or_default();
+    if let Some(mask) = &options.mask {
+        entries.retain(|&entry| mask.pi_value(entry).is_none());
+    }
+    entries
+}
+
+/// The optimizer's coordinate for `value` at `sigma[entry]`: its log for a diagonal
+/// entry under [`OptimizationOptions::cholesky`], and `value` itself otherwise.
+pub(crate) fn search_coordinate(
+    entry: (usize, usize),
+    value: f64,
+    options: &OptimizationOptions,
+) -> f64 {
+    if options.cholesky && entry.0 == entry.1 {
+        value.max(f64::MIN_POSITIVE).ln()
+    } else {
+        value
+    }
+}
+
+/// Inverse of [`search_coordinate`].
+pub(crate) fn sigma_entry(
+    entry: (usize, usize),
+    coordinate: f64,
+    options: &OptimizationOptions,
+) -> f64 {
+    if options.cholesky && entry.0 == entry.1 {
+        coordinate.exp()
+    } else {
+        coordinate
+    }
+}
+
+/// The configured starting `sigma` of a model with `k2` random coefficients, after
+/// checking its shape and [validating](crate::ParameterBounds::validate) any bounds.
+pub(crate) fn initial_sigma(options: &OptimizationOptions, k2: usize) -> Result<DMatrix<f64>> {
+    let start = match &options.initial_sigma {
+        Some(sigma) => sigma.clone(),
+        None if k2 == 0 => DMatrix::zeros(0, 0),
+        None => return Err(BlpError::missing_component("starting values for sigma")),
+    };
+    if start.shape() != (k2, k2) {
+        return Err(BlpError::dimension_mismatch(
+            "starting sigma rows",
+            k2,
+            start.nrows(),
+        ));
+    }
+    if let Some(bounds) = &options.bounds {
+        bounds.validate(k2)?;
+    }
+    Ok(start)
+}
+
+/// The starting `sigma` with fixed entries at their bounds or masked values and, under
+/// [`OptimizationOptions::diagonal_sigma`], the off-diagonal entries at zero.
+pub(crate) fn starting_sigma(
+    mut sigma: DMatrix<f64>,
+    options: &OptimizationOptions,
+) -> DMatrix<f64> {
+    if let Some(bounds) = &options.bounds {
+        bounds.hold_fixed(&mut sigma);
+    }
+    if options.diagonal_sigma {
+        sigma = DMatrix::from_diagonal(&sigma.diagonal());
+    }
+    if options.cholesky {
+        sigma = cholesky_factor(sigma);
+    }
+    if let Some(mask) = &options.mask {
+        mask.hold_sigma(&mut sigma);
+    }
+    sigma
+}
+
+/// A lower-triangular `L` with a nonnegative diagonal and `L L' = sigma sigma'`.
+///
+/// A lower-triangular `sigma` only has the signs of its columns flipped, keeping its
+/// zeros; otherwise the factor of a singular `sigma sigma'` falls back to the lower
+/// triangle of `sigma`.
+fn cholesky_factor(mut sigma: DMatrix<f64>) -> DMatrix<f64> {
+    if sigma.is_square() && sigma.upper_triangle() == DMatrix::from_diagonal(&sigma.diagonal()) {
+        for column in 0..sigma.ncols() {
+            if sigma[(column, column)] < 0.0 {
+                sigma.column_mut(column).neg_mut();
+            }
+        }
+        return sigma;
+    }
+    match (&sigma * sigma.transpose()).cholesky() {
+        Some(factor) => factor.l(),
+        None => cholesky_factor(sigma.lower_triangle()),
+    }
+}
+
+/// One objective evaluation on the optimizer's path.
+///
+/// `delta` and `xi` are stored in single precision to halve the memory of long runs;
+/// that is plenty for plotting or animating the path but not for resuming from it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EvaluationRecord {
+    /// `[beta; vec(sigma); vec(pi)]`, with `sigma` and `pi` stacked column-major, and
+    /// `vec(pi)` only when `pi` is estimated, followed by `rho` under nesting.
+    pub theta: Vec<f64>,
+    /// GMM objective.
+    pub objective: f64,
+    /// Mean utilities.
+    pub delta: Vec<f32>,
+    /// Structural errors.
+    pub xi: Vec<f32>,
+}
+
+impl EvaluationRecord {
+    fn new(results: &ProblemResults) -> Self {
+        let single = |values: &[f64]| values.iter().map(|&value| value as f32).collect();
+        Self {
+            theta: results
+                .beta
+                .iter()
+                .chain(results.sigma.iter())
+                .chain(results.pi.iter().flatten())
+                .chain(results.rho.iter())
+                .copied()
+                .collect(),
+            objective: results.gmm_value,
+            delta: single(results.delta.as_slice()),
+            xi: single(results.xi.as_slice()),
+        }
+    }
+}
+
+/// Outcome of [`Problem::optimize`].
+#[derive(Clone, Debug)]
+pub struct OptimizationResults {
+    /// Solution at the best `sigma` found.
+    pub results: ProblemResults,
+    /// Number of optimizer iterations.
+    pub iterations: usize,
+    /// Number of objective evaluations, including failed ones.
+    pub evaluations: usize,
+    /// Whether the objective and parameter tolerances were met before the iteration or
+    /// evaluation limits.
+    pub converged: bool,
+    /// Every successful evaluation in order, when
+    /// [`OptimizationOptions::record_history`] is set.
+    pub history: Vec<EvaluationRecord>,
+}
+
+impl ProblemResults {
+    /// The entries of `sigma` and `pi` that [`Problem::optimize`] held fixed, at their
+    /// values: zero entries and those fixed by equal bounds or by
+    /// [`OptimizationOptions::mask`].
+    ///
+    /// Which entries were searched follows from the starting values in
+    /// [`options_used`](Self::options_used), not from the estimates, so a searched entry
+    /// that ended up at zero is still reported as free.
+    pub fn held_fixed(&self) -> ParameterMask {
+        let options = &self.options_used.optimization;
+        let start = options.initial_sigma.clone().map_or_else(
+            || self.sigma.clone(),
+            |sigma| starting_sigma(sigma, options),
+        );
+        let searched = searched_entries(&start, options);
+        let sigma = DMatrix::from_fn(self.sigma.nrows(), self.sigma.ncols(), |row, column| {
+            (!searched.contains(&(row, column))).then(|| self.sigma[(row, column)])
+        });
+        let pi = self.pi.as_ref().map(|pi| {
+            let mut start = options.initial_pi.clone().unwrap_or_else(|| pi.clone());
+            if let Some(mask) = &options.mask {
+                mask.hold_pi(&mut start);
+            }
+            let searched = searched_pi_entries(Some(&start), options);
+            DMatrix::from_fn(pi.nrows(), pi.ncols(), |row, column| {
+                (!searched.contains(&(row, column))).then(|| pi[(row, column)])
+            })
+        });
+        ParameterMask::from_entries(sigma, pi)
+    }
+}
+
+impl Problem {
+    /// Minimize the GMM objective over the nonzero entries of the starting `sigma` and
+    /// [`OptimizationOptions::initial_pi`], and over `rho` when
+    /// [`OptimizationOptions::initial_rho`] is set. Entries fixed by
+    /// [`OptimizationOptions::mask`] are held at their values.
+    ///
+    /// Uses a Nelder–Mead simplex search, configured by
+    /// [`ProblemOptions::optimization`](crate::ProblemOptions::optimization); candidates
+    /// outside the bounds are moved onto them, and candidates whose contraction fails are
+    /// treated as infeasible.
+    ///
+    /// With [`GmmOptions::update_weighting`](crate::GmmOptions::update_weighting) set, this
+    /// is two-step (or iterated) GMM: after each step the weighting matrix is rebuilt as
+    /// `(Z' diag(xi^2) Z)^{-1}` from that step's `xi` and the objective is re-minimized from
+    /// the step's `sigma`, for up to `gmm.max_iterations` steps (at least two). Every step
+    /// is reported in [`ProblemResults::gmm_steps`]. Continuously-updated weighting
+    /// ([`WeightingMatrix::ContinuouslyUpdated`]) is already efficient and runs one step.
+    ///
+    /// The estimates carry their sandwich covariance in [`ProblemResults::covariance`]
+    /// whenever [`Problem::parameter_covariance`] can compute it.
+    pub fn optimize(&self) -> Result<OptimizationResults> {
+        if let Some(problem) = self.recording_run()? {
+            return problem.optimize();
+        }
+        let start = self.starting_parameters(&self.options().optimization)?;
+        let gmm = &self.options().gmm;
+        if !gmm.update_weighting || matches!(gmm.weighting, WeightingMatrix::ContinuouslyUpdated) {
+            return Ok(self.with_covariance(self.optimize_step(&start)?));
+        }
+        let mut steps = Vec::new();
+        let mut total = self.optimize_step(&start)?;
+        loop {
+            let step = &total.results;
+            steps.push(gmm_step(step));
+            if steps.len() >= gmm.max_iterations.max(2) {
+                break;
+            }
+            let mut factorizations = Factorizations::new(gmm.strict_factorizations);
+            let (weighting, approximation) =
+                updated_weighting(self.data(), &step.xi, gmm, &mut factorizations)?;
+            let mut options = self.options().clone();
+            options.gmm.weighting = WeightingMatrix::Provided(weighting);
+            let mut next = self
+                .with_options_override(options)
+                .optimize_step(&step.parameters())?;
+            next.results
+                .conditioning
+                .pseudo_inverses
+                .extend(factorizations.into_fallbacks());
+            if approximation.is_some() {
+                next.results.conditioning.weighting_approximation = approximation;
+            }
+            let pi_moved = match (&next.results.pi, &step.pi) {
+                (Some(next), Some(previous)) => (next - previous).amax(),
+                _ => 0.0,
+            };
+            let moved = (&next.results.sigma - &step.sigma)
+                .amax()
+                .max(pi_moved)
+                .max((next.results.rho.unwrap_or(0.0) - step.rho.unwrap_or(0.0)).abs());
+            total = OptimizationResults {
+                iterations: total.iterations + next.iterations,
+                evaluations: total.evaluations + next.evaluations,
+                history: [total.history, next.history].concat(),
+                ..next
+            };
+            if moved < gmm.tolerance {
+                steps.push(gmm_step(&total.results));
+                break;
+            }
+        }
+        total.results.gmm_steps = steps;
+        Ok(self.with_covariance(total))
+    }
+
+    /// The starting values in `options`, checked against the problem's dimensions, with
+    /// the entries that the bounds and mask fix at their values and the shape that
+    /// [`OptimizationOptions::diagonal_sigma`] and [`OptimizationOptions::cholesky`]
+    /// impose.
+    fn starting_parameters(&self, options: &OptimizationOptions) -> Result<NonlinearParameters> {
+        let k2 = self.data().nonlinear_dim();
+        let start = initial_sigma(options, k2)?;
+
+        let demographics = self.draws().demographics().map_or(0, |d| d.ncols());
+        if let Some(mask) = &options.mask {
+            mask.validate(k2, demographics, options.initial_pi.is_some())?;
+        }
+        let mut start = NonlinearParameters::new(starting_sigma(start, options));
+        if let Some(pi) = &options.initial_pi {
+            if pi.shape() != (k2, demographics) {
+                return Err(BlpError::dimension_mismatch(
+                    "starting pi columns",
+                    demographics,
+                    pi.ncols(),
+                ));
+            }
+            let mut pi = pi.clone();
+            if let Some(mask) = &options.mask {
+                mask.hold_pi(&mut pi);
+            }
+            start = start.with_pi(pi);
+        }
+        if let Some(rho) = options.initial_rho {
+            if !(0.0..1.0).contains(&rho) {
+                return Err(BlpError::InvalidNestingParameter { rho });
+            }
+            start = start.with_rho(rho);
+        }
+        Ok(start)
+    }
+
+    /// Attach the sandwich covariance of the estimates when it can be computed.
+    fn with_covariance(&self, mut optimized: OptimizationResults) -> OptimizationResults {
+        match self.parameter_covariance(&optimized.results) {
+            Ok(covariance) => optimized.results.covariance = Some(covariance),
+            Err(err) => log::debug!("no standard errors at the estimates: {err}"),
+        }
+        optimized
+    }
+
+    /// One Nelder–Mead minimization from `start` under the problem's weighting matrix,
+    /// over the nonzero, unfixed entries of `sigma` and `pi` and, when it is set, `rho`.
+    fn optimize_step(&self, start: &NonlinearParameters) -> Result<OptimizationResults> {
+        let options = &self.options().optimization;
+        let entries = searched_entries(start.sigma(), options);
+        let pi_entries = searched_pi_entries(start.pi(), options);
+        let searched = entries.len() + pi_entries.len();
+        let to_parameters = |point: &[f64]| {
+            let mut sigma = start.sigma().clone();
+            for (&entry, value) in entries.iter().zip(point) {
+                sigma[entry] = sigma_entry(entry, *value, options);
+            }
+            let mut parameters = NonlinearParameters::new(sigma);
+            if let Some(pi) = start.pi() {
+                let mut pi = pi.clone();
+                for (&entry, value) in pi_entries.iter().zip(&point[entries.len()..]) {
+                    pi[entry] = *value;
+                }
+                parameters = parameters.with_pi(pi);
+            }
+            match point.get(searched) {
+                Some(&rho) => parameters.with_rho(rho),
+                None => parameters,
+            }
+        };
+        let project = |point: &mut [f64]| {
+            if let Some(bounds) = &options.bounds {
+                let mut sigma = to_parameters(point).sigma().clone();
+                bounds.clamp(&mut sigma);
+                for (&entry, value) in entries.iter().zip(point.iter_mut()) {
+                    *value = search_coordinate(entry, sigma[entry], options);
+                }
+            }
+            if let Some(rho) = point.get_mut(searched) {
+                *rho = rho.clamp(0.0, MAX_RHO);
+            }
+        };
+        let mut best: Option<ProblemResults> = None;
+        let mut history = Vec::new();
+        let mut origin: Vec<f64> = entries
+            .iter()
+            .map(|&entry| search_coordinate(entry, start.sigma()[entry], options))
+            .collect();
+        if let Some(pi) = start.pi() {
+            origin.extend(pi_entries.iter().map(|&entry| pi[entry]));
+        }
+        origin.extend(start.rho());
+        let searching = self.deferring_conditioning();
+        let search = nelder_mead(origin, options, project, |point| {
+            match searching.solve(&to_parameters(point)) {
+                Ok(results) => {
+                    if options.record_history {
+                        history.push(EvaluationRecord::new(&results));
+                    }
+                    let value = results.gmm_value;
+                    if best.as_ref().is_none_or(|best| value < best.gmm_value) {
+                        best = Some(results);
+                    }
+                    value
+                }
+                Err(err) => {
+                    log::debug!("infeasible nonlinear parameter candidate: {err}");
+                    f64::INFINITY
+                }
+            }
+        });
+        let results = match best {
+            Some(mut results) => {
+                self.complete_conditioning(&mut results);
+                results
+            }
+            // Surface the inner-loop error at the starting values.
+            None => self.solve(&to_parameters(&search.point))?,
+        };
+        Ok(OptimizationResults {
+            results,
+            iterations: search.iterations,
+            evaluations: search.evaluations,
+            converged: search.converged,
+            history,
+        })
+    }
+}
+
+/// Largest nesting parameter the optimizer visits; the contraction slows to a crawl as
+/// `rho` approaches one.
+pub(crate) const MAX_RHO: f64 = 0.99;
+
+fn gmm_step(results: &ProblemResults) -> GmmStep {
+    GmmStep {
+        sigma: results.sigma.clone(),
+        pi: results.pi.clone(),
+        rho: results.rho,
+        beta: results.beta.clone(),
+        gmm_value: results.gmm_value,
+        weighting_matrix: results.weighting_matrix.clone(),
+    }
+}
+
+/// Terminal state of a [`nelder_mead`] search.
+pub(crate) struct SimplexSearch {
+    pub(crate) point: Vec<f64>,
+    pub(crate) iterations: usize,
+    pub(crate) evaluations: usize,
+    pub(crate) converged: bool,
+}
+
+/// Minimize `objective` from `origin` with a Nelder–Mead simplex search, using the
+/// termination settings in `options`.
+///
+/// Every candidate is first moved by `project` (e.g. onto bounds); infeasible candidates
+/// should evaluate to infinity.
+pub(crate) fn nelder_mead(
+    origin: Vec<f64>,
+    options: &OptimizationOptions,
+    project: impl Fn(&mut [f64]),
+    mut objective: impl FnMut(&[f64]) -> f64,
+) -> SimplexSearch {
+    let dimension = origin.len();
+    let evaluations = Cell::new(0);
+    let mut evaluate = |point: &mut Vec<f64>| {
+        project(point);
+        evaluations.set(evaluations.get() + 1);
+        objective(point)
+    };
+
+    let mut origin = origin;
+    let origin_value = evaluate(&mut origin);
+    let mut simplex = vec![(origin.clone(), origin_value)];
+    for index in 0..dimension {
+        // Step away from a bound the starting value sits on so the simplex has volume.
+        let mut vertex = origin.clone();
+        vertex[index] += options.initial_step;
+        project(&mut vertex);
+        if vertex[index] == origin[index] {
+            vertex[index] -= options.initial_step;
+        }
+        let value = evaluate(&mut vertex);
+        simplex.push((vertex, value));
+    }
+
+    let mut iterations = 0;
+    let mut converged = dimension == 0;
+    while !converged
+        && iterations < options.max_iterations
+        && evaluations.get() < options.max_evaluations
+    {
+        iterations += 1;
+        simplex.sort_by(|a, b| a.1.total_cmp(&b.1));
+        let (best, best_value) = &simplex[0];
+        let (value_spread, point_spread) = simplex[1..]
+            .iter()
+            .map(|(vertex, value)| {
+                let distance = vertex
+                    .iter()
+                    .zip(best)
+                    .map(|(a, b)| (a - b).abs())
+                    .fold(0.0, f64::max);
+                ((value - best_value).abs(), distance)
+            })
+            .fold((0.0_f64, 0.0_f64), |acc, next| {
+                (acc.0.max(next.0), acc.1.max(next.1))
+            });
+        if value_spread <= options.objective_tolerance
+            && point_spread <= options.parameter_tolerance
+        {
+            converged = true;
+            break;
+        }
+
+        let worst = simplex[dimension].clone();
+        let centroid: Vec<f64> = (0..dimension)
+            .map(|index| {
+                simplex[..dimension]
+                    .iter()
+                    .map(|(vertex, _)| vertex[index])
+                    .sum::<f64>()
+                    / dimension as f64
+            })
+            .collect();
+        let toward = |scale: f64| -> Vec<f64> {
+            centroid
+                .iter()
+                .zip(&worst.0)
+                .map(|(c, w)| c + scale * (c - w))
+                .collect()
+        };
+
+        let mut reflected = toward(1.0);
+        let reflected_value = evaluate(&mut reflected);
+        if reflected_value < simplex[0].1 {
+            let mut expanded = toward(2.0);
+            let expanded_value = evaluate(&mut expanded);
+            simplex[dimension] = if expanded_value < reflected_value {
+                (expanded, expanded_value)
+            } else {
+                (reflected, reflected_value)
+            };
+        } else if reflected_value < simplex[dimension - 1].1 {
+            simplex[dimension] = (reflected, reflected_value);
+        } else {
+            let outside = reflected_value < worst.1;
+            let mut contracted = toward(if outside { 0.5 } else { -0.5 });
+            let contracted_value = evaluate(&mut contracted);
+            if contracted_value < reflected_value.min(worst.1) {
+                simplex[dimension] = (contracted, contracted_value);
+            } else {
+                let anchor = simplex[0].0.clone();
+                for (vertex, value) in simplex.iter_mut().skip(1) {
+                    for (coordinate, best) in vertex.iter_mut().zip(&anchor) {
+                        *coordinate = best + 0.5 * (*coordinate - best);
+                    }
+                    *value = evaluate(vertex);
+                }
+            }
+        }
+    }
+
+    simplex.sort_by(|a, b| a.1.total_cmp(&b.1));
+    SimplexSearch {
+        point: simplex.swap_remove(0).0,
+        iterations,
+        evaluations: evaluations.get(),
+        converged,
+    }
+}
+
+/// Settings for alternating (block coordinate) minimization of the GMM objective.
+#[derive(Clone, Debug)]
+pub struct BlockCoordinateOptions {
+    /// Blocks visited in turn within each sweep.
+    pub blocks: ParameterBlocks,
+    /// Maximum number of sweeps over all blocks.
+    pub max_sweeps: usize,
+    /// Stop when a full sweep lowers the objective by less than this fraction of
+    /// `1 + |objective|`.
+    pub objective_tolerance: f64,
+    /// Starting step of the compass search within each block.
+    pub initial_step: f64,
+    /// A block is done when its step has shrunk below this size.
+    pub step_tolerance: f64,
+}
+
+impl BlockCoordinateOptions {
+    /// Default search settings over `blocks`.
+    pub fn new(blocks: ParameterBlocks) -> Self {
+        Self {
+            blocks,
+            max_sweeps: 50,
+            objective_tolerance: 1e-8,
+            initial_step: 0.5,
+            step_tolerance: 1e-4,
+        }
+    }
+}
+
+/// Outcome of a block coordinate search.
+#[derive(Clone, Debug)]
+pub struct BlockCoordinateResults {
+    /// Solution at the best `sigma` found.
+    pub results: ProblemResults,
+    /// Objective after each sweep.
+    pub history: Vec<f64>,
+    /// Number of objective evaluations, including failed ones.
+    pub evaluations: usize,
+    /// Whether the sweep-level objective tolerance was met.
+    pub converged: bool,
+}
+
+impl Problem {
+    /// Minimize the GMM objective over the nonlinear parameters one block of coordinates
+    /// at a time, starting from `initial`.
+    ///
+    /// Each block is searched with a compass (pattern) search while the other coordinates
+    /// are held fixed; sweeps repeat until the objective stops improving. Alternating over
+    /// blocks can make progress where a joint search stalls, e.g. when some parameters
+    /// are much better identified than others. Candidates whose contraction fails are
+    /// treated as infeasible.
+    ///
+    /// The search honours [`ProblemOptions::optimization`](crate::ProblemOptions::optimization)
+    /// as [`Problem::optimize`] does: `initial` is checked and shaped the same way, block
+    /// coordinates that `optimize` would hold fixed (zero entries, entries fixed by the
+    /// bounds or mask, and off-diagonal entries under
+    /// [`OptimizationOptions::diagonal_sigma`]) stay at their starting values, and
+    /// candidates are moved onto the bounds.
+    pub fn optimize_blocks(
+        &self,
+        initial: &NonlinearParameters,
+        options: &BlockCoordinateOptions,
+    ) -> Result<BlockCoordinateResults> {
+        if let Some(problem) = self.recording_run()? {
+            return problem.optimize_blocks(initial, options);
+        }
+        let mut optimization = self.options().optimization.clone();
+        optimization.initial_sigma = Some(initial.sigma().clone());
+        optimization.initial_pi = initial.pi().cloned();
+        optimization.initial_rho = initial.rho();
+        let start = self.starting_parameters(&optimization)?;
+        options.blocks.pack(&start)?;
+        let entries = searched_entries(start.sigma(), &optimization);
+        let pi_entries = searched_pi_entries(start.pi(), &optimization);
+        let blocks: Vec<Vec<ParameterCoordinate>> = options
+            .blocks
+            .blocks()
+            .iter()
+            .map(|block| {
+                block
+                    .iter()
+                    .copied()
+                    .filter(|&coordinate| match coordinate {
+                        ParameterCoordinate::Sigma(row, column) => entries.contains(&(row, column)),
+                        ParameterCoordinate::Pi(row, column) => pi_entries.contains(&(row, column)),
+                        ParameterCoordinate::Rho => true,
+                    })
+                    .collect()
+            })
+            .collect();
+        // A step of `step` along `coordinate` from `parameters`, in the optimizer's
+        // coordinates and moved onto the bounds.
+        let step_along =
+            |parameters: &NonlinearParameters, coordinate: ParameterCoordinate, step: f64| {
+                let value = coordinate.value(parameters).unwrap_or_default();
+                let moved = match coordinate {
+                    ParameterCoordinate::Sigma(row, column) => {
+                        let entry = (row, column);
+                        let moved = search_coordinate(entry, value, &optimization) + step;
+                        let moved = sigma_entry(entry, moved, &optimization);
+                        match &optimization.bounds {
+                            Some(bounds) => moved.clamp(bounds.lower[entry], bounds.upper[entry]),
+                            None => moved,
+                        }
+                    }
+                    ParameterCoordinate::Pi(..) => value + step,
+                    ParameterCoordinate::Rho => (value + step).clamp(0.0, MAX_RHO),
+                };
+                (moved != value).then(|| coordinate.set(parameters, moved))
+            };
+        let searching = self.deferring_conditioning();
+        let mut best = searching.solve(&start)?;
+        let mut evaluations = 1;
+        let mut history = Vec::new();
+        let mut converged = false;
+
+        for _ in 0..options.max_sweeps {
+            let start = best.gmm_value;
+            for block in &blocks {
+                let mut step = options.initial_step;
+                while step >= options.step_tolerance {
+                    let mut improved = false;
+                    'coordinates: for &coordinate in block {
+                        for direction in [1.0, -1.0] {
+                            let Some(parameters) =
+                                step_along(&best.parameters(), coordinate, direction * step)
+                            else {
+                                continue;
+                            };
+                            evaluations += 1;
+                            match searching.solve(&parameters) {
+                                Ok(candidate) if candidate.gmm_value < best.gmm_value => {
+                                    best = candidate;
+                                    improved = true;
+                                    break 'coordinates;
+                                }
+                                Ok(_) => {}
+                                Err(err) => {
+                                    log::debug!("infeasible nonlinear parameter candidate: {err}")
+                                }
+                            }
+                        }
+                    }
+                    if !improved {
+                        step /= 2.0;
+                    }
+                }
+            }
+            history.push(best.gmm_value);
+            if start - best.gmm_value < options.objective_tolerance * (1.0 + start.abs()) {
+                converged = true;
+                break;
+            }
+        }
+
+        self.complete_conditioning(&mut best);
+        Ok(BlockCoordinateResults {
+            results: best,
+            history,
+            evaluations,
+            converged,
+        })
+    }
+}
+
+/// Settings for dropping random coefficients with negligible estimated spread.
+#[derive(Clone, Debug)]
+pub struct PruningOptions {
+    /// A random coefficient is dropped when `|sigma_kk|` is below this value.
+    pub threshold: f64,
+    /// Search settings for re-estimating the reduced model. Its `blocks` are replaced by
+    /// one block per remaining nonzero entry of `sigma` and `pi`, and one for `rho`.
+    pub search: BlockCoordinateOptions,
+}
+
+impl PruningOptions {
+    /// Prune below `threshold` and re-estimate with default search settings.
+    pub fn new(threshold: f64) -> Self {
+        Self {
+            threshold,
+            search: BlockCoordinateOptions::new(ParameterBlocks::new(Vec::new())),
+        }
+    }
+}
+
+/// Whether one nonlinear characteristic kept its random coefficient.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PruningDecision {
+    /// Position of the characteristic in the original `X2`.
+    pub column: usize,
+    /// Label of the characteristic.
+    pub label: String,
+    /// Estimated `sigma_kk` that the decision was based on.
+    pub sigma: f64,
+    /// Whether the column was removed from `X2` and the integration dimension.
+    pub dropped: bool,
+}
+
+/// Outcome of [`Problem::prune_heterogeneity`].
+#[derive(Clone, Debug)]
+pub struct PrunedEstimate {
+    /// One decision per original nonlinear characteristic.
+    pub decisions: Vec<PruningDecision>,
+    /// The reduced problem (a copy of the original when nothing was dropped).
+    pub problem: Problem,
+    /// Re-estimation of the reduced problem, or `None` when nothing was dropped.
+    pub estimate: Option<BlockCoordinateResults>,
+}
+
+impl PrunedEstimate {
+    /// Original `X2` positions of the characteristics that were kept.
+    pub fn kept_columns(&self) -> Vec<usize> {
+        self.decisions
+            .iter()
+            .filter(|decision| !decision.dropped)
+            .map(|decision| decision.column)
+            .collect()
+    }
+}
+
+impl Problem {
+    /// Drop random coefficients whose estimated standard deviation is below
+    /// `options.threshold`, shrink the integration dimension to match, and re-estimate.
+    ///
+    /// A dropped characteristic loses its whole row and column of `sigma`, including any
+    /// correlations with other random coefficients, and its row of `pi`; it stays in `X1`
+    /// if it was there. The reduced model starts from the remaining entries of
+    /// `results.sigma` and `results.pi` and from `results.rho`, under the problem's
+    /// [`OptimizationOptions`] cut down the same way (starting values, bounds, and mask).
+    /// Each prediction costs roughly `K2' / K2` of the original's.
+    pub fn prune_heterogeneity(
+        &self,
+        results: &ProblemResults,
+        options: &PruningOptions,
+    ) -> Result<PrunedEstimate> {
+        let data = self.data();
+        let k2 = data.nonlinear_dim();
+        if results.sigma.shape() != (k2, k2) {
+            return Err(BlpError::dimension_mismatch(
+                "sigma rows",
+                k2,
+                results.sigma.nrows(),
+            ));
+        }
+        let decisions: Vec<PruningDecision> = (0..k2)
+            .map(|column| {
+                let sigma = results.sigma[(column, column)];
+                PruningDecision {
+                    column,
+                    label: data.labels().x2[column].clone(),
+                    sigma,
+                    dropped: sigma.abs() < options.threshold,
+                }
+            })
+            .collect();
+        for decision in decisions.iter().filter(|decision| decision.dropped) {
+            log::info!(
+                "dropping random coefficient on {} (sigma = {:.3e})",
+                decision.label,
+                decision.sigma
+            );
+        }
+        let kept: Vec<usize> = decisions
+            .iter()
+            .filter(|decision| !decision.dropped)
+            .map(|decision| decision.column)
+            .collect();
+        if kept.len() == k2 {
+            return Ok(PrunedEstimate {
+                decisions,
+                problem: self.clone(),
+                estimate: None,
+            });
+        }
+
+        let optimization = &self.options().optimization;
+        let squares = optimization.initial_sigma.iter().chain(
+            optimization
+                .bounds
+                .iter()
+                .flat_map(|bounds| [&bounds.lower, &bounds.upper]),
+        );
+        if let Some(matrix) = squares
+            .into_iter()
+            .find(|matrix| matrix.shape() != (k2, k2))
+        {
+            return Err(BlpError::dimension_mismatch(
+                "starting sigma or bounds rows",
+                k2,
+                matrix.nrows(),
+            ));
+        }
+        if let Some(pi) = optimization
+            .initial_pi
+            .iter()
+            .chain(&results.pi)
+            .find(|pi| pi.nrows() != k2)
+        {
+            return Err(BlpError::dimension_mismatch("pi rows", k2, pi.nrows()));
+        }
+        if let Some(mask) = &optimization.mask {
+            let demographics = self.draws().demographics().map_or(0, |d| d.ncols());
+            mask.validate(k2, demographics, optimization.initial_pi.is_some())?;
+        }
+        let mut reduced = self.options().clone();
+        reduced.optimization = optimization.select_nonlinear(&kept);
+        let problem = self.with_options_override(reduced).with_inputs(
+            data.select_nonlinear(&kept)?,
+            self.draws().select_dimensions(&kept)?,
+        )?;
+        let mut start = NonlinearParameters::new(
+            results
+                .sigma
+                .select_rows(kept.iter())
+                .select_columns(kept.iter()),
+        );
+        if let Some(pi) = &results.pi {
+            start = start.with_pi(pi.select_rows(kept.iter()));
+        }
+        if let Some(rho) = results.rho {
+            start = start.with_rho(rho);
+        }
+        let search = BlockCoordinateOptions {
+            blocks: ParameterBlocks::per_parameter(&start),
+            ..options.search.clone()
+        };
+        let estimate = problem.optimize_blocks(&start, &search)?;
+        Ok(PrunedEstimate {
+            decisions,
+            problem,
+            estimate: Some(estimate),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::DVector;
+
+    use super::*;
+    use crate::data::ProductData;
+    use crate::diagnostics::ConditioningReport;
+    use crate::estimation::efficient_weighting;
+    use crate::integration::SimulationDraws;
+    use crate::options::{ParameterBounds, ProblemOptions};
+    use crate::parameters::{NonlinearParameters, ParameterMask};
+    use crate::progress::ProgressOptions;
+    use crate::testing::TestMarkets;
+
+    /// Fixed shares on one random characteristic `x`.
+    fn single_characteristic_markets() -> ProductData {
+        let markets = TestMarkets::new(24);
+        let x = markets.x.clone();
+        markets
+            .with_x1(DMatrix::from_fn(24, 2, |j, k| [1.0, x[j]][k]), vec![1])
+            .with_instruments(DMatrix::from_fn(24, 3, |j, k| [1.0, x[j], x[j] * x[j]][k]))
+            .data(DVector::from_fn(24, |j, _| {
+                0.05 + 0.04 * ((j * 3) % 4) as f64
+            }))
+    }
+
+    /// Shares simulated with an income interaction on `x`, and the draws carrying income.
+    fn demographic_markets() -> (ProductData, SimulationDraws) {
+        let markets = TestMarkets::new(36);
+        let (x, w) = (markets.x.clone(), markets.w.clone());
+        let markets = markets.with_instruments(DMatrix::from_fn(36, 7, |j, k| {
+            [
+                1.0,
+                x[j],
+                w[j],
+                x[j] * x[j],
+                w[j] * x[j],
+                w[j] * w[j],
+                x[j].powi(3),
+            ][k]
+        }));
+        let income = DMatrix::from_fn(30, 1, |r, _| (r % 6) as f64 / 2.5 - 1.0);
+        let draws = SimulationDraws::standard_normal(30, 2, 2)
+            .with_demographics(income)
+            .unwrap();
+        let truth = NonlinearParameters::diagonal(DVector::from_vec(vec![1.0, 0.5]))
+            .with_pi(DMatrix::from_column_slice(2, 1, &[0.8, 0.0]));
+        let delta = DVector::from_fn(36, |j, _| -1.0 + x[j] - w[j]);
+        (markets.simulate(&draws, &truth, &delta), draws)
+    }
+
+    #[test]
+    fn block_search_lowers_the_objective() {
+        let markets = TestMarkets::new(24);
+        let (x, w, xi) = (&markets.x, &markets.w, markets.xi(0.05));
+        let draws = SimulationDraws::standard_normal(30, 2, 2);
+        let truth = NonlinearParameters::diagonal(DVector::from_vec(vec![1.0, 0.5]));
+        let delta = DVector::from_fn(24, |j, _| -1.0 + x[j] - w[j] + xi[j]);
+        let problem = Problem::new(markets.simulate(&draws, &truth, &delta), draws).unwrap();
+
+        let start = NonlinearParameters::diagonal(DVector::from_vec(vec![2.0, 1.5]));
+        let blocks = ParameterBlocks::per_parameter(&start);
+        assert_eq!(blocks.pack(&start).unwrap(), vec![2.0, 1.5]);
+        let options = BlockCoordinateOptions {
+            objective_tolerance: 1e-4,
+            step_tolerance: 1e-2,
+            ..BlockCoordinateOptions::new(blocks)
+        };
+        let search = problem.optimize_blocks(&start, &options).unwrap();
+        let initial = problem.solve(&start).unwrap().gmm_value;
+        assert!(search.results.gmm_value < 0.1 * initial);
+        assert!(search.converged);
+        assert_eq!(search.results.sigma[(0, 1)], 0.0);
+        assert!(search.history.windows(2).all(|pair| pair[1] <= pair[0]));
+    }
+
+    #[test]
+    fn progress_records_count_across_one_optimization() {
+        let data = single_characteristic_markets();
+        let path = std::env::temp_dir().join(format!(
+            "blprs-optimize-progress-{}.jsonl",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let options = ProblemOptions::default()
+            .with_optimization(
+                OptimizationOptions::new(DMatrix::from_element(1, 1, 1.5))
+                    .with_tolerances(1e-8, 1e-3),
+            )
+            .with_progress(ProgressOptions::json_lines(&path));
+        let problem =
+            Problem::with_options(data, SimulationDraws::standard_normal(20, 1, 3), options)
+                .unwrap();
+        problem.optimize().unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let records: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert!(records.len() > 2);
+        let mut best = f64::INFINITY;
+        for (evaluation, pair) in records.windows(2).enumerate() {
+            assert_eq!(pair[0]["evaluation"], evaluation);
+            assert_eq!(pair[1]["evaluation"], evaluation + 1);
+            assert!(pair[1]["elapsed_seconds"].as_f64() >= pair[0]["elapsed_seconds"].as_f64());
+        }
+        for record in &records {
+            best = best.min(record["objective"].as_f64().unwrap());
+            assert_eq!(record["best_objective"].as_f64().unwrap(), best);
+        }
+    }
+
+    #[test]
+    fn searches_leave_conditioning_to_the_estimates() {
+        let data = single_characteristic_markets();
+        let options = ProblemOptions::default().with_optimization(
+            OptimizationOptions::new(DMatrix::from_element(1, 1, 1.5)).with_tolerances(1e-8, 1e-3),
+        );
+        let problem =
+            Problem::with_options(data, SimulationDraws::standard_normal(20, 1, 3), options)
+                .unwrap();
+
+        let parameters = NonlinearParameters::new(DMatrix::from_element(1, 1, 1.0));
+        let evaluation = problem
+            .deferring_conditioning()
+            .solve(&parameters)
+            .unwrap()
+            .conditioning;
+        let solved = problem.solve(&parameters).unwrap().conditioning;
+        assert_eq!(evaluation.ztz, solved.ztz);
+        assert!(evaluation.weighting.is_nan() && evaluation.xzwzx.is_nan());
+
+        let results = problem.optimize().unwrap().results;
+        let expected = ConditioningReport::compute(
+            problem.data().x1(),
+            problem.data().instruments(),
+            &results.weighting_matrix,
+            results.conditioning.threshold,
+        );
+        assert_eq!(results.conditioning.ztz, expected.ztz);
+        assert_eq!(results.conditioning.weighting, expected.weighting);
+        assert_eq!(results.conditioning.xzwzx, expected.xzwzx);
+    }
+
+    #[test]
+    fn searched_entries_skip_zero_and_fixed_entries() {
+        let sigma = DMatrix::from_row_slice(2, 2, &[1.0, 0.0, 0.3, 0.5]);
+        let options = OptimizationOptions::new(sigma.clone());
+        assert_eq!(
+            searched_entries(&sigma, &options),
+            vec![(0, 0), (1, 0), (1, 1)]
+        );
+        // Equal bounds fix an entry whatever its starting value, zero or not.
+        let options = options.with_bounds(
+            ParameterBounds::unbounded(2)
+                .with_fixed(1, 0, 0.3)
+                .with_fixed(0, 1, 0.2),
+        );
+        assert_eq!(searched_entries(&sigma, &options), vec![(0, 0), (1, 1)]);
+        let mut start = sigma.clone();
+        options.bounds.as_ref().unwrap().hold_fixed(&mut start);
+        assert_eq!(start[(0, 1)], 0.2);
+    }
+
+    #[test]
+    fn negligible_random_coefficients_are_pruned() {
+        let markets = TestMarkets::new(18);
+        let (x, w) = (markets.x.clone(), markets.w.clone());
+        let markets = markets
+            .with_instruments(DMatrix::from_fn(18, 4, |j, k| {
+                [1.0, x[j], w[j], x[j] * x[j]][k]
+            }))
+            .with_x1_labels(&["constant", "x", "w"]);
+        let draws = SimulationDraws::standard_normal(20, 2, 8);
+        let truth = NonlinearParameters::diagonal(DVector::from_vec(vec![1.0, 0.0]));
+        let delta = DVector::from_fn(18, |j, _| -1.0 + x[j] - w[j]);
+        let data = markets.simulate(&draws, &truth, &delta);
+        // The full model's starting values, bounds, and mask are cut down with it.
+        let optimization =
+            OptimizationOptions::new(DMatrix::from_diagonal(&DVector::from_vec(vec![1.5, 0.3])))
+                .with_bounds(ParameterBounds::nonnegative_diagonal(2).with_upper(0, 0, 3.0))
+                .with_mask(ParameterMask::new(2).fix_sigma(1, 1, 0.01).unwrap());
+        let options = ProblemOptions::default().with_optimization(optimization);
+        let problem = Problem::with_options(data, draws, options).unwrap();
+        let results = problem.optimize().unwrap().results;
+        let mut pruning = PruningOptions::new(0.05);
+        pruning.search.step_tolerance = 1e-2;
+        let pruned = problem.prune_heterogeneity(&results, &pruning).unwrap();
+
+        assert_eq!(pruned.kept_columns(), vec![0]);
+        assert!(pruned.decisions[1].dropped && pruned.decisions[1].label == "w");
+        assert_eq!(pruned.problem.draws().dimension(), 1);
+        let reduced = &pruned.problem.options().optimization;
+        assert_eq!(reduced.initial_sigma.as_ref().unwrap().shape(), (1, 1));
+        assert_eq!(reduced.bounds.as_ref().unwrap().upper[(0, 0)], 3.0);
+        assert_eq!(reduced.mask.as_ref().unwrap().fixed_count(), 0);
+        let estimate = pruned.estimate.unwrap();
+        assert_eq!(estimate.results.sigma.shape(), (1, 1));
+        assert!((estimate.results.sigma[(0, 0)] - 1.0).abs() < 0.1);
+        assert!(estimate.results.sigma[(0, 0)] <= 3.0);
+    }
+
+    #[test]
+    fn nelder_mead_recovers_sigma_within_bounds() {
+        let markets = TestMarkets::new(24);
+        let draws = SimulationDraws::standard_normal(30, 2, 2);
+        let truth = NonlinearParameters::diagonal(DVector::from_vec(vec![1.0, 0.5]));
+        let delta = DVector::from_fn(24, |j, _| -1.0 + markets.x[j] - markets.w[j]);
+        let data = markets.simulate(&draws, &truth, &delta);
+
+        let start = DMatrix::from_diagonal(&DVector::from_vec(vec![1.5, 1.0]));
+        let optimization = OptimizationOptions::new(start)
+            .with_tolerances(1e-8, 1e-3)
+            .with_history();
+        let options = crate::ProblemOptions::default().with_optimization(optimization.clone());
+        let problem = Problem::with_options(data, draws, options).unwrap();
+        let optimized = problem.optimize().unwrap();
+        let sigma = &optimized.results.sigma;
+        assert!(optimized.converged);
+        assert!((sigma[(0, 0)] - 1.0).abs() < 0.05, "sigma {sigma}");
+        assert!((sigma[(1, 1)] - 0.5).abs() < 0.05, "sigma {sigma}");
+        assert_eq!(sigma[(0, 1)], 0.0);
+        assert_eq!(optimized.history.len(), optimized.evaluations);
+        let last = optimized
+            .history
+            .iter()
+            .min_by(|a, b| a.objective.total_cmp(&b.objective))
+            .unwrap();
+        assert_eq!(last.objective, optimized.results.gmm_value);
+        assert_eq!(last.theta.len(), 3 + 4);
+        assert_eq!(last.xi.len(), 24);
+
+        let bounds = ParameterBounds::new(DMatrix::zeros(2, 2), DMatrix::from_element(2, 2, 0.8));
+        let bounded =
+            crate::ProblemOptions::default().with_optimization(optimization.with_bounds(bounds));
+        let bounded =
+            Problem::with_options(problem.data().clone(), problem.draws().clone(), bounded)
+                .unwrap()
+                .optimize()
+                .unwrap();
+        assert_eq!(bounded.results.sigma[(0, 0)], 0.8);
+        assert!(bounded.results.sigma[(1, 1)] <= 0.8);
+        assert!(bounded.results.gmm_value >= optimized.results.gmm_value);
+
+        let fixed = problem
+            .options()
+            .clone()
+            .with_bounds(ParameterBounds::nonnegative_diagonal(2).with_fixed(0, 0, 1.0));
+        let fixed = Problem::with_options(problem.data().clone(), problem.draws().clone(), fixed)
+            .unwrap()
+            .optimize()
+            .unwrap();
+        assert_eq!(fixed.results.sigma[(0, 0)], 1.0);
+        assert!((fixed.results.sigma[(1, 1)] - 0.5).abs() < 0.05);
+        assert!(fixed.evaluations < optimized.evaluations);
+
+        let cholesky = problem.options().clone().with_optimization(
+            OptimizationOptions::new(DMatrix::from_diagonal(&DVector::from_vec(vec![-1.5, 1.0])))
+                .with_tolerances(1e-8, 1e-3)
+                .with_cholesky(),
+        );
+        let cholesky =
+            Problem::with_options(problem.data().clone(), problem.draws().clone(), cholesky)
+                .unwrap()
+                .optimize()
+                .unwrap();
+        let covariance = cholesky.results.sigma_covariance();
+        assert!(
+            (covariance[(0, 0)] - 1.0).abs() < 0.1,
+            "covariance {covariance}"
+        );
+        assert!(
+            (covariance[(1, 1)] - 0.25).abs() < 0.05,
+            "covariance {covariance}"
+        );
+        assert!(
+            cholesky
+                .results
+                .sigma
+                .diagonal()
+                .iter()
+                .all(|&value| value > 0.0)
+        );
+
+        let unstarted = Problem::new(problem.data().clone(), problem.draws().clone()).unwrap();
+        assert!(unstarted.optimize().is_err());
+    }
+
+    #[test]
+    fn pi_is_searched_alongside_sigma() {
+        let (data, draws) = demographic_markets();
+
+        let optimization =
+            OptimizationOptions::new(DMatrix::from_diagonal(&DVector::from_vec(vec![1.5, 0.5])))
+                .with_pi(DMatrix::from_column_slice(2, 1, &[0.3, 0.0]))
+                .with_tolerances(1e-10, 1e-4);
+        let options = crate::ProblemOptions::default().with_optimization(optimization.clone());
+        let problem = Problem::with_options(data, draws, options).unwrap();
+        let optimized = problem.optimize().unwrap().results;
+        let pi = optimized.pi.as_ref().unwrap();
+        assert!((pi[(0, 0)] - 0.8).abs() < 0.05, "pi {pi}");
+        // Zero entries of the starting pi stay at zero, as for sigma.
+        assert_eq!(pi[(1, 0)], 0.0);
+        assert!((optimized.sigma[(0, 0)] - 1.0).abs() < 0.05);
+
+        // Blocks address pi as well, and hold the entries the bounds fix as `optimize` does.
+        let start = NonlinearParameters::diagonal(DVector::from_vec(vec![1.5, 0.7]))
+            .with_pi(DMatrix::from_column_slice(2, 1, &[0.3, 0.0]));
+        let blocks = ParameterBlocks::new(vec![
+            vec![
+                ParameterCoordinate::Sigma(0, 0),
+                ParameterCoordinate::Sigma(1, 1),
+            ],
+            vec![ParameterCoordinate::Pi(0, 0), ParameterCoordinate::Pi(1, 0)],
+        ]);
+        let mut unpacked = start.clone();
+        blocks.unpack(&[1.0, 0.5, 0.8, 0.0], &mut unpacked);
+        assert_eq!(blocks.pack(&unpacked).unwrap(), vec![1.0, 0.5, 0.8, 0.0]);
+        let bounded = problem.options().clone().with_optimization(
+            optimization
+                .clone()
+                .with_bounds(ParameterBounds::unbounded(2).with_fixed(1, 1, 0.5)),
+        );
+        let search = BlockCoordinateOptions {
+            step_tolerance: 1e-3,
+            ..BlockCoordinateOptions::new(blocks)
+        };
+        let blocked = problem
+            .with_options_override(bounded)
+            .optimize_blocks(&start, &search)
+            .unwrap()
+            .results;
+        let initial = problem.solve(&start).unwrap().gmm_value;
+        assert!(blocked.gmm_value < 1e-2 * initial);
+        let pi = blocked.pi.as_ref().unwrap();
+        assert!(pi[(0, 0)] > 0.3, "pi {pi}");
+        assert_eq!(pi[(1, 0)], 0.0);
+        assert_eq!(blocked.sigma[(1, 1)], 0.5);
+        let rho = ParameterBlocks::new(vec![vec![ParameterCoordinate::Rho]]);
+        assert!(
+            problem
+                .optimize_blocks(&start, &BlockCoordinateOptions::new(rho))
+                .is_err()
+        );
+
+        let wrong = problem
+            .options()
+            .clone()
+            .with_optimization(optimization.with_pi(DMatrix::zeros(2, 2)));
+        let wrong = Problem::with_options(problem.data().clone(), problem.draws().clone(), wrong);
+        assert!(matches!(
+            wrong.unwrap_err(),
+            BlpError::InconsistentSpecification { .. }
+        ));
+    }
+
+    #[test]
+    fn masked_entries_stay_fixed_while_pi_is_searched() {
+        let (data, draws) = demographic_markets();
+
+        let mask = ParameterMask::new(2)
+            .with_demographics(1)
+            .fix_sigma(1, 1, 0.5)
+            .and_then(|mask| mask.fix_pi(1, 0, 0.0))
+            .unwrap();
+        let optimization =
+            OptimizationOptions::new(DMatrix::from_diagonal(&DVector::from_vec(vec![1.5, 1.0])))
+                .with_pi(DMatrix::from_column_slice(2, 1, &[0.3, 0.4]))
+                .with_mask(mask.clone())
+                .with_tolerances(1e-10, 1e-4);
+        let options = crate::ProblemOptions::default().with_optimization(optimization);
+        let problem = Problem::with_options(data, draws, options).unwrap();
+        let optimized = problem.optimize().unwrap().results;
+        let pi = optimized.pi.as_ref().unwrap();
+        assert_eq!(optimized.sigma[(1, 1)], 0.5);
+        assert_eq!(pi[(1, 0)], 0.0);
+        assert!((optimized.sigma[(0, 0)] - 1.0).abs() < 0.05);
+        assert!((pi[(0, 0)] - 0.8).abs() < 0.05, "pi {pi}");
+
+        let fixed = optimized.held_fixed();
+        assert_eq!(fixed.sigma_value((1, 1)), Some(0.5));
+        assert_eq!(fixed.sigma_value((0, 1)), Some(0.0));
+        assert_eq!(fixed.sigma_value((0, 0)), None);
+        assert_eq!(
+            (fixed.pi_value((0, 0)), fixed.pi_value((1, 0))),
+            (None, Some(0.0))
+        );
+        // Estimated pi enters the covariance after sigma; held entries get no error.
+        assert_eq!(
+            optimized.covariance.as_ref().unwrap().shape(),
+            (3 + 4 + 2, 3 + 4 + 2)
+        );
+        let (sigma_se, pi_se) = (optimized.sigma_se().unwrap(), optimized.pi_se().unwrap());
+        assert_eq!((sigma_se[(1, 1)], pi_se[(1, 0)]), (0.0, 0.0));
+        assert!(sigma_se[(0, 0)] > 0.0 && pi_se[(0, 0)] > 0.0);
+        assert!(
+            (pi[(0, 0)] - 0.8).abs() < 3.0 * pi_se[(0, 0)],
+            "pi se {pi_se}"
+        );
+        // Searched entries stay free even when their estimate is zero.
+        let mut at_zero = optimized.clone();
+        at_zero.sigma[(0, 0)] = 0.0;
+        at_zero.pi.as_mut().unwrap()[(0, 0)] = 0.0;
+        let fixed = at_zero.held_fixed();
+        assert_eq!(
+            (fixed.sigma_value((0, 0)), fixed.pi_value((0, 0))),
+            (None, None)
+        );
+
+        let without_pi = problem
+            .options()
+            .clone()
+            .with_optimization(OptimizationOptions::new(DMatrix::identity(2, 2)).with_mask(mask));
+        let without_pi =
+            Problem::with_options(problem.data().clone(), problem.draws().clone(), without_pi);
+        assert!(without_pi.is_err());
+
+        let wrong = problem
+            .options()
+            .clone()
+            .with_mask(ParameterMask::new(3).fix_sigma(0, 0, 1.0).unwrap());
+        let wrong = Problem::with_options(problem.data().clone(), problem.draws().clone(), wrong);
+        assert!(wrong.is_err());
+    }
+
+    #[test]
+    fn diagonal_sigma_searches_only_standard_deviations() {
+        let full = DMatrix::from_row_slice(3, 3, &[1.0, 0.2, 0.0, 0.3, 0.5, 0.1, 0.0, 0.0, 0.0]);
+        let options = OptimizationOptions::new(full.clone()).with_diagonal_sigma();
+        let start = starting_sigma(full.clone(), &options);
+        assert!(NonlinearParameters::new(start.clone()).is_diagonal());
+        assert_eq!(start.diagonal(), full.diagonal());
+        assert_eq!(searched_entries(&start, &options), vec![(0, 0), (1, 1)]);
+        assert_eq!(searched_entries(&full, &options), vec![(0, 0), (1, 1)]);
+        assert_eq!(
+            searched_entries(&full, &OptimizationOptions::new(full.clone())).len(),
+            5
+        );
+
+        let diagonal = OptimizationOptions::diagonal(DVector::from_vec(vec![0.5, 1.0]));
+        assert!(diagonal.diagonal_sigma);
+        assert_eq!(
+            diagonal.initial_sigma.as_ref(),
+            Some(NonlinearParameters::diagonal(DVector::from_vec(vec![0.5, 1.0])).sigma())
+        );
+    }
+
+    #[test]
+    fn cholesky_start_keeps_the_covariance_of_sigma() {
+        let options = OptimizationOptions::default().with_cholesky();
+        let full = DMatrix::from_row_slice(2, 2, &[1.0, 0.5, -0.3, 0.8]);
+        let factor = starting_sigma(full.clone(), &options);
+        assert_eq!(factor[(0, 1)], 0.0);
+        assert!(factor.diagonal().iter().all(|&value| value > 0.0));
+        assert!((&factor * factor.transpose() - &full * full.transpose()).amax() < 1e-12);
+        assert_eq!(
+            searched_entries(&full, &options),
+            vec![(0, 0), (1, 0), (1, 1)]
+        );
+
+        let triangular = DMatrix::from_row_slice(2, 2, &[-1.0, 0.0, 0.4, 0.0]);
+        let factor = starting_sigma(triangular, &options);
+        assert_eq!(
+            factor,
+            DMatrix::from_row_slice(2, 2, &[1.0, 0.0, -0.4, 0.0])
+        );
+        for (entry, value) in [((0, 0), 0.7), ((1, 0), -0.4)] {
+            let coordinate = search_coordinate(entry, value, &options);
+            assert!((sigma_entry(entry, coordinate, &options) - value).abs() < 1e-15);
+        }
+        assert_eq!(search_coordinate((0, 0), 1.0, &options), 0.0);
+    }
+
+    #[test]
+    fn two_step_gmm_reweights_with_first_step_residuals() {
+        let markets = TestMarkets::new(18);
+        let (x, xi) = (markets.x.clone(), markets.xi(0.1));
+        let markets = markets
+            .with_x1(DMatrix::from_fn(18, 2, |j, k| [1.0, x[j]][k]), vec![1])
+            .with_instruments(DMatrix::from_fn(18, 4, |j, k| {
+                [1.0, x[j], x[j] * x[j], x[j].powi(3)][k]
+            }));
+        let draws = SimulationDraws::standard_normal(20, 1, 4);
+        let truth = NonlinearParameters::new(DMatrix::from_element(1, 1, 0.8));
+        let delta = DVector::from_fn(18, |j, _| -1.0 + x[j] + xi[j]);
+        let data = markets.simulate(&draws, &truth, &delta);
+        let options = crate::ProblemOptions::default()
+            .with_weighting_updates(true)
+            .with_optimization(
+                OptimizationOptions::new(DMatrix::from_element(1, 1, 0.5))
+                    .with_tolerances(1e-10, 1e-4),
+            );
+        let problem = Problem::with_options(data, draws, options).unwrap();
+
+        let optimized = problem.optimize().unwrap();
+        let steps = &optimized.results.gmm_steps;
+        assert_eq!(steps.len(), 2);
+        let first = problem
+            .with_options_override(crate::ProblemOptions::default())
+            .solve(&NonlinearParameters::new(steps[0].sigma.clone()))
+            .unwrap();
+        let efficient = efficient_weighting(
+            problem.data(),
+            &first.xi,
+            Default::default(),
+            &mut Factorizations::strict(),
+        )
+        .unwrap();
+        assert!((&optimized.results.weighting_matrix - &efficient).amax() < 1e-8);
+        assert_eq!(steps[1].sigma, optimized.results.sigma);
+        assert_eq!(steps[1].gmm_value, optimized.results.gmm_value);
+    }
+
+    #[test]
+    fn iterated_gmm_keeps_going_while_pi_moves() {
+        let (data, draws) = demographic_markets();
+        let bounds = ParameterBounds::unbounded(2)
+            .with_fixed(0, 0, 1.0)
+            .with_fixed(1, 1, 0.5);
+        let options = crate::ProblemOptions::default()
+            .with_weighting_updates(true)
+            .with_max_gmm_iterations(4)
+            .with_optimization(
+                OptimizationOptions::new(DMatrix::from_diagonal(&DVector::from_vec(vec![
+                    1.0, 0.5,
+                ])))
+                .with_pi(DMatrix::from_column_slice(2, 1, &[0.3, 0.0]))
+                .with_bounds(bounds)
+                .with_tolerances(1e-10, 1e-4),
+            );
+        let problem = Problem::with_options(data, draws, options).unwrap();
+
+        let optimized = problem.optimize().unwrap().results;
+        let steps = &optimized.gmm_steps;
+        // Sigma is held, so only pi moving keeps the iterations going past two steps.
+        assert!(steps.len() > 2, "{} steps", steps.len());
+        assert!(steps.iter().all(|step| step.sigma == optimized.sigma));
+        assert_ne!(steps[0].pi, steps[1].pi);
+        assert_eq!(steps.last().unwrap().pi, optimized.pi);
+    }
+
+    #[test]
+    fn nesting_parameter_is_estimated_with_sigma() {
+        let markets = TestMarkets::new(24).with_market_size(4);
+        let (x, w, x1) = (markets.x.clone(), markets.w.clone(), markets.x1().clone());
+        let unnested = markets
+            .with_x1(x1, vec![1])
+            .with_instruments(DMatrix::from_fn(24, 6, |j, k| {
+                let rivals = (0..24)
+                    .filter(|&i| i / 4 == j / 4 && i % 2 == j % 2 && i != j)
+                    .map(|i| w[i])
+                    .sum::<f64>();
+                [1.0, x[j], w[j], x[j] * x[j], w[j] * x[j], rivals][k]
+            }));
+        let markets = unnested
+            .clone()
+            .with_nesting_ids((0..24).map(|j| format!("n{}", j % 2)).collect());
+        let draws = SimulationDraws::standard_normal(10, 1, 6);
+        let truth = NonlinearParameters::new(DMatrix::from_element(1, 1, 0.8)).with_rho(0.5);
+        let delta = DVector::from_fn(24, |j, _| -1.0 + x[j] - w[j]);
+        let data = markets.simulate(&draws, &truth, &delta);
+
+        let optimization = OptimizationOptions::new(DMatrix::from_element(1, 1, 0.5))
+            .with_rho(0.2)
+            .with_tolerances(1e-12, 1e-4)
+            .with_history();
+        let options = crate::ProblemOptions::default().with_optimization(optimization);
+        let problem = Problem::with_options(data, draws, options).unwrap();
+        let optimized = problem.optimize().unwrap();
+        let rho = optimized.results.rho.unwrap();
+        assert!((rho - 0.5).abs() < 0.05, "rho {rho}");
+        assert!((optimized.results.sigma[(0, 0)] - 0.8).abs() < 0.1);
+        assert_eq!(optimized.history[0].theta.len(), 3 + 1 + 1);
+
+        let unnested = unnested.data(problem.data().shares().clone());
+        let options = problem.options().clone();
+        assert!(Problem::with_options(unnested, problem.draws().clone(), options).is_err());
+    }
+}
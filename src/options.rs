@@ -1,29 +1,128 @@
 //! Configuration structures that mirror pyBLP's solver and GMM options while remaining idiomatic Rust.
 
-use nalgebra::DMatrix;
+use nalgebra::{DMatrix, DVector};
+use serde::{Deserialize, Serialize};
 
+use crate::error::{BlpError, Result};
+use crate::parameters::ParameterMask;
+use crate::progress::ProgressOptions;
 use crate::solving::ContractionOptions;
 
 /// Choice of weighting matrix used in the GMM objective.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum WeightingMatrix {
     /// Use the inverse of `Z'Z`, matching the canonical two-step BLP estimator.
     InverseZTZ,
     /// Provide a custom positive-definite weighting matrix.
     Provided(DMatrix<f64>),
+    /// Continuously-updated GMM (CUE): every evaluation rebuilds the efficient weighting
+    /// matrix from its own `xi`, so the objective is `g(xi)' S(xi)^{-1} g(xi)` with `S` the
+    /// [`MomentCovariance`] of the moments. At each evaluation the reported `beta`
+    /// minimizes this objective, with the weighting moving along with `beta`, and the
+    /// reported weighting matrix is built from the reported `xi` (unless `beta` is held at
+    /// [`GmmOptions::fixed_beta`]). `beta` is found by Gauss–Newton steps on the exact
+    /// gradient, which includes the derivative of the weighting; a search that stops
+    /// short returns
+    /// [`ContinuouslyUpdatedDidNotConverge`](crate::error::BlpError::ContinuouslyUpdatedDidNotConverge).
+    /// This differs in finite samples from the iterated-GMM fixed point, where `beta`
+    /// minimizes the objective under its own weighting. The weighting is already
+    /// efficient, so [`GmmOptions::update_weighting`] has no effect.
+    ContinuouslyUpdated,
+}
+
+/// Randomized low-rank approximation of the weighting matrix for very many instruments.
+///
+/// Instead of inverting the `L x L` matrix `Z'Z` or moment covariance `S`, the weighting
+/// matrix is the pseudo-inverse of its best rank-`rank` approximation from a randomized
+/// sketch (see [`low_rank_inverse`](crate::linalg::low_rank_inverse)), which is never
+/// formed. This is GMM on the `rank` leading principal components of the moments, and
+/// the sketch's relative error is reported in
+/// [`ConditioningReport::weighting_approximation`](crate::diagnostics::ConditioningReport::weighting_approximation).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LowRankWeighting {
+    /// Number of principal directions kept.
+    pub rank: usize,
+    /// Extra sketch columns beyond `rank`, which sharpen the leading directions.
+    pub oversampling: usize,
+    /// Further products with the matrix, for slowly decaying spectra.
+    pub power_iterations: usize,
+    /// Seed of the Gaussian sketch.
+    pub seed: u64,
+}
+
+impl LowRankWeighting {
+    /// Keep `rank` directions, with ten oversampling columns and two power iterations.
+    pub fn new(rank: usize) -> Self {
+        Self {
+            rank,
+            oversampling: 10,
+            power_iterations: 2,
+            seed: 0,
+        }
+    }
+}
+
+/// Strategy for solving the linear IV step that concentrates out `beta`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LinearSolver {
+    /// Cholesky factorization of the normal equations `X'ZWZ'X beta = X'ZWZ'delta`.
+    #[default]
+    Cholesky,
+    /// Householder QR of `L'Z'X`, where `W = LL'`, which avoids squaring the condition number.
+    Qr,
+    /// Singular value decomposition of `L'Z'X`; the most robust and the slowest option.
+    Svd,
+}
+
+/// How the covariance of the sample moments is estimated, for the efficient weighting
+/// matrix and standard errors (pyBLP's `W_type` and `se_type`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MomentCovariance {
+    /// Heteroskedasticity-robust `Z' diag(xi^2) Z`.
+    #[default]
+    Robust,
+    /// Cluster-robust `sum_c (Z_c' xi_c)(Z_c' xi_c)'` over the clusters in
+    /// [`ProductData::clustering_ids`](crate::data::ProductData::clustering_ids).
+    Clustered,
 }
 
 /// Controls the outer GMM loop and weighting updates.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GmmOptions {
-    /// Maximum number of outer iterations (weighting updates).
+    /// Maximum number of GMM steps run by [`Problem::optimize`](crate::Problem::optimize)
+    /// when `update_weighting` is set (at least two).
     pub max_iterations: usize,
-    /// Convergence tolerance for the GMM objective (not yet enforced by the minimal implementation).
+    /// Iterated GMM stops early once no entry of `sigma`, `pi`, or `rho` moves by more
+    /// than this between steps.
     pub tolerance: f64,
-    /// Whether to update the weighting matrix between iterations.
+    /// Whether [`Problem::optimize`](crate::Problem::optimize) rebuilds the weighting
+    /// matrix from the previous step's `xi` (heteroskedasticity-robust
+    /// `(Z' diag(xi^2) Z)^{-1}`) and re-minimizes.
     pub update_weighting: bool,
     /// Strategy for constructing the weighting matrix.
     pub weighting: WeightingMatrix,
+    /// Factorization used to solve for the linear parameters.
+    pub linear_solver: LinearSolver,
+    /// Condition number above which `Z'Z`, `W`, or `X'ZWZ'X` trigger a logged warning.
+    pub condition_warning_threshold: f64,
+    /// Linear parameters held at externally estimated values (e.g. from micro data)
+    /// instead of being concentrated out, so only `sigma` is estimated.
+    #[serde(default)]
+    pub fixed_beta: Option<DVector<f64>>,
+    /// Moment covariance behind the efficient weighting matrix and standard errors.
+    #[serde(default)]
+    pub moment_covariance: MomentCovariance,
+    /// Return [`SingularMatrix`](crate::error::BlpError::SingularMatrix) when a Cholesky
+    /// factorization of `Z'Z`, `W`, `X'ZWZ'X`, the moment covariance, or `G'WG` fails,
+    /// instead of continuing with its SVD pseudo-inverse. Fallbacks are logged, and those
+    /// behind an objective evaluation are listed in
+    /// [`ConditioningReport::pseudo_inverses`](crate::diagnostics::ConditioningReport::pseudo_inverses).
+    #[serde(default)]
+    pub strict_factorizations: bool,
+    /// Approximate the `Z'Z` and efficient weighting matrices at low rank instead of
+    /// inverting them; `None` inverts them exactly.
+    #[serde(default)]
+    pub low_rank_weighting: Option<LowRankWeighting>,
 }
 
 impl Default for GmmOptions {
@@ -33,28 +132,351 @@ impl Default for GmmOptions {
             tolerance: 1e-10,
             update_weighting: false,
             weighting: WeightingMatrix::InverseZTZ,
+            linear_solver: LinearSolver::Cholesky,
+            condition_warning_threshold: 1e12,
+            fixed_beta: None,
+            moment_covariance: MomentCovariance::default(),
+            strict_factorizations: false,
+            low_rank_weighting: None,
         }
     }
 }
 
-/// Aggregated solver configuration used when estimating a [`Problem`](crate::Problem).
-#[derive(Clone, Debug)]
-pub struct ProblemOptions {
-    /// Configuration for the contraction mapping that recovers mean utilities.
-    pub contraction: ContractionOptions,
-    /// Configuration for the outer GMM iterations.
-    pub gmm: GmmOptions,
+/// Elementwise bounds on `sigma` respected by [`Problem::optimize`](crate::Problem::optimize).
+///
+/// An entry whose lower and upper bounds are equal is fixed at that value and left out of
+/// the search, whatever its starting value.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ParameterBounds {
+    /// Lower bound of each entry (`-inf` for none).
+    pub lower: DMatrix<f64>,
+    /// Upper bound of each entry (`+inf` for none).
+    pub upper: DMatrix<f64>,
+}
+
+impl ParameterBounds {
+    /// Bounds from two matrices shaped like `sigma`.
+    pub fn new(lower: DMatrix<f64>, upper: DMatrix<f64>) -> Self {
+        Self { lower, upper }
+    }
+
+    /// No bounds on a `k2 x k2` `sigma`, to be tightened entry by entry.
+    pub fn unbounded(k2: usize) -> Self {
+        Self::new(
+            DMatrix::from_element(k2, k2, f64::NEG_INFINITY),
+            DMatrix::from_element(k2, k2, f64::INFINITY),
+        )
+    }
+
+    /// A nonnegative diagonal, the usual normalization of the standard deviations.
+    pub fn nonnegative_diagonal(k2: usize) -> Self {
+        let mut bounds = Self::unbounded(k2);
+        bounds.lower.fill_diagonal(0.0);
+        bounds
+    }
+
+    /// Set the lower bound of `sigma[(row, column)]`.
+    pub fn with_lower(mut self, row: usize, column: usize, value: f64) -> Self {
+        self.lower[(row, column)] = value;
+        self
+    }
+
+    /// Set the upper bound of `sigma[(row, column)]`.
+    pub fn with_upper(mut self, row: usize, column: usize, value: f64) -> Self {
+        self.upper[(row, column)] = value;
+        self
+    }
+
+    /// Hold `sigma[(row, column)]` fixed at `value`.
+    pub fn with_fixed(self, row: usize, column: usize, value: f64) -> Self {
+        self.with_lower(row, column, value)
+            .with_upper(row, column, value)
+    }
+
+    /// Whether `sigma[entry]` is fixed by equal bounds.
+    pub fn is_fixed(&self, entry: (usize, usize)) -> bool {
+        self.lower[entry] == self.upper[entry]
+    }
+
+    /// Set the fixed entries of `sigma` to their values, leaving the others alone.
+    pub fn hold_fixed(&self, sigma: &mut DMatrix<f64>) {
+        for ((value, lower), upper) in sigma.iter_mut().zip(&self.lower).zip(&self.upper) {
+            if lower == upper {
+                *value = *lower;
+            }
+        }
+    }
+
+    /// Check that the bounds are shaped like a `k2 x k2` `sigma` and that no entry has a
+    /// NaN bound or a lower bound above its upper bound.
+    pub fn validate(&self, k2: usize) -> Result<()> {
+        if self.lower.shape() != (k2, k2) || self.upper.shape() != (k2, k2) {
+            return Err(BlpError::dimension_mismatch(
+                "bounds rows",
+                k2,
+                self.lower.nrows().min(self.upper.nrows()),
+            ));
+        }
+        for column in 0..k2 {
+            for row in 0..k2 {
+                let (lower, upper) = (self.lower[(row, column)], self.upper[(row, column)]);
+                if lower > upper || lower.is_nan() || upper.is_nan() {
+                    return Err(BlpError::InvalidBounds {
+                        row,
+                        column,
+                        lower,
+                        upper,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Move `sigma` to the closest point inside the bounds, which must be
+    /// [valid](Self::validate).
+    pub fn clamp(&self, sigma: &mut DMatrix<f64>) {
+        for ((value, lower), upper) in sigma.iter_mut().zip(&self.lower).zip(&self.upper) {
+            *value = value.clamp(*lower, *upper);
+        }
+    }
 }
 
-impl Default for ProblemOptions {
+/// Starting values, bounds, and termination criteria for the outer search over `sigma`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OptimizationOptions {
+    /// Starting `sigma`; its zero entries are held fixed at zero, as in pyBLP. Required
+    /// unless the model has no random coefficients.
+    pub initial_sigma: Option<DMatrix<f64>>,
+    /// Starting demographic interactions `pi`; its nonzero entries are searched along
+    /// with `sigma`, its zero entries held fixed at zero.
+    #[serde(default)]
+    pub initial_pi: Option<DMatrix<f64>>,
+    /// Bounds on the entries of `sigma`; `None` leaves them unbounded.
+    pub bounds: Option<ParameterBounds>,
+    /// Entries of `sigma` and `pi` held fixed at given values.
+    #[serde(default)]
+    pub mask: Option<ParameterMask>,
+    /// Starting nesting parameter; when set, `rho` is estimated along with `sigma` (the
+    /// random coefficients nested logit) and kept within `[0, 0.99]`.
+    #[serde(default)]
+    pub initial_rho: Option<f64>,
+    /// Maximum number of optimizer iterations.
+    pub max_iterations: usize,
+    /// Maximum number of objective evaluations (inner-loop solves).
+    pub max_evaluations: usize,
+    /// Stop when the objective differs by at most this much across the search simplex.
+    pub objective_tolerance: f64,
+    /// ... and the parameters differ by at most this much.
+    pub parameter_tolerance: f64,
+    /// Initial step taken away from each starting value.
+    pub initial_step: f64,
+    /// Keep every evaluation's parameters, objective, `delta`, and `xi` in
+    /// [`OptimizationResults::history`](crate::optimization::OptimizationResults::history).
+    /// To stream them to disk instead, use
+    /// [`ProgressOptions::with_residuals`](crate::ProgressOptions::with_residuals).
+    #[serde(default)]
+    pub record_history: bool,
+    /// Search over the diagonal of `sigma` only, holding the off-diagonal entries at zero
+    /// whatever their starting values.
+    #[serde(default)]
+    pub diagonal_sigma: bool,
+    /// Search over the lower-triangular Cholesky factor of the taste covariance
+    /// `sigma sigma'`, with its diagonal on the log scale, so every candidate is valid
+    /// without bounds and the covariance stays positive semi-definite.
+    #[serde(default)]
+    pub cholesky: bool,
+}
+
+impl Default for OptimizationOptions {
     fn default() -> Self {
         Self {
-            contraction: ContractionOptions::default(),
-            gmm: GmmOptions::default(),
+            initial_sigma: None,
+            initial_pi: None,
+            bounds: None,
+            mask: None,
+            initial_rho: None,
+            diagonal_sigma: false,
+            cholesky: false,
+            max_iterations: 500,
+            max_evaluations: 2_000,
+            objective_tolerance: 1e-8,
+            parameter_tolerance: 1e-6,
+            initial_step: 0.5,
+            record_history: false,
+        }
+    }
+}
+
+impl OptimizationOptions {
+    /// Default termination settings starting from `initial_sigma`.
+    pub fn new(initial_sigma: DMatrix<f64>) -> Self {
+        Self {
+            initial_sigma: Some(initial_sigma),
+            ..Self::default()
+        }
+    }
+
+    /// Independent random coefficients starting from the standard deviations
+    /// `initial_diagonal`; see [`with_diagonal_sigma`](Self::with_diagonal_sigma).
+    pub fn diagonal(initial_diagonal: DVector<f64>) -> Self {
+        Self::new(DMatrix::from_diagonal(&initial_diagonal)).with_diagonal_sigma()
+    }
+
+    /// Restrict the search to the diagonal of `sigma`, so there are at most `K2`
+    /// parameters and shares take the diagonal fast path.
+    pub fn with_diagonal_sigma(mut self) -> Self {
+        self.diagonal_sigma = true;
+        self
+    }
+
+    /// Parametrize `sigma` by its Cholesky factor for correlated random coefficients.
+    ///
+    /// The starting `sigma` is replaced by the lower-triangular factor with a positive
+    /// diagonal that gives the same covariance `sigma sigma'`; its zero entries are held
+    /// at zero as usual. The optimizer searches the logs of the diagonal entries and the
+    /// off-diagonal entries unconstrained, and [`ProblemResults::sigma`] reports the
+    /// factor itself, whose covariance is
+    /// [`ProblemResults::sigma_covariance`].
+    ///
+    /// [`ProblemResults::sigma`]: crate::ProblemResults::sigma
+    /// [`ProblemResults::sigma_covariance`]: crate::ProblemResults::sigma_covariance
+    pub fn with_cholesky(mut self) -> Self {
+        self.cholesky = true;
+        self
+    }
+
+    /// Keep `sigma` within `bounds`.
+    pub fn with_bounds(mut self, bounds: ParameterBounds) -> Self {
+        self.bounds = Some(bounds);
+        self
+    }
+
+    /// Estimate the demographic interactions as well, starting from `pi`.
+    pub fn with_pi(mut self, pi: DMatrix<f64>) -> Self {
+        self.initial_pi = Some(pi);
+        self
+    }
+
+    /// Hold the entries of `sigma` and `pi` that `mask` fixes at their values and search
+    /// over the others only.
+    pub fn with_mask(mut self, mask: ParameterMask) -> Self {
+        self.mask = Some(mask);
+        self
+    }
+
+    /// Estimate the nesting parameter as well, starting from `rho`.
+    pub fn with_rho(mut self, rho: f64) -> Self {
+        self.initial_rho = Some(rho);
+        self
+    }
+
+    /// Keep the optimizer's path in memory.
+    pub fn with_history(mut self) -> Self {
+        self.record_history = true;
+        self
+    }
+
+    /// Stop once the objective and parameters have settled to these tolerances.
+    pub fn with_tolerances(mut self, objective: f64, parameter: f64) -> Self {
+        self.objective_tolerance = objective;
+        self.parameter_tolerance = parameter;
+        self
+    }
+
+    /// Keep only the random coefficients at positions `columns`: those rows and columns of
+    /// the starting `sigma`, bounds, and mask, and those rows of the starting `pi`. The
+    /// shapes must already have been checked against the full model.
+    pub(crate) fn select_nonlinear(&self, columns: &[usize]) -> Self {
+        let square = |matrix: &DMatrix<f64>| {
+            matrix
+                .select_rows(columns.iter())
+                .select_columns(columns.iter())
+        };
+        Self {
+            initial_sigma: self.initial_sigma.as_ref().map(square),
+            initial_pi: self
+                .initial_pi
+                .as_ref()
+                .map(|pi| pi.select_rows(columns.iter())),
+            bounds: self
+                .bounds
+                .as_ref()
+                .map(|bounds| ParameterBounds::new(square(&bounds.lower), square(&bounds.upper))),
+            mask: self
+                .mask
+                .as_ref()
+                .map(|mask| mask.select_nonlinear(columns)),
+            ..self.clone()
         }
     }
 }
 
+/// Thread budget shared by outer tasks (starts, folds) and per-market share computation.
+///
+/// Outer tasks run on `outer_tasks` threads and each gets an inner pool of
+/// `threads / outer_tasks` threads for its per-market work, so nesting never uses more
+/// than `threads` threads in total.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ParallelismOptions {
+    /// Total number of threads; `None` uses rayon's global pool (one per core).
+    pub threads: Option<usize>,
+    /// Maximum number of outer tasks run concurrently; `None` gives every thread to
+    /// outer tasks when there are enough of them.
+    pub outer_tasks: Option<usize>,
+}
+
+impl ParallelismOptions {
+    /// Limit the total number of threads.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = Some(threads.max(1));
+        self
+    }
+
+    /// Limit how many outer tasks run at once.
+    pub fn with_outer_tasks(mut self, outer_tasks: usize) -> Self {
+        self.outer_tasks = Some(outer_tasks.max(1));
+        self
+    }
+
+    /// Split the thread budget for `tasks` outer tasks into `(outer, inner)` pool sizes.
+    pub fn split(&self, tasks: usize) -> (usize, usize) {
+        let threads = self
+            .threads
+            .unwrap_or_else(rayon::current_num_threads)
+            .max(1);
+        let outer = self
+            .outer_tasks
+            .unwrap_or(threads)
+            .min(tasks)
+            .min(threads)
+            .max(1);
+        (outer, (threads / outer).max(1))
+    }
+}
+
+/// Aggregated solver configuration used when estimating a [`Problem`](crate::Problem).
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ProblemOptions {
+    /// Configuration for the contraction mapping that recovers mean utilities.
+    pub contraction: ContractionOptions,
+    /// Configuration for the outer GMM iterations.
+    pub gmm: GmmOptions,
+    /// Optional sink that streams intermediate results to disk while estimating.
+    pub progress: Option<ProgressOptions>,
+    /// Thread budget for per-market work and outer tasks.
+    #[serde(default)]
+    pub parallelism: ParallelismOptions,
+    /// Number of inner-loop solutions kept in the least-recently-used cache; `0` disables
+    /// caching. Contractions driven by an external
+    /// [`solver`](crate::solving::ContractionOptions::solver) are never cached.
+    #[serde(default)]
+    pub cache_capacity: usize,
+    /// Settings for [`Problem::optimize`](crate::Problem::optimize).
+    #[serde(default)]
+    pub optimization: OptimizationOptions,
+}
+
 impl ProblemOptions {
     /// Override the contraction settings while preserving other defaults.
     pub fn with_contraction(mut self, contraction: ContractionOptions) -> Self {
@@ -62,30 +484,149 @@ impl ProblemOptions {
         self
     }
 
+    /// Keep `sigma` within `bounds` while optimizing; see
+    /// [`OptimizationOptions::with_bounds`].
+    pub fn with_bounds(mut self, bounds: ParameterBounds) -> Self {
+        self.optimization.bounds = Some(bounds);
+        self
+    }
+
+    /// Hold the entries `mask` fixes while optimizing; see
+    /// [`OptimizationOptions::with_mask`].
+    pub fn with_mask(mut self, mask: ParameterMask) -> Self {
+        self.optimization.mask = Some(mask);
+        self
+    }
+
     /// Override the weighting configuration while preserving other defaults.
     pub fn with_weighting(mut self, weighting: WeightingMatrix) -> Self {
         self.gmm.weighting = weighting;
         self
     }
 
-    /// Set the maximum number of outer GMM iterations that should be attempted.
+    /// Set the maximum number of GMM steps that should be attempted.
     pub fn with_max_gmm_iterations(mut self, max_iterations: usize) -> Self {
         self.gmm.max_iterations = max_iterations.max(1);
         self
     }
 
-    /// Set the convergence tolerance for the GMM objective (stored for diagnostics).
+    /// Set how far `sigma`, `pi`, and `rho` may move between GMM steps before iterated GMM
+    /// stops.
     pub fn with_gmm_tolerance(mut self, tolerance: f64) -> Self {
         self.gmm.tolerance = tolerance;
         self
     }
 
-    /// Enable or disable weighting matrix updates between GMM iterations.
+    /// Enable or disable efficient weighting matrix updates between GMM steps.
     pub fn with_weighting_updates(mut self, update: bool) -> Self {
         self.gmm.update_weighting = update;
         self
     }
+
+    /// Choose the factorization used to concentrate out the linear parameters.
+    pub fn with_linear_solver(mut self, solver: LinearSolver) -> Self {
+        self.gmm.linear_solver = solver;
+        self
+    }
+
+    /// Hold `beta` fixed at `beta` and evaluate the objective at `xi = delta - X1 beta`.
+    pub fn with_fixed_beta(mut self, beta: DVector<f64>) -> Self {
+        self.gmm.fixed_beta = Some(beta);
+        self
+    }
+
+    /// Estimate the moment covariance as `covariance` when updating the weighting matrix
+    /// and computing standard errors.
+    pub fn with_moment_covariance(mut self, covariance: MomentCovariance) -> Self {
+        self.gmm.moment_covariance = covariance;
+        self
+    }
+
+    /// Fail on singular factorizations instead of falling back to pseudo-inverses.
+    pub fn with_strict_factorizations(mut self, strict: bool) -> Self {
+        self.gmm.strict_factorizations = strict;
+        self
+    }
+
+    /// Approximate the weighting matrices at low rank, for very many instruments.
+    pub fn with_low_rank_weighting(mut self, settings: LowRankWeighting) -> Self {
+        self.gmm.low_rank_weighting = Some(settings);
+        self
+    }
+
+    /// Bound the threads used by estimation.
+    pub fn with_parallelism(mut self, parallelism: ParallelismOptions) -> Self {
+        self.parallelism = parallelism;
+        self
+    }
+
+    /// Keep up to `capacity` inner-loop solutions so revisited `sigma` values are not
+    /// re-solved.
+    pub fn with_cache_capacity(mut self, capacity: usize) -> Self {
+        self.cache_capacity = capacity;
+        self
+    }
+
+    /// Configure the outer search over `sigma` run by
+    /// [`Problem::optimize`](crate::Problem::optimize).
+    pub fn with_optimization(mut self, optimization: OptimizationOptions) -> Self {
+        self.optimization = optimization;
+        self
+    }
+
+    /// Stream intermediate results to `progress.path` as estimation proceeds.
+    pub fn with_progress(mut self, progress: ProgressOptions) -> Self {
+        self.progress = Some(progress);
+        self
+    }
 }
 
 /// Backwards-compatible alias for users migrating from earlier versions.
 pub type EstimationOptions = ProblemOptions;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounds_builders_fix_and_clamp_entries() {
+        let bounds = ParameterBounds::nonnegative_diagonal(2)
+            .with_upper(1, 1, 0.8)
+            .with_lower(1, 0, -0.5)
+            .with_fixed(0, 1, 0.25);
+        assert_eq!(bounds.lower[(0, 0)], 0.0);
+        assert_eq!(bounds.upper[(0, 0)], f64::INFINITY);
+        assert_eq!(bounds.lower[(1, 0)], -0.5);
+        assert!(bounds.is_fixed((0, 1)));
+        assert!(!bounds.is_fixed((1, 1)));
+        assert!(!ParameterBounds::unbounded(2).is_fixed((0, 0)));
+
+        let mut sigma = DMatrix::from_row_slice(2, 2, &[-1.0, 0.0, -2.0, 3.0]);
+        bounds.hold_fixed(&mut sigma);
+        assert_eq!(
+            sigma,
+            DMatrix::from_row_slice(2, 2, &[-1.0, 0.25, -2.0, 3.0])
+        );
+        bounds.clamp(&mut sigma);
+        assert_eq!(
+            sigma,
+            DMatrix::from_row_slice(2, 2, &[0.0, 0.25, -0.5, 0.8])
+        );
+        assert!(bounds.validate(2).is_ok());
+        assert!(matches!(
+            bounds.validate(3),
+            Err(BlpError::DimensionMismatch { .. })
+        ));
+        assert!(matches!(
+            bounds.clone().with_upper(1, 0, -1.0).validate(2),
+            Err(BlpError::InvalidBounds {
+                row: 1,
+                column: 0,
+                ..
+            })
+        ));
+
+        let options = ProblemOptions::default().with_bounds(bounds.clone());
+        assert_eq!(options.optimization.bounds, Some(bounds));
+    }
+}
@@ -0,0 +1,85 @@
+//! Bounded nested parallelism over outer tasks and markets.
+//!
+//! Per-market work uses rayon's *current* pool, so running it inside a pool installed
+//! here bounds it. Outer tasks (folds, optimizer starts) go through [`run_nested`], which
+//! splits the budget so that nested `par_iter` calls do not oversubscribe the machine.
+
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use crate::error::{BlpError, Result};
+use crate::options::ParallelismOptions;
+
+fn pool(threads: usize) -> Result<ThreadPool> {
+    ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .map_err(|err| BlpError::ThreadPool {
+            message: err.to_string(),
+        })
+}
+
+/// Run `work` with per-market parallelism bounded by `options.threads`.
+///
+/// Work that is already running on a rayon worker (e.g. inside [`run_nested`]) stays in
+/// that worker's pool, which already carries its share of the budget.
+pub(crate) fn install<T, F>(options: &ParallelismOptions, work: F) -> Result<T>
+where
+    T: Send,
+    F: FnOnce() -> Result<T> + Send,
+{
+    match options.threads {
+        Some(threads) if rayon::current_thread_index().is_none() => pool(threads)?.install(work),
+        _ => work(),
+    }
+}
+
+/// Run `tasks` outer tasks concurrently, each with its own inner pool for per-market work.
+pub(crate) fn run_nested<T, F>(
+    options: &ParallelismOptions,
+    tasks: usize,
+    work: F,
+) -> Result<Vec<T>>
+where
+    T: Send,
+    F: Fn(usize) -> Result<T> + Sync,
+{
+    let (outer, inner) = options.split(tasks);
+    log::debug!("running {tasks} tasks on {outer} outer x {inner} inner threads");
+    let inner_pools = (0..outer)
+        .map(|_| pool(inner))
+        .collect::<Result<Vec<_>>>()?;
+    pool(outer)?.install(|| {
+        (0..tasks)
+            .into_par_iter()
+            .map(|task| {
+                let slot = rayon::current_thread_index().unwrap_or(0) % outer;
+                inner_pools[slot].install(|| work(task))
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nested_tasks_share_the_thread_budget() {
+        let options = ParallelismOptions::default()
+            .with_threads(4)
+            .with_outer_tasks(2);
+        assert_eq!(options.split(10), (2, 2));
+        assert_eq!(options.split(1), (1, 4));
+
+        let inner =
+            run_nested(&options, 6, |task| Ok((task, rayon::current_num_threads()))).unwrap();
+        assert_eq!(inner.len(), 6);
+        assert!(
+            inner
+                .iter()
+                .enumerate()
+                .all(|(i, (task, threads))| i == *task && *threads == 2)
+        );
+    }
+}
@@ -0,0 +1,438 @@
+//! Typed container for the nonlinear parameters of a demand model.
+//!
+//! [`NonlinearParameters`] bundles the random coefficient scale `sigma` (`K2 x K2`), the
+//! demographic interactions `pi` (`K2 x D`), and the nesting parameter `rho`, and checks
+//! their shapes against the product data and draws before anything is solved. Consumer
+//! `r`'s taste shift for product `j` is `x2_j' (sigma nu_r + pi d_r)`, where `d_r` is the
+//! agent's row of [`SimulationDraws::demographics`]. A [`ParameterMask`] marks entries of
+//! `sigma` and `pi` that the outer search holds fixed.
+
+use nalgebra::{DMatrix, DVector};
+use serde::{Deserialize, Serialize};
+
+use crate::data::ProductData;
+use crate::error::{BlpError, Result};
+use crate::integration::SimulationDraws;
+
+/// Nonlinear parameters `sigma`, `pi`, and `rho`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NonlinearParameters {
+    sigma: DMatrix<f64>,
+    pi: Option<DMatrix<f64>>,
+    rho: Option<f64>,
+}
+
+/// Whether every off-diagonal entry of `sigma` is zero.
+pub(crate) fn is_diagonal(sigma: &DMatrix<f64>) -> bool {
+    sigma.column_iter().enumerate().all(|(column, values)| {
+        values
+            .iter()
+            .enumerate()
+            .all(|(row, v)| row == column || *v == 0.0)
+    })
+}
+
+impl Default for NonlinearParameters {
+    /// No nonlinear parameters: the plain logit.
+    fn default() -> Self {
+        Self::new(DMatrix::zeros(0, 0))
+    }
+}
+
+impl From<DMatrix<f64>> for NonlinearParameters {
+    fn from(sigma: DMatrix<f64>) -> Self {
+        Self::new(sigma)
+    }
+}
+
+impl NonlinearParameters {
+    /// Random coefficients with scale `sigma`, indexed `(X2 column, draw column)`.
+    pub fn new(sigma: DMatrix<f64>) -> Self {
+        Self {
+            sigma,
+            pi: None,
+            rho: None,
+        }
+    }
+
+    /// Independent random coefficients with standard deviations `diagonal`, i.e. a
+    /// diagonal `sigma`. Share computations take a cheaper path for diagonal `sigma`.
+    pub fn diagonal(diagonal: DVector<f64>) -> Self {
+        Self::new(DMatrix::from_diagonal(&diagonal))
+    }
+
+    /// Whether every off-diagonal entry of `sigma` is zero.
+    pub fn is_diagonal(&self) -> bool {
+        is_diagonal(&self.sigma)
+    }
+
+    /// Interact the `X2` characteristics with agent demographics through `pi`, indexed
+    /// `(X2 column, demographic column)`.
+    pub fn with_pi(mut self, pi: DMatrix<f64>) -> Self {
+        self.pi = Some(pi);
+        self
+    }
+
+    /// Correlate tastes within nests by `rho` (see
+    /// [`NestedLogit`](crate::models::NestedLogit)).
+    pub fn with_rho(mut self, rho: f64) -> Self {
+        self.rho = Some(rho);
+        self
+    }
+
+    /// Random coefficient scale.
+    pub fn sigma(&self) -> &DMatrix<f64> {
+        &self.sigma
+    }
+
+    /// Demographic interactions, if any.
+    pub fn pi(&self) -> Option<&DMatrix<f64>> {
+        self.pi.as_ref()
+    }
+
+    /// Nesting parameter, if any.
+    pub fn rho(&self) -> Option<f64> {
+        self.rho
+    }
+
+    /// Check the shapes against `data` and `draws`: `sigma` must be `K2 x K2` with one
+    /// draw column per `X2` column, `pi` must be `K2 x D` for the `D` attached
+    /// demographics, and `rho` must lie in `[0, 1)` with nesting groups in the data.
+    pub fn validate(&self, data: &ProductData, draws: &SimulationDraws) -> Result<()> {
+        let k2 = data.nonlinear_dim();
+        if self.sigma.nrows() != k2 {
+            return Err(BlpError::dimension_mismatch(
+                "sigma rows",
+                k2,
+                self.sigma.nrows(),
+            ));
+        }
+        if self.sigma.ncols() != draws.dimension() {
+            return Err(BlpError::dimension_mismatch(
+                "sigma columns",
+                draws.dimension(),
+                self.sigma.ncols(),
+            ));
+        }
+        if let Some(pi) = &self.pi {
+            let demographics = draws
+                .demographics()
+                .ok_or_else(|| BlpError::missing_component("demographics"))?;
+            if pi.nrows() != k2 {
+                return Err(BlpError::dimension_mismatch("pi rows", k2, pi.nrows()));
+            }
+            if pi.ncols() != demographics.ncols() {
+                return Err(BlpError::dimension_mismatch(
+                    "pi columns",
+                    demographics.ncols(),
+                    pi.ncols(),
+                ));
+            }
+        }
+        if let Some(rho) = self.rho {
+            if !(0.0..1.0).contains(&rho) {
+                return Err(BlpError::InvalidNestingParameter { rho });
+            }
+            if data.nesting_ids().is_none() {
+                return Err(BlpError::missing_component("nesting ids"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Named values of the nonzero entries (every diagonal entry of `sigma`), e.g.
+    /// `sigma[price]`, `sigma[price, sugar]`, `pi[price, d_0]`, and `rho`, using the `X2`
+    /// labels of `data`.
+    pub fn labelled(&self, data: &ProductData) -> Vec<(String, f64)> {
+        let x2 = &data.labels().x2;
+        let name = |row: usize| x2.get(row).cloned().unwrap_or_else(|| format!("x2_{row}"));
+        let mut values = Vec::new();
+        for column in 0..self.sigma.ncols() {
+            for row in 0..self.sigma.nrows() {
+                let value = self.sigma[(row, column)];
+                if row == column {
+                    values.push((format!("sigma[{}]", name(row)), value));
+                } else if value != 0.0 {
+                    values.push((format!("sigma[{}, {}]", name(row), name(column)), value));
+                }
+            }
+        }
+        if let Some(pi) = &self.pi {
+            for column in 0..pi.ncols() {
+                for row in 0..pi.nrows() {
+                    let value = pi[(row, column)];
+                    if value != 0.0 {
+                        values.push((format!("pi[{}, d_{column}]", name(row)), value));
+                    }
+                }
+            }
+        }
+        if let Some(rho) = self.rho {
+            values.push(("rho".to_string(), rho));
+        }
+        values
+    }
+}
+
+/// Entries of `sigma` and `pi` held fixed at given values by
+/// [`Problem::optimize`](crate::Problem::optimize); every other entry is free.
+///
+/// A free entry is still only searched when its starting value is nonzero, as usual. A
+/// fixed entry is set to its value before the search, whatever its starting value.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ParameterMask {
+    sigma: DMatrix<Option<f64>>,
+    pi: Option<DMatrix<Option<f64>>>,
+}
+
+impl ParameterMask {
+    /// Every entry of a `k2 x k2` `sigma` free, with no `pi`.
+    pub fn new(k2: usize) -> Self {
+        Self {
+            sigma: DMatrix::from_element(k2, k2, None),
+            pi: None,
+        }
+    }
+
+    /// Every entry free, including those of a `k2 x demographics` `pi`.
+    pub fn with_demographics(mut self, demographics: usize) -> Self {
+        self.pi = Some(DMatrix::from_element(
+            self.sigma.nrows(),
+            demographics,
+            None,
+        ));
+        self
+    }
+
+    /// A mask with the given entries fixed, for shapes that are already known to agree.
+    pub(crate) fn from_entries(
+        sigma: DMatrix<Option<f64>>,
+        pi: Option<DMatrix<Option<f64>>>,
+    ) -> Self {
+        Self { sigma, pi }
+    }
+
+    /// Hold `sigma[(row, column)]` fixed at `value`, which must be finite and inside the
+    /// `k2 x k2` matrix.
+    pub fn fix_sigma(mut self, row: usize, column: usize, value: f64) -> Result<Self> {
+        let k2 = self.sigma.nrows();
+        if row >= k2 || column >= k2 {
+            return Err(BlpError::dimension_mismatch(
+                "mask sigma entry",
+                k2,
+                row.max(column) + 1,
+            ));
+        }
+        if !value.is_finite() {
+            return Err(BlpError::NumericalError {
+                context: "mask sigma value",
+            });
+        }
+        self.sigma[(row, column)] = Some(value);
+        Ok(self)
+    }
+
+    /// Hold `pi[(row, column)]` fixed at `value`, which must be finite and inside the
+    /// `pi` set up by [`with_demographics`](Self::with_demographics).
+    pub fn fix_pi(mut self, row: usize, column: usize, value: f64) -> Result<Self> {
+        let pi = self
+            .pi
+            .as_mut()
+            .ok_or_else(|| BlpError::missing_component("demographics in the parameter mask"))?;
+        if row >= pi.nrows() {
+            return Err(BlpError::dimension_mismatch(
+                "mask pi rows",
+                pi.nrows(),
+                row + 1,
+            ));
+        }
+        if column >= pi.ncols() {
+            return Err(BlpError::dimension_mismatch(
+                "mask pi columns",
+                pi.ncols(),
+                column + 1,
+            ));
+        }
+        if !value.is_finite() {
+            return Err(BlpError::NumericalError {
+                context: "mask pi value",
+            });
+        }
+        pi[(row, column)] = Some(value);
+        Ok(self)
+    }
+
+    /// The value `sigma[entry]` is held at, if it is fixed.
+    pub fn sigma_value(&self, entry: (usize, usize)) -> Option<f64> {
+        self.sigma.get(entry).copied().flatten()
+    }
+
+    /// The value `pi[entry]` is held at, if it is fixed.
+    pub fn pi_value(&self, entry: (usize, usize)) -> Option<f64> {
+        self.pi
+            .as_ref()
+            .and_then(|pi| pi.get(entry).copied().flatten())
+    }
+
+    /// Shape of the mask on `sigma`.
+    pub(crate) fn sigma_shape(&self) -> (usize, usize) {
+        self.sigma.shape()
+    }
+
+    /// Shape of the mask on `pi`, if it covers `pi`.
+    pub(crate) fn pi_shape(&self) -> Option<(usize, usize)> {
+        self.pi.as_ref().map(DMatrix::shape)
+    }
+
+    /// Whether any entry of `pi` is fixed.
+    pub(crate) fn fixes_pi(&self) -> bool {
+        self.pi.iter().flatten().any(Option::is_some)
+    }
+
+    /// Number of fixed entries.
+    pub fn fixed_count(&self) -> usize {
+        self.sigma
+            .iter()
+            .chain(self.pi.iter().flatten())
+            .filter(|value| value.is_some())
+            .count()
+    }
+
+    /// Keep only the random coefficients at positions `columns`: those rows and columns
+    /// of `sigma` and those rows of `pi`.
+    pub(crate) fn select_nonlinear(&self, columns: &[usize]) -> Self {
+        Self {
+            sigma: self
+                .sigma
+                .select_rows(columns.iter())
+                .select_columns(columns.iter()),
+            pi: self.pi.as_ref().map(|pi| pi.select_rows(columns.iter())),
+        }
+    }
+
+    /// Set the fixed entries of `sigma` to their values.
+    pub(crate) fn hold_sigma(&self, sigma: &mut DMatrix<f64>) {
+        hold(sigma, &self.sigma);
+    }
+
+    /// Set the fixed entries of `pi` to their values.
+    pub(crate) fn hold_pi(&self, pi: &mut DMatrix<f64>) {
+        if let Some(mask) = &self.pi {
+            hold(pi, mask);
+        }
+    }
+
+    /// Check the shapes: `sigma` must be `k2 x k2` and `pi`, if any, `k2 x demographics`.
+    /// Fixed `pi` entries need `pi` to be estimated, i.e. a starting `pi`.
+    pub(crate) fn validate(&self, k2: usize, demographics: usize, searches_pi: bool) -> Result<()> {
+        if self.sigma.shape() != (k2, k2) {
+            return Err(BlpError::dimension_mismatch(
+                "mask sigma rows",
+                k2,
+                self.sigma.nrows(),
+            ));
+        }
+        if let Some(pi) = &self.pi
+            && pi.shape() != (k2, demographics)
+        {
+            return Err(BlpError::dimension_mismatch(
+                "mask pi columns",
+                demographics,
+                pi.ncols(),
+            ));
+        }
+        if !searches_pi && self.fixes_pi() {
+            return Err(BlpError::missing_component(
+                "starting values for the pi entries fixed by the mask",
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn hold(values: &mut DMatrix<f64>, mask: &DMatrix<Option<f64>>) {
+    for (value, fixed) in values.iter_mut().zip(mask) {
+        if let Some(fixed) = fixed {
+            *value = *fixed;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Problem;
+    use crate::data::ProductDataBuilder;
+    use nalgebra::DVector;
+
+    #[test]
+    fn parameters_are_checked_and_labelled() {
+        let market_ids = ["m1", "m1", "m2", "m2"].map(String::from).to_vec();
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.25, 0.15]);
+        let x1 = DMatrix::from_row_slice(4, 2, &[1.0, 1.0, 1.0, 2.0, 1.0, 1.5, 1.0, 0.5]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .x2_from_x1(vec![1])
+            .x1_labels(vec!["constant".into(), "price".into()])
+            .build()
+            .unwrap();
+        let draws = SimulationDraws::standard_normal(20, 1, 5);
+        let income = DMatrix::from_fn(20, 1, |r, _| r as f64 / 10.0 - 1.0);
+        let draws = draws.with_demographics(income).unwrap();
+
+        let sigma = NonlinearParameters::new(DMatrix::from_element(1, 1, 0.5));
+        assert!(sigma.validate(&data, &draws).is_ok());
+        let with_pi = sigma.clone().with_pi(DMatrix::from_element(1, 1, 0.3));
+        assert_eq!(
+            with_pi.labelled(&data),
+            vec![
+                ("sigma[price]".to_string(), 0.5),
+                ("pi[price, d_0]".to_string(), 0.3)
+            ]
+        );
+        for invalid in [
+            NonlinearParameters::new(DMatrix::zeros(2, 1)),
+            sigma.clone().with_pi(DMatrix::zeros(1, 2)),
+            sigma.clone().with_rho(0.5),
+            NonlinearParameters::default(),
+        ] {
+            assert!(invalid.validate(&data, &draws).is_err());
+        }
+
+        let problem = Problem::new(data, draws).unwrap();
+        let base = problem.solve(&sigma).unwrap();
+        let zero_pi = problem
+            .solve(&sigma.clone().with_pi(DMatrix::zeros(1, 1)))
+            .unwrap();
+        assert_eq!(zero_pi.delta, base.delta);
+        let interacted = problem.solve(&with_pi).unwrap();
+        assert_ne!(interacted.delta, base.delta);
+        assert_eq!(interacted.parameters(), with_pi);
+    }
+
+    #[test]
+    fn masks_reject_entries_outside_their_parameters() {
+        let mask = ParameterMask::new(2).fix_sigma(1, 0, 0.5).unwrap();
+        assert_eq!(mask.sigma_value((1, 0)), Some(0.5));
+        assert_eq!(
+            (mask.sigma_value((2, 0)), mask.pi_value((0, 0))),
+            (None, None)
+        );
+        assert!(ParameterMask::new(2).fix_sigma(2, 0, 0.5).is_err());
+        assert!(ParameterMask::new(2).fix_sigma(0, 0, f64::NAN).is_err());
+        assert!(ParameterMask::new(2).fix_pi(0, 0, 0.5).is_err());
+
+        let with_pi = ParameterMask::new(2).with_demographics(1);
+        assert!(with_pi.clone().fix_pi(2, 0, 0.5).is_err());
+        assert!(with_pi.clone().fix_pi(0, 1, 0.5).is_err());
+        assert!(with_pi.clone().fix_pi(0, 0, f64::INFINITY).is_err());
+        let fixed = with_pi.clone().fix_pi(1, 0, 0.5).unwrap();
+        assert_eq!(fixed.pi_value((1, 0)), Some(0.5));
+
+        assert!(with_pi.validate(2, 1, false).is_ok());
+        assert!(fixed.validate(2, 1, true).is_ok());
+        assert!(fixed.validate(2, 1, false).is_err());
+        assert!(fixed.validate(2, 2, true).is_err());
+        assert!(mask.validate(3, 0, false).is_err());
+    }
+}
@@ -0,0 +1,406 @@
+//! Streaming sinks that record intermediate estimation results to disk.
+//!
+//! Long estimations are easier to monitor (and to debug after a crash) when every
+//! objective evaluation leaves a trace on disk. A [`ProgressWriter`] appends one
+//! [`ProgressRecord`] per evaluation to a JSON Lines or CSV file and flushes after
+//! each write so that partial runs remain inspectable. One writer serves a whole
+//! [`Problem::optimize`](crate::Problem::optimize) run, so records are numbered and
+//! timed from the start of the run and carry the best parameters found so far. A CSV file
+//! keeps the columns of its header: appending records of another width is an error.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{BlpError, Result};
+use crate::estimation::ProblemResults;
+
+/// File format used by a [`ProgressWriter`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProgressFormat {
+    /// One JSON object per line.
+    JsonLines,
+    /// Comma-separated values with a header row written when the file is empty.
+    Csv,
+}
+
+/// Destination and format of the streamed estimation progress.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProgressOptions {
+    /// File that records are appended to; created if it does not exist.
+    pub path: PathBuf,
+    /// Serialization format for each record.
+    pub format: ProgressFormat,
+    /// Also record the mean utilities and structural errors of every evaluation, e.g.
+    /// to replay or animate an optimizer's path afterwards.
+    #[serde(default)]
+    pub include_residuals: bool,
+}
+
+impl ProgressOptions {
+    /// Stream records as JSON Lines to `path`.
+    pub fn json_lines<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            format: ProgressFormat::JsonLines,
+            include_residuals: false,
+        }
+    }
+
+    /// Stream records as CSV to `path`.
+    pub fn csv<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            format: ProgressFormat::Csv,
+            include_residuals: false,
+        }
+    }
+
+    /// Record `delta` and `xi` alongside the parameters.
+    pub fn with_residuals(mut self) -> Self {
+        self.include_residuals = true;
+        self
+    }
+}
+
+/// Snapshot of the estimator after a single objective evaluation.
+#[derive(Clone, Debug, Serialize)]
+pub struct ProgressRecord {
+    /// Zero-based index of the evaluation within the current run.
+    pub evaluation: usize,
+    /// Seconds elapsed since the writer was opened.
+    pub elapsed_seconds: f64,
+    /// GMM objective value at this evaluation.
+    pub objective: f64,
+    /// Lowest objective recorded by the writer so far, this evaluation included.
+    pub best_objective: f64,
+    /// `sigma` at this evaluation, flattened in column-major order.
+    pub sigma: Vec<f64>,
+    /// `pi` at this evaluation, flattened in column-major order; empty without
+    /// demographics.
+    pub pi: Vec<f64>,
+    /// Nesting parameter at this evaluation, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rho: Option<f64>,
+    /// Linear parameters concentrated out at this evaluation.
+    pub beta: Vec<f64>,
+    /// `sigma` at the evaluation with [`best_objective`](Self::best_objective).
+    pub best_sigma: Vec<f64>,
+    /// `pi` at the evaluation with the best objective.
+    pub best_pi: Vec<f64>,
+    /// `rho` at the evaluation with the best objective, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub best_rho: Option<f64>,
+    /// `beta` at the evaluation with the best objective.
+    pub best_beta: Vec<f64>,
+    /// Mean utilities, when residuals are recorded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delta: Option<Vec<f64>>,
+    /// Structural errors, when residuals are recorded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub xi: Option<Vec<f64>>,
+}
+
+/// Appends [`ProgressRecord`]s to a file as estimation proceeds.
+#[derive(Debug)]
+pub struct ProgressWriter {
+    file: BufWriter<File>,
+    format: ProgressFormat,
+    columns: Option<usize>,
+    started: Instant,
+    evaluations: usize,
+    best: Option<ProgressRecord>,
+}
+
+impl ProgressWriter {
+    /// Open (or create) the destination file in append mode.
+    pub fn open(options: &ProgressOptions) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&options.path)
+            .map_err(|err| BlpError::io("opening progress file", err))?;
+        let existing = file
+            .metadata()
+            .map_err(|err| BlpError::io("inspecting progress file", err))?
+            .len();
+        let columns = if options.format == ProgressFormat::Csv && existing > 0 {
+            let mut header = String::new();
+            BufReader::new(
+                File::open(&options.path)
+                    .map_err(|err| BlpError::io("reading progress header", err))?,
+            )
+            .read_line(&mut header)
+            .map_err(|err| BlpError::io("reading progress header", err))?;
+            Some(header.trim_end().split(',').count())
+        } else {
+            None
+        };
+        Ok(Self {
+            file: BufWriter::new(file),
+            format: options.format,
+            columns,
+            started: Instant::now(),
+            evaluations: 0,
+            best: None,
+        })
+    }
+
+    /// Record the parameters and objective of the latest evaluation.
+    pub fn record(&mut self, sigma: &[f64], beta: &[f64], objective: f64) -> Result<()> {
+        let record = self.next_record(sigma, &[], None, beta, objective);
+        self.write(&record)
+    }
+
+    /// Record the latest evaluation together with its mean utilities and structural
+    /// errors.
+    pub fn record_residuals(
+        &mut self,
+        sigma: &[f64],
+        beta: &[f64],
+        objective: f64,
+        delta: &[f64],
+        xi: &[f64],
+    ) -> Result<()> {
+        let mut record = self.next_record(sigma, &[], None, beta, objective);
+        record.delta = Some(delta.to_vec());
+        record.xi = Some(xi.to_vec());
+        self.write(&record)
+    }
+
+    /// Record an evaluation's results, with its residuals when `include_residuals` is set.
+    pub(crate) fn record_results(
+        &mut self,
+        results: &ProblemResults,
+        include_residuals: bool,
+    ) -> Result<()> {
+        let pi = results.pi.as_ref().map_or(&[][..], |pi| pi.as_slice());
+        let mut record = self.next_record(
+            results.sigma.as_slice(),
+            pi,
+            results.rho,
+            results.beta.as_slice(),
+            results.gmm_value,
+        );
+        if include_residuals {
+            record.delta = Some(results.delta.as_slice().to_vec());
+            record.xi = Some(results.xi.as_slice().to_vec());
+        }
+        self.write(&record)
+    }
+
+    fn next_record(
+        &mut self,
+        sigma: &[f64],
+        pi: &[f64],
+        rho: Option<f64>,
+        beta: &[f64],
+        objective: f64,
+    ) -> ProgressRecord {
+        let mut record = ProgressRecord {
+            evaluation: self.evaluations,
+            elapsed_seconds: self.started.elapsed().as_secs_f64(),
+            objective,
+            best_objective: objective,
+            sigma: sigma.to_vec(),
+            pi: pi.to_vec(),
+            rho,
+            beta: beta.to_vec(),
+            best_sigma: sigma.to_vec(),
+            best_pi: pi.to_vec(),
+            best_rho: rho,
+            best_beta: beta.to_vec(),
+            delta: None,
+            xi: None,
+        };
+        let kept = self
+            .best
+            .as_ref()
+            .filter(|best| objective.is_nan() || best.best_objective <= objective);
+        match kept {
+            Some(best) => {
+                record.best_objective = best.best_objective;
+                record.best_sigma.clone_from(&best.best_sigma);
+                record.best_pi.clone_from(&best.best_pi);
+                record.best_rho = best.best_rho;
+                record.best_beta.clone_from(&best.best_beta);
+            }
+            _ => self.best = Some(record.clone()),
+        }
+        self.evaluations += 1;
+        record
+    }
+
+    /// Append an already-assembled record and flush it to disk.
+    pub fn write(&mut self, record: &ProgressRecord) -> Result<()> {
+        match self.format {
+            ProgressFormat::JsonLines => {
+                serde_json::to_writer(&mut self.file, record).map_err(|err| {
+                    BlpError::io("writing progress record", std::io::Error::other(err))
+                })?;
+                writeln!(self.file).map_err(|err| BlpError::io("writing progress record", err))?;
+            }
+            ProgressFormat::Csv => {
+                let (header, fields) = csv_columns(record);
+                match self.columns {
+                    None => {
+                        writeln!(self.file, "{}", header.join(","))
+                            .map_err(|err| BlpError::io("writing progress header", err))?;
+                        self.columns = Some(header.len());
+                    }
+                    Some(columns) if columns != fields.len() => {
+                        return Err(BlpError::dimension_mismatch(
+                            "progress CSV columns",
+                            columns,
+                            fields.len(),
+                        ));
+                    }
+                    Some(_) => {}
+                }
+                writeln!(self.file, "{}", fields.join(","))
+                    .map_err(|err| BlpError::io("writing progress record", err))?;
+            }
+        }
+        self.file
+            .flush()
+            .map_err(|err| BlpError::io("flushing progress file", err))
+    }
+}
+
+/// The CSV header and fields of `record`, in the same order.
+fn csv_columns(record: &ProgressRecord) -> (Vec<String>, Vec<String>) {
+    let mut header: Vec<String> = [
+        "evaluation",
+        "elapsed_seconds",
+        "objective",
+        "best_objective",
+    ]
+    .map(String::from)
+    .to_vec();
+    let mut fields = vec![
+        record.evaluation.to_string(),
+        record.elapsed_seconds.to_string(),
+        record.objective.to_string(),
+        record.best_objective.to_string(),
+    ];
+    for (prefix, sigma, pi, rho, beta) in [
+        ("", &record.sigma, &record.pi, record.rho, &record.beta),
+        (
+            "best_",
+            &record.best_sigma,
+            &record.best_pi,
+            record.best_rho,
+            &record.best_beta,
+        ),
+    ] {
+        for (name, values) in [("sigma", sigma), ("pi", pi)] {
+            header.extend((0..values.len()).map(|i| format!("{prefix}{name}_{i}")));
+            fields.extend(values.iter().map(f64::to_string));
+        }
+        if let Some(rho) = rho {
+            header.push(format!("{prefix}rho"));
+            fields.push(rho.to_string());
+        }
+        header.extend((0..beta.len()).map(|i| format!("{prefix}beta_{i}")));
+        fields.extend(beta.iter().map(f64::to_string));
+    }
+    for (name, values) in [("delta", &record.delta), ("xi", &record.xi)] {
+        if let Some(values) = values {
+            header.extend((0..values.len()).map(|i| format!("{name}_{i}")));
+            fields.extend(values.iter().map(f64::to_string));
+        }
+    }
+    (header, fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_writer_emits_header_once() {
+        let path = std::env::temp_dir().join(format!("blprs-progress-{}.csv", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let options = ProgressOptions::csv(&path);
+
+        let mut writer = ProgressWriter::open(&options).unwrap();
+        writer.record(&[0.5], &[1.0, -2.0], 3.0).unwrap();
+        drop(writer);
+        let mut writer = ProgressWriter::open(&options).unwrap();
+        writer.record(&[0.6], &[1.1, -2.1], 2.0).unwrap();
+        writer.record(&[0.7], &[1.2, -2.2], 2.5).unwrap();
+        // Appending records of another width would misalign the columns.
+        assert!(writer.record(&[0.7, 0.1], &[1.2, -2.2], 2.5).is_err());
+        drop(writer);
+        let mut writer = ProgressWriter::open(&options).unwrap();
+        assert!(writer.record(&[0.7], &[1.2], 2.5).is_err());
+        drop(writer);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[0],
+            "evaluation,elapsed_seconds,objective,best_objective,sigma_0,beta_0,beta_1,\
+             best_sigma_0,best_beta_0,best_beta_1"
+        );
+        // The second evaluation of the run is worse, so the best parameters stay put.
+        assert!(lines[3].ends_with(",2.5,2,0.7,1.2,-2.2,0.6,1.1,-2.1"));
+    }
+
+    #[test]
+    fn records_carry_pi_rho_and_the_best_parameters() {
+        let path =
+            std::env::temp_dir().join(format!("blprs-progress-best-{}.csv", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut writer = ProgressWriter::open(&ProgressOptions::csv(&path)).unwrap();
+        for (sigma, objective) in [(0.5, 3.0), (0.6, 1.0), (0.7, 2.0)] {
+            let record = writer.next_record(&[sigma], &[0.2], Some(0.3), &[1.0], objective);
+            writer.write(&record).unwrap();
+        }
+        let record = writer.next_record(&[0.8], &[0.1], Some(0.4), &[2.0], 1.5);
+        assert_eq!(record.best_objective, 1.0);
+        assert_eq!(
+            (record.best_sigma, record.best_pi, record.best_rho),
+            (vec![0.6], vec![0.2], Some(0.3))
+        );
+        drop(writer);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(
+            lines[0],
+            "evaluation,elapsed_seconds,objective,best_objective,sigma_0,pi_0,rho,beta_0,\
+             best_sigma_0,best_pi_0,best_rho,best_beta_0"
+        );
+        assert!(lines[3].ends_with(",2,1,0.7,0.2,0.3,1,0.6,0.2,0.3,1"));
+    }
+
+    #[test]
+    fn json_lines_record_residuals_on_request() {
+        let path =
+            std::env::temp_dir().join(format!("blprs-progress-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut writer = ProgressWriter::open(&ProgressOptions::json_lines(&path)).unwrap();
+        writer.record(&[0.5], &[1.0], 3.0).unwrap();
+        writer
+            .record_residuals(&[0.6], &[1.1], 2.0, &[0.1, 0.2], &[-0.1, 0.3])
+            .unwrap();
+        drop(writer);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert!(lines[0].get("xi").is_none());
+        assert_eq!(lines[1]["evaluation"], 1);
+        assert_eq!(lines[1]["xi"][1], 0.3);
+    }
+}
@@ -0,0 +1,572 @@
+//! Human-readable summaries of estimation results.
+//!
+//! [`ProblemResults::summary`] renders aligned tables of the parameters, the objective,
+//! contraction diagnostics, and timing as plain text, Markdown, or LaTeX, and
+//! `ProblemResults` implements [`Display`](fmt::Display) with the default settings.
+//! [`ProblemResults::to_latex`] emits a publication-style coefficient table.
+
+use std::fmt;
+
+use nalgebra::DVector;
+
+use crate::data::ProductData;
+use crate::error::{BlpError, Result};
+use crate::estimation::{LinearEstimator, ProblemResults};
+use crate::statistics::normal_two_sided_p;
+
+/// Markup used when rendering tables.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TableFormat {
+    /// Space-aligned columns for terminals and logs.
+    #[default]
+    Plain,
+    /// GitHub-flavoured Markdown tables.
+    Markdown,
+    /// LaTeX `tabular` environments.
+    Latex,
+}
+
+/// Settings for [`ProblemResults::summary`].
+#[derive(Clone, Debug)]
+pub struct SummaryOptions {
+    /// Significant digits shown for every number.
+    pub digits: usize,
+    /// Markup of the rendered tables.
+    pub format: TableFormat,
+    /// Names of the `X1` columns; `x1_<i>` is used when the length does not match.
+    pub beta_labels: Vec<String>,
+    /// Names of the `X2` columns; `x2_<i>` is used when the length does not match.
+    pub sigma_labels: Vec<String>,
+    /// Standard errors in `theta = [beta; vec(sigma)]` order, shown next to the estimates;
+    /// `None` falls back to [`ProblemResults::standard_errors`].
+    pub standard_errors: Option<DVector<f64>>,
+}
+
+impl Default for SummaryOptions {
+    fn default() -> Self {
+        Self {
+            digits: 4,
+            format: TableFormat::default(),
+            beta_labels: Vec::new(),
+            sigma_labels: Vec::new(),
+            standard_errors: None,
+        }
+    }
+}
+
+impl SummaryOptions {
+    /// Default settings with parameter names taken from the column labels of `data`.
+    pub fn labelled(data: &ProductData) -> Self {
+        Self {
+            beta_labels: data.labels().x1.clone(),
+            sigma_labels: data.labels().x2.clone(),
+            ..Self::default()
+        }
+    }
+
+    /// Show `digits` significant digits (at least one).
+    pub fn with_digits(mut self, digits: usize) -> Self {
+        self.digits = digits.max(1);
+        self
+    }
+
+    /// Render the tables in `format`.
+    pub fn with_format(mut self, format: TableFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Show standard errors, ordered like `theta = [beta; vec(sigma)]`.
+    pub fn with_standard_errors(mut self, standard_errors: DVector<f64>) -> Self {
+        self.standard_errors = Some(standard_errors);
+        self
+    }
+}
+
+/// Settings for [`ProblemResults::to_latex`].
+#[derive(Clone, Debug)]
+pub struct LatexTableOptions {
+    /// Labels, significant digits, and standard errors; `format` is ignored.
+    pub summary: SummaryOptions,
+    /// Parameter names as shown in the table (e.g. `price` or `sigma[price]`), in the
+    /// order they should appear. Parameters not listed are left out; `None` keeps every
+    /// parameter in the default order.
+    pub row_order: Option<Vec<String>>,
+    /// Two-sided significance levels marked with one, two, three, ... stars, loosest
+    /// first.
+    pub star_levels: Vec<f64>,
+    /// Heading of the estimate column.
+    pub column_title: String,
+}
+
+impl LatexTableOptions {
+    /// Conventional stars at 10%, 5%, and 1% under the given labels and standard errors.
+    pub fn new(summary: SummaryOptions) -> Self {
+        Self {
+            summary,
+            row_order: None,
+            star_levels: vec![0.1, 0.05, 0.01],
+            column_title: "Estimate".to_string(),
+        }
+    }
+
+    /// Show only the named parameters, in this order.
+    pub fn with_row_order(mut self, names: Vec<String>) -> Self {
+        self.row_order = Some(names);
+        self
+    }
+}
+
+/// One estimated parameter with its display name and position in `theta`.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ParameterRow {
+    pub(crate) name: String,
+    pub(crate) estimate: f64,
+    pub(crate) standard_error: Option<f64>,
+}
+
+/// Format `value` with `digits` significant digits, switching to scientific notation for
+/// very large or small magnitudes.
+pub fn format_significant(value: f64, digits: usize) -> String {
+    let digits = digits.max(1);
+    if !value.is_finite() {
+        return value.to_string();
+    }
+    if value == 0.0 {
+        return "0".to_string();
+    }
+    let magnitude = value.abs().log10().floor() as i64;
+    if (-4..digits as i64).contains(&magnitude) {
+        let decimals = (digits as i64 - 1 - magnitude).max(0) as usize;
+        format!("{value:.decimals$}")
+    } else {
+        format!("{value:.*e}", digits - 1)
+    }
+}
+
+fn label(labels: &[String], expected: usize, prefix: &str, index: usize) -> String {
+    if labels.len() == expected {
+        labels[index].clone()
+    } else {
+        format!("{prefix}_{index}")
+    }
+}
+
+impl ProblemResults {
+    /// `beta` followed by the diagonal and nonzero off-diagonal entries of `sigma`.
+    pub(crate) fn parameter_rows(&self, options: &SummaryOptions) -> Vec<ParameterRow> {
+        let k1 = self.beta.len();
+        let k2 = self.sigma.nrows();
+        let estimated = self.standard_errors();
+        let error = |index: usize| {
+            options
+                .standard_errors
+                .as_ref()
+                .or(estimated.as_ref())
+                .and_then(|errors| errors.get(index).copied())
+        };
+        let mut rows: Vec<ParameterRow> = (0..k1)
+            .map(|i| ParameterRow {
+                name: label(&options.beta_labels, k1, "x1", i),
+                estimate: self.beta[i],
+                standard_error: error(i),
+            })
+            .collect();
+        for column in 0..k2 {
+            for row in 0..k2 {
+                let estimate = self.sigma[(row, column)];
+                if row != column && estimate == 0.0 {
+                    continue;
+                }
+                let name = if row == column {
+                    format!("sigma[{}]", label(&options.sigma_labels, k2, "x2", row))
+                } else {
+                    format!(
+                        "sigma[{}, {}]",
+                        label(&options.sigma_labels, k2, "x2", row),
+                        label(&options.sigma_labels, k2, "x2", column)
+                    )
+                };
+                rows.push(ParameterRow {
+                    name,
+                    estimate,
+                    standard_error: error(k1 + column * k2 + row),
+                });
+            }
+        }
+        rows
+    }
+
+    /// Render the parameters, objective, convergence, and timing as aligned tables.
+    pub fn summary(&self, options: &SummaryOptions) -> String {
+        let digits = options.digits;
+        let number = |value: f64| format_significant(value, digits);
+        let with_errors = options.standard_errors.is_some() || self.covariance.is_some();
+
+        let mut header = vec!["Parameter", "Estimate"];
+        if with_errors {
+            header.push("Std. Error");
+        }
+        let parameters: Vec<Vec<String>> = self
+            .parameter_rows(options)
+            .into_iter()
+            .map(|row| {
+                let mut cells = vec![row.name, number(row.estimate)];
+                if with_errors {
+                    cells.push(row.standard_error.map_or_else(String::new, number));
+                }
+                cells
+            })
+            .collect();
+
+        let contraction = &self.contraction;
+        let criterion = contraction
+            .criterion
+            .map_or_else(|| "none".to_string(), |criterion| format!("{criterion:?}"));
+        let mut statistics = vec![
+            vec!["GMM objective".to_string(), number(self.gmm_value)],
+            vec![
+                "Contraction iterations".to_string(),
+                contraction.iterations.to_string(),
+            ],
+            vec![
+                "Contraction max gap".to_string(),
+                number(contraction.max_gap),
+            ],
+            vec![
+                "Contraction share error".to_string(),
+                number(contraction.max_share_error),
+            ],
+            vec![
+                "Share evaluations".to_string(),
+                contraction.share_evaluations.to_string(),
+            ],
+            vec!["Convergence criterion".to_string(), criterion],
+        ];
+        match self.linear_estimator {
+            LinearEstimator::InstrumentalVariables => {}
+            LinearEstimator::OrdinaryLeastSquares => statistics.push(vec![
+                "Linear estimator".to_string(),
+                "OLS (no instruments)".to_string(),
+            ]),
+            LinearEstimator::WeightedLeastSquares => statistics.push(vec![
+                "Linear estimator".to_string(),
+                "WLS (no instruments)".to_string(),
+            ]),
+        }
+        if contraction.is_truncated() {
+            statistics.push(vec![
+                "Truncated markets".to_string(),
+                contraction.truncated_markets.len().to_string(),
+            ]);
+        }
+        let profiling = &self.profiling;
+        statistics.extend([
+            vec![
+                "Contraction seconds".to_string(),
+                number(profiling.contraction_seconds),
+            ],
+            vec![
+                "Linear seconds".to_string(),
+                number(profiling.linear_seconds),
+            ],
+        ]);
+        if profiling.reused_contraction_seconds > 0.0 {
+            statistics.push(vec![
+                "Reused contraction seconds".to_string(),
+                number(profiling.reused_contraction_seconds),
+            ]);
+        }
+
+        let mut output = render_table(options.format, &header, &parameters);
+        output.push('\n');
+        output.push_str(&render_table(
+            options.format,
+            &["Statistic", "Value"],
+            &statistics,
+        ));
+        output
+    }
+}
+
+impl ProblemResults {
+    /// A LaTeX `tabular` of the coefficients with standard errors in parentheses beneath
+    /// each estimate and significance stars from two-sided normal tests.
+    ///
+    /// Stars and parentheses are only shown when
+    /// [`standard_errors`](SummaryOptions::standard_errors) are supplied or the results
+    /// carry a [`covariance`](ProblemResults::covariance). Fails when
+    /// [`row_order`](LatexTableOptions::row_order) names an unknown parameter.
+    pub fn to_latex(&self, options: &LatexTableOptions) -> Result<String> {
+        let digits = options.summary.digits;
+        let mut rows = self.parameter_rows(&options.summary);
+        if let Some(order) = &options.row_order {
+            rows = order
+                .iter()
+                .map(|name| {
+                    rows.iter()
+                        .find(|row| row.name == *name)
+                        .cloned()
+                        .ok_or_else(|| BlpError::UnknownParameter { name: name.clone() })
+                })
+                .collect::<Result<_>>()?;
+        }
+
+        let mut lines = vec![
+            "\\begin{tabular}{lc}".to_string(),
+            "\\hline".to_string(),
+            format!(" & {} \\\\", latex_escape(&options.column_title)),
+            "\\hline".to_string(),
+        ];
+        for row in &rows {
+            let stars = row.standard_error.map_or(0, |error| {
+                let p_value = normal_two_sided_p(row.estimate / error);
+                options
+                    .star_levels
+                    .iter()
+                    .filter(|&&level| p_value < level)
+                    .count()
+            });
+            let superscript = if stars > 0 {
+                format!("^{{{}}}", "*".repeat(stars))
+            } else {
+                String::new()
+            };
+            lines.push(format!(
+                "{} & ${}{superscript}$ \\\\",
+                latex_escape(&row.name),
+                format_significant(row.estimate, digits)
+            ));
+            if let Some(error) = row.standard_error {
+                lines.push(format!(" & $({})$ \\\\", format_significant(error, digits)));
+            }
+        }
+        lines.push("\\hline".to_string());
+        lines.push(format!(
+            "GMM objective & {} \\\\",
+            format_significant(self.gmm_value, digits)
+        ));
+        lines.push(format!("Products & {} \\\\", self.xi.len()));
+        lines.push("\\hline".to_string());
+        let with_errors = options.summary.standard_errors.is_some() || self.covariance.is_some();
+        if with_errors && !options.star_levels.is_empty() {
+            let notes: Vec<String> = options
+                .star_levels
+                .iter()
+                .enumerate()
+                .map(|(index, level)| format!("$^{{{}}}p<{level}$", "*".repeat(index + 1)))
+                .collect();
+            lines.push(format!(
+                "\\multicolumn{{2}}{{l}}{{\\footnotesize {}}} \\\\",
+                notes.join("; ")
+            ));
+        }
+        lines.push("\\end{tabular}".to_string());
+        let mut output = lines.join("\n");
+        output.push('\n');
+        Ok(output)
+    }
+}
+
+impl fmt::Display for ProblemResults {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.summary(&SummaryOptions::default()))
+    }
+}
+
+/// Escape the characters LaTeX treats specially in text mode.
+pub(crate) fn latex_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        match character {
+            '&' | '%' | '$' | '#' | '_' | '{' | '}' => {
+                escaped.push('\\');
+                escaped.push(character);
+            }
+            '~' => escaped.push_str("\\textasciitilde{}"),
+            '^' => escaped.push_str("\\textasciicircum{}"),
+            '\\' => escaped.push_str("\\textbackslash{}"),
+            _ => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+/// Lay out `rows` under `header`; the first column is left-aligned, the rest right-aligned.
+pub(crate) fn render_table(format: TableFormat, header: &[&str], rows: &[Vec<String>]) -> String {
+    let escape = |cell: &str| match format {
+        TableFormat::Latex => latex_escape(cell),
+        _ => cell.to_string(),
+    };
+    let header: Vec<String> = header.iter().map(|cell| escape(cell)).collect();
+    let rows: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| row.iter().map(|cell| escape(cell)).collect())
+        .collect();
+    let widths: Vec<usize> = (0..header.len())
+        .map(|column| {
+            rows.iter()
+                .filter_map(|row| row.get(column))
+                .chain(std::iter::once(&header[column]))
+                .map(|cell| cell.chars().count())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let pad = |cells: &[String]| -> Vec<String> {
+        widths
+            .iter()
+            .enumerate()
+            .map(|(column, &width)| {
+                let cell = cells.get(column).map_or("", String::as_str);
+                if column == 0 {
+                    format!("{cell:<width$}")
+                } else {
+                    format!("{cell:>width$}")
+                }
+            })
+            .collect()
+    };
+
+    let mut lines = Vec::with_capacity(rows.len() + 4);
+    match format {
+        TableFormat::Plain => {
+            lines.push(pad(&header).join("  "));
+            lines.push(
+                widths
+                    .iter()
+                    .map(|&width| "-".repeat(width))
+                    .collect::<Vec<_>>()
+                    .join("  "),
+            );
+            lines.extend(rows.iter().map(|row| pad(row).join("  ")));
+        }
+        TableFormat::Markdown => {
+            lines.push(format!("| {} |", pad(&header).join(" | ")));
+            let rule: Vec<String> = widths
+                .iter()
+                .enumerate()
+                .map(|(column, &width)| {
+                    let dashes = "-".repeat(width.max(3) - 1);
+                    if column == 0 {
+                        format!(":{dashes}")
+                    } else {
+                        format!("{dashes}:")
+                    }
+                })
+                .collect();
+            lines.push(format!("| {} |", rule.join(" | ")));
+            lines.extend(
+                rows.iter()
+                    .map(|row| format!("| {} |", pad(row).join(" | "))),
+            );
+        }
+        TableFormat::Latex => {
+            lines.push(format!(
+                "\\begin{{tabular}}{{l{}}}",
+                "r".repeat(widths.len().saturating_sub(1))
+            ));
+            lines.push("\\hline".to_string());
+            lines.push(format!("{} \\\\", pad(&header).join(" & ")));
+            lines.push("\\hline".to_string());
+            lines.extend(
+                rows.iter()
+                    .map(|row| format!("{} \\\\", pad(row).join(" & "))),
+            );
+            lines.push("\\hline".to_string());
+            lines.push("\\end{tabular}".to_string());
+        }
+    }
+    let mut output = lines.join("\n");
+    output.push('\n');
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::DMatrix;
+
+    use super::*;
+    use crate::data::ProductDataBuilder;
+    use crate::estimation::Problem;
+    use crate::integration::SimulationDraws;
+    use crate::parameters::NonlinearParameters;
+
+    #[test]
+    fn summary_renders_labelled_aligned_tables() {
+        assert_eq!(format_significant(1234.5678, 4), "1235");
+        assert_eq!(format_significant(1234.5678, 3), "1.23e3");
+        assert_eq!(format_significant(-0.012345, 3), "-0.0123");
+        assert_eq!(format_significant(1.5e-7, 2), "1.5e-7");
+
+        let market_ids = ["m1", "m1", "m2"].map(String::from).to_vec();
+        let x1 = DMatrix::from_row_slice(3, 2, &[1.0, 1.0, 1.0, 2.0, 1.0, 1.5]);
+        let data = ProductDataBuilder::new(market_ids, DVector::from_vec(vec![0.2, 0.3, 0.4]))
+            .x1(x1)
+            .x1_labels(vec!["const".into(), "unit_price".into()])
+            .x2_from_x1(vec![1])
+            .build()
+            .unwrap();
+        let problem = Problem::new(data, SimulationDraws::standard_normal(10, 1, 1)).unwrap();
+        let results = problem
+            .solve(&NonlinearParameters::new(DMatrix::from_element(1, 1, 0.5)))
+            .unwrap();
+
+        let options = SummaryOptions::labelled(problem.data())
+            .with_standard_errors(DVector::from_vec(vec![0.1, 0.2, 0.05]));
+        let plain = results.summary(&options);
+        let lines: Vec<&str> = plain.lines().collect();
+        assert!(lines[0].starts_with("Parameter"));
+        assert!(lines[0].ends_with("Std. Error"));
+        assert!(lines[4].starts_with("sigma[unit_price]"));
+        assert!(lines[4].ends_with("0.05000"));
+        assert_eq!(lines[2].len(), lines[3].len());
+        assert!(plain.contains("GMM objective"));
+
+        let markdown = results.summary(&options.clone().with_format(TableFormat::Markdown));
+        assert!(markdown.starts_with("| Parameter"));
+        let latex = results.summary(&options.with_format(TableFormat::Latex));
+        assert!(latex.contains("unit\\_price"));
+        assert!(latex.starts_with("\\begin{tabular}{lrr}"));
+        assert!(results.to_string().contains("sigma[x2_0]"));
+    }
+
+    #[test]
+    fn latex_table_orders_rows_and_marks_significance() {
+        let market_ids = ["m1", "m1", "m2"].map(String::from).to_vec();
+        let x1 = DMatrix::from_row_slice(3, 2, &[1.0, 1.0, 1.0, 2.0, 1.0, 1.5]);
+        let data = ProductDataBuilder::new(market_ids, DVector::from_vec(vec![0.2, 0.3, 0.4]))
+            .x1(x1)
+            .x1_labels(vec!["const".into(), "price".into()])
+            .x2_from_x1(vec![1])
+            .build()
+            .unwrap();
+        let problem = Problem::new(data, SimulationDraws::standard_normal(10, 1, 1)).unwrap();
+        let results = problem
+            .solve(&NonlinearParameters::new(DMatrix::from_element(1, 1, 0.5)))
+            .unwrap();
+        let errors = DVector::from_vec(vec![
+            results.beta[0].abs() * 10.0,
+            results.beta[1].abs() / 10.0,
+            0.5 / 2.2,
+        ]);
+        let summary = SummaryOptions::labelled(problem.data()).with_standard_errors(errors);
+        let options = LatexTableOptions::new(summary)
+            .with_row_order(vec!["sigma[price]".into(), "price".into()]);
+
+        let latex = results.to_latex(&options).unwrap();
+        let lines: Vec<&str> = latex.lines().collect();
+        assert_eq!(lines[0], "\\begin{tabular}{lc}");
+        assert!(lines[4].starts_with("sigma[price] & $0.5000^{**}$"));
+        assert!(lines[5].starts_with(" & $(0.2273)$"));
+        assert!(lines[6].contains("^{***}$"));
+        assert!(!latex.contains("const"));
+        assert!(latex.contains("$^{***}p<0.01$"));
+
+        let unknown = options.with_row_order(vec!["income".into()]);
+        assert!(matches!(
+            results.to_latex(&unknown),
+            Err(BlpError::UnknownParameter { name }) if name == "income"
+        ));
+    }
+}
@@ -0,0 +1,299 @@
+//! Sensitivity of estimates to noise in the observed shares or instruments.
+//!
+//! [`Problem::noise_robustness`] perturbs the data a number of times, re-estimates
+//! `sigma` (and `beta`) on every perturbed copy from the baseline estimate, and reports
+//! how far the parameters move. Shares are scaled by independent lognormal factors
+//! `exp(scale * e)`, which keeps them positive; instruments receive additive normal
+//! noise with standard deviation `scale` times the column's own standard deviation, so
+//! constant columns are left untouched. Replications run in parallel within the
+//! problem's [`ParallelismOptions`](crate::ParallelismOptions).
+
+use nalgebra::{DMatrix, DVector};
+use rand::SeedableRng;
+use rand::rngs::SmallRng;
+use rand_distr::{Distribution, StandardNormal};
+use serde::{Deserialize, Serialize};
+
+use crate::data::ProductData;
+use crate::error::{BlpError, Result};
+use crate::estimation::{Problem, ProblemResults};
+use crate::parallel;
+
+/// Which observed inputs are perturbed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PerturbationTarget {
+    /// Multiplicative lognormal noise on the market shares.
+    #[default]
+    Shares,
+    /// Additive normal noise on every non-constant instrument column.
+    Instruments,
+}
+
+/// Settings for [`Problem::noise_robustness`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NoiseOptions {
+    /// Inputs that are perturbed.
+    pub target: PerturbationTarget,
+    /// Standard deviation of the noise: of the log factor for shares, relative to each
+    /// column's standard deviation for instruments.
+    pub scale: f64,
+    /// Number of perturbed re-estimations.
+    pub replications: usize,
+    /// Seed of replication 0; replication `r` uses `seed + r`, so results do not depend
+    /// on scheduling.
+    pub seed: u64,
+}
+
+impl Default for NoiseOptions {
+    fn default() -> Self {
+        Self {
+            target: PerturbationTarget::default(),
+            scale: 0.05,
+            replications: 20,
+            seed: 0,
+        }
+    }
+}
+
+impl NoiseOptions {
+    /// `replications` perturbations of `target` with noise of size `scale`.
+    pub fn new(target: PerturbationTarget, scale: f64, replications: usize) -> Self {
+        Self {
+            target,
+            scale,
+            replications,
+            ..Self::default()
+        }
+    }
+
+    /// Seed the noise with `seed`.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+
+/// Estimate on one perturbed copy of the data.
+#[derive(Clone, Debug)]
+pub struct PerturbedEstimate {
+    /// Replication number, which also determines its noise seed.
+    pub replication: usize,
+    /// Estimated `sigma`.
+    pub sigma: DMatrix<f64>,
+    /// Estimated `beta`.
+    pub beta: DVector<f64>,
+    /// GMM objective at the estimate.
+    pub gmm_value: f64,
+    /// Whether the optimizer met its termination tolerances.
+    pub converged: bool,
+}
+
+impl PerturbedEstimate {
+    /// `theta = [beta; vec(sigma)]`.
+    pub fn theta(&self) -> DVector<f64> {
+        stack_theta(&self.beta, &self.sigma)
+    }
+}
+
+/// Outcome of [`Problem::noise_robustness`].
+#[derive(Clone, Debug)]
+pub struct NoiseRobustness {
+    /// Settings the exercise ran with.
+    pub options: NoiseOptions,
+    /// Baseline `theta = [beta; vec(sigma)]`.
+    pub baseline: DVector<f64>,
+    /// Successful re-estimations, in replication order.
+    pub estimates: Vec<PerturbedEstimate>,
+    /// Replications whose perturbed data was invalid (e.g. shares summing past one) or
+    /// whose estimation failed.
+    pub failures: Vec<usize>,
+}
+
+impl NoiseRobustness {
+    /// Mean of `theta` across successful replications.
+    pub fn mean(&self) -> DVector<f64> {
+        let mut sum = DVector::zeros(self.baseline.len());
+        for estimate in &self.estimates {
+            sum += estimate.theta();
+        }
+        sum / self.estimates.len().max(1) as f64
+    }
+
+    /// Standard deviation of each element of `theta` across successful replications.
+    pub fn std_dev(&self) -> DVector<f64> {
+        let mean = self.mean();
+        let mut squares = DVector::zeros(self.baseline.len());
+        for estimate in &self.estimates {
+            squares += (estimate.theta() - &mean).map(|deviation| deviation * deviation);
+        }
+        let denominator = self.estimates.len().saturating_sub(1).max(1) as f64;
+        squares.map(|square| (square / denominator).sqrt())
+    }
+
+    /// Largest absolute distance of each element of `theta` from its baseline value.
+    pub fn max_deviation(&self) -> DVector<f64> {
+        let mut largest = DVector::zeros(self.baseline.len());
+        for estimate in &self.estimates {
+            let deviation = (estimate.theta() - &self.baseline).abs();
+            largest = largest.sup(&deviation);
+        }
+        largest
+    }
+}
+
+impl Problem {
+    /// Re-estimate the model on `noise.replications` perturbed copies of the data,
+    /// starting each search from `baseline.sigma` (and `baseline.rho`) with the problem's
+    /// [`OptimizationOptions`](crate::OptimizationOptions) otherwise, and collect the
+    /// estimates.
+    pub fn noise_robustness(
+        &self,
+        baseline: &ProblemResults,
+        noise: &NoiseOptions,
+    ) -> Result<NoiseRobustness> {
+        if noise.replications == 0 {
+            return Err(BlpError::dimension_mismatch("noise replications", 1, 0));
+        }
+        if !noise.scale.is_finite() || noise.scale < 0.0 {
+            return Err(BlpError::NumericalError {
+                context: "noise scale",
+            });
+        }
+        let mut options = self.options().clone();
+        options.optimization.initial_sigma = Some(baseline.sigma.clone());
+        if baseline.rho.is_some() {
+            options.optimization.initial_rho = baseline.rho;
+        }
+
+        let outcomes = parallel::run_nested(
+            &self.options().parallelism,
+            noise.replications,
+            |replication| {
+                let mut rng = SmallRng::seed_from_u64(noise.seed.wrapping_add(replication as u64));
+                let perturbed = self.perturb(noise, &mut rng).and_then(|data| {
+                    self.with_data(data)?
+                        .with_options_override(options.clone())
+                        .optimize()
+                });
+                Ok(match perturbed {
+                    Ok(optimized) => Some(PerturbedEstimate {
+                        replication,
+                        sigma: optimized.results.sigma,
+                        beta: optimized.results.beta,
+                        gmm_value: optimized.results.gmm_value,
+                        converged: optimized.converged,
+                    }),
+                    Err(err) => {
+                        log::debug!("noise replication {replication} failed: {err}");
+                        None
+                    }
+                })
+            },
+        )?;
+
+        let failures = outcomes
+            .iter()
+            .enumerate()
+            .filter(|(_, outcome)| outcome.is_none())
+            .map(|(replication, _)| replication)
+            .collect();
+        Ok(NoiseRobustness {
+            options: noise.clone(),
+            baseline: stack_theta(&baseline.beta, &baseline.sigma),
+            estimates: outcomes.into_iter().flatten().collect(),
+            failures,
+        })
+    }
+
+    /// A copy of the product data with `noise` applied to its target.
+    fn perturb(&self, noise: &NoiseOptions, rng: &mut SmallRng) -> Result<ProductData> {
+        let data = self.data();
+        let mut normal = || -> f64 { StandardNormal.sample(&mut *rng) };
+        let builder = data.to_builder();
+        match noise.target {
+            PerturbationTarget::Shares => {
+                let shares = data
+                    .shares()
+                    .map(|share| share * (noise.scale * normal()).exp());
+                builder.shares(shares).build()
+            }
+            PerturbationTarget::Instruments => {
+                let mut instruments = data.instruments().clone();
+                let n = instruments.nrows() as f64;
+                for mut column in instruments.column_iter_mut() {
+                    let mean = column.sum() / n;
+                    let spread = (column.map(|value| (value - mean).powi(2)).sum() / n).sqrt();
+                    if spread > 0.0 {
+                        for value in column.iter_mut() {
+                            *value += noise.scale * spread * normal();
+                        }
+                    }
+                }
+                builder.instruments(instruments).build()
+            }
+        }
+    }
+}
+
+fn stack_theta(beta: &DVector<f64>, sigma: &DMatrix<f64>) -> DVector<f64> {
+    DVector::from_iterator(
+        beta.len() + sigma.len(),
+        beta.iter().chain(sigma.iter()).copied(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OptimizationOptions;
+    use crate::data::ProductDataBuilder;
+    use crate::integration::SimulationDraws;
+    use crate::options::ProblemOptions;
+
+    #[test]
+    fn perturbed_estimates_move_with_the_noise() {
+        let n = 12;
+        let market_ids: Vec<String> = (0..n).map(|j| format!("m{}", j / 3)).collect();
+        let x = DVector::from_fn(n, |j, _| ((j * 7) % 5) as f64 / 2.5);
+        let shares = DVector::from_fn(n, |j, _| 0.05 + 0.04 * ((j * 3) % 4) as f64);
+        let x1 = DMatrix::from_fn(n, 2, |j, k| [1.0, x[j]][k]);
+        let z = DMatrix::from_fn(n, 3, |j, k| [1.0, x[j], x[j] * x[j]][k]);
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1.clone())
+            .x2(x1.columns(1, 1).into_owned())
+            .instruments(z)
+            .build()
+            .unwrap();
+        let options = ProblemOptions::default().with_optimization(
+            OptimizationOptions::new(DMatrix::from_element(1, 1, 0.5)).with_tolerances(1e-6, 1e-3),
+        );
+        let problem =
+            Problem::with_options(data, SimulationDraws::standard_normal(20, 1, 4), options)
+                .unwrap();
+        let baseline = problem.optimize().unwrap().results;
+
+        let quiet = problem
+            .noise_robustness(
+                &baseline,
+                &NoiseOptions::new(PerturbationTarget::Shares, 0.0, 2),
+            )
+            .unwrap();
+        assert!(quiet.failures.is_empty());
+        assert_eq!(quiet.std_dev().amax(), 0.0);
+
+        let noise = NoiseOptions::new(PerturbationTarget::Instruments, 0.2, 3).with_seed(9);
+        let noisy = problem.noise_robustness(&baseline, &noise).unwrap();
+        assert_eq!(noisy.estimates.len() + noisy.failures.len(), 3);
+        assert!(noisy.std_dev().amax() > 0.0);
+        let again = problem.noise_robustness(&baseline, &noise).unwrap();
+        assert_eq!(again.mean(), noisy.mean());
+        assert!(
+            problem
+                .noise_robustness(
+                    &baseline,
+                    &NoiseOptions::new(PerturbationTarget::Shares, -1.0, 2)
+                )
+                .is_err()
+        );
+    }
+}
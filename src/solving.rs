@@ -1,7 +1,15 @@
 //! Contraction solver configuration and diagnostics.
 
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use nalgebra::DVector;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Result;
+
 /// Configuration for the BLP fixed-point contraction that recovers mean utilities.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ContractionOptions {
     /// Supremum norm tolerance for convergence.
     pub tolerance: f64,
@@ -11,6 +19,84 @@ pub struct ContractionOptions {
     pub damping: f64,
     /// Lower bound enforced on predicted shares to avoid taking `ln(0)`.
     pub minimum_share: f64,
+    /// Effective sample size of the integration weights below which a warning is logged
+    /// (skipped for enumerated consumer types, which carry no simulation error).
+    #[serde(default = "default_minimum_effective_draws")]
+    pub minimum_effective_draws: f64,
+    /// CPU-seconds one inversion may spend before unconverged markets are accepted as-is.
+    ///
+    /// The budget is split across markets in proportion to their products and each
+    /// market's contraction is timed on its own; time left by markets that converge early
+    /// goes to those that ran out. Markets still moving after that are listed in
+    /// [`ContractionSummary::truncated_markets`] instead of failing the evaluation. Only
+    /// the built-in iteration honours the budget.
+    #[serde(default)]
+    pub cpu_budget: Option<f64>,
+    /// Markets with at least this many products accumulate their share Jacobian over
+    /// blocks of consumers in parallel, so a single huge market does not run on one thread.
+    #[serde(default = "default_large_market_products")]
+    pub large_market_products: usize,
+    /// How the tolerance is weighted across products and markets.
+    #[serde(default)]
+    pub tolerance_scaling: ToleranceScaling,
+    /// Also declare convergence once `max_j |s_j - s_j(delta)|` falls below this value,
+    /// pyBLP's default norm. The delta-update criterion stays active, and whichever is met
+    /// first is reported in [`ContractionSummary::criterion`]. Only the built-in iteration
+    /// checks it.
+    #[serde(default)]
+    pub share_tolerance: Option<f64>,
+    /// External solver that drives the contraction operator instead of plain iteration.
+    ///
+    /// Not serialized; archived options always deserialize with the built-in iteration.
+    #[serde(skip)]
+    pub solver: Option<Arc<dyn FixedPointSolver>>,
+}
+
+impl ContractionOptions {
+    /// Cap the CPU-seconds spent per contraction (see [`cpu_budget`](Self::cpu_budget)).
+    pub fn with_cpu_budget(mut self, seconds: f64) -> Self {
+        self.cpu_budget = Some(seconds);
+        self
+    }
+
+    /// Weight the convergence check by observed shares (see [`ToleranceScaling`]).
+    pub fn with_tolerance_scaling(mut self, scaling: ToleranceScaling) -> Self {
+        self.tolerance_scaling = scaling;
+        self
+    }
+
+    /// Stop once observed and predicted shares agree to within `tolerance`.
+    pub fn with_share_tolerance(mut self, tolerance: f64) -> Self {
+        self.share_tolerance = Some(tolerance);
+        self
+    }
+
+    /// Drive the contraction with a user-provided fixed-point solver.
+    pub fn with_solver<S: FixedPointSolver + 'static>(mut self, solver: S) -> Self {
+        self.solver = Some(Arc::new(solver));
+        self
+    }
+}
+
+/// Weighting of the contraction tolerance across products.
+///
+/// A change of `e` in `delta_j` moves the share of product `j` by roughly `s_j e`, so a
+/// uniform tolerance on `delta` is needlessly strict for tiny products and comparatively
+/// loose for large ones. Under the share-based variants the update of product `j` is
+/// multiplied by a weight in `(0, 1]` before it is compared with
+/// [`tolerance`](ContractionOptions::tolerance); the largest weight is one, so the
+/// heaviest product or market converges exactly as tightly as under `Uniform`. Only the
+/// built-in iteration honours the scaling.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ToleranceScaling {
+    /// Every update is held to the same tolerance.
+    #[default]
+    Uniform,
+    /// Weight each product by its observed share relative to the largest share.
+    ProductShare,
+    /// Weight every product in a market by the market's total inside share relative to
+    /// the largest market's.
+    MarketShare,
 }
 
 impl Default for ContractionOptions {
@@ -20,15 +106,96 @@ impl Default for ContractionOptions {
             max_iterations: 1_000,
             damping: 1.0,
             minimum_share: 1e-16,
+            minimum_effective_draws: default_minimum_effective_draws(),
+            cpu_budget: None,
+            large_market_products: default_large_market_products(),
+            tolerance_scaling: ToleranceScaling::default(),
+            share_tolerance: None,
+            solver: None,
         }
     }
 }
 
+fn default_minimum_effective_draws() -> f64 {
+    30.0
+}
+
+fn default_large_market_products() -> usize {
+    1_000
+}
+
 /// Diagnostics returned alongside the contracted mean utilities.
-#[derive(Clone, Debug)]
+///
+/// External [`FixedPointSolver`]s only need to fill in the iteration fields; the time,
+/// share error, and share evaluations are recorded around them.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct ContractionSummary {
     /// Number of iterations performed.
     pub iterations: usize,
     /// Maximum absolute change observed in the final iteration.
     pub max_gap: f64,
+    /// Markets that had not converged when the CPU budget ran out.
+    #[serde(default)]
+    pub truncated_markets: Vec<String>,
+    /// Criterion that ended the contraction; `None` when it was stopped early (CPU
+    /// budget or by hand) or the markets were solved under different criteria.
+    #[serde(default)]
+    pub criterion: Option<ConvergenceCriterion>,
+    /// Wall-clock time spent inverting the shares, in seconds.
+    #[serde(default)]
+    pub seconds: f64,
+    /// `max_j |s_j - s_j(delta)|` at the returned `delta`, which costs one share
+    /// prediction beyond the iterations; zero for closed forms.
+    #[serde(default, deserialize_with = "crate::archive::nan_if_null")]
+    pub max_share_error: f64,
+    /// Number of share predictions the inversion made, each a pass over every market
+    /// and draw.
+    #[serde(default)]
+    pub share_evaluations: usize,
+}
+
+/// Which test declared the contraction converged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConvergenceCriterion {
+    /// The (weighted) largest update to `delta` fell below the tolerance.
+    DeltaUpdate,
+    /// The largest gap between observed and predicted shares fell below
+    /// [`share_tolerance`](ContractionOptions::share_tolerance).
+    ShareResidual,
+    /// The model was inverted in closed form.
+    ClosedForm,
+}
+
+impl ContractionSummary {
+    /// Whether the contraction stopped early on some markets because of the CPU budget.
+    pub fn is_truncated(&self) -> bool {
+        !self.truncated_markets.is_empty()
+    }
+}
+
+/// A map `T` whose fixed point `x = T(x)` is sought.
+///
+/// The BLP contraction is exposed through this trait so that generic acceleration
+/// schemes (Anderson mixing, SQUAREM, ...) can drive it without knowing about shares.
+pub trait FixedPointOperator {
+    /// Length of the iterate.
+    fn dimension(&self) -> usize;
+
+    /// Evaluate `T(x)`.
+    fn apply(&mut self, x: &DVector<f64>) -> Result<DVector<f64>>;
+}
+
+/// Strategy for finding the fixed point of a [`FixedPointOperator`].
+///
+/// Implementations should honour `options.tolerance` (on the supremum norm of
+/// `T(x) - x`) and `options.max_iterations`, and report the number of operator
+/// evaluations in [`ContractionSummary::iterations`].
+pub trait FixedPointSolver: Debug + Send + Sync {
+    /// Iterate from `initial` until convergence.
+    fn solve(
+        &self,
+        operator: &mut dyn FixedPointOperator,
+        initial: DVector<f64>,
+        options: &ContractionOptions,
+    ) -> Result<(DVector<f64>, ContractionSummary)>;
 }
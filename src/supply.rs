@@ -0,0 +1,355 @@
+//! Supply side: Bertrand–Nash markups, marginal costs, and cost-side moments.
+//!
+//! Mirrors pyBLP's `X3` formulation. Firms set prices to maximize the profits of the
+//! products they own, so within market `t` the first-order conditions are
+//! `s + (O ⊙ (ds/dp)') (p - c) = 0`, where `O` is the ownership matrix. Solving for the
+//! markup `eta = p - c` recovers marginal costs from demand estimates, and the cost
+//! equation `c = X3 gamma + omega` (or `log c = X3 gamma + omega`) gives the cost-side
+//! structural error `omega`. Attaching a [`SupplySide`] with [`Problem::with_supply`]
+//! stacks `Z_S' omega` under the demand moments `Z_D' xi` in the GMM objective.
+
+use std::sync::Arc;
+
+use nalgebra::{DMatrix, DVector};
+use serde::{Deserialize, Serialize};
+
+use crate::data::ProductData;
+use crate::elasticities::Characteristic;
+use crate::error::{BlpError, Result};
+use crate::estimation::{Problem, ProblemResults, inverse_ztz};
+use crate::linalg::Factorizations;
+
+/// How marginal costs enter the cost equation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CostSpecification {
+    /// `c = X3 gamma + omega`.
+    #[default]
+    Linear,
+    /// `log c = X3 gamma + omega`, which requires every recovered cost to be positive.
+    Log,
+}
+
+/// Firm ownership and cost-side data for joint demand and supply estimation.
+#[derive(Clone, Debug)]
+pub struct SupplySide {
+    firm_ids: Vec<String>,
+    x3: DMatrix<f64>,
+    instruments: DMatrix<f64>,
+    price: Characteristic,
+    costs: CostSpecification,
+    ownership: Option<Vec<DMatrix<f64>>>,
+}
+
+impl SupplySide {
+    /// Cost shifters `x3` and supply-side instruments, one row per product, with the
+    /// firm that owns each product and the demand characteristic that is price.
+    ///
+    /// Ownership defaults to single-firm ownership built from `firm_ids`; see
+    /// [`ownership_matrices`].
+    pub fn new(
+        firm_ids: Vec<String>,
+        x3: DMatrix<f64>,
+        instruments: DMatrix<f64>,
+        price: Characteristic,
+    ) -> Self {
+        Self {
+            firm_ids,
+            x3,
+            instruments,
+            price,
+            costs: CostSpecification::default(),
+            ownership: None,
+        }
+    }
+
+    /// Model marginal costs in levels or in logs.
+    pub fn with_costs(mut self, costs: CostSpecification) -> Self {
+        self.costs = costs;
+        self
+    }
+
+    /// Replace the ownership implied by `firm_ids` with one `J_t x J_t` matrix per market,
+    /// e.g. for partial cross-ownership or a post-merger structure.
+    pub fn with_ownership(mut self, ownership: Vec<DMatrix<f64>>) -> Self {
+        self.ownership = Some(ownership);
+        self
+    }
+
+    /// Firm that owns each product.
+    pub fn firm_ids(&self) -> &[String] {
+        &self.firm_ids
+    }
+
+    /// Cost shifters (`X3`).
+    pub fn x3(&self) -> &DMatrix<f64> {
+        &self.x3
+    }
+
+    /// Supply-side instruments (`Z_S`).
+    pub fn instruments(&self) -> &DMatrix<f64> {
+        &self.instruments
+    }
+
+    /// The characteristic treated as price.
+    pub fn price(&self) -> Characteristic {
+        self.price
+    }
+
+    /// Levels or logs.
+    pub fn costs(&self) -> CostSpecification {
+        self.costs
+    }
+
+    /// Ownership matrices in partition order, built from `firm_ids` unless overridden.
+    pub fn ownership(&self, data: &ProductData) -> Vec<DMatrix<f64>> {
+        self.ownership
+            .clone()
+            .unwrap_or_else(|| ownership_matrices(data, &self.firm_ids))
+    }
+
+    /// Check every input against the product data it will be paired with.
+    fn validate(&self, data: &ProductData) -> Result<()> {
+        let n = data.product_count();
+        if self.firm_ids.len() != n {
+            return Err(BlpError::dimension_mismatch(
+                "firm ids",
+                n,
+                self.firm_ids.len(),
+            ));
+        }
+        if self.x3.nrows() != n {
+            return Err(BlpError::dimension_mismatch("X3 rows", n, self.x3.nrows()));
+        }
+        if self.instruments.nrows() != n {
+            return Err(BlpError::dimension_mismatch(
+                "supply instrument rows",
+                n,
+                self.instruments.nrows(),
+            ));
+        }
+        if self.instruments.ncols() < self.x3.ncols() {
+            return Err(BlpError::Underidentified {
+                instruments: self.instruments.ncols(),
+                parameters: self.x3.ncols(),
+            });
+        }
+        let Characteristic { x1, x2 } = self.price;
+        let x1_valid = x1.is_none_or(|column| column < data.linear_dim());
+        let x2_valid = x2.is_none_or(|column| column < data.nonlinear_dim());
+        if (x1.is_none() && x2.is_none()) || !x1_valid || !x2_valid {
+            return Err(BlpError::missing_component("price characteristic"));
+        }
+        if let Some(ownership) = &self.ownership {
+            let markets = data.partition().market_count();
+            if ownership.len() != markets {
+                return Err(BlpError::dimension_mismatch(
+                    "ownership matrices",
+                    markets,
+                    ownership.len(),
+                ));
+            }
+            for (market, matrix) in data.partition().markets().zip(ownership) {
+                let size = market.product_count();
+                if matrix.shape() != (size, size) {
+                    return Err(BlpError::dimension_mismatch(
+                        "ownership matrix size",
+                        size,
+                        matrix.nrows(),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Single-firm ownership: entry `(j, k)` of market `t`'s matrix is one when products `j`
+/// and `k` belong to the same firm and zero otherwise.
+pub fn ownership_matrices(data: &ProductData, firm_ids: &[String]) -> Vec<DMatrix<f64>> {
+    data.partition()
+        .markets()
+        .map(|market| {
+            let range = market.range();
+            DMatrix::from_fn(range.len(), range.len(), |j, k| {
+                if firm_ids[range.start + j] == firm_ids[range.start + k] {
+                    1.0
+                } else {
+                    0.0
+                }
+            })
+        })
+        .collect()
+}
+
+/// Cost-side output evaluated alongside the demand side.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SupplyResults {
+    /// Cost parameters on `X3`.
+    pub gamma: DVector<f64>,
+    /// Bertrand–Nash markups `p - c`.
+    pub markups: DVector<f64>,
+    /// Marginal costs `p - markups`.
+    pub marginal_costs: DVector<f64>,
+    /// Cost-side structural error.
+    pub omega: DVector<f64>,
+    /// Supply contribution `(Z_S' omega)' (Z_S' Z_S)^{-1} (Z_S' omega)` to the GMM
+    /// objective.
+    pub gmm_value: f64,
+}
+
+impl Problem {
+    /// Attach a supply side so that every solve also recovers markups and marginal costs
+    /// and adds the cost-side moments to the GMM objective.
+    ///
+    /// `beta` is still concentrated out on the demand moments alone; the supply moments
+    /// then identify `gamma` given the demand parameters. Problems derived from this one
+    /// on different products (e.g. [`Problem::with_modified_data`] dropping markets) do
+    /// not carry the supply side over.
+    pub fn with_supply(mut self, supply: SupplySide) -> Result<Self> {
+        supply.validate(self.data())?;
+        self.set_supply(Some(Arc::new(supply)));
+        Ok(self)
+    }
+
+    /// Bertrand–Nash markups `-(O ⊙ (ds/dp)')^{-1} s` at the demand estimates in
+    /// `results`, using the observed shares.
+    pub fn markups(&self, results: &ProblemResults, supply: &SupplySide) -> Result<DVector<f64>> {
+        supply.validate(self.data())?;
+        let data = self.data();
+        let derivatives = self.share_derivatives(results, supply.price())?;
+        let ownership = supply.ownership(data);
+        let mut markups = DVector::zeros(data.product_count());
+        for ((market, derivatives), ownership) in
+            data.partition().markets().zip(&derivatives).zip(&ownership)
+        {
+            let range = market.range();
+            let weighted = DMatrix::from_fn(range.len(), range.len(), |j, k| {
+                ownership[(j, k)] * derivatives[(k, j)]
+            });
+            let shares = data.shares().rows(range.start, range.len()).into_owned();
+            let solved = weighted
+                .lu()
+                .solve(&shares)
+                .ok_or_else(|| BlpError::singular("ownership-weighted share derivatives"))?;
+            markups
+                .rows_mut(range.start, range.len())
+                .copy_from(&-solved);
+        }
+        Ok(markups)
+    }
+
+    /// Markups, marginal costs, `gamma`, and `omega` at the demand estimates in `results`.
+    pub fn supply_results(
+        &self,
+        results: &ProblemResults,
+        supply: &SupplySide,
+    ) -> Result<SupplyResults> {
+        let markups = self.markups(results, supply)?;
+        let marginal_costs = supply.price().values(self.data()) - &markups;
+        let costs = match supply.costs() {
+            CostSpecification::Linear => marginal_costs.clone(),
+            CostSpecification::Log => {
+                if let Some(index) = marginal_costs.iter().position(|cost| *cost <= 0.0) {
+                    return Err(BlpError::NonPositiveCost {
+                        index,
+                        cost: marginal_costs[index],
+                    });
+                }
+                marginal_costs.map(f64::ln)
+            }
+        };
+
+        let z = supply.instruments();
+        let mut factorizations = Factorizations::new(self.options().gmm.strict_factorizations);
+        let weighting = inverse_ztz(z, &mut factorizations)?;
+        let zx = z.transpose() * supply.x3();
+        let xzwzx = zx.transpose() * &weighting * &zx;
+        let rhs = zx.transpose() * (&weighting * (z.transpose() * &costs));
+        let gamma = factorizations.solve(&xzwzx, &rhs, "X3'Z_S W Z_S'X3")?;
+        let omega = costs - supply.x3() * &gamma;
+        let moments = z.transpose() * &omega;
+        let gmm_value = moments.dot(&(&weighting * &moments));
+        Ok(SupplyResults {
+            gamma,
+            markups,
+            marginal_costs,
+            omega,
+            gmm_value,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+    use crate::data::ProductDataBuilder;
+    use crate::integration::SimulationDraws;
+    use crate::parameters::NonlinearParameters;
+
+    #[test]
+    fn logit_markups_solve_the_first_order_conditions() {
+        let market_ids = ["m1", "m1", "m1", "m2", "m2", "m2"]
+            .map(String::from)
+            .to_vec();
+        let shares = DVector::from_vec(vec![0.2, 0.15, 0.25, 0.1, 0.3, 0.2]);
+        let prices = [2.0, 2.5, 1.5, 3.0, 2.0, 2.2];
+        let x1 = DMatrix::from_fn(6, 2, |row, col| if col == 0 { 1.0 } else { prices[row] });
+        let cost_shifter = [0.5, 1.0, 0.2, 0.8, 0.4, 0.6];
+        let z = DMatrix::from_fn(6, 3, |row, col| match col {
+            0 => 1.0,
+            1 => cost_shifter[row],
+            _ => (row as f64 * 0.7).sin(),
+        });
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .instruments(z.clone())
+            .x1_labels(vec!["const".into(), "price".into()])
+            .build()
+            .unwrap();
+        let problem = Problem::new(data, SimulationDraws::standard_normal(1, 0, 1)).unwrap();
+        let demand = problem.solve(&NonlinearParameters::default()).unwrap();
+        let alpha = demand.beta[1];
+        assert!(alpha < 0.0);
+
+        let firms = ["a", "a", "b", "a", "b", "c"].map(String::from).to_vec();
+        let x3 = z.columns(0, 2).into_owned();
+        let supply = SupplySide::new(firms, x3, z, Characteristic::linear(1));
+        let markups = problem.markups(&demand, &supply).unwrap();
+        // Single-product firms price at -1 / (alpha (1 - s)).
+        let s = problem.data().shares();
+        assert_relative_eq!(markups[2], -1.0 / (alpha * (1.0 - s[2])), epsilon = 1e-10);
+        // Multi-product logit firms charge the same markup on every product.
+        let common = -1.0 / (alpha * (1.0 - s[0] - s[1]));
+        assert_relative_eq!(markups[0], common, epsilon = 1e-10);
+        assert_relative_eq!(markups[1], common, epsilon = 1e-10);
+
+        let joint = problem.clone().with_supply(supply.clone()).unwrap();
+        let results = joint.solve(&NonlinearParameters::default()).unwrap();
+        let cost_side = results.supply.as_ref().unwrap();
+        assert_relative_eq!(cost_side.markups, markups, epsilon = 1e-12);
+        assert_relative_eq!(
+            results.gmm_value,
+            demand.gmm_value + cost_side.gmm_value,
+            epsilon = 1e-12
+        );
+        assert_eq!(cost_side.gamma.len(), 2);
+        // The sandwich covers only the demand moments, so it is refused.
+        assert!(matches!(
+            joint.parameter_covariance(&results),
+            Err(BlpError::Unsupported { .. })
+        ));
+        let estimated = joint.optimize().unwrap().results;
+        assert!(estimated.covariance.is_none());
+        assert!(estimated.beta_se().is_none());
+
+        let wrong = SupplySide::new(
+            vec!["a".into()],
+            DMatrix::zeros(1, 1),
+            DMatrix::zeros(1, 1),
+            Characteristic::linear(1),
+        );
+        assert!(problem.clone().with_supply(wrong).is_err());
+    }
+}
@@ -0,0 +1,145 @@
+//! Simulated markets shared by the unit tests.
+//!
+//! [`TestMarkets`] lays out deterministic characteristics and builds product data on
+//! them, either with given shares or with the shares a model predicts, so a test only
+//! states the design and parameters it varies.
+
+use nalgebra::{DMatrix, DVector};
+
+use crate::data::{ProductData, ProductDataBuilder};
+use crate::demand::{ShareInputs, predict_shares_with};
+use crate::error::Result;
+use crate::integration::SimulationDraws;
+use crate::parameters::NonlinearParameters;
+
+/// `n` products in markets of equal size, with characteristics `x` and `w` spread over
+/// `[-1, 1]` by fixed integer hashes of the row.
+///
+/// By default markets have three products, `X1 = [1, x, w]`, `X2 = [x, w]`, and the
+/// instruments are `[1, x, w, x^2, w x]`.
+#[derive(Clone, Debug)]
+pub(crate) struct TestMarkets {
+    pub(crate) x: DVector<f64>,
+    pub(crate) w: DVector<f64>,
+    market_size: usize,
+    x1: DMatrix<f64>,
+    x2_columns: Vec<usize>,
+    instruments: DMatrix<f64>,
+    x1_labels: Option<Vec<String>>,
+    nesting_ids: Option<Vec<String>>,
+}
+
+impl TestMarkets {
+    pub(crate) fn new(n: usize) -> Self {
+        let x = DVector::from_fn(n, |j, _| ((j * 7919) % 97) as f64 / 48.5 - 1.0);
+        let w = DVector::from_fn(n, |j, _| ((j * 31) % 11) as f64 / 5.5 - 1.0);
+        let x1 = DMatrix::from_fn(n, 3, |j, k| [1.0, x[j], w[j]][k]);
+        let instruments =
+            DMatrix::from_fn(n, 5, |j, k| [1.0, x[j], w[j], x[j] * x[j], w[j] * x[j]][k]);
+        Self {
+            x,
+            w,
+            market_size: 3,
+            x1,
+            x2_columns: vec![1, 2],
+            instruments,
+            x1_labels: None,
+            nesting_ids: None,
+        }
+    }
+
+    /// Markets of `products` products each.
+    pub(crate) fn with_market_size(mut self, products: usize) -> Self {
+        self.market_size = products;
+        self
+    }
+
+    /// Use `x1` as `X1`, with its columns `x2_columns` as `X2`.
+    pub(crate) fn with_x1(mut self, x1: DMatrix<f64>, x2_columns: Vec<usize>) -> Self {
+        self.x1 = x1;
+        self.x2_columns = x2_columns;
+        self
+    }
+
+    /// Use `instruments` as `Z`.
+    pub(crate) fn with_instruments(mut self, instruments: DMatrix<f64>) -> Self {
+        self.instruments = instruments;
+        self
+    }
+
+    /// Label the columns of `X1`; `X2` takes the labels of its columns.
+    pub(crate) fn with_x1_labels(mut self, labels: &[&str]) -> Self {
+        self.x1_labels = Some(labels.iter().map(|&label| label.into()).collect());
+        self
+    }
+
+    /// Group the products into nests.
+    pub(crate) fn with_nesting_ids(mut self, ids: Vec<String>) -> Self {
+        self.nesting_ids = Some(ids);
+        self
+    }
+
+    /// Number of products.
+    pub(crate) fn len(&self) -> usize {
+        self.x.len()
+    }
+
+    /// Structural errors of size `scale`, spread over `[-scale, scale]`.
+    pub(crate) fn xi(&self, scale: f64) -> DVector<f64> {
+        DVector::from_fn(self.len(), |j, _| {
+            scale * (((j * 104_729) % 61) as f64 / 30.0 - 1.0)
+        })
+    }
+
+    pub(crate) fn x1(&self) -> &DMatrix<f64> {
+        &self.x1
+    }
+
+    pub(crate) fn instruments(&self) -> &DMatrix<f64> {
+        &self.instruments
+    }
+
+    /// Product data with `shares`.
+    pub(crate) fn data(&self, shares: DVector<f64>) -> ProductData {
+        let market_ids = (0..self.len())
+            .map(|j| format!("m{}", j / self.market_size))
+            .collect();
+        let mut builder = ProductDataBuilder::new(market_ids, shares)
+            .x1(self.x1.clone())
+            .x2_from_x1(self.x2_columns.clone())
+            .instruments(self.instruments.clone());
+        if let Some(labels) = &self.x1_labels {
+            builder = builder.x1_labels(labels.clone());
+        }
+        if let Some(ids) = &self.nesting_ids {
+            builder = builder.nesting_ids(ids.clone());
+        }
+        builder.build().unwrap()
+    }
+
+    /// Product data with the shares `shares` computes from share inputs at `parameters`.
+    pub(crate) fn simulate_with(
+        &self,
+        draws: &SimulationDraws,
+        parameters: &NonlinearParameters,
+        shares: impl FnOnce(&ShareInputs) -> Result<DVector<f64>>,
+    ) -> ProductData {
+        let placeholder = self.data(DVector::from_element(self.len(), 0.1));
+        let contraction = Default::default();
+        let inputs = ShareInputs::for_parameters(&placeholder, draws, parameters, &contraction);
+        self.data(shares(&inputs).unwrap())
+    }
+
+    /// Product data with the random coefficients logit shares at mean utilities `delta`
+    /// and `parameters`.
+    pub(crate) fn simulate(
+        &self,
+        draws: &SimulationDraws,
+        parameters: &NonlinearParameters,
+        delta: &DVector<f64>,
+    ) -> ProductData {
+        self.simulate_with(draws, parameters, |inputs| {
+            predict_shares_with(delta, inputs)
+        })
+    }
+}
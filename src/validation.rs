@@ -0,0 +1,280 @@
+//! Out-of-sample validation over markets for choosing between specifications, and
+//! sample splitting for the efficient-GMM step.
+
+use nalgebra::{DMatrix, DVector};
+
+use crate::demand::ShareInputs;
+use crate::error::{BlpError, Result};
+use crate::estimation::{Problem, ProblemResults, efficient_weighting};
+use crate::linalg::Factorizations;
+use crate::options::WeightingMatrix;
+use crate::parallel;
+use crate::parameters::NonlinearParameters;
+
+/// Out-of-sample fit on one held-out fold of markets.
+#[derive(Clone, Debug)]
+pub struct FoldScore {
+    /// Identifiers of the held-out markets.
+    pub held_out: Vec<String>,
+    /// Root mean squared error of predicted shares.
+    pub share_rmse: f64,
+    /// Root mean squared error of predicted log shares.
+    pub log_share_rmse: f64,
+}
+
+/// Result of k-fold cross-validation over markets.
+#[derive(Clone, Debug)]
+pub struct CrossValidation {
+    /// Score of each fold, in fold order.
+    pub folds: Vec<FoldScore>,
+}
+
+impl CrossValidation {
+    /// Share RMSE averaged over folds.
+    pub fn mean_share_rmse(&self) -> f64 {
+        self.folds.iter().map(|fold| fold.share_rmse).sum::<f64>() / self.folds.len() as f64
+    }
+
+    /// Log-share RMSE averaged over folds.
+    pub fn mean_log_share_rmse(&self) -> f64 {
+        self.folds
+            .iter()
+            .map(|fold| fold.log_share_rmse)
+            .sum::<f64>()
+            / self.folds.len() as f64
+    }
+}
+
+/// Estimates from two-fold cross-fitting of the optimal instruments and the efficient
+/// weighting matrix.
+#[derive(Clone, Debug)]
+pub struct CrossFit {
+    /// Second-step estimates on each half, using the instruments and weighting matrix
+    /// from the other half.
+    pub halves: Vec<ProblemResults>,
+    /// Average of the two halves' `beta`.
+    pub beta: DVector<f64>,
+    /// Average of the two halves' nonlinear parameters.
+    pub parameters: NonlinearParameters,
+}
+
+impl Problem {
+    /// Efficient GMM with the optimal instruments and weighting matrix estimated out of
+    /// sample.
+    ///
+    /// Markets are split into two halves (even and odd positions in partition order). On
+    /// each half a first step minimizes the objective under `(Z'Z)^{-1}` from the starting
+    /// values `parameters`, within the problem's
+    /// [`OptimizationOptions`](crate::OptimizationOptions) bounds and mask. Its estimates
+    /// give the [optimal instruments](ProblemResults::compute_optimal_instruments) of
+    /// *both* halves and, from its `xi`, the robust weighting matrix
+    /// `(Z*' diag(xi^2) Z*)^{-1}` in those instruments. The *other* half is then
+    /// re-estimated, nonlinear parameters included, with these instruments and this
+    /// weighting matrix, and the two halves' estimates are averaged. Keeping both
+    /// independent of the estimation sample reduces the small-sample bias of efficient
+    /// GMM.
+    pub fn cross_fit(&self, parameters: &NonlinearParameters) -> Result<CrossFit> {
+        let market_count = self.data().partition().market_count();
+        if market_count < 2 {
+            return Err(BlpError::dimension_mismatch(
+                "cross-fitting markets",
+                2,
+                market_count,
+            ));
+        }
+        let (even, odd): (Vec<usize>, Vec<usize>) =
+            (0..market_count).partition(|market| market % 2 == 0);
+        let splits = [(even.clone(), odd.clone()), (odd, even)];
+        let mut options = self.options().clone();
+        options.gmm.weighting = WeightingMatrix::InverseZTZ;
+        options.gmm.update_weighting = false;
+        options.optimization.initial_sigma = Some(parameters.sigma().clone());
+        options.optimization.initial_pi = parameters.pi().cloned();
+        options.optimization.initial_rho = parameters.rho();
+
+        let halves = parallel::run_nested(&self.options().parallelism, 2, |half| {
+            let (auxiliary, estimation) = &splits[half];
+            let auxiliary = self
+                .with_data(self.data().select_markets(auxiliary)?)?
+                .with_options_override(options.clone());
+            let first_step = auxiliary.optimize()?.results;
+            let instruments = first_step
+                .compute_optimal_instruments(&auxiliary)?
+                .to_problem(&auxiliary)?;
+            let weighting = efficient_weighting(
+                instruments.data(),
+                &first_step.xi,
+                options.gmm.moment_covariance,
+                &mut Factorizations::new(options.gmm.strict_factorizations),
+            )?;
+
+            let estimation = self.with_data(self.data().select_markets(estimation)?)?;
+            let mut second = options.clone();
+            second.gmm.weighting = WeightingMatrix::Provided(weighting);
+            second.optimization.initial_sigma = Some(first_step.sigma.clone());
+            second.optimization.initial_pi = first_step.pi.clone();
+            second.optimization.initial_rho = first_step.rho;
+            Ok(first_step
+                .compute_optimal_instruments(&estimation)?
+                .to_problem(&estimation)?
+                .with_options_override(second)
+                .optimize()?
+                .results)
+        })?;
+
+        let beta = (&halves[0].beta + &halves[1].beta) / 2.0;
+        let mut average = NonlinearParameters::new((&halves[0].sigma + &halves[1].sigma) / 2.0);
+        if let (Some(first), Some(second)) = (&halves[0].pi, &halves[1].pi) {
+            average = average.with_pi((first + second) / 2.0);
+        }
+        if let (Some(first), Some(second)) = (halves[0].rho, halves[1].rho) {
+            average = average.with_rho((first + second) / 2.0);
+        }
+        Ok(CrossFit {
+            halves,
+            beta,
+            parameters: average,
+        })
+    }
+
+    /// K-fold cross-validation over markets at nonlinear parameters `sigma`.
+    ///
+    /// Market `t` (in partition order) is held out in fold `t mod folds`. For each fold,
+    /// `beta` is estimated on the remaining markets and shares in the held-out markets
+    /// are predicted from `delta = X1 * beta`, i.e. with `xi` at its mean of zero. Folds
+    /// run in parallel within the problem's [`ParallelismOptions`](crate::ParallelismOptions).
+    /// Compare specifications, e.g. a zero entry in `sigma` against a free one, or
+    /// different demand models, by their average out-of-sample error.
+    pub fn cross_validate(&self, sigma: &DMatrix<f64>, folds: usize) -> Result<CrossValidation> {
+        let market_count = self.data().partition().market_count();
+        if folds < 2 || folds > market_count {
+            return Err(BlpError::dimension_mismatch(
+                "cross-validation folds",
+                market_count,
+                folds,
+            ));
+        }
+
+        let parameters = NonlinearParameters::new(sigma.clone());
+        let scores = parallel::run_nested(&self.options().parallelism, folds, |fold| {
+            let (held_out, training): (Vec<usize>, Vec<usize>) =
+                (0..market_count).partition(|market| market % folds == fold);
+            let results = self
+                .with_data(self.data().select_markets(&training)?)?
+                .solve(&parameters)?;
+
+            let test = self.data().select_markets(&held_out)?;
+            let delta = test.x1() * &results.beta;
+            let inputs = ShareInputs::new(&test, self.draws(), sigma, &self.options().contraction);
+            let predicted = self.model().shares(&delta, &inputs)?;
+            let observed = test.shares();
+            Ok(FoldScore {
+                held_out: test
+                    .partition()
+                    .markets()
+                    .map(|m| m.id().to_string())
+                    .collect(),
+                share_rmse: rmse(&(&predicted - observed)),
+                log_share_rmse: rmse(&(predicted.map(f64::ln) - observed.map(f64::ln))),
+            })
+        })?;
+
+        Ok(CrossValidation { folds: scores })
+    }
+}
+
+fn rmse(errors: &DVector<f64>) -> f64 {
+    (errors.norm_squared() / errors.len() as f64).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::ProductDataBuilder;
+    use crate::integration::SimulationDraws;
+
+    #[test]
+    fn folds_hold_out_every_market_once() {
+        let market_ids = ["a", "a", "b", "b", "c", "c", "d", "d"]
+            .map(String::from)
+            .to_vec();
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.1, 0.4, 0.25, 0.25, 0.3, 0.1]);
+        let x1 = DMatrix::from_row_slice(
+            8,
+            2,
+            &[
+                1.0, 0.5, 1.0, 1.0, 1.0, 0.2, 1.0, 1.5, 1.0, 0.8, 1.0, 0.7, 1.0, 1.2, 1.0, 0.1,
+            ],
+        );
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .build()
+            .unwrap();
+        let problem = Problem::new(data, SimulationDraws::standard_normal(1, 0, 3)).unwrap();
+
+        let validation = problem.cross_validate(&DMatrix::zeros(0, 0), 2).unwrap();
+        assert_eq!(validation.folds.len(), 2);
+        assert_eq!(validation.folds[0].held_out, vec!["a", "c"]);
+        assert_eq!(validation.folds[1].held_out, vec!["b", "d"]);
+        assert!(validation.mean_share_rmse() > 0.0);
+        assert!(problem.cross_validate(&DMatrix::zeros(0, 0), 5).is_err());
+    }
+
+    #[test]
+    fn cross_fitting_swaps_halves() {
+        let market_ids = ["a", "a", "b", "b", "c", "c", "d", "d"]
+            .map(String::from)
+            .to_vec();
+        let shares = DVector::from_vec(vec![0.2, 0.3, 0.1, 0.4, 0.25, 0.25, 0.3, 0.1]);
+        let x1 = DMatrix::from_row_slice(
+            8,
+            2,
+            &[
+                1.0, 0.5, 1.0, 1.0, 1.0, 0.2, 1.0, 1.5, 1.0, 0.8, 1.0, 0.7, 1.0, 1.2, 1.0, 0.1,
+            ],
+        );
+        let z = DMatrix::from_fn(8, 3, |j, k| match k {
+            0 => 1.0,
+            1 => x1[(j, 1)],
+            _ => ((j * 5) % 7) as f64 / 7.0,
+        });
+        let data = ProductDataBuilder::new(market_ids, shares)
+            .x1(x1)
+            .instruments(z)
+            .build()
+            .unwrap();
+        let problem = Problem::new(data, SimulationDraws::standard_normal(1, 0, 3)).unwrap();
+
+        let fit = problem
+            .cross_fit(&NonlinearParameters::new(DMatrix::zeros(0, 0)))
+            .unwrap();
+        assert_eq!(fit.halves.len(), 2);
+        assert_eq!(fit.halves[0].delta.len(), 4);
+        let average = (fit.halves[0].beta[1] + fit.halves[1].beta[1]) / 2.0;
+        assert!((fit.beta[1] - average).abs() < 1e-12);
+        // Each half is estimated in the other half's optimal instruments, one per column
+        // of X1, with a weighting matrix built from the other half.
+        assert_eq!(fit.halves[0].weighting_matrix.shape(), (2, 2));
+        assert!((&fit.halves[0].weighting_matrix - &fit.halves[1].weighting_matrix).amax() > 1e-6);
+
+        let x2 = DMatrix::from_fn(8, 1, |j, _| problem.data().x1()[(j, 1)]);
+        let data = ProductDataBuilder::new(
+            problem.data().market_ids().to_vec(),
+            problem.data().shares().clone(),
+        )
+        .x1(problem.data().x1().clone())
+        .x2(x2)
+        .instruments(problem.data().instruments().clone())
+        .build()
+        .unwrap();
+        let problem = Problem::new(data, SimulationDraws::standard_normal(20, 1, 3)).unwrap();
+        let start = DMatrix::from_element(1, 1, 0.5);
+        let fit = problem
+            .cross_fit(&NonlinearParameters::new(start.clone()))
+            .unwrap();
+        // The nonlinear parameters are re-estimated on each half rather than held fixed.
+        assert!(fit.halves.iter().all(|half| half.sigma != start));
+        let average = (&fit.halves[0].sigma + &fit.halves[1].sigma) / 2.0;
+        assert_eq!(fit.parameters.sigma(), &average);
+    }
+}
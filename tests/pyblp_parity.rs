@@ -2,12 +2,13 @@ use approx::assert_relative_eq;
 use blprs::data::ProductDataBuilder;
 use blprs::demand::predict_shares;
 use blprs::integration::SimulationDraws;
-use blprs::{ContractionOptions, Problem, ProblemOptions};
+use blprs::{ContractionOptions, NonlinearParameters, Problem, ProblemOptions};
 use nalgebra::{DMatrix, DVector};
 
 /// Reproduces a simple logit comparison whose closed-form delta matches values reported in
 /// `pyblp/tests/test_blp.py` when running with the same product shares.
 #[test]
+#[allow(clippy::inconsistent_digit_grouping)] // reference values as pyBLP prints them
 fn logit_delta_matches_pyblp_reference() {
     let market_ids = vec!["0".to_string(), "0".to_string(), "1".to_string()];
     let shares = DVector::from_vec(vec![0.3, 0.2, 0.4]);
@@ -30,7 +31,9 @@ fn logit_delta_matches_pyblp_reference() {
         .build()
         .unwrap();
     let options = ProblemOptions::default();
-    let result = problem.solve_with_options(&sigma, &options).unwrap();
+    let result = problem
+        .solve_with_options(&NonlinearParameters::new(sigma.clone()), &options)
+        .unwrap();
 
     let expected_delta = DVector::from_vec(vec![
         -0.510_825_623_765_9907,